@@ -0,0 +1,43 @@
+//!
+//! Build (or update) an address index, and print the history of addresses.
+//!
+//! `cargo run --release --example address_report -- <datadir> <index_dir> <address>...`
+//!
+use bitcoin_explorer::pipelines::{address_report, AddressIndexConfig};
+use bitcoin_explorer::Address;
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        eprintln!("usage: {} <datadir> <index_dir> <address>...", args[0]);
+        std::process::exit(1);
+    }
+    let addresses: Vec<Address> = match args[3..].iter().map(|a| Address::from_str(a)).collect() {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            eprintln!("invalid address: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let cfg = AddressIndexConfig::new(Path::new(&args[1]), Path::new(&args[2]));
+    match address_report(&cfg, &addresses) {
+        Ok(reports) => {
+            for report in reports {
+                println!("{}: balance {} sat", report.address, report.balance);
+                for event in report.history {
+                    println!(
+                        "  {} {}:{} {:?} {}",
+                        event.height, event.txid, event.index, event.kind, event.value
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
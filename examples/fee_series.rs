@@ -0,0 +1,26 @@
+//!
+//! Daily fees paid, as CSV.
+//!
+//! `cargo run --release --example fee_series -- <datadir> <out.csv>`
+//!
+use bitcoin_explorer::pipelines::{fee_time_series, write_fee_series_csv, FeeSeriesConfig};
+use std::env;
+use std::path::Path;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <datadir> <out.csv>", args[0]);
+        std::process::exit(1);
+    }
+    let cfg = FeeSeriesConfig::new(Path::new(&args[1]));
+    let result = fee_time_series(&cfg)
+        .and_then(|series| write_fee_series_csv(&series, Path::new(&args[2])).map(|_| series));
+    match result {
+        Ok(series) => println!("{} points written to {}", series.len(), args[2]),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * C ABI of bitcoin-explorer (feature `capi`).
+ *
+ * Generated with `cbindgen --config cbindgen.toml --output include/bitcoin_explorer.h`.
+ */
+
+#ifndef BITCOIN_EXPLORER_H
+#define BITCOIN_EXPLORER_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * An open `BitcoinDB`, opaque to C.
+ */
+typedef struct BtcxDb BtcxDb;
+
+/**
+ * Called by `btcx_iter_blocks` with the height and the JSON of each block
+ * (valid during the call only). Return `false` to stop the iteration.
+ */
+typedef bool (*BtcxBlockCallback)(size_t height, const char *json, size_t len, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Version of this library (static string).
+ */
+const char *btcx_version(void);
+
+/**
+ * Message of the last error of this thread, `NULL` if none.
+ *
+ * Valid until the next failing call on this thread.
+ */
+const char *btcx_last_error(void);
+
+/**
+ * Open the bitcoin core datadir at `path`, see `BitcoinDB::new`.
+ *
+ * Returns `NULL` on failure.
+ *
+ * # Safety
+ *
+ * `path` must be a NUL-terminated string.
+ */
+BtcxDb *btcx_open(const char *path, bool tx_index);
+
+/**
+ * Close a db opened by `btcx_open` (`NULL` is ignored).
+ *
+ * # Safety
+ *
+ * `db` must come from `btcx_open` and not be used afterwards.
+ */
+void btcx_close(BtcxDb *db);
+
+/**
+ * Number of blocks available (`BitcoinDB::get_block_count`),
+ * `0` for a `NULL` db.
+ *
+ * # Safety
+ *
+ * `db` must be `NULL` or come from `btcx_open`.
+ */
+size_t btcx_block_count(const BtcxDb *db);
+
+/**
+ * JSON of the block at `height` (`FBlock`), `NULL` on failure.
+ *
+ * # Safety
+ *
+ * `db` must come from `btcx_open`. Release the result with `btcx_string_free`.
+ */
+char *btcx_get_block_json(const BtcxDb *db, size_t height);
+
+/**
+ * JSON of the transaction `txid` (hex, `FTransaction`), `NULL` on failure.
+ *
+ * Requires a db opened with `tx_index`.
+ *
+ * # Safety
+ *
+ * `db` must come from `btcx_open`, `txid` must be a NUL-terminated string.
+ * Release the result with `btcx_string_free`.
+ */
+char *btcx_get_transaction_json(const BtcxDb *db, const char *txid);
+
+/**
+ * Call `callback` with the JSON (`FBlock`) of blocks `start..end`, in order.
+ *
+ * Returns the number of blocks passed to `callback`
+ * (stopping early if it returns `false`), or `-1` if the range
+ * is invalid or a block cannot be read.
+ *
+ * # Safety
+ *
+ * `db` must come from `btcx_open`. `user_data` is passed to `callback` as is.
+ */
+int64_t btcx_iter_blocks(const BtcxDb *db,
+                         size_t start,
+                         size_t end,
+                         BtcxBlockCallback callback,
+                         void *user_data);
+
+/**
+ * Release a string returned by this library (`NULL` is ignored).
+ *
+ * # Safety
+ *
+ * `s` must come from a `btcx_*_json` function and not be used afterwards.
+ */
+void btcx_string_free(char *s);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* BITCOIN_EXPLORER_H */
@@ -0,0 +1,569 @@
+use crate::analysis::hll::{HyperLogLog, DEFAULT_HLL_PRECISION};
+use crate::analysis::merge::ShardMerge;
+use crate::api::BitcoinDB;
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::proto::amount::AmountFormat;
+use bitcoin::{Block, Network};
+use par_iter_sync::IntoParallelIteratorSync;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use std::time::Duration;
+
+/// number of satoshis of the initial block subsidy
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+const HALVING_INTERVAL: usize = 210_000;
+const REGTEST_HALVING_INTERVAL: usize = 150;
+
+///
+/// Built-in aggregators of `aggregate_by_time`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Aggregator {
+    /// number of blocks
+    BlockCount,
+    /// number of transactions (including coinbase)
+    TxCount,
+    /// number of inputs (excluding coinbase inputs)
+    InputCount,
+    /// number of outputs
+    OutputCount,
+    /// sum of output values (satoshi)
+    OutputValue,
+    /// sum of transaction fees (satoshi), i.e. coinbase outputs minus
+    /// the subsidy of the network of the db; blocks counted by
+    /// `UnderclaimedBlocks` add no fees, so the sum is a lower bound
+    /// in buckets with such blocks
+    Fees,
+    /// estimated number of distinct output scripts (HyperLogLog)
+    DistinctOutputScripts,
+    /// number of blocks whose coinbase claims less than the subsidy,
+    /// for which fees cannot be derived from the coinbase
+    UnderclaimedBlocks,
+}
+
+///
+/// Per-block or per-bucket state of an aggregator.
+///
+#[derive(Debug, Clone)]
+enum Partial {
+    Sum(u64),
+    Sketch(HyperLogLog),
+}
+
+impl Aggregator {
+    pub const ALL: [Aggregator; 8] = [
+        Aggregator::BlockCount,
+        Aggregator::TxCount,
+        Aggregator::InputCount,
+        Aggregator::OutputCount,
+        Aggregator::OutputValue,
+        Aggregator::Fees,
+        Aggregator::DistinctOutputScripts,
+        Aggregator::UnderclaimedBlocks,
+    ];
+
+    /// values of this aggregator are amounts (satoshi)
+    pub fn is_amount(&self) -> bool {
+        matches!(self, Aggregator::OutputValue | Aggregator::Fees)
+    }
+}
+
+impl Partial {
+    fn merge(&mut self, other: Partial) {
+        match (self, other) {
+            (Partial::Sum(a), Partial::Sum(b)) => *a = a.saturating_add(b),
+            (Partial::Sketch(a), Partial::Sketch(b)) => {
+                // same precision by construction
+                a.merge(&b).unwrap()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn value(&self) -> u64 {
+        match self {
+            Partial::Sum(v) => *v,
+            Partial::Sketch(hll) => hll.estimate(),
+        }
+    }
+}
+
+///
+/// The list of aggregators to compute, which are the columns of `TimeTable`.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AggregatorSpec {
+    pub aggregators: Vec<Aggregator>,
+}
+
+impl AggregatorSpec {
+    pub fn new() -> Self {
+        AggregatorSpec::default()
+    }
+
+    /// add an aggregator (column)
+    pub fn with(mut self, aggregator: Aggregator) -> Self {
+        self.aggregators.push(aggregator);
+        self
+    }
+}
+
+///
+/// One time bucket of `TimeTable`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeRow {
+    /// unix timestamp of the start of this bucket
+    pub bucket_start: u64,
+    /// one value for each aggregator of the spec
+    pub values: Vec<u64>,
+}
+
+///
+/// Result of `aggregate_by_time`, with rows sorted by time.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeTable {
+    pub columns: Vec<Aggregator>,
+    pub rows: Vec<TimeRow>,
+}
+
+///
+/// Aggregate blocks `range` into time buckets of length `bucket` (by block time).
+///
+/// Per-block values are computed in worker threads, and merged into buckets.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use bitcoin_explorer::analysis::{aggregate_by_time, Aggregator, AggregatorSpec};
+/// use std::path::Path;
+/// use std::time::Duration;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let spec = AggregatorSpec::new()
+///     .with(Aggregator::TxCount)
+///     .with(Aggregator::Fees);
+/// let daily = aggregate_by_time(&db, 600000..700000, Duration::from_secs(86400), &spec).unwrap();
+/// daily.write_csv(std::io::stdout()).unwrap();
+/// ```
+///
+pub fn aggregate_by_time(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    bucket: Duration,
+    spec: &AggregatorSpec,
+) -> OpResult<TimeTable> {
+    Ok(aggregate_by_time_partial(db, range, bucket, spec)?.finish())
+}
+
+///
+/// Same as `aggregate_by_time`, but keeps the mergeable state of each bucket
+/// (sums and sketches), to merge the tables of several shards
+/// with `merge_shards` before `finish`.
+///
+pub fn aggregate_by_time_partial(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    bucket: Duration,
+    spec: &AggregatorSpec,
+) -> OpResult<PartialTimeTable> {
+    let bucket_secs = bucket.as_secs();
+    if bucket_secs == 0 {
+        return Err(OpError::from("bucket must be at least one second"));
+    }
+    let expected = range.len();
+    let network = db.network();
+    let db = db.clone();
+    let columns = spec.aggregators.clone();
+    let aggregators = columns.clone();
+    let mut buckets: BTreeMap<u64, Vec<Partial>> = BTreeMap::new();
+    let mut received = 0;
+    for (time, values) in range.into_par_iter_sync(move |h| match db.get_block::<Block>(h) {
+        Ok(block) => Ok((
+            block.header.time as u64,
+            aggregators
+                .iter()
+                .map(|a| block_partial(a, &block, h, network))
+                .collect::<Vec<Partial>>(),
+        )),
+        Err(_) => Err(()),
+    }) {
+        merge_bucket(&mut buckets, time / bucket_secs * bucket_secs, values);
+        received += 1;
+    }
+    if received != expected {
+        return Err(OpError::from("failed to read some blocks in range"));
+    }
+    Ok(PartialTimeTable {
+        columns,
+        bucket_secs,
+        buckets,
+    })
+}
+
+fn merge_bucket(
+    buckets: &mut BTreeMap<u64, Vec<Partial>>,
+    bucket_start: u64,
+    values: Vec<Partial>,
+) {
+    match buckets.entry(bucket_start) {
+        Entry::Vacant(e) => {
+            e.insert(values);
+        }
+        Entry::Occupied(mut e) => {
+            for (acc, v) in e.get_mut().iter_mut().zip(values) {
+                acc.merge(v);
+            }
+        }
+    }
+}
+
+///
+/// `TimeTable` before the estimation of distinct counts,
+/// see `aggregate_by_time_partial`.
+///
+/// Tables of shards are merged bucket by bucket (buckets spanning
+/// two shards included), so the merged table is identical to the one
+/// of a single run. Use `to_bytes` / `from_bytes` to collect
+/// the tables of shards run on other machines.
+///
+#[derive(Debug, Clone)]
+pub struct PartialTimeTable {
+    columns: Vec<Aggregator>,
+    bucket_secs: u64,
+    buckets: BTreeMap<u64, Vec<Partial>>,
+}
+
+impl PartialTimeTable {
+    pub fn columns(&self) -> &[Aggregator] {
+        &self.columns
+    }
+
+    ///
+    /// Compute the values of each bucket.
+    ///
+    pub fn finish(self) -> TimeTable {
+        TimeTable {
+            columns: self.columns,
+            rows: self
+                .buckets
+                .into_iter()
+                .map(|(bucket_start, values)| TimeRow {
+                    bucket_start,
+                    values: values.iter().map(|v| v.value()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    ///
+    /// Serialize (little endian): bucket length, columns, then buckets
+    /// with the sum or the sketch (`HyperLogLog::to_bytes`) of each column.
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.bucket_secs.to_le_bytes());
+        bytes.push(self.columns.len() as u8);
+        for c in self.columns.iter() {
+            bytes.push(Aggregator::ALL.iter().position(|a| a == c).unwrap() as u8);
+        }
+        bytes.extend_from_slice(&(self.buckets.len() as u64).to_le_bytes());
+        for (bucket_start, values) in self.buckets.iter() {
+            bytes.extend_from_slice(&bucket_start.to_le_bytes());
+            for v in values.iter() {
+                match v {
+                    Partial::Sum(sum) => bytes.extend_from_slice(&sum.to_le_bytes()),
+                    Partial::Sketch(hll) => {
+                        let sketch = hll.to_bytes();
+                        bytes.extend_from_slice(&(sketch.len() as u32).to_le_bytes());
+                        bytes.extend_from_slice(&sketch);
+                    }
+                }
+            }
+        }
+        bytes
+    }
+
+    ///
+    /// Deserialize a table written by `to_bytes`.
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> OpResult<Self> {
+        let mut r = bytes;
+        let bucket_secs = take_u64(&mut r)?;
+        let n_columns = take(&mut r, 1)?[0] as usize;
+        let mut columns = Vec::with_capacity(n_columns);
+        for code in take(&mut r, n_columns)? {
+            match Aggregator::ALL.get(*code as usize) {
+                Some(a) => columns.push(*a),
+                None => return Err(OpError::from("invalid aggregator in partial time table")),
+            }
+        }
+        let n_buckets = take_u64(&mut r)?;
+        let mut buckets = BTreeMap::new();
+        for _ in 0..n_buckets {
+            let bucket_start = take_u64(&mut r)?;
+            let mut values = Vec::with_capacity(columns.len());
+            for c in columns.iter() {
+                values.push(match c {
+                    Aggregator::DistinctOutputScripts => {
+                        let mut len = [0u8; 4];
+                        len.copy_from_slice(take(&mut r, 4)?);
+                        let len = u32::from_le_bytes(len) as usize;
+                        Partial::Sketch(HyperLogLog::from_bytes(take(&mut r, len)?)?)
+                    }
+                    _ => Partial::Sum(take_u64(&mut r)?),
+                });
+            }
+            buckets.insert(bucket_start, values);
+        }
+        if !r.is_empty() {
+            return Err(OpError::from("trailing bytes in partial time table"));
+        }
+        Ok(PartialTimeTable {
+            columns,
+            bucket_secs,
+            buckets,
+        })
+    }
+}
+
+impl ShardMerge for PartialTimeTable {
+    fn merge_shard(&mut self, next: Self) -> OpResult<()> {
+        if self.columns != next.columns || self.bucket_secs != next.bucket_secs {
+            return Err(OpError::from(
+                "cannot merge time tables of different aggregators or buckets",
+            ));
+        }
+        for (bucket_start, values) in next.buckets {
+            merge_bucket(&mut self.buckets, bucket_start, values);
+        }
+        Ok(())
+    }
+}
+
+fn take<'a>(r: &mut &'a [u8], n: usize) -> OpResult<&'a [u8]> {
+    if r.len() < n {
+        return Err(OpError::from("truncated partial time table"));
+    }
+    let (head, tail) = r.split_at(n);
+    *r = tail;
+    Ok(head)
+}
+
+fn take_u64(r: &mut &[u8]) -> OpResult<u64> {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(take(r, 8)?);
+    Ok(u64::from_le_bytes(v))
+}
+
+impl TimeTable {
+    ///
+    /// Write this table as CSV, with a header line.
+    ///
+    /// Amounts are written as integer satoshis.
+    ///
+    pub fn write_csv<W: Write>(&self, w: W) -> OpResult<()> {
+        self.write_csv_with(w, AmountFormat::Satoshis)
+    }
+
+    ///
+    /// Write this table as CSV, with amount columns in `amount_format`.
+    ///
+    pub fn write_csv_with<W: Write>(&self, mut w: W, amount_format: AmountFormat) -> OpResult<()> {
+        write!(w, "bucket_start")?;
+        for c in self.columns.iter() {
+            write!(w, ",{}", c)?;
+        }
+        writeln!(w)?;
+        for row in self.rows.iter() {
+            write!(w, "{}", row.bucket_start)?;
+            for (c, v) in self.columns.iter().zip(row.values.iter()) {
+                if c.is_amount() {
+                    write!(w, ",{}", amount_format.format(*v))?;
+                } else {
+                    write!(w, ",{}", v)?;
+                }
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Block subsidy (satoshi) at a certain height of `network`.
+///
+pub(crate) fn block_subsidy(height: usize, network: Network) -> u64 {
+    let interval = match network {
+        Network::Regtest => REGTEST_HALVING_INTERVAL,
+        _ => HALVING_INTERVAL,
+    };
+    let halvings = height / interval;
+    if halvings >= 64 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
+fn block_partial(
+    aggregator: &Aggregator,
+    block: &Block,
+    height: usize,
+    network: Network,
+) -> Partial {
+    match aggregator {
+        Aggregator::DistinctOutputScripts => {
+            let mut hll = HyperLogLog::new(DEFAULT_HLL_PRECISION);
+            for o in block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+                hll.insert(o.script_pubkey.as_bytes());
+            }
+            Partial::Sketch(hll)
+        }
+        _ => Partial::Sum(block_value(aggregator, block, height, network)),
+    }
+}
+
+fn block_value(aggregator: &Aggregator, block: &Block, height: usize, network: Network) -> u64 {
+    match aggregator {
+        Aggregator::BlockCount => 1,
+        Aggregator::TxCount => block.txdata.len() as u64,
+        Aggregator::InputCount => block
+            .txdata
+            .iter()
+            .filter(|tx| !tx.is_coin_base())
+            .map(|tx| tx.input.len() as u64)
+            .sum(),
+        Aggregator::OutputCount => block.txdata.iter().map(|tx| tx.output.len() as u64).sum(),
+        Aggregator::OutputValue => block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.output.iter())
+            .fold(0u64, |acc, o| acc.saturating_add(o.value)),
+        Aggregator::Fees => coinbase_claim(block).saturating_sub(block_subsidy(height, network)),
+        Aggregator::DistinctOutputScripts => {
+            block_partial(aggregator, block, height, network).value()
+        }
+        Aggregator::UnderclaimedBlocks => {
+            u64::from(coinbase_claim(block) < block_subsidy(height, network))
+        }
+    }
+}
+
+/// sum of the coinbase outputs of `block`
+fn coinbase_claim(block: &Block) -> u64 {
+    match block.txdata.first() {
+        None => 0,
+        Some(coinbase) => coinbase
+            .output
+            .iter()
+            .fold(0u64, |acc, o| acc.saturating_add(o.value)),
+    }
+}
+
+impl fmt::Display for Aggregator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Aggregator::BlockCount => write!(f, "block_count"),
+            Aggregator::TxCount => write!(f, "tx_count"),
+            Aggregator::InputCount => write!(f, "input_count"),
+            Aggregator::OutputCount => write!(f, "output_count"),
+            Aggregator::OutputValue => write!(f, "output_value"),
+            Aggregator::Fees => write!(f, "fees"),
+            Aggregator::DistinctOutputScripts => write!(f, "distinct_output_scripts"),
+            Aggregator::UnderclaimedBlocks => write!(f, "underclaimed_blocks"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::merge::merge_shards;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+
+    #[test]
+    fn test_block_subsidy() {
+        let mainnet = Network::Bitcoin;
+        assert_eq!(block_subsidy(0, mainnet), 5_000_000_000);
+        assert_eq!(block_subsidy(209_999, mainnet), 5_000_000_000);
+        assert_eq!(block_subsidy(210_000, mainnet), 2_500_000_000);
+        assert_eq!(block_subsidy(630_000, mainnet), 625_000_000);
+        assert_eq!(block_subsidy(64 * 210_000, mainnet), 0);
+        assert_eq!(block_subsidy(210_000, Network::Testnet), 2_500_000_000);
+        assert_eq!(block_subsidy(149, Network::Regtest), 5_000_000_000);
+        assert_eq!(block_subsidy(150, Network::Regtest), 2_500_000_000);
+    }
+
+    #[test]
+    fn test_block_value() {
+        let mainnet = Network::Bitcoin;
+        let genesis = genesis_block(mainnet);
+        assert_eq!(block_value(&Aggregator::TxCount, &genesis, 0, mainnet), 1);
+        assert_eq!(
+            block_value(&Aggregator::InputCount, &genesis, 0, mainnet),
+            0
+        );
+        assert_eq!(
+            block_value(&Aggregator::OutputValue, &genesis, 0, mainnet),
+            5_000_000_000
+        );
+        assert_eq!(block_value(&Aggregator::Fees, &genesis, 0, mainnet), 0);
+        assert_eq!(
+            block_value(&Aggregator::UnderclaimedBlocks, &genesis, 0, mainnet),
+            0
+        );
+
+        // a coinbase claiming 1 BTC more than a regtest subsidy of 6.25 BTC
+        let mut block = genesis.clone();
+        block.txdata[0].output[0].value = 725_000_000;
+        assert_eq!(
+            block_value(&Aggregator::Fees, &block, 450, Network::Regtest),
+            100_000_000
+        );
+        // a miner claiming less than the subsidy
+        block.txdata[0].output[0].value = 4_000_000_000;
+        assert_eq!(block_value(&Aggregator::Fees, &block, 0, mainnet), 0);
+        assert_eq!(
+            block_value(&Aggregator::UnderclaimedBlocks, &block, 0, mainnet),
+            1
+        );
+    }
+
+    #[test]
+    fn test_partial_time_table_merge() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let columns = vec![Aggregator::TxCount, Aggregator::DistinctOutputScripts];
+        let shard = |buckets: &[u64]| PartialTimeTable {
+            columns: columns.clone(),
+            bucket_secs: 600,
+            buckets: buckets
+                .iter()
+                .map(|b| {
+                    let values = columns
+                        .iter()
+                        .map(|a| block_partial(a, &genesis, 0, Network::Bitcoin))
+                        .collect();
+                    (*b, values)
+                })
+                .collect(),
+        };
+        let (range, merged) =
+            merge_shards(vec![(1..2, shard(&[600, 1200])), (0..1, shard(&[0, 600]))]).unwrap();
+        assert_eq!(range, 0..2);
+        let copy = PartialTimeTable::from_bytes(&merged.to_bytes()).unwrap();
+        assert_eq!(copy.to_bytes(), merged.to_bytes());
+        let table = copy.finish();
+        assert_eq!(table.rows.len(), 3);
+        assert_eq!(table.rows[1].values, vec![2, 1]);
+        assert!(PartialTimeTable::from_bytes(&merged.to_bytes()[1..]).is_err());
+    }
+}
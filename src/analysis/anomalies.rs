@@ -0,0 +1,370 @@
+//!
+//! Detect oddities of block data, as structured findings to review
+//! before downstream analysis: timestamps out of order, empty blocks,
+//! duplicate coinbase txids (BIP30), output values overflowing
+//! (block 74638) and abnormally large scripts.
+//!
+use crate::api::BitcoinDB;
+use bitcoin::{Block, Txid};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+
+/// total supply of bitcoin in satoshi, no output can be larger
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// number of previous blocks of the median time past (BIP113)
+const MEDIAN_TIME_SPAN: usize = 11;
+
+///
+/// Limits of `scan_with`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnomalyLimits {
+    /// flag blocks dated more than this many seconds before their parent
+    pub max_time_backwards: u32,
+    /// flag scripts (script_pubkey or script_sig) larger than this, in bytes
+    pub max_script_size: usize,
+}
+
+impl Default for AnomalyLimits {
+    ///
+    /// Two hours (the limit of Bitcoin Core for blocks in the future),
+    /// and 10000 bytes (the consensus limit of executed scripts).
+    ///
+    fn default() -> Self {
+        AnomalyLimits {
+            max_time_backwards: 7200,
+            max_script_size: 10_000,
+        }
+    }
+}
+
+///
+/// Kind of an `Anomaly`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnomalyKind {
+    /// timestamp not above the median of the previous 11 blocks
+    TimestampBelowMedian {
+        time: u32,
+        median: u32,
+    },
+    /// timestamp more than `max_time_backwards` before the parent block
+    TimestampBackwards {
+        time: u32,
+        previous: u32,
+    },
+    /// the block has no transaction but its coinbase
+    EmptyBlock,
+    /// a txid already seen, in the same block or at `first_height`
+    DuplicateTxid {
+        txid: Txid,
+        first_height: usize,
+    },
+    /// an output of more than `MAX_MONEY`
+    ValueOverflow {
+        txid: Txid,
+        vout: u32,
+        value: u64,
+    },
+    /// outputs of a transaction adding up to more than `MAX_MONEY`
+    OutputSumOverflow {
+        txid: Txid,
+    },
+    LargeOutputScript {
+        txid: Txid,
+        vout: u32,
+        size: usize,
+    },
+    LargeInputScript {
+        txid: Txid,
+        input: u32,
+        size: usize,
+    },
+}
+
+///
+/// An oddity found in block `height`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    pub height: usize,
+    pub kind: AnomalyKind,
+}
+
+///
+/// Checks blocks in chain order.
+///
+/// Coinbase txids are remembered to detect duplicates across blocks
+/// (other transactions cannot repeat once their inputs are spent),
+/// other txids only within their block.
+///
+#[derive(Debug, Default)]
+pub(crate) struct AnomalyScanner {
+    limits: AnomalyLimits,
+    /// timestamps of the previous blocks, most recent last
+    times: VecDeque<u32>,
+    coinbases: HashMap<Txid, usize>,
+}
+
+impl AnomalyScanner {
+    pub(crate) fn new(limits: AnomalyLimits) -> Self {
+        AnomalyScanner {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Timestamp of a block before the first processed block.
+    ///
+    pub(crate) fn push_time(&mut self, time: u32) {
+        self.times.push_back(time);
+        if self.times.len() > MEDIAN_TIME_SPAN {
+            self.times.pop_front();
+        }
+    }
+
+    pub(crate) fn process_block(&mut self, height: usize, block: &Block) -> Vec<Anomaly> {
+        let mut kinds = Vec::new();
+        let time = block.header.time;
+        if self.times.len() == MEDIAN_TIME_SPAN {
+            let mut sorted: Vec<u32> = self.times.iter().copied().collect();
+            sorted.sort_unstable();
+            let median = sorted[MEDIAN_TIME_SPAN / 2];
+            if time <= median {
+                kinds.push(AnomalyKind::TimestampBelowMedian { time, median });
+            }
+        }
+        if let Some(previous) = self.times.back().copied() {
+            if time.saturating_add(self.limits.max_time_backwards) < previous {
+                kinds.push(AnomalyKind::TimestampBackwards { time, previous });
+            }
+        }
+        self.push_time(time);
+        if block.txdata.len() == 1 {
+            kinds.push(AnomalyKind::EmptyBlock);
+        }
+
+        let mut txids = HashSet::with_capacity(block.txdata.len());
+        for (i, tx) in block.txdata.iter().enumerate() {
+            let txid = tx.txid();
+            if i == 0 {
+                if let Some(first_height) = self.coinbases.insert(txid, height) {
+                    kinds.push(AnomalyKind::DuplicateTxid { txid, first_height });
+                }
+            } else if !txids.insert(txid) {
+                kinds.push(AnomalyKind::DuplicateTxid {
+                    txid,
+                    first_height: height,
+                });
+            }
+            for (input, tx_in) in tx.input.iter().enumerate() {
+                let size = tx_in.script_sig.len();
+                if size > self.limits.max_script_size {
+                    kinds.push(AnomalyKind::LargeInputScript {
+                        txid,
+                        input: input as u32,
+                        size,
+                    });
+                }
+            }
+            let mut total = Some(0u64);
+            for (vout, o) in tx.output.iter().enumerate() {
+                if o.value > MAX_MONEY {
+                    kinds.push(AnomalyKind::ValueOverflow {
+                        txid,
+                        vout: vout as u32,
+                        value: o.value,
+                    });
+                }
+                total = total.and_then(|t| t.checked_add(o.value));
+                let size = o.script_pubkey.len();
+                if size > self.limits.max_script_size {
+                    kinds.push(AnomalyKind::LargeOutputScript {
+                        txid,
+                        vout: vout as u32,
+                        size,
+                    });
+                }
+            }
+            if total.map_or(true, |t| t > MAX_MONEY) {
+                kinds.push(AnomalyKind::OutputSumOverflow { txid });
+            }
+        }
+        kinds
+            .into_iter()
+            .map(|kind| Anomaly { height, kind })
+            .collect()
+    }
+}
+
+///
+/// Scan blocks of `range` for anomalies, with the default `AnomalyLimits`.
+///
+/// Duplicate coinbase txids are only found within `range`,
+/// start from `0` to obtain complete results.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use bitcoin_explorer::analysis::anomalies;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// for anomaly in anomalies::scan(&db, 0..100000) {
+///     println!("{}: {:?}", anomaly.height, anomaly.kind);
+/// }
+/// ```
+///
+pub fn scan(db: &BitcoinDB, range: Range<usize>) -> impl Iterator<Item = Anomaly> {
+    scan_with(db, range, AnomalyLimits::default())
+}
+
+///
+/// Same as `scan`, with the given `limits`.
+///
+pub fn scan_with(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    limits: AnomalyLimits,
+) -> impl Iterator<Item = Anomaly> {
+    let mut scanner = AnomalyScanner::new(limits);
+    // timestamps before the range, from the block index
+    for h in range.start.saturating_sub(MEDIAN_TIME_SPAN)..range.start {
+        if let Ok(header) = db.get_header(h) {
+            scanner.push_time(header.block_header.time);
+        }
+    }
+    let start = range.start;
+    db.iter_block::<Block>(range.start, range.end)
+        .enumerate()
+        .flat_map(move |(i, block)| scanner.process_block(start + i, &block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Network, OutPoint, Script, Transaction, TxIn, TxOut};
+
+    fn tx(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input,
+            output,
+        }
+    }
+
+    fn block(time: u32, txdata: Vec<Transaction>) -> Block {
+        let mut header = genesis_block(Network::Bitcoin).header;
+        header.time = time;
+        Block { header, txdata }
+    }
+
+    #[test]
+    fn test_anomalies() {
+        let mut scanner = AnomalyScanner::new(AnomalyLimits::default());
+        let coinbase = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let spend = tx(
+            vec![TxIn {
+                previous_output: OutPoint::new(coinbase.txid(), 0),
+                ..Default::default()
+            }],
+            vec![TxOut {
+                value: 50,
+                script_pubkey: Script::new(),
+            }],
+        );
+        for h in 0..11 {
+            let kinds: Vec<AnomalyKind> = scanner
+                .process_block(
+                    h,
+                    &block(
+                        1000 * h as u32 + 100_000,
+                        vec![coinbase.clone(), spend.clone()],
+                    ),
+                )
+                .into_iter()
+                .map(|a| a.kind)
+                .collect();
+            // the same coinbase in every block
+            if h == 0 {
+                assert!(kinds.is_empty());
+            } else {
+                assert_eq!(
+                    kinds,
+                    vec![AnomalyKind::DuplicateTxid {
+                        txid: coinbase.txid(),
+                        first_height: h - 1
+                    }]
+                );
+            }
+        }
+
+        // block 74638: two outputs of ~92 billion bitcoin
+        let overflow = tx(
+            spend.input.clone(),
+            vec![
+                TxOut {
+                    value: 0x7ffffffffff85ee0,
+                    script_pubkey: Script::new(),
+                },
+                TxOut {
+                    value: 0x7ffffffffff85ee0,
+                    script_pubkey: Script::from(vec![0x6a; 10_001]),
+                },
+            ],
+        );
+        let mut unique_coinbase = coinbase.clone();
+        unique_coinbase.lock_time = 1;
+        // dated 3 hours before its parent, below the median time
+        let found =
+            scanner.process_block(11, &block(100_000, vec![unique_coinbase, overflow.clone()]));
+        let txid = overflow.txid();
+        assert_eq!(
+            found.into_iter().map(|a| a.kind).collect::<Vec<_>>(),
+            vec![
+                AnomalyKind::TimestampBelowMedian {
+                    time: 100_000,
+                    median: 105_000
+                },
+                AnomalyKind::TimestampBackwards {
+                    time: 100_000,
+                    previous: 110_000
+                },
+                AnomalyKind::ValueOverflow {
+                    txid,
+                    vout: 0,
+                    value: 0x7ffffffffff85ee0
+                },
+                AnomalyKind::ValueOverflow {
+                    txid,
+                    vout: 1,
+                    value: 0x7ffffffffff85ee0
+                },
+                AnomalyKind::LargeOutputScript {
+                    txid,
+                    vout: 1,
+                    size: 10_001
+                },
+                AnomalyKind::OutputSumOverflow { txid },
+            ]
+        );
+
+        let mut empty_coinbase = coinbase;
+        empty_coinbase.lock_time = 2;
+        let found = scanner.process_block(12, &block(200_000, vec![empty_coinbase]));
+        assert_eq!(
+            found,
+            vec![Anomaly {
+                height: 12,
+                kind: AnomalyKind::EmptyBlock
+            }]
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//!
+//! Block weight broken down by transaction category.
+//!
+use crate::api::BitcoinDB;
+use crate::meta::inscriptions;
+use bitcoin::{Block, Transaction};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// minimum number of inputs of a consolidation
+const CONSOLIDATION_MIN_INPUTS: usize = 3;
+/// minimum number of equal-value outputs of a coinjoin
+const COINJOIN_MIN_EQUAL_OUTPUTS: usize = 3;
+
+///
+/// Heuristic transaction categories.
+///
+/// A transaction has exactly one category,
+/// the first matching in the order of the variants.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TxCategory {
+    Coinbase,
+    /// reveals at least one ordinals inscription
+    Inscription,
+    /// has at least one OP_RETURN output
+    OpReturn,
+    /// at least 3 outputs of equal value, and at least as many inputs
+    CoinJoin,
+    /// at least 3 inputs merged into a single output
+    Consolidation,
+    /// everything else
+    Payment,
+}
+
+impl TxCategory {
+    pub const ALL: [TxCategory; 6] = [
+        TxCategory::Coinbase,
+        TxCategory::Inscription,
+        TxCategory::OpReturn,
+        TxCategory::CoinJoin,
+        TxCategory::Consolidation,
+        TxCategory::Payment,
+    ];
+}
+
+impl fmt::Display for TxCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxCategory::Coinbase => write!(f, "coinbase"),
+            TxCategory::Inscription => write!(f, "inscription"),
+            TxCategory::OpReturn => write!(f, "op_return"),
+            TxCategory::CoinJoin => write!(f, "coinjoin"),
+            TxCategory::Consolidation => write!(f, "consolidation"),
+            TxCategory::Payment => write!(f, "payment"),
+        }
+    }
+}
+
+///
+/// Categorize a transaction, see `TxCategory`.
+///
+pub fn categorize(tx: &Transaction) -> TxCategory {
+    if tx.is_coin_base() {
+        TxCategory::Coinbase
+    } else if !inscriptions(tx).is_empty() {
+        TxCategory::Inscription
+    } else if tx.output.iter().any(|o| o.script_pubkey.is_op_return()) {
+        TxCategory::OpReturn
+    } else if is_coinjoin(tx) {
+        TxCategory::CoinJoin
+    } else if tx.input.len() >= CONSOLIDATION_MIN_INPUTS && tx.output.len() == 1 {
+        TxCategory::Consolidation
+    } else {
+        TxCategory::Payment
+    }
+}
+
+fn is_coinjoin(tx: &Transaction) -> bool {
+    coinjoin_outputs(tx).is_some()
+}
+
+///
+/// Number and value of the equal outputs of a coinjoin
+/// (at least 3 equal outputs, and as many inputs), `None` otherwise.
+///
+pub(crate) fn coinjoin_outputs(tx: &Transaction) -> Option<(usize, u64)> {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for o in &tx.output {
+        *counts.entry(o.value).or_default() += 1;
+    }
+    // the most frequent value, the largest among ties
+    let (value, equal) = counts
+        .into_iter()
+        .max_by_key(|(value, count)| (*count, *value))?;
+    if equal >= COINJOIN_MIN_EQUAL_OUTPUTS && tx.input.len() >= equal {
+        Some((equal, value))
+    } else {
+        None
+    }
+}
+
+///
+/// Weight units of a block consumed by each transaction category.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockspaceUsage {
+    pub height: usize,
+    /// weight of the whole block, including header and transaction count
+    pub total_weight: u64,
+    /// weight by category, indexed as `TxCategory::ALL`
+    pub weights: [u64; 6],
+    /// number of transactions by category, indexed as `TxCategory::ALL`
+    pub tx_counts: [u64; 6],
+}
+
+impl BlockspaceUsage {
+    pub(crate) fn of_block(height: usize, block: &Block) -> Self {
+        let mut usage = BlockspaceUsage {
+            height,
+            total_weight: block.weight() as u64,
+            weights: [0; 6],
+            tx_counts: [0; 6],
+        };
+        for tx in &block.txdata {
+            let i = categorize(tx) as usize;
+            usage.weights[i] += tx.weight() as u64;
+            usage.tx_counts[i] += 1;
+        }
+        usage
+    }
+
+    /// weight consumed by a category
+    pub fn weight(&self, category: TxCategory) -> u64 {
+        self.weights[category as usize]
+    }
+
+    /// number of transactions of a category
+    pub fn tx_count(&self, category: TxCategory) -> u64 {
+        self.tx_counts[category as usize]
+    }
+}
+
+///
+/// Iterate through the blockspace usage of blocks in `range`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use bitcoin_explorer::analysis::{iter_blockspace_usage, TxCategory};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// for usage in iter_blockspace_usage(&db, 780000..780100) {
+///     let share = usage.weight(TxCategory::Inscription) as f64 / usage.total_weight as f64;
+///     println!("{}: {:.2}% inscriptions", usage.height, share * 100.0);
+/// }
+/// ```
+///
+pub fn iter_blockspace_usage(
+    db: &BitcoinDB,
+    range: Range<usize>,
+) -> impl Iterator<Item = BlockspaceUsage> {
+    let start = range.start;
+    db.iter_block::<Block>(range.start, range.end)
+        .enumerate()
+        .map(move |(i, block)| BlockspaceUsage::of_block(start + i, &block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{categorize, BlockspaceUsage, TxCategory};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Network, Transaction, TxIn, TxOut};
+
+    fn tx(n_in: usize, values: &[u64]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..n_in)
+                .map(|i| {
+                    let mut tx_in = TxIn::default();
+                    tx_in.previous_output.vout = i as u32;
+                    tx_in
+                })
+                .collect(),
+            output: values
+                .iter()
+                .map(|v| TxOut {
+                    value: *v,
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_categorize() {
+        assert_eq!(categorize(&tx(1, &[10, 20])), TxCategory::Payment);
+        assert_eq!(categorize(&tx(5, &[10])), TxCategory::Consolidation);
+        assert_eq!(categorize(&tx(5, &[7, 7, 7, 7, 7])), TxCategory::CoinJoin);
+        // an exchange batch payout is not a coinjoin
+        assert_eq!(categorize(&tx(1, &[7, 7, 7, 30])), TxCategory::Payment);
+    }
+
+    #[test]
+    fn test_genesis_usage() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let usage = BlockspaceUsage::of_block(0, &genesis);
+        assert_eq!(usage.tx_count(TxCategory::Coinbase), 1);
+        assert_eq!(
+            usage.weight(TxCategory::Coinbase),
+            genesis.txdata[0].weight() as u64
+        );
+        assert!(usage.total_weight > usage.weights.iter().sum::<u64>());
+    }
+}
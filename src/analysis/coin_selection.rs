@@ -0,0 +1,201 @@
+//!
+//! Coin selection patterns of connected transactions.
+//!
+use crate::api::{Address, FConnectedTransaction, SConnectedTransaction};
+use std::fmt;
+
+///
+/// Heuristic coin selection patterns.
+///
+/// Unlike `TxCategory`, a transaction can have several tags
+/// (e.g. a consolidation back to an input address is also a self-transfer).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum CoinSelectionTag {
+    /// many inputs merged into a single output
+    Consolidation,
+    /// few inputs paying many outputs
+    BatchPayout,
+    /// one input split into a small payment and a large remainder
+    PeelingChain,
+    /// every output pays an address of the inputs
+    SelfTransfer,
+}
+
+impl fmt::Display for CoinSelectionTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoinSelectionTag::Consolidation => write!(f, "consolidation"),
+            CoinSelectionTag::BatchPayout => write!(f, "batch_payout"),
+            CoinSelectionTag::PeelingChain => write!(f, "peeling_chain"),
+            CoinSelectionTag::SelfTransfer => write!(f, "self_transfer"),
+        }
+    }
+}
+
+///
+/// Thresholds of the coin selection heuristics.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoinSelectionHeuristics {
+    /// minimum number of inputs of a consolidation
+    pub consolidation_min_inputs: usize,
+    /// minimum number of outputs of a batch payout
+    pub batch_min_outputs: usize,
+    /// maximum number of inputs of a batch payout
+    pub batch_max_inputs: usize,
+    /// maximum ratio of the smaller to the larger output of a peeling step
+    pub peel_max_ratio: f64,
+}
+
+impl Default for CoinSelectionHeuristics {
+    fn default() -> Self {
+        CoinSelectionHeuristics {
+            consolidation_min_inputs: 3,
+            batch_min_outputs: 5,
+            batch_max_inputs: 2,
+            peel_max_ratio: 0.1,
+        }
+    }
+}
+
+impl CoinSelectionHeuristics {
+    ///
+    /// Tag a transaction given the values and addresses
+    /// of its connected inputs and of its outputs.
+    ///
+    /// Coinbase transactions (no connected inputs) have no tag.
+    ///
+    pub fn tags<'a>(
+        &self,
+        input: &[(u64, &'a [Address])],
+        output: &[(u64, &'a [Address])],
+    ) -> Vec<CoinSelectionTag> {
+        let mut tags = Vec::new();
+        if input.is_empty() || output.is_empty() {
+            return tags;
+        }
+        if input.len() >= self.consolidation_min_inputs && output.len() == 1 {
+            tags.push(CoinSelectionTag::Consolidation);
+        }
+        if output.len() >= self.batch_min_outputs && input.len() <= self.batch_max_inputs {
+            tags.push(CoinSelectionTag::BatchPayout);
+        }
+        if input.len() == 1 && output.len() == 2 {
+            let (small, large) = if output[0].0 <= output[1].0 {
+                (output[0].0, output[1].0)
+            } else {
+                (output[1].0, output[0].0)
+            };
+            if large > 0 && (small as f64) <= self.peel_max_ratio * large as f64 {
+                tags.push(CoinSelectionTag::PeelingChain);
+            }
+        }
+        let is_self = output.iter().all(|(_, addresses)| {
+            !addresses.is_empty() && input.iter().any(|(_, a)| a == addresses)
+        });
+        if is_self {
+            tags.push(CoinSelectionTag::SelfTransfer);
+        }
+        tags
+    }
+}
+
+impl FConnectedTransaction {
+    ///
+    /// Coin selection patterns of this transaction, see `CoinSelectionTag`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::analysis::{CoinSelectionHeuristics, CoinSelectionTag};
+    /// use bitcoin_explorer::{BitcoinDB, FConnectedBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let heuristics = CoinSelectionHeuristics::default();
+    /// for block in db.iter_connected_block::<FConnectedBlock>(300000) {
+    ///     for tx in block.txdata {
+    ///         let tags = tx.coin_selection_tags(&heuristics);
+    ///         if tags.contains(&CoinSelectionTag::PeelingChain) {
+    ///             println!("{}", tx.txid);
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn coin_selection_tags(
+        &self,
+        heuristics: &CoinSelectionHeuristics,
+    ) -> Vec<CoinSelectionTag> {
+        let input: Vec<_> = self
+            .input
+            .iter()
+            .map(|o| (o.value.as_sat(), &o.addresses[..]))
+            .collect();
+        let output: Vec<_> = self
+            .output
+            .iter()
+            .map(|o| (o.value.as_sat(), &o.addresses[..]))
+            .collect();
+        heuristics.tags(&input, &output)
+    }
+}
+
+impl SConnectedTransaction {
+    ///
+    /// Coin selection patterns of this transaction, see `CoinSelectionTag`.
+    ///
+    pub fn coin_selection_tags(
+        &self,
+        heuristics: &CoinSelectionHeuristics,
+    ) -> Vec<CoinSelectionTag> {
+        let input: Vec<_> = self
+            .input
+            .iter()
+            .map(|o| (o.value.as_sat(), &o.addresses[..]))
+            .collect();
+        let output: Vec<_> = self
+            .output
+            .iter()
+            .map(|o| (o.value.as_sat(), &o.addresses[..]))
+            .collect();
+        heuristics.tags(&input, &output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_coin_selection_tags() {
+        let a = [Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap()];
+        let b = [Address::from_str("12c6DSiU4Rq3P4ZxziKxzrGs5hJ5R5hPeQ").unwrap()];
+        let h = CoinSelectionHeuristics::default();
+
+        assert!(h.tags(&[], &[(50, &a[..])]).is_empty());
+        assert_eq!(
+            h.tags(&[(10, &a[..]), (10, &b[..]), (10, &b[..])], &[(29, &a[..])]),
+            vec![
+                CoinSelectionTag::Consolidation,
+                CoinSelectionTag::SelfTransfer
+            ]
+        );
+        assert_eq!(
+            h.tags(&[(100, &a[..])], &vec![(10, &b[..]); 5]),
+            vec![CoinSelectionTag::BatchPayout]
+        );
+        assert_eq!(
+            h.tags(&[(100, &a[..])], &[(5, &b[..]), (94, &a[..])]),
+            vec![CoinSelectionTag::PeelingChain]
+        );
+        assert!(h
+            .tags(&[(100, &a[..])], &[(40, &b[..]), (59, &a[..])])
+            .is_empty());
+        assert!(h.tags(&[(100, &a[..])], &[(99, &[][..])]).is_empty());
+    }
+}
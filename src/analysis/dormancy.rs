@@ -0,0 +1,203 @@
+//!
+//! Spending of long dormant outputs.
+//!
+use crate::api::{BitcoinDB, ConnectedTx};
+use crate::parser::blk_file::BlkFile;
+use crate::parser::block_index::BlockIndex;
+use crate::parser::errors::OpResult;
+use crate::parser::proto::connected_proto::{connect_tx_at, SummaryBlock};
+use crate::parser::tx_index::TxDB;
+use bitcoin::{OutPoint, Transaction, TxIn, TxOut, Txid};
+use std::ops::Range;
+use std::time::Duration;
+
+///
+/// An output spent at least `min_age` after it was created.
+///
+/// Ages are measured with block header timestamps.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DormancyEvent {
+    /// height and time of the spending block
+    pub height: usize,
+    pub time: u32,
+    /// spending transaction and input index
+    pub txid: Txid,
+    pub vin: usize,
+    pub outpoint: OutPoint,
+    /// value of the spent output (satoshi)
+    pub value: u64,
+    /// height and time of the block that created the output
+    pub created_height: usize,
+    pub created_time: u32,
+}
+
+impl DormancyEvent {
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(self.time.saturating_sub(self.created_time) as u64)
+    }
+
+    pub fn age_blocks(&self) -> usize {
+        self.height - self.created_height
+    }
+}
+
+///
+/// Iterate through the spending of outputs older than `min_age`,
+/// in blocks of `range`.
+///
+/// Uses connected block iteration from the genesis block
+/// (the UTXO cache records the creation height of every output),
+/// so blocks before `range.start` are processed without emitting events.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::iter_dormancy_events;
+/// use bitcoin_explorer::BitcoinDB;
+/// use std::path::Path;
+/// use std::time::Duration;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let five_years = Duration::from_secs(5 * 365 * 24 * 3600);
+/// for e in iter_dormancy_events(&db, 600000..700000, five_years) {
+///     println!(
+///         "{}: {} sat created at height {} ({} days old)",
+///         e.txid, e.value, e.created_height, e.age().as_secs() / 86400
+///     );
+/// }
+/// ```
+///
+pub fn iter_dormancy_events(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    min_age: Duration,
+) -> impl Iterator<Item = DormancyEvent> {
+    let db_copy = db.clone();
+    let start = range.start;
+    db.iter_connected_block::<DormancyBlock>(range.end)
+        .enumerate()
+        .skip(start)
+        .flat_map(move |(height, block)| {
+            let time = block.time;
+            let mut events = Vec::new();
+            for tx in block.txdata {
+                for (vin, input) in tx.input.into_iter().enumerate() {
+                    let created_time = match db_copy.get_header(input.created_height) {
+                        Ok(record) => record.block_header.time,
+                        Err(_) => continue,
+                    };
+                    if (time.saturating_sub(created_time) as u64) < min_age.as_secs() {
+                        continue;
+                    }
+                    events.push(DormancyEvent {
+                        height,
+                        time,
+                        txid: tx.txid,
+                        vin,
+                        outpoint: input.outpoint,
+                        value: input.value,
+                        created_height: input.created_height,
+                        created_time,
+                    });
+                }
+            }
+            events
+        })
+}
+
+///
+/// Spent outputs with their creation heights.
+///
+type DormancyBlock = SummaryBlock<DormancyTx>;
+
+struct DormancyTx {
+    txid: Txid,
+    input: Vec<DormancyInput>,
+}
+
+struct DormancyInput {
+    outpoint: OutPoint,
+    value: u64,
+    created_height: usize,
+}
+
+struct DormancyTxOut(u64);
+
+impl From<TxOut> for DormancyTxOut {
+    fn from(o: TxOut) -> Self {
+        DormancyTxOut(o.value)
+    }
+}
+
+impl ConnectedTx for DormancyTx {
+    type TOut = DormancyTxOut;
+
+    fn from(tx: &Transaction) -> Self {
+        DormancyTx {
+            txid: tx.txid(),
+            input: Vec::new(),
+        }
+    }
+
+    fn add_input(&mut self, _input: Self::TOut) {
+        // creation height unknown, cannot be an event
+    }
+
+    fn add_input_at(&mut self, input: Self::TOut, tx_in: &TxIn, created_height: usize) {
+        self.input.push(DormancyInput {
+            outpoint: tx_in.previous_output,
+            value: input.0,
+            created_height,
+        });
+    }
+
+    fn connect(
+        tx: Transaction,
+        tx_db: &TxDB,
+        blk_index: &BlockIndex,
+        blk_file: &BlkFile,
+    ) -> OpResult<Self> {
+        connect_tx_at(tx, tx_db, blk_index, blk_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+
+    #[test]
+    fn test_dormancy_events() {
+        // block 3 spends the coinbases of blocks 1 (1200s old) and 2 (600s old)
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let outpoints: Vec<OutPoint> = tips
+            .iter()
+            .map(|h| chain.coinbase_outpoint(h).unwrap())
+            .collect();
+        let mut spend = SyntheticChain::spend(outpoints[0], 1000);
+        spend.input.push(TxIn {
+            previous_output: outpoints[1],
+            ..Default::default()
+        });
+        let txid = spend.txid();
+        chain.mine(&tips[1], vec![spend]);
+        let db = TestDb::new("dormancy", &chain);
+
+        let events: Vec<_> = iter_dormancy_events(&db, 0..4, Duration::from_secs(1000)).collect();
+        assert_eq!(events.len(), 1);
+        let e = &events[0];
+        assert_eq!((e.height, e.txid, e.vin), (3, txid, 0));
+        assert_eq!(e.outpoint, outpoints[0]);
+        assert_eq!(e.value, 50 * 100_000_000);
+        assert_eq!(e.created_height, 1);
+        assert_eq!(e.age(), Duration::from_secs(1200));
+        assert_eq!(e.age_blocks(), 2);
+
+        assert_eq!(iter_dormancy_events(&db, 0..4, Duration::ZERO).count(), 2);
+        assert_eq!(iter_dormancy_events(&db, 0..3, Duration::ZERO).count(), 0);
+    }
+}
@@ -0,0 +1,273 @@
+//!
+//! Backtesting of fee estimation strategies against historical blocks.
+//!
+use crate::analysis::fullness::FullnessBlock;
+use crate::api::BitcoinDB;
+use crate::parser::errors::{OpError, OpResult};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::ops::Range;
+
+/// default minimum relay feerate of Bitcoin Core (sat/vB)
+pub const MIN_RELAY_FEERATE: f64 = 1.0;
+/// blocks below this fullness had room for any transaction paying the relay fee
+const NOT_FULL_THRESHOLD: f64 = 0.95;
+
+///
+/// How a wallet picks the feerate of a transaction.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeeStrategy {
+    /// always pay this feerate (sat/vB)
+    Fixed(f64),
+    ///
+    /// pay the `percentile` (0 to 100) of the feerates of transactions
+    /// included in the last `lookback` blocks
+    /// (`MIN_RELAY_FEERATE` without history)
+    ///
+    Percentile { percentile: f64, lookback: usize },
+}
+
+impl FeeStrategy {
+    fn feerate(&self, history: &VecDeque<Vec<f64>>) -> f64 {
+        match *self {
+            FeeStrategy::Fixed(feerate) => feerate,
+            FeeStrategy::Percentile {
+                percentile,
+                lookback,
+            } => {
+                let skip = history.len().saturating_sub(lookback);
+                let mut feerates: Vec<f64> = history.iter().skip(skip).flatten().copied().collect();
+                feerates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                percentile_of(&feerates, percentile).unwrap_or(MIN_RELAY_FEERATE)
+            }
+        }
+    }
+
+    fn lookback(&self) -> usize {
+        match *self {
+            FeeStrategy::Fixed(_) => 0,
+            FeeStrategy::Percentile { lookback, .. } => lookback,
+        }
+    }
+}
+
+///
+/// A simulated transaction, broadcast right before block `height`.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktestOutcome {
+    /// first block that could include the transaction
+    pub height: usize,
+    /// feerate chosen by the strategy (sat/vB)
+    pub feerate: f64,
+    /// block that would have included it, `None` if not before the end of the range
+    pub confirmed_height: Option<usize>,
+}
+
+impl BacktestOutcome {
+    /// number of blocks until confirmation (1 for the next block)
+    pub fn delay(&self) -> Option<usize> {
+        self.confirmed_height.map(|h| h + 1 - self.height)
+    }
+}
+
+///
+/// Result of `fee_backtest`, one outcome per block of the range.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeBacktest {
+    pub strategy: FeeStrategy,
+    pub outcomes: Vec<BacktestOutcome>,
+}
+
+impl FeeBacktest {
+    /// share of transactions confirmed within `blocks` blocks
+    pub fn confirmed_within(&self, blocks: usize) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        let n = self
+            .outcomes
+            .iter()
+            .filter(|o| o.delay().map_or(false, |d| d <= blocks))
+            .count();
+        n as f64 / self.outcomes.len() as f64
+    }
+
+    /// mean number of blocks until confirmation, of confirmed transactions
+    pub fn mean_delay(&self) -> Option<f64> {
+        let delays: Vec<usize> = self.outcomes.iter().filter_map(|o| o.delay()).collect();
+        if delays.is_empty() {
+            None
+        } else {
+            Some(delays.iter().sum::<usize>() as f64 / delays.len() as f64)
+        }
+    }
+
+    /// mean feerate paid (sat/vB)
+    pub fn mean_feerate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        self.outcomes.iter().map(|o| o.feerate).sum::<f64>() / self.outcomes.len() as f64
+    }
+
+    /// number of transactions still unconfirmed at the end of the range
+    pub fn unconfirmed(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|o| o.confirmed_height.is_none())
+            .count()
+    }
+}
+
+///
+/// Replay blocks of `range`, broadcasting one simulated transaction
+/// before each block with the feerate of `strategy`.
+///
+/// A transaction confirms in the first block whose lowest included
+/// feerate it matches, or in a block that was not full
+/// (below 95% of the weight limit) if it pays `MIN_RELAY_FEERATE`.
+///
+/// Fees require input values, so this uses connected block iteration
+/// from the genesis block (no txindex required).
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::{fee_backtest, FeeStrategy};
+/// use bitcoin_explorer::BitcoinDB;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// for strategy in [
+///     FeeStrategy::Fixed(5.0),
+///     FeeStrategy::Percentile { percentile: 25.0, lookback: 6 },
+/// ] {
+///     let result = fee_backtest(&db, 700000..701000, strategy).unwrap();
+///     println!(
+///         "{:?}: {:.1}% within 3 blocks, mean {:.1} sat/vB",
+///         strategy,
+///         result.confirmed_within(3) * 100.0,
+///         result.mean_feerate()
+///     );
+/// }
+/// ```
+///
+pub fn fee_backtest(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    strategy: FeeStrategy,
+) -> OpResult<FeeBacktest> {
+    if range.end > db.get_block_count() {
+        return Err(OpError::from("height not found"));
+    }
+    let history_len = strategy.lookback();
+    let mut history: VecDeque<Vec<f64>> = VecDeque::with_capacity(history_len + 1);
+    let mut outcomes = Vec::with_capacity(range.len());
+    // pending transactions by feerate (milli sat/vB), oldest first
+    let mut pending: BinaryHeap<(u64, Reverse<usize>)> = BinaryHeap::new();
+    let mut processed = 0;
+    for (height, block) in db
+        .iter_connected_block::<FullnessBlock>(range.end)
+        .enumerate()
+    {
+        processed += 1;
+        if height >= range.start {
+            let feerate = strategy.feerate(&history);
+            pending.push((to_milli(feerate), Reverse(outcomes.len())));
+            outcomes.push(BacktestOutcome {
+                height,
+                feerate,
+                confirmed_height: None,
+            });
+            let fullness = block.fullness(height, NOT_FULL_THRESHOLD);
+            let mut threshold = fullness.min_feerate.unwrap_or(f64::INFINITY);
+            if fullness.not_full {
+                threshold = threshold.min(MIN_RELAY_FEERATE);
+            }
+            while let Some((milli, Reverse(i))) = pending.peek().copied() {
+                if (milli as f64) < threshold * 1000.0 {
+                    break;
+                }
+                pending.pop();
+                outcomes[i].confirmed_height = Some(height);
+            }
+        }
+        if history_len > 0 && height + history_len >= range.start {
+            history.push_back(block.feerates().collect());
+            if history.len() > history_len {
+                history.pop_front();
+            }
+        }
+    }
+    if processed != range.end {
+        return Err(OpError::from("failed to read some blocks in range"));
+    }
+    Ok(FeeBacktest { strategy, outcomes })
+}
+
+fn to_milli(feerate: f64) -> u64 {
+    (feerate.max(0.0) * 1000.0).round() as u64
+}
+
+/// nearest-rank percentile of sorted values
+fn percentile_of(sorted: &[f64], percentile: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64).round();
+    Some(sorted[rank as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+
+    #[test]
+    fn test_percentile_of() {
+        assert_eq!(percentile_of(&[], 50.0), None);
+        assert_eq!(percentile_of(&[1.0, 2.0, 3.0, 4.0, 5.0], 50.0), Some(3.0));
+        assert_eq!(percentile_of(&[1.0, 2.0, 3.0, 4.0, 5.0], 0.0), Some(1.0));
+        assert_eq!(percentile_of(&[1.0, 2.0, 3.0, 4.0, 5.0], 100.0), Some(5.0));
+    }
+
+    #[test]
+    fn test_fee_backtest() {
+        // block 2 includes a transaction paying 10000 sat
+        let mut chain = SyntheticChain::new();
+        let tip = chain.extend(&chain.genesis(), 1).pop().unwrap();
+        let spend = SyntheticChain::spend(
+            chain.coinbase_outpoint(&tip).unwrap(),
+            50 * 100_000_000 - 10_000,
+        );
+        let feerate = 10_000.0 / ((spend.weight() + 3) / 4) as f64;
+        let tip = chain.mine(&tip, vec![spend]);
+        chain.extend(&tip, 2);
+        let db = TestDb::new("fee_backtest", &chain);
+
+        // blocks are far from full: anything paying the relay fee confirms at once
+        let result = fee_backtest(&db, 1..5, FeeStrategy::Fixed(2.0)).unwrap();
+        assert_eq!(result.outcomes.len(), 4);
+        assert!(result.outcomes.iter().all(|o| o.delay() == Some(1)));
+        assert_eq!(result.confirmed_within(1), 1.0);
+
+        let result = fee_backtest(&db, 1..5, FeeStrategy::Fixed(0.5)).unwrap();
+        assert_eq!(result.unconfirmed(), 4);
+        assert_eq!(result.mean_delay(), None);
+
+        let strategy = FeeStrategy::Percentile {
+            percentile: 50.0,
+            lookback: 2,
+        };
+        let result = fee_backtest(&db, 2..5, strategy).unwrap();
+        let feerates: Vec<f64> = result.outcomes.iter().map(|o| o.feerate).collect();
+        assert_eq!(feerates, vec![MIN_RELAY_FEERATE, feerate, feerate]);
+
+        assert!(fee_backtest(&db, 1..6, strategy).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//!
+//! Block fullness and minimum included feerates.
+//!
+use crate::api::{BitcoinDB, ConnectedTx};
+use crate::parser::blk_file::BlkFile;
+use crate::parser::block_index::BlockIndex;
+use crate::parser::errors::OpResult;
+use crate::parser::proto::connected_proto::{connect_tx, SummaryBlock};
+use crate::parser::tx_index::TxDB;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::{Transaction, TxOut};
+use std::ops::Range;
+
+/// consensus limit of block weight (BIP141)
+pub const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+/// serialized size of a block header
+const HEADER_SIZE: u64 = 80;
+
+///
+/// How full a block is, and the cheapest transaction it includes.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockFullness {
+    pub height: usize,
+    pub time: u32,
+    /// witness-aware block weight (weight units)
+    pub weight: u64,
+    /// `weight / MAX_BLOCK_WEIGHT`
+    pub fullness: f64,
+    /// number of transactions, including coinbase
+    pub n_tx: usize,
+    ///
+    /// lowest feerate of non-coinbase transactions (sat/vB),
+    /// `None` for blocks with only a coinbase
+    ///
+    pub min_feerate: Option<f64>,
+    /// `fullness` below the threshold of `iter_block_fullness`
+    pub not_full: bool,
+}
+
+///
+/// Iterate through the fullness of blocks in `range`.
+///
+/// Blocks with `fullness < full_threshold` (e.g. `0.95`) are flagged
+/// `not_full`: miners left space although (from the min feerate)
+/// they may have had transactions to include.
+///
+/// Fees require input values, so this uses connected block iteration
+/// from the genesis block (no txindex required), and blocks
+/// before `range.start` are processed without being produced.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::{iter_block_fullness, FullnessHistogram};
+/// use bitcoin_explorer::BitcoinDB;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let mut histogram = FullnessHistogram::new(20);
+/// for b in iter_block_fullness(&db, 700000..710000, 0.95) {
+///     histogram.add(&b);
+///     if b.not_full {
+///         println!("{}: {:.1}% full, min feerate {:?}", b.height, b.fullness * 100.0, b.min_feerate);
+///     }
+/// }
+/// println!("{:?}", histogram.counts());
+/// ```
+///
+pub fn iter_block_fullness(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    full_threshold: f64,
+) -> impl Iterator<Item = BlockFullness> {
+    let start = range.start;
+    db.iter_connected_block::<FullnessBlock>(range.end)
+        .enumerate()
+        .skip(start)
+        .map(move |(height, block)| block.fullness(height, full_threshold))
+}
+
+///
+/// Histogram of block fullness, in equal-width bins over `[0, 1]`
+/// (blocks at full weight are counted in the last bin).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FullnessHistogram {
+    counts: Vec<u64>,
+}
+
+impl FullnessHistogram {
+    ///
+    /// An empty histogram of `bins` bins (at least one).
+    ///
+    pub fn new(bins: usize) -> Self {
+        FullnessHistogram {
+            counts: vec![0; bins.max(1)],
+        }
+    }
+
+    pub fn add(&mut self, block: &BlockFullness) {
+        let bins = self.counts.len();
+        let bin = ((block.fullness * bins as f64) as usize).min(bins - 1);
+        self.counts[bin] += 1;
+    }
+
+    /// number of blocks in each bin
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// lower bound of the fullness of each bin
+    pub fn bin_starts(&self) -> Vec<f64> {
+        let bins = self.counts.len();
+        (0..bins).map(|i| i as f64 / bins as f64).collect()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+///
+/// Weight and fee of each transaction.
+///
+pub(crate) type FullnessBlock = SummaryBlock<FullnessTx>;
+
+pub(crate) struct FullnessTx {
+    is_coinbase: bool,
+    weight: u64,
+    input_value: u64,
+    output_value: u64,
+}
+
+impl FullnessTx {
+    /// fee per virtual byte
+    fn feerate(&self) -> f64 {
+        let vsize = (self.weight + 3) / 4;
+        self.input_value.saturating_sub(self.output_value) as f64 / vsize as f64
+    }
+}
+
+impl FullnessBlock {
+    /// feerates of non-coinbase transactions (sat/vB)
+    pub(crate) fn feerates(&self) -> impl Iterator<Item = f64> + '_ {
+        self.txdata
+            .iter()
+            .filter(|tx| !tx.is_coinbase)
+            .map(|tx| tx.feerate())
+    }
+
+    pub(crate) fn fullness(&self, height: usize, full_threshold: f64) -> BlockFullness {
+        let n_tx = self.txdata.len();
+        let weight = 4 * (HEADER_SIZE + VarInt(n_tx as u64).len() as u64)
+            + self.txdata.iter().map(|tx| tx.weight).sum::<u64>();
+        let fullness = weight as f64 / MAX_BLOCK_WEIGHT as f64;
+        let min_feerate = self.feerates().fold(None, |min: Option<f64>, r| {
+            Some(min.map_or(r, |m| m.min(r)))
+        });
+        BlockFullness {
+            height,
+            time: self.time,
+            weight,
+            fullness,
+            n_tx,
+            min_feerate,
+            not_full: fullness < full_threshold,
+        }
+    }
+}
+
+pub(crate) struct FullnessTxOut(u64);
+
+impl From<TxOut> for FullnessTxOut {
+    fn from(o: TxOut) -> Self {
+        FullnessTxOut(o.value)
+    }
+}
+
+impl ConnectedTx for FullnessTx {
+    type TOut = FullnessTxOut;
+
+    fn from(tx: &Transaction) -> Self {
+        FullnessTx {
+            is_coinbase: tx.is_coin_base(),
+            weight: tx.weight() as u64,
+            input_value: 0,
+            output_value: tx
+                .output
+                .iter()
+                .fold(0u64, |acc, o| acc.saturating_add(o.value)),
+        }
+    }
+
+    fn add_input(&mut self, input: Self::TOut) {
+        self.input_value = self.input_value.saturating_add(input.0);
+    }
+
+    fn connect(
+        tx: Transaction,
+        tx_db: &TxDB,
+        blk_index: &BlockIndex,
+        blk_file: &BlkFile,
+    ) -> OpResult<Self> {
+        connect_tx(tx, tx_db, blk_index, blk_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+
+    #[test]
+    fn test_block_fullness() {
+        let mut chain = SyntheticChain::new();
+        let tip = chain.extend(&chain.genesis(), 1).pop().unwrap();
+        let spend = SyntheticChain::spend(
+            chain.coinbase_outpoint(&tip).unwrap(),
+            50 * 100_000_000 - 10_000,
+        );
+        let vsize = (spend.weight() as f64 / 4.0).ceil();
+        let tip = chain.mine(&tip, vec![spend]);
+        let db = TestDb::new("fullness", &chain);
+
+        let blocks: Vec<_> = iter_block_fullness(&db, 1..3, 0.95).collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!((blocks[0].height, blocks[0].min_feerate), (1, None));
+        let b = &blocks[1];
+        let block = chain.block(&tip).unwrap();
+        assert_eq!(b.weight, block.weight() as u64);
+        assert_eq!(b.n_tx, 2);
+        assert_eq!(b.min_feerate, Some(10_000.0 / vsize));
+        assert!(b.not_full);
+
+        let mut histogram = FullnessHistogram::new(10);
+        blocks.iter().for_each(|b| histogram.add(b));
+        assert_eq!(histogram.counts()[0], 2);
+        assert_eq!(histogram.total(), 2);
+    }
+}
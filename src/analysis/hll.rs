@@ -0,0 +1,188 @@
+use crate::parser::errors::{OpError, OpResult};
+
+/// default precision: 4096 registers, about 1.6% standard error
+pub const DEFAULT_HLL_PRECISION: u8 = 12;
+
+///
+/// HyperLogLog cardinality estimator.
+///
+/// Estimates the number of distinct items inserted with a few KB of memory.
+/// Sketches with the same precision can be merged, so that
+/// distinct counts of longer periods can be computed from shorter ones.
+///
+/// Hashing is deterministic and platform independent,
+/// so sketches can be saved (`to_bytes`) and merged across machines.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::HyperLogLog;
+///
+/// let mut hll = HyperLogLog::new(12);
+/// for i in 0..100000u32 {
+///     hll.insert(&i.to_le_bytes());
+/// }
+/// let estimate = hll.estimate();
+/// assert!((estimate as f64 - 100000.0).abs() < 5000.0);
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    ///
+    /// Create an empty sketch with `2^precision` registers.
+    ///
+    /// `precision` is clamped to `4..=18`.
+    ///
+    pub fn new(precision: u8) -> Self {
+        let precision = precision.clamp(4, 18);
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    #[inline]
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    ///
+    /// Add an item.
+    ///
+    pub fn insert(&mut self, item: &[u8]) {
+        let hash = hash64(item);
+        let index = (hash >> (64 - self.precision)) as usize;
+        // position of the first 1 bit in the remaining bits
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() as u8).min(64 - self.precision) + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    ///
+    /// Merge another sketch into this one.
+    ///
+    pub fn merge(&mut self, other: &HyperLogLog) -> OpResult<()> {
+        if self.precision != other.precision {
+            return Err(OpError::from(
+                "cannot merge HyperLogLog of different precisions",
+            ));
+        }
+        for (r, o) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *o > *r {
+                *r = *o;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Estimated number of distinct items.
+    ///
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|r| 1.0 / (1u64 << *r) as f64)
+            .sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // small range correction: linear counting
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+
+    ///
+    /// Serialize: 1 byte precision followed by the registers.
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.registers.len() + 1);
+        bytes.push(self.precision);
+        bytes.extend_from_slice(&self.registers);
+        bytes
+    }
+
+    ///
+    /// Deserialize a sketch written by `to_bytes`.
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> OpResult<Self> {
+        match bytes.split_first() {
+            Some((precision, registers))
+                if (4..=18).contains(precision) && registers.len() == 1 << *precision =>
+            {
+                Ok(HyperLogLog {
+                    precision: *precision,
+                    registers: registers.to_vec(),
+                })
+            }
+            _ => Err(OpError::from("invalid HyperLogLog bytes")),
+        }
+    }
+}
+
+///
+/// 64 bits FNV-1a, followed by SplitMix64 finalizer for better bit mixing.
+///
+#[inline]
+pub(crate) fn hash64(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D049BB133111EB);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hll_estimate_and_merge() {
+        let mut a = HyperLogLog::new(DEFAULT_HLL_PRECISION);
+        let mut b = HyperLogLog::new(DEFAULT_HLL_PRECISION);
+        assert_eq!(a.estimate(), 0);
+        for i in 0..50000u32 {
+            a.insert(&i.to_le_bytes());
+            // duplicates are not counted twice
+            a.insert(&i.to_le_bytes());
+        }
+        for i in 25000..100000u32 {
+            b.insert(&i.to_le_bytes());
+        }
+        let est_a = a.estimate() as f64;
+        assert!((est_a - 50000.0).abs() / 50000.0 < 0.05);
+        a.merge(&b).unwrap();
+        let est = a.estimate() as f64;
+        assert!((est - 100000.0).abs() / 100000.0 < 0.05);
+        assert_eq!(HyperLogLog::from_bytes(&a.to_bytes()).unwrap(), a);
+        assert!(a.merge(&HyperLogLog::new(10)).is_err());
+    }
+
+    #[test]
+    fn test_hll_small_cardinality() {
+        let mut hll = HyperLogLog::new(DEFAULT_HLL_PRECISION);
+        for i in 0..10u8 {
+            hll.insert(&[i]);
+        }
+        assert_eq!(hll.estimate(), 10);
+    }
+}
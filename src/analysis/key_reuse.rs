@@ -0,0 +1,194 @@
+//!
+//! Detect addresses receiving funds after their public key has been exposed.
+//!
+use crate::api::BitcoinDB;
+use crate::parser::script::{extract_input_pubkeys, extract_pubkeys};
+use bitcoin::hashes::{hash160, Hash};
+use bitcoin::{Address, Block, Network, Script, Txid};
+use std::collections::HashMap;
+use std::ops::Range;
+
+///
+/// An output paying to a public key that was already exposed on chain.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyReuse {
+    /// height of the block containing the reusing output
+    pub height: usize,
+    pub txid: Txid,
+    pub vout: u32,
+    /// address of the output, on the network of the datadir
+    pub address: Option<Address>,
+    /// height at which the public key was first exposed
+    pub exposed_height: usize,
+}
+
+///
+/// Tracks exposed public keys (by hash160) and their first exposure height.
+///
+#[derive(Debug)]
+pub(crate) struct KeyReuseTracker {
+    exposed: HashMap<[u8; 20], usize>,
+    /// network of the addresses of reusing outputs
+    network: Network,
+}
+
+impl KeyReuseTracker {
+    pub(crate) fn new(network: Network) -> Self {
+        KeyReuseTracker {
+            exposed: HashMap::new(),
+            network,
+        }
+    }
+
+    ///
+    /// Report reusing outputs of a block and record exposed keys.
+    ///
+    /// Within a transaction, inputs are processed before outputs,
+    /// so a change output paying back to a spent key counts as reuse.
+    ///
+    pub(crate) fn process_block(&mut self, height: usize, block: &Block) -> Vec<KeyReuse> {
+        let mut events = Vec::new();
+        for tx in &block.txdata {
+            for tx_in in &tx.input {
+                for pk in extract_input_pubkeys(tx_in) {
+                    self.expose(&pk.to_bytes(), height);
+                }
+            }
+            let txid = tx.txid();
+            for (vout, o) in tx.output.iter().enumerate() {
+                if let Some(hash) = key_hash(&o.script_pubkey) {
+                    if let Some(exposed_height) = self.exposed.get(&hash) {
+                        events.push(KeyReuse {
+                            height,
+                            txid,
+                            vout: vout as u32,
+                            address: Address::from_script(&o.script_pubkey, self.network),
+                            exposed_height: *exposed_height,
+                        });
+                    }
+                }
+                for pk in extract_pubkeys(&o.script_pubkey) {
+                    self.expose(&pk.to_bytes(), height);
+                }
+            }
+        }
+        events
+    }
+
+    fn expose(&mut self, pubkey: &[u8], height: usize) {
+        let hash = hash160::Hash::hash(pubkey).into_inner();
+        self.exposed.entry(hash).or_insert(height);
+    }
+}
+
+///
+/// hash160 of the key an output pays to (P2PKH, P2WPKH, P2PK).
+///
+fn key_hash(script: &Script) -> Option<[u8; 20]> {
+    let bytes = script.as_bytes();
+    let mut hash = [0u8; 20];
+    if script.is_p2pkh() {
+        hash.copy_from_slice(&bytes[3..23]);
+    } else if script.is_v0_p2wpkh() {
+        hash.copy_from_slice(&bytes[2..22]);
+    } else if script.is_p2pk() {
+        let pk = extract_pubkeys(script).pop()?;
+        hash = hash160::Hash::hash(&pk.to_bytes()).into_inner();
+    } else {
+        return None;
+    }
+    Some(hash)
+}
+
+///
+/// Iterate over outputs in blocks of `range` that pay to a public key
+/// exposed earlier (by a spending input or a P2PK / bare multisig output).
+///
+/// Keys exposed before `range.start` are not known,
+/// start from `0` to obtain complete results.
+/// The set of exposed keys is kept in memory.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use bitcoin_explorer::analysis::key_reuse;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// for reuse in key_reuse(&db, 0..200000) {
+///     println!("{}:{} exposed at {}", reuse.txid, reuse.vout, reuse.exposed_height);
+/// }
+/// ```
+///
+pub fn key_reuse(db: &BitcoinDB, range: Range<usize>) -> impl Iterator<Item = KeyReuse> {
+    let start = range.start;
+    let mut tracker = KeyReuseTracker::new(db.network());
+    db.iter_block::<Block>(range.start, range.end)
+        .enumerate()
+        .flat_map(move |(i, block)| tracker.process_block(start + i, &block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyReuseTracker;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::{Block, Network, OutPoint, PublicKey, Script, Transaction, TxIn, TxOut};
+
+    fn tx(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input,
+            output,
+        }
+    }
+
+    fn block(txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: genesis_block(Network::Bitcoin).header,
+            txdata,
+        }
+    }
+
+    #[test]
+    fn test_key_reuse() {
+        let pk = Vec::<u8>::from_hex(
+            "022df8750480ad5b26950b25c7ba79d3e37d75f640f8e5d9bcd5b150a0f85014da",
+        )
+        .unwrap();
+        let p2pkh = Script::new_p2pkh(&PublicKey::from_slice(&pk).unwrap().pubkey_hash());
+        let pay = TxOut {
+            value: 1000,
+            script_pubkey: p2pkh,
+        };
+        let receive = tx(vec![TxIn::default()], vec![pay.clone()]);
+        let spend = tx(
+            vec![TxIn {
+                previous_output: OutPoint::new(receive.txid(), 0),
+                script_sig: Builder::new()
+                    .push_slice(&[0x30; 71])
+                    .push_slice(&pk)
+                    .into_script(),
+                ..Default::default()
+            }],
+            Vec::new(),
+        );
+        let mut tracker = KeyReuseTracker::new(Network::Testnet);
+        // receiving to an unexposed key is not reuse
+        assert!(tracker.process_block(1, &block(vec![receive])).is_empty());
+        assert!(tracker.process_block(2, &block(vec![spend])).is_empty());
+        let reuse = tx(vec![TxIn::default()], vec![pay]);
+        let events = tracker.process_block(3, &block(vec![reuse]));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].height, 3);
+        assert_eq!(events[0].exposed_height, 2);
+        let address = events[0].address.as_ref().unwrap();
+        assert_eq!(address.network, Network::Testnet);
+    }
+}
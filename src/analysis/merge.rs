@@ -0,0 +1,114 @@
+//!
+//! Deterministic merging of results computed on shards of the chain
+//! (see `BitcoinDB::partition_heights`).
+//!
+use crate::analysis::hll::HyperLogLog;
+use crate::parser::errors::{OpError, OpResult};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+///
+/// A result that can be computed per height range and merged.
+///
+/// `merge_shard` is always called with the result of the range
+/// right after the ranges already merged into `self`, so that merging
+/// the shards of a chain gives the same result (and the same bytes when
+/// written out) as a single run over the whole chain.
+///
+pub trait ShardMerge: Sized {
+    fn merge_shard(&mut self, next: Self) -> OpResult<()>;
+}
+
+///
+/// Merge shard results, given with the height range each was computed on.
+///
+/// Shards can be given in any order (e.g. as workers finish), they are
+/// merged by increasing height. Fails if the ranges are not contiguous.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::{aggregate_by_time_partial, merge_shards, Aggregator, AggregatorSpec};
+/// use bitcoin_explorer::{BitcoinDB, PartitionStrategy};
+/// use std::path::Path;
+/// use std::time::Duration;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+/// let spec = AggregatorSpec::new().with(Aggregator::DistinctOutputScripts);
+/// let day = Duration::from_secs(86400);
+///
+/// // each shard would run on a different machine
+/// let shards = db.partition_heights(4, PartitionStrategy::Transactions).unwrap();
+/// let results = shards
+///     .into_iter()
+///     .map(|r| (r.clone(), aggregate_by_time_partial(&db, r, day, &spec).unwrap()))
+///     .collect();
+/// let (range, merged) = merge_shards(results).unwrap();
+/// merged.finish().write_csv(std::io::stdout()).unwrap();
+/// ```
+///
+pub fn merge_shards<T: ShardMerge>(
+    mut shards: Vec<(Range<usize>, T)>,
+) -> OpResult<(Range<usize>, T)> {
+    shards.sort_by_key(|(r, _)| (r.start, r.end));
+    let mut shards = shards.into_iter();
+    let (mut range, mut merged) = match shards.next() {
+        Some(first) => first,
+        None => return Err(OpError::from("no shard to merge")),
+    };
+    for (r, result) in shards {
+        if r.start != range.end {
+            return Err(OpError::from(
+                format!("shards are not contiguous: {:?} after {:?}", r, range).as_str(),
+            ));
+        }
+        merged.merge_shard(result)?;
+        range.end = r.end;
+    }
+    Ok((range, merged))
+}
+
+impl ShardMerge for HyperLogLog {
+    fn merge_shard(&mut self, next: Self) -> OpResult<()> {
+        self.merge(&next)
+    }
+}
+
+///
+/// Postings indexes (e.g. address to heights or txids):
+/// postings of later shards are appended, keeping the order
+/// of a single run.
+///
+impl<K: Ord, V> ShardMerge for BTreeMap<K, Vec<V>> {
+    fn merge_shard(&mut self, next: Self) -> OpResult<()> {
+        for (key, mut postings) in next {
+            self.entry(key).or_default().append(&mut postings);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_shards() {
+        let mut a = BTreeMap::new();
+        a.insert("x", vec![1, 2]);
+        let mut b = BTreeMap::new();
+        b.insert("x", vec![5]);
+        b.insert("y", vec![6]);
+        let mut c = BTreeMap::new();
+        c.insert("y", vec![9]);
+
+        let (range, merged) =
+            merge_shards(vec![(5..9, b.clone()), (9..12, c), (0..5, a.clone())]).unwrap();
+        assert_eq!(range, 0..12);
+        assert_eq!(merged["x"], vec![1, 2, 5]);
+        assert_eq!(merged["y"], vec![6, 9]);
+
+        assert!(merge_shards(vec![(0..5, a), (6..9, b)]).is_err());
+        assert!(merge_shards::<BTreeMap<&str, Vec<i32>>>(vec![]).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//!
+//! Built-in analyses computed over ranges of blocks.
+//!
+
+/// aggregate block statistics into time buckets
+pub mod aggregate;
+
+/// oddities of block data (timestamps, duplicate txids, overflows)
+pub mod anomalies;
+
+/// block weight by transaction category
+pub mod blockspace;
+
+/// coin selection patterns of connected transactions
+#[cfg(feature = "analysis")]
+pub mod coin_selection;
+
+/// spends of long dormant outputs
+pub mod dormancy;
+
+/// fee strategy backtesting
+pub mod fee_backtest;
+
+/// block fullness and minimum included feerates
+pub mod fullness;
+
+/// streaming distinct count estimation
+pub mod hll;
+
+/// deterministic merging of sharded results
+pub mod merge;
+
+/// public key exposure and reuse
+pub mod key_reuse;
+
+/// OpenTimestamps commitments and their merkle paths
+pub mod opentimestamps;
+
+/// fiat exchange rates
+pub mod price;
+
+/// realized cap and SOPR
+pub mod realized;
+
+/// output script templates and their frequencies
+pub mod templates;
+
+pub use aggregate::{
+    aggregate_by_time, aggregate_by_time_partial, Aggregator, AggregatorSpec, PartialTimeTable,
+    TimeRow, TimeTable,
+};
+pub use anomalies::{Anomaly, AnomalyKind, AnomalyLimits};
+pub use blockspace::{categorize, iter_blockspace_usage, BlockspaceUsage, TxCategory};
+#[cfg(feature = "analysis")]
+pub use coin_selection::{CoinSelectionHeuristics, CoinSelectionTag};
+pub use dormancy::{iter_dormancy_events, DormancyEvent};
+pub use fee_backtest::{
+    fee_backtest, BacktestOutcome, FeeBacktest, FeeStrategy, MIN_RELAY_FEERATE,
+};
+pub use fullness::{iter_block_fullness, BlockFullness, FullnessHistogram, MAX_BLOCK_WEIGHT};
+pub use hll::HyperLogLog;
+pub use key_reuse::{key_reuse, KeyReuse};
+pub use merge::{merge_shards, ShardMerge};
+pub use opentimestamps::{OtsAttestation, OtsOp, OtsPath};
+pub use price::{to_fiat, PriceKey, PriceTable};
+pub use realized::{iter_realized_metrics, RealizedMetrics};
+pub use templates::{script_template, script_templates, TemplateCounts, TemplateStats};
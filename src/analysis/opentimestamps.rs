@@ -0,0 +1,337 @@
+//!
+//! OpenTimestamps attestations: calendar commitments found in OP_RETURN
+//! outputs, with their commitment path to the block merkle root.
+//!
+use crate::api::{BitcoinDB, Txid};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::{ripemd160, sha256, sha256d, Hash};
+use bitcoin::{Block, Transaction, Witness};
+use std::ops::Range;
+
+const TAG_SHA256: u8 = 0x08;
+const TAG_RIPEMD160: u8 = 0x03;
+const TAG_APPEND: u8 = 0xf0;
+const TAG_PREPEND: u8 = 0xf1;
+
+///
+/// An operation of an OpenTimestamps commitment path.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtsOp {
+    Sha256,
+    Ripemd160,
+    Append(Vec<u8>),
+    Prepend(Vec<u8>),
+}
+
+impl OtsOp {
+    pub fn apply(&self, msg: &[u8]) -> Vec<u8> {
+        match self {
+            OtsOp::Sha256 => sha256::Hash::hash(msg).into_inner().to_vec(),
+            OtsOp::Ripemd160 => ripemd160::Hash::hash(msg).into_inner().to_vec(),
+            OtsOp::Append(data) => [msg, &data[..]].concat(),
+            OtsOp::Prepend(data) => [&data[..], msg].concat(),
+        }
+    }
+}
+
+///
+/// A sequence of operations leading from a commitment to a block merkle root
+/// (in internal byte order), as in `BitcoinBlockHeaderAttestation` of `.ots` files.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OtsPath {
+    pub ops: Vec<OtsOp>,
+}
+
+impl OtsPath {
+    /// apply all operations to `msg`
+    pub fn apply(&self, msg: &[u8]) -> Vec<u8> {
+        self.ops.iter().fold(msg.to_vec(), |msg, op| op.apply(&msg))
+    }
+
+    ///
+    /// Encode with the binary format of OpenTimestamps
+    /// (an op tag, followed by a varuint length and data for binary ops).
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for op in self.ops.iter() {
+            match op {
+                OtsOp::Sha256 => out.push(TAG_SHA256),
+                OtsOp::Ripemd160 => out.push(TAG_RIPEMD160),
+                OtsOp::Append(data) => write_binary_op(&mut out, TAG_APPEND, data),
+                OtsOp::Prepend(data) => write_binary_op(&mut out, TAG_PREPEND, data),
+            }
+        }
+        out
+    }
+
+    ///
+    /// Decode a path encoded by `to_bytes`, only the operations
+    /// used by bitcoin attestations are supported.
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> OpResult<Self> {
+        let mut ops = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let tag = bytes[pos];
+            pos += 1;
+            let op = match tag {
+                TAG_SHA256 => OtsOp::Sha256,
+                TAG_RIPEMD160 => OtsOp::Ripemd160,
+                TAG_APPEND | TAG_PREPEND => {
+                    let len = read_varuint(bytes, &mut pos)? as usize;
+                    let data = match bytes.get(pos..pos.saturating_add(len)) {
+                        Some(data) => data.to_vec(),
+                        None => return Err(OpError::from("truncated ots operation")),
+                    };
+                    pos += len;
+                    if tag == TAG_APPEND {
+                        OtsOp::Append(data)
+                    } else {
+                        OtsOp::Prepend(data)
+                    }
+                }
+                _ => {
+                    return Err(OpError::from(
+                        format!("unsupported ots operation: {:#04x}", tag).as_str(),
+                    ))
+                }
+            };
+            ops.push(op);
+        }
+        Ok(OtsPath { ops })
+    }
+}
+
+fn write_binary_op(out: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    out.push(tag);
+    write_varuint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+/// LEB128, as `varuint` of OpenTimestamps
+fn write_varuint(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varuint(bytes: &[u8], pos: &mut usize) -> OpResult<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = match bytes.get(*pos) {
+            Some(byte) => *byte,
+            None => return Err(OpError::from("truncated ots varuint")),
+        };
+        *pos += 1;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(OpError::from("ots varuint too long"))
+}
+
+///
+/// A commitment of an OpenTimestamps calendar: an OP_RETURN output
+/// pushing exactly 32 bytes (the tip of the calendar's merkle tree).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtsAttestation {
+    pub height: usize,
+    pub txid: Txid,
+    pub vout: u32,
+    pub digest: [u8; 32],
+    /// path from `digest` to the merkle root of block `height`
+    pub path: OtsPath,
+    /// `path` applied to `digest` gives the merkle root of the block header
+    pub verified: bool,
+}
+
+/// digest committed by an output script `OP_RETURN <32 bytes>`
+pub fn ots_digest(script: &bitcoin::Script) -> Option<[u8; 32]> {
+    let bytes = script.as_bytes();
+    if bytes.len() == 34 && bytes[0] == 0x6a && bytes[1] == 0x20 {
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&bytes[2..]);
+        Some(digest)
+    } else {
+        None
+    }
+}
+
+///
+/// Path from `digest`, found in transaction `tx_index` of `block`,
+/// to the merkle root of `block`.
+///
+/// `None` if the digest is not in the (non-witness) transaction.
+///
+pub fn commitment_path(block: &Block, tx_index: usize, digest: &[u8]) -> Option<OtsPath> {
+    let tx = block.txdata.get(tx_index)?;
+    let bytes = serialize(&strip_witness(tx));
+    let pos = bytes.windows(digest.len()).position(|w| w == digest)?;
+    let mut ops = vec![
+        OtsOp::Prepend(bytes[..pos].to_vec()),
+        OtsOp::Append(bytes[pos + digest.len()..].to_vec()),
+        OtsOp::Sha256,
+        OtsOp::Sha256,
+    ];
+    let mut level: Vec<sha256d::Hash> = block.txdata.iter().map(|tx| tx.txid().as_hash()).collect();
+    let mut index = tx_index;
+    while level.len() > 1 {
+        // the last node is paired with itself on odd levels
+        let sibling = level[(index ^ 1).min(level.len() - 1)];
+        if index % 2 == 0 {
+            ops.push(OtsOp::Append(sibling.into_inner().to_vec()));
+        } else {
+            ops.push(OtsOp::Prepend(sibling.into_inner().to_vec()));
+        }
+        ops.push(OtsOp::Sha256);
+        ops.push(OtsOp::Sha256);
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                let right = pair.get(1).unwrap_or(&pair[0]);
+                sha256d::Hash::hash(&[&pair[0][..], &right[..]].concat())
+            })
+            .collect();
+        index /= 2;
+    }
+    Some(OtsPath { ops })
+}
+
+/// the transaction serialized as in its txid
+fn strip_witness(tx: &Transaction) -> Transaction {
+    let mut tx = tx.clone();
+    for input in tx.input.iter_mut() {
+        input.witness = Witness::default();
+    }
+    tx
+}
+
+///
+/// Check that `path` leads from `digest` to the merkle root of block `height`,
+/// e.g. for the bitcoin attestation of an `.ots` proof.
+///
+pub fn verify_path(db: &BitcoinDB, digest: &[u8], path: &OtsPath, height: usize) -> OpResult<bool> {
+    let merkle_root = db.get_header(height)?.block_header.merkle_root;
+    Ok(path.apply(digest) == merkle_root.into_inner().to_vec())
+}
+
+fn block_attestations(height: usize, block: &Block) -> Vec<OtsAttestation> {
+    let merkle_root = block.header.merkle_root.into_inner().to_vec();
+    let mut attestations = Vec::new();
+    for (i, tx) in block.txdata.iter().enumerate().skip(1) {
+        for (vout, output) in tx.output.iter().enumerate() {
+            if let Some(digest) = ots_digest(&output.script_pubkey) {
+                let path = commitment_path(block, i, &digest).unwrap_or_default();
+                let verified = path.apply(&digest) == merkle_root;
+                attestations.push(OtsAttestation {
+                    height,
+                    txid: tx.txid(),
+                    vout: vout as u32,
+                    digest,
+                    path,
+                    verified,
+                });
+            }
+        }
+    }
+    attestations
+}
+
+///
+/// Iterate through OpenTimestamps commitments of blocks in `range`,
+/// each with its verified path to the block merkle root.
+///
+/// Calendars commit bare hashes, so any OP_RETURN pushing exactly
+/// 32 bytes (outside of coinbase transactions) is reported.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::opentimestamps;
+/// use bitcoin_explorer::{BitcoinDB, ToHex};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// for a in opentimestamps::iter(&db, 700000..700100) {
+///     println!("{} {} {}", a.height, a.txid, a.path.to_bytes().to_hex());
+/// }
+/// ```
+///
+pub fn iter(db: &BitcoinDB, range: Range<usize>) -> impl Iterator<Item = OtsAttestation> {
+    let start = range.start;
+    db.iter_block::<Block>(range.start, range.end)
+        .enumerate()
+        .flat_map(move |(i, block)| block_attestations(start + i, &block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use bitcoin::blockdata::opcodes::all::OP_RETURN;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{TxIn, TxOut};
+
+    #[test]
+    fn test_path_encoding() {
+        let path = OtsPath {
+            ops: vec![
+                OtsOp::Prepend(vec![1; 200]),
+                OtsOp::Sha256,
+                OtsOp::Append(vec![2, 3]),
+                OtsOp::Ripemd160,
+            ],
+        };
+        let bytes = path.to_bytes();
+        assert_eq!(&bytes[..3], &[TAG_PREPEND, 0xc8, 0x01]);
+        assert_eq!(OtsPath::from_bytes(&bytes).unwrap(), path);
+        assert!(OtsPath::from_bytes(&bytes[..bytes.len() - 3]).is_err());
+        assert!(OtsPath::from_bytes(&[0x67]).is_err());
+    }
+
+    #[test]
+    fn test_iter_attestations() {
+        let digest = [0x42u8; 32];
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 1);
+        let txs: Vec<Transaction> = (0..3)
+            .map(|i| Transaction {
+                version: 2,
+                lock_time: i,
+                input: vec![TxIn::default()],
+                output: vec![TxOut {
+                    value: 0,
+                    script_pubkey: Builder::new()
+                        .push_opcode(OP_RETURN)
+                        .push_slice(if i == 2 { &digest[..] } else { &digest[..31] })
+                        .into_script(),
+                }],
+            })
+            .collect();
+        chain.mine(&tips[0], txs.clone());
+        let db = TestDb::new("ots", &chain);
+
+        let attestations: Vec<OtsAttestation> = iter(&db, 0..3).collect();
+        assert_eq!(attestations.len(), 1);
+        let a = &attestations[0];
+        assert_eq!((a.height, a.txid, a.vout), (2, txs[2].txid(), 0));
+        assert!(a.verified);
+        assert!(verify_path(&db, &digest, &a.path, 2).unwrap());
+        assert!(!verify_path(&db, &digest, &a.path, 1).unwrap());
+        assert!(!verify_path(&db, &[0u8; 32], &a.path, 2).unwrap());
+    }
+}
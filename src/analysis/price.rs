@@ -0,0 +1,196 @@
+//!
+//! Fiat exchange rates of blocks.
+//!
+use crate::analysis::hll::hash64;
+use crate::join::split_csv_line;
+use crate::parser::errors::{OpError, OpResult};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+const SATS_PER_BTC: f64 = 100_000_000.0;
+
+///
+/// What prices of a `PriceTable` are keyed by.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceKey {
+    Height,
+    /// unix timestamp, compared to the block header time
+    Time,
+}
+
+///
+/// Fiat price of one bitcoin by height or by time (user-supplied).
+///
+/// The price of a block is the last price at or before it
+/// (a step function), `None` before the first price.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::PriceTable;
+/// use bitcoin_explorer::enrich::{EnrichSpec, FiatValues};
+/// use bitcoin_explorer::BitcoinDB;
+/// use std::path::Path;
+/// use std::sync::Arc;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// // time,price
+/// let prices = PriceTable::from_csv(Path::new("./btc_usd_daily.csv")).unwrap();
+/// let spec = EnrichSpec::new().fiat(Arc::new(prices));
+/// for blk in db.iter_enriched(600000, 700000, &spec) {
+///     let fiat = blk.enrichments.get::<FiatValues>().unwrap();
+///     println!("{}: ${:.0} transferred", blk.height, fiat.total_output());
+/// }
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceTable {
+    key: PriceKey,
+    /// sorted by key
+    prices: Vec<(u64, f64)>,
+}
+
+impl PriceTable {
+    ///
+    /// Prices keyed by height, in any order.
+    ///
+    pub fn from_heights(prices: Vec<(usize, f64)>) -> Self {
+        Self::new(
+            PriceKey::Height,
+            prices.into_iter().map(|(h, p)| (h as u64, p)).collect(),
+        )
+    }
+
+    ///
+    /// Prices keyed by unix timestamp, in any order.
+    ///
+    pub fn from_times(prices: Vec<(u32, f64)>) -> Self {
+        Self::new(
+            PriceKey::Time,
+            prices.into_iter().map(|(t, p)| (t as u64, p)).collect(),
+        )
+    }
+
+    ///
+    /// Read a CSV file with a header line `height,price` or `time,price`.
+    ///
+    pub fn from_csv(path: &Path) -> OpResult<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = match lines.next() {
+            Some(line) => split_csv_line(&line?)?,
+            None => return Err(OpError::from("empty csv file")),
+        };
+        let key = match header.first().map(|c| c.trim()) {
+            Some("height") => PriceKey::Height,
+            Some("time") => PriceKey::Time,
+            _ => return Err(OpError::from("price csv must start with height or time")),
+        };
+        let mut prices = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = split_csv_line(&line)?;
+            let parsed = match (fields.first(), fields.get(1)) {
+                (Some(k), Some(p)) => k
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .zip(p.trim().parse::<f64>().ok()),
+                _ => None,
+            };
+            match parsed {
+                Some((k, p)) if p.is_finite() => prices.push((k, p)),
+                _ => return Err(OpError::from("invalid row in price csv")),
+            }
+        }
+        Ok(Self::new(key, prices))
+    }
+
+    fn new(key: PriceKey, mut prices: Vec<(u64, f64)>) -> Self {
+        prices.sort_by_key(|(k, _)| *k);
+        PriceTable { key, prices }
+    }
+
+    pub fn key(&self) -> PriceKey {
+        self.key
+    }
+
+    pub fn len(&self) -> usize {
+        self.prices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    ///
+    /// Hash of the content of this table.
+    ///
+    pub(crate) fn fingerprint(&self) -> u64 {
+        let mut bytes = Vec::with_capacity(1 + self.prices.len() * 16);
+        bytes.push(self.key as u8);
+        for (k, p) in self.prices.iter() {
+            bytes.extend_from_slice(&k.to_le_bytes());
+            bytes.extend_from_slice(&p.to_bits().to_le_bytes());
+        }
+        hash64(&bytes)
+    }
+
+    ///
+    /// Price of a block at `height` with header timestamp `time`.
+    ///
+    pub fn price_at(&self, height: usize, time: u32) -> Option<f64> {
+        let k = match self.key {
+            PriceKey::Height => height as u64,
+            PriceKey::Time => time as u64,
+        };
+        match self.prices.partition_point(|(key, _)| *key <= k) {
+            0 => None,
+            i => Some(self.prices[i - 1].1),
+        }
+    }
+}
+
+///
+/// Fiat value of `sats` at `price` (per bitcoin).
+///
+#[inline]
+pub fn to_fiat(sats: u64, price: f64) -> f64 {
+    sats as f64 / SATS_PER_BTC * price
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestDir;
+
+    #[test]
+    fn test_price_at() {
+        let table = PriceTable::from_heights(vec![(200, 2.0), (100, 1.0)]);
+        assert_eq!(table.price_at(99, 0), None);
+        assert_eq!(table.price_at(100, 0), Some(1.0));
+        assert_eq!(table.price_at(199, 0), Some(1.0));
+        assert_eq!(table.price_at(5000, 0), Some(2.0));
+        let table = PriceTable::from_times(vec![(1000, 3.0)]);
+        assert_eq!(table.price_at(0, 1000), Some(3.0));
+        assert_eq!(to_fiat(150_000_000, 20000.0), 30000.0);
+    }
+
+    #[test]
+    fn test_from_csv() {
+        let dir = TestDir::new("prices");
+        let path = dir.join("prices.csv");
+        std::fs::write(&path, "time,price\n1231006505,0.0\n1300000000,\"0.9\"\n\n").unwrap();
+        let table = PriceTable::from_csv(&path).unwrap();
+        assert_eq!(table.key(), PriceKey::Time);
+        assert_eq!(table.len(), 2);
+        std::fs::write(&path, "time,price\nx,1\n").unwrap();
+        assert!(PriceTable::from_csv(&path).is_err());
+    }
+}
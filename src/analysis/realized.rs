@@ -0,0 +1,205 @@
+//!
+//! Realized capitalization, realized profit / loss and SOPR.
+//!
+use crate::analysis::price::{to_fiat, PriceTable};
+use crate::api::{BitcoinDB, ConnectedTx};
+use crate::parser::blk_file::BlkFile;
+use crate::parser::block_index::BlockIndex;
+use crate::parser::errors::OpResult;
+use crate::parser::proto::connected_proto::{connect_tx_at, SummaryBlock};
+use crate::parser::tx_index::TxDB;
+use bitcoin::{Transaction, TxIn, TxOut};
+
+///
+/// Realized metrics of a block.
+///
+/// Every output is valued at the price of the block that created it
+/// (its realized price). Heights without a price are valued at 0.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct RealizedMetrics {
+    pub height: usize,
+    pub price: Option<f64>,
+    ///
+    /// realized capitalization after this block:
+    /// all spendable unspent outputs at their realized price
+    ///
+    pub realized_cap: f64,
+    /// spent outputs (excluding coinbase inputs) at their realized price
+    pub spent_realized_value: f64,
+    /// spent outputs at the price of this block
+    pub spent_value: f64,
+    /// sum of gains of spent outputs sold above their realized price
+    pub realized_profit: f64,
+    /// sum of losses of spent outputs sold below their realized price (positive)
+    pub realized_loss: f64,
+}
+
+impl RealizedMetrics {
+    ///
+    /// Spent Output Profit Ratio: `spent_value / spent_realized_value`.
+    ///
+    /// `None` if nothing of positive realized value is spent.
+    ///
+    pub fn sopr(&self) -> Option<f64> {
+        if self.spent_realized_value > 0.0 {
+            Some(self.spent_value / self.spent_realized_value)
+        } else {
+            None
+        }
+    }
+
+    /// realized profit minus realized loss
+    pub fn net_realized_pnl(&self) -> f64 {
+        self.realized_profit - self.realized_loss
+    }
+}
+
+///
+/// Iterate through realized metrics of blocks from 0 to `end` (excluded).
+///
+/// Uses connected block iteration (no txindex required),
+/// since realized cap accumulates from the genesis block.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::analysis::{iter_realized_metrics, PriceTable};
+/// use bitcoin_explorer::BitcoinDB;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let prices = PriceTable::from_csv(Path::new("./btc_usd_daily.csv")).unwrap();
+/// for m in iter_realized_metrics(&db, 700000, prices) {
+///     println!("{}: realized cap {:.0}, sopr {:?}", m.height, m.realized_cap, m.sopr());
+/// }
+/// ```
+///
+pub fn iter_realized_metrics(
+    db: &BitcoinDB,
+    end: usize,
+    prices: PriceTable,
+) -> impl Iterator<Item = RealizedMetrics> {
+    let db_copy = db.clone();
+    let mut realized_cap = 0.0;
+    db.iter_connected_block::<RealizedBlock>(end)
+        .enumerate()
+        .map(move |(height, block)| {
+            let price_of = |h: usize| match db_copy.get_header(h) {
+                Ok(record) => prices.price_at(h, record.block_header.time),
+                Err(_) => None,
+            };
+            let price = prices.price_at(height, block.time);
+            let p = price.unwrap_or(0.0);
+            let mut m = RealizedMetrics {
+                height,
+                price,
+                realized_cap: 0.0,
+                spent_realized_value: 0.0,
+                spent_value: 0.0,
+                realized_profit: 0.0,
+                realized_loss: 0.0,
+            };
+            for tx in block.txdata.iter() {
+                for (value, created_height) in tx.input.iter() {
+                    let realized = to_fiat(*value, price_of(*created_height).unwrap_or(0.0));
+                    let now = to_fiat(*value, p);
+                    m.spent_realized_value += realized;
+                    m.spent_value += now;
+                    if now >= realized {
+                        m.realized_profit += now - realized;
+                    } else {
+                        m.realized_loss += realized - now;
+                    }
+                }
+                realized_cap += tx.output.iter().map(|v| to_fiat(*v, p)).sum::<f64>();
+            }
+            realized_cap -= m.spent_realized_value;
+            m.realized_cap = realized_cap;
+            m
+        })
+}
+
+///
+/// Spent outputs with their creation heights, and spendable outputs.
+///
+type RealizedBlock = SummaryBlock<RealizedTx>;
+
+struct RealizedTx {
+    /// (value, created height)
+    input: Vec<(u64, usize)>,
+    /// values of spendable outputs
+    output: Vec<u64>,
+}
+
+struct RealizedTxOut(u64);
+
+impl From<TxOut> for RealizedTxOut {
+    fn from(o: TxOut) -> Self {
+        RealizedTxOut(o.value)
+    }
+}
+
+impl ConnectedTx for RealizedTx {
+    type TOut = RealizedTxOut;
+
+    fn from(tx: &Transaction) -> Self {
+        RealizedTx {
+            input: Vec::new(),
+            output: tx
+                .output
+                .iter()
+                .filter(|o| !o.script_pubkey.is_provably_unspendable())
+                .map(|o| o.value)
+                .collect(),
+        }
+    }
+
+    fn add_input(&mut self, input: Self::TOut) {
+        // creation height unknown
+        self.input.push((input.0, 0));
+    }
+
+    fn add_input_at(&mut self, input: Self::TOut, _tx_in: &TxIn, created_height: usize) {
+        self.input.push((input.0, created_height));
+    }
+
+    fn connect(
+        tx: Transaction,
+        tx_db: &TxDB,
+        blk_index: &BlockIndex,
+        blk_file: &BlkFile,
+    ) -> OpResult<Self> {
+        connect_tx_at(tx, tx_db, blk_index, blk_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RealizedMetrics;
+
+    #[test]
+    fn test_sopr() {
+        let m = RealizedMetrics {
+            height: 0,
+            price: Some(2.0),
+            realized_cap: 0.0,
+            spent_realized_value: 4.0,
+            spent_value: 6.0,
+            realized_profit: 3.0,
+            realized_loss: 1.0,
+        };
+        assert_eq!(m.sopr(), Some(1.5));
+        assert_eq!(m.net_realized_pnl(), 2.0);
+        assert_eq!(
+            RealizedMetrics {
+                spent_realized_value: 0.0,
+                ..m
+            }
+            .sopr(),
+            None
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//!
+//! Output script templates: scripts with their constants abstracted,
+//! counted over a range of blocks.
+//!
+//! A template keeps the opcodes of a script and replaces each data push
+//! by its length, e.g. `OP_DUP OP_HASH160 <20> OP_EQUALVERIFY OP_CHECKSIG`
+//! for P2PKH. Unlike `ScriptType`, templates are not a fixed list: new
+//! (non-standard) script patterns show up as new templates.
+//!
+use crate::analysis::merge::ShardMerge;
+use crate::api::BitcoinDB;
+use crate::parser::errors::OpResult;
+use crate::parser::script::{evaluate_script, ScriptType};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{Block, Network, OutPoint, Script};
+use std::collections::HashMap;
+use std::ops::Range;
+
+///
+/// Template of a script: opcodes as named by Bitcoin Core (`OP_DUP`),
+/// data pushes as `<length>`, empty pushes as `OP_0`.
+///
+/// Bytes that do not parse as opcodes end the template with `<invalid>`.
+///
+pub fn script_template(script: &Script) -> String {
+    let mut parts = Vec::new();
+    for instruction in script.instructions() {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) if data.is_empty() => parts.push("OP_0".to_string()),
+            Ok(Instruction::PushBytes(data)) => parts.push(format!("<{}>", data.len())),
+            Ok(Instruction::Op(op)) => parts.push(format!("{:?}", op)),
+            Err(_) => {
+                parts.push("<invalid>".to_string());
+                break;
+            }
+        }
+    }
+    parts.join(" ")
+}
+
+///
+/// Occurrences of a template.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateStats {
+    pub template: String,
+    /// classification of the scripts of this template
+    pub pattern: ScriptType,
+    /// number of outputs
+    pub count: u64,
+    pub first_height: usize,
+    pub last_height: usize,
+    /// first output seen with this template
+    pub example: OutPoint,
+}
+
+impl TemplateStats {
+    ///
+    /// Whether the template is one of the known `ScriptType`s.
+    ///
+    pub fn is_standard(&self) -> bool {
+        self.pattern != ScriptType::NotRecognised
+    }
+}
+
+///
+/// Counts of output script templates.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateCounts {
+    templates: HashMap<String, TemplateStats>,
+}
+
+impl TemplateCounts {
+    pub fn new() -> Self {
+        TemplateCounts::default()
+    }
+
+    ///
+    /// Count an output script of block `height`.
+    ///
+    pub fn add_script(&mut self, height: usize, outpoint: OutPoint, script: &Script) {
+        let template = script_template(script);
+        if let Some(stats) = self.templates.get_mut(&template) {
+            stats.count += 1;
+            stats.last_height = stats.last_height.max(height);
+            return;
+        }
+        // classified once per template
+        let pattern = evaluate_script(script, Network::Bitcoin).pattern;
+        self.templates.insert(
+            template.clone(),
+            TemplateStats {
+                template,
+                pattern,
+                count: 1,
+                first_height: height,
+                last_height: height,
+                example: outpoint,
+            },
+        );
+    }
+
+    ///
+    /// Count the output scripts of block `height`.
+    ///
+    pub fn add_block(&mut self, height: usize, block: &Block) {
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            for (vout, o) in tx.output.iter().enumerate() {
+                self.add_script(height, OutPoint::new(txid, vout as u32), &o.script_pubkey);
+            }
+        }
+    }
+
+    /// number of distinct templates
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    pub fn get(&self, template: &str) -> Option<&TemplateStats> {
+        self.templates.get(template)
+    }
+
+    ///
+    /// All templates, most frequent first.
+    ///
+    pub fn by_count(&self) -> Vec<&TemplateStats> {
+        let mut all: Vec<&TemplateStats> = self.templates.values().collect();
+        all.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.template.cmp(&b.template))
+        });
+        all
+    }
+
+    ///
+    /// Templates first seen at or after `since`, used by at least
+    /// `min_count` outputs, most frequent first.
+    ///
+    pub fn emerging(&self, since: usize, min_count: u64) -> Vec<&TemplateStats> {
+        self.by_count()
+            .into_iter()
+            .filter(|t| t.first_height >= since && t.count >= min_count)
+            .collect()
+    }
+
+    ///
+    /// Templates not recognised as a `ScriptType`, most frequent first.
+    ///
+    pub fn non_standard(&self) -> Vec<&TemplateStats> {
+        self.by_count()
+            .into_iter()
+            .filter(|t| !t.is_standard())
+            .collect()
+    }
+}
+
+impl ShardMerge for TemplateCounts {
+    fn merge_shard(&mut self, next: Self) -> OpResult<()> {
+        for (template, stats) in next.templates {
+            match self.templates.get_mut(&template) {
+                Some(merged) => {
+                    merged.count += stats.count;
+                    merged.last_height = merged.last_height.max(stats.last_height);
+                }
+                None => {
+                    self.templates.insert(template, stats);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Count the output script templates of blocks `range`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use bitcoin_explorer::analysis::script_templates;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let counts = script_templates(&db, 0..700000);
+/// // non-standard patterns appearing in the last 50000 blocks
+/// for t in counts.emerging(650000, 100).into_iter().filter(|t| !t.is_standard()) {
+///     println!("{} {} e.g. {}", t.count, t.template, t.example);
+/// }
+/// ```
+///
+pub fn script_templates(db: &BitcoinDB, range: Range<usize>) -> TemplateCounts {
+    let mut counts = TemplateCounts::new();
+    for (i, block) in db.iter_block::<Block>(range.start, range.end).enumerate() {
+        counts.add_block(range.start + i, &block);
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::opcodes::all;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{PubkeyHash, Txid};
+
+    #[test]
+    fn test_script_templates() {
+        let p2pkh = Script::new_p2pkh(&PubkeyHash::hash(&[1]));
+        assert_eq!(
+            script_template(&p2pkh),
+            "OP_DUP OP_HASH160 <20> OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        let odd = Builder::new()
+            .push_slice(&[7; 5])
+            .push_opcode(all::OP_DROP)
+            .push_int(0)
+            .into_script();
+        assert_eq!(script_template(&odd), "<5> OP_DROP OP_0");
+        // push of 10 bytes with 2 bytes left
+        assert_eq!(
+            script_template(&Script::from(vec![0x51, 0x0a, 1, 2])),
+            "OP_PUSHNUM_1 <invalid>"
+        );
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let outpoint = |n: u8| OutPoint::new(Txid::from_inner([n; 32]), 0);
+        let mut first = TemplateCounts::new();
+        first.add_block(0, &genesis);
+        first.add_script(1, outpoint(1), &p2pkh);
+        let mut second = TemplateCounts::new();
+        second.add_script(2, outpoint(2), &Script::new_p2pkh(&PubkeyHash::hash(&[2])));
+        second.add_script(3, outpoint(3), &odd);
+        second.add_script(4, outpoint(4), &odd);
+        first.merge_shard(second).unwrap();
+
+        assert_eq!(first.len(), 3);
+        let p2pkh_stats = first.get(&script_template(&p2pkh)).unwrap();
+        assert_eq!(p2pkh_stats.count, 2);
+        assert_eq!((p2pkh_stats.first_height, p2pkh_stats.last_height), (1, 2));
+        assert_eq!(p2pkh_stats.example, outpoint(1));
+        assert_eq!(p2pkh_stats.pattern, ScriptType::Pay2PublicKeyHash);
+        // the genesis output pays to a public key
+        assert_eq!(
+            first.get("<65> OP_CHECKSIG").unwrap().pattern,
+            ScriptType::Pay2PublicKey
+        );
+
+        let emerging: Vec<&str> = first
+            .emerging(1, 2)
+            .iter()
+            .map(|t| t.template.as_str())
+            .collect();
+        assert_eq!(
+            emerging,
+            vec!["<5> OP_DROP OP_0", p2pkh_stats.template.as_str()]
+        );
+        let non_standard = first.non_standard();
+        assert_eq!(non_standard.len(), 1);
+        assert_eq!(non_standard[0].example, outpoint(3));
+        assert_eq!(non_standard[0].count, 2);
+    }
+}
@@ -0,0 +1,173 @@
+//!
+//! Point-in-time queries of the chain state.
+//!
+use crate::api::{Address, Amount, BitcoinDB, Script, Txid};
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::proto::amount::checked_sum;
+use crate::utxo::{Utxo, UtxoView};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::OutPoint;
+use std::collections::HashMap;
+
+///
+/// The chain state as of a block height, see `BitcoinDB::at_height`.
+///
+/// All queries answer as if the chain ended at `height` (included).
+///
+/// # Memory
+///
+/// The UTXO set at `height` is kept in memory, see `UtxoView`,
+/// with its outpoints indexed by the sha256 of their script.
+///
+pub struct ChainView<'a> {
+    db: &'a BitcoinDB,
+    height: usize,
+    utxos: UtxoView,
+    by_script: HashMap<sha256::Hash, Vec<OutPoint>>,
+}
+
+impl BitcoinDB {
+    ///
+    /// Query the chain state as of block `height` (included).
+    ///
+    /// This replays blocks `0..=height`, which is slow for large heights,
+    /// so keep the `ChainView` around for multiple queries.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{Address, BitcoinDB};
+    /// use std::path::Path;
+    /// use std::str::FromStr;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let view = db.at_height(100000).unwrap();
+    /// let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+    /// println!("balance at 100000: {}", view.get_balance(&address).unwrap());
+    /// ```
+    ///
+    pub fn at_height(&self, height: usize) -> OpResult<ChainView<'_>> {
+        if height >= self.get_block_count() {
+            return Err(OpError::from("height not found"));
+        }
+        let utxos = UtxoView::load(self, height + 1)?;
+        let mut by_script: HashMap<sha256::Hash, Vec<OutPoint>> = HashMap::new();
+        for (outpoint, utxo) in utxos.iter() {
+            by_script
+                .entry(script_hash(&utxo.txout.script_pubkey))
+                .or_default()
+                .push(*outpoint);
+        }
+        Ok(ChainView {
+            db: self,
+            height,
+            utxos,
+            by_script,
+        })
+    }
+}
+
+impl<'a> ChainView<'a> {
+    /// height of the last block included in this view
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// the UTXO set as of `height`
+    #[inline]
+    pub fn utxos(&self) -> &UtxoView {
+        &self.utxos
+    }
+
+    ///
+    /// Unspent outputs locked by `script`.
+    ///
+    pub fn get_utxos_of_script(&self, script: &Script) -> Vec<(OutPoint, Utxo)> {
+        self.by_script
+            .get(&script_hash(script))
+            .map(|outpoints| {
+                outpoints
+                    .iter()
+                    .filter_map(|o| self.utxos.get(o).map(|u| (*o, u.clone())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    ///
+    /// Unspent outputs paying to `address`.
+    ///
+    /// Only outputs with the exact script of the address are included
+    /// (e.g. P2PK outputs are not included for P2PKH addresses).
+    ///
+    pub fn get_utxos_of(&self, address: &Address) -> Vec<(OutPoint, Utxo)> {
+        self.get_utxos_of_script(&address.script_pubkey())
+    }
+
+    ///
+    /// Sum of unspent outputs paying to `address`, see `get_utxos_of`.
+    /// Fails if the sum overflows, which only a corrupted datadir can cause.
+    ///
+    pub fn get_balance(&self, address: &Address) -> OpResult<Amount> {
+        let values = self
+            .get_utxos_of(address)
+            .into_iter()
+            .map(|(_, u)| Amount::from_sat(u.txout.value));
+        checked_sum(values).ok_or_else(|| OpError::from("balance overflows the amount range"))
+    }
+
+    ///
+    /// Whether a transaction is confirmed at or before `height`.
+    ///
+    /// Requires txindex, unless the transaction has unspent outputs at `height`.
+    ///
+    pub fn tx_exists(&self, txid: &Txid) -> OpResult<bool> {
+        if self.db.tx_db.is_open() {
+            let height = self.db.tx_db.find_block_height_of_tx(txid)?;
+            return Ok(height.map_or(false, |h| h <= self.height));
+        }
+        if self.utxos.iter().any(|(o, _)| &o.txid == txid) {
+            Ok(true)
+        } else {
+            Err(self.db.tx_index_unavailable())
+        }
+    }
+}
+
+fn script_hash(script: &Script) -> sha256::Hash {
+    sha256::Hash::hash(script.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{Address, Amount};
+    use crate::testutil::{SyntheticChain, TestDb};
+    use bitcoin::Network;
+
+    #[test]
+    fn test_chain_view() {
+        // block 3 spends the coinbase of block 1
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let coinbase = &chain.block(&tips[0]).unwrap().txdata[0];
+        let address =
+            Address::from_script(&coinbase.output[0].script_pubkey, Network::Bitcoin).unwrap();
+        let outpoint = chain.coinbase_outpoint(&tips[0]).unwrap();
+        chain.mine(&tips[1], vec![SyntheticChain::spend(outpoint, 1000)]);
+        let db = TestDb::new("chain_view", &chain);
+
+        let before = db.at_height(2).unwrap();
+        assert_eq!(before.get_utxos_of(&address)[0].0, outpoint);
+        assert_eq!(
+            before.get_balance(&address).unwrap(),
+            Amount::from_sat(50 * 100_000_000)
+        );
+        let after = db.at_height(3).unwrap();
+        assert!(after.get_utxos_of(&address).is_empty());
+        assert_eq!(after.get_balance(&address).unwrap(), Amount::ZERO);
+        assert!(db.at_height(4).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//!
+//! Coinbase transactions only: the blocks are read up to the end
+//! of their first transaction, the other transactions are not decoded.
+//!
+use crate::api::{BitcoinDB, Transaction};
+use crate::iter::{par_map_ordered, IterError, ParMapOptions};
+use crate::parser::errors::{OpError, OpResult};
+use log::error;
+use std::ops::Range;
+
+impl BitcoinDB {
+    ///
+    /// Get the coinbase transaction of block `height`,
+    /// reading only the beginning of the block.
+    ///
+    pub fn get_coinbase<T: From<Transaction>>(&self, height: usize) -> OpResult<T> {
+        if let Some(index) = self.block_index.records.get(height) {
+            let tx = self
+                .blk_file
+                .read_coinbase(index.n_file, index.n_data_pos)?;
+            Ok(self.convert(|| tx.into()))
+        } else {
+            Err(OpError::from("height not found"))
+        }
+    }
+
+    ///
+    /// Iterate through the coinbase transactions of blocks `range`,
+    /// with their heights, in chain order.
+    ///
+    /// Only the first transaction of each block is read and decoded,
+    /// a fraction of the work of `iter_block` for miner or subsidy studies.
+    ///
+    /// The iteration ends at the first block that cannot be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, Transaction};
+    /// use std::path::Path;
+    ///
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+    ///
+    /// // total rewards claimed by the miners
+    /// let rewards: u64 = db
+    ///     .iter_coinbases::<Transaction>(0..700000)
+    ///     .map(|(_, tx)| tx.output.iter().map(|o| o.value).sum::<u64>())
+    ///     .sum();
+    /// ```
+    ///
+    pub fn iter_coinbases<T>(&self, range: Range<usize>) -> impl Iterator<Item = (usize, T)>
+    where
+        T: From<Transaction> + Send + 'static,
+    {
+        let db = self.clone();
+        let heights = range.start..range.end.max(range.start);
+        par_map_ordered(
+            heights,
+            move |h| {
+                db.get_coinbase::<T>(h)
+                    .map(|tx| (h, tx))
+                    .map_err(|e| IterError::new(h, format!("cannot read coinbase: {}", e)))
+            },
+            ParMapOptions::default(),
+        )
+        .scan((), |_, result| match result {
+            Ok(coinbase) => Some(coinbase),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{Block, Transaction};
+    use crate::testutil::{SyntheticChain, TestDb};
+    use crate::STransaction;
+
+    #[test]
+    fn test_iter_coinbases() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 10);
+        let db = TestDb::new("iter_coinbases", &chain);
+
+        let coinbases: Vec<(usize, Transaction)> = db.iter_coinbases(2..11).collect();
+        let blocks: Vec<Block> = db.iter_block(2, 11).collect();
+        assert_eq!(coinbases.len(), 9);
+        for ((h, tx), block) in coinbases.iter().zip(blocks) {
+            assert!(tx.is_coin_base());
+            assert_eq!(tx, &block.txdata[0]);
+            assert_eq!(db.get_coinbase::<Transaction>(*h).unwrap(), *tx);
+        }
+        assert_eq!(coinbases[0].0, 2);
+
+        // stops at the first missing height
+        let simple: Vec<(usize, STransaction)> = db.iter_coinbases(9..20).collect();
+        assert_eq!(
+            simple.iter().map(|(h, _)| *h).collect::<Vec<_>>(),
+            vec![9, 10]
+        );
+        assert!(db.get_coinbase::<Transaction>(11).is_err());
+    }
+}
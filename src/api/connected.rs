@@ -1,8 +1,15 @@
 //!
 //! implementation of methods that retrieve block info with outpoints connected
 //!
-use crate::api::{BitcoinDB, ConnectedBlock, ConnectedBlockIter, ConnectedTx, Txid};
+use crate::api::{
+    BitcoinDB, ConnectedBlock, ConnectedBlockIter, ConnectedBlockIterOptions, ConnectedTx, Txid,
+};
+use crate::parser::block_index::BLOCK_HAVE_UNDO;
 use crate::parser::errors::{OpError, OpResult};
+use crate::parser::undo_file::BlockUndo;
+use bitcoin::{Block, Transaction};
+use par_iter_sync::IntoParallelIteratorSync;
+use std::path::Path;
 
 impl BitcoinDB {
     ///
@@ -23,10 +30,10 @@ impl BitcoinDB {
     ///
     pub fn get_connected_block<T: ConnectedBlock>(&self, height: usize) -> OpResult<T> {
         if !self.tx_db.is_open() {
-            return Err(OpError::from("TxDB not open"));
+            return Err(self.tx_index_unavailable());
         }
         let tx = self.get_block(height)?;
-        T::connect(tx, &self.tx_db, &self.block_index, &self.blk_file)
+        self.convert(|| T::connect(tx, &self.tx_db, &self.block_index, &self.blk_file))
     }
 
     ///
@@ -49,10 +56,10 @@ impl BitcoinDB {
     ///
     pub fn get_connected_transaction<T: ConnectedTx>(&self, txid: &Txid) -> OpResult<T> {
         if !self.tx_db.is_open() {
-            return Err(OpError::from("TxDB not open"));
+            return Err(self.tx_index_unavailable());
         }
-        let tx = self.get_transaction(txid)?;
-        T::connect(tx, &self.tx_db, &self.block_index, &self.blk_file)
+        let tx = self.get_transaction::<Transaction>(txid)?;
+        self.convert(|| T::connect(tx, &self.tx_db, &self.block_index, &self.blk_file))
     }
 
     ///
@@ -99,4 +106,194 @@ impl BitcoinDB {
     {
         ConnectedBlockIter::new(self, end)
     }
+
+    ///
+    /// Same as `iter_connected_block`, with options.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, ConnectedBlockIterOptions, SConnectedBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let options = ConnectedBlockIterOptions {
+    ///     strict_chronology: true,
+    ///     ..Default::default()
+    /// };
+    /// let mut iter = db.iter_connected_block_with_options::<SConnectedBlock>(700000, options);
+    /// for block in &mut iter {
+    ///     println!("{}", block.txdata.len());
+    /// }
+    /// let report = iter.consistency_report().unwrap();
+    /// for v in report.violations {
+    ///     println!("inconsistent input at height {}: {:?}", v.height, v.kind);
+    /// }
+    /// ```
+    ///
+    pub fn iter_connected_block_with_options<TBlock>(
+        &self,
+        end: usize,
+        options: ConnectedBlockIterOptions,
+    ) -> ConnectedBlockIter<TBlock>
+    where
+        TBlock: 'static + ConnectedBlock + Send,
+    {
+        ConnectedBlockIter::with_options(self, end, options)
+    }
+
+    ///
+    /// Iterate through connected blocks `start..end`, resuming from
+    /// a UTXO snapshot at height `start`, see `ConnectedBlockIter::new_range`.
+    ///
+    pub fn iter_connected_block_range<TBlock>(
+        &self,
+        start: usize,
+        end: usize,
+        snapshot: Option<&Path>,
+    ) -> OpResult<ConnectedBlockIter<TBlock>>
+    where
+        TBlock: 'static + ConnectedBlock + Send,
+    {
+        ConnectedBlockIter::new_range(self, start, end, snapshot)
+    }
+
+    ///
+    /// Get the outputs spent by block `height`, from Bitcoin Core undo files
+    /// (`blocks/rev*.dat`).
+    ///
+    pub fn get_block_undo(&self, height: usize) -> OpResult<BlockUndo> {
+        let record = self.get_header(height)?;
+        if height == 0 {
+            // the genesis block has no undo data
+            return Ok(BlockUndo::default());
+        }
+        if record.n_status & BLOCK_HAVE_UNDO == 0 {
+            return Err(OpError::from(
+                format!("no undo data for block {}", height).as_str(),
+            ));
+        }
+        self.undo_file.read_block_undo(
+            record.n_file,
+            record.n_undo_pos,
+            &record.block_header.prev_blockhash,
+        )
+    }
+
+    ///
+    /// Get a block with inputs replaced by connected outputs,
+    /// read from Bitcoin Core undo files.
+    ///
+    /// Unlike `get_connected_block`, this does not require `txindex`,
+    /// and only reads block `height` and its undo data.
+    ///
+    pub fn get_connected_block_undo<T: ConnectedBlock>(&self, height: usize) -> OpResult<T> {
+        let block = self.get_block::<Block>(height)?;
+        let undo = self.get_block_undo(height)?;
+        if undo.txs.len() + 1 != block.txdata.len() && height > 0 {
+            return Err(OpError::from(
+                format!(
+                    "undo data of block {} does not match its transactions",
+                    height
+                )
+                .as_str(),
+            ));
+        }
+        self.convert(|| {
+            let mut connected = T::from(block.header, block.block_hash());
+            let mut undo_txs = undo.txs.into_iter();
+            for (i, tx) in block.txdata.iter().enumerate() {
+                let mut connected_tx = <T::Tx as ConnectedTx>::from(tx);
+                // the coinbase has no undo entry
+                if i > 0 {
+                    let spent = undo_txs.next().unwrap_or_default();
+                    if spent.len() != tx.input.len() {
+                        return Err(OpError::from(
+                            format!("undo data of tx {} does not match its inputs", tx.txid())
+                                .as_str(),
+                        ));
+                    }
+                    for (tx_in, spent) in tx.input.iter().zip(spent) {
+                        connected_tx.add_input_at(spent.txout.into(), tx_in, spent.height as usize);
+                    }
+                }
+                connected.add_tx(connected_tx);
+            }
+            Ok(connected)
+        })
+    }
+
+    ///
+    /// Iterate through connected blocks `start..end` using Bitcoin Core
+    /// undo files (`blocks/rev*.dat`) instead of tracking unspent outputs.
+    ///
+    /// Blocks are read and connected independently, so any sub-range can be
+    /// iterated without building a UTXO cache from the genesis block.
+    /// Requires undo files for the whole range (not the case for pruned
+    /// or reindexing nodes), the iterator stops at the first block
+    /// without valid undo data.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SConnectedBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// for block in db.iter_connected_block_undo::<SConnectedBlock>(700000, 700100) {
+    ///     for tx in block.txdata {
+    ///         println!("{} inputs", tx.input.len());
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn iter_connected_block_undo<TBlock>(
+        &self,
+        start: usize,
+        end: usize,
+    ) -> impl Iterator<Item = TBlock>
+    where
+        TBlock: 'static + ConnectedBlock + Send,
+    {
+        let db = self.clone();
+        (start..end.max(start))
+            .into_par_iter_sync(move |h| db.get_connected_block_undo::<TBlock>(h).map_err(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{SyntheticChain, TestDb};
+    use crate::FConnectedBlock;
+    use bitcoin::TxIn;
+
+    #[test]
+    fn test_iter_connected_block_undo() {
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        let mut spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        spend.input.push(TxIn {
+            previous_output: chain.coinbase_outpoint(&tips[2]).unwrap(),
+            ..Default::default()
+        });
+        let spending = chain.mine(&tips[2], vec![spend]);
+        chain.extend(&spending, 2);
+        let db = TestDb::new("undo", &chain);
+
+        let expected: Vec<FConnectedBlock> = db.iter_connected_block(7).collect();
+        let from_undo: Vec<FConnectedBlock> = db.iter_connected_block_undo(0, 7).collect();
+        assert_eq!(from_undo, expected);
+        // any sub-range, without unspent outputs of earlier blocks
+        let sub_range: Vec<FConnectedBlock> = db.iter_connected_block_undo(4, 6).collect();
+        assert_eq!(sub_range, expected[4..6].to_vec());
+        assert_eq!(sub_range[0].txdata[1].input.len(), 2);
+
+        assert!(db.get_block_undo(0).unwrap().txs.is_empty());
+        assert_eq!(db.get_block_undo(4).unwrap().txs[0][1].height, 3);
+        assert!(db.get_block_undo(7).is_err());
+    }
 }
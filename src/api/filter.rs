@@ -0,0 +1,352 @@
+//!
+//! Filtered iteration: transactions matching a `BlockFilter`,
+//! tested on the serialized blocks before decoding.
+//!
+use crate::api::{BitcoinDB, BlockHash, Script, Transaction, Txid};
+use crate::iter::{par_map_ordered, recycle_vec, IterError, ParMapOptions};
+use crate::parser::era::SliceReader;
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::OutPoint;
+use log::error;
+use std::collections::HashSet;
+use std::ops::Range;
+
+///
+/// Transactions to select in `BitcoinDB::iter_filtered`.
+///
+/// A transaction matches if one of its outputs matches the output
+/// criteria (`script_pubkeys` and `value_range`, both applying when set),
+/// if its txid is in `txids`, or if it spends one of `spent_outpoints`
+/// or an output of a transaction of `txids`. An empty filter matches
+/// all transactions.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// outputs paying to one of these scripts
+    pub script_pubkeys: HashSet<Script>,
+    /// these transactions, and the transactions spending their outputs
+    pub txids: HashSet<Txid>,
+    /// outputs of value (satoshi) in this range
+    pub value_range: Option<Range<u64>>,
+    /// transactions spending one of these outputs
+    pub spent_outpoints: HashSet<OutPoint>,
+}
+
+impl BlockFilter {
+    fn has_output_criteria(&self) -> bool {
+        !self.script_pubkeys.is_empty() || self.value_range.is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.has_output_criteria() && self.txids.is_empty() && self.spent_outpoints.is_empty()
+    }
+}
+
+///
+/// A transaction matching a `BlockFilter`, with its block context.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedTransaction<T> {
+    pub height: usize,
+    pub block_hash: BlockHash,
+    /// block timestamp
+    pub time: u32,
+    /// position of the transaction in its block
+    pub index: usize,
+    /// outputs matching the output criteria of the filter
+    pub matched_outputs: Vec<u32>,
+    pub tx: T,
+}
+
+/// `BlockFilter` with scripts looked up by their bytes
+struct CompiledFilter {
+    filter: BlockFilter,
+    scripts: HashSet<Vec<u8>>,
+}
+
+impl CompiledFilter {
+    fn new(filter: BlockFilter) -> Self {
+        let scripts = filter
+            .script_pubkeys
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        CompiledFilter { filter, scripts }
+    }
+
+    #[inline]
+    fn output_matches(&self, value: u64, script: &[u8]) -> bool {
+        self.filter.has_output_criteria()
+            && self
+                .filter
+                .value_range
+                .as_ref()
+                .map_or(true, |r| r.contains(&value))
+            && (self.scripts.is_empty() || self.scripts.contains(script))
+    }
+
+    #[inline]
+    fn input_matches(&self, txid: &[u8], vout: u32) -> bool {
+        if self.filter.txids.is_empty() && self.filter.spent_outpoints.is_empty() {
+            return false;
+        }
+        let txid = match Txid::from_slice(txid) {
+            Ok(txid) => txid,
+            Err(_) => return false,
+        };
+        self.filter.txids.contains(&txid)
+            || self
+                .filter
+                .spent_outpoints
+                .contains(&OutPoint::new(txid, vout))
+    }
+}
+
+/// a transaction found by `scan_block`: byte range and matched outputs
+struct ScannedTx {
+    index: usize,
+    range: Range<usize>,
+    matched_outputs: Vec<u32>,
+}
+
+///
+/// Find the transactions of a serialized block matching `filter`,
+/// skipping scripts and witnesses without copying them.
+/// Txids are only computed if the filter has txids.
+/// `None` if the block is malformed.
+///
+fn scan_block(raw: &[u8], filter: &CompiledFilter) -> Option<Vec<ScannedTx>> {
+    let mut r = SliceReader::new(raw);
+    r.take(80)?;
+    let n_tx = r.compact_size()?;
+    let match_all = filter.filter.is_empty();
+    let mut matched = Vec::new();
+    for index in 0..n_tx {
+        let start = r.position();
+        r.take(4)?;
+        // segwit marker and flag
+        let segwit = raw.get(start + 4..start + 6) == Some(&[0u8, 1u8][..]);
+        if segwit {
+            r.take(2)?;
+        }
+        let body_start = r.position();
+        let mut is_match = match_all;
+        let n_in = r.compact_size()?;
+        for _ in 0..n_in {
+            let prevout = r.take(36)?;
+            let vout = u32::from_le_bytes([prevout[32], prevout[33], prevout[34], prevout[35]]);
+            is_match |= filter.input_matches(&prevout[..32], vout);
+            let script_len = r.compact_size()?;
+            r.take(script_len)?;
+            r.take(4)?;
+        }
+        let n_out = r.compact_size()?;
+        let mut matched_outputs = Vec::new();
+        for vout in 0..n_out {
+            let value = r.u64()?;
+            let script_len = r.compact_size()?;
+            if filter.output_matches(value, r.take(script_len)?) {
+                matched_outputs.push(vout as u32);
+            }
+        }
+        let body_end = r.position();
+        if segwit {
+            for _ in 0..n_in {
+                for _ in 0..r.compact_size()? {
+                    let item_len = r.compact_size()?;
+                    r.take(item_len)?;
+                }
+            }
+        }
+        let lock_time = r.take(4)?;
+        is_match |= !matched_outputs.is_empty();
+        if !is_match && !filter.filter.txids.is_empty() {
+            // the txid commits to the serialization without witness
+            let mut engine = Txid::engine();
+            engine.input(&raw[start..start + 4]);
+            engine.input(&raw[body_start..body_end]);
+            engine.input(lock_time);
+            is_match = filter.filter.txids.contains(&Txid::from_engine(engine));
+        }
+        if is_match {
+            matched.push(ScannedTx {
+                index,
+                range: start..r.position(),
+                matched_outputs,
+            });
+        }
+    }
+    if r.remaining() != 0 {
+        return None;
+    }
+    Some(matched)
+}
+
+impl BitcoinDB {
+    ///
+    /// Transactions of block `height` matching `filter`.
+    ///
+    /// Only the matching transactions are decoded.
+    ///
+    pub fn get_filtered<T: From<Transaction>>(
+        &self,
+        height: usize,
+        filter: &BlockFilter,
+    ) -> OpResult<Vec<MatchedTransaction<T>>> {
+        self.filter_block(height, &CompiledFilter::new(filter.clone()))
+    }
+
+    fn filter_block<T: From<Transaction>>(
+        &self,
+        height: usize,
+        filter: &CompiledFilter,
+    ) -> OpResult<Vec<MatchedTransaction<T>>> {
+        let header = self.get_header(height)?.block_header;
+        let raw = self.get_raw_block(height)?;
+        let scanned = scan_block(&raw, filter)
+            .ok_or_else(|| OpError::from(format!("malformed block {}", height).as_str()))?;
+        let block_hash = header.block_hash();
+        let matched = scanned
+            .into_iter()
+            .map(|tx| {
+                let decoded: Transaction = deserialize(&raw[tx.range])?;
+                Ok(MatchedTransaction {
+                    height,
+                    block_hash,
+                    time: header.time,
+                    index: tx.index,
+                    matched_outputs: tx.matched_outputs,
+                    tx: self.convert(|| decoded.into()),
+                })
+            })
+            .collect();
+        recycle_vec(raw);
+        matched
+    }
+
+    ///
+    /// Iterate through the transactions of blocks `range` matching `filter`,
+    /// in chain order.
+    ///
+    /// The filter is tested on the serialized blocks: scripts and witnesses
+    /// are not copied, and only matching transactions are decoded, which is
+    /// much faster than decoding every block when few transactions match.
+    /// Blocks are read and filtered in parallel.
+    ///
+    /// The iteration ends at the first block that cannot be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{Address, BitcoinDB, BlockFilter, Transaction};
+    /// use std::path::Path;
+    /// use std::str::FromStr;
+    ///
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+    ///
+    /// let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+    /// let filter = BlockFilter {
+    ///     script_pubkeys: vec![address.script_pubkey()].into_iter().collect(),
+    ///     ..Default::default()
+    /// };
+    /// for matched in db.iter_filtered::<Transaction>(0..700000, filter) {
+    ///     println!("{} {} {:?}", matched.height, matched.tx.txid(), matched.matched_outputs);
+    /// }
+    /// ```
+    ///
+    pub fn iter_filtered<T>(
+        &self,
+        range: Range<usize>,
+        filter: BlockFilter,
+    ) -> impl Iterator<Item = MatchedTransaction<T>>
+    where
+        T: From<Transaction> + Send + 'static,
+    {
+        let db = self.clone();
+        let filter = CompiledFilter::new(filter);
+        let heights = range.start..range.end.max(range.start);
+        par_map_ordered(
+            heights,
+            move |h| {
+                db.filter_block::<T>(h, &filter)
+                    .map_err(|e| IterError::new(h, format!("cannot filter block: {}", e)))
+            },
+            ParMapOptions::default(),
+        )
+        .scan((), |_, result| match result {
+            Ok(matched) => Some(matched),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        })
+        .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Block;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use bitcoin::Witness;
+
+    #[test]
+    fn test_iter_filtered() {
+        // block 4 spends the coinbase of block 1 with a witness
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        let funding = chain.block(&tips[0]).unwrap().txdata[0].clone();
+        let mut spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1234);
+        spend.input[0].witness = Witness::from_vec(vec![vec![0x30; 72], vec![0x02; 33]]);
+        let spending = chain.mine(&tips[2], vec![spend.clone()]);
+        chain.extend(&spending, 1);
+        let db = TestDb::new("iter_filtered", &chain);
+
+        // an empty filter matches all transactions
+        let all: Vec<MatchedTransaction<Transaction>> =
+            db.iter_filtered(0..6, BlockFilter::default()).collect();
+        let blocks: Vec<Block> = db.iter_block(0, 6).collect();
+        let expected: Vec<Transaction> = blocks.into_iter().flat_map(|b| b.txdata).collect();
+        assert_eq!(all.into_iter().map(|m| m.tx).collect::<Vec<_>>(), expected);
+
+        // received by a script, and spent
+        let script = funding.output[0].script_pubkey.clone();
+        let filter = BlockFilter {
+            script_pubkeys: vec![script].into_iter().collect(),
+            spent_outpoints: vec![chain.coinbase_outpoint(&tips[0]).unwrap()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let matched: Vec<MatchedTransaction<Transaction>> =
+            db.iter_filtered(0..6, filter).collect();
+        assert_eq!(matched.len(), 2);
+        assert_eq!((matched[0].height, matched[0].index), (1, 0));
+        assert_eq!(matched[0].matched_outputs, vec![0]);
+        assert_eq!(matched[0].tx, funding);
+        assert_eq!((matched[1].height, matched[1].index), (4, 1));
+        assert_eq!(matched[1].block_hash, spending);
+        assert!(matched[1].matched_outputs.is_empty());
+        assert_eq!(matched[1].tx, spend);
+
+        // by txid of a segwit transaction, and by value
+        let filter = BlockFilter {
+            txids: vec![spend.txid()].into_iter().collect(),
+            ..Default::default()
+        };
+        let matched = db.get_filtered::<Transaction>(4, &filter).unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].tx.txid(), spend.txid());
+        let filter = BlockFilter {
+            value_range: Some(1000..2000),
+            ..Default::default()
+        };
+        let matched: Vec<MatchedTransaction<Transaction>> =
+            db.iter_filtered(0..6, filter).collect();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].matched_outputs, vec![0]);
+    }
+}
@@ -0,0 +1,91 @@
+//!
+//! In-memory iteration over block headers with derived timestamps.
+//!
+use crate::api::{BitcoinDB, BlockHash};
+use crate::parser::errors::{OpError, OpResult};
+
+/// number of blocks used for median time past (see BIP113)
+const MEDIAN_TIME_SPAN: usize = 11;
+
+///
+/// Timestamps of a block header.
+///
+/// # Receive Time
+///
+/// Bitcoin Core only keeps the time at which it received a block in memory
+/// (it is not part of the serialized block index on disk), so it cannot be
+/// recovered from the datadir. `time_max` and `median_time_past` are the
+/// time-related values Core derives from the index.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub height: usize,
+    pub block_hash: BlockHash,
+    /// timestamp in block header (set by the miner)
+    pub time: u32,
+    /// median of the timestamps of the last 11 blocks (including this one)
+    pub median_time_past: u32,
+    /// maximum header timestamp from genesis up to this block
+    pub time_max: u32,
+}
+
+impl BitcoinDB {
+    ///
+    /// Get the median time past (BIP113) of a block.
+    ///
+    pub fn get_median_time_past(&self, height: usize) -> OpResult<u32> {
+        let records = &self.block_index.records;
+        if height >= records.len() {
+            return Err(OpError::from("height not found"));
+        }
+        let first = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times = records[first..=height]
+            .iter()
+            .map(|r| r.block_header.time)
+            .collect::<Vec<u32>>();
+        times.sort_unstable();
+        Ok(times[times.len() / 2])
+    }
+
+    ///
+    /// Iterate through header timestamps of blocks from `start` to `end` (excluded).
+    ///
+    /// This is an in-memory iteration, thus very fast.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// // blocks with a timestamp earlier than their median time past
+    /// for h in db.iter_headers(0, db.get_block_count()) {
+    ///     if h.time < h.median_time_past {
+    ///         println!("{}: {} < {}", h.height, h.time, h.median_time_past);
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn iter_headers(&self, start: usize, end: usize) -> impl Iterator<Item = HeaderInfo> + '_ {
+        let end = end.min(self.block_index.records.len());
+        let mut time_max = self.block_index.records[..start.min(end)]
+            .iter()
+            .map(|r| r.block_header.time)
+            .max()
+            .unwrap_or(0);
+        (start..end).map(move |height| {
+            let header = &self.block_index.records[height].block_header;
+            time_max = time_max.max(header.time);
+            HeaderInfo {
+                height,
+                block_hash: header.block_hash(),
+                time: header.time,
+                median_time_past: self.get_median_time_past(height).unwrap(),
+                time_max,
+            }
+        })
+    }
+}
@@ -0,0 +1,279 @@
+//!
+//! Pick up blocks written by the node after `BitcoinDB` was opened.
+//!
+use crate::api::{BitcoinDB, Block, BlockIndex, InnerDB};
+use crate::parser::blk_file::BlkFile;
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::undo_file::UndoFile;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+///
+/// Changes of the available blocks found by `BitcoinDB::refresh`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefreshReport {
+    /// previously available blocks replaced or removed by a reorg
+    pub invalidated: Range<usize>,
+    /// blocks available now but not before (including replacements)
+    pub new_blocks: Range<usize>,
+}
+
+impl RefreshReport {
+    /// whether some previously available blocks were reorganized out
+    pub fn is_reorg(&self) -> bool {
+        !self.invalidated.is_empty()
+    }
+}
+
+///
+/// Item of `NewBlockIter`.
+///
+#[derive(Debug, Clone)]
+pub enum ChainEvent<T> {
+    /// the next block of the main chain
+    Block { height: usize, block: T },
+    ///
+    /// Blocks previously yielded at heights `from_height` and above
+    /// were reorganized out. Their replacements follow.
+    ///
+    Invalidated { from_height: usize },
+}
+
+impl BitcoinDB {
+    ///
+    /// Re-read the block index to pick up blocks (and reorgs)
+    /// written by the node since this `BitcoinDB` was opened.
+    ///
+    /// The whole block index is re-read and the blk files are rescanned,
+    /// the txindex DB is kept open.
+    ///
+    /// The block index is a LevelDB locked by a running `bitcoind`:
+    /// as `BitcoinDB::new`, `refresh` then fails. Following a running
+    /// node is not supported, refresh on a copy of its datadir (synced
+    /// periodically) or on a datadir whose node is stopped.
+    ///
+    /// Only this handle is updated: clones made before keep the
+    /// previous view of the chain, which remains readable as long as
+    /// the node does not prune it.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let mut db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// // ... later
+    /// let report = db.refresh().unwrap();
+    /// if report.is_reorg() {
+    ///     println!("blocks {:?} were reorganized out", report.invalidated);
+    /// }
+    /// println!("new blocks: {:?}", report.new_blocks);
+    /// ```
+    ///
+    pub fn refresh(&mut self) -> OpResult<RefreshReport> {
+        let blk_path = self.datadir.join("blocks");
+        let block_index = BlockIndex::new(&blk_path.join("index"))?;
+        let fresh = BitcoinDB(Arc::new(InnerDB {
+            tx_db: self.tx_db.reindexed(&block_index),
+            block_index,
+            blk_file: BlkFile::new(&blk_path)?,
+            undo_file: UndoFile::new(&blk_path)?,
+            network: self.network,
+            datadir: self.datadir.clone(),
+        }));
+        let old_count = self.get_block_count();
+        let new_count = fresh.get_block_count();
+        // number of blocks in common
+        let mut common = old_count.min(new_count);
+        while common > 0
+            && fresh.block_index.records[common - 1].block_header
+                != self.block_index.records[common - 1].block_header
+        {
+            common -= 1;
+        }
+        *self = fresh;
+        Ok(RefreshReport {
+            invalidated: common..old_count,
+            new_blocks: common..new_count,
+        })
+    }
+
+    ///
+    /// Follow the main chain from block `start`, waiting for new blocks.
+    ///
+    /// The index is refreshed every `poll_interval` once all available
+    /// blocks are yielded (see `refresh` for the datadirs it supports).
+    /// The iterator ends after yielding an error, reading a block or
+    /// refreshing the index, and never ends otherwise: stop consuming it
+    /// to stop following.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, ChainEvent, SBlock};
+    /// use std::path::Path;
+    /// use std::time::Duration;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let start = db.get_block_count();
+    /// for event in db.iter_new_blocks::<SBlock>(start, Duration::from_secs(10)) {
+    ///     match event.unwrap() {
+    ///         ChainEvent::Block { height, block } => {
+    ///             println!("block {}: {} transactions", height, block.txdata.len())
+    ///         }
+    ///         ChainEvent::Invalidated { from_height } => {
+    ///             println!("reorg: blocks from {} are invalid", from_height)
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn iter_new_blocks<T>(&self, start: usize, poll_interval: Duration) -> NewBlockIter<T>
+    where
+        T: From<Block>,
+    {
+        NewBlockIter {
+            db: self.clone(),
+            start,
+            next: start,
+            poll_interval,
+            failed: false,
+            _block: PhantomData,
+        }
+    }
+}
+
+///
+/// Iterator returned by `BitcoinDB::iter_new_blocks`.
+///
+pub struct NewBlockIter<T> {
+    db: BitcoinDB,
+    /// first height to yield
+    start: usize,
+    /// next height to yield
+    next: usize,
+    poll_interval: Duration,
+    /// an error was yielded
+    failed: bool,
+    _block: PhantomData<fn() -> T>,
+}
+
+impl<T> NewBlockIter<T> {
+    /// the database yielded blocks are read from
+    pub fn db(&self) -> &BitcoinDB {
+        &self.db
+    }
+
+    fn fail(&mut self, e: OpError) -> Option<OpResult<ChainEvent<T>>> {
+        self.failed = true;
+        Some(Err(e))
+    }
+}
+
+impl<T> Iterator for NewBlockIter<T>
+where
+    T: From<Block>,
+{
+    type Item = OpResult<ChainEvent<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            if self.next < self.db.get_block_count() {
+                match self.db.get_block::<T>(self.next) {
+                    Ok(block) => {
+                        let height = self.next;
+                        self.next += 1;
+                        return Some(Ok(ChainEvent::Block { height, block }));
+                    }
+                    Err(e) => {
+                        let msg = format!(" (reading block {})", self.next);
+                        return self.fail(e.join_msg(&msg));
+                    }
+                }
+            }
+            thread::sleep(self.poll_interval);
+            match self.db.refresh() {
+                Ok(report) => {
+                    let from_height = report.invalidated.start.max(self.start);
+                    if report.is_reorg() && from_height < self.next {
+                        self.next = from_height;
+                        return Some(Ok(ChainEvent::Invalidated { from_height }));
+                    }
+                }
+                Err(e) => return self.fail(e.join_msg(" (refreshing the block index)")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::BlockHash;
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    #[test]
+    fn test_refresh_and_tail() {
+        let dir = TestDir::new("live");
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        chain.write(&dir).unwrap();
+        let mut db = BitcoinDB::new(&dir, false).unwrap();
+        let old_db = db.clone();
+
+        let mut tail = db.iter_new_blocks::<Block>(2, Duration::from_millis(1));
+        let mut hashes = |n: usize| -> Vec<(usize, BlockHash)> {
+            (0..n)
+                .map(|_| match tail.next().unwrap().unwrap() {
+                    ChainEvent::Block { height, block } => (height, block.block_hash()),
+                    ChainEvent::Invalidated { from_height } => (from_height, BlockHash::default()),
+                })
+                .collect()
+        };
+        let old = chain.main_chain();
+        assert_eq!(hashes(2), vec![(2, old[2]), (3, old[3])]);
+
+        // new blocks
+        let tips = chain.extend(&tips[2], 1);
+        chain.write(&dir).unwrap();
+        let report = db.refresh().unwrap();
+        assert_eq!((report.invalidated, report.new_blocks), (4..4, 4..5));
+        assert_eq!(old_db.get_block_count(), 4);
+        assert_eq!(db.get_block_count(), 5);
+        assert_eq!(hashes(1), vec![(4, tips[0])]);
+
+        // a longer branch from height 2
+        chain.extend(&old[2], 3);
+        chain.write(&dir).unwrap();
+        let new = chain.main_chain();
+        let report = db.refresh().unwrap();
+        assert!(report.is_reorg());
+        assert_eq!((report.invalidated, report.new_blocks), (3..5, 3..6));
+        assert_eq!(
+            hashes(4),
+            vec![
+                (3, BlockHash::default()),
+                (3, new[3]),
+                (4, new[4]),
+                (5, new[5])
+            ]
+        );
+
+        // failures to refresh are yielded, and end the iteration
+        std::fs::remove_dir_all(dir.join("blocks").join("index")).unwrap();
+        assert!(tail.next().unwrap().is_err());
+        assert!(tail.next().is_none());
+    }
+}
@@ -20,26 +20,76 @@
 //! ```
 //!
 
+mod chain_view;
+mod coinbase;
 mod connected;
+mod filter;
+mod headers;
+mod live;
+mod pagination;
+mod partition;
+mod prefetch;
+mod probe;
+mod sampling;
+mod search;
+mod slice;
+mod verify;
 
+use crate::iter::{recycle_vec, ParMapOptions};
 use crate::parser::blk_file::BlkFile;
-use crate::parser::errors::{OpError, OpResult};
-use crate::parser::script::{evaluate_script, ScriptInfo};
+use crate::parser::era::{decode_block, BlockEra};
+use crate::parser::errors::{OpError, OpErrorKind, OpResult, TxIndexAlternative};
+use crate::parser::script::{address_network, evaluate_script, with_address_network, ScriptInfo};
 use crate::parser::tx_index::TxDB;
+use crate::parser::undo_file::UndoFile;
+pub use chain_view::ChainView;
+pub use filter::{BlockFilter, MatchedTransaction};
+pub use headers::HeaderInfo;
+pub use live::{ChainEvent, NewBlockIter, RefreshReport};
+pub use pagination::{BlockSummary, TxSummary};
+pub use partition::{partition_snapshot_path, PartitionStrategy};
+pub use prefetch::{PrefetchBudget, PrefetchHandle};
+pub use probe::DatadirReport;
+use rayon::prelude::*;
+pub use sampling::SampleStrategy;
+pub(crate) use sampling::{sample_uniform, SplitMix64};
+pub use search::SearchResult;
+use std::collections::BTreeMap;
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+pub use verify::{HeaderInconsistency, HeaderInconsistencyKind};
 // re-exports
-pub use crate::iter::{BlockIter, ConnectedBlockIter};
+pub use crate::iter::{
+    BlockIter, ConnectedBlockIter, ConnectedBlockIterOptions, IterError, OnBadData,
+};
+pub use crate::parser::asm::{
+    script_ops, script_sig_to_asm, script_to_asm, ScriptOp, ScriptOps, TruncatedPush,
+};
+pub use crate::parser::blk_file::{BlkStorage, BlockLocation, TxLocation};
+#[cfg(feature = "mmap")]
+pub use crate::parser::blk_mmap::MappedBytes;
 pub use crate::parser::block_index::{BlockIndex, BlockIndexRecord};
+pub use crate::parser::core_json::{
+    core_script_type, descriptor_checksum, script_pubkey_json, tx_to_corelike_json, CoreBlockInfo,
+    CorePrevout, CoreTxContext,
+};
+pub use crate::parser::proto::amount::{checked_sum, AmountFormat};
 pub use crate::parser::proto::connected_proto::{
     ConnectedBlock, ConnectedTx, FConnectedBlock, FConnectedTransaction, SConnectedBlock,
     SConnectedTransaction,
 };
 pub use crate::parser::proto::full_proto::{FBlock, FBlockHeader, FTransaction, FTxOut};
+pub use crate::parser::proto::legacy_proto::{
+    serialize_block_legacy, serialize_tx_legacy, LegacyBlock, LegacyTransaction,
+};
 pub use crate::parser::proto::simple_proto::{SBlock, SBlockHeader, STransaction, STxOut};
+pub use crate::parser::undo_file::{BlockUndo, SpentOutput};
 pub use bitcoin::hashes::hex::{FromHex, ToHex};
-pub use bitcoin::{Address, Block, BlockHash, BlockHeader, Network, Script, Transaction, Txid};
+pub use bitcoin::{
+    Address, Amount, Block, BlockHash, BlockHeader, Denomination, Network, Script, Transaction,
+    Txid, Wtxid,
+};
 
 ///
 /// Extract addresses from a script public key.
@@ -52,16 +102,53 @@ pub fn parse_script(script_pub_key: &str) -> OpResult<ScriptInfo> {
 ///
 /// Extract addresses from a script public key.
 ///
+/// Addresses are mainnet addresses, unless called within
+/// `with_address_network`.
+///
 #[inline]
 pub fn get_addresses_from_script(script_pub_key: &str) -> OpResult<ScriptInfo> {
     let script = Script::from_hex(script_pub_key)?;
-    Ok(evaluate_script(&script, Network::Bitcoin))
+    Ok(evaluate_script(&script, address_network()))
+}
+
+///
+/// Directory of `network` under a datadir (Core puts non-mainnet
+/// data in a subdirectory), or `p` itself if it holds blocks.
+///
+fn network_datadir(p: &Path, network: Option<Network>) -> PathBuf {
+    let candidates: &[Network] = match network {
+        Some(Network::Bitcoin) => &[],
+        Some(ref network) => std::slice::from_ref(network),
+        None if p.join("blocks").exists() => &[],
+        None => &[Network::Testnet, Network::Signet, Network::Regtest],
+    };
+    candidates
+        .iter()
+        .map(|network| p.join(network_dir_name(*network)))
+        .find(|dir| dir.join("blocks").exists())
+        .unwrap_or_else(|| p.to_path_buf())
+}
+
+///
+/// Name of the datadir subdirectory of `network` used by Bitcoin Core.
+///
+pub(crate) fn network_dir_name(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "",
+        Network::Testnet => "testnet3",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+    }
 }
 
 pub struct InnerDB {
     pub block_index: BlockIndex,
     pub blk_file: BlkFile,
     pub tx_db: TxDB,
+    pub undo_file: UndoFile,
+    pub network: Network,
+    /// datadir of the network, for `refresh`
+    pub datadir: PathBuf,
 }
 
 ///
@@ -88,7 +175,10 @@ impl BitcoinDB {
     ///
     /// Instantiating this class by passing the `-datadir` directory of
     /// Bitcoin core to the `new()` method.
-    /// `tx_index`: whether to try to open tx_index levelDB.
+    /// `tx_index`: whether to try to open tx_index levelDB, or else
+    /// the `TxIndex` at `default_tx_index_path` if built
+    /// (feature `on-disk-utxo`).
+    /// Without either, only txid lookups fail (see `with_tx_index_fallback`).
     ///
     /// # Example
     ///
@@ -105,10 +195,52 @@ impl BitcoinDB {
     /// let db = BitcoinDB::new(path, true).unwrap();
     /// ```
     pub fn new(p: &Path, tx_index: bool) -> OpResult<BitcoinDB> {
+        BitcoinDB::open(p, tx_index, None)
+    }
+
+    ///
+    /// Open the datadir of a given network.
+    ///
+    /// `p` may be the network specific directory (e.g. `~/.bitcoin/testnet3`)
+    /// or its parent. Fails if the blk files belong to another network.
+    /// `BitcoinDB::new` detects the network from the blk files instead,
+    /// and looks into `testnet3`, `signet` and `regtest` when `p`
+    /// has no `blocks` directory.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, Network};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    ///
+    /// // opens /Users/me/bitcoin/signet
+    /// let db = BitcoinDB::new_with_network(path, false, Network::Signet).unwrap();
+    /// assert_eq!(db.network(), Network::Signet);
+    /// ```
+    ///
+    pub fn new_with_network(p: &Path, tx_index: bool, network: Network) -> OpResult<BitcoinDB> {
+        BitcoinDB::open(p, tx_index, Some(network))
+    }
+
+    fn open(p: &Path, tx_index: bool, network: Option<Network>) -> OpResult<BitcoinDB> {
         if !p.exists() {
             return Err(OpError::from("data_dir does not exist"));
         }
+        let p = network_datadir(p, network);
         let blk_path = p.join("blocks");
+        let blk_file = BlkFile::new(blk_path.as_path())?;
+        let detected = blk_file.detect_network()?;
+        let network = match (network, detected) {
+            (Some(expected), Some(found)) if expected != found => {
+                return Err(OpError::from(
+                    format!("blk files are of network {}, not {}", found, expected).as_str(),
+                ));
+            }
+            (Some(network), _) => network,
+            (None, found) => found.unwrap_or(Network::Bitcoin),
+        };
         let index_path = blk_path.join("index");
         let block_index = BlockIndex::new(index_path.as_path())?;
         let tx_db = if tx_index {
@@ -119,10 +251,61 @@ impl BitcoinDB {
         };
         let inner = InnerDB {
             block_index,
-            blk_file: BlkFile::new(blk_path.as_path())?,
+            blk_file,
             tx_db,
+            undo_file: UndoFile::new(blk_path.as_path())?,
+            network,
+            datadir: p,
         };
-        Ok(BitcoinDB(Arc::new(inner)))
+        let db = BitcoinDB(Arc::new(inner));
+        #[cfg(feature = "on-disk-utxo")]
+        let db = if tx_index {
+            db.with_tx_index_fallback(&db.default_tx_index_path())
+        } else {
+            db
+        };
+        Ok(db)
+    }
+
+    ///
+    /// The same datadir, with txid lookups answered by `tx_db`.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub(crate) fn with_tx_db(&self, tx_db: TxDB) -> BitcoinDB {
+        BitcoinDB(Arc::new(InnerDB {
+            block_index: self.block_index.clone(),
+            blk_file: self.blk_file.clone(),
+            tx_db,
+            undo_file: self.undo_file.clone(),
+            network: self.network,
+            datadir: self.datadir.clone(),
+        }))
+    }
+
+    ///
+    /// Network of the opened datadir.
+    ///
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    ///
+    /// Run `f` with the addresses of the proto types it converts
+    /// derived for the network of this datadir.
+    ///
+    pub(crate) fn convert<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        with_address_network(self.network, f)
+    }
+
+    ///
+    /// Key of `blocks/xor.dat`, with which Bitcoin Core v28+ obfuscates
+    /// blk and rev files: byte `i` of a file is XORed with `key[i % 8]`.
+    /// `None` if the blocks directory is not obfuscated.
+    ///
+    /// Blocks read through `BitcoinDB` are always deobfuscated.
+    ///
+    pub fn blk_xor_key(&self) -> Option<[u8; 8]> {
+        self.blk_file.xor_key()
     }
 
     ///
@@ -213,6 +396,31 @@ impl BitcoinDB {
         }
     }
 
+    ///
+    /// Whether the block of `hash` is in the main chain.
+    ///
+    /// The block index only keeps main chain blocks,
+    /// so this is a single in-memory hash lookup.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, BlockHash, FromHex};
+    /// use std::path::Path;
+    ///
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+    ///
+    /// let hash = BlockHash::from_hex(
+    ///     "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    /// ).unwrap();
+    /// assert!(db.is_main_chain(&hash));
+    /// ```
+    ///
+    #[inline]
+    pub fn is_main_chain(&self, hash: &BlockHash) -> bool {
+        self.block_index.hash_to_height.contains_key(hash)
+    }
+
     ///
     /// Get a raw block as bytes
     ///
@@ -227,6 +435,82 @@ impl BitcoinDB {
         }
     }
 
+    ///
+    /// Get the location (blk file, offset, size) of a block.
+    ///
+    /// Useful for scheduling I/O manually, or slicing raw block bytes
+    /// directly out of blk files. Bytes of obfuscated blocks directories
+    /// (Bitcoin Core v28+) must be XORed with `blk_xor_key()`, and offsets
+    /// in archived blk files do not address bytes on disk (see `BlkStorage`).
+    ///
+    /// # Example
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, BlkStorage};
+    /// use std::fs::File;
+    /// use std::io::{Read, Seek, SeekFrom};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    ///
+    /// // launch without reading txindex
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let loc = db.get_block_location(600000).unwrap();
+    /// assert_eq!(loc.storage, BlkStorage::Plain);
+    /// let mut f = File::open(&loc.path).unwrap();
+    /// f.seek(SeekFrom::Start(loc.offset as u64)).unwrap();
+    /// let mut raw = vec![0u8; loc.size as usize];
+    /// f.read_exact(&mut raw).unwrap();
+    /// ```
+    ///
+    pub fn get_block_location(&self, height: usize) -> OpResult<BlockLocation> {
+        if let Some(index) = self.block_index.records.get(height) {
+            self.blk_file.locate_block(index.n_file, index.n_data_pos)
+        } else {
+            Err(OpError::from("height not found"))
+        }
+    }
+
+    ///
+    /// Get a raw block as bytes of the memory mapped blk file, without copy.
+    ///
+    /// Requires feature `mmap`. Fails for archived or obfuscated blk files.
+    ///
+    /// # Example
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, Block};
+    /// use bitcoin::consensus::deserialize;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    ///
+    /// // launch without reading txindex
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let raw = db.get_mapped_block(600000).unwrap();
+    /// let block: Block = deserialize(&raw).unwrap();
+    /// ```
+    ///
+    #[cfg(feature = "mmap")]
+    pub fn get_mapped_block(&self, height: usize) -> OpResult<MappedBytes> {
+        if let Some(index) = self.block_index.records.get(height) {
+            self.blk_file.map_block(index.n_file, index.n_data_pos)
+        } else {
+            Err(OpError::from("height not found"))
+        }
+    }
+
+    ///
+    /// Get `len` bytes at `offset` of `blk{n_file}.dat`, from its memory map.
+    ///
+    /// Requires feature `mmap`. Fails for archived or obfuscated blk files,
+    /// and for ranges past the end of the file.
+    ///
+    #[cfg(feature = "mmap")]
+    pub fn get_blk_bytes(&self, n_file: i32, offset: u64, len: usize) -> OpResult<MappedBytes> {
+        self.blk_file.map_range(n_file, offset, len)
+    }
+
     ///
     /// Get a block (in different formats (Block, FBlock, SBlock))
     ///
@@ -248,13 +532,134 @@ impl BitcoinDB {
     ///
     pub fn get_block<T: From<Block>>(&self, height: usize) -> OpResult<T> {
         if let Some(index) = self.block_index.records.get(height) {
-            let blk = self.blk_file.read_block(index.n_file, index.n_data_pos)?;
-            Ok(blk.into())
+            let blk = self.blk_file.read_block(
+                index.n_file,
+                index.n_data_pos,
+                BlockEra::of_height(height, self.network),
+            )?;
+            Ok(self.convert(|| blk.into()))
+        } else {
+            Err(OpError::from("height not found"))
+        }
+    }
+
+    ///
+    /// Get transaction `tx_index` (position in its block) of block `height`.
+    ///
+    /// The transactions before it are skipped by their lengths and only
+    /// the requested transaction is decoded, which is much faster than
+    /// `get_block` for random access into large blocks.
+    /// No txindex is needed.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, Transaction};
+    /// use std::path::Path;
+    ///
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+    ///
+    /// // the 1000th transaction of block 700000
+    /// let tx: Transaction = db.get_transaction_at(700000, 999).unwrap();
+    /// ```
+    ///
+    pub fn get_transaction_at<T: From<Transaction>>(
+        &self,
+        height: usize,
+        tx_index: usize,
+    ) -> OpResult<T> {
+        if let Some(index) = self.block_index.records.get(height) {
+            let tx = self
+                .blk_file
+                .read_transaction_at(index.n_file, index.n_data_pos, tx_index)?;
+            Ok(self.convert(|| tx.into()))
+        } else {
+            Err(OpError::from("height not found"))
+        }
+    }
+
+    ///
+    /// Same as `get_block`, with the serialized size of the block.
+    ///
+    pub(crate) fn get_block_sized<T: From<Block>>(&self, height: usize) -> OpResult<(T, usize)> {
+        if let Some(index) = self.block_index.records.get(height) {
+            let (blk, size) = self.blk_file.read_block_sized(
+                index.n_file,
+                index.n_data_pos,
+                BlockEra::of_height(height, self.network),
+            )?;
+            Ok((self.convert(|| blk.into()), size))
         } else {
             Err(OpError::from("height not found"))
         }
     }
 
+    ///
+    /// Get several blocks at once, results are in the order of `heights`.
+    ///
+    /// Requested blocks are grouped by blk file and read in file order,
+    /// so that close blocks are read sequentially instead of randomly.
+    /// Different blk files are read in parallel.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// // a page of 50 blocks
+    /// let heights: Vec<usize> = (600000..600050).rev().collect();
+    /// for block in db.get_blocks::<SBlock>(&heights) {
+    ///     println!("{}", block.unwrap().header.block_hash);
+    /// }
+    /// ```
+    ///
+    pub fn get_blocks<T: From<Block> + Send>(&self, heights: &[usize]) -> Vec<OpResult<T>> {
+        // (offset, request position, era) grouped by file
+        let mut by_file: BTreeMap<i32, Vec<(u32, usize, BlockEra)>> = BTreeMap::new();
+        let mut results: Vec<Option<OpResult<T>>> = Vec::with_capacity(heights.len());
+        for (i, height) in heights.iter().enumerate() {
+            match self.block_index.records.get(*height) {
+                Some(index) => {
+                    by_file.entry(index.n_file).or_default().push((
+                        index.n_data_pos,
+                        i,
+                        BlockEra::of_height(*height, self.network),
+                    ));
+                    results.push(None);
+                }
+                None => results.push(Some(Err(OpError::from("height not found")))),
+            }
+        }
+        let read: Vec<(usize, OpResult<T>)> = by_file
+            .into_par_iter()
+            .flat_map_iter(|(n_file, mut requests)| {
+                requests.sort_unstable_by_key(|r| (r.0, r.1));
+                let offsets: Vec<u32> = requests.iter().map(|(offset, _, _)| *offset).collect();
+                self.blk_file
+                    .read_raw_blocks(n_file, &offsets)
+                    .into_iter()
+                    .zip(requests)
+                    .map(|(raw, (_, i, era))| {
+                        let block = raw.and_then(|raw| {
+                            let block = decode_block(&raw, era);
+                            recycle_vec(raw);
+                            block
+                        });
+                        (i, block.map(|b| self.convert(|| b.into())))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        for (i, block) in read {
+            results[i] = Some(block);
+        }
+        results.into_iter().map(|r| r.unwrap()).collect()
+    }
+
     ///
     /// Get a transaction by providing txid.
     ///
@@ -265,6 +670,9 @@ impl BitcoinDB {
     /// A transaction cannot be found using this function if it is
     /// not yet indexed using `txindex`.
     ///
+    /// Without `txindex` (nor a `TxIndex` attached, see `with_tx_index_fallback`),
+    /// fails with `OpErrorKind::TxIndexUnavailable` listing the alternatives.
+    ///
     /// # Example
     /// ```rust
     /// use bitcoin_explorer::{BitcoinDB, Transaction, FTransaction, STransaction, Txid, FromHex};
@@ -288,17 +696,62 @@ impl BitcoinDB {
     ///
     pub fn get_transaction<T: From<Transaction>>(&self, txid: &Txid) -> OpResult<T> {
         if !self.tx_db.is_open() {
-            return Err(OpError::from("TxDB not open"));
+            return Err(self.tx_index_unavailable());
         }
         // give special treatment for genesis transaction
         if self.tx_db.is_genesis_tx(txid) {
-            return Ok(self.get_block::<Block>(0)?.txdata.swap_remove(0).into());
+            let genesis = self.get_block::<Block>(0)?.txdata.swap_remove(0);
+            return Ok(self.convert(|| genesis.into()));
         }
         let record = self.tx_db.get_tx_record(txid)?;
         let tx = self
             .blk_file
             .read_transaction(record.n_file, record.n_pos, record.n_tx_offset)?;
-        Ok(tx.into())
+        Ok(self.convert(|| tx.into()))
+    }
+
+    ///
+    /// Get the location (blk file, offset, size) of a transaction.
+    ///
+    /// This function requires `txindex` to be set to `true` for `BitcoinDB`,
+    /// and requires that flag `txindex=1` has been enabled when
+    /// running Bitcoin Core.
+    ///
+    pub fn get_tx_location(&self, txid: &Txid) -> OpResult<TxLocation> {
+        if !self.tx_db.is_open() {
+            return Err(self.tx_index_unavailable());
+        }
+        // genesis transaction is the first transaction of block 0
+        if self.tx_db.is_genesis_tx(txid) {
+            let index = self.get_header(0)?;
+            // 1 byte varint for the transaction count of genesis block
+            return self
+                .blk_file
+                .locate_transaction(index.n_file, index.n_data_pos, 1);
+        }
+        let record = self.tx_db.get_tx_record(txid)?;
+        self.blk_file
+            .locate_transaction(record.n_file, record.n_pos, record.n_tx_offset)
+    }
+
+    ///
+    /// Error of txid lookups without txindex, listing the alternatives.
+    ///
+    pub(crate) fn tx_index_unavailable(&self) -> OpError {
+        let mut alternatives = Vec::new();
+        let core_tx_index = self.datadir.join("indexes").join("txindex");
+        if core_tx_index.exists() {
+            alternatives.push(TxIndexAlternative::CoreTxIndex(core_tx_index));
+        } else {
+            alternatives.push(TxIndexAlternative::EnableCoreTxIndex);
+        }
+        #[cfg(feature = "on-disk-utxo")]
+        match self.tx_db.stale_index() {
+            Some(path) => alternatives.push(TxIndexAlternative::UpdateTxIndex(path.to_path_buf())),
+            None => alternatives.push(TxIndexAlternative::BuildTxIndex),
+        }
+        alternatives.push(TxIndexAlternative::TxBloomIndex);
+        OpError::new(OpErrorKind::TxIndexUnavailable(alternatives))
     }
 
     ///
@@ -313,7 +766,7 @@ impl BitcoinDB {
     ///
     pub fn get_height_of_transaction(&self, txid: &Txid) -> OpResult<usize> {
         if !self.tx_db.is_open() {
-            return Err(OpError::from("TxDB not open"));
+            return Err(self.tx_index_unavailable());
         }
         self.tx_db.get_block_height_of_tx(txid)
     }
@@ -377,6 +830,44 @@ impl BitcoinDB {
         BlockIter::from_range(self, start, end)
     }
 
+    ///
+    /// Same as `iter_block`, with the worker threads and
+    /// queue length (backpressure) of `options`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::iter::ParMapOptions;
+    /// use bitcoin_explorer::{BitcoinDB, SBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let options = ParMapOptions {
+    ///     threads: 2,
+    ///     buffer: 8,
+    /// };
+    /// let mut iter = db.iter_block_with_options::<SBlock>(600000, 700000, options);
+    /// for block in iter.results() {
+    ///     if let Err(e) = block {
+    ///         eprintln!("stopped at height {}", e.height);
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn iter_block_with_options<T>(
+        &self,
+        start: usize,
+        end: usize,
+        options: ParMapOptions,
+    ) -> BlockIter<T>
+    where
+        T: From<Block> + Send + 'static,
+    {
+        BlockIter::with_options(self, start..end.max(start), options)
+    }
+
     ///
     /// Iterate through all blocks of given heights.
     ///
@@ -443,3 +934,56 @@ impl BitcoinDB {
         BlockIter::new(self, heights)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    #[test]
+    fn test_network_datadir() {
+        let dir = TestDir::new("network");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 2);
+        chain.write_network(&dir, Network::Regtest).unwrap();
+
+        // the regtest subdirectory is found and the network detected
+        let db = BitcoinDB::new(&dir, false).unwrap();
+        assert_eq!(db.network(), Network::Regtest);
+        assert_eq!(db.get_block_count(), 3);
+        let db = BitcoinDB::new(&dir.join("regtest"), false).unwrap();
+        assert_eq!(db.network(), Network::Regtest);
+        let db = BitcoinDB::new_with_network(&dir, false, Network::Regtest).unwrap();
+        assert_eq!(db.get_block_count(), 3);
+
+        // blk files of another network are rejected
+        let regtest = dir.join("regtest");
+        assert!(BitcoinDB::new_with_network(&regtest, false, Network::Testnet).is_err());
+        assert!(BitcoinDB::new_with_network(&regtest, false, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_obfuscated_blocks_dir() {
+        use crate::parser::blk_scan::ScanOptions;
+
+        let dir = TestDir::new("xor");
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        chain.mine(&tips[1], vec![spend]);
+        let key = [0x5a, 0x01, 0xff, 0x10, 0x22, 0x00, 0x9c, 0x3e];
+        chain.write_obfuscated(&dir, key).unwrap();
+
+        let db = BitcoinDB::new(&dir, false).unwrap();
+        assert_eq!(db.blk_xor_key(), Some(key));
+        assert_eq!(db.network(), Network::Bitcoin);
+        for (height, hash) in chain.main_chain().iter().enumerate() {
+            let block: Block = db.get_block(height).unwrap();
+            assert_eq!(&block, chain.block(hash).unwrap());
+        }
+        assert_eq!(db.get_block_undo(3).unwrap().txs[0][0].height, 1);
+        let scan = db.scan_blk_file(0, &ScanOptions::default()).unwrap();
+        assert_eq!(scan.blocks.len(), 4);
+        assert!(scan.is_intact());
+    }
+}
@@ -0,0 +1,126 @@
+//!
+//! Keyset pagination for explorer-style consumers.
+//!
+use crate::api::{BitcoinDB, BlockHash, Txid};
+use crate::parser::errors::OpResult;
+use bitcoin::Block;
+
+///
+/// Lightweight summary of a block, read from the block index only.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSummary {
+    pub height: usize,
+    pub block_hash: BlockHash,
+    pub time: u32,
+    pub n_tx: u32,
+}
+
+///
+/// Lightweight summary of a transaction.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxSummary {
+    /// position of the transaction in its block
+    pub index: usize,
+    pub txid: Txid,
+    pub n_input: usize,
+    pub n_output: usize,
+    /// sum of output values (sat)
+    pub output_value: u64,
+    pub weight: usize,
+}
+
+impl BitcoinDB {
+    ///
+    /// List at most `limit` blocks, newest first.
+    ///
+    /// Starts from the tip if `before_height` is `None`, otherwise from the
+    /// block below `before_height`. Pass the height of the last block of a
+    /// page to get the next page.
+    ///
+    /// This only reads the in-memory block index, thus very fast.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let first_page = db.list_blocks(None, 20);
+    /// let next_page = db.list_blocks(first_page.last().map(|b| b.height), 20);
+    /// ```
+    ///
+    pub fn list_blocks(&self, before_height: Option<usize>, limit: usize) -> Vec<BlockSummary> {
+        let end = before_height
+            .unwrap_or(usize::MAX)
+            .min(self.get_block_count());
+        (end.saturating_sub(limit)..end)
+            .rev()
+            .map(|height| {
+                let record = &self.block_index.records[height];
+                BlockSummary {
+                    height,
+                    block_hash: record.block_header.block_hash(),
+                    time: record.block_header.time,
+                    n_tx: record.n_tx,
+                }
+            })
+            .collect()
+    }
+
+    ///
+    /// List at most `limit` transactions of a block, in block order.
+    ///
+    /// Starts from the first transaction if `after_index` is `None`,
+    /// otherwise from the transaction after `after_index`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let hash = db.get_hash_from_height(600000).unwrap();
+    /// let first_page = db.list_txs_of_block(&hash, None, 50).unwrap();
+    /// let next_page = db
+    ///     .list_txs_of_block(&hash, first_page.last().map(|t| t.index), 50)
+    ///     .unwrap();
+    /// ```
+    ///
+    pub fn list_txs_of_block(
+        &self,
+        hash: &BlockHash,
+        after_index: Option<usize>,
+        limit: usize,
+    ) -> OpResult<Vec<TxSummary>> {
+        let height = self.get_height_from_hash(hash)?;
+        let start = after_index.map(|i| i + 1).unwrap_or(0);
+        let n_tx = self.block_index.records[height].n_tx as usize;
+        if start >= n_tx || limit == 0 {
+            return Ok(Vec::new());
+        }
+        let block = self.get_block::<Block>(height)?;
+        Ok(block
+            .txdata
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(limit)
+            .map(|(index, tx)| TxSummary {
+                index,
+                txid: tx.txid(),
+                n_input: tx.input.len(),
+                n_output: tx.output.len(),
+                output_value: tx.output.iter().map(|o| o.value).sum(),
+                weight: tx.weight(),
+            })
+            .collect())
+    }
+}
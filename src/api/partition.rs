@@ -0,0 +1,179 @@
+//!
+//! Split the chain into balanced height ranges, to shard
+//! full-chain jobs across processes or machines.
+//!
+use crate::api::BitcoinDB;
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::UtxoView;
+use rayon::prelude::*;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+///
+/// What the ranges of `BitcoinDB::partition_heights` are balanced by.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionStrategy {
+    /// number of blocks
+    Blocks,
+    /// number of transactions (from the block index, no block is read)
+    Transactions,
+    /// serialized block sizes (reads the size prefix of every block)
+    Bytes,
+}
+
+impl BitcoinDB {
+    ///
+    /// Split all heights into (at most) `n_workers` contiguous ranges of
+    /// about equal work according to `strategy`.
+    ///
+    /// Ranges are ordered, non-empty, and cover `0..get_block_count()`.
+    /// Transaction counts grow by orders of magnitude along the chain,
+    /// so `Transactions` or `Bytes` give far better balanced
+    /// shards than `Blocks`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, PartitionStrategy};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let shards = db.partition_heights(8, PartitionStrategy::Transactions).unwrap();
+    /// let worker_id = 3;
+    /// for block in db.iter_block::<bitcoin_explorer::SBlock>(shards[worker_id].start, shards[worker_id].end) {
+    ///     // ...
+    /// }
+    /// ```
+    ///
+    pub fn partition_heights(
+        &self,
+        n_workers: usize,
+        strategy: PartitionStrategy,
+    ) -> OpResult<Vec<Range<usize>>> {
+        self.partition_range(0..self.get_block_count(), n_workers, strategy)
+    }
+
+    ///
+    /// Split `range` into (at most) `n_workers` balanced ranges,
+    /// see `partition_heights`.
+    ///
+    pub fn partition_range(
+        &self,
+        range: Range<usize>,
+        n_workers: usize,
+        strategy: PartitionStrategy,
+    ) -> OpResult<Vec<Range<usize>>> {
+        if n_workers == 0 {
+            return Err(OpError::from("n_workers must be positive"));
+        }
+        if range.end > self.get_block_count() {
+            return Err(OpError::from("height not found"));
+        }
+        let weights: Vec<u64> = match strategy {
+            PartitionStrategy::Blocks => vec![1; range.len()],
+            PartitionStrategy::Transactions => range
+                .clone()
+                .map(|h| self.get_header(h).map(|r| r.n_tx as u64))
+                .collect::<OpResult<_>>()?,
+            PartitionStrategy::Bytes => range
+                .clone()
+                .into_par_iter()
+                .map(|h| self.get_block_location(h).map(|l| l.size as u64))
+                .collect::<OpResult<_>>()?,
+        };
+        Ok(balanced_ranges(range.start, &weights, n_workers))
+    }
+
+    ///
+    /// Bootstrap the workers of stateful (UTXO dependent) jobs:
+    /// replay the chain once and write the `UtxoView` at the start
+    /// of each partition to `dir` (see `partition_snapshot_path`).
+    ///
+    /// A worker then restores its starting state with
+    /// `UtxoView::read_snapshot` instead of replaying all earlier blocks.
+    ///
+    /// The full UTXO set is kept in memory (see `UtxoView`).
+    /// Returns the snapshot paths, in the order of `partitions`.
+    ///
+    pub fn write_partition_snapshots(
+        &self,
+        partitions: &[Range<usize>],
+        dir: &Path,
+    ) -> OpResult<Vec<PathBuf>> {
+        std::fs::create_dir_all(dir)?;
+        let mut starts: Vec<usize> = partitions.iter().map(|r| r.start).collect();
+        starts.sort_unstable();
+        starts.dedup();
+        let mut view = UtxoView::new();
+        for start in starts {
+            view.advance_to(self, start)?;
+            view.write_snapshot(&partition_snapshot_path(dir, start))?;
+        }
+        Ok(partitions
+            .iter()
+            .map(|r| partition_snapshot_path(dir, r.start))
+            .collect())
+    }
+}
+
+///
+/// Path of the `UtxoView` snapshot before block `start`
+/// written by `BitcoinDB::write_partition_snapshots`.
+///
+pub fn partition_snapshot_path(dir: &Path, start: usize) -> PathBuf {
+    dir.join(format!("utxo-{}.snapshot", start))
+}
+
+///
+/// Cut `weights` (of heights from `start`) into `n` contiguous ranges,
+/// each ending once the cumulated weight reaches its share of the total.
+///
+fn balanced_ranges(start: usize, weights: &[u64], n: usize) -> Vec<Range<usize>> {
+    if weights.is_empty() {
+        return Vec::new();
+    }
+    let n = n.min(weights.len());
+    let total: u128 = weights.iter().map(|w| *w as u128).sum();
+    let mut ranges = Vec::with_capacity(n);
+    let mut begin = 0;
+    let mut acc: u128 = 0;
+    for (i, w) in weights.iter().enumerate() {
+        acc += *w as u128;
+        let k = ranges.len() + 1;
+        if k == n {
+            break;
+        }
+        // leave at least one height to each remaining range
+        let remaining = weights.len() - (i + 1);
+        if acc * n as u128 >= total * k as u128 || remaining == n - k {
+            ranges.push(start + begin..start + i + 1);
+            begin = i + 1;
+        }
+    }
+    ranges.push(start + begin..start + weights.len());
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::balanced_ranges;
+
+    #[test]
+    fn test_balanced_ranges() {
+        assert_eq!(balanced_ranges(0, &[1; 10], 3), vec![0..4, 4..7, 7..10]);
+        assert_eq!(
+            balanced_ranges(100, &[1, 1, 1, 1, 4, 4], 2),
+            vec![100..105, 105..106]
+        );
+        // the heavy block gets a range of its own
+        assert_eq!(
+            balanced_ranges(0, &[1, 1, 1, 100], 3),
+            vec![0..2, 2..3, 3..4]
+        );
+        assert_eq!(balanced_ranges(0, &[1, 1], 5), vec![0..1, 1..2]);
+        assert!(balanced_ranges(0, &[], 5).is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+//!
+//! Hint the OS about upcoming block reads.
+//!
+use crate::api::{BitcoinDB, BlkStorage, BlockLocation};
+use crate::index::{BuildMonitor, BuildOptions};
+use crate::iter::recycle_vec;
+use crate::parser::errors::{OpError, OpResult};
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs::File;
+#[cfg(not(target_os = "linux"))]
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+///
+/// Limits of `BitcoinDB::background_prefetch`.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefetchBudget {
+    /// read rate limit (bytes per second), `None` for unlimited
+    pub max_bytes_per_sec: Option<u64>,
+    /// stop after reading this many bytes, `None` for the whole range
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Default)]
+struct PrefetchState {
+    cancelled: AtomicBool,
+    blocks: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+///
+/// Handle of a running `background_prefetch`.
+///
+/// Dropping the handle does not stop the prefetch, call `cancel` for that.
+///
+pub struct PrefetchHandle {
+    state: Arc<PrefetchState>,
+    thread: JoinHandle<()>,
+}
+
+impl PrefetchHandle {
+    /// stop after the current block
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// number of blocks read so far
+    pub fn blocks_done(&self) -> usize {
+        self.state.blocks.load(Ordering::SeqCst)
+    }
+
+    /// number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.state.bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// wait for the prefetch to finish, returning the number of blocks read
+    pub fn join(self) -> usize {
+        let PrefetchHandle { state, thread } = self;
+        let _ = thread.join();
+        state.blocks.load(Ordering::SeqCst)
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Hint that the blocks of `heights` will be read soon.
+    ///
+    /// On Linux this issues `posix_fadvise(WILLNEED)` so that the kernel
+    /// reads the blocks into the page cache asynchronously.
+    /// On other platforms the blocks are read in a background thread.
+    ///
+    /// This function returns immediately, and is useful for random-access
+    /// workloads (e.g., graph traversal) that know their next accesses.
+    ///
+    /// Blocks of archived blk files are skipped, since their offsets
+    /// are positions in the decompressed file.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let next_heights = vec![123456, 234567, 345678];
+    /// db.prefetch_blocks(&next_heights).unwrap();
+    /// // ... do some other work
+    /// for h in next_heights {
+    ///     let block: SBlock = db.get_block(h).unwrap();
+    /// }
+    /// ```
+    ///
+    pub fn prefetch_blocks(&self, heights: &[usize]) -> OpResult<()> {
+        let mut by_file: BTreeMap<PathBuf, Vec<BlockLocation>> = BTreeMap::new();
+        for h in heights {
+            let loc = self.get_block_location(*h)?;
+            if loc.storage == BlkStorage::Archived {
+                continue;
+            }
+            by_file.entry(loc.path.clone()).or_default().push(loc);
+        }
+        for (path, mut locations) in by_file {
+            locations.sort_by_key(|l| l.offset);
+            advise_will_need(path, locations)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Read the blocks of `range` in a background thread,
+    /// within `budget`, to warm the OS page cache for an upcoming analysis.
+    ///
+    /// The thread asks for idle I/O priority and the lowest CPU priority
+    /// (Linux only), so that the machine stays responsive
+    /// (e.g., while working interactively in a notebook).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, PrefetchBudget};
+    /// use std::path::Path;
+    ///
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+    ///
+    /// let budget = PrefetchBudget {
+    ///     max_bytes_per_sec: Some(50 * 1024 * 1024),
+    ///     max_bytes: Some(8 * 1024 * 1024 * 1024),
+    /// };
+    /// let prefetch = db.background_prefetch(600000..650000, budget).unwrap();
+    /// // ... meanwhile
+    /// println!("{} blocks prefetched", prefetch.blocks_done());
+    /// ```
+    ///
+    pub fn background_prefetch(
+        &self,
+        range: Range<usize>,
+        budget: PrefetchBudget,
+    ) -> OpResult<PrefetchHandle> {
+        if range.end > self.get_block_count() {
+            return Err(OpError::from("range exceeds block count"));
+        }
+        let db = self.clone();
+        let state = Arc::new(PrefetchState::default());
+        let thread_state = state.clone();
+        let thread = thread::Builder::new()
+            .name("prefetch".to_string())
+            .spawn(move || {
+                lower_thread_priority();
+                let options = BuildOptions {
+                    max_bytes_per_sec: budget.max_bytes_per_sec,
+                    ..Default::default()
+                };
+                let mut throttle = BuildMonitor::new(range.len(), &options);
+                for height in range {
+                    let bytes = thread_state.bytes.load(Ordering::SeqCst);
+                    if thread_state.cancelled.load(Ordering::SeqCst)
+                        || budget.max_bytes.map_or(false, |max| bytes >= max)
+                    {
+                        break;
+                    }
+                    match db.get_raw_block(height) {
+                        Ok(raw) => {
+                            let size = raw.len() as u64;
+                            recycle_vec(raw);
+                            thread_state.bytes.fetch_add(size, Ordering::SeqCst);
+                            thread_state.blocks.fetch_add(1, Ordering::SeqCst);
+                            throttle.block_done(size);
+                        }
+                        Err(e) => {
+                            warn!("prefetch stopped at height {}: {}", height, e);
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(PrefetchHandle { state, thread })
+    }
+}
+
+///
+/// Idle I/O class and lowest nice value for the calling thread.
+///
+#[cfg(target_os = "linux")]
+fn lower_thread_priority() {
+    // ioprio_set(IOPRIO_WHO_PROCESS, 0 (calling thread), IOPRIO_CLASS_IDLE << 13)
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    // SAFETY: these calls only change scheduling attributes of this thread.
+    unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        );
+        libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_thread_priority() {}
+
+#[cfg(target_os = "linux")]
+fn advise_will_need(path: PathBuf, locations: Vec<BlockLocation>) -> OpResult<()> {
+    use std::os::unix::io::AsRawFd;
+    let f = File::open(path)?;
+    for loc in locations {
+        // SAFETY: the file descriptor is valid while `f` is alive.
+        let err = unsafe {
+            libc::posix_fadvise(
+                f.as_raw_fd(),
+                loc.offset as libc::off_t,
+                loc.size as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if err != 0 {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_will_need(path: PathBuf, locations: Vec<BlockLocation>) -> OpResult<()> {
+    let mut f = File::open(path)?;
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        for loc in locations {
+            buf.resize(loc.size as usize, 0);
+            if f.seek(SeekFrom::Start(loc.offset as u64)).is_err()
+                || f.read_exact(&mut buf).is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
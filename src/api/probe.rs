@@ -0,0 +1,337 @@
+//!
+//! Inspect a datadir before opening it.
+//!
+use crate::api::{network_datadir, BitcoinDB};
+use crate::parser::blk_file::BlkReader;
+use crate::parser::block_index::load_block_index;
+use crate::parser::xor::read_xor_key;
+use bitcoin::{BlockHash, Network};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+///
+/// Layout of a datadir, found by `BitcoinDB::probe`.
+///
+/// `errors` tells why `BitcoinDB::new` would fail (and what to do),
+/// `warnings` what would not be available once opened.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatadirReport {
+    /// directory `BitcoinDB::new` opens (the network subdirectory if any)
+    pub datadir: PathBuf,
+    /// network of the blk files, from their magic bytes
+    pub network: Option<Network>,
+    pub blk_files: usize,
+    /// lowest and highest numbers of blk files (`blk{n:05}.dat`)
+    pub blk_file_range: Option<(i32, i32)>,
+    /// numbers missing between the lowest and the highest blk files
+    pub missing_blk_files: Vec<i32>,
+    /// total size of blk files in bytes
+    pub blk_bytes: u64,
+    pub rev_files: usize,
+    /// total size of rev (undo) files in bytes
+    pub rev_bytes: u64,
+    /// blk and rev files XORed with `blocks/xor.dat` (Bitcoin Core v28+)
+    pub obfuscated: bool,
+    /// height and hash of the tip of the block index
+    pub index_tip: Option<(usize, BlockHash)>,
+    /// `indexes/txindex` is present
+    pub txindex: bool,
+    /// size of `indexes/txindex` in bytes
+    pub txindex_bytes: u64,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl DatadirReport {
+    /// `true` if no problem prevents reading the datadir
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Inspect the datadir at `path` without opening it: network, blk and rev
+    /// files, obfuscation, block index tip and txindex, with the problems found.
+    ///
+    /// Reading the block index takes a few seconds on mainnet.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let report = BitcoinDB::probe(Path::new("/Users/me/bitcoin"));
+    /// for error in report.errors.iter() {
+    ///     eprintln!("error: {}", error);
+    /// }
+    /// for warning in report.warnings.iter() {
+    ///     eprintln!("warning: {}", warning);
+    /// }
+    /// if report.is_ok() {
+    ///     let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), report.txindex).unwrap();
+    /// }
+    /// ```
+    ///
+    pub fn probe(path: &Path) -> DatadirReport {
+        let datadir = network_datadir(path, None);
+        let mut report = DatadirReport {
+            datadir: datadir.clone(),
+            network: None,
+            blk_files: 0,
+            blk_file_range: None,
+            missing_blk_files: Vec::new(),
+            blk_bytes: 0,
+            rev_files: 0,
+            rev_bytes: 0,
+            obfuscated: false,
+            index_tip: None,
+            txindex: false,
+            txindex_bytes: 0,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        };
+        if !path.exists() {
+            report
+                .errors
+                .push(format!("{} does not exist", path.display()));
+            return report;
+        }
+        let blocks_dir = datadir.join("blocks");
+        if !blocks_dir.is_dir() {
+            report.errors.push(format!(
+                "no blocks directory in {} (nor in testnet3, signet or regtest), \
+                 pass the datadir of Bitcoin Core",
+                path.display()
+            ));
+            return report;
+        }
+        probe_blocks(&blocks_dir, &mut report);
+        probe_index(&blocks_dir.join("index"), &mut report);
+
+        let txindex = datadir.join("indexes").join("txindex");
+        report.txindex = txindex.is_dir();
+        if report.txindex {
+            report.txindex_bytes = dir_size(&txindex);
+        } else {
+            report.warnings.push(String::from(
+                "no txindex: lookups by txid are unavailable, run bitcoind with -txindex=1",
+            ));
+        }
+        report
+    }
+}
+
+fn probe_blocks(blocks_dir: &Path, report: &mut DatadirReport) {
+    let key = match read_xor_key(blocks_dir) {
+        Ok(key) => key,
+        Err(e) => {
+            report.errors.push(format!("invalid xor.dat: {}", e));
+            None
+        }
+    };
+    report.obfuscated = key.is_some();
+    let entries = match fs::read_dir(blocks_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report
+                .errors
+                .push(format!("cannot list {}: {}", blocks_dir.display(), e));
+            return;
+        }
+    };
+    let mut blk_files = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        // follows symlinks
+        let size = fs::metadata(entry.path()).map_or(0, |m| m.len());
+        if let Some(n) = file_number(name, "blk").or_else(|| archive_number(name)) {
+            blk_files.push((n, entry.path()));
+            report.blk_bytes += size;
+        } else if file_number(name, "rev").is_some() {
+            report.rev_files += 1;
+            report.rev_bytes += size;
+        }
+    }
+    blk_files.sort();
+    // a blk file and its archive
+    blk_files.dedup_by_key(|(n, _)| *n);
+    report.blk_files = blk_files.len();
+    let (first, last) = match (blk_files.first(), blk_files.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            report.errors.push(format!(
+                "no blk files in {}, is the node synced?",
+                blocks_dir.display()
+            ));
+            return;
+        }
+    };
+    report.blk_file_range = Some((first.0, last.0));
+    let mut expected = first.0;
+    for (n, _) in blk_files.iter() {
+        report.missing_blk_files.extend(expected..*n);
+        expected = n + 1;
+    }
+    if first.0 > 0 {
+        report.warnings.push(format!(
+            "blk files start at blk{:05}.dat: pruned node, earlier blocks are unavailable",
+            first.0
+        ));
+    }
+    if !report.missing_blk_files.is_empty() {
+        report.errors.push(format!(
+            "{} blk files are missing (e.g. blk{:05}.dat), copy them from the node",
+            report.missing_blk_files.len(),
+            report.missing_blk_files[0]
+        ));
+    }
+    if report.rev_files == 0 {
+        report.warnings.push(String::from(
+            "no rev files: connected iteration from undo data is unavailable",
+        ));
+    }
+
+    let mut magic = [0u8; 4];
+    let read = BlkReader::open(&first.1, key).and_then(|mut r| Ok(r.read_exact(&mut magic)?));
+    match read {
+        Ok(()) => {
+            report.network = Network::from_magic(u32::from_le_bytes(magic));
+            if report.network.is_none() {
+                report.errors.push(format!(
+                    "unknown magic {:02x?} in {}{}",
+                    magic,
+                    first.1.display(),
+                    if report.obfuscated {
+                        ""
+                    } else {
+                        " (obfuscated blocks without xor.dat?)"
+                    }
+                ));
+            }
+        }
+        Err(e) => report
+            .errors
+            .push(format!("cannot read {}: {}", first.1.display(), e)),
+    }
+}
+
+fn probe_index(index_dir: &Path, report: &mut DatadirReport) {
+    if !index_dir.is_dir() {
+        report.errors.push(format!(
+            "no block index at {}, let bitcoind start once",
+            index_dir.display()
+        ));
+        return;
+    }
+    // an inconsistent index panics
+    match std::panic::catch_unwind(|| load_block_index(index_dir)) {
+        Err(_) => report.errors.push(String::from(
+            "the block index is inconsistent, restart bitcoind with -reindex",
+        )),
+        Ok(Ok(records)) => match records.last() {
+            Some(tip) => {
+                report.index_tip = Some((records.len() - 1, tip.block_header.block_hash()));
+                if let Some((first, last)) = report.blk_file_range {
+                    if tip.n_file > last || tip.n_file < first {
+                        report.errors.push(format!(
+                            "the index tip is in blk{:05}.dat, which is missing",
+                            tip.n_file
+                        ));
+                    }
+                }
+            }
+            None => report.errors.push(String::from(
+                "the block index is empty, is the node synced?",
+            )),
+        },
+        Ok(Err(e)) => {
+            let message = e.to_string();
+            if message.to_lowercase().contains("lock") {
+                report.errors.push(format!(
+                    "the block index is locked ({}): stop bitcoind, or open a copy of the datadir",
+                    message
+                ));
+            } else {
+                report
+                    .errors
+                    .push(format!("cannot read the block index: {}", message));
+            }
+        }
+    }
+}
+
+/// `n` of files named `{prefix}{n}.dat`
+fn file_number(name: &str, prefix: &str) -> Option<i32> {
+    name.strip_prefix(prefix)?
+        .strip_suffix(".dat")?
+        .parse::<i32>()
+        .ok()
+}
+
+/// `n` of archived blk files named `blk{n}.dat.zst`
+#[cfg(feature = "compression")]
+fn archive_number(name: &str) -> Option<i32> {
+    file_number(name.strip_suffix(".zst")?, "blk")
+}
+
+#[cfg(not(feature = "compression"))]
+fn archive_number(_name: &str) -> Option<i32> {
+    None
+}
+
+/// total size of the files under `dir`
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir).map_or(0, |entries| {
+        entries
+            .flatten()
+            .map(|entry| match entry.metadata() {
+                Ok(m) if m.is_dir() => dir_size(&entry.path()),
+                Ok(m) => m.len(),
+                Err(_) => 0,
+            })
+            .sum()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    #[test]
+    fn test_probe() {
+        let dir = TestDir::new("probe");
+        let report = BitcoinDB::probe(&dir);
+        assert!(!report.is_ok());
+        assert!(report.errors[0].contains("no blocks directory"));
+
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        chain.write_network(&dir, Network::Regtest).unwrap();
+        let report = BitcoinDB::probe(&dir);
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.datadir, dir.join("regtest"));
+        assert_eq!(report.network, Some(Network::Regtest));
+        assert_eq!((report.blk_files, report.blk_file_range), (1, Some((0, 0))));
+        assert!(report.blk_bytes > 0);
+        assert!(!report.obfuscated);
+        assert_eq!(report.index_tip, Some((3, tips[2])));
+        assert!(!report.txindex);
+        assert!(!report.warnings.is_empty());
+
+        // a gap in blk files
+        let blocks = dir.join("regtest").join("blocks");
+        fs::copy(blocks.join("blk00000.dat"), blocks.join("blk00002.dat")).unwrap();
+        let report = BitcoinDB::probe(&dir);
+        assert_eq!(report.missing_blk_files, vec![1]);
+        assert!(!report.is_ok());
+    }
+}
@@ -0,0 +1,244 @@
+//!
+//! Draw representative samples of blocks, for prototyping analyses
+//! before running them over the full chain.
+//!
+use crate::api::{BitcoinDB, Block, BlockIter};
+use std::collections::BTreeMap;
+
+///
+/// How `sample_blocks` draws heights.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleStrategy {
+    /// every height has the same probability
+    Uniform,
+    /// heights with more transactions are more likely to be drawn
+    WeightedByTxCount,
+    /// sample count of each calendar year (UTC, block time) is proportional
+    /// to its number of blocks, uniform within each year
+    StratifiedByYear,
+}
+
+impl BitcoinDB {
+    ///
+    /// Sample `n` distinct block heights from `0..get_block_count()`.
+    ///
+    /// The result is deterministic given `seed`, and sorted by height.
+    /// If `n` exceeds the number of blocks, all heights are returned.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SampleStrategy, SBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let heights = db.sample_blocks(1000, 42, SampleStrategy::StratifiedByYear);
+    /// for block in db.iter_heights::<SBlock, _>(heights) {
+    ///     println!("{}", block.txdata.len());
+    /// }
+    /// ```
+    ///
+    pub fn sample_blocks(&self, n: usize, seed: u64, strategy: SampleStrategy) -> Vec<usize> {
+        let count = self.get_block_count();
+        let mut rng = SplitMix64::new(seed);
+        let mut heights = if n >= count {
+            (0..count).collect()
+        } else {
+            match strategy {
+                SampleStrategy::Uniform => {
+                    sample_uniform(&mut rng, &(0..count).collect::<Vec<usize>>(), n)
+                }
+                SampleStrategy::WeightedByTxCount => {
+                    let weights = (0..count)
+                        .map(|h| self.block_index.records[h].n_tx as f64)
+                        .collect::<Vec<f64>>();
+                    sample_weighted(&mut rng, &weights, n)
+                }
+                SampleStrategy::StratifiedByYear => {
+                    let mut strata: BTreeMap<i32, Vec<usize>> = BTreeMap::new();
+                    for h in 0..count {
+                        let time = self.block_index.records[h].block_header.time;
+                        strata.entry(year_of_timestamp(time)).or_default().push(h);
+                    }
+                    let strata = strata.into_values().collect::<Vec<Vec<usize>>>();
+                    let sizes = strata.iter().map(|s| s.len()).collect::<Vec<usize>>();
+                    allocate_proportionally(&sizes, n)
+                        .into_iter()
+                        .zip(strata.iter())
+                        .flat_map(|(k, stratum)| sample_uniform(&mut rng, stratum, k))
+                        .collect()
+                }
+            }
+        };
+        heights.sort_unstable();
+        heights
+    }
+
+    ///
+    /// Iterate through blocks drawn by `sample_blocks`, in height order.
+    ///
+    pub fn iter_sampled_blocks<T>(
+        &self,
+        n: usize,
+        seed: u64,
+        strategy: SampleStrategy,
+    ) -> BlockIter<T>
+    where
+        T: 'static + From<Block> + Send,
+    {
+        BlockIter::new(self, self.sample_blocks(n, seed, strategy))
+    }
+}
+
+///
+/// A small deterministic pseudo random generator (SplitMix64).
+///
+pub(crate) struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// uniform in `[0, 1)`
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// uniform in `0..bound`
+    pub(crate) fn below(&mut self, bound: usize) -> usize {
+        (self.next_f64() * bound as f64) as usize
+    }
+}
+
+///
+/// Draw `k` distinct elements by partial Fisher-Yates shuffle.
+///
+pub(crate) fn sample_uniform<T: Clone>(rng: &mut SplitMix64, items: &[T], k: usize) -> Vec<T> {
+    let mut pool = items.to_vec();
+    let k = k.min(pool.len());
+    for i in 0..k {
+        let j = i + rng.below(pool.len() - i);
+        pool.swap(i, j);
+    }
+    pool.truncate(k);
+    pool
+}
+
+///
+/// Draw `k` distinct indices with probability proportional to weights
+/// (Efraimidis-Spirakis), zero weights are never drawn.
+///
+fn sample_weighted(rng: &mut SplitMix64, weights: &[f64], k: usize) -> Vec<usize> {
+    let mut keys = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| **w > 0.0)
+        .map(|(i, w)| {
+            // log(u) / w, larger is better; avoid log(0)
+            let u = rng.next_f64().max(f64::MIN_POSITIVE);
+            (u.ln() / w, i)
+        })
+        .collect::<Vec<(f64, usize)>>();
+    keys.sort_unstable_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    keys.into_iter().take(k).map(|(_, i)| i).collect()
+}
+
+///
+/// Split `n` among strata proportional to their sizes (largest remainder).
+///
+fn allocate_proportionally(sizes: &[usize], n: usize) -> Vec<usize> {
+    let total: usize = sizes.iter().sum();
+    if total == 0 {
+        return vec![0; sizes.len()];
+    }
+    let mut alloc = sizes.iter().map(|s| s * n / total).collect::<Vec<usize>>();
+    let mut remainders = sizes
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (s * n % total, i))
+        .collect::<Vec<(usize, usize)>>();
+    remainders.sort_unstable_by(|a, b| b.cmp(a));
+    let mut left = n - alloc.iter().sum::<usize>();
+    for (_, i) in remainders {
+        if left == 0 {
+            break;
+        }
+        if alloc[i] < sizes[i] {
+            alloc[i] += 1;
+            left -= 1;
+        }
+    }
+    alloc
+}
+
+///
+/// UTC calendar year of a unix timestamp.
+///
+pub(crate) fn year_of_timestamp(timestamp: u32) -> i32 {
+    civil_from_days((timestamp / 86400) as i64).0
+}
+
+///
+/// (year, month, day) of days since 1970-01-01.
+///
+/// See Howard Hinnant's `civil_from_days` algorithm.
+///
+pub(crate) fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = (yoe + era * 400 + i64::from(m <= 2)) as i32;
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // genesis block time 2009-01-03
+        assert_eq!(civil_from_days(1231006505 / 86400), (2009, 1, 3));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(year_of_timestamp(1640995199), 2021);
+        assert_eq!(year_of_timestamp(1640995200), 2022);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut rng = SplitMix64::new(7);
+        let items = (0..100).collect::<Vec<usize>>();
+        let mut sample = sample_uniform(&mut rng, &items, 10);
+        sample.sort_unstable();
+        sample.dedup();
+        assert_eq!(sample.len(), 10);
+
+        let weighted = sample_weighted(&mut rng, &[0.0, 1.0, 0.0, 5.0], 4);
+        assert_eq!(weighted.len(), 2);
+        assert!(!weighted.contains(&0) && !weighted.contains(&2));
+
+        assert_eq!(allocate_proportionally(&[10, 30, 60], 10), vec![1, 3, 6]);
+        assert_eq!(
+            allocate_proportionally(&[1, 1, 1], 2).iter().sum::<usize>(),
+            2
+        );
+    }
+}
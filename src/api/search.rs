@@ -0,0 +1,91 @@
+//!
+//! Resolve free-form search strings (as typed in a block explorer).
+//!
+use crate::api::{Address, BitcoinDB, BlockHash, FromHex, Txid};
+use crate::parser::errors::{OpError, OpResult};
+use std::str::FromStr;
+
+///
+/// What a search string resolved to.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchResult {
+    /// a block, searched by height or block hash
+    Block {
+        height: usize,
+        block_hash: BlockHash,
+    },
+    /// a transaction, with the height of its block
+    Transaction { txid: Txid, height: usize },
+    /// a syntactically valid address (not checked against the chain)
+    Address(Address),
+}
+
+impl BitcoinDB {
+    ///
+    /// Resolve `query` to a block (height or block hash),
+    /// a transaction (txid) or an address.
+    ///
+    /// Surrounding whitespace is ignored. A 64 hex digit query is first
+    /// looked up as a main chain block hash, then as a txid
+    /// (which requires `txindex`). Fails if nothing matches.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SearchResult};
+    /// use std::path::Path;
+    ///
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), true).unwrap();
+    ///
+    /// match db.search("f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16") {
+    ///     Ok(SearchResult::Block { height, .. }) => println!("block {}", height),
+    ///     Ok(SearchResult::Transaction { txid, height }) => println!("{} in {}", txid, height),
+    ///     Ok(SearchResult::Address(address)) => println!("address {}", address),
+    ///     Err(e) => println!("not found: {}", e),
+    /// }
+    /// ```
+    ///
+    pub fn search(&self, query: &str) -> OpResult<SearchResult> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Err(OpError::from("empty search query"));
+        }
+        if query.len() == 64 && query.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return self.search_hash(query);
+        }
+        if query.bytes().all(|b| b.is_ascii_digit()) {
+            let height = usize::from_str(query).map_err(|_| OpError::from("height not found"))?;
+            return Ok(SearchResult::Block {
+                height,
+                block_hash: self.get_hash_from_height(height)?,
+            });
+        }
+        match Address::from_str(query) {
+            Ok(address) => Ok(SearchResult::Address(address)),
+            Err(_) => Err(OpError::from(
+                "unrecognized search query (expected height, hash, txid or address)",
+            )),
+        }
+    }
+
+    fn search_hash(&self, query: &str) -> OpResult<SearchResult> {
+        let block_hash = BlockHash::from_hex(query)?;
+        if let Ok(height) = self.get_height_from_hash(&block_hash) {
+            return Ok(SearchResult::Block { height, block_hash });
+        }
+        if !self.tx_db.is_open() {
+            return Err(OpError::from(
+                "block hash not found (txindex not open for txid search)",
+            ));
+        }
+        let txid = Txid::from_hex(query)?;
+        let height = if self.tx_db.is_genesis_tx(&txid) {
+            0
+        } else {
+            self.get_height_of_transaction(&txid)
+                .map_err(|_| OpError::from("no block or transaction found"))?
+        };
+        Ok(SearchResult::Transaction { txid, height })
+    }
+}
@@ -0,0 +1,118 @@
+//!
+//! Extraction of self-contained datadirs with a subset of blocks.
+//!
+use crate::api::BitcoinDB;
+use crate::parser::blk_file::magic_of;
+use crate::parser::block_index::{write_block_index, BLOCK_HAVE_DATA, BLOCK_VALID_SCRIPTS};
+use crate::parser::errors::{OpError, OpResult};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// maximum size of a blk file (same as Bitcoin Core)
+const MAX_BLK_FILE_SIZE: u64 = 0x8000000;
+/// blk file index of blocks excluded from a slice (no such file)
+const EXCLUDED_N_FILE: i32 = i32::MAX;
+
+impl BitcoinDB {
+    ///
+    /// Write a minimal datadir at `out_dir` containing only the blocks at
+    /// `heights`, readable by `BitcoinDB::new(out_dir, false)`.
+    ///
+    /// Headers of all blocks up to the highest height are kept in the
+    /// rebuilt block index, so heights, hashes and header fields are
+    /// unchanged. Excluded blocks have no data: in the slice,
+    /// `get_block_count()` stops at the first excluded height, and
+    /// included blocks are read with `get_block`, `get_blocks`
+    /// or `iter_heights`.
+    ///
+    /// Returns the number of blocks written. Fails if `out_dir/blocks`
+    /// already exists.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let heights: Vec<usize> = (600000..700000).step_by(100).collect();
+    /// db.extract_slice(&heights, Path::new("./slice")).unwrap();
+    ///
+    /// let slice = BitcoinDB::new(Path::new("./slice"), false).unwrap();
+    /// for block in slice.iter_heights::<SBlock, _>(heights) {
+    ///     println!("{}", block.header.block_hash);
+    /// }
+    /// ```
+    ///
+    pub fn extract_slice(&self, heights: &[usize], out_dir: &Path) -> OpResult<usize> {
+        let mut heights = heights.to_vec();
+        heights.sort_unstable();
+        heights.dedup();
+        let max_height = match heights.last() {
+            None => return Err(OpError::from("no height to extract")),
+            Some(h) if *h >= self.get_block_count() => {
+                return Err(OpError::from("height not found"))
+            }
+            Some(h) => *h,
+        };
+        let blocks_dir = out_dir.join("blocks");
+        if blocks_dir.exists() {
+            return Err(OpError::from("blocks directory already exists"));
+        }
+        fs::create_dir_all(&blocks_dir)?;
+
+        let mut records = Vec::with_capacity(max_height + 1);
+        let mut included = heights.into_iter().peekable();
+        let mut n_file = 0;
+        let mut w = BufWriter::new(File::create(blocks_dir.join(blk_file_name(n_file)))?);
+        let mut pos = 0u64;
+        for height in 0..=max_height {
+            let mut record = self.get_header(height)?.clone();
+            if included.peek() == Some(&height) {
+                included.next();
+                let raw = self.get_raw_block(height)?;
+                let size = 8 + raw.len() as u64;
+                if pos > 0 && pos + size > MAX_BLK_FILE_SIZE {
+                    w.flush()?;
+                    n_file += 1;
+                    w = BufWriter::new(File::create(blocks_dir.join(blk_file_name(n_file)))?);
+                    pos = 0;
+                }
+                w.write_all(&magic_of(self.network))?;
+                w.write_all(&(raw.len() as u32).to_le_bytes())?;
+                w.write_all(&raw)?;
+                record.n_file = n_file;
+                record.n_data_pos = pos as u32 + 8;
+                pos += size;
+            } else {
+                record.n_tx = 0;
+                record.n_file = EXCLUDED_N_FILE;
+                record.n_data_pos = 8;
+            }
+            record.n_status = BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA;
+            record.n_undo_pos = u32::MAX;
+            records.push(record);
+        }
+        w.flush()?;
+        write_block_index(&blocks_dir.join("index"), &records)?;
+        Ok(records.iter().filter(|r| r.n_tx > 0).count())
+    }
+}
+
+fn blk_file_name(n_file: i32) -> String {
+    format!("blk{:05}.dat", n_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::blk_file_name;
+
+    #[test]
+    fn test_blk_file_name() {
+        assert_eq!(blk_file_name(0), "blk00000.dat");
+        assert_eq!(blk_file_name(3012), "blk03012.dat");
+    }
+}
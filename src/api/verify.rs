@@ -0,0 +1,132 @@
+//!
+//! Integrity checks of the block index.
+//!
+use crate::api::{BitcoinDB, Block, BlockHash};
+use bitcoin::blockdata::constants::genesis_block;
+use rayon::prelude::*;
+use std::fmt;
+
+///
+/// Kind of inconsistency found by `verify_header_chain`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderInconsistencyKind {
+    /// block 0 is not the genesis block of the network
+    GenesisMismatch { found: BlockHash },
+    /// height recorded in the block index differs from the position in the chain
+    HeightMismatch { recorded: i32 },
+    /// `prev_blockhash` does not point to the block below
+    BrokenLink {
+        expected: BlockHash,
+        found: BlockHash,
+    },
+    /// block hash does not meet the target of the header
+    InvalidProofOfWork,
+}
+
+///
+/// The first inconsistency found by `verify_header_chain`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderInconsistency {
+    pub height: usize,
+    pub kind: HeaderInconsistencyKind,
+}
+
+impl BitcoinDB {
+    ///
+    /// Walk the header chain from genesis to tip, checking prev-hash links,
+    /// recorded heights, and proof of work of each header.
+    ///
+    /// This is an in-memory check using the block index (no blk file access),
+    /// cheap insurance before a long analysis over a possibly damaged datadir.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// if let Err(e) = db.verify_header_chain() {
+    ///     panic!("damaged block index: {}", e);
+    /// }
+    /// ```
+    ///
+    pub fn verify_header_chain(&self) -> Result<(), HeaderInconsistency> {
+        let records = &self.block_index.records;
+        let hashes: Vec<Option<BlockHash>> = records
+            .par_iter()
+            .map(|r| r.block_header.validate_pow(&r.block_header.target()).ok())
+            .collect();
+        let genesis_hash = genesis_block(self.network).block_hash();
+        for (h, (record, hash)) in records.iter().zip(hashes.iter()).enumerate() {
+            let fail = |kind| Err(HeaderInconsistency { height: h, kind });
+            if record.n_height as usize != h {
+                return fail(HeaderInconsistencyKind::HeightMismatch {
+                    recorded: record.n_height,
+                });
+            }
+            let hash = match hash {
+                Some(hash) => *hash,
+                None => return fail(HeaderInconsistencyKind::InvalidProofOfWork),
+            };
+            if h == 0 {
+                if hash != genesis_hash {
+                    return fail(HeaderInconsistencyKind::GenesisMismatch { found: hash });
+                }
+            } else {
+                // hash of the block below has passed the checks
+                let expected = hashes[h - 1].unwrap();
+                let found = record.block_header.prev_blockhash;
+                if found != expected {
+                    return fail(HeaderInconsistencyKind::BrokenLink { expected, found });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Check the BIP141 witness commitment of blocks from `start` to `end`
+    /// (excluded), and return the heights of blocks whose witness data
+    /// does not match the commitment in their coinbase (or cannot be read).
+    ///
+    /// Blocks without witness data pass the check.
+    ///
+    pub fn verify_witness_commitments(&self, start: usize, end: usize) -> Vec<usize> {
+        let end = end.min(self.get_block_count());
+        (start..end)
+            .into_par_iter()
+            .filter(|h| match self.get_block::<Block>(*h) {
+                Ok(block) => !block.check_witness_commitment(),
+                Err(_) => true,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for HeaderInconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            HeaderInconsistencyKind::GenesisMismatch { found } => {
+                write!(f, "unexpected genesis block hash {}", found)
+            }
+            HeaderInconsistencyKind::HeightMismatch { recorded } => write!(
+                f,
+                "block at height {} is recorded at height {}",
+                self.height, recorded
+            ),
+            HeaderInconsistencyKind::BrokenLink { expected, found } => write!(
+                f,
+                "block at height {} points to {} instead of {}",
+                self.height, found, expected
+            ),
+            HeaderInconsistencyKind::InvalidProofOfWork => {
+                write!(f, "invalid proof of work at height {}", self.height)
+            }
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//!
+//! C ABI (feature `capi`), to bind the crate from C, C++, R, Julia, ...
+//!
+//! The declarations are in `include/bitcoin_explorer.h`
+//! (generated with `cbindgen --config cbindgen.toml`).
+//! Build a shared or static library with
+//! `cargo rustc --release --features capi --crate-type cdylib`
+//! (or `--crate-type staticlib`).
+//!
+//! Blocks and transactions are returned as JSON strings (`FBlock`,
+//! `FTransaction`) owned by the caller, to be released with
+//! `btcx_string_free`. Functions failing return `NULL` (or `-1`)
+//! and set a message read by `btcx_last_error`. Panics do not unwind
+//! into C: they are caught and reported as failures.
+//!
+//! # Example
+//!
+//! ```c
+//! #include "bitcoin_explorer.h"
+//!
+//! BtcxDb *db = btcx_open("/Users/me/bitcoin", false);
+//! if (db == NULL) {
+//!     fprintf(stderr, "%s\n", btcx_last_error());
+//!     return 1;
+//! }
+//! char *block = btcx_get_block_json(db, 600000);
+//! puts(block);
+//! btcx_string_free(block);
+//! btcx_close(db);
+//! ```
+//!
+use crate::api::{BitcoinDB, FBlock, FTransaction, FromHex, Txid};
+use crate::parser::errors::{OpError, OpResult};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+///
+/// An open `BitcoinDB`, opaque to C.
+///
+pub struct BtcxDb(BitcoinDB);
+
+///
+/// Called by `btcx_iter_blocks` with the height and the JSON of each block
+/// (valid during the call only). Return `false` to stop the iteration.
+///
+pub type BtcxBlockCallback =
+    extern "C" fn(height: usize, json: *const c_char, len: usize, user_data: *mut c_void) -> bool;
+
+fn set_last_error(err: &OpError) {
+    let msg = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// store the error of `result` and map it to `None`
+fn ok_or_set_error<T>(result: OpResult<T>) -> Option<T> {
+    match result {
+        Ok(v) => Some(v),
+        Err(e) => {
+            set_last_error(&e);
+            None
+        }
+    }
+}
+
+/// run `f`, or store the message of its panic and return `on_panic`
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => v,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(msg) => msg.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(msg) => msg.clone(),
+                    None => "unknown panic".to_string(),
+                },
+            };
+            set_last_error(&OpError::from(format!("panic: {}", msg).as_str()));
+            on_panic
+        }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> OpResult<CString> {
+    let json = serde_json::to_string(value)
+        .map_err(|e| OpError::from(format!("failed to encode json: {}", e).as_str()))?;
+    // JSON strings escape control characters, so there is no NUL byte
+    CString::new(json).map_err(|_| OpError::from("NUL byte in json"))
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> OpResult<&'a str> {
+    if s.is_null() {
+        return Err(OpError::from("null string argument"));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| OpError::from("string argument is not UTF-8"))
+}
+
+unsafe fn db_arg<'a>(db: *const BtcxDb) -> OpResult<&'a BitcoinDB> {
+    match db.as_ref() {
+        Some(db) => Ok(&db.0),
+        None => Err(OpError::from("null db argument")),
+    }
+}
+
+///
+/// Version of this library (static string).
+///
+#[no_mangle]
+pub extern "C" fn btcx_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+///
+/// Message of the last error of this thread, `NULL` if none.
+///
+/// Valid until the next failing call on this thread.
+///
+#[no_mangle]
+pub extern "C" fn btcx_last_error() -> *const c_char {
+    catch_panic(ptr::null(), || {
+        LAST_ERROR.with(|e| match e.borrow().as_ref() {
+            Some(msg) => msg.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+}
+
+///
+/// Open the bitcoin core datadir at `path`, see `BitcoinDB::new`.
+///
+/// Returns `NULL` on failure.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string.
+///
+#[no_mangle]
+pub unsafe extern "C" fn btcx_open(path: *const c_char, tx_index: bool) -> *mut BtcxDb {
+    catch_panic(ptr::null_mut(), || {
+        let db = str_arg(path).and_then(|p| BitcoinDB::new(Path::new(p), tx_index));
+        match ok_or_set_error(db) {
+            Some(db) => Box::into_raw(Box::new(BtcxDb(db))),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+///
+/// Close a db opened by `btcx_open` (`NULL` is ignored).
+///
+/// # Safety
+///
+/// `db` must come from `btcx_open` and not be used afterwards.
+///
+#[no_mangle]
+pub unsafe extern "C" fn btcx_close(db: *mut BtcxDb) {
+    catch_panic((), || {
+        if !db.is_null() {
+            drop(Box::from_raw(db));
+        }
+    })
+}
+
+///
+/// Number of blocks available (`BitcoinDB::get_block_count`),
+/// `0` for a `NULL` db.
+///
+/// # Safety
+///
+/// `db` must be `NULL` or come from `btcx_open`.
+///
+#[no_mangle]
+pub unsafe extern "C" fn btcx_block_count(db: *const BtcxDb) -> usize {
+    catch_panic(0, || {
+        ok_or_set_error(db_arg(db))
+            .map(|db| db.get_block_count())
+            .unwrap_or(0)
+    })
+}
+
+///
+/// JSON of the block at `height` (`FBlock`), `NULL` on failure.
+///
+/// # Safety
+///
+/// `db` must come from `btcx_open`. Release the result with `btcx_string_free`.
+///
+#[no_mangle]
+pub unsafe extern "C" fn btcx_get_block_json(db: *const BtcxDb, height: usize) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let json = db_arg(db)
+            .and_then(|db| db.get_block::<FBlock>(height))
+            .and_then(|block| to_json(&block));
+        match ok_or_set_error(json) {
+            Some(json) => json.into_raw(),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+///
+/// JSON of the transaction `txid` (hex, `FTransaction`), `NULL` on failure.
+///
+/// Requires a db opened with `tx_index`.
+///
+/// # Safety
+///
+/// `db` must come from `btcx_open`, `txid` must be a NUL-terminated string.
+/// Release the result with `btcx_string_free`.
+///
+#[no_mangle]
+pub unsafe extern "C" fn btcx_get_transaction_json(
+    db: *const BtcxDb,
+    txid: *const c_char,
+) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let json = db_arg(db)
+            .and_then(|db| {
+                let txid = Txid::from_hex(str_arg(txid)?)?;
+                db.get_transaction::<FTransaction>(&txid)
+            })
+            .and_then(|tx| to_json(&tx));
+        match ok_or_set_error(json) {
+            Some(json) => json.into_raw(),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+///
+/// Call `callback` with the JSON (`FBlock`) of blocks `start..end`, in order.
+///
+/// Returns the number of blocks passed to `callback`
+/// (stopping early if it returns `false`), or `-1` if the range
+/// is invalid or a block cannot be read.
+///
+/// # Safety
+///
+/// `db` must come from `btcx_open`. `user_data` is passed to `callback` as is.
+///
+#[no_mangle]
+pub unsafe extern "C" fn btcx_iter_blocks(
+    db: *const BtcxDb,
+    start: usize,
+    end: usize,
+    callback: BtcxBlockCallback,
+    user_data: *mut c_void,
+) -> i64 {
+    catch_panic(-1, || {
+        let db = match ok_or_set_error(db_arg(db)) {
+            Some(db) => db,
+            None => return -1,
+        };
+        if start > end || end > db.get_block_count() {
+            set_last_error(&OpError::from("invalid block range"));
+            return -1;
+        }
+        let mut count = 0;
+        for (i, block) in db.iter_block::<FBlock>(start, end).enumerate() {
+            let json = match ok_or_set_error(to_json(&block)) {
+                Some(json) => json,
+                None => return -1,
+            };
+            count += 1;
+            let bytes = json.as_bytes();
+            if !callback(start + i, json.as_ptr(), bytes.len(), user_data) {
+                return count;
+            }
+        }
+        if count != (end - start) as i64 {
+            set_last_error(&OpError::from("failed to read some blocks in range"));
+            return -1;
+        }
+        count
+    })
+}
+
+///
+/// Release a string returned by this library (`NULL` is ignored).
+///
+/// # Safety
+///
+/// `s` must come from a `btcx_*_json` function and not be used afterwards.
+///
+#[no_mangle]
+pub unsafe extern "C" fn btcx_string_free(s: *mut c_char) {
+    catch_panic((), || {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    const HEADER: &str = include_str!("../../include/bitcoin_explorer.h");
+
+    #[test]
+    fn test_header_declares_exports() {
+        for name in [
+            "btcx_version",
+            "btcx_last_error",
+            "btcx_open",
+            "btcx_close",
+            "btcx_block_count",
+            "btcx_get_block_json",
+            "btcx_get_transaction_json",
+            "btcx_iter_blocks",
+            "btcx_string_free",
+        ] {
+            assert!(
+                HEADER.contains(&format!("{}(", name)),
+                "{} not declared",
+                name
+            );
+        }
+        assert!(HEADER.contains("(*BtcxBlockCallback)("));
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(-1, || 1), 1);
+        assert_eq!(catch_panic(-1, || panic!("boom {}", 1)), -1);
+        let msg = unsafe { CStr::from_ptr(btcx_last_error()) };
+        assert_eq!(msg.to_str().unwrap(), "panic: boom 1");
+    }
+
+    extern "C" fn collect(
+        height: usize,
+        json: *const c_char,
+        len: usize,
+        data: *mut c_void,
+    ) -> bool {
+        let heights = unsafe { &mut *(data as *mut Vec<usize>) };
+        let json = unsafe { CStr::from_ptr(json) }.to_str().unwrap();
+        assert_eq!(json.len(), len);
+        assert!(json.starts_with('{'));
+        heights.push(height);
+        heights.len() < 3
+    }
+
+    #[test]
+    fn test_capi() {
+        let dir = TestDir::new("capi");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 4);
+        chain.write(&dir).unwrap();
+        let path = CString::new(dir.to_str().unwrap()).unwrap();
+        let missing = CString::new(dir.join("missing").to_str().unwrap()).unwrap();
+
+        unsafe {
+            assert!(btcx_open(missing.as_ptr(), false).is_null());
+            assert!(!btcx_last_error().is_null());
+
+            let db = btcx_open(path.as_ptr(), false);
+            assert!(!db.is_null());
+            assert_eq!(btcx_block_count(db), 5);
+
+            let json = btcx_get_block_json(db, 1);
+            let block: FBlock =
+                serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+            assert_eq!(block.header.block_hash, chain.main_chain()[1]);
+            btcx_string_free(json);
+            assert!(btcx_get_block_json(db, 5).is_null());
+
+            let mut heights: Vec<usize> = Vec::new();
+            let data = &mut heights as *mut Vec<usize> as *mut c_void;
+            assert_eq!(btcx_iter_blocks(db, 1, 5, collect, data), 3);
+            assert_eq!(heights, vec![1, 2, 3]);
+            assert_eq!(btcx_iter_blocks(db, 1, 6, collect, data), -1);
+            btcx_close(db);
+        }
+    }
+}
@@ -0,0 +1,627 @@
+//!
+//! Built-in indexers of `IndexerDaemon`.
+//!
+use crate::analysis::ShardMerge;
+use crate::api::{Block, Script, Txid};
+use crate::daemon::ChainIndexer;
+use crate::index::{AddressEvent, AddressEventKind};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hash_types::FilterHeader;
+use bitcoin::hashes::Hash;
+use bitcoin::util::bip158::{self, BlockFilter};
+use bitcoin::{OutPoint, TxOut};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+
+/// number of blocks indexers tracking unspent outputs can disconnect
+pub const DEFAULT_UNDO_DEPTH: usize = 100;
+
+///
+/// Height of every transaction of the main chain.
+///
+#[derive(Debug, Clone, Default)]
+pub struct TxHeightIndex {
+    heights: HashMap<Txid, usize>,
+}
+
+impl TxHeightIndex {
+    pub fn height_of(&self, txid: &Txid) -> Option<usize> {
+        self.heights.get(txid).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.heights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heights.is_empty()
+    }
+}
+
+impl ChainIndexer for TxHeightIndex {
+    fn name(&self) -> &'static str {
+        "tx_height"
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        for tx in block.txdata.iter() {
+            // duplicate coinbase txids (BIP30) keep the latest height
+            self.heights.insert(tx.txid(), height);
+        }
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            if self.heights.get(&txid) == Some(&height) {
+                self.heights.remove(&txid);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ShardMerge for TxHeightIndex {
+    fn merge_shard(&mut self, next: Self) -> OpResult<()> {
+        for (txid, height) in next.heights {
+            // duplicate coinbase txids keep the latest height, as in a single run
+            let h = self.heights.entry(txid).or_insert(height);
+            *h = (*h).max(height);
+        }
+        Ok(())
+    }
+}
+
+///
+/// Statistics of a block, summed by `BlockStatsRollup`.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    pub n_tx: u64,
+    /// number of inputs, excluding coinbase inputs
+    pub n_inputs: u64,
+    pub n_outputs: u64,
+    /// sum of output values (satoshi)
+    pub output_value: u64,
+    /// serialized size (bytes)
+    pub size: u64,
+}
+
+impl BlockStats {
+    pub fn of(block: &Block) -> Self {
+        BlockStats {
+            n_tx: block.txdata.len() as u64,
+            n_inputs: block
+                .txdata
+                .iter()
+                .filter(|tx| !tx.is_coin_base())
+                .map(|tx| tx.input.len() as u64)
+                .sum(),
+            n_outputs: block.txdata.iter().map(|tx| tx.output.len() as u64).sum(),
+            output_value: block
+                .txdata
+                .iter()
+                .flat_map(|tx| tx.output.iter())
+                .map(|o| o.value)
+                .sum(),
+            size: block.size() as u64,
+        }
+    }
+
+    fn add(&mut self, other: &BlockStats) {
+        self.n_tx += other.n_tx;
+        self.n_inputs += other.n_inputs;
+        self.n_outputs += other.n_outputs;
+        self.output_value += other.output_value;
+        self.size += other.size;
+    }
+}
+
+///
+/// `BlockStats` of every block of the main chain, by height.
+///
+/// Starts at the genesis block, or at the first height of a shard
+/// (`starting_at`).
+///
+#[derive(Debug, Clone, Default)]
+pub struct BlockStatsRollup {
+    start: usize,
+    stats: Vec<BlockStats>,
+}
+
+impl BlockStatsRollup {
+    ///
+    /// An empty rollup of the blocks from `start`,
+    /// to build on a shard and merge with `merge_shards`.
+    ///
+    pub fn starting_at(start: usize) -> Self {
+        BlockStatsRollup {
+            start,
+            stats: Vec::new(),
+        }
+    }
+
+    /// first indexed height
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn get(&self, height: usize) -> Option<&BlockStats> {
+        height
+            .checked_sub(self.start)
+            .and_then(|i| self.stats.get(i))
+    }
+
+    /// sum of the stats of `heights` (clamped to the indexed blocks)
+    pub fn range_total(&self, heights: Range<usize>) -> BlockStats {
+        let end = heights.end.saturating_sub(self.start).min(self.stats.len());
+        let start = heights.start.saturating_sub(self.start).min(end);
+        let mut total = BlockStats::default();
+        for s in self.stats[start..end].iter() {
+            total.add(s);
+        }
+        total
+    }
+
+    /// sum of the stats of all indexed blocks
+    pub fn total(&self) -> BlockStats {
+        self.range_total(self.start..self.start + self.stats.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.is_empty()
+    }
+}
+
+impl ChainIndexer for BlockStatsRollup {
+    fn name(&self) -> &'static str {
+        "block_stats"
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        let expected = self.start + self.stats.len();
+        if height != expected {
+            return Err(OpError::from(
+                format!("block stats: expected height {}", expected).as_str(),
+            ));
+        }
+        self.stats.push(BlockStats::of(block));
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, height: usize, _: &Block) -> OpResult<()> {
+        if height + 1 != self.start + self.stats.len() {
+            return Err(OpError::from(
+                format!("block stats: cannot disconnect height {}", height).as_str(),
+            ));
+        }
+        self.stats.pop();
+        Ok(())
+    }
+}
+
+impl ShardMerge for BlockStatsRollup {
+    fn merge_shard(&mut self, mut next: Self) -> OpResult<()> {
+        if next.start != self.start + self.stats.len() {
+            return Err(OpError::from(
+                format!(
+                    "block stats: shard starting at {} is not contiguous",
+                    next.start
+                )
+                .as_str(),
+            ));
+        }
+        self.stats.append(&mut next.stats);
+        Ok(())
+    }
+}
+
+///
+/// Unspent outputs of the main chain, and the outputs spent by
+/// the last `depth` blocks (to disconnect them on reorgs).
+///
+#[derive(Debug, Clone)]
+struct PrevoutTracker {
+    unspent: HashMap<OutPoint, TxOut>,
+    /// height of each of the last blocks, and the outputs it spent
+    undo: VecDeque<(usize, Vec<(OutPoint, TxOut)>)>,
+    depth: usize,
+}
+
+impl PrevoutTracker {
+    fn new(depth: usize) -> Self {
+        PrevoutTracker {
+            unspent: HashMap::new(),
+            undo: VecDeque::new(),
+            depth,
+        }
+    }
+
+    ///
+    /// Spend the inputs and add the outputs of `block`, returning the
+    /// outputs spent by each transaction (none for the coinbase).
+    ///
+    /// The tracker is unchanged if an input spends an unknown output.
+    ///
+    fn connect(&mut self, height: usize, block: &Block) -> OpResult<Vec<Vec<TxOut>>> {
+        // outputs of this block, spendable in the same block
+        let mut created: HashMap<OutPoint, TxOut> = HashMap::new();
+        let mut spent_before: HashSet<OutPoint> = HashSet::new();
+        let mut spent = Vec::with_capacity(block.txdata.len());
+        for tx in block.txdata.iter() {
+            let mut prevouts = Vec::new();
+            if !tx.is_coin_base() {
+                for input in tx.input.iter() {
+                    let outpoint = input.previous_output;
+                    let prevout = match created.remove(&outpoint) {
+                        Some(prevout) => prevout,
+                        None => match self.unspent.get(&outpoint) {
+                            Some(prevout) if spent_before.insert(outpoint) => prevout.clone(),
+                            _ => {
+                                return Err(OpError::from(
+                                    format!(
+                                        "output {} spent at height {} not found",
+                                        outpoint, height
+                                    )
+                                    .as_str(),
+                                ))
+                            }
+                        },
+                    };
+                    prevouts.push(prevout);
+                }
+            }
+            let txid = tx.txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                if !output.script_pubkey.is_provably_unspendable() {
+                    created.insert(OutPoint::new(txid, vout as u32), output.clone());
+                }
+            }
+            spent.push(prevouts);
+        }
+        let undo = spent_before
+            .into_iter()
+            .map(|outpoint| {
+                let prevout = self.unspent.remove(&outpoint).unwrap();
+                (outpoint, prevout)
+            })
+            .collect();
+        self.unspent.extend(created);
+        self.undo.push_back((height, undo));
+        if self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+        Ok(spent)
+    }
+
+    ///
+    /// Undo `connect` of the last connected block, returning the
+    /// outputs it spent from earlier blocks.
+    ///
+    /// Fails (with the tracker unchanged) beyond `depth` blocks.
+    ///
+    fn disconnect(&mut self, height: usize, block: &Block) -> OpResult<Vec<(OutPoint, TxOut)>> {
+        match self.undo.back() {
+            Some((h, _)) if *h == height => {}
+            _ => {
+                return Err(OpError::from(
+                    format!(
+                        "cannot disconnect height {}, deeper than {} blocks",
+                        height, self.depth
+                    )
+                    .as_str(),
+                ))
+            }
+        }
+        let (_, undo) = self.undo.pop_back().unwrap();
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            for vout in 0..tx.output.len() {
+                self.unspent.remove(&OutPoint::new(txid, vout as u32));
+            }
+        }
+        for (outpoint, prevout) in undo.iter() {
+            self.unspent.insert(*outpoint, prevout.clone());
+        }
+        Ok(undo)
+    }
+}
+
+///
+/// History of every script public key of the main chain, as the
+/// RocksDB `index::AddressIndex`, with reorgs of up to `undo_depth` blocks.
+///
+/// Unspent outputs are kept in memory.
+///
+#[derive(Debug, Clone)]
+pub struct AddressHistoryIndex {
+    prevouts: PrevoutTracker,
+    history: HashMap<Script, Vec<AddressEvent>>,
+}
+
+impl Default for AddressHistoryIndex {
+    fn default() -> Self {
+        Self::with_undo_depth(DEFAULT_UNDO_DEPTH)
+    }
+}
+
+impl AddressHistoryIndex {
+    pub fn with_undo_depth(undo_depth: usize) -> Self {
+        AddressHistoryIndex {
+            prevouts: PrevoutTracker::new(undo_depth),
+            history: HashMap::new(),
+        }
+    }
+
+    /// history of `script`, sorted by height
+    pub fn history(&self, script: &Script) -> &[AddressEvent] {
+        self.history
+            .get(script)
+            .map_or(&[], |events| events.as_slice())
+    }
+
+    /// balance (satoshi) of `script` at the indexed tip
+    pub fn balance(&self, script: &Script) -> u64 {
+        let (mut received, mut spent) = (0u64, 0u64);
+        for event in self.history(script) {
+            match event.kind {
+                AddressEventKind::Received => received += event.value,
+                AddressEventKind::Spent => spent += event.value,
+            }
+        }
+        received.saturating_sub(spent)
+    }
+
+    /// number of scripts with a history
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.history.is_empty()
+    }
+
+    fn push(&mut self, script: &Script, event: AddressEvent) {
+        match self.history.get_mut(script) {
+            Some(events) => events.push(event),
+            None => {
+                self.history.insert(script.clone(), vec![event]);
+            }
+        }
+    }
+}
+
+impl ChainIndexer for AddressHistoryIndex {
+    fn name(&self) -> &'static str {
+        "address_history"
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        let spent = self.prevouts.connect(height, block)?;
+        for (tx, prevouts) in block.txdata.iter().zip(spent) {
+            let txid = tx.txid();
+            for (vin, prevout) in prevouts.into_iter().enumerate() {
+                let event = AddressEvent {
+                    height,
+                    txid,
+                    index: vin as u32,
+                    kind: AddressEventKind::Spent,
+                    value: prevout.value,
+                };
+                self.push(&prevout.script_pubkey, event);
+            }
+            for (vout, output) in tx.output.iter().enumerate() {
+                if output.script_pubkey.is_provably_unspendable() {
+                    continue;
+                }
+                let event = AddressEvent {
+                    height,
+                    txid,
+                    index: vout as u32,
+                    kind: AddressEventKind::Received,
+                    value: output.value,
+                };
+                self.push(&output.script_pubkey, event);
+            }
+        }
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        let undo = self.prevouts.disconnect(height, block)?;
+        // outputs spent within the block are among its outputs
+        let scripts = block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.output.iter())
+            .chain(undo.iter().map(|(_, prevout)| prevout))
+            .map(|o| &o.script_pubkey);
+        for script in scripts {
+            if let Some(events) = self.history.get_mut(script) {
+                while events.last().map_or(false, |e| e.height == height) {
+                    events.pop();
+                }
+                if events.is_empty() {
+                    self.history.remove(script);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// The input spending an output.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpendingInput {
+    pub txid: Txid,
+    pub vin: u32,
+    pub height: usize,
+}
+
+///
+/// The spending input of every spent output of the main chain.
+///
+#[derive(Debug, Clone, Default)]
+pub struct SpentIndex {
+    spenders: HashMap<OutPoint, SpendingInput>,
+}
+
+impl SpentIndex {
+    /// the input spending `outpoint`, `None` if it is unspent (or unknown)
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<SpendingInput> {
+        self.spenders.get(outpoint).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.spenders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spenders.is_empty()
+    }
+}
+
+impl ChainIndexer for SpentIndex {
+    fn name(&self) -> &'static str {
+        "spent"
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        for tx in block.txdata.iter().filter(|tx| !tx.is_coin_base()) {
+            let txid = tx.txid();
+            for (vin, input) in tx.input.iter().enumerate() {
+                let spender = SpendingInput {
+                    txid,
+                    vin: vin as u32,
+                    height,
+                };
+                self.spenders.insert(input.previous_output, spender);
+            }
+        }
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        for tx in block.txdata.iter().filter(|tx| !tx.is_coin_base()) {
+            for input in tx.input.iter() {
+                let outpoint = input.previous_output;
+                if self.spenders.get(&outpoint).map(|s| s.height) == Some(height) {
+                    self.spenders.remove(&outpoint);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ShardMerge for SpentIndex {
+    fn merge_shard(&mut self, next: Self) -> OpResult<()> {
+        self.spenders.extend(next.spenders);
+        Ok(())
+    }
+}
+
+///
+/// BIP158 basic filters (and filter headers) of every block of
+/// the main chain, by height, with reorgs of up to `undo_depth` blocks.
+///
+/// Unspent outputs are kept in memory, to add spent scripts to filters.
+///
+#[derive(Debug, Clone)]
+pub struct BlockFilterIndex {
+    prevouts: PrevoutTracker,
+    filters: Vec<(BlockFilter, FilterHeader)>,
+}
+
+impl Default for BlockFilterIndex {
+    fn default() -> Self {
+        Self::with_undo_depth(DEFAULT_UNDO_DEPTH)
+    }
+}
+
+impl BlockFilterIndex {
+    pub fn with_undo_depth(undo_depth: usize) -> Self {
+        BlockFilterIndex {
+            prevouts: PrevoutTracker::new(undo_depth),
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn filter(&self, height: usize) -> Option<&BlockFilter> {
+        self.filters.get(height).map(|(filter, _)| filter)
+    }
+
+    /// filter header (BIP157) of the block at `height`
+    pub fn filter_header(&self, height: usize) -> Option<FilterHeader> {
+        self.filters.get(height).map(|(_, header)| *header)
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl ChainIndexer for BlockFilterIndex {
+    fn name(&self) -> &'static str {
+        "block_filter"
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        if height != self.filters.len() {
+            return Err(OpError::from(
+                format!("block filters: expected height {}", self.filters.len()).as_str(),
+            ));
+        }
+        let spent = self.prevouts.connect(height, block)?;
+        let mut scripts: HashMap<OutPoint, Script> = HashMap::new();
+        for (tx, prevouts) in block.txdata.iter().zip(spent) {
+            for (input, prevout) in tx.input.iter().zip(prevouts) {
+                scripts.insert(input.previous_output, prevout.script_pubkey);
+            }
+        }
+        let filter = BlockFilter::new_script_filter(block, |outpoint| {
+            scripts
+                .get(outpoint)
+                .cloned()
+                .ok_or(bip158::Error::UtxoMissing(*outpoint))
+        });
+        let filter = match filter {
+            Ok(filter) => filter,
+            Err(e) => {
+                self.prevouts.disconnect(height, block)?;
+                return Err(OpError::from(
+                    format!("block filter at height {}: {}", height, e).as_str(),
+                ));
+            }
+        };
+        let previous = match self.filters.last() {
+            Some((_, header)) => *header,
+            None => FilterHeader::from_inner([0; 32]),
+        };
+        let header = filter.filter_header(&previous);
+        self.filters.push((filter, header));
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        if height + 1 != self.filters.len() {
+            return Err(OpError::from(
+                format!("block filters: cannot disconnect height {}", height).as_str(),
+            ));
+        }
+        self.prevouts.disconnect(height, block)?;
+        self.filters.pop();
+        Ok(())
+    }
+}
@@ -0,0 +1,681 @@
+//!
+//! Keep indexes in sync with a live datadir.
+//!
+//! `IndexerDaemon` polls the datadir, and connects new blocks to its
+//! `ChainIndexer`s (disconnecting reorged blocks first, from the tip down).
+//! The RocksDB `index::TxIndex` and `index::AddressIndex` are indexers
+//! persisting their tip: a restarted daemon resumes from it.
+//! Built-in indexers map transactions to heights, scripts to their
+//! history, outputs to their spending inputs, and blocks to their BIP158
+//! filters and statistics. They are kept in memory, and rebuilt from
+//! the genesis block by each new daemon.
+//! Indexers are shared as `Arc<Mutex<_>>`, so that they can be queried
+//! while the daemon runs in another thread.
+//! Wrap them in `SnapshotIndexer` to read consistent states during
+//! catch-up and reorgs, without waiting for the daemon.
+//!
+//! Each round re-reads the block index (`BitcoinDB::refresh`), a LevelDB
+//! locked by a running `bitcoind`: the daemon follows a datadir the node
+//! is not running on (e.g. a copy synced periodically, or a node stopped
+//! between rounds), and stops with an error while the node holds the lock.
+//!
+//! # Example
+//!
+//! ```rust
+//! use bitcoin_explorer::daemon::{DaemonConfig, IndexerDaemon};
+//! use bitcoin_explorer::index::TxIndex;
+//! use std::path::Path;
+//! use std::sync::{Arc, Mutex};
+//! use std::thread;
+//!
+//! let tx_index = Arc::new(Mutex::new(TxIndex::open(Path::new("./tx_index")).unwrap()));
+//! let config = DaemonConfig::new(Path::new("/Users/me/bitcoin")).with_indexer(tx_index.clone());
+//! let stop = config.stop_handle();
+//! let daemon = thread::spawn(move || IndexerDaemon::run(config));
+//!
+//! // ... query `tx_index.lock().unwrap().indexed_height()` meanwhile
+//!
+//! stop.stop();
+//! daemon.join().unwrap().unwrap();
+//! ```
+//!
+mod builtin;
+#[cfg(feature = "on-disk-utxo")]
+mod persistent;
+mod snapshot;
+
+pub use builtin::{
+    AddressHistoryIndex, BlockFilterIndex, BlockStats, BlockStatsRollup, SpendingInput, SpentIndex,
+    TxHeightIndex, DEFAULT_UNDO_DEPTH,
+};
+pub use snapshot::{Snapshot, SnapshotIndexer, SnapshotReader};
+
+use crate::api::{BitcoinDB, Block, BlockHash};
+use crate::parser::errors::{OpError, OpResult};
+use log::info;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+///
+/// An index maintained by `IndexerDaemon`.
+///
+/// Blocks are connected in increasing height order,
+/// and disconnected (on reorgs) in decreasing height order.
+///
+/// An indexer returning an error must be left unchanged by the call:
+/// the daemon then undoes the block on the indexers updated before it,
+/// so that all indexers stay at the same tip.
+///
+pub trait ChainIndexer: Send {
+    /// name of this index, for logging
+    fn name(&self) -> &'static str;
+
+    ///
+    /// Height and hash of the last block connected before the daemon
+    /// started (e.g. persisted by the index), `None` if it is empty.
+    ///
+    fn indexed_tip(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        Ok(None)
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()>;
+
+    /// undo `connect_block` of the same block
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()>;
+
+    /// called at the end of each sync round (e.g. to persist the index)
+    fn flush(&mut self) -> OpResult<()> {
+        Ok(())
+    }
+}
+
+///
+/// Stops `IndexerDaemon::run` after the current round.
+///
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+///
+/// Configuration of `IndexerDaemon`.
+///
+#[derive(Clone)]
+pub struct DaemonConfig {
+    pub datadir: PathBuf,
+    /// whether to open Core's txindex (for queries through `IndexerDaemon::db`)
+    pub tx_index: bool,
+    /// interval between two sync rounds
+    pub poll_interval: Duration,
+    pub indexers: Vec<Arc<Mutex<dyn ChainIndexer>>>,
+    pub stop: StopHandle,
+}
+
+impl DaemonConfig {
+    pub fn new(datadir: &Path) -> Self {
+        DaemonConfig {
+            datadir: datadir.to_path_buf(),
+            tx_index: false,
+            poll_interval: Duration::from_secs(10),
+            indexers: Vec::new(),
+            stop: StopHandle::default(),
+        }
+    }
+
+    pub fn with_indexer<I: ChainIndexer + 'static>(mut self, indexer: Arc<Mutex<I>>) -> Self {
+        self.indexers.push(indexer);
+        self
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+}
+
+///
+/// Result of a sync round.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// number of blocks disconnected by a reorg
+    pub disconnected: usize,
+    pub connected: usize,
+    /// height and hash of the indexed tip
+    pub tip: Option<(usize, BlockHash)>,
+}
+
+///
+/// Maintains `ChainIndexer`s on a datadir, through reorgs.
+///
+pub struct IndexerDaemon {
+    config: DaemonConfig,
+    /// hashes of indexed blocks, by height
+    chain: Vec<BlockHash>,
+    /// the database the indexed chain was read from
+    db: Option<BitcoinDB>,
+}
+
+impl IndexerDaemon {
+    pub fn new(config: DaemonConfig) -> Self {
+        IndexerDaemon {
+            config,
+            chain: Vec::new(),
+            db: None,
+        }
+    }
+
+    ///
+    /// Sync every `poll_interval` until stopped by `config.stop`.
+    ///
+    /// Errors of indexers and failures to read the datadir (e.g. locked
+    /// by a running node) are returned.
+    ///
+    pub fn run(config: DaemonConfig) -> OpResult<()> {
+        let mut daemon = IndexerDaemon::new(config);
+        while !daemon.config.stop.is_stopped() {
+            let report = daemon.sync_once()?;
+            if report.connected > 0 || report.disconnected > 0 {
+                info!(
+                    "indexer daemon: -{} +{} blocks, tip {:?}",
+                    report.disconnected, report.connected, report.tip
+                );
+            }
+            thread::sleep(daemon.config.poll_interval);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Refresh the database of the last sync (open the datadir
+    /// on the first one), and sync once.
+    ///
+    pub fn sync_once(&mut self) -> OpResult<SyncReport> {
+        let db = match &self.db {
+            // the previous view is kept to read disconnected blocks
+            Some(db) => {
+                let mut db = db.clone();
+                db.refresh()?;
+                db
+            }
+            None => BitcoinDB::new(&self.config.datadir, self.config.tx_index)?,
+        };
+        self.sync_with(db)
+    }
+
+    /// height and hash of the indexed tip
+    pub fn tip(&self) -> Option<(usize, BlockHash)> {
+        self.chain.last().map(|hash| (self.chain.len() - 1, *hash))
+    }
+
+    /// the database of the last sync, for queries consistent with the indexes
+    pub fn db(&self) -> Option<&BitcoinDB> {
+        self.db.as_ref()
+    }
+
+    ///
+    /// Start from the tip persisted by the indexers,
+    /// which must be the same for all of them.
+    ///
+    fn resume(&mut self, db: &BitcoinDB) -> OpResult<()> {
+        let mut tip = None;
+        for (i, indexer) in self.config.indexers.iter().enumerate() {
+            let indexer = indexer.lock().unwrap();
+            let indexed = indexer
+                .indexed_tip()
+                .map_err(|e| e.join_msg(&format!(" (indexer {})", indexer.name())))?;
+            if i > 0 && indexed != tip {
+                return Err(OpError::from(
+                    format!(
+                        "indexer {} is at tip {:?}, others at {:?}",
+                        indexer.name(),
+                        indexed,
+                        tip
+                    )
+                    .as_str(),
+                ));
+            }
+            tip = indexed;
+        }
+        if let Some((height, hash)) = tip {
+            if db.get_hash_from_height(height).ok() != Some(hash) {
+                return Err(OpError::from(
+                    format!(
+                        "indexed tip {} at height {} is not in the main chain",
+                        hash, height
+                    )
+                    .as_str(),
+                ));
+            }
+            self.chain = (0..=height)
+                .map(|h| db.get_hash_from_height(h))
+                .collect::<OpResult<_>>()?;
+        }
+        Ok(())
+    }
+
+    fn sync_with(&mut self, db: BitcoinDB) -> OpResult<SyncReport> {
+        if self.chain.is_empty() {
+            self.resume(&db)?;
+        }
+        let count = db.get_block_count();
+        // number of blocks in common with the new chain
+        let mut common = self.chain.len().min(count);
+        while common > 0 && db.get_hash_from_height(common - 1)? != self.chain[common - 1] {
+            common -= 1;
+        }
+        let mut report = SyncReport::default();
+
+        // disconnect reorged blocks, reading them from the previous database
+        while self.chain.len() > common {
+            let height = self.chain.len() - 1;
+            let old_db = self
+                .db
+                .as_ref()
+                .ok_or_else(|| OpError::from("no database of indexed blocks"))?;
+            let block = old_db.get_block::<Block>(height)?;
+            if block.block_hash() != self.chain[height] {
+                return Err(OpError::from(
+                    format!("cannot read disconnected block at height {}", height).as_str(),
+                ));
+            }
+            self.apply_block(height, &block, false)?;
+            self.chain.pop();
+            report.disconnected += 1;
+        }
+
+        for block in db.iter_block::<Block>(common, count) {
+            let height = self.chain.len();
+            self.apply_block(height, &block, true)?;
+            self.chain.push(block.block_hash());
+            report.connected += 1;
+        }
+        self.apply(|indexer| indexer.flush())?;
+        self.db = Some(db);
+        report.tip = self.tip();
+        Ok(report)
+    }
+
+    ///
+    /// Connect (or disconnect) `block` on every indexer. If an indexer fails,
+    /// the indexers updated before it are reverted, so that they all stay
+    /// at the same tip.
+    ///
+    fn apply_block(&self, height: usize, block: &Block, connect: bool) -> OpResult<()> {
+        let step = |indexer: &mut dyn ChainIndexer, connect: bool| {
+            if connect {
+                indexer.connect_block(height, block)
+            } else {
+                indexer.disconnect_block(height, block)
+            }
+        };
+        for (i, indexer) in self.config.indexers.iter().enumerate() {
+            let result = {
+                let mut indexer = indexer.lock().unwrap();
+                step(&mut *indexer, connect)
+                    .map_err(|e| e.join_msg(&format!(" (indexer {})", indexer.name())))
+            };
+            if let Err(e) = result {
+                for done in self.config.indexers[..i].iter().rev() {
+                    let mut done = done.lock().unwrap();
+                    if let Err(undo) = step(&mut *done, !connect) {
+                        return Err(e.join_msg(&format!(
+                            ", then failed to revert indexer {}: {}",
+                            done.name(),
+                            undo
+                        )));
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// run `f` on every indexer, tagging errors with the indexer name
+    fn apply<F>(&self, mut f: F) -> OpResult<()>
+    where
+        F: FnMut(&mut dyn ChainIndexer) -> OpResult<()>,
+    {
+        for indexer in self.config.indexers.iter() {
+            let mut indexer = indexer.lock().unwrap();
+            if let Err(e) = f(&mut *indexer) {
+                return Err(e.join_msg(&format!(" (indexer {})", indexer.name())));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ShardMerge;
+    use crate::index::AddressEventKind;
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    #[test]
+    fn test_daemon_reorg() {
+        let dir = TestDir::new("daemon");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 4);
+        chain.write(&dir).unwrap();
+        let old = chain.main_chain();
+
+        let tx_index = Arc::new(Mutex::new(TxHeightIndex::default()));
+        let stats = Arc::new(Mutex::new(BlockStatsRollup::default()));
+        let config = DaemonConfig::new(&dir)
+            .with_indexer(tx_index.clone())
+            .with_indexer(stats.clone());
+        let mut daemon = IndexerDaemon::new(config);
+        let report = daemon.sync_once().unwrap();
+        assert_eq!(report.connected, 5);
+        assert_eq!(report.tip, Some((4, old[4])));
+        let orphaned_coinbase = chain.block(&old[4]).unwrap().txdata[0].txid();
+        assert_eq!(
+            tx_index.lock().unwrap().height_of(&orphaned_coinbase),
+            Some(4)
+        );
+
+        // nothing new
+        assert_eq!(daemon.sync_once().unwrap().connected, 0);
+
+        // a longer branch from height 2
+        chain.extend(&old[2], 3);
+        chain.write(&dir).unwrap();
+        let new = chain.main_chain();
+        let report = daemon.sync_once().unwrap();
+        assert_eq!(report.disconnected, 2);
+        assert_eq!(report.connected, 3);
+        assert_eq!(report.tip, Some((5, new[5])));
+
+        let tx_index = tx_index.lock().unwrap();
+        assert_eq!(tx_index.height_of(&orphaned_coinbase), None);
+        let coinbase = chain.block(&new[5]).unwrap().txdata[0].txid();
+        assert_eq!(tx_index.height_of(&coinbase), Some(5));
+        assert_eq!(tx_index.len(), 6);
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.len(), 6);
+        assert_eq!(stats.total().n_tx, 6);
+    }
+
+    #[test]
+    fn test_builtin_indexers() {
+        let dir = TestDir::new("daemon_builtin");
+
+        // block 3 spends the coinbase of block 1 to the miner of block 2
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let script_of = |chain: &SyntheticChain, hash| {
+            chain.block(hash).unwrap().txdata[0].output[0]
+                .script_pubkey
+                .clone()
+        };
+        let (miner_1, miner_2) = (script_of(&chain, &tips[0]), script_of(&chain, &tips[1]));
+        let outpoint = chain.coinbase_outpoint(&tips[0]).unwrap();
+        let mut spend = SyntheticChain::spend(outpoint, 1000);
+        spend.output[0].script_pubkey = miner_2.clone();
+        let spending = chain.mine(&tips[1], vec![spend.clone()]);
+        chain.write(&dir).unwrap();
+
+        let addresses = Arc::new(Mutex::new(AddressHistoryIndex::default()));
+        let spent = Arc::new(Mutex::new(SpentIndex::default()));
+        let filters = Arc::new(Mutex::new(BlockFilterIndex::default()));
+        let config = DaemonConfig::new(&dir)
+            .with_indexer(addresses.clone())
+            .with_indexer(spent.clone())
+            .with_indexer(filters.clone());
+        let mut daemon = IndexerDaemon::new(config);
+        assert_eq!(daemon.sync_once().unwrap().connected, 4);
+        {
+            let addresses = addresses.lock().unwrap();
+            let kinds: Vec<_> = addresses
+                .history(&miner_1)
+                .iter()
+                .map(|e| (e.height, e.kind))
+                .collect();
+            assert_eq!(
+                kinds,
+                vec![
+                    (1, AddressEventKind::Received),
+                    (3, AddressEventKind::Spent)
+                ]
+            );
+            assert_eq!(addresses.balance(&miner_1), 0);
+            assert_eq!(addresses.balance(&miner_2), 50 * 100_000_000 + 1000);
+            let spender = SpendingInput {
+                txid: spend.txid(),
+                vin: 0,
+                height: 3,
+            };
+            assert_eq!(spent.lock().unwrap().spender(&outpoint), Some(spender));
+            let filters = filters.lock().unwrap();
+            assert_eq!(filters.len(), 4);
+            let filter = filters.filter(3).unwrap();
+            let mut query = std::iter::once(miner_1.as_bytes());
+            assert!(filter.match_any(&spending, &mut query).unwrap());
+            let header = filter.filter_header(&filters.filter_header(2).unwrap());
+            assert_eq!(filters.filter_header(3), Some(header));
+        }
+
+        // a longer branch from height 2, without the spend
+        chain.extend(&tips[1], 2);
+        chain.write(&dir).unwrap();
+        let report = daemon.sync_once().unwrap();
+        assert_eq!((report.disconnected, report.connected), (1, 2));
+        let addresses = addresses.lock().unwrap();
+        assert_eq!(addresses.history(&miner_1).len(), 1);
+        assert_eq!(addresses.balance(&miner_1), 50 * 100_000_000);
+        assert_eq!(addresses.balance(&miner_2), 50 * 100_000_000);
+        assert_eq!(spent.lock().unwrap().spender(&outpoint), None);
+        assert_eq!(filters.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_daemon_reverts_failed_block() {
+        let dir = TestDir::new("daemon_revert");
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let outpoint = chain.coinbase_outpoint(&tips[0]).unwrap();
+        chain.mine(&tips[1], vec![SyntheticChain::spend(outpoint, 1000)]);
+        chain.write(&dir).unwrap();
+
+        // the second indexer fails on the first block
+        let tx_index = Arc::new(Mutex::new(TxHeightIndex::default()));
+        let config = DaemonConfig::new(&dir)
+            .with_indexer(tx_index.clone())
+            .with_indexer(Arc::new(Mutex::new(BlockStatsRollup::starting_at(1))));
+        let mut daemon = IndexerDaemon::new(config);
+        assert!(daemon.sync_once().is_err());
+        assert!(tx_index.lock().unwrap().is_empty());
+        assert_eq!(daemon.tip(), None);
+
+        // the second indexer cannot disconnect blocks
+        let spent = Arc::new(Mutex::new(SpentIndex::default()));
+        let config = DaemonConfig::new(&dir)
+            .with_indexer(spent.clone())
+            .with_indexer(Arc::new(Mutex::new(AddressHistoryIndex::with_undo_depth(
+                0,
+            ))));
+        let mut daemon = IndexerDaemon::new(config);
+        daemon.sync_once().unwrap();
+        let tip = daemon.tip();
+        chain.extend(&tips[1], 2);
+        chain.write(&dir).unwrap();
+        assert!(daemon.sync_once().is_err());
+        assert_eq!(daemon.tip(), tip);
+        assert_eq!(spent.lock().unwrap().spender(&outpoint).unwrap().height, 3);
+    }
+
+    #[cfg(feature = "on-disk-utxo")]
+    #[test]
+    fn test_persistent_indexers() {
+        use crate::index::{AddressIndex, TxIndex};
+
+        let dir = TestDir::new("daemon_persistent");
+        let datadir = dir.join("datadir");
+        let open = || {
+            let tx_index = TxIndex::open(&dir.join("tx_index")).unwrap();
+            let addresses = AddressIndex::open(&dir.join("address_index")).unwrap();
+            (
+                Arc::new(Mutex::new(tx_index)),
+                Arc::new(Mutex::new(addresses)),
+            )
+        };
+
+        // block 3 spends the coinbase of block 1
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let miner = chain.block(&tips[0]).unwrap().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+        let outpoint = chain.coinbase_outpoint(&tips[0]).unwrap();
+        let spending = chain.mine(&tips[1], vec![SyntheticChain::spend(outpoint, 1000)]);
+        chain.write(&datadir).unwrap();
+
+        let (tx_index, addresses) = open();
+        let config = DaemonConfig::new(&datadir)
+            .with_indexer(tx_index.clone())
+            .with_indexer(addresses.clone());
+        let mut daemon = IndexerDaemon::new(config);
+        assert_eq!(daemon.sync_once().unwrap().connected, 4);
+        assert_eq!(
+            addresses
+                .lock()
+                .unwrap()
+                .script_history(&miner)
+                .unwrap()
+                .len(),
+            2
+        );
+        drop((daemon, tx_index, addresses));
+
+        // a new daemon resumes from the persisted tip
+        let (tx_index, addresses) = open();
+        let config = DaemonConfig::new(&datadir)
+            .with_indexer(tx_index.clone())
+            .with_indexer(addresses.clone());
+        let mut daemon = IndexerDaemon::new(config);
+        let report = daemon.sync_once().unwrap();
+        assert_eq!(report.connected, 0);
+        assert_eq!(report.tip, Some((3, spending)));
+
+        // a longer branch from height 2, without the spend
+        chain.extend(&tips[1], 2);
+        chain.write(&datadir).unwrap();
+        let new = chain.main_chain();
+        let report = daemon.sync_once().unwrap();
+        assert_eq!((report.disconnected, report.connected), (1, 2));
+        assert_eq!(
+            tx_index.lock().unwrap().indexed_tip().unwrap(),
+            Some((4, new[4]))
+        );
+        let addresses_lock = addresses.lock().unwrap();
+        assert_eq!(addresses_lock.script_history(&miner).unwrap().len(), 1);
+        assert_eq!(
+            addresses_lock.script_balance(&miner, 4).unwrap(),
+            50 * 100_000_000
+        );
+        drop(addresses_lock);
+
+        // indexers must start at the same tip
+        let config = DaemonConfig::new(&datadir)
+            .with_indexer(addresses.clone())
+            .with_indexer(Arc::new(Mutex::new(TxHeightIndex::default())));
+        assert!(IndexerDaemon::new(config).sync_once().is_err());
+        drop((daemon, tx_index, addresses));
+    }
+
+    #[test]
+    fn test_snapshot_indexer() {
+        let dir = TestDir::new("snapshot");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 4);
+        chain.write(&dir).unwrap();
+        let old = chain.main_chain();
+
+        let indexer = SnapshotIndexer::new(TxHeightIndex::default()).with_publish_every(2);
+        let reader = indexer.reader();
+        let indexer = Arc::new(Mutex::new(indexer));
+        let mut daemon = IndexerDaemon::new(DaemonConfig::new(&dir).with_indexer(indexer.clone()));
+        assert_eq!(reader.snapshot().tip, None);
+        daemon.sync_once().unwrap();
+        let snapshot = reader.snapshot();
+        assert_eq!(snapshot.tip, Some((4, old[4])));
+        assert_eq!(snapshot.index.len(), 5);
+
+        // blocks applied outside of a round are published every 2 blocks
+        let mut indexer = indexer.lock().unwrap();
+        let orphan = chain.block(&old[4]).unwrap().clone();
+        indexer.disconnect_block(4, &orphan).unwrap();
+        assert_eq!(reader.snapshot().tip, Some((4, old[4])));
+        indexer.connect_block(4, &orphan).unwrap();
+        assert_eq!(reader.snapshot().tip, Some((4, old[4])));
+        let next = chain.mine(&old[4], vec![]);
+        indexer
+            .connect_block(5, chain.block(&next).unwrap())
+            .unwrap();
+        assert_eq!(reader.snapshot().tip, Some((5, next)));
+        assert_eq!(snapshot.index.len(), 5);
+
+        // rounds without changes do not republish
+        indexer.flush().unwrap();
+        let published = reader.snapshot();
+        indexer.flush().unwrap();
+        assert!(Arc::ptr_eq(&published, &reader.snapshot()));
+    }
+
+    #[test]
+    fn test_merge_shard_indexes() {
+        use crate::analysis::merge_shards;
+
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 4);
+        let blocks: Vec<_> = chain
+            .main_chain()
+            .iter()
+            .map(|h| chain.block(h).unwrap().clone())
+            .collect();
+
+        let mut full = (TxHeightIndex::default(), BlockStatsRollup::default());
+        let mut first = (TxHeightIndex::default(), BlockStatsRollup::default());
+        let mut second = (TxHeightIndex::default(), BlockStatsRollup::starting_at(3));
+        for (height, block) in blocks.iter().enumerate() {
+            full.0.connect_block(height, block).unwrap();
+            full.1.connect_block(height, block).unwrap();
+            let shard = if height < 3 { &mut first } else { &mut second };
+            shard.0.connect_block(height, block).unwrap();
+            shard.1.connect_block(height, block).unwrap();
+        }
+        assert!(second.1.connect_block(0, &blocks[0]).is_err());
+
+        let (range, tx_index) = merge_shards(vec![(3..5, second.0), (0..3, first.0)]).unwrap();
+        assert_eq!(range, 0..5);
+        assert_eq!(tx_index.len(), full.0.len());
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(tx_index.height_of(&block.txdata[0].txid()), Some(height));
+        }
+        let (_, stats) = merge_shards(vec![(3..5, second.1), (0..3, first.1)]).unwrap();
+        assert_eq!(stats.len(), 5);
+        assert_eq!(stats.total(), full.1.total());
+        assert_eq!(stats.get(4), full.1.get(4));
+        assert!(BlockStatsRollup::default()
+            .merge_shard(BlockStatsRollup::starting_at(1))
+            .is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//!
+//! The RocksDB indexes of `index` as `ChainIndexer`s,
+//! resumed by `IndexerDaemon` from their persisted tip.
+//!
+use crate::api::{Block, BlockHash};
+use crate::daemon::ChainIndexer;
+use crate::index::{AddressIndex, TxIndex};
+use crate::parser::errors::{OpError, OpResult};
+
+///
+/// Check that `block`, at `height`, is a child of the indexed `tip`.
+///
+fn check_extends(
+    name: &str,
+    tip: Option<(usize, BlockHash)>,
+    height: usize,
+    block: &Block,
+) -> OpResult<()> {
+    let extends = match tip {
+        Some((tip_height, hash)) => tip_height + 1 == height && hash == block.header.prev_blockhash,
+        None => height == 0,
+    };
+    if extends {
+        Ok(())
+    } else {
+        Err(OpError::from(
+            format!(
+                "{}: block at height {} does not extend the indexed tip {:?}",
+                name, height, tip
+            )
+            .as_str(),
+        ))
+    }
+}
+
+///
+/// Check that `block`, at `height`, is the indexed `tip`.
+///
+fn check_is_tip(
+    name: &str,
+    tip: Option<(usize, BlockHash)>,
+    height: usize,
+    block: &Block,
+) -> OpResult<()> {
+    if tip == Some((height, block.block_hash())) {
+        Ok(())
+    } else {
+        Err(OpError::from(
+            format!(
+                "{}: block at height {} is not the indexed tip {:?}",
+                name, height, tip
+            )
+            .as_str(),
+        ))
+    }
+}
+
+///
+/// Blocks are written as by `TxIndex::update`, each atomically.
+///
+impl ChainIndexer for TxIndex {
+    fn name(&self) -> &'static str {
+        "tx_index"
+    }
+
+    fn indexed_tip(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        self.tip()
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        check_extends(self.name(), self.tip()?, height, block)?;
+        self.index_block(height, block)
+    }
+
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        check_is_tip(self.name(), self.tip()?, height, block)?;
+        self.unindex_block(height)
+    }
+}
+
+///
+/// Blocks are written as by `AddressIndex::update`, each atomically.
+/// The outputs spent by the last 100 blocks are kept to disconnect them.
+///
+impl ChainIndexer for AddressIndex {
+    fn name(&self) -> &'static str {
+        "address_index"
+    }
+
+    fn indexed_tip(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        self.tip()
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        check_extends(self.name(), self.tip()?, height, block)?;
+        self.index_block(height, block)
+    }
+
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        check_is_tip(self.name(), self.tip()?, height, block)?;
+        self.unindex_block(height, block)
+    }
+}
@@ -0,0 +1,154 @@
+//!
+//! Consistent reads of indexes while `IndexerDaemon` updates them.
+//!
+use crate::api::{Block, BlockHash};
+use crate::daemon::ChainIndexer;
+use crate::parser::errors::OpResult;
+use std::sync::{Arc, RwLock};
+
+///
+/// A published state of an index, with the tip it was built up to.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot<I> {
+    /// height and hash of the last connected block
+    pub tip: Option<(usize, BlockHash)>,
+    pub index: I,
+}
+
+///
+/// Reads the last published `Snapshot` of a `SnapshotIndexer`.
+///
+/// Cheap to clone, and never blocked by the daemon for longer than
+/// the swap of a pointer.
+///
+pub struct SnapshotReader<I>(Arc<RwLock<Arc<Snapshot<I>>>>);
+
+impl<I> Clone for SnapshotReader<I> {
+    fn clone(&self) -> Self {
+        SnapshotReader(self.0.clone())
+    }
+}
+
+impl<I> SnapshotReader<I> {
+    pub fn snapshot(&self) -> Arc<Snapshot<I>> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+///
+/// Wrap a `ChainIndexer`, so that queries see a consistent state
+/// while the daemon catches up or handles a reorg.
+///
+/// Blocks are applied to a working copy. A clone of it is published
+/// (replacing the previous snapshot atomically) at the end of each sync
+/// round that changed it, and every `publish_every` connected blocks
+/// during long rounds.
+/// Snapshots are never taken in the middle of a reorg: a published state
+/// is always that of a chain prefix.
+///
+/// Publishing clones the whole index, so choose `publish_every`
+/// according to its size. Indexers of the daemon keep their state in
+/// memory, so there is no storage snapshot (e.g. of RocksDB) to hand
+/// to readers instead.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::daemon::{DaemonConfig, IndexerDaemon, SnapshotIndexer, TxHeightIndex};
+/// use std::path::Path;
+/// use std::sync::{Arc, Mutex};
+/// use std::thread;
+///
+/// let indexer = SnapshotIndexer::new(TxHeightIndex::default()).with_publish_every(10000);
+/// let reader = indexer.reader();
+/// let config =
+///     DaemonConfig::new(Path::new("/Users/me/bitcoin")).with_indexer(Arc::new(Mutex::new(indexer)));
+/// thread::spawn(move || IndexerDaemon::run(config));
+///
+/// // served during catch-up, from the last published state
+/// let snapshot = reader.snapshot();
+/// println!("{} transactions up to {:?}", snapshot.index.len(), snapshot.tip);
+/// ```
+///
+pub struct SnapshotIndexer<I> {
+    working: Snapshot<I>,
+    published: Arc<RwLock<Arc<Snapshot<I>>>>,
+    /// `0` to publish only at the end of sync rounds
+    publish_every: usize,
+    connected_since_publish: usize,
+    /// the working copy changed since the last publication
+    dirty: bool,
+}
+
+impl<I: Clone> SnapshotIndexer<I> {
+    pub fn new(index: I) -> Self {
+        let working = Snapshot { tip: None, index };
+        SnapshotIndexer {
+            published: Arc::new(RwLock::new(Arc::new(working.clone()))),
+            working,
+            publish_every: 0,
+            connected_since_publish: 0,
+            dirty: false,
+        }
+    }
+
+    pub fn with_publish_every(mut self, publish_every: usize) -> Self {
+        self.publish_every = publish_every;
+        self
+    }
+
+    pub fn reader(&self) -> SnapshotReader<I> {
+        SnapshotReader(self.published.clone())
+    }
+
+    fn publish(&mut self) {
+        let snapshot = Arc::new(self.working.clone());
+        *self.published.write().unwrap() = snapshot;
+        self.connected_since_publish = 0;
+        self.dirty = false;
+    }
+}
+
+impl<I> ChainIndexer for SnapshotIndexer<I>
+where
+    I: ChainIndexer + Clone + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        self.working.index.name()
+    }
+
+    fn indexed_tip(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        self.working.index.indexed_tip()
+    }
+
+    fn connect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        self.working.index.connect_block(height, block)?;
+        self.working.tip = Some((height, block.block_hash()));
+        self.connected_since_publish += 1;
+        self.dirty = true;
+        if self.publish_every > 0 && self.connected_since_publish >= self.publish_every {
+            self.publish();
+        }
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, height: usize, block: &Block) -> OpResult<()> {
+        self.working.index.disconnect_block(height, block)?;
+        self.working.tip = height
+            .checked_sub(1)
+            .map(|parent| (parent, block.header.prev_blockhash));
+        // count the blocks connected on the new branch only
+        self.connected_since_publish = 0;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> OpResult<()> {
+        self.working.index.flush()?;
+        if self.dirty {
+            self.publish();
+        }
+        Ok(())
+    }
+}
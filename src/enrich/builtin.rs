@@ -0,0 +1,179 @@
+//!
+//! Built-in enrichers.
+//!
+use crate::analysis::{to_fiat, PriceTable};
+use crate::api::{Address, Amount, Block, Transaction, Txid};
+use crate::enrich::{EnrichContext, Enricher, Enrichments};
+use crate::parser::proto::amount::fee_of;
+use crate::parser::script::{evaluate_script, ScriptType};
+use std::sync::Arc;
+
+/// txid of each transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxIds(pub Vec<Txid>);
+
+/// script type of each output of each transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputScriptTypes(pub Vec<Vec<ScriptType>>);
+
+/// addresses of each output of each transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputAddresses(pub Vec<Vec<Box<[Address]>>>);
+
+///
+/// Fee of each transaction,
+/// `None` for coinbase and transactions whose inputs cannot be found
+/// (e.g. txindex is not available).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fees(pub Vec<Option<Amount>>);
+
+///
+/// Fiat values of a block at the price of its `PriceTable`.
+///
+/// `price` is `None` (and all values are 0) if the table
+/// has no price at or before this block.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiatValues {
+    pub price: Option<f64>,
+    /// fiat value of each output of each transaction
+    pub outputs: Vec<Vec<f64>>,
+    ///
+    /// fiat fee of each transaction,
+    /// only computed if `Fees` is computed in an earlier stage
+    ///
+    pub fees: Option<Vec<Option<f64>>>,
+}
+
+impl FiatValues {
+    /// fiat value of all outputs (including coinbase)
+    pub fn total_output(&self) -> f64 {
+        self.outputs.iter().flatten().sum()
+    }
+
+    /// fiat value of all known fees
+    pub fn total_fees(&self) -> f64 {
+        self.fees.iter().flatten().flatten().sum()
+    }
+}
+
+pub(crate) struct TxIdsEnricher;
+pub(crate) struct ScriptTypesEnricher;
+pub(crate) struct AddressesEnricher;
+pub(crate) struct FeesEnricher;
+pub(crate) struct FiatEnricher(pub(crate) Arc<PriceTable>);
+
+impl Enricher for TxIdsEnricher {
+    fn name(&self) -> &'static str {
+        "txids"
+    }
+
+    fn enrich(&self, _ctx: &EnrichContext, block: &Block, out: &mut Enrichments) {
+        out.insert(TxIds(block.txdata.iter().map(|tx| tx.txid()).collect()));
+    }
+}
+
+impl Enricher for ScriptTypesEnricher {
+    fn name(&self) -> &'static str {
+        "script_types"
+    }
+
+    fn enrich(&self, ctx: &EnrichContext, block: &Block, out: &mut Enrichments) {
+        let network = ctx.db.network();
+        out.insert(OutputScriptTypes(
+            block
+                .txdata
+                .iter()
+                .map(|tx| {
+                    tx.output
+                        .iter()
+                        .map(|o| evaluate_script(&o.script_pubkey, network).pattern)
+                        .collect()
+                })
+                .collect(),
+        ));
+    }
+}
+
+impl Enricher for AddressesEnricher {
+    fn name(&self) -> &'static str {
+        "addresses"
+    }
+
+    fn enrich(&self, ctx: &EnrichContext, block: &Block, out: &mut Enrichments) {
+        let network = ctx.db.network();
+        out.insert(OutputAddresses(
+            block
+                .txdata
+                .iter()
+                .map(|tx| {
+                    tx.output
+                        .iter()
+                        .map(|o| {
+                            evaluate_script(&o.script_pubkey, network)
+                                .addresses
+                                .into_boxed_slice()
+                        })
+                        .collect()
+                })
+                .collect(),
+        ));
+    }
+}
+
+impl Enricher for FeesEnricher {
+    fn name(&self) -> &'static str {
+        "fees"
+    }
+
+    fn enrich(&self, ctx: &EnrichContext, block: &Block, out: &mut Enrichments) {
+        out.insert(Fees(
+            block.txdata.iter().map(|tx| tx_fee(ctx, tx)).collect(),
+        ));
+    }
+}
+
+impl Enricher for FiatEnricher {
+    fn name(&self) -> &'static str {
+        "fiat"
+    }
+
+    fn enrich(&self, ctx: &EnrichContext, block: &Block, out: &mut Enrichments) {
+        let price = self.0.price_at(ctx.height, block.header.time);
+        let p = price.unwrap_or(0.0);
+        let outputs = block
+            .txdata
+            .iter()
+            .map(|tx| tx.output.iter().map(|o| to_fiat(o.value, p)).collect())
+            .collect();
+        let fees = out.get::<Fees>().map(|fees| {
+            fees.0
+                .iter()
+                .map(|fee| fee.map(|f| to_fiat(f.as_sat(), p)))
+                .collect()
+        });
+        out.insert(FiatValues {
+            price,
+            outputs,
+            fees,
+        });
+    }
+
+    fn fingerprint(&self) -> u64 {
+        self.0.fingerprint()
+    }
+}
+
+fn tx_fee(ctx: &EnrichContext, tx: &Transaction) -> Option<Amount> {
+    if tx.is_coin_base() {
+        return None;
+    }
+    let mut inputs = Vec::with_capacity(tx.input.len());
+    for input in &tx.input {
+        let prev: Transaction = ctx.db.get_transaction(&input.previous_output.txid).ok()?;
+        let prev_out = prev.output.get(input.previous_output.vout as usize)?;
+        inputs.push(Amount::from_sat(prev_out.value));
+    }
+    fee_of(inputs, tx.output.iter().map(|o| Amount::from_sat(o.value)))
+}
@@ -0,0 +1,170 @@
+//!
+//! On-disk cache of per-block results of enriched iterations.
+//!
+use crate::analysis::hll::hash64;
+use crate::api::{BitcoinDB, Block, BlockHash};
+use crate::enrich::{EnrichSpec, EnrichedBlock};
+use crate::iter::par_map_ordered;
+use crate::parser::errors::OpResult;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+///
+/// A content-addressed store of per-block results,
+/// keyed by (block hash, cache key).
+///
+/// Each result is a JSON file under `{dir}/{cache key}/`, sharded by the
+/// last two hex digits of the block hash. Writes are atomic (renamed
+/// from a temporary file), so concurrent runs never read partial results.
+///
+#[derive(Debug, Clone)]
+pub struct ResultsCache {
+    dir: PathBuf,
+}
+
+impl ResultsCache {
+    ///
+    /// Open (or create) a cache at `dir`.
+    ///
+    pub fn open(dir: &Path) -> OpResult<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(ResultsCache {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    ///
+    /// The cached result of a block, `None` if absent or unreadable.
+    ///
+    pub fn get<T: DeserializeOwned>(&self, block_hash: &BlockHash, key: u64) -> Option<T> {
+        let file = File::open(self.path_of(block_hash, key)).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    pub fn put<T: Serialize>(&self, block_hash: &BlockHash, key: u64, value: &T) -> OpResult<()> {
+        let path = self.path_of(block_hash, key);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension(format!("{}.partial", std::process::id()));
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut w, value).map_err(std::io::Error::from)?;
+        w.flush()?;
+        drop(w);
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    ///
+    /// Remove all results of a cache key.
+    ///
+    pub fn clear(&self, key: u64) -> OpResult<()> {
+        let dir = self.dir.join(format!("{:016x}", key));
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    fn path_of(&self, block_hash: &BlockHash, key: u64) -> PathBuf {
+        let hash = block_hash.to_string();
+        self.dir
+            .join(format!("{:016x}", key))
+            .join(&hash[hash.len() - 2..])
+            .join(format!("{}.json", hash))
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Like `iter_enriched`, but each block is converted by `to_proto`,
+    /// and results are read from / written to `cache`.
+    ///
+    /// Results are keyed by block hash and by `spec.cache_key()` combined
+    /// with the type of `T`, so changing the spec or the output type does
+    /// not reuse stale results. A custom `to_proto` with the same output
+    /// type must be distinguished by `salt`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::enrich::{EnrichSpec, ResultsCache, TxIds};
+    /// use bitcoin_explorer::{BitcoinDB, Txid};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    /// let cache = ResultsCache::open(Path::new("./cache")).unwrap();
+    ///
+    /// let spec = EnrichSpec::new().txids();
+    /// // the second run is served from the cache
+    /// for _ in 0..2 {
+    ///     for txids in db.iter_enriched_cached(0, 100000, &spec, &cache, 0, |mut blk| {
+    ///         blk.enrichments.remove::<TxIds>().unwrap().0
+    ///     }) {
+    ///         let txids: Vec<Txid> = txids;
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn iter_enriched_cached<T, F>(
+        &self,
+        start: usize,
+        end: usize,
+        spec: &EnrichSpec,
+        cache: &ResultsCache,
+        salt: u64,
+        to_proto: F,
+    ) -> impl Iterator<Item = T>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        F: Fn(EnrichedBlock) -> T + Send + Sync + 'static,
+    {
+        let db = self.clone();
+        let spec_copy = spec.clone();
+        let cache = cache.clone();
+        let key = spec.cache_key()
+            ^ hash64(format!("{}:{}", std::any::type_name::<T>(), salt).as_bytes());
+        let end = end.min(self.get_block_count());
+        par_map_ordered(
+            start..end,
+            move |height| {
+                let hash = db.get_hash_from_height(height).ok()?;
+                if let Some(cached) = cache.get::<T>(&hash, key) {
+                    return Some(cached);
+                }
+                let block = db.get_block::<Block>(height).ok()?;
+                let value = to_proto(spec_copy.run(&db, height, block));
+                if let Err(e) = cache.put(&hash, key, &value) {
+                    warn!("failed to cache block {}: {}", height, e);
+                }
+                Some(value)
+            },
+            spec.options,
+        )
+        .map_while(|value| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResultsCache;
+    use crate::api::BlockHash;
+    use crate::testutil::TestDir;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_results_cache() {
+        let dir = TestDir::new("results_cache");
+        let cache = ResultsCache::open(&dir).unwrap();
+        let hash = BlockHash::hash(b"block");
+        assert_eq!(cache.get::<Vec<u32>>(&hash, 1), None);
+        cache.put(&hash, 1, &vec![1u32, 2, 3]).unwrap();
+        assert_eq!(cache.get::<Vec<u32>>(&hash, 1), Some(vec![1, 2, 3]));
+        assert_eq!(cache.get::<Vec<u32>>(&hash, 2), None);
+        cache.clear(1).unwrap();
+        assert_eq!(cache.get::<Vec<u32>>(&hash, 1), None);
+    }
+}
@@ -0,0 +1,282 @@
+//!
+//! Composable enrichment of blocks.
+//!
+//! Instead of a fixed output format (`SBlock`, `FBlock`, ...),
+//! an `EnrichSpec` lists the enrichments to compute for each block,
+//! and `BitcoinDB::iter_enriched` runs them in worker threads.
+//! Only requested enrichments are computed.
+//!
+//! Enrichments are stored by type in `Enrichments`,
+//! so custom `Enricher`s can attach their own types.
+//!
+//! # Example
+//!
+//! ```rust
+//! use bitcoin_explorer::BitcoinDB;
+//! use bitcoin_explorer::enrich::{EnrichSpec, OutputScriptTypes, TxIds};
+//! use std::path::Path;
+//!
+//! let path = Path::new("/Users/me/bitcoin");
+//! let db = BitcoinDB::new(path, false).unwrap();
+//!
+//! let spec = EnrichSpec::new().txids().script_types();
+//! for blk in db.iter_enriched(0, 1000, &spec) {
+//!     let txids = blk.enrichments.get::<TxIds>().unwrap();
+//!     let types = blk.enrichments.get::<OutputScriptTypes>().unwrap();
+//!     println!("{}: {} txs, {} outputs", blk.height, txids.0.len(), types.0.concat().len());
+//! }
+//! ```
+//!
+mod builtin;
+mod cache;
+
+pub use builtin::{Fees, FiatValues, OutputAddresses, OutputScriptTypes, TxIds};
+pub use cache::ResultsCache;
+
+use crate::analysis::hll::hash64;
+use crate::analysis::PriceTable;
+use crate::api::{BitcoinDB, Block};
+use crate::iter::{par_map_ordered, ParMapOptions};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+///
+/// Context available to enrichers.
+///
+pub struct EnrichContext<'a> {
+    pub db: &'a BitcoinDB,
+    pub height: usize,
+}
+
+///
+/// A stage of the enrichment pipeline.
+///
+/// Enrichers run in worker threads, once per block,
+/// in the order they are added to the `EnrichSpec`.
+///
+pub trait Enricher: Send + Sync {
+    /// name of this enrichment
+    fn name(&self) -> &'static str;
+
+    /// compute the enrichment of `block` and insert it to `out`
+    fn enrich(&self, ctx: &EnrichContext, block: &Block, out: &mut Enrichments);
+
+    ///
+    /// Identifies the parameters of this enricher in `EnrichSpec::cache_key`,
+    /// enrichers with parameters must override it.
+    ///
+    fn fingerprint(&self) -> u64 {
+        0
+    }
+}
+
+///
+/// Enrichments of a block, indexed by type.
+///
+#[derive(Default)]
+pub struct Enrichments {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Enrichments {
+    /// insert (or replace) an enrichment of type `T`
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    /// the enrichment of type `T`, if computed
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref::<T>())
+    }
+
+    /// take the enrichment of type `T` out
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.downcast::<T>().ok())
+            .map(|v| *v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for Enrichments {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Enrichments")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+///
+/// A block with its enrichments.
+///
+#[derive(Debug)]
+pub struct EnrichedBlock {
+    pub height: usize,
+    pub block: Block,
+    pub enrichments: Enrichments,
+}
+
+///
+/// The list of enrichments to compute.
+///
+#[derive(Clone, Default)]
+pub struct EnrichSpec {
+    stages: Vec<Arc<dyn Enricher>>,
+    /// threads and buffering of the pipeline
+    pub options: ParMapOptions,
+}
+
+impl EnrichSpec {
+    pub fn new() -> Self {
+        EnrichSpec::default()
+    }
+
+    /// add a custom stage
+    pub fn with<E: Enricher + 'static>(mut self, enricher: E) -> Self {
+        self.stages.push(Arc::new(enricher));
+        self
+    }
+
+    /// compute `TxIds`
+    pub fn txids(self) -> Self {
+        self.with(builtin::TxIdsEnricher)
+    }
+
+    /// compute `OutputScriptTypes`
+    pub fn script_types(self) -> Self {
+        self.with(builtin::ScriptTypesEnricher)
+    }
+
+    /// compute `OutputAddresses`
+    pub fn addresses(self) -> Self {
+        self.with(builtin::AddressesEnricher)
+    }
+
+    /// compute `Fees` (requires txindex)
+    pub fn fees(self) -> Self {
+        self.with(builtin::FeesEnricher)
+    }
+
+    ///
+    /// compute `FiatValues` with `prices`
+    /// (add after `fees` to also convert fees)
+    ///
+    pub fn fiat(self, prices: Arc<PriceTable>) -> Self {
+        self.with(builtin::FiatEnricher(prices))
+    }
+
+    /// names of the stages, in execution order
+    pub fn names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+
+    ///
+    /// Hash of the crate version and of the stages (names and fingerprints),
+    /// used to key results in `ResultsCache`.
+    ///
+    pub fn cache_key(&self) -> u64 {
+        let mut desc = String::from(env!("CARGO_PKG_VERSION"));
+        for stage in &self.stages {
+            desc.push_str(&format!(";{}:{:016x}", stage.name(), stage.fingerprint()));
+        }
+        hash64(desc.as_bytes())
+    }
+
+    pub(crate) fn run(&self, db: &BitcoinDB, height: usize, block: Block) -> EnrichedBlock {
+        let ctx = EnrichContext { db, height };
+        let mut enrichments = Enrichments::default();
+        for stage in &self.stages {
+            stage.enrich(&ctx, &block, &mut enrichments);
+        }
+        EnrichedBlock {
+            height,
+            block,
+            enrichments,
+        }
+    }
+}
+
+impl fmt::Debug for EnrichSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EnrichSpec")
+            .field("stages", &self.names())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Iterate through blocks from `start` to `end` (excluded)
+    /// with the enrichments of `spec`, computed in worker threads.
+    ///
+    /// Blocks are produced in order, the iteration stops at the
+    /// first block that cannot be read.
+    ///
+    pub fn iter_enriched(
+        &self,
+        start: usize,
+        end: usize,
+        spec: &EnrichSpec,
+    ) -> impl Iterator<Item = EnrichedBlock> {
+        let db = self.clone();
+        let spec_copy = spec.clone();
+        let end = end.min(self.get_block_count());
+        par_map_ordered(
+            start..end,
+            move |height| {
+                db.get_block::<Block>(height)
+                    .ok()
+                    .map(|block| spec_copy.run(&db, height, block))
+            },
+            spec.options,
+        )
+        .map_while(|blk| blk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use bitcoin::Network;
+
+    #[test]
+    fn test_enrichments_type_map() {
+        let mut e = Enrichments::default();
+        e.insert(5u32);
+        e.insert(String::from("x"));
+        assert_eq!(e.get::<u32>(), Some(&5));
+        assert_eq!(e.remove::<String>().as_deref(), Some("x"));
+        assert_eq!(e.get::<u64>(), None);
+        assert_eq!(e.len(), 1);
+    }
+
+    #[test]
+    fn test_addresses_of_db_network() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 2);
+        let db = TestDb::with_network("enrich_network", &chain, Network::Regtest);
+
+        let spec = EnrichSpec::new().addresses();
+        let enriched: Vec<EnrichedBlock> = db.iter_enriched(1, 3, &spec).collect();
+        assert_eq!(enriched.len(), 2);
+        for block in enriched.iter() {
+            let addresses = block.enrichments.get::<OutputAddresses>().unwrap();
+            let address = &addresses.0[0][0][0];
+            assert_eq!(address.network, Network::Regtest);
+        }
+    }
+}
@@ -0,0 +1,552 @@
+//!
+//! Typed events of several detectors, found in a single pass over blocks.
+//!
+//! Subscribe to kinds of events with an `EventSubscription`, then iterate
+//! through the events of a range of blocks (`iter_events`) or of the
+//! blocks written by the node while following the chain (`iter_live_events`):
+//! each block is read (and connected) once for all subscribed detectors.
+//!
+//! Events:
+//! - `InscriptionFound`: ordinals inscriptions (see `meta::inscriptions`)
+//! - `CoinjoinDetected`: transactions with 3 or more equal outputs and as many inputs
+//! - `DormantSpend`: spends of outputs older than a minimum age (see `analysis::DormancyEvent`)
+//! - `PoolBlockMined`: blocks whose coinbase text contains the tag of a registered pool
+//! - `ReorgObserved`: blocks reorganized out while following the chain
+//!
+//! # Example
+//!
+//! ```rust
+//! use bitcoin_explorer::events::{iter_events, Event, EventSubscription};
+//! use bitcoin_explorer::BitcoinDB;
+//! use std::path::Path;
+//! use std::time::Duration;
+//!
+//! let path = Path::new("/Users/me/bitcoin");
+//! let db = BitcoinDB::new(path, false).unwrap();
+//!
+//! let subscription = EventSubscription::new()
+//!     .inscriptions()
+//!     .coinjoins()
+//!     .dormant_spends(Duration::from_secs(10 * 365 * 24 * 3600))
+//!     .pool_blocks(vec![("Foundry USA", "Foundry"), ("AntPool", "AntPool")]);
+//! for record in iter_events(&db, 800000..800100, subscription) {
+//!     match record.event {
+//!         Event::PoolBlockMined { pool, .. } => println!("{}: {}", record.height, pool),
+//!         event => println!("{}: {:?}", record.height, event.kind()),
+//!     }
+//! }
+//! ```
+//!
+use crate::analysis::blockspace::coinjoin_outputs;
+use crate::analysis::DormancyEvent;
+use crate::api::{BitcoinDB, ChainEvent, ConnectedTx};
+use crate::index::coinbase_text;
+use crate::meta::{inscriptions, Inscription};
+use crate::parser::blk_file::BlkFile;
+use crate::parser::block_index::BlockIndex;
+use crate::parser::errors::OpResult;
+use crate::parser::proto::connected_proto::{connect_tx_at, SummaryBlock};
+use crate::parser::tx_index::TxDB;
+use bitcoin::{Block, BlockHash, OutPoint, Transaction, TxIn, TxOut, Txid};
+use log::warn;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::time::Duration;
+
+///
+/// Kinds of `Event`s, to subscribe to.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    InscriptionFound,
+    CoinjoinDetected,
+    DormantSpend,
+    PoolBlockMined,
+    ReorgObserved,
+}
+
+///
+/// An event found in a block.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// an inscription revealed by input `inscription.input` of `txid`
+    InscriptionFound {
+        txid: Txid,
+        inscription: Inscription,
+    },
+    /// a coinjoin with `equal_outputs` outputs of `value` satoshi
+    CoinjoinDetected {
+        txid: Txid,
+        equal_outputs: usize,
+        value: u64,
+    },
+    /// an output spent at least the subscribed minimum age after its creation
+    DormantSpend(DormancyEvent),
+    /// a block whose coinbase text (see `index::coinbase_text`) contains the tag of `pool`
+    PoolBlockMined {
+        pool: String,
+        block_hash: BlockHash,
+        coinbase_text: String,
+    },
+    /// blocks from the height of the record, yielded before, were reorganized out
+    ReorgObserved,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::InscriptionFound { .. } => EventKind::InscriptionFound,
+            Event::CoinjoinDetected { .. } => EventKind::CoinjoinDetected,
+            Event::DormantSpend(_) => EventKind::DormantSpend,
+            Event::PoolBlockMined { .. } => EventKind::PoolBlockMined,
+            Event::ReorgObserved => EventKind::ReorgObserved,
+        }
+    }
+}
+
+///
+/// An `Event` with the height of its block.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRecord {
+    pub height: usize,
+    pub event: Event,
+}
+
+///
+/// Kinds of events to find, and their parameters.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventSubscription {
+    kinds: HashSet<EventKind>,
+    /// minimum age of `DormantSpend`
+    min_age: Duration,
+    /// (name, tag) of pools, tags are matched ignoring case
+    pools: Vec<(String, String)>,
+}
+
+impl EventSubscription {
+    /// subscribe to nothing
+    pub fn new() -> Self {
+        EventSubscription::default()
+    }
+
+    /// subscribe to `InscriptionFound`
+    pub fn inscriptions(mut self) -> Self {
+        self.kinds.insert(EventKind::InscriptionFound);
+        self
+    }
+
+    /// subscribe to `CoinjoinDetected`
+    pub fn coinjoins(mut self) -> Self {
+        self.kinds.insert(EventKind::CoinjoinDetected);
+        self
+    }
+
+    ///
+    /// Subscribe to `DormantSpend` of outputs older than `min_age`
+    /// (measured with block header timestamps).
+    ///
+    /// `iter_events` then connects blocks from the genesis block,
+    /// `iter_live_events` reads undo files.
+    ///
+    pub fn dormant_spends(mut self, min_age: Duration) -> Self {
+        self.kinds.insert(EventKind::DormantSpend);
+        self.min_age = min_age;
+        self
+    }
+
+    ///
+    /// Subscribe to `PoolBlockMined` of `pools`, given as (name, tag):
+    /// a block is attributed to the first pool whose tag is in its coinbase text.
+    ///
+    pub fn pool_blocks<I, N, T>(mut self, pools: I) -> Self
+    where
+        I: IntoIterator<Item = (N, T)>,
+        N: Into<String>,
+        T: AsRef<str>,
+    {
+        self.kinds.insert(EventKind::PoolBlockMined);
+        self.pools.extend(
+            pools
+                .into_iter()
+                .map(|(name, tag)| (name.into(), tag.as_ref().to_lowercase())),
+        );
+        self
+    }
+
+    /// subscribe to `ReorgObserved` (only found by `iter_live_events`)
+    pub fn reorgs(mut self) -> Self {
+        self.kinds.insert(EventKind::ReorgObserved);
+        self
+    }
+
+    pub fn is_subscribed(&self, kind: EventKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+}
+
+///
+/// Iterate through the subscribed events of blocks in `range`,
+/// in block order, then transaction order.
+///
+/// Events of a block are `PoolBlockMined` first, then for each transaction
+/// `InscriptionFound`, `CoinjoinDetected` and `DormantSpend` (in input order).
+/// The blocks of a range are not reorganized: `ReorgObserved` is never found.
+///
+/// With `DormantSpend`, blocks are connected from the genesis block
+/// (the UTXO cache records the creation height of every output),
+/// blocks before `range.start` are processed without emitting events.
+///
+pub fn iter_events(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    subscription: EventSubscription,
+) -> impl Iterator<Item = EventRecord> {
+    let db_copy = db.clone();
+    let start = range.start;
+    let events: Box<dyn Iterator<Item = EventRecord>> =
+        if subscription.is_subscribed(EventKind::DormantSpend) {
+            Box::new(
+                db.iter_connected_block::<EventBlock>(range.end)
+                    .enumerate()
+                    .skip(start)
+                    .flat_map(move |(height, block)| {
+                        block_events(&db_copy, height, block, &subscription)
+                    }),
+            )
+        } else {
+            Box::new(
+                db.iter_block::<Block>(range.start, range.end)
+                    .enumerate()
+                    .flat_map(move |(i, block)| {
+                        let block = EventBlock::of_block(&block);
+                        block_events(&db_copy, start + i, block, &subscription)
+                    }),
+            )
+        };
+    events
+}
+
+///
+/// Follow the main chain from block `start` (see `BitcoinDB::iter_new_blocks`),
+/// iterating through the subscribed events of new blocks.
+///
+/// A reorg yields `ReorgObserved` at the first invalidated height (if
+/// subscribed), then the events of the replacing blocks.
+/// `DormantSpend` is found with undo files (`get_connected_block_undo`),
+/// blocks without undo data yet are retried at the next poll.
+/// As `iter_new_blocks`, the iterator ends after yielding an error,
+/// and never ends otherwise: stop consuming it to stop following.
+///
+pub fn iter_live_events(
+    db: &BitcoinDB,
+    start: usize,
+    poll_interval: Duration,
+    subscription: EventSubscription,
+) -> impl Iterator<Item = OpResult<EventRecord>> {
+    let mut blocks = db.iter_new_blocks::<Block>(start, poll_interval);
+    let connect = subscription.is_subscribed(EventKind::DormantSpend);
+    std::iter::from_fn(move || loop {
+        match blocks.next()? {
+            Err(e) => return Some(vec![Err(e)]),
+            Ok(ChainEvent::Block { height, block }) => {
+                let block = if connect {
+                    match connect_live(blocks.db(), height, poll_interval) {
+                        Some(block) => block,
+                        None => continue,
+                    }
+                } else {
+                    EventBlock::of_block(&block)
+                };
+                let events = block_events(blocks.db(), height, block, &subscription);
+                return Some(events.into_iter().map(Ok).collect());
+            }
+            Ok(ChainEvent::Invalidated { from_height }) => {
+                if subscription.is_subscribed(EventKind::ReorgObserved) {
+                    return Some(vec![Ok(EventRecord {
+                        height: from_height,
+                        event: Event::ReorgObserved,
+                    })]);
+                }
+            }
+        }
+    })
+    .flatten()
+}
+
+///
+/// Block `height` connected with undo data, waiting for the node to write it.
+///
+/// `None` if the block was reorganized out in the meantime.
+///
+fn connect_live(db: &BitcoinDB, height: usize, poll_interval: Duration) -> Option<EventBlock> {
+    let hash = db.get_hash_from_height(height).ok()?;
+    loop {
+        match db.get_connected_block_undo::<EventBlock>(height) {
+            Ok(block) => return Some(block),
+            Err(e) => warn!("cannot connect block {}, retrying: {}", height, e),
+        }
+        std::thread::sleep(poll_interval);
+        // same view of the chain as the caller, see `BitcoinDB::refresh`
+        if db.get_hash_from_height(height).ok()? != hash {
+            return None;
+        }
+    }
+}
+
+fn block_events(
+    db: &BitcoinDB,
+    height: usize,
+    block: EventBlock,
+    subscription: &EventSubscription,
+) -> Vec<EventRecord> {
+    let mut events = Vec::new();
+    if subscription.is_subscribed(EventKind::PoolBlockMined) {
+        let text = block
+            .txdata
+            .first()
+            .and_then(|tx| tx.coinbase_text.as_deref())
+            .unwrap_or_default();
+        if let Some(pool) = pool_of(&subscription.pools, text) {
+            events.push(Event::PoolBlockMined {
+                pool: pool.to_string(),
+                block_hash: block.hash,
+                coinbase_text: text.to_string(),
+            });
+        }
+    }
+    for tx in block.txdata {
+        if subscription.is_subscribed(EventKind::InscriptionFound) {
+            for inscription in tx.inscriptions {
+                events.push(Event::InscriptionFound {
+                    txid: tx.txid,
+                    inscription,
+                });
+            }
+        }
+        if subscription.is_subscribed(EventKind::CoinjoinDetected) {
+            if let Some((equal_outputs, value)) = tx.coinjoin {
+                events.push(Event::CoinjoinDetected {
+                    txid: tx.txid,
+                    equal_outputs,
+                    value,
+                });
+            }
+        }
+        if subscription.is_subscribed(EventKind::DormantSpend) {
+            for (vin, input) in tx.input.into_iter().enumerate() {
+                let created_time = match db.get_header(input.created_height) {
+                    Ok(record) => record.block_header.time,
+                    Err(_) => continue,
+                };
+                if (block.time.saturating_sub(created_time) as u64) < subscription.min_age.as_secs()
+                {
+                    continue;
+                }
+                events.push(Event::DormantSpend(DormancyEvent {
+                    height,
+                    time: block.time,
+                    txid: tx.txid,
+                    vin,
+                    outpoint: input.outpoint,
+                    value: input.value,
+                    created_height: input.created_height,
+                    created_time,
+                }));
+            }
+        }
+    }
+    events
+        .into_iter()
+        .map(|event| EventRecord { height, event })
+        .collect()
+}
+
+///
+/// Name of the first of `pools` whose tag (lower case) is in `text`.
+///
+fn pool_of<'a>(pools: &'a [(String, String)], text: &str) -> Option<&'a str> {
+    if text.is_empty() {
+        return None;
+    }
+    let lower = text.to_lowercase();
+    pools
+        .iter()
+        .find(|(_, tag)| lower.contains(tag.as_str()))
+        .map(|(name, _)| name.as_str())
+}
+
+///
+/// What the detectors need of a block, computed once.
+///
+type EventBlock = SummaryBlock<EventTx>;
+
+impl EventBlock {
+    /// a block without connected inputs
+    fn of_block(block: &Block) -> Self {
+        EventBlock {
+            hash: block.block_hash(),
+            time: block.header.time,
+            txdata: block
+                .txdata
+                .iter()
+                .map(<EventTx as ConnectedTx>::from)
+                .collect(),
+        }
+    }
+}
+
+struct EventTx {
+    txid: Txid,
+    /// of a coinbase transaction
+    coinbase_text: Option<String>,
+    inscriptions: Vec<Inscription>,
+    coinjoin: Option<(usize, u64)>,
+    /// inputs of known creation height
+    input: Vec<SpentInput>,
+}
+
+struct SpentInput {
+    outpoint: OutPoint,
+    value: u64,
+    created_height: usize,
+}
+
+struct SpentValue(u64);
+
+impl From<TxOut> for SpentValue {
+    fn from(o: TxOut) -> Self {
+        SpentValue(o.value)
+    }
+}
+
+impl ConnectedTx for EventTx {
+    type TOut = SpentValue;
+
+    fn from(tx: &Transaction) -> Self {
+        let coinbase = tx.is_coin_base();
+        EventTx {
+            txid: tx.txid(),
+            coinbase_text: if coinbase {
+                tx.input.first().map(|i| coinbase_text(&i.script_sig))
+            } else {
+                None
+            },
+            inscriptions: inscriptions(tx),
+            coinjoin: if coinbase { None } else { coinjoin_outputs(tx) },
+            input: Vec::new(),
+        }
+    }
+
+    fn add_input(&mut self, _input: Self::TOut) {
+        // creation height unknown, cannot be a dormant spend
+    }
+
+    fn add_input_at(&mut self, input: Self::TOut, tx_in: &TxIn, created_height: usize) {
+        self.input.push(SpentInput {
+            outpoint: tx_in.previous_output,
+            value: input.0,
+            created_height,
+        });
+    }
+
+    fn connect(
+        tx: Transaction,
+        tx_db: &TxDB,
+        blk_index: &BlockIndex,
+        blk_file: &BlkFile,
+    ) -> OpResult<Self> {
+        connect_tx_at(tx, tx_db, blk_index, blk_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use bitcoin::Script;
+
+    #[test]
+    fn test_pool_of() {
+        let subscription =
+            EventSubscription::new().pool_blocks(vec![("Slush", "/SLUSH/"), ("Any", "mined")]);
+        let pools = &subscription.pools;
+        assert_eq!(pool_of(pools, "/slush/|Mined by x"), Some("Slush"));
+        assert_eq!(pool_of(pools, "MINED by y"), Some("Any"));
+        assert_eq!(pool_of(pools, "other"), None);
+        assert_eq!(pool_of(pools, ""), None);
+    }
+
+    #[test]
+    fn test_events() {
+        // block 4 is a coinjoin of the coinbases of blocks 1, 2 and 3
+        // (1800s, 1200s and 600s old)
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        let outpoints: Vec<OutPoint> = tips
+            .iter()
+            .map(|h| chain.coinbase_outpoint(h).unwrap())
+            .collect();
+        let coinjoin = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: outpoints
+                .iter()
+                .map(|o| TxIn {
+                    previous_output: *o,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![
+                TxOut {
+                    value: 1000,
+                    script_pubkey: Script::new(),
+                };
+                3
+            ],
+        };
+        let txid = coinjoin.txid();
+        chain.mine(&tips[2], vec![coinjoin]);
+        let db = TestDb::new("events", &chain);
+
+        let subscription = EventSubscription::new()
+            .inscriptions()
+            .coinjoins()
+            .dormant_spends(Duration::from_secs(1000))
+            .pool_blocks(vec![("Test", "no such tag")])
+            .reorgs();
+        let events: Vec<EventRecord> = iter_events(&db, 1..5, subscription).collect();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|e| e.height == 4));
+        assert_eq!(
+            events[0].event,
+            Event::CoinjoinDetected {
+                txid,
+                equal_outputs: 3,
+                value: 1000
+            }
+        );
+        let dormant: Vec<(usize, usize)> = events[1..]
+            .iter()
+            .map(|e| match &e.event {
+                Event::DormantSpend(e) => (e.vin, e.created_height),
+                e => panic!("unexpected event {:?}", e),
+            })
+            .collect();
+        assert_eq!(dormant, vec![(0, 1), (1, 2)]);
+
+        // without dormant spends, blocks are not connected
+        let unconnected = EventSubscription::new().coinjoins();
+        let events: Vec<EventRecord> = iter_events(&db, 4..5, unconnected).collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.kind(), EventKind::CoinjoinDetected);
+        assert_eq!(iter_events(&db, 0..5, EventSubscription::new()).count(), 0);
+
+        // live events, dormant spends from undo data
+        let subscription = EventSubscription::new().dormant_spends(Duration::from_secs(1000));
+        let mut live = iter_live_events(&db, 2, Duration::from_millis(1), subscription);
+        assert_eq!(live.next().unwrap().unwrap().height, 4);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::api::{BitcoinDB, BlockHash};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::Block;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+///
+/// Cumulative state of the chain after the block at `height`,
+/// counted from the start of the checkpointed range.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: usize,
+    pub block_hash: BlockHash,
+    /// number of transactions from the start of the range to `height` (included)
+    pub cumulative_tx_count: u64,
+    /// sum of output values (in satoshis) from the start of the range to `height`
+    pub cumulative_value: u64,
+    ///
+    /// Content hash of every block hash of the range up to `height`:
+    /// `digest = sha256(previous_digest || block_hash)`,
+    /// starting from 32 zero bytes.
+    ///
+    pub digest: sha256::Hash,
+}
+
+impl BitcoinDB {
+    ///
+    /// Write a checkpoint after the last block of every interval of `every_n`
+    /// heights (i.e. at heights `h` with `(h + 1) % every_n == 0`) and after
+    /// the last block of `range`, as JSON lines in `path`.
+    ///
+    /// Multi-stage pipelines can compare their intermediate outputs
+    /// with the checkpoints to verify that they align with the chain read.
+    ///
+    /// Returns the checkpoints written.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let checkpoints = db
+    ///     .write_checkpoints(0..700000, 10000, Path::new("./checkpoints.jsonl"))
+    ///     .unwrap();
+    /// let last = checkpoints.last().unwrap();
+    /// println!("{} transactions up to {}", last.cumulative_tx_count, last.height);
+    /// ```
+    ///
+    pub fn write_checkpoints(
+        &self,
+        range: Range<usize>,
+        every_n: usize,
+        path: &Path,
+    ) -> OpResult<Vec<Checkpoint>> {
+        if every_n == 0 {
+            return Err(OpError::from("every_n must be positive"));
+        }
+        if range.end > self.get_block_count() {
+            return Err(OpError::from(
+                format!("range end {} exceeds block count", range.end).as_str(),
+            ));
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut checkpoints = Vec::new();
+        let mut cumulative_tx_count = 0u64;
+        let mut cumulative_value = 0u64;
+        let mut digest = sha256::Hash::from_inner([0; 32]);
+        let mut height = range.start;
+        for block in self.iter_block::<Block>(range.start, range.end) {
+            let block_hash = block.block_hash();
+            cumulative_tx_count += block.txdata.len() as u64;
+            cumulative_value += block
+                .txdata
+                .iter()
+                .flat_map(|tx| tx.output.iter())
+                .map(|o| o.value)
+                .sum::<u64>();
+            let mut engine = sha256::Hash::engine();
+            engine.input(&digest[..]);
+            engine.input(&block_hash[..]);
+            digest = sha256::Hash::from_engine(engine);
+
+            if (height + 1) % every_n == 0 || height + 1 == range.end {
+                let checkpoint = Checkpoint {
+                    height,
+                    block_hash,
+                    cumulative_tx_count,
+                    cumulative_value,
+                    digest,
+                };
+                serde_json::to_writer(&mut out, &checkpoint).map_err(|e| {
+                    OpError::from(format!("failed to write checkpoint: {}", e).as_str())
+                })?;
+                out.write_all(b"\n")?;
+                checkpoints.push(checkpoint);
+            }
+            height += 1;
+        }
+        if height < range.end {
+            return Err(OpError::from(
+                format!("failed to read block at height {}", height).as_str(),
+            ));
+        }
+        out.flush()?;
+        Ok(checkpoints)
+    }
+}
+
+///
+/// Read checkpoints written by `BitcoinDB::write_checkpoints`.
+///
+pub fn load_checkpoints(path: &Path) -> OpResult<Vec<Checkpoint>> {
+    let mut checkpoints = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        checkpoints.push(
+            serde_json::from_str(&line)
+                .map_err(|e| OpError::from(format!("invalid checkpoint: {}", e).as_str()))?,
+        );
+    }
+    Ok(checkpoints)
+}
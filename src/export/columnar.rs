@@ -0,0 +1,441 @@
+use crate::api::{BitcoinDB, SBlock, SConnectedBlock};
+use crate::export::{
+    export_partitioned, DatasetHeader, DatasetManifest, ExportOptions, HeaderStyle, Pseudonymizer,
+};
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::proto::amount::AmountFormat;
+use arrow::array::{ArrayRef, ListBuilder, StringBuilder, UInt32Builder, UInt64Builder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use parquet::format::KeyValue;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// number of blocks per `RecordBatch` (row group) of parquet files
+const BATCH_BLOCKS: usize = 1000;
+
+///
+/// Tables of `BitcoinDB::export_parquet`, one row per
+/// block, transaction, output or connected transaction.
+///
+/// Amounts are in satoshi.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArrowTable {
+    /// `height, block_hash, time, n_tx`
+    Blocks,
+    /// `height, txid, n_inputs, n_outputs, output_value` (no inputs for coinbases)
+    Transactions,
+    /// `height, txid, vout, value, addresses` (or their pseudonyms)
+    Outputs,
+    ///
+    /// `height, txid, n_inputs, n_outputs, input_value, output_value, fee`
+    /// (`fee` is null for coinbases), connected from undo files
+    /// (see `BitcoinDB::iter_connected_block_undo`).
+    ///
+    ConnectedTransactions,
+}
+
+impl ArrowTable {
+    /// prefix of the files of this table
+    fn prefix(&self) -> &'static str {
+        match self {
+            ArrowTable::Blocks => "blocks-",
+            ArrowTable::Transactions => "transactions-",
+            ArrowTable::Outputs => "outputs-",
+            ArrowTable::ConnectedTransactions => "connected-transactions-",
+        }
+    }
+
+    ///
+    /// Arrow schema of the table.
+    ///
+    pub fn schema(&self) -> SchemaRef {
+        let height = Field::new("height", DataType::UInt32, false);
+        let fields = match self {
+            ArrowTable::Blocks => vec![
+                height,
+                Field::new("block_hash", DataType::Utf8, false),
+                Field::new("time", DataType::UInt32, false),
+                Field::new("n_tx", DataType::UInt32, false),
+            ],
+            ArrowTable::Transactions => vec![
+                height,
+                Field::new("txid", DataType::Utf8, false),
+                Field::new("n_inputs", DataType::UInt32, false),
+                Field::new("n_outputs", DataType::UInt32, false),
+                Field::new("output_value", DataType::UInt64, false),
+            ],
+            ArrowTable::Outputs => vec![
+                height,
+                Field::new("txid", DataType::Utf8, false),
+                Field::new("vout", DataType::UInt32, false),
+                Field::new("value", DataType::UInt64, false),
+                Field::new("script_type", DataType::Utf8, false),
+                Field::new(
+                    "addresses",
+                    DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+                    false,
+                ),
+            ],
+            ArrowTable::ConnectedTransactions => vec![
+                height,
+                Field::new("txid", DataType::Utf8, false),
+                Field::new("n_inputs", DataType::UInt32, false),
+                Field::new("n_outputs", DataType::UInt32, false),
+                Field::new("input_value", DataType::UInt64, false),
+                Field::new("output_value", DataType::UInt64, false),
+                Field::new("fee", DataType::UInt64, true),
+            ],
+        };
+        Arc::new(Schema::new(fields))
+    }
+}
+
+///
+/// Rows of `ArrowTable::Blocks` for `blocks`, the first one at height `start`.
+///
+pub fn blocks_record_batch(start: usize, blocks: &[SBlock]) -> OpResult<RecordBatch> {
+    let mut height = UInt32Builder::new();
+    let mut block_hash = StringBuilder::new();
+    let mut time = UInt32Builder::new();
+    let mut n_tx = UInt32Builder::new();
+    for (i, block) in blocks.iter().enumerate() {
+        height.append_value((start + i) as u32);
+        block_hash.append_value(block.header.block_hash.to_string());
+        time.append_value(block.header.time);
+        n_tx.append_value(block.txdata.len() as u32);
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(height.finish()),
+        Arc::new(block_hash.finish()),
+        Arc::new(time.finish()),
+        Arc::new(n_tx.finish()),
+    ];
+    Ok(RecordBatch::try_new(ArrowTable::Blocks.schema(), columns)?)
+}
+
+///
+/// Rows of `ArrowTable::Transactions` for `blocks`, the first one at height `start`.
+///
+pub fn transactions_record_batch(start: usize, blocks: &[SBlock]) -> OpResult<RecordBatch> {
+    let mut height = UInt32Builder::new();
+    let mut txid = StringBuilder::new();
+    let mut n_inputs = UInt32Builder::new();
+    let mut n_outputs = UInt32Builder::new();
+    let mut output_value = UInt64Builder::new();
+    for (i, block) in blocks.iter().enumerate() {
+        for tx in block.txdata.iter() {
+            height.append_value((start + i) as u32);
+            txid.append_value(tx.txid.to_string());
+            n_inputs.append_value(tx.input.len() as u32);
+            n_outputs.append_value(tx.output.len() as u32);
+            output_value.append_value(tx.output.iter().map(|o| o.value.as_sat()).sum());
+        }
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(height.finish()),
+        Arc::new(txid.finish()),
+        Arc::new(n_inputs.finish()),
+        Arc::new(n_outputs.finish()),
+        Arc::new(output_value.finish()),
+    ];
+    Ok(RecordBatch::try_new(
+        ArrowTable::Transactions.schema(),
+        columns,
+    )?)
+}
+
+///
+/// Rows of `ArrowTable::Outputs` for `blocks`, the first one at height `start`,
+/// with pseudonyms of addresses if `pseudonymizer` is set.
+///
+pub fn outputs_record_batch(
+    start: usize,
+    blocks: &[SBlock],
+    pseudonymizer: Option<&Pseudonymizer>,
+) -> OpResult<RecordBatch> {
+    let mut height = UInt32Builder::new();
+    let mut txid = StringBuilder::new();
+    let mut vout = UInt32Builder::new();
+    let mut value = UInt64Builder::new();
+    let mut script_type = StringBuilder::new();
+    let mut addresses = ListBuilder::new(StringBuilder::new());
+    for (i, block) in blocks.iter().enumerate() {
+        for tx in block.txdata.iter() {
+            let txid_str = tx.txid.to_string();
+            for (n, o) in tx.output.iter().enumerate() {
+                height.append_value((start + i) as u32);
+                txid.append_value(&txid_str);
+                vout.append_value(n as u32);
+                value.append_value(o.value.as_sat());
+                script_type.append_value(o.script_type.to_string());
+                for address in o.addresses.iter() {
+                    match pseudonymizer {
+                        Some(p) => addresses.values().append_value(p.address(address)),
+                        None => addresses.values().append_value(address.to_string()),
+                    }
+                }
+                addresses.append(true);
+            }
+        }
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(height.finish()),
+        Arc::new(txid.finish()),
+        Arc::new(vout.finish()),
+        Arc::new(value.finish()),
+        Arc::new(script_type.finish()),
+        Arc::new(addresses.finish()),
+    ];
+    Ok(RecordBatch::try_new(ArrowTable::Outputs.schema(), columns)?)
+}
+
+///
+/// Rows of `ArrowTable::ConnectedTransactions` for `blocks`,
+/// the first one at height `start`.
+///
+pub fn connected_transactions_record_batch(
+    start: usize,
+    blocks: &[SConnectedBlock],
+) -> OpResult<RecordBatch> {
+    let mut height = UInt32Builder::new();
+    let mut txid = StringBuilder::new();
+    let mut n_inputs = UInt32Builder::new();
+    let mut n_outputs = UInt32Builder::new();
+    let mut input_value = UInt64Builder::new();
+    let mut output_value = UInt64Builder::new();
+    let mut fee = UInt64Builder::new();
+    for (i, block) in blocks.iter().enumerate() {
+        for tx in block.txdata.iter() {
+            height.append_value((start + i) as u32);
+            txid.append_value(tx.txid.to_string());
+            n_inputs.append_value(tx.input.len() as u32);
+            n_outputs.append_value(tx.output.len() as u32);
+            input_value.append_value(tx.input.iter().map(|o| o.value.as_sat()).sum());
+            output_value.append_value(tx.output.iter().map(|o| o.value.as_sat()).sum());
+            fee.append_option(tx.fee().map(|f| f.as_sat()));
+        }
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(height.finish()),
+        Arc::new(txid.finish()),
+        Arc::new(n_inputs.finish()),
+        Arc::new(n_outputs.finish()),
+        Arc::new(input_value.finish()),
+        Arc::new(output_value.finish()),
+        Arc::new(fee.finish()),
+    ];
+    Ok(RecordBatch::try_new(
+        ArrowTable::ConnectedTransactions.schema(),
+        columns,
+    )?)
+}
+
+///
+/// Write the rows of `table` for blocks `heights` as a parquet file
+/// (snappy compressed, one row group per 1000 blocks),
+/// with the run metadata (`DatasetHeader::key_values`) as key-value metadata.
+///
+/// This is the writer of `BitcoinDB::export_parquet`,
+/// usable with `export_partitioned` and custom `ExportOptions`
+/// (amounts are always in satoshi).
+///
+pub fn write_parquet<W: Write + Send>(
+    db: &BitcoinDB,
+    table: ArrowTable,
+    heights: Range<usize>,
+    options: &ExportOptions,
+    out: W,
+) -> OpResult<()> {
+    let metadata = DatasetHeader::new(db, options)?
+        .key_values()
+        .into_iter()
+        .map(|(key, value)| KeyValue::new(key, value))
+        .collect();
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_key_value_metadata(Some(metadata))
+        .build();
+    let mut writer = ArrowWriter::try_new(out, table.schema(), Some(props))?;
+    let mut start = heights.start;
+    while start < heights.end {
+        let end = (start + BATCH_BLOCKS).min(heights.end);
+        let batch = match table {
+            ArrowTable::ConnectedTransactions => {
+                let blocks: Vec<SConnectedBlock> =
+                    db.iter_connected_block_undo(start, end).collect();
+                if blocks.len() != end - start {
+                    return Err(OpError::from(
+                        format!("no undo data for block {}", start + blocks.len()).as_str(),
+                    ));
+                }
+                connected_transactions_record_batch(start, &blocks)?
+            }
+            _ => {
+                let blocks: Vec<SBlock> = db.iter_block(start, end).collect();
+                match table {
+                    ArrowTable::Blocks => blocks_record_batch(start, &blocks)?,
+                    ArrowTable::Transactions => transactions_record_batch(start, &blocks)?,
+                    _ => outputs_record_batch(start, &blocks, options.pseudonymizer.as_ref())?,
+                }
+            }
+        };
+        writer.write(&batch)?;
+        start = end;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+impl BitcoinDB {
+    ///
+    /// Export `table` for blocks of `range` as parquet files
+    /// `{out_dir}/{table}-{start}-{end}.parquet` of 10000 heights each,
+    /// with a journal and a manifest (see `export_partitioned`).
+    ///
+    /// Run again after an interruption to resume.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::export::ArrowTable;
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let manifest = db
+    ///     .export_parquet(600000..700000, ArrowTable::Outputs, Path::new("./parquet"))
+    ///     .unwrap();
+    /// println!("{} partitions", manifest.partitions.len());
+    /// ```
+    ///
+    pub fn export_parquet(
+        &self,
+        range: Range<usize>,
+        table: ArrowTable,
+        out_dir: &Path,
+    ) -> OpResult<DatasetManifest> {
+        self.export_parquet_with(range, table, out_dir, ExportOptions::default())
+    }
+
+    ///
+    /// `export_parquet` with `options` (partition size, pseudonyms, etc.),
+    /// whose `prefix` and `extension` are set by `table`
+    /// (and `header` ignored, run metadata is in parquet metadata).
+    /// Amounts are integer satoshi columns: `amount_format` is set to
+    /// `Satoshis`, so the manifest records the actual format.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::export::{ArrowTable, ExportOptions, Pseudonymizer};
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let options = ExportOptions {
+    ///     pseudonymizer: Some(Pseudonymizer::new(b"a long random key, kept private")),
+    ///     ..Default::default()
+    /// };
+    /// db.export_parquet_with(0..700000, ArrowTable::Outputs, Path::new("./shared"), options)
+    ///     .unwrap();
+    /// ```
+    ///
+    pub fn export_parquet_with(
+        &self,
+        range: Range<usize>,
+        table: ArrowTable,
+        out_dir: &Path,
+        options: ExportOptions,
+    ) -> OpResult<DatasetManifest> {
+        let options = ExportOptions {
+            prefix: table.prefix().to_string(),
+            extension: ".parquet".to_string(),
+            header: HeaderStyle::None,
+            amount_format: AmountFormat::Satoshis,
+            ..options
+        };
+        export_partitioned(self, range, out_dir, &options, |db, heights, file| {
+            write_parquet(db, table, heights, &options, file)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::fs::File;
+
+    fn read_rows(path: &Path) -> usize {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum()
+    }
+
+    #[test]
+    fn test_export_parquet() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 4);
+        let db = TestDb::new("parquet", &chain);
+        let out = db.dir.join("out");
+
+        let manifest = db.export_parquet(0..5, ArrowTable::Blocks, &out).unwrap();
+        assert_eq!(manifest.partitions.len(), 1);
+        assert_eq!(read_rows(&out.join("blocks-0-5.parquet")), 5);
+        let reader = ParquetRecordBatchReaderBuilder::try_new(
+            File::open(out.join("blocks-0-5.parquet")).unwrap(),
+        )
+        .unwrap();
+        let metadata = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap();
+        let tip_hash = metadata
+            .iter()
+            .find(|kv| kv.key == "dataset_header.tip_hash")
+            .and_then(|kv| kv.value.clone());
+        assert_eq!(tip_hash, Some(manifest.tip_hash.to_string()));
+
+        let blocks: Vec<SBlock> = db.iter_block(0, 5).collect();
+        let outputs = outputs_record_batch(0, &blocks, None).unwrap();
+        let n_outputs: usize = blocks
+            .iter()
+            .flat_map(|b| b.txdata.iter())
+            .map(|tx| tx.output.len())
+            .sum();
+        assert_eq!(outputs.num_rows(), n_outputs);
+
+        // amounts are typed columns whatever the requested format
+        let options = ExportOptions {
+            amount_format: AmountFormat::DecimalString,
+            ..Default::default()
+        };
+        let manifest = db
+            .export_parquet_with(0..5, ArrowTable::Outputs, &out, options)
+            .unwrap();
+        assert_eq!(manifest.parameters["amount_format"], "satoshis");
+
+        db.export_parquet(0..5, ArrowTable::ConnectedTransactions, &out)
+            .unwrap();
+        assert_eq!(
+            read_rows(&out.join("connected-transactions-0-5.parquet")),
+            5
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use crate::api::{BitcoinDB, BlockHash};
+use crate::export::journal::{ExportOptions, PartitionRecord};
+use crate::export::pseudonym::PSEUDONYM_SCHEME;
+use crate::parser::errors::{OpError, OpResult};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// version of the manifest format itself
+pub const SCHEMA_FORMAT: u32 = 1;
+/// file name suffix of manifests (after the export prefix)
+pub(crate) const MANIFEST_SUFFIX: &str = "manifest.json";
+
+/// key of the header record, and prefix of parquet metadata keys
+const HEADER_KEY: &str = "dataset_header";
+
+///
+/// Header record written at the start of each partition file,
+/// see `ExportOptions::header`.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderStyle {
+    /// no header record (e.g. binary formats, or metadata written by the exporter)
+    #[default]
+    None,
+    /// a JSON line `{"dataset_header": {...}}`, for JSON lines exports
+    JsonLine,
+    /// the same JSON line after `# `, for CSV exports read with a comment character
+    Comment,
+}
+
+///
+/// Run metadata of an export, embedded in its partition files
+/// (`ExportOptions::header`, key-value metadata of parquet files)
+/// and in its manifest.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetHeader {
+    /// version of the schema of partition files, set by the exporter
+    pub schema_version: u32,
+    /// version of bitcoin-explorer that generated the dataset
+    pub crate_version: String,
+    /// chain tip when the dataset was generated
+    pub tip_height: usize,
+    pub tip_hash: BlockHash,
+    pub parameters: BTreeMap<String, String>,
+}
+
+impl DatasetHeader {
+    pub(crate) fn new(db: &BitcoinDB, options: &ExportOptions) -> OpResult<Self> {
+        let tip_height = db.get_block_count().saturating_sub(1);
+        let mut parameters = options.parameters.clone();
+        parameters.insert(
+            "partition_size".to_string(),
+            options.partition_size.to_string(),
+        );
+        parameters.insert(
+            "amount_format".to_string(),
+            options.amount_format.to_string(),
+        );
+        if options.pseudonymizer.is_some() {
+            parameters.insert(
+                "address_pseudonyms".to_string(),
+                PSEUDONYM_SCHEME.to_string(),
+            );
+        }
+        Ok(DatasetHeader {
+            schema_version: options.schema_version,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            tip_height,
+            tip_hash: db.get_hash_from_height(tip_height)?,
+            parameters,
+        })
+    }
+
+    ///
+    /// Fields as key-value pairs (parameters as `parameters.{name}`),
+    /// keys prefixed by `dataset_header.`, e.g. for parquet metadata.
+    ///
+    pub fn key_values(&self) -> Vec<(String, String)> {
+        let mut pairs = vec![
+            (
+                "schema_version".to_string(),
+                self.schema_version.to_string(),
+            ),
+            ("crate_version".to_string(), self.crate_version.clone()),
+            ("tip_height".to_string(), self.tip_height.to_string()),
+            ("tip_hash".to_string(), self.tip_hash.to_string()),
+        ];
+        for (name, value) in &self.parameters {
+            pairs.push((format!("parameters.{}", name), value.clone()));
+        }
+        pairs
+            .into_iter()
+            .map(|(key, value)| (format!("{}.{}", HEADER_KEY, key), value))
+            .collect()
+    }
+
+    ///
+    /// Write the header record of `style` (nothing for `HeaderStyle::None`).
+    ///
+    pub fn write_record(&self, style: HeaderStyle, out: &mut dyn Write) -> OpResult<()> {
+        let prefix = match style {
+            HeaderStyle::None => return Ok(()),
+            HeaderStyle::JsonLine => "",
+            HeaderStyle::Comment => "# ",
+        };
+        let record = serde_json::json!({ HEADER_KEY: self });
+        writeln!(out, "{}{}", prefix, record)?;
+        Ok(())
+    }
+
+    ///
+    /// Parse a header record line written by `write_record` (of any style).
+    ///
+    pub fn parse_record(line: &str) -> OpResult<Self> {
+        let line = line.trim_end();
+        let json = line.strip_prefix("# ").unwrap_or(line);
+        let mut record: BTreeMap<String, DatasetHeader> = serde_json::from_str(json)
+            .map_err(|e| OpError::from(format!("invalid header record: {}", e).as_str()))?;
+        record
+            .remove(HEADER_KEY)
+            .ok_or_else(|| OpError::from("invalid header record"))
+    }
+}
+
+///
+/// A partition file listed in a `DatasetManifest`.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartitionEntry {
+    pub start: usize,
+    pub end: usize,
+    /// file name, relative to the manifest directory
+    pub file: String,
+    pub size: u64,
+}
+
+///
+/// Description of an exported dataset, saved as JSON beside the partitions.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetManifest {
+    /// version of this manifest format, see `SCHEMA_FORMAT`
+    pub manifest_version: u32,
+    /// version of the schema of partition files, set by the exporter
+    pub schema_version: u32,
+    /// version of bitcoin-explorer that generated the dataset
+    pub crate_version: String,
+    /// chain tip when the dataset was generated
+    pub tip_height: usize,
+    pub tip_hash: BlockHash,
+    /// exported heights
+    pub start: usize,
+    pub end: usize,
+    /// hash of the last exported block (`end - 1`), to detect reorgs
+    pub last_block_hash: Option<BlockHash>,
+    pub parameters: BTreeMap<String, String>,
+    pub partitions: Vec<PartitionEntry>,
+}
+
+impl DatasetManifest {
+    pub(crate) fn new(
+        db: &BitcoinDB,
+        range: Range<usize>,
+        header: DatasetHeader,
+        partitions: Vec<PartitionRecord>,
+    ) -> OpResult<Self> {
+        let last_block_hash = if range.is_empty() {
+            None
+        } else {
+            Some(db.get_hash_from_height(range.end - 1)?)
+        };
+        Ok(DatasetManifest {
+            manifest_version: SCHEMA_FORMAT,
+            schema_version: header.schema_version,
+            crate_version: header.crate_version,
+            tip_height: header.tip_height,
+            tip_hash: header.tip_hash,
+            start: range.start,
+            end: range.end,
+            last_block_hash,
+            parameters: header.parameters,
+            partitions: partitions
+                .into_iter()
+                .map(|p| PartitionEntry {
+                    start: p.heights.start,
+                    end: p.heights.end,
+                    file: p
+                        .path
+                        .file_name()
+                        .map(|f| f.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    size: p.size,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn load(path: &Path) -> OpResult<Self> {
+        serde_json::from_reader(File::open(path)?)
+            .map_err(|e| OpError::from(format!("invalid manifest: {}", e).as_str()))
+    }
+
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|e| OpError::from(format!("failed to write manifest: {}", e).as_str()))
+    }
+}
+
+///
+/// Result of `verify_dataset`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatasetReport {
+    pub manifest: DatasetManifest,
+    /// height ranges of `start..end` not covered by any partition
+    pub missing: Vec<Range<usize>>,
+    /// partition files missing or with a different size
+    pub corrupted: Vec<PathBuf>,
+    /// the last exported block is no longer in the main chain
+    pub reorged: bool,
+}
+
+impl DatasetReport {
+    /// the dataset is complete and consistent with the current index
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty() && !self.reorged
+    }
+}
+
+///
+/// Check an exported dataset (described by its manifest at `path`)
+/// for completeness against the block index of `db`.
+///
+pub fn verify_dataset(db: &BitcoinDB, path: &Path) -> OpResult<DatasetReport> {
+    let manifest = DatasetManifest::load(path)?;
+    if manifest.manifest_version > SCHEMA_FORMAT {
+        return Err(OpError::from(
+            format!("unsupported manifest version {}", manifest.manifest_version).as_str(),
+        ));
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut corrupted = Vec::new();
+    for p in &manifest.partitions {
+        let file = dir.join(&p.file);
+        match fs::metadata(&file) {
+            Ok(meta) if meta.len() == p.size => {}
+            _ => corrupted.push(file),
+        }
+    }
+
+    let mut partitions: Vec<(usize, usize)> = manifest
+        .partitions
+        .iter()
+        .map(|p| (p.start, p.end))
+        .collect();
+    partitions.sort_unstable();
+    let mut missing = Vec::new();
+    let mut covered = manifest.start;
+    for (start, end) in partitions {
+        if start > covered {
+            missing.push(covered..start);
+        }
+        covered = covered.max(end);
+    }
+    if covered < manifest.end {
+        missing.push(covered..manifest.end);
+    }
+
+    let reorged = match manifest.last_block_hash {
+        Some(hash) => db.get_hash_from_height(manifest.end - 1).ok() != Some(hash),
+        None => false,
+    };
+
+    Ok(DatasetReport {
+        manifest,
+        missing,
+        corrupted,
+        reorged,
+    })
+}
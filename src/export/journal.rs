@@ -0,0 +1,333 @@
+use crate::api::BitcoinDB;
+use crate::export::dataset::{DatasetHeader, DatasetManifest, HeaderStyle, MANIFEST_SUFFIX};
+use crate::export::pseudonym::Pseudonymizer;
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::proto::amount::AmountFormat;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// suffix of partition files being written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+///
+/// A completed partition of an export job.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionRecord {
+    /// heights covered by this partition
+    pub heights: Range<usize>,
+    /// output file of this partition
+    pub path: PathBuf,
+    /// size of the output file in bytes when completed
+    pub size: u64,
+}
+
+///
+/// Append-only journal of completed partitions of an export job.
+///
+/// Each completed partition is a line `start end size path`.
+/// On `open`, records are re-validated: a record whose output file is
+/// missing or has a different size (e.g. a partition truncated by a crash),
+/// as well as a trailing incomplete line, is dropped and the partition
+/// will be exported again.
+///
+#[derive(Debug)]
+pub struct JobJournal {
+    path: PathBuf,
+    records: Vec<PartitionRecord>,
+}
+
+impl JobJournal {
+    ///
+    /// Open (or create) the journal at `path`.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> OpResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut content = String::new();
+        if path.exists() {
+            File::open(&path)?.read_to_string(&mut content)?;
+        }
+        let complete_lines = match content.rfind('\n') {
+            Some(end) => &content[..end],
+            None => "",
+        };
+        let mut records = Vec::new();
+        let mut dropped = complete_lines.len() + 1 < content.len();
+        for line in complete_lines.lines() {
+            match parse_record(line) {
+                Some(record) if is_valid(&record) => records.push(record),
+                _ => dropped = true,
+            }
+        }
+        let journal = JobJournal { path, records };
+        if dropped {
+            journal.rewrite()?;
+        }
+        Ok(journal)
+    }
+
+    /// completed partitions
+    pub fn records(&self) -> &[PartitionRecord] {
+        &self.records
+    }
+
+    /// whether partition `heights` has been completed
+    pub fn is_done(&self, heights: &Range<usize>) -> bool {
+        self.records.iter().any(|r| &r.heights == heights)
+    }
+
+    ///
+    /// Record a completed partition, durably (fsync) before returning.
+    ///
+    pub fn mark_done(&mut self, heights: Range<usize>, output: &Path) -> OpResult<()> {
+        let record = PartitionRecord {
+            heights,
+            path: output.to_path_buf(),
+            size: fs::metadata(output)?.len(),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(format_record(&record).as_bytes())?;
+        file.sync_all()?;
+        self.records.push(record);
+        Ok(())
+    }
+
+    fn rewrite(&self) -> OpResult<()> {
+        let tmp = tmp_path(&self.path);
+        {
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            for record in &self.records {
+                file.write_all(format_record(record).as_bytes())?;
+            }
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn format_record(record: &PartitionRecord) -> String {
+    format!(
+        "{} {} {} {}\n",
+        record.heights.start,
+        record.heights.end,
+        record.size,
+        record.path.display()
+    )
+}
+
+fn parse_record(line: &str) -> Option<PartitionRecord> {
+    let mut parts = line.splitn(4, ' ');
+    let start = parts.next()?.parse().ok()?;
+    let end = parts.next()?.parse().ok()?;
+    let size = parts.next()?.parse().ok()?;
+    let path = PathBuf::from(parts.next()?);
+    Some(PartitionRecord {
+        heights: start..end,
+        path,
+        size,
+    })
+}
+
+fn is_valid(record: &PartitionRecord) -> bool {
+    match fs::metadata(&record.path) {
+        Ok(meta) => meta.len() == record.size,
+        Err(_) => false,
+    }
+}
+
+/// `{prefix}{start}-{end}{extension}`
+pub(crate) fn partition_file_name(options: &ExportOptions, heights: &Range<usize>) -> String {
+    format!(
+        "{}{}-{}{}",
+        options.prefix, heights.start, heights.end, options.extension
+    )
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(PARTIAL_SUFFIX);
+    PathBuf::from(name)
+}
+
+///
+/// Options of `export_partitioned`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// number of heights per partition
+    pub partition_size: usize,
+    /// prefix of all files of this export (partitions, journal, manifest)
+    pub prefix: String,
+    /// extension of partition files, e.g. `.csv`
+    pub extension: String,
+    /// version of the schema of partition files, recorded in the manifest
+    pub schema_version: u32,
+    /// generation parameters, recorded in the manifest
+    pub parameters: BTreeMap<String, String>,
+    ///
+    /// Format of amounts in partition files, recorded in the manifest
+    /// (parameter `amount_format`). `export_partitioned` does not format
+    /// anything itself: exporters of text must format amounts with it,
+    /// exporters of typed columns must override it with what they write.
+    ///
+    pub amount_format: AmountFormat,
+    ///
+    /// Keyed pseudonyms of addresses and scripts, `None` to export them
+    /// in clear (parameter `address_pseudonyms`). Exporters should write
+    /// pseudonyms in place of addresses and scripts when set.
+    ///
+    pub pseudonymizer: Option<Pseudonymizer>,
+    ///
+    /// Header record of run metadata (`DatasetHeader`) written at the start
+    /// of each partition file, before the output of the exporter.
+    ///
+    pub header: HeaderStyle,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            partition_size: 10000,
+            prefix: String::new(),
+            extension: String::new(),
+            schema_version: 1,
+            parameters: BTreeMap::new(),
+            amount_format: AmountFormat::default(),
+            pseudonymizer: None,
+            header: HeaderStyle::None,
+        }
+    }
+}
+
+///
+/// Export blocks of `range` in partitions of `options.partition_size` heights,
+/// skipping partitions completed in the journal `{dir}/{prefix}journal`.
+///
+/// `write` is called for each remaining partition with its heights
+/// and the file to write to. The file is written under a temporary name
+/// and renamed to `{dir}/{prefix}{start}-{end}{extension}` on success,
+/// so an interrupted partition never appears complete.
+///
+/// Once all partitions are completed, a manifest
+/// `{dir}/{prefix}manifest.json` is written (see `DatasetManifest`).
+/// The run metadata of the manifest also starts each partition,
+/// unless `options.header` is `HeaderStyle::None`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{BitcoinDB, SBlock};
+/// use bitcoin_explorer::export::{export_partitioned, ExportOptions};
+/// use std::io::Write;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let options = ExportOptions {
+///     prefix: "blocks-".to_string(),
+///     extension: ".csv".to_string(),
+///     ..Default::default()
+/// };
+/// // run again after an interruption to resume
+/// export_partitioned(&db, 0..700000, Path::new("./out"), &options, |db, heights, file| {
+///     for blk in db.iter_block::<SBlock>(heights.start, heights.end) {
+///         writeln!(file, "{},{}", blk.header.block_hash, blk.txdata.len())?;
+///     }
+///     Ok(())
+/// }).unwrap();
+/// ```
+///
+pub fn export_partitioned<F>(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    dir: &Path,
+    options: &ExportOptions,
+    mut write: F,
+) -> OpResult<DatasetManifest>
+where
+    F: FnMut(&BitcoinDB, Range<usize>, &mut BufWriter<File>) -> OpResult<()>,
+{
+    if options.partition_size == 0 {
+        return Err(OpError::from("partition_size must be positive"));
+    }
+    if range.end > db.get_block_count() {
+        return Err(OpError::from("range exceeds block count"));
+    }
+    fs::create_dir_all(dir)?;
+    let prefix = &options.prefix;
+    let mut journal = JobJournal::open(dir.join(format!("{}journal", prefix)))?;
+    let header = DatasetHeader::new(db, options)?;
+    let mut start = range.start;
+    while start < range.end {
+        let end = (start + options.partition_size).min(range.end);
+        let heights = start..end;
+        if !journal.is_done(&heights) {
+            let output = dir.join(partition_file_name(options, &heights));
+            let tmp = tmp_path(&output);
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            header.write_record(options.header, &mut file)?;
+            write(db, heights.clone(), &mut file)?;
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, &output)?;
+            journal.mark_done(heights, &output)?;
+        }
+        start = end;
+    }
+    let partitions = journal
+        .records()
+        .iter()
+        .filter(|r| r.heights.start >= range.start && r.heights.end <= range.end)
+        .cloned()
+        .collect();
+    let manifest = DatasetManifest::new(db, range, header, partitions)?;
+    manifest.save(&dir.join(format!("{}{}", prefix, MANIFEST_SUFFIX)))?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JobJournal;
+    use crate::testutil::TestDir;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_journal_resume() {
+        let dir = TestDir::new("journal");
+        let journal_path = dir.join("journal");
+        let part_a = dir.join("a");
+        let part_b = dir.join("b");
+        fs::write(&part_a, b"aaaa").unwrap();
+        fs::write(&part_b, b"bbbb").unwrap();
+
+        let mut journal = JobJournal::open(&journal_path).unwrap();
+        journal.mark_done(0..10, &part_a).unwrap();
+        journal.mark_done(10..20, &part_b).unwrap();
+        // simulate a crash while appending
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&journal_path)
+            .unwrap()
+            .write_all(b"20 3")
+            .unwrap();
+        // partition b got truncated
+        fs::write(&part_b, b"bb").unwrap();
+
+        let journal = JobJournal::open(&journal_path).unwrap();
+        assert!(journal.is_done(&(0..10)));
+        assert!(!journal.is_done(&(10..20)));
+        assert_eq!(journal.records().len(), 1);
+        assert_eq!(
+            fs::read_to_string(&journal_path).unwrap(),
+            format!("0 10 4 {}\n", part_a.display())
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use crate::api::BitcoinDB;
+use crate::export::dataset::DatasetManifest;
+use crate::export::journal::{export_partitioned, partition_file_name, ExportOptions};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Component, Path};
+
+/// file name suffix of commitments (after the export prefix)
+const COMMITMENT_SUFFIX: &str = "merkle_sum.json";
+/// file name suffix of the leaves recorded during export
+const LEAVES_SUFFIX: &str = "merkle_sum_leaves";
+
+///
+/// The exported content of a block, a leaf of the merkle-sum tree.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleSumLeaf {
+    pub height: usize,
+    /// partition file, relative to the commitment directory
+    pub file: String,
+    /// byte range of the block content in `file`
+    pub offset: u64,
+    pub len: u64,
+    /// sha256 of the block content
+    pub content_hash: sha256::Hash,
+    /// value attributed to the block by the exporter (e.g. output satoshis)
+    pub value: u64,
+}
+
+impl MerkleSumLeaf {
+    /// commits to height, content hash and value (not to the location)
+    fn node(&self) -> MerkleSumNode {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&[0]);
+        engine.input(&(self.height as u64).to_le_bytes());
+        engine.input(&self.content_hash[..]);
+        engine.input(&self.value.to_le_bytes());
+        MerkleSumNode {
+            hash: sha256::Hash::from_engine(engine),
+            sum: self.value,
+        }
+    }
+}
+
+///
+/// A node of the merkle-sum tree: a hash, and the sum of the values below.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleSumNode {
+    pub hash: sha256::Hash,
+    pub sum: u64,
+}
+
+impl MerkleSumNode {
+    fn parent(left: &MerkleSumNode, right: &MerkleSumNode) -> Option<MerkleSumNode> {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&[1]);
+        engine.input(&left.hash[..]);
+        engine.input(&left.sum.to_le_bytes());
+        engine.input(&right.hash[..]);
+        engine.input(&right.sum.to_le_bytes());
+        Some(MerkleSumNode {
+            hash: sha256::Hash::from_engine(engine),
+            sum: left.sum.checked_add(right.sum)?,
+        })
+    }
+}
+
+///
+/// A sibling on the path from a leaf to the root.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleSumStep {
+    pub sibling: MerkleSumNode,
+    /// the sibling is the left child
+    pub is_left: bool,
+}
+
+///
+/// Proof that a leaf (content hash and value) is committed by a root.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleSumProof {
+    pub steps: Vec<MerkleSumStep>,
+}
+
+impl MerkleSumProof {
+    pub fn verify(&self, leaf: &MerkleSumLeaf, root: &MerkleSumNode) -> bool {
+        let mut node = leaf.node();
+        for step in self.steps.iter() {
+            let parent = if step.is_left {
+                MerkleSumNode::parent(&step.sibling, &node)
+            } else {
+                MerkleSumNode::parent(&node, &step.sibling)
+            };
+            match parent {
+                Some(parent) => node = parent,
+                None => return false,
+            }
+        }
+        node == *root
+    }
+}
+
+/// levels of the tree from the leaves up to the root (an odd last node moves up)
+fn tree_levels(leaves: &[MerkleSumLeaf]) -> OpResult<Vec<Vec<MerkleSumNode>>> {
+    let mut levels = vec![leaves.iter().map(|l| l.node()).collect::<Vec<_>>()];
+    while levels.last().unwrap().len() > 1 {
+        let level = levels.last().unwrap();
+        let mut next = Vec::with_capacity((level.len() + 1) / 2);
+        for pair in level.chunks(2) {
+            next.push(match pair {
+                [left, right] => MerkleSumNode::parent(left, right)
+                    .ok_or_else(|| OpError::from("merkle-sum overflow"))?,
+                [single] => *single,
+                _ => unreachable!(),
+            });
+        }
+        levels.push(next);
+    }
+    Ok(levels)
+}
+
+///
+/// Root of the merkle-sum tree over `leaves` (in height order).
+///
+/// The root of no leaf is the zero hash with sum 0.
+///
+pub fn merkle_sum_root(leaves: &[MerkleSumLeaf]) -> OpResult<MerkleSumNode> {
+    Ok(tree_levels(leaves)?
+        .last()
+        .and_then(|level| level.first().copied())
+        .unwrap_or(MerkleSumNode {
+            hash: sha256::Hash::from_inner([0; 32]),
+            sum: 0,
+        }))
+}
+
+///
+/// Merkle-sum commitment over an exported dataset, saved as JSON
+/// beside the manifest (`{prefix}merkle_sum.json`).
+///
+/// Share `root` over a trusted channel: recipients check the dataset
+/// with `verify_merkle_sum`, and single blocks (content and value)
+/// with `MerkleSumProof`s, without trusting the rest of the file.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleSumCommitment {
+    pub root: MerkleSumNode,
+    pub leaves: Vec<MerkleSumLeaf>,
+}
+
+impl MerkleSumCommitment {
+    pub fn new(leaves: Vec<MerkleSumLeaf>) -> OpResult<Self> {
+        if leaves.windows(2).any(|w| w[0].height >= w[1].height) {
+            return Err(OpError::from("merkle-sum leaves must be in height order"));
+        }
+        Ok(MerkleSumCommitment {
+            root: merkle_sum_root(&leaves)?,
+            leaves,
+        })
+    }
+
+    /// proof of the leaf at `height`
+    pub fn proof(&self, height: usize) -> Option<MerkleSumProof> {
+        let mut index = self
+            .leaves
+            .binary_search_by_key(&height, |l| l.height)
+            .ok()?;
+        let levels = tree_levels(&self.leaves).ok()?;
+        let mut steps = Vec::new();
+        for level in levels.iter().take(levels.len() - 1) {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                steps.push(MerkleSumStep {
+                    sibling: level[sibling],
+                    is_left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        Some(MerkleSumProof { steps })
+    }
+
+    pub fn load(path: &Path) -> OpResult<Self> {
+        serde_json::from_reader(BufReader::new(File::open(path)?))
+            .map_err(|e| OpError::from(format!("invalid merkle-sum commitment: {}", e).as_str()))
+    }
+
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(file, self).map_err(|e| {
+            OpError::from(format!("failed to write merkle-sum commitment: {}", e).as_str())
+        })
+    }
+}
+
+///
+/// Hashes and counts what is written through it.
+///
+struct LeafWriter<'a, W: Write> {
+    inner: &'a mut W,
+    engine: sha256::HashEngine,
+    len: u64,
+}
+
+impl<W: Write> Write for LeafWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.engine.input(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+///
+/// `export_partitioned`, writing block by block,
+/// and committing to every block with a merkle-sum tree.
+///
+/// `write_block` writes the content of the block at a height,
+/// and returns the value attributed to it (e.g. output satoshis).
+/// The commitment is saved as `{dir}/{prefix}merkle_sum.json`.
+/// Leaves are journaled (`{prefix}merkle_sum_leaves`),
+/// so that an interrupted export resumes like `export_partitioned`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::export::{export_with_merkle_sum, verify_merkle_sum, ExportOptions};
+/// use bitcoin_explorer::{BitcoinDB, SBlock};
+/// use std::io::Write;
+/// use std::path::Path;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+///
+/// let options = ExportOptions {
+///     prefix: "values-".to_string(),
+///     extension: ".csv".to_string(),
+///     ..Default::default()
+/// };
+/// let dir = Path::new("./out");
+/// let (_, commitment) = export_with_merkle_sum(&db, 0..700000, dir, &options, |db, h, file| {
+///     let block = db.get_block::<SBlock>(h)?;
+///     let value: u64 = block.txdata.iter().flat_map(|tx| tx.output.iter()).map(|o| o.value.as_sat()).sum();
+///     writeln!(file, "{},{}", h, value)?;
+///     Ok(value)
+/// }).unwrap();
+/// println!("root {} (total {} sat)", commitment.root.hash, commitment.root.sum);
+///
+/// // by a recipient
+/// let report = verify_merkle_sum(dir, &dir.join("values-merkle_sum.json")).unwrap();
+/// assert!(report.is_ok() && report.root == commitment.root);
+/// ```
+///
+pub fn export_with_merkle_sum<F>(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    dir: &Path,
+    options: &ExportOptions,
+    mut write_block: F,
+) -> OpResult<(DatasetManifest, MerkleSumCommitment)>
+where
+    F: FnMut(&BitcoinDB, usize, &mut dyn Write) -> OpResult<u64>,
+{
+    let leaves_path = dir.join(format!("{}{}", options.prefix, LEAVES_SUFFIX));
+    let manifest = export_partitioned(db, range.clone(), dir, options, |db, heights, file| {
+        let name = partition_file_name(options, &heights);
+        let mut leaves = Vec::with_capacity(heights.len());
+        // after the header record, if any
+        let mut offset = file.stream_position()?;
+        for height in heights {
+            let mut writer = LeafWriter {
+                inner: &mut *file,
+                engine: sha256::Hash::engine(),
+                len: 0,
+            };
+            let value = write_block(db, height, &mut writer)?;
+            let (engine, len) = (writer.engine, writer.len);
+            leaves.push(MerkleSumLeaf {
+                height,
+                file: name.clone(),
+                offset,
+                len,
+                content_hash: sha256::Hash::from_engine(engine),
+                value,
+            });
+            offset += len;
+        }
+        // journaled before the partition is marked done,
+        // a redone partition supersedes its previous leaves
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&leaves_path)?;
+        let mut lines = Vec::new();
+        for leaf in leaves.iter() {
+            serde_json::to_writer(&mut lines, leaf)
+                .map_err(|e| OpError::from(format!("failed to write leaf: {}", e).as_str()))?;
+            lines.push(b'\n');
+        }
+        journal.write_all(&lines)?;
+        journal.sync_all()?;
+        Ok(())
+    })?;
+
+    // the last journaled leaf of each height
+    let mut by_height = BTreeMap::new();
+    for line in BufReader::new(File::open(&leaves_path)?).lines() {
+        // a torn last line belongs to a partition that was redone
+        if let Ok(leaf) = serde_json::from_str::<MerkleSumLeaf>(&line?) {
+            by_height.insert(leaf.height, leaf);
+        }
+    }
+    let leaves: Vec<MerkleSumLeaf> = by_height
+        .range(range.clone())
+        .map(|(_, l)| l.clone())
+        .collect();
+    if leaves.len() != range.len() {
+        return Err(OpError::from(
+            "merkle-sum leaves missing, remove the journal and export again",
+        ));
+    }
+    let commitment = MerkleSumCommitment::new(leaves)?;
+    commitment.save(&dir.join(format!("{}{}", options.prefix, COMMITMENT_SUFFIX)))?;
+    Ok((manifest, commitment))
+}
+
+///
+/// Result of `verify_merkle_sum`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleSumReport {
+    /// root recomputed from the leaves of the commitment
+    pub root: MerkleSumNode,
+    /// the recomputed root is the one recorded in the commitment
+    pub root_matches: bool,
+    /// heights whose content is missing or differs from its leaf
+    pub mismatched: Vec<usize>,
+}
+
+impl MerkleSumReport {
+    pub fn is_ok(&self) -> bool {
+        self.root_matches && self.mismatched.is_empty()
+    }
+}
+
+///
+/// Check the partition files in `dir` against the commitment at `path`.
+///
+/// Compare `MerkleSumReport::root` with a root obtained from a trusted
+/// source, since the commitment file may have been modified as well.
+///
+pub fn verify_merkle_sum(dir: &Path, path: &Path) -> OpResult<MerkleSumReport> {
+    let commitment = MerkleSumCommitment::load(path)?;
+    if commitment
+        .leaves
+        .windows(2)
+        .any(|w| w[0].height >= w[1].height)
+    {
+        return Err(OpError::from("merkle-sum leaves must be in height order"));
+    }
+    if let Some(leaf) = commitment.leaves.iter().find(|l| !is_file_name(&l.file)) {
+        return Err(OpError::from(
+            format!("merkle-sum leaf file is not a file name: {}", leaf.file).as_str(),
+        ));
+    }
+    let root = merkle_sum_root(&commitment.leaves)?;
+    let mut mismatched = Vec::new();
+    let mut files: BTreeMap<&str, Option<File>> = BTreeMap::new();
+    for leaf in commitment.leaves.iter() {
+        let file = files
+            .entry(leaf.file.as_str())
+            .or_insert_with(|| File::open(dir.join(&leaf.file)).ok());
+        let matches = match file {
+            Some(file) => content_hash(file, leaf.offset, leaf.len).ok() == Some(leaf.content_hash),
+            None => false,
+        };
+        if !matches {
+            mismatched.push(leaf.height);
+        }
+    }
+    Ok(MerkleSumReport {
+        root,
+        root_matches: root == commitment.root,
+        mismatched,
+    })
+}
+
+/// a single plain file name, so leaves cannot point outside `dir`
+fn is_file_name(file: &str) -> bool {
+    let mut components = Path::new(file).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+fn content_hash(file: &mut File, offset: u64, len: u64) -> OpResult<sha256::Hash> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut content = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut content)?;
+    if content.len() as u64 != len {
+        return Err(OpError::from("truncated partition"));
+    }
+    Ok(sha256::Hash::hash(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestDir;
+
+    fn leaf(height: usize, value: u64) -> MerkleSumLeaf {
+        MerkleSumLeaf {
+            height,
+            file: String::new(),
+            offset: 0,
+            len: 0,
+            content_hash: sha256::Hash::hash(&height.to_le_bytes()),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_merkle_sum_proofs() {
+        let leaves: Vec<_> = (0..7).map(|h| leaf(h, h as u64 * 10)).collect();
+        let commitment = MerkleSumCommitment::new(leaves.clone()).unwrap();
+        assert_eq!(commitment.root.sum, 210);
+        for l in leaves.iter() {
+            let proof = commitment.proof(l.height).unwrap();
+            assert!(proof.verify(l, &commitment.root));
+            // a different value is not committed
+            let mut forged = l.clone();
+            forged.value += 1;
+            assert!(!proof.verify(&forged, &commitment.root));
+        }
+        assert!(commitment.proof(7).is_none());
+
+        let single = MerkleSumCommitment::new(vec![leaf(3, 5)]).unwrap();
+        assert!(single.proof(3).unwrap().steps.is_empty());
+        assert!(single.proof(3).unwrap().verify(&leaf(3, 5), &single.root));
+        assert!(MerkleSumCommitment::new(vec![leaf(1, 0), leaf(1, 0)]).is_err());
+        assert!(MerkleSumCommitment::new(vec![leaf(0, u64::MAX), leaf(1, 1)]).is_err());
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_commitment() {
+        let dir = TestDir::new("merkle_sum_verify");
+        let path = dir.join(COMMITMENT_SUFFIX);
+        for file in ["../escape", "/etc/passwd", "sub/part", ""] {
+            let mut l = leaf(0, 1);
+            l.file = file.to_string();
+            MerkleSumCommitment::new(vec![l])
+                .unwrap()
+                .save(&path)
+                .unwrap();
+            assert!(verify_merkle_sum(&dir, &path).is_err(), "{}", file);
+        }
+        // heights out of order, saved without going through `new`
+        let mut leaves = vec![leaf(1, 1), leaf(0, 1)];
+        for l in leaves.iter_mut() {
+            l.file = "part".to_string();
+        }
+        let commitment = MerkleSumCommitment {
+            root: merkle_sum_root(&leaves).unwrap(),
+            leaves,
+        };
+        commitment.save(&path).unwrap();
+        assert!(verify_merkle_sum(&dir, &path).is_err());
+    }
+}
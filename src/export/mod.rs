@@ -0,0 +1,46 @@
+//!
+//! Building blocks of long-running export jobs.
+//!
+//! Exports are split into partitions of block heights.
+//! `JobJournal` records completed partitions so that
+//! an interrupted export resumes where it stopped,
+//! and `DatasetManifest` describes a finished export,
+//! which can be checked with `verify_dataset`.
+//! `BitcoinDB::write_checkpoints` records cumulative chain state
+//! at regular heights, for pipelines to validate their outputs.
+//! `export_with_merkle_sum` commits to the content and value of every
+//! exported block, for recipients to check with `verify_merkle_sum`.
+//! `BitcoinDB::export_spv_proofs` bundles merkle proofs of transactions
+//! with their headers chain, for auditors to check with `verify_spv_proofs`.
+//! `Pseudonymizer` replaces addresses and scripts of exports
+//! with keyed pseudonyms, for datasets shared externally.
+//! With feature `parquet`, blocks, transactions and connected transactions
+//! convert to Arrow `RecordBatch`es, and `BitcoinDB::export_parquet`
+//! writes them as partitioned parquet files.
+//!
+mod checkpoint;
+#[cfg(feature = "parquet")]
+mod columnar;
+mod dataset;
+mod journal;
+mod merkle_sum;
+mod pseudonym;
+mod spv;
+
+pub use checkpoint::{load_checkpoints, Checkpoint};
+#[cfg(feature = "parquet")]
+pub use columnar::{
+    blocks_record_batch, connected_transactions_record_batch, outputs_record_batch,
+    transactions_record_batch, write_parquet, ArrowTable,
+};
+pub use dataset::{
+    verify_dataset, DatasetHeader, DatasetManifest, DatasetReport, HeaderStyle, PartitionEntry,
+    SCHEMA_FORMAT,
+};
+pub use journal::{export_partitioned, ExportOptions, JobJournal, PartitionRecord};
+pub use merkle_sum::{
+    export_with_merkle_sum, merkle_sum_root, verify_merkle_sum, MerkleSumCommitment, MerkleSumLeaf,
+    MerkleSumNode, MerkleSumProof, MerkleSumReport, MerkleSumStep,
+};
+pub use pseudonym::{Pseudonymizer, PSEUDONYM_SCHEME};
+pub use spv::{verify_spv_proofs, SpvProof, SpvProofBundle, SpvReport, SPV_PROOF_FORMAT};
@@ -0,0 +1,93 @@
+use crate::api::{Address, Script};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use std::fmt;
+
+/// value of the manifest parameter `address_pseudonyms`
+pub const PSEUDONYM_SCHEME: &str = "hmac-sha256";
+
+///
+/// Keyed pseudonyms of addresses and scripts for shared datasets.
+///
+/// The pseudonym of a script is the hex HMAC-SHA256 of its bytes,
+/// and an address has the pseudonym of its `script_pubkey`. The same
+/// key gives the same pseudonyms in every table and every export,
+/// so datasets keep their linkability structure, while recovering
+/// addresses requires the key.
+///
+/// Set it in `ExportOptions::pseudonymizer`: exporters then write
+/// pseudonyms in place of addresses and scripts, and the manifest
+/// records the scheme (never the key).
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::export::Pseudonymizer;
+/// use bitcoin_explorer::Address;
+/// use std::str::FromStr;
+///
+/// let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+/// let p = Pseudonymizer::new(b"a secret key shared by nobody");
+/// assert_eq!(p.address(&address), p.script(&address.script_pubkey()));
+/// assert_ne!(p.address(&address), Pseudonymizer::new(b"another key").address(&address));
+/// ```
+///
+#[derive(Clone, PartialEq, Eq)]
+pub struct Pseudonymizer {
+    key: Vec<u8>,
+}
+
+impl Pseudonymizer {
+    ///
+    /// Pseudonyms keyed by `key`, which should be
+    /// random and at least 32 bytes long.
+    ///
+    pub fn new(key: &[u8]) -> Self {
+        Pseudonymizer { key: key.to_vec() }
+    }
+
+    /// pseudonym of arbitrary bytes
+    pub fn bytes(&self, data: &[u8]) -> String {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&self.key);
+        engine.input(data);
+        hmac::Hmac::<sha256::Hash>::from_engine(engine)
+            .into_inner()
+            .to_hex()
+    }
+
+    /// pseudonym of a script
+    pub fn script(&self, script: &Script) -> String {
+        self.bytes(script.as_bytes())
+    }
+
+    /// pseudonym of an address, the one of its `script_pubkey`
+    pub fn address(&self, address: &Address) -> String {
+        self.script(&address.script_pubkey())
+    }
+}
+
+impl fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the key
+        write!(f, "Pseudonymizer({})", PSEUDONYM_SCHEME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_pseudonymizer() {
+        let a = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        let b = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq").unwrap();
+        let p = Pseudonymizer::new(&[7u8; 32]);
+        assert_eq!(p.address(&a), Pseudonymizer::new(&[7u8; 32]).address(&a));
+        assert_eq!(p.address(&a), p.script(&a.script_pubkey()));
+        assert_ne!(p.address(&a), p.address(&b));
+        assert_ne!(p.address(&a), Pseudonymizer::new(&[8u8; 32]).address(&a));
+        assert_eq!(p.address(&a).len(), 64);
+        assert_eq!(format!("{:?}", p), "Pseudonymizer(hmac-sha256)");
+    }
+}
@@ -0,0 +1,309 @@
+use crate::api::{BitcoinDB, BlockHash, FromHex, ToHex, Txid};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::{Block, BlockHeader};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// value of `SpvProofBundle::format`
+pub const SPV_PROOF_FORMAT: &str = "bitcoin-explorer-spv-proofs/1";
+
+///
+/// Merkle proof of the transactions of one block.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpvProof {
+    pub height: usize,
+    pub block_hash: BlockHash,
+    /// transactions proven by `merkle_block`
+    pub txids: Vec<Txid>,
+    ///
+    /// hex of the consensus encoded `MerkleBlock` (header and partial
+    /// merkle tree), the format of Core's `gettxoutproof`
+    ///
+    pub merkle_block: String,
+}
+
+///
+/// Proofs of inclusion of transactions, with the chain of headers
+/// linking their blocks, verifiable offline with `verify_spv_proofs`.
+///
+/// Written as JSON:
+///
+/// ```json
+/// {
+///   "format": "bitcoin-explorer-spv-proofs/1",
+///   "start_height": 100,
+///   "headers": ["<80-byte header hex at start_height>", "..."],
+///   "proofs": [
+///     {"height": 100, "block_hash": "...", "txids": ["..."], "merkle_block": "..."}
+///   ]
+/// }
+/// ```
+///
+/// `headers` covers every height from the lowest to the highest proof,
+/// each header committing to the previous one.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpvProofBundle {
+    pub format: String,
+    pub start_height: usize,
+    pub headers: Vec<String>,
+    pub proofs: Vec<SpvProof>,
+}
+
+///
+/// Result of `verify_spv_proofs`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpvReport {
+    /// last header of the bundle, to compare with a trusted source
+    pub tip: BlockHash,
+    pub tip_height: usize,
+    /// every header commits to the previous one
+    pub headers_linked: bool,
+    /// heights of headers not meeting their own difficulty target
+    pub invalid_pow: Vec<usize>,
+    /// transactions proven to be in their block
+    pub verified: Vec<Txid>,
+    /// transactions whose proof is invalid or does not match the headers
+    pub failed: Vec<Txid>,
+}
+
+impl SpvReport {
+    pub fn is_ok(&self) -> bool {
+        self.headers_linked && self.invalid_pow.is_empty() && self.failed.is_empty()
+    }
+}
+
+impl SpvProofBundle {
+    pub fn load(path: &Path) -> OpResult<Self> {
+        let bundle: SpvProofBundle = serde_json::from_reader(BufReader::new(File::open(path)?))
+            .map_err(|e| OpError::from(format!("invalid spv proofs: {}", e).as_str()))?;
+        if bundle.format != SPV_PROOF_FORMAT {
+            return Err(OpError::from(
+                format!("unsupported spv proof format: {}", bundle.format).as_str(),
+            ));
+        }
+        Ok(bundle)
+    }
+
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|e| OpError::from(format!("failed to write spv proofs: {}", e).as_str()))
+    }
+
+    ///
+    /// Check the headers chain and every proof, without access to a node.
+    ///
+    pub fn verify(&self) -> OpResult<SpvReport> {
+        let headers = self
+            .headers
+            .iter()
+            .map(|h| Ok(deserialize::<BlockHeader>(&Vec::<u8>::from_hex(h)?)?))
+            .collect::<OpResult<Vec<BlockHeader>>>()?;
+        let tip = match headers.last() {
+            Some(tip) => tip.block_hash(),
+            None => return Err(OpError::from("spv proofs have no header")),
+        };
+        let headers_linked = headers
+            .windows(2)
+            .all(|w| w[1].prev_blockhash == w[0].block_hash());
+        let invalid_pow = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| h.validate_pow(&h.target()).is_err())
+            .map(|(i, _)| self.start_height + i)
+            .collect();
+        let mut verified = Vec::new();
+        let mut failed = Vec::new();
+        for proof in self.proofs.iter() {
+            let header = proof
+                .height
+                .checked_sub(self.start_height)
+                .and_then(|i| headers.get(i));
+            if header.map_or(false, |h| proof_matches(proof, h)) {
+                verified.extend(proof.txids.iter().copied());
+            } else {
+                failed.extend(proof.txids.iter().copied());
+            }
+        }
+        Ok(SpvReport {
+            tip,
+            tip_height: self.start_height + headers.len() - 1,
+            headers_linked,
+            invalid_pow,
+            verified,
+            failed,
+        })
+    }
+}
+
+/// the merkle block is of `header` and proves all txids of `proof`
+fn proof_matches(proof: &SpvProof, header: &BlockHeader) -> bool {
+    let merkle_block = match Vec::<u8>::from_hex(&proof.merkle_block)
+        .ok()
+        .and_then(|bytes| deserialize::<MerkleBlock>(&bytes).ok())
+    {
+        Some(merkle_block) => merkle_block,
+        None => return false,
+    };
+    if merkle_block.header != *header || header.block_hash() != proof.block_hash {
+        return false;
+    }
+    let mut matches = Vec::new();
+    let mut indexes = Vec::new();
+    if merkle_block
+        .extract_matches(&mut matches, &mut indexes)
+        .is_err()
+    {
+        return false;
+    }
+    let matches: HashSet<Txid> = matches.into_iter().collect();
+    proof.txids.iter().all(|txid| matches.contains(txid))
+}
+
+impl BitcoinDB {
+    ///
+    /// Write proofs of inclusion of `txids` and the chain of headers
+    /// linking their blocks to `path` (see `SpvProofBundle` for the format).
+    ///
+    /// Transactions of the same block share one merkle proof.
+    /// This function requires `txindex` to be set to `true` for `BitcoinDB`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::export::verify_spv_proofs;
+    /// use bitcoin_explorer::{BitcoinDB, FromHex, Txid};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, true).unwrap();
+    ///
+    /// let txid = Txid::from_hex("e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468").unwrap();
+    /// let proofs = Path::new("./proofs.json");
+    /// db.export_spv_proofs(&[txid], proofs).unwrap();
+    ///
+    /// // by an auditor, offline
+    /// let report = verify_spv_proofs(proofs).unwrap();
+    /// assert!(report.is_ok());
+    /// println!("proven up to {} at {}", report.tip, report.tip_height);
+    /// ```
+    ///
+    pub fn export_spv_proofs(&self, txids: &[Txid], path: &Path) -> OpResult<SpvProofBundle> {
+        let mut by_height: BTreeMap<usize, Vec<Txid>> = BTreeMap::new();
+        for txid in txids {
+            let height = self.get_height_of_transaction(txid)?;
+            by_height.entry(height).or_default().push(*txid);
+        }
+        let bundle = self.spv_proofs(by_height)?;
+        bundle.save(path)?;
+        Ok(bundle)
+    }
+
+    fn spv_proofs(&self, by_height: BTreeMap<usize, Vec<Txid>>) -> OpResult<SpvProofBundle> {
+        let (start, end) = match (by_height.keys().next(), by_height.keys().last()) {
+            (Some(&start), Some(&end)) => (start, end),
+            _ => return Err(OpError::from("no transaction to prove")),
+        };
+        let headers = (start..=end)
+            .map(|h| Ok(serialize(&self.get_header(h)?.block_header).to_hex()))
+            .collect::<OpResult<Vec<String>>>()?;
+        let mut proofs = Vec::with_capacity(by_height.len());
+        for (height, mut txids) in by_height {
+            txids.sort();
+            txids.dedup();
+            let block = self.get_block::<Block>(height)?;
+            let wanted: HashSet<Txid> = txids.iter().copied().collect();
+            let found = block
+                .txdata
+                .iter()
+                .filter(|tx| wanted.contains(&tx.txid()))
+                .count();
+            if found != wanted.len() {
+                return Err(OpError::from(
+                    format!("transactions not found in block {}", height).as_str(),
+                ));
+            }
+            let merkle_block =
+                MerkleBlock::from_block_with_predicate(&block, |txid| wanted.contains(txid));
+            proofs.push(SpvProof {
+                height,
+                block_hash: block.block_hash(),
+                txids,
+                merkle_block: serialize(&merkle_block).to_hex(),
+            });
+        }
+        Ok(SpvProofBundle {
+            format: SPV_PROOF_FORMAT.to_string(),
+            start_height: start,
+            headers,
+            proofs,
+        })
+    }
+}
+
+///
+/// Verify the proofs written by `BitcoinDB::export_spv_proofs`.
+///
+/// Compare `SpvReport::tip` with a block hash obtained from a trusted
+/// source, since a forged bundle can be internally consistent.
+///
+pub fn verify_spv_proofs(path: &Path) -> OpResult<SpvReport> {
+    SpvProofBundle::load(path)?.verify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use bitcoin::Transaction;
+
+    #[test]
+    fn test_spv_proofs() {
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let spends: Vec<Transaction> = (0..2)
+            .map(|i| SyntheticChain::spend(chain.coinbase_outpoint(&tips[i]).unwrap(), 1000))
+            .collect();
+        let txids: Vec<Txid> = spends.iter().map(|tx| tx.txid()).collect();
+        let tip = chain.mine(&tips[1], spends);
+        chain.extend(&tip, 2);
+        let db = TestDb::new("spv", &chain);
+        let coinbase = chain.block(&tips[0]).unwrap().txdata[0].txid();
+
+        let mut by_height = BTreeMap::new();
+        by_height.insert(1, vec![coinbase]);
+        by_height.insert(3, vec![txids[1], txids[0]]);
+        let bundle = db.spv_proofs(by_height).unwrap();
+        assert_eq!((bundle.start_height, bundle.headers.len()), (1, 3));
+        assert_eq!(bundle.proofs.len(), 2);
+
+        let path = db.dir.join("proofs.json");
+        bundle.save(&path).unwrap();
+        let report = verify_spv_proofs(&path).unwrap();
+        assert!(report.headers_linked);
+        assert_eq!((report.tip, report.tip_height), (tip, 3));
+        assert_eq!(report.verified.len(), 3);
+        assert!(report.failed.is_empty());
+
+        // a proof moved to another block fails
+        let mut forged = bundle.clone();
+        forged.proofs[0].height = 2;
+        assert_eq!(forged.verify().unwrap().failed, vec![coinbase]);
+        // a missing header breaks the chain
+        let mut forged = bundle.clone();
+        forged.headers.remove(1);
+        assert!(!forged.verify().unwrap().headers_linked);
+
+        let mut missing = BTreeMap::new();
+        missing.insert(2, vec![txids[0]]);
+        assert!(db.spv_proofs(missing).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use crate::api::BitcoinDB;
+use crate::index::{BuildMonitor, BuildOptions, BuildProgress, ValueCodec};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::{Block, Script, Txid};
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+
+pub(crate) const ID_MAP_MAGIC: &[u8; 4] = b"IDMP";
+const COMPRESSED_ID_MAP_MAGIC: &[u8; 4] = b"IDMZ";
+
+///
+/// Map keys (e.g., txid) to sequential `u64` ids starting from 0,
+/// held in memory.
+///
+/// The whole mapping can be saved to and loaded from a file, so that
+/// ids remain stable across runs. For mappings too large for memory,
+/// or written as they are assigned, see `IdStore` (feature `on-disk-utxo`).
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{BitcoinDB, FBlock};
+/// use bitcoin_explorer::ids::{ScriptIdMap, TxIdMap};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let mut tx_ids = TxIdMap::new();
+/// let mut script_ids = ScriptIdMap::new();
+/// for block in db.iter_block::<FBlock>(0, 100000) {
+///     for tx in block.txdata {
+///         let tx_id = tx_ids.get_or_assign(&tx.txid);
+///         for o in tx.output.iter() {
+///             let script_id = script_ids.get_or_assign(&o.script_pubkey);
+///             println!("{} -> {} ({} sat)", tx_id, script_id, o.value);
+///         }
+///     }
+/// }
+/// tx_ids.save(Path::new("./txids.bin")).unwrap();
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct IdMap<K> {
+    ids: HashMap<K, u64>,
+    keys: Vec<K>,
+}
+
+/// Map txids to sequential integers.
+pub type TxIdMap = IdMap<Txid>;
+
+/// Map script public keys to sequential integers.
+pub type ScriptIdMap = IdMap<Script>;
+
+impl<K> Default for IdMap<K> {
+    fn default() -> Self {
+        IdMap {
+            ids: HashMap::new(),
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl<K> IdMap<K>
+where
+    K: Hash + Eq + Clone + Encodable + Decodable,
+{
+    pub fn new() -> Self {
+        IdMap::default()
+    }
+
+    ///
+    /// Get the id of a key, assigning the next id if it is new.
+    ///
+    pub fn get_or_assign(&mut self, key: &K) -> u64 {
+        if let Some(id) = self.ids.get(key) {
+            return *id;
+        }
+        let id = self.keys.len() as u64;
+        self.ids.insert(key.clone(), id);
+        self.keys.push(key.clone());
+        id
+    }
+
+    #[inline]
+    pub fn get(&self, key: &K) -> Option<u64> {
+        self.ids.get(key).copied()
+    }
+
+    #[inline]
+    pub fn key_of(&self, id: u64) -> Option<&K> {
+        self.keys.get(id as usize)
+    }
+
+    ///
+    /// All keys, ordered by id.
+    ///
+    #[inline]
+    pub fn keys(&self) -> &[K] {
+        &self.keys
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    ///
+    /// Write the mapping to a file.
+    ///
+    /// Format: 4 bytes magic `IDMP`, u64 count,
+    /// followed by consensus-encoded keys ordered by id.
+    ///
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(ID_MAP_MAGIC)?;
+        self.encode_payload(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Write the mapping to a file, with the payload
+    /// (count and keys) encoded by `codec`.
+    ///
+    /// Format: 4 bytes magic `IDMZ`, followed by the encoded payload.
+    ///
+    pub fn save_compressed(&self, path: &Path, codec: &ValueCodec) -> OpResult<()> {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload)?;
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(COMPRESSED_ID_MAP_MAGIC)?;
+        w.write_all(&codec.encode(&payload)?)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Read a mapping written by `save`, or by `save_compressed`
+    /// without a zstd dictionary.
+    ///
+    pub fn load(path: &Path) -> OpResult<Self> {
+        Self::load_with(path, &ValueCodec::default())
+    }
+
+    ///
+    /// Read a mapping written by `save` or `save_compressed`.
+    ///
+    /// `codec` must carry the dictionary used when saving, if any.
+    ///
+    pub fn load_with(path: &Path, codec: &ValueCodec) -> OpResult<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic == ID_MAP_MAGIC {
+            Self::decode_payload(&mut r)
+        } else if &magic == COMPRESSED_ID_MAP_MAGIC {
+            let mut encoded = Vec::new();
+            r.read_to_end(&mut encoded)?;
+            Self::decode_payload(&mut Cursor::new(codec.decode(&encoded)?))
+        } else {
+            Err(OpError::from("not an id map file"))
+        }
+    }
+
+    fn encode_payload<W: Write>(&self, w: &mut W) -> OpResult<()> {
+        (self.keys.len() as u64).consensus_encode(&mut *w)?;
+        for k in self.keys.iter() {
+            k.consensus_encode(&mut *w)?;
+        }
+        Ok(())
+    }
+
+    fn decode_payload<R: Read>(r: &mut R) -> OpResult<Self> {
+        let count = u64::consensus_decode(&mut *r)?;
+        let mut map = IdMap::new();
+        for _ in 0..count {
+            let key = K::consensus_decode(&mut *r)?;
+            map.get_or_assign(&key);
+        }
+        if map.len() as u64 != count {
+            return Err(OpError::from("duplicate keys in id map file"));
+        }
+        Ok(map)
+    }
+}
+
+///
+/// Assign ids to the txids of blocks `start..end`, in chain order.
+///
+/// Reports progress and applies the I/O throttle of `options`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use bitcoin_explorer::ids::build_tx_id_map;
+/// use bitcoin_explorer::index::BuildOptions;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// // at most 50 MB/s, so that bitcoind is not starved
+/// let options = BuildOptions::default()
+///     .with_progress(|p| println!("{}", p))
+///     .with_io_limit(50_000_000);
+/// let (tx_ids, _) = build_tx_id_map(&db, 0, db.get_block_count(), &options);
+/// ```
+///
+pub fn build_tx_id_map(
+    db: &BitcoinDB,
+    start: usize,
+    end: usize,
+    options: &BuildOptions,
+) -> (TxIdMap, BuildProgress) {
+    let end = end.min(db.get_block_count());
+    let mut map = TxIdMap::new();
+    let mut monitor = BuildMonitor::new(end.saturating_sub(start), options);
+    for block in db.iter_block::<Block>(start, end) {
+        for tx in block.txdata.iter() {
+            map.get_or_assign(&tx.txid());
+        }
+        monitor.block_done(block.size() as u64);
+    }
+    (map, monitor.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestDir;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_id_map_roundtrip() {
+        let mut map = TxIdMap::new();
+        let a = Txid::hash(b"a");
+        let b = Txid::hash(b"b");
+        assert_eq!(map.get_or_assign(&a), 0);
+        assert_eq!(map.get_or_assign(&b), 1);
+        assert_eq!(map.get_or_assign(&a), 0);
+        assert_eq!(map.key_of(1), Some(&b));
+        assert_eq!(map.len(), 2);
+
+        let dir = TestDir::new("id_map");
+        let path = dir.join("id_map.bin");
+        map.save(&path).unwrap();
+        let loaded = TxIdMap::load(&path).unwrap();
+        assert_eq!(loaded.keys(), map.keys());
+        assert_eq!(loaded.get(&b), Some(1));
+    }
+
+    #[test]
+    fn test_id_map_compressed_roundtrip() {
+        use crate::index::{IndexCompression, ValueCodec};
+        let mut map = TxIdMap::new();
+        for i in 0u32..100 {
+            map.get_or_assign(&Txid::hash(&i.to_le_bytes()));
+        }
+        let dir = TestDir::new("id_map_z");
+        let path = dir.join("id_map.bin");
+        map.save_compressed(&path, &ValueCodec::new(IndexCompression::None))
+            .unwrap();
+        let loaded = TxIdMap::load(&path).unwrap();
+        assert_eq!(loaded.keys(), map.keys());
+    }
+}
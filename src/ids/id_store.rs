@@ -0,0 +1,269 @@
+use crate::api::BitcoinDB;
+use crate::ids::id_map::ID_MAP_MAGIC;
+use crate::index::{BuildMonitor, BuildOptions, BuildProgress};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::{deserialize, serialize, Decodable, Encodable};
+use bitcoin::{Block, Script, Txid};
+use rocksdb::{BlockBasedOptions, Options, WriteBatch, DB};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// ids: `'k' || consensus-encoded key` to id
+const KEY_PREFIX: u8 = b'k';
+/// keys: `'i' || id` to consensus-encoded key
+const ID_PREFIX: u8 = b'i';
+/// next id to assign
+const META_KEY: &[u8] = b"m";
+
+///
+/// Map keys (e.g., txid) to sequential `u64` ids starting from 0,
+/// stored in a RocksDB at a given path.
+///
+/// Unlike `IdMap`, the mapping is not held in memory: each new key is
+/// written as it is assigned, atomically with the next id, so that ids
+/// remain stable across runs and an interrupted job resumes with the
+/// ids already assigned. `export` writes the mapping in the file format
+/// of `IdMap::save`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{BitcoinDB, FBlock};
+/// use bitcoin_explorer::ids::TxIdStore;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let mut tx_ids = TxIdStore::open(Path::new("./tx_ids")).unwrap();
+/// for block in db.iter_block::<FBlock>(0, 100000) {
+///     let txids: Vec<_> = block.txdata.iter().map(|tx| tx.txid).collect();
+///     let ids = tx_ids.get_or_assign_all(&txids).unwrap();
+///     println!("{:?}", ids);
+/// }
+/// tx_ids.export(Path::new("./txids.bin")).unwrap();
+/// ```
+///
+pub struct IdStore<K> {
+    db: DB,
+    next: u64,
+    _key: PhantomData<K>,
+}
+
+/// Store txids as sequential integers.
+pub type TxIdStore = IdStore<Txid>;
+
+/// Store script public keys as sequential integers.
+pub type ScriptIdStore = IdStore<Script>;
+
+impl<K> IdStore<K>
+where
+    K: Encodable + Decodable,
+{
+    ///
+    /// Open (or create) the store at `path`.
+    ///
+    pub fn open(path: &Path) -> OpResult<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        // most lookups during iteration are of new keys
+        let mut block_options = BlockBasedOptions::default();
+        block_options.set_bloom_filter(10.0, false);
+        options.set_block_based_table_factory(&block_options);
+        let db = DB::open(&options, path).map_err(|e| {
+            OpError::from(format!("failed to open rocksDB for id store: {}", e).as_str())
+        })?;
+        let next = match read(&db, META_KEY)? {
+            Some(value) if value.len() == 8 => u64::from_le_bytes(value[..].try_into().unwrap()),
+            Some(_) => return Err(OpError::from("invalid id store metadata")),
+            None => 0,
+        };
+        Ok(IdStore {
+            db,
+            next,
+            _key: PhantomData,
+        })
+    }
+
+    ///
+    /// Get the id of a key, assigning (and writing) the next id if it is new.
+    ///
+    pub fn get_or_assign(&mut self, key: &K) -> OpResult<u64> {
+        Ok(self.get_or_assign_all(std::slice::from_ref(key))?[0])
+    }
+
+    ///
+    /// Get the ids of `keys`, assigning the next ids to new keys
+    /// in order. The new keys are written in one atomic batch.
+    ///
+    pub fn get_or_assign_all(&mut self, keys: &[K]) -> OpResult<Vec<u64>> {
+        let encoded: Vec<Vec<u8>> = keys.iter().map(|k| key_key(&serialize(k))).collect();
+        let found = self
+            .db
+            .multi_get(encoded.iter())
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?;
+        let mut batch = WriteBatch::default();
+        // keys repeated within `keys` are only assigned once
+        let mut assigned: HashMap<&[u8], u64> = HashMap::new();
+        let mut ids = Vec::with_capacity(keys.len());
+        for (key, value) in encoded.iter().zip(found) {
+            let id = match value {
+                Some(value) => decode_id(&value)?,
+                None => match assigned.get(&key[..]) {
+                    Some(id) => *id,
+                    None => {
+                        let id = self.next + assigned.len() as u64;
+                        batch.put(key, id.to_le_bytes());
+                        batch.put(id_key(id), &key[1..]);
+                        assigned.insert(key, id);
+                        id
+                    }
+                },
+            };
+            ids.push(id);
+        }
+        if !assigned.is_empty() {
+            let next = self.next + assigned.len() as u64;
+            batch.put(META_KEY, next.to_le_bytes());
+            self.db
+                .write(batch)
+                .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))?;
+            self.next = next;
+        }
+        Ok(ids)
+    }
+
+    pub fn get(&self, key: &K) -> OpResult<Option<u64>> {
+        read(&self.db, &key_key(&serialize(key)))?
+            .map(|value| decode_id(&value))
+            .transpose()
+    }
+
+    pub fn key_of(&self, id: u64) -> OpResult<Option<K>> {
+        match read(&self.db, &id_key(id))? {
+            Some(value) => Ok(Some(deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.next
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.next == 0
+    }
+
+    ///
+    /// Write the mapping to a file readable by `IdMap::load`.
+    ///
+    pub fn export(&self, path: &Path) -> OpResult<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(ID_MAP_MAGIC)?;
+        self.next.consensus_encode(&mut w)?;
+        for id in 0..self.next {
+            match read(&self.db, &id_key(id))? {
+                Some(value) => w.write_all(&value)?,
+                None => {
+                    return Err(OpError::from(
+                        format!("id {} missing from id store", id).as_str(),
+                    ))
+                }
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// Assign ids in `store` to the txids of blocks `start..end`, in chain order.
+///
+/// Txids already in the store keep their ids, so that a build interrupted
+/// (or extended to new blocks) continues the same numbering.
+/// Reports progress and applies the I/O throttle of `options`.
+///
+pub fn update_tx_id_store(
+    store: &mut TxIdStore,
+    db: &BitcoinDB,
+    start: usize,
+    end: usize,
+    options: &BuildOptions,
+) -> OpResult<BuildProgress> {
+    let end = end.min(db.get_block_count());
+    let mut monitor = BuildMonitor::new(end.saturating_sub(start), options);
+    for block in db.iter_block::<Block>(start, end) {
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        store.get_or_assign_all(&txids)?;
+        monitor.block_done(block.size() as u64);
+    }
+    Ok(monitor.finish())
+}
+
+fn read(db: &DB, key: &[u8]) -> OpResult<Option<Vec<u8>>> {
+    db.get(key)
+        .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))
+}
+
+fn decode_id(value: &[u8]) -> OpResult<u64> {
+    value
+        .try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|_| OpError::from("corrupted id store entry"))
+}
+
+fn key_key(encoded: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + encoded.len());
+    key.push(KEY_PREFIX);
+    key.extend_from_slice(encoded);
+    key
+}
+
+fn id_key(id: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(9);
+    key.push(ID_PREFIX);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::TxIdMap;
+    use crate::testutil::TestDir;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_id_store_persists() {
+        let dir = TestDir::new("id_store");
+        let path = dir.join("id_store");
+        let a = Txid::hash(b"a");
+        let b = Txid::hash(b"b");
+        let c = Txid::hash(b"c");
+        {
+            let mut store = TxIdStore::open(&path).unwrap();
+            assert_eq!(store.get_or_assign(&a).unwrap(), 0);
+            assert_eq!(store.get_or_assign_all(&[b, a, b]).unwrap(), vec![1, 0, 1]);
+            assert_eq!(store.len(), 2);
+        }
+        let mut store = TxIdStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(&b).unwrap(), Some(1));
+        assert_eq!(store.get(&c).unwrap(), None);
+        assert_eq!(store.key_of(0).unwrap(), Some(a));
+        assert_eq!(store.get_or_assign(&c).unwrap(), 2);
+
+        let exported = dir.join("id_map.bin");
+        store.export(&exported).unwrap();
+        let loaded = TxIdMap::load(&exported).unwrap();
+        assert_eq!(loaded.keys(), &[a, b, c]);
+    }
+}
@@ -0,0 +1,21 @@
+//!
+//! Compact integer identifiers for txids and scripts.
+//!
+//! Assign sequential `u64` ids during iteration, so that exported graphs
+//! are integer-labeled instead of using 32-byte hashes.
+//! `IdMap` holds the mapping in memory and saves it as a whole;
+//! `IdStore` (feature `on-disk-utxo`) writes it to a RocksDB
+//! as ids are assigned, and exports it in the format of `IdMap`.
+//! `TxNumbering` numbers transactions in chain order from the block index,
+//! mapping dense numbers to (height, index) and back without any map.
+//!
+
+mod id_map;
+#[cfg(feature = "on-disk-utxo")]
+mod id_store;
+mod tx_number;
+
+pub use id_map::{build_tx_id_map, IdMap, ScriptIdMap, TxIdMap};
+#[cfg(feature = "on-disk-utxo")]
+pub use id_store::{update_tx_id_store, IdStore, ScriptIdStore, TxIdStore};
+pub use tx_number::TxNumbering;
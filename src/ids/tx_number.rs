@@ -0,0 +1,146 @@
+use crate::api::BitcoinDB;
+use crate::parser::errors::{OpError, OpResult};
+
+///
+/// Dense transaction numbers of the main chain.
+///
+/// Transactions are numbered in chain order, from 0 (the genesis coinbase),
+/// using the transaction counts of the block index: no block is read.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use std::path::Path;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+///
+/// let numbering = db.tx_numbering();
+/// let n = numbering.tx_number(170, 1).unwrap();
+/// assert_eq!(numbering.position(n), Some((170, 1)));
+/// println!("{} transactions in total", numbering.total());
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxNumbering {
+    /// number of transactions before each height, followed by the total
+    cumulative: Vec<u64>,
+}
+
+impl TxNumbering {
+    pub(crate) fn from_counts<I: IntoIterator<Item = u32>>(n_tx: I) -> Self {
+        let mut cumulative = vec![0];
+        let mut total = 0u64;
+        for n in n_tx {
+            total += n as u64;
+            cumulative.push(total);
+        }
+        TxNumbering { cumulative }
+    }
+
+    /// number of blocks covered
+    pub fn len(&self) -> usize {
+        self.cumulative.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of transactions of all covered blocks
+    pub fn total(&self) -> u64 {
+        *self.cumulative.last().unwrap()
+    }
+
+    /// number of transactions of the block at `height`
+    pub fn ntx_at(&self, height: usize) -> Option<u64> {
+        Some(self.cumulative.get(height + 1)? - self.cumulative[height])
+    }
+
+    /// number of transactions of blocks `0..=height`
+    pub fn cumulative_tx_count(&self, height: usize) -> Option<u64> {
+        self.cumulative.get(height + 1).copied()
+    }
+
+    /// number of the first transaction (coinbase) of the block at `height`
+    pub fn first_tx_number(&self, height: usize) -> Option<u64> {
+        if height < self.len() {
+            Some(self.cumulative[height])
+        } else {
+            None
+        }
+    }
+
+    /// number of the `index`-th transaction of the block at `height`
+    pub fn tx_number(&self, height: usize, index: usize) -> Option<u64> {
+        if (index as u64) < self.ntx_at(height)? {
+            Some(self.cumulative[height] + index as u64)
+        } else {
+            None
+        }
+    }
+
+    /// (height, index in block) of a transaction number
+    pub fn position(&self, tx_number: u64) -> Option<(usize, usize)> {
+        if tx_number >= self.total() {
+            return None;
+        }
+        // the last block starting at or before `tx_number`
+        // (empty blocks do not exist, but are handled anyway)
+        let height = self.cumulative.partition_point(|c| *c <= tx_number) - 1;
+        Some((height, (tx_number - self.cumulative[height]) as usize))
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Number of transactions of the block at `height`, from the block index.
+    ///
+    pub fn ntx_at(&self, height: usize) -> OpResult<usize> {
+        match self.block_index.records.get(height) {
+            Some(record) if record.n_tx > 0 => Ok(record.n_tx as usize),
+            Some(_) => Err(OpError::from("block not downloaded")),
+            None => Err(OpError::from("height not found")),
+        }
+    }
+
+    ///
+    /// Dense transaction numbers of blocks `0..get_block_count()`.
+    ///
+    pub fn tx_numbering(&self) -> TxNumbering {
+        TxNumbering::from_counts(
+            self.block_index.records[..self.get_block_count()]
+                .iter()
+                .map(|r| r.n_tx),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tx_numbering() {
+        let numbering = TxNumbering::from_counts(vec![1, 3, 1, 2]);
+        assert_eq!(numbering.len(), 4);
+        assert_eq!(numbering.total(), 7);
+        assert_eq!(numbering.ntx_at(1), Some(3));
+        assert_eq!(numbering.ntx_at(4), None);
+        assert_eq!(numbering.cumulative_tx_count(1), Some(4));
+        assert_eq!(numbering.first_tx_number(2), Some(4));
+        assert_eq!(numbering.tx_number(1, 2), Some(3));
+        assert_eq!(numbering.tx_number(1, 3), None);
+
+        let mut n = 0;
+        for height in 0..numbering.len() {
+            for index in 0..numbering.ntx_at(height).unwrap() as usize {
+                assert_eq!(numbering.tx_number(height, index), Some(n));
+                assert_eq!(numbering.position(n), Some((height, index)));
+                n += 1;
+            }
+        }
+        assert_eq!(numbering.position(7), None);
+        assert!(TxNumbering::from_counts(vec![]).is_empty());
+    }
+}
@@ -0,0 +1,842 @@
+//!
+//! Address (script public key) to transaction history index,
+//! persisted in RocksDB and updated incrementally.
+//!
+use crate::api::{Address, BitcoinDB, BlockHash, Script, SplitMix64, Txid};
+use crate::index::limits::LimitTracker;
+use crate::index::{
+    AddressEvent, AddressEventKind, BuildMonitor, BuildOptions, BuildProgress, QueryLimits,
+    QueryResults, ValueCodec,
+};
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::ChainState;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Block, Network, OutPoint, TxOut};
+use rocksdb::{IteratorMode, Options, ReadOptions, WriteBatch, DB};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::path::Path;
+
+/// history entries: `'h' || script hash || height || txid || index || kind`
+const HISTORY_PREFIX: u8 = b'h';
+/// unspent outputs: `'o' || txid || vout` to `script hash || value`
+const OUTPUT_PREFIX: u8 = b'o';
+/// next height to index and hash of the last indexed block
+const META_KEY: &[u8] = b"m";
+/// outputs spent by a block: `'u' || height` to `(txid || vout || script hash || value)*`
+const UNDO_PREFIX: u8 = b'u';
+/// number of last blocks keeping undo records (to be disconnected by `unindex_block`)
+const UNDO_DEPTH: usize = 100;
+///
+/// mainnet blocks whose coinbase duplicates the coinbase of an earlier
+/// block (91812 and 91722), overwriting its unspent outputs in Core
+///
+const BIP30_DUPLICATE_HEIGHTS: [usize; 2] = [91842, 91880];
+
+///
+/// A script whose balance in an `AddressIndex` differs from
+/// the UTXO set of Bitcoin Core.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceMismatch {
+    pub script_pubkey: Script,
+    ///
+    /// satoshi of the unspent outputs of the chainstate, and of the
+    /// genesis coinbase (which Core does not add to the UTXO set)
+    ///
+    pub chainstate: u64,
+    /// satoshi of the history of the index
+    pub index: u64,
+}
+
+///
+/// Result of `AddressIndex::verify_against_chainstate`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainStateReport {
+    /// tip of both the index and the chainstate
+    pub best_block: BlockHash,
+    /// number of scripts compared
+    pub checked: usize,
+    pub mismatches: Vec<BalanceMismatch>,
+}
+
+impl ChainStateReport {
+    /// every sampled balance matches
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+///
+/// An index of the transactions receiving to and spending from
+/// each script public key, stored in a RocksDB at a given path.
+///
+/// The index also keeps the unspent outputs it has seen, so that
+/// `update` only processes blocks added since the last build.
+/// Each block is written atomically: an interrupted build resumes
+/// at the first block not indexed.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::AddressIndex;
+/// use bitcoin_explorer::{Address, BitcoinDB};
+/// use std::path::Path;
+/// use std::str::FromStr;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// // scan the chain once (hours), later calls only index new blocks
+/// let index = AddressIndex::build(&db, Path::new("./address_index")).unwrap();
+///
+/// let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+/// for event in db.get_address_history(&index, &address).unwrap() {
+///     println!("{} {} {:?} {}", event.height, event.txid, event.kind, event.value);
+/// }
+/// let balance = db.get_address_balance(&index, &address, 700000).unwrap();
+/// ```
+///
+pub struct AddressIndex {
+    db: DB,
+    codec: ValueCodec,
+}
+
+impl AddressIndex {
+    ///
+    /// Open (or create) the index at `path`, without indexing any block.
+    ///
+    pub fn open(path: &Path) -> OpResult<Self> {
+        AddressIndex::open_with_codec(path, ValueCodec::default())
+    }
+
+    ///
+    /// `open`, with values encoded by `codec` (e.g. zstd compressed).
+    ///
+    /// Values record their encoding: an index written with any
+    /// compression opens with any codec of the same dictionary.
+    ///
+    pub fn open_with_codec(path: &Path, codec: ValueCodec) -> OpResult<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        let db = DB::open(&options, path).map_err(|e| {
+            OpError::from(format!("failed to open rocksDB for address index: {}", e).as_str())
+        })?;
+        Ok(AddressIndex { db, codec })
+    }
+
+    ///
+    /// Open the index at `path` and index all blocks of `db` not yet indexed.
+    ///
+    pub fn build(db: &BitcoinDB, path: &Path) -> OpResult<Self> {
+        let index = AddressIndex::open(path)?;
+        index.update(db, &BuildOptions::default())?;
+        Ok(index)
+    }
+
+    ///
+    /// Number of blocks indexed (blocks `0..indexed_height()`).
+    ///
+    pub fn indexed_height(&self) -> OpResult<usize> {
+        Ok(self.meta()?.map_or(0, |(height, _)| height))
+    }
+
+    /// next height to index and the hash of the last indexed block
+    fn meta(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        match self.read(META_KEY)? {
+            Some(value) if value.len() == 36 => {
+                let height = u32::from_le_bytes(value[..4].try_into().unwrap()) as usize;
+                Ok(Some((height, BlockHash::from_slice(&value[4..])?)))
+            }
+            Some(_) => Err(OpError::from("invalid address index metadata")),
+            None => Ok(None),
+        }
+    }
+
+    fn read(&self, key: &[u8]) -> OpResult<Option<Vec<u8>>> {
+        self.db
+            .get(key)
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?
+            .map(|value| self.codec.decode(&value))
+            .transpose()
+    }
+
+    ///
+    /// Check that the last indexed block is in the main chain of `db`.
+    ///
+    fn check_tip(&self, db: &BitcoinDB) -> OpResult<usize> {
+        match self.meta()? {
+            Some((height, tip)) => {
+                if db.get_hash_from_height(height - 1).ok() != Some(tip) {
+                    return Err(OpError::from(
+                        "address index tip is not in the main chain, rebuild the index",
+                    ));
+                }
+                Ok(height)
+            }
+            None => Ok(0),
+        }
+    }
+
+    ///
+    /// Index the blocks of `db` added since the last build.
+    ///
+    /// Fails if the last indexed block has been reorganized out
+    /// of the main chain (the index must then be rebuilt).
+    ///
+    pub fn update(&self, db: &BitcoinDB, options: &BuildOptions) -> OpResult<BuildProgress> {
+        let start = self.check_tip(db)?;
+        let end = db.get_block_count();
+        let mut monitor = BuildMonitor::new(end.saturating_sub(start), options);
+        for (i, block) in db.iter_block::<Block>(start, end).enumerate() {
+            self.index_block(start + i, &block)?;
+            monitor.block_done(block.size() as u64);
+        }
+        Ok(monitor.finish())
+    }
+
+    /// height and hash of the last indexed block
+    pub(crate) fn tip(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        Ok(self.meta()?.map(|(height, hash)| (height - 1, hash)))
+    }
+
+    pub(crate) fn index_block(&self, height: usize, block: &Block) -> OpResult<()> {
+        let mut batch = WriteBatch::default();
+        // outputs created in this block, spendable in the same block
+        let mut created: HashMap<OutPoint, ([u8; 32], u64)> = HashMap::new();
+        // outputs of earlier blocks spent by this block
+        let mut undo = Vec::new();
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            if !tx.is_coin_base() {
+                for (vin, input) in tx.input.iter().enumerate() {
+                    let outpoint = input.previous_output;
+                    let (script_hash, value) = match created.remove(&outpoint) {
+                        Some(spent) => spent,
+                        None => {
+                            let key = output_key(&outpoint);
+                            let spent = match self.read(&key)? {
+                                Some(value) => decode_output(&value)?,
+                                // unindexed output (e.g. OP_RETURN)
+                                None => continue,
+                            };
+                            batch.delete(key);
+                            undo.extend_from_slice(&outpoint.txid[..]);
+                            undo.extend_from_slice(&outpoint.vout.to_le_bytes());
+                            undo.extend_from_slice(&spent.0);
+                            undo.extend_from_slice(&spent.1.to_le_bytes());
+                            spent
+                        }
+                    };
+                    let key = history_key(&script_hash, height, &txid, vin as u32, 1);
+                    batch.put(key, self.codec.encode(&value.to_le_bytes())?);
+                }
+            }
+            for (vout, output) in tx.output.iter().enumerate() {
+                if output.script_pubkey.is_provably_unspendable() {
+                    continue;
+                }
+                let script_hash = script_hash(&output.script_pubkey);
+                let key = history_key(&script_hash, height, &txid, vout as u32, 0);
+                batch.put(key, self.codec.encode(&output.value.to_le_bytes())?);
+                let outpoint = OutPoint::new(txid, vout as u32);
+                created.insert(outpoint, (script_hash, output.value));
+            }
+        }
+        for (outpoint, (script_hash, value)) in created {
+            let value = [&script_hash[..], &value.to_le_bytes()].concat();
+            batch.put(output_key(&outpoint), self.codec.encode(&value)?);
+        }
+        batch.put(undo_key(height), self.codec.encode(&undo)?);
+        if height >= UNDO_DEPTH {
+            batch.delete(undo_key(height - UNDO_DEPTH));
+        }
+        let mut meta = (height as u32 + 1).to_le_bytes().to_vec();
+        meta.extend_from_slice(&block.block_hash()[..]);
+        batch.put(META_KEY, self.codec.encode(&meta)?);
+        self.db
+            .write(batch)
+            .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))
+    }
+
+    ///
+    /// Undo `index_block` of the last indexed block, at `height`.
+    ///
+    /// Only the last `UNDO_DEPTH` indexed blocks can be disconnected.
+    ///
+    pub(crate) fn unindex_block(&self, height: usize, block: &Block) -> OpResult<()> {
+        let undo = match self.read(&undo_key(height))? {
+            Some(undo) if undo.len() % 76 == 0 => undo,
+            Some(_) => return Err(OpError::from("invalid undo record in address index")),
+            None => {
+                return Err(OpError::from(
+                    format!(
+                        "address index cannot disconnect height {}, deeper than {} blocks",
+                        height, UNDO_DEPTH
+                    )
+                    .as_str(),
+                ))
+            }
+        };
+        let mut batch = WriteBatch::default();
+        // script hash of the outputs spent by this block
+        let mut spent: HashMap<OutPoint, [u8; 32]> = HashMap::new();
+        for entry in undo.chunks(76) {
+            let txid = Txid::from_slice(&entry[..32])?;
+            let vout = u32::from_le_bytes(entry[32..36].try_into().unwrap());
+            let outpoint = OutPoint::new(txid, vout);
+            spent.insert(outpoint, entry[36..68].try_into().unwrap());
+            batch.put(output_key(&outpoint), self.codec.encode(&entry[36..])?);
+        }
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            if !tx.is_coin_base() {
+                for (vin, input) in tx.input.iter().enumerate() {
+                    if let Some(script_hash) = spent.get(&input.previous_output) {
+                        batch.delete(history_key(script_hash, height, &txid, vin as u32, 1));
+                    }
+                }
+            }
+            for (vout, output) in tx.output.iter().enumerate() {
+                if output.script_pubkey.is_provably_unspendable() {
+                    continue;
+                }
+                let script_hash = script_hash(&output.script_pubkey);
+                batch.delete(history_key(&script_hash, height, &txid, vout as u32, 0));
+                let outpoint = OutPoint::new(txid, vout as u32);
+                batch.delete(output_key(&outpoint));
+                spent.insert(outpoint, script_hash);
+            }
+        }
+        batch.delete(undo_key(height));
+        if height == 0 {
+            batch.delete(META_KEY);
+        } else {
+            let mut meta = (height as u32).to_le_bytes().to_vec();
+            meta.extend_from_slice(&block.header.prev_blockhash[..]);
+            batch.put(META_KEY, self.codec.encode(&meta)?);
+        }
+        self.db
+            .write(batch)
+            .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))
+    }
+
+    ///
+    /// History of a script public key, sorted by height
+    /// (then by txid, receptions before spends).
+    ///
+    pub fn script_history(&self, script: &Script) -> OpResult<Vec<AddressEvent>> {
+        self.iter_script_history(script).collect()
+    }
+
+    ///
+    /// Stream the history of a script public key, in the order of `script_history`,
+    /// reading the index as the iterator advances.
+    ///
+    pub fn iter_script_history(
+        &self,
+        script: &Script,
+    ) -> impl Iterator<Item = OpResult<AddressEvent>> + '_ {
+        let mut prefix = vec![HISTORY_PREFIX];
+        prefix.extend_from_slice(&script_hash(script));
+        let key_start = prefix.len();
+        // the iterator owns its start key
+        let mut opts = ReadOptions::default();
+        opts.set_iterate_lower_bound(prefix.clone());
+        self.db
+            .iterator_opt(IteratorMode::Start, opts)
+            .map(|entry| {
+                entry.map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))
+            })
+            .take_while(move |entry| match entry {
+                Ok((key, _)) => key.starts_with(&prefix),
+                Err(_) => true,
+            })
+            .map(move |entry| {
+                let (key, value) = entry?;
+                decode_event(&key[key_start..], &self.codec.decode(&value)?)
+            })
+    }
+
+    ///
+    /// `script_history` under `limits`: the returned events are the first
+    /// events of the history if a limit is reached.
+    ///
+    pub fn script_history_limited(
+        &self,
+        script: &Script,
+        limits: &QueryLimits,
+    ) -> OpResult<QueryResults<AddressEvent>> {
+        LimitTracker::new(limits).try_collect(self.iter_script_history(script))
+    }
+
+    ///
+    /// Balance (satoshi) of a script public key after block `height`.
+    ///
+    pub fn script_balance(&self, script: &Script, height: usize) -> OpResult<u64> {
+        // spends may be sorted before receptions within a block
+        let mut balance = 0i64;
+        for event in self.script_history(script)? {
+            if event.height > height {
+                break;
+            }
+            match event.kind {
+                AddressEventKind::Received => balance += event.value as i64,
+                AddressEventKind::Spent => balance -= event.value as i64,
+            }
+        }
+        Ok(balance as u64)
+    }
+
+    ///
+    /// Compare the balances of up to `sample_n` scripts with the UTXO set
+    /// of Bitcoin Core in the chainstate levelDB at `chainstate_path`
+    /// (`datadir/chainstate`, or a copy of it).
+    ///
+    /// Scripts are drawn uniformly among the distinct scripts of the UTXO set,
+    /// deterministically given `seed`. The chainstate must be at the tip of
+    /// the index: update the index with Bitcoin Core stopped.
+    /// Reads the whole chainstate twice.
+    ///
+    /// `db` is the chain of the index, used to account for the coins
+    /// Core leaves out of the UTXO set (the genesis coinbase and,
+    /// on mainnet, the coinbases overwritten by BIP30 duplicates).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::index::AddressIndex;
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    /// let index = AddressIndex::open(Path::new("./address_index")).unwrap();
+    /// let report = index
+    ///     .verify_against_chainstate(&db, &path.join("chainstate"), 1000, 42)
+    ///     .unwrap();
+    /// for m in report.mismatches.iter() {
+    ///     println!("{}: {} != {}", m.script_pubkey, m.index, m.chainstate);
+    /// }
+    /// ```
+    ///
+    pub fn verify_against_chainstate(
+        &self,
+        db: &BitcoinDB,
+        chainstate_path: &Path,
+        sample_n: usize,
+        seed: u64,
+    ) -> OpResult<ChainStateReport> {
+        let chainstate = ChainState::open_path(chainstate_path)?;
+        let best_block = chainstate.best_block()?;
+        let tip = self.meta()?.map(|(_, tip)| tip);
+        if tip != Some(best_block) {
+            return Err(OpError::from(
+                format!(
+                    "chainstate is at block {}, address index at {:?}",
+                    best_block, tip
+                )
+                .as_str(),
+            ));
+        }
+
+        // the `sample_n` scripts of the smallest seeded keys
+        let mut drawn: BTreeMap<(u64, [u8; 32]), Script> = BTreeMap::new();
+        for utxo in chainstate.iter_utxos() {
+            let script = utxo?.1.txout.script_pubkey;
+            let script_hash = script_hash(&script);
+            let key = (sample_key(seed, &script_hash), script_hash);
+            if drawn.contains_key(&key) {
+                continue;
+            }
+            if drawn.len() >= sample_n {
+                match drawn.keys().next_back() {
+                    Some(last) if *last > key => {
+                        let last = *last;
+                        drawn.remove(&last);
+                    }
+                    _ => continue,
+                }
+            }
+            drawn.insert(key, script);
+        }
+        // sampled script hash to (script, chainstate balance)
+        let mut sample: HashMap<[u8; 32], (Script, u64)> = drawn
+            .into_iter()
+            .map(|((_, script_hash), script)| (script_hash, (script, 0)))
+            .collect();
+        for utxo in chainstate.iter_utxos() {
+            let txout = utxo?.1.txout;
+            if let Some((_, balance)) = sample.get_mut(&script_hash(&txout.script_pubkey)) {
+                *balance += txout.value;
+            }
+        }
+        for txout in self.coins_missing_from_chainstate(db)? {
+            if let Some((_, balance)) = sample.get_mut(&script_hash(&txout.script_pubkey)) {
+                *balance += txout.value;
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        for (script_pubkey, chainstate) in sample.values() {
+            let index = self.script_balance(script_pubkey, usize::MAX)?;
+            if index != *chainstate {
+                mismatches.push(BalanceMismatch {
+                    script_pubkey: script_pubkey.clone(),
+                    chainstate: *chainstate,
+                    index,
+                });
+            }
+        }
+        mismatches.sort_by(|a, b| a.script_pubkey.cmp(&b.script_pubkey));
+        Ok(ChainStateReport {
+            best_block,
+            checked: sample.len(),
+            mismatches,
+        })
+    }
+
+    ///
+    /// Outputs counted by the index but not in the UTXO set of Core:
+    /// the genesis coinbase, which Core never adds, and, on mainnet,
+    /// the first of each pair of duplicated coinbases (BIP30), which
+    /// the index receives twice while Core keeps a single coin.
+    ///
+    fn coins_missing_from_chainstate(&self, db: &BitcoinDB) -> OpResult<Vec<TxOut>> {
+        let mut missing = Vec::new();
+        let genesis = genesis_block(db.network()).txdata.swap_remove(0);
+        if self
+            .read(&output_key(&OutPoint::new(genesis.txid(), 0)))?
+            .is_some()
+        {
+            missing.extend(genesis.output);
+        }
+        if db.network() == Network::Bitcoin {
+            let indexed = self.indexed_height()?;
+            for height in BIP30_DUPLICATE_HEIGHTS.iter().filter(|h| **h < indexed) {
+                let block: Block = db.get_block(*height)?;
+                missing.extend(block.txdata[0].output.iter().cloned());
+            }
+        }
+        Ok(missing)
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// History of `address` from an `AddressIndex` of this chain.
+    ///
+    /// Fails if the index is not up to date with the main chain
+    /// (call `AddressIndex::update` first).
+    ///
+    pub fn get_address_history(
+        &self,
+        index: &AddressIndex,
+        address: &Address,
+    ) -> OpResult<Vec<AddressEvent>> {
+        self.check_address_index(index)?;
+        index.script_history(&address.script_pubkey())
+    }
+
+    ///
+    /// Balance (satoshi) of `address` after block `height`,
+    /// from an `AddressIndex` of this chain.
+    ///
+    pub fn get_address_balance(
+        &self,
+        index: &AddressIndex,
+        address: &Address,
+        height: usize,
+    ) -> OpResult<u64> {
+        let indexed = index.check_tip(self)?;
+        if height >= indexed {
+            return Err(OpError::from(
+                format!("address index only covers heights below {}", indexed).as_str(),
+            ));
+        }
+        index.script_balance(&address.script_pubkey(), height)
+    }
+
+    ///
+    /// `get_address_history` under `limits`, for services answering
+    /// queries of arbitrary addresses.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::index::{AddressIndex, QueryLimits};
+    /// use bitcoin_explorer::{Address, BitcoinDB};
+    /// use std::path::Path;
+    /// use std::str::FromStr;
+    /// use std::time::Duration;
+    ///
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+    /// let index = AddressIndex::open(Path::new("./address_index")).unwrap();
+    ///
+    /// let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+    /// let limits = QueryLimits {
+    ///     max_results: Some(1000),
+    ///     ..Default::default()
+    /// }
+    /// .with_timeout(Duration::from_millis(500));
+    /// let history = db.get_address_history_limited(&index, &address, &limits).unwrap();
+    /// if let Some(limit) = history.limit_reached {
+    ///     println!("first {} events only ({})", history.items.len(), limit);
+    /// }
+    /// ```
+    ///
+    pub fn get_address_history_limited(
+        &self,
+        index: &AddressIndex,
+        address: &Address,
+        limits: &QueryLimits,
+    ) -> OpResult<QueryResults<AddressEvent>> {
+        self.check_address_index(index)?;
+        index.script_history_limited(&address.script_pubkey(), limits)
+    }
+
+    fn check_address_index(&self, index: &AddressIndex) -> OpResult<()> {
+        let indexed = index.check_tip(self)?;
+        if indexed != self.get_block_count() {
+            return Err(OpError::from(
+                format!(
+                    "address index covers {} of {} blocks, update it first",
+                    indexed,
+                    self.get_block_count()
+                )
+                .as_str(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// seeded sort key of a script in `verify_against_chainstate`
+fn sample_key(seed: u64, script_hash: &[u8; 32]) -> u64 {
+    let prefix = u64::from_le_bytes(script_hash[..8].try_into().unwrap());
+    SplitMix64::new(seed ^ prefix).next_u64()
+}
+
+/// sha256 of the script, as electrum script hashes
+fn script_hash(script: &Script) -> [u8; 32] {
+    sha256::Hash::hash(script.as_bytes()).into_inner()
+}
+
+fn output_key(outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = Vec::with_capacity(37);
+    key.push(OUTPUT_PREFIX);
+    key.extend_from_slice(&outpoint.txid[..]);
+    key.extend_from_slice(&outpoint.vout.to_be_bytes());
+    key
+}
+
+fn decode_output(value: &[u8]) -> OpResult<([u8; 32], u64)> {
+    if value.len() != 40 {
+        return Err(OpError::from("invalid output in address index"));
+    }
+    let script_hash = value[..32].try_into().unwrap();
+    Ok((
+        script_hash,
+        u64::from_le_bytes(value[32..].try_into().unwrap()),
+    ))
+}
+
+fn undo_key(height: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(5);
+    key.push(UNDO_PREFIX);
+    key.extend_from_slice(&(height as u32).to_be_bytes());
+    key
+}
+
+/// big endian height and index, so that keys sort chronologically
+fn history_key(
+    script_hash: &[u8; 32],
+    height: usize,
+    txid: &Txid,
+    index: u32,
+    kind: u8,
+) -> Vec<u8> {
+    let mut key = Vec::with_capacity(74);
+    key.push(HISTORY_PREFIX);
+    key.extend_from_slice(script_hash);
+    key.extend_from_slice(&(height as u32).to_be_bytes());
+    key.extend_from_slice(&txid[..]);
+    key.extend_from_slice(&index.to_be_bytes());
+    key.push(kind);
+    key
+}
+
+/// decode a history key (after the script hash) and its value
+fn decode_event(key: &[u8], value: &[u8]) -> OpResult<AddressEvent> {
+    if key.len() != 41 || value.len() != 8 {
+        return Err(OpError::from("invalid history entry in address index"));
+    }
+    Ok(AddressEvent {
+        height: u32::from_be_bytes(key[..4].try_into().unwrap()) as usize,
+        txid: Txid::from_slice(&key[4..36])?,
+        index: u32::from_be_bytes(key[36..40].try_into().unwrap()),
+        kind: if key[40] == 0 {
+            AddressEventKind::Received
+        } else {
+            AddressEventKind::Spent
+        },
+        value: u64::from_le_bytes(value.try_into().unwrap()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::LimitReached;
+    use crate::testutil::{SyntheticChain, TestDir};
+    use crate::utxo::{write_chainstate, Utxo};
+    use bitcoin::{Network, Transaction};
+
+    #[test]
+    fn test_address_index() {
+        let dir = TestDir::new("address_index");
+        let datadir = dir.join("datadir");
+        let index_path = dir.join("index");
+
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let miner = chain.block(&tips[0]).unwrap().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+        let address = Address::from_script(&miner, Network::Bitcoin).unwrap();
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        let index = AddressIndex::build(&db, &index_path).unwrap();
+        assert_eq!(index.indexed_height().unwrap(), 3);
+        let history = db.get_address_history(&index, &address).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            (history[0].height, history[0].kind),
+            (1, AddressEventKind::Received)
+        );
+        drop(index);
+
+        // block 3 spends the coinbase of block 1, back to the same address
+        let mut spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        spend.output[0].script_pubkey = miner;
+        let spending = chain.mine(&tips[1], vec![spend.clone()]);
+        chain.extend(&spending, 1);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        let index = AddressIndex::open(&index_path).unwrap();
+        assert!(db.get_address_history(&index, &address).is_err());
+        let progress = index.update(&db, &BuildOptions::default()).unwrap();
+        assert_eq!(progress.done, 2);
+
+        let history = db.get_address_history(&index, &address).unwrap();
+        let kinds: Vec<_> = history
+            .iter()
+            .map(|e| (e.height, e.kind, e.value))
+            .collect();
+        let spent = (3, AddressEventKind::Spent, 50 * 100_000_000);
+        let received = (3, AddressEventKind::Received, 1000);
+        assert_eq!(kinds[0], (1, AddressEventKind::Received, 50 * 100_000_000));
+        assert!(kinds[1..].contains(&spent) && kinds[1..].contains(&received));
+        assert_eq!(history[1].txid, spend.txid());
+        assert_eq!(
+            db.get_address_balance(&index, &address, 2).unwrap(),
+            50 * 100_000_000
+        );
+        assert_eq!(db.get_address_balance(&index, &address, 3).unwrap(), 1000);
+        assert!(db.get_address_balance(&index, &address, 5).is_err());
+
+        let limits = QueryLimits {
+            max_results: Some(2),
+            ..Default::default()
+        };
+        let first = db
+            .get_address_history_limited(&index, &address, &limits)
+            .unwrap();
+        assert_eq!(first.items, history[..2].to_vec());
+        assert_eq!(first.limit_reached, Some(LimitReached::MaxResults));
+        let all = db
+            .get_address_history_limited(&index, &address, &QueryLimits::default())
+            .unwrap();
+        assert!(all.is_complete());
+        assert_eq!(all.items, history);
+
+        // a reorg of the indexed tip is detected
+        chain.extend(&tips[1], 3);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        assert!(index.update(&db, &BuildOptions::default()).is_err());
+        drop(index);
+    }
+
+    #[test]
+    fn test_verify_against_chainstate() {
+        let dir = TestDir::new("address_chainstate");
+        let datadir = dir.join("datadir");
+
+        // block 3 spends the coinbase of block 1 to the miner of block 2
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let coinbase_2 = chain.block(&tips[1]).unwrap().txdata[0].clone();
+        let miner = coinbase_2.output[0].script_pubkey.clone();
+        let mut spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        spend.output[0].script_pubkey = miner;
+        let tip = chain.mine(&tips[1], vec![spend.clone()]);
+        let coinbase_3 = chain.block(&tip).unwrap().txdata[0].clone();
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        let index = AddressIndex::build(&db, &dir.join("index")).unwrap();
+
+        let utxo = |tx: &Transaction, height, is_coinbase| {
+            let utxo = Utxo {
+                txout: tx.output[0].clone(),
+                height,
+                is_coinbase,
+            };
+            (OutPoint::new(tx.txid(), 0), utxo)
+        };
+        let utxos = vec![
+            utxo(&coinbase_2, 2, true),
+            utxo(&coinbase_3, 3, true),
+            utxo(&spend, 3, false),
+        ];
+        write_chainstate(&datadir, &tip, &utxos);
+        let chainstate = datadir.join("chainstate");
+        let report = index
+            .verify_against_chainstate(&db, &chainstate, 10, 0)
+            .unwrap();
+        assert_eq!((report.best_block, report.checked), (tip, 2));
+        assert!(report.is_ok());
+        assert_eq!(
+            index
+                .verify_against_chainstate(&db, &chainstate, 1, 0)
+                .unwrap()
+                .checked,
+            1
+        );
+
+        // the chainstate misses the output of the spend
+        std::fs::remove_dir_all(datadir.join("chainstate")).unwrap();
+        write_chainstate(&datadir, &tip, &utxos[..2]);
+        let report = index
+            .verify_against_chainstate(&db, &chainstate, 10, 0)
+            .unwrap();
+        assert_eq!(
+            report.mismatches,
+            vec![BalanceMismatch {
+                script_pubkey: spend.output[0].script_pubkey.clone(),
+                chainstate: 50 * 100_000_000,
+                index: 50 * 100_000_000 + 1000,
+            }]
+        );
+
+        // the chainstate is not at the tip of the index
+        std::fs::remove_dir_all(datadir.join("chainstate")).unwrap();
+        write_chainstate(&datadir, &tips[1], &utxos[..1]);
+        assert!(index
+            .verify_against_chainstate(&db, &chainstate, 10, 0)
+            .is_err());
+        drop(index);
+    }
+}
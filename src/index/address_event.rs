@@ -0,0 +1,30 @@
+//!
+//! Entries of address histories, shared by `AddressIndex`
+//! and the in-memory `daemon::AddressHistoryIndex`.
+//!
+use crate::api::Txid;
+
+///
+/// Whether an entry of an address history receives or spends coins.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AddressEventKind {
+    /// output `index` of `txid` pays to the address
+    Received,
+    /// input `index` of `txid` spends an output of the address
+    Spent,
+}
+
+///
+/// An entry of an address history.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressEvent {
+    pub height: usize,
+    pub txid: Txid,
+    /// vout for `Received`, vin for `Spent`
+    pub index: u32,
+    pub kind: AddressEventKind,
+    /// satoshi received or spent
+    pub value: u64,
+}
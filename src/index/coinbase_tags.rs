@@ -0,0 +1,322 @@
+//!
+//! Searchable index of the ASCII text of coinbase scriptSigs
+//! (pool tags, messages), by block height.
+//!
+use crate::api::{BitcoinDB, BlockHash, Script};
+use crate::index::limits::LimitTracker;
+use crate::index::{
+    BuildMonitor, BuildOptions, BuildProgress, QueryLimits, QueryResults, ValueCodec,
+};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hashes::Hash;
+use bitcoin::Block;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+
+/// magic bytes of coinbase tag index files
+const TAGS_MAGIC: &[u8; 8] = b"CBTAGS01";
+/// magic bytes of coinbase tag index files with an encoded payload
+const COMPRESSED_TAGS_MAGIC: &[u8; 8] = b"CBTAGSZ1";
+/// shorter printable runs are mostly bytes of pushed numbers
+const MIN_RUN: usize = 3;
+
+///
+/// Printable ASCII runs of at least 3 characters of a coinbase scriptSig,
+/// separated by `|`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::coinbase_text;
+/// use bitcoin_explorer::Script;
+///
+/// let script_sig = Script::from(b"\x03\x1b\x4e\x0a/slush/\x00\x01mined by x".to_vec());
+/// assert_eq!(coinbase_text(&script_sig), "/slush/|mined by x");
+/// ```
+///
+pub fn coinbase_text(script_sig: &Script) -> String {
+    let mut runs: Vec<String> = Vec::new();
+    let mut run = String::new();
+    for &b in script_sig.as_bytes().iter().chain(std::iter::once(&0u8)) {
+        if (0x20..0x7f).contains(&b) {
+            run.push(b as char);
+        } else {
+            if run.len() >= MIN_RUN {
+                runs.push(run.clone());
+            }
+            run.clear();
+        }
+    }
+    runs.join("|")
+}
+
+///
+/// Index of coinbase texts (see `coinbase_text`) by block height,
+/// built in a single pass over the blocks and saved to a file.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::{BuildOptions, CoinbaseTagIndex};
+/// use bitcoin_explorer::BitcoinDB;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let index_path = Path::new("./coinbase_tags.bin");
+/// let mut index = CoinbaseTagIndex::load(index_path).unwrap_or_default();
+/// index.update(&db, &BuildOptions::default()).unwrap();
+/// index.save(index_path).unwrap();
+///
+/// let slush = index.find_blocks_by_coinbase_tag("slush");
+/// println!("{} blocks, first at {:?}", slush.len(), slush.first());
+/// ```
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoinbaseTagIndex {
+    /// blocks `0..indexed` are indexed
+    indexed: usize,
+    /// hash of block `indexed - 1`
+    tip: BlockHash,
+    /// coinbase text of blocks having some, sorted by height
+    texts: Vec<(u32, String)>,
+}
+
+impl CoinbaseTagIndex {
+    ///
+    /// Index all blocks of `db`.
+    ///
+    pub fn build(db: &BitcoinDB, options: &BuildOptions) -> OpResult<Self> {
+        let mut index = CoinbaseTagIndex::default();
+        index.update(db, options)?;
+        Ok(index)
+    }
+
+    /// number of blocks indexed
+    pub fn indexed_blocks(&self) -> usize {
+        self.indexed
+    }
+
+    ///
+    /// Index the blocks of `db` added since the last update.
+    ///
+    /// Fails if the last indexed block has been reorganized out
+    /// of the main chain (the index must then be rebuilt).
+    ///
+    pub fn update(&mut self, db: &BitcoinDB, options: &BuildOptions) -> OpResult<BuildProgress> {
+        if self.indexed > 0 && db.get_hash_from_height(self.indexed - 1).ok() != Some(self.tip) {
+            return Err(OpError::from(
+                "coinbase tag index tip is not in the main chain, rebuild the index",
+            ));
+        }
+        let start = self.indexed;
+        let end = db.get_block_count().max(start);
+        let mut monitor = BuildMonitor::new(end - start, options);
+        for (i, block) in db.iter_block::<Block>(start, end).enumerate() {
+            let text = coinbase_text(&block.txdata[0].input[0].script_sig);
+            if !text.is_empty() {
+                self.texts.push(((start + i) as u32, text));
+            }
+            self.indexed = start + i + 1;
+            self.tip = block.block_hash();
+            monitor.block_done(block.size() as u64);
+        }
+        Ok(monitor.finish())
+    }
+
+    ///
+    /// Heights of blocks whose coinbase text contains `tag`
+    /// (case insensitive), sorted.
+    ///
+    pub fn find_blocks_by_coinbase_tag(&self, tag: &str) -> Vec<usize> {
+        let tag = tag.to_ascii_lowercase();
+        self.texts
+            .iter()
+            .filter(|(_, text)| text.to_ascii_lowercase().contains(&tag))
+            .map(|(height, _)| *height as usize)
+            .collect()
+    }
+
+    ///
+    /// `find_blocks_by_coinbase_tag` under `limits`:
+    /// the lowest matching heights if a limit is reached.
+    ///
+    pub fn find_blocks_by_coinbase_tag_limited(
+        &self,
+        tag: &str,
+        limits: &QueryLimits,
+    ) -> QueryResults<usize> {
+        let tag = tag.to_ascii_lowercase();
+        let matches = self
+            .texts
+            .iter()
+            .filter(|(_, text)| text.to_ascii_lowercase().contains(&tag))
+            .map(|(height, _)| *height as usize);
+        LimitTracker::new(limits).collect(matches)
+    }
+
+    ///
+    /// Coinbase text of block `height`, `None` if not indexed or empty.
+    ///
+    pub fn coinbase_text(&self, height: usize) -> Option<&str> {
+        self.texts
+            .binary_search_by_key(&(height as u32), |(h, _)| *h)
+            .ok()
+            .map(|i| self.texts[i].1.as_str())
+    }
+
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(TAGS_MAGIC)?;
+        self.encode_payload(&mut out)?;
+        out.flush()?;
+        drop(out);
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    ///
+    /// `save`, with the payload (all but the magic bytes) encoded by `codec`.
+    ///
+    pub fn save_compressed(&self, path: &Path, codec: &ValueCodec) -> OpResult<()> {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload)?;
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(COMPRESSED_TAGS_MAGIC)?;
+        out.write_all(&codec.encode(&payload)?)?;
+        out.flush()?;
+        drop(out);
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    ///
+    /// Read an index written by `save`, or by `save_compressed`
+    /// without a zstd dictionary.
+    ///
+    pub fn load(path: &Path) -> OpResult<Self> {
+        CoinbaseTagIndex::load_with(path, &ValueCodec::default())
+    }
+
+    ///
+    /// Read an index written by `save` or `save_compressed`.
+    ///
+    /// `codec` must carry the dictionary used when saving, if any.
+    ///
+    pub fn load_with(path: &Path, codec: &ValueCodec) -> OpResult<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic == TAGS_MAGIC {
+            CoinbaseTagIndex::decode_payload(&mut reader)
+        } else if &magic == COMPRESSED_TAGS_MAGIC {
+            let mut encoded = Vec::new();
+            reader.read_to_end(&mut encoded)?;
+            CoinbaseTagIndex::decode_payload(&mut Cursor::new(codec.decode(&encoded)?))
+        } else {
+            Err(OpError::from("not a coinbase tag index"))
+        }
+    }
+
+    fn encode_payload<W: Write>(&self, out: &mut W) -> OpResult<()> {
+        out.write_u32::<LittleEndian>(self.indexed as u32)?;
+        out.write_all(&self.tip[..])?;
+        out.write_u32::<LittleEndian>(self.texts.len() as u32)?;
+        for (height, text) in self.texts.iter() {
+            out.write_u32::<LittleEndian>(*height)?;
+            out.write_u16::<LittleEndian>(text.len() as u16)?;
+            out.write_all(text.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn decode_payload<R: Read>(reader: &mut R) -> OpResult<Self> {
+        let indexed = reader.read_u32::<LittleEndian>()? as usize;
+        let mut tip = [0u8; 32];
+        reader.read_exact(&mut tip)?;
+        let len = reader.read_u32::<LittleEndian>()?;
+        let mut texts = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let height = reader.read_u32::<LittleEndian>()?;
+            let mut text = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+            reader.read_exact(&mut text)?;
+            texts.push((height, String::from_utf8(text)?));
+        }
+        Ok(CoinbaseTagIndex {
+            indexed,
+            tip: BlockHash::from_inner(tip),
+            texts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexCompression;
+    use crate::testutil::{SyntheticChain, TestDir};
+    use bitcoin::blockdata::script::Builder;
+
+    #[test]
+    fn test_coinbase_text() {
+        let script = Builder::new()
+            .push_int(700000)
+            .push_slice(b"/ViaBTC/Mined by abc/")
+            .push_slice(&[0xff, 0x41, 0x42, 0x00])
+            .into_script();
+        assert_eq!(coinbase_text(&script), "/ViaBTC/Mined by abc/");
+        assert_eq!(coinbase_text(&Script::new()), "");
+    }
+
+    #[test]
+    fn test_coinbase_tag_index() {
+        let dir = TestDir::new("coinbase_tags");
+        let datadir = dir.join("datadir");
+
+        // synthetic coinbases push only the height and a nonce
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        let mut index = CoinbaseTagIndex::build(&db, &BuildOptions::default()).unwrap();
+        assert_eq!(index.indexed_blocks(), 3);
+        assert!(index.texts.is_empty());
+
+        index.texts.push((1, "/slush/".to_string()));
+        index.texts.push((2, "Mined by SlushPool".to_string()));
+        assert_eq!(index.find_blocks_by_coinbase_tag("SLUSH"), vec![1, 2]);
+        assert_eq!(index.find_blocks_by_coinbase_tag("/slush/"), vec![1]);
+        let limits = QueryLimits {
+            max_results: Some(1),
+            ..Default::default()
+        };
+        let first = index.find_blocks_by_coinbase_tag_limited("SLUSH", &limits);
+        assert_eq!(first.items, vec![1]);
+        assert!(!first.is_complete());
+        assert_eq!(index.coinbase_text(2), Some("Mined by SlushPool"));
+        assert_eq!(index.coinbase_text(0), None);
+
+        let path = dir.join("tags.bin");
+        index.save(&path).unwrap();
+        assert_eq!(CoinbaseTagIndex::load(&path).unwrap(), index);
+        let codec = ValueCodec::new(IndexCompression::None);
+        index.save_compressed(&path, &codec).unwrap();
+        assert_eq!(CoinbaseTagIndex::load_with(&path, &codec).unwrap(), index);
+
+        chain.extend(&tips[1], 2);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        assert_eq!(index.update(&db, &BuildOptions::default()).unwrap().done, 2);
+        assert_eq!(index.indexed_blocks(), 5);
+        // a reorg of the indexed tip is detected
+        chain.extend(&tips[0], 6);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        assert!(index.update(&db, &BuildOptions::default()).is_err());
+    }
+}
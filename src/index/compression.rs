@@ -0,0 +1,212 @@
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::{Decodable, Encodable};
+use std::io::Cursor;
+
+/// value stored as is
+const TAG_RAW: u8 = 0;
+/// value compressed by zstd
+#[cfg(feature = "compression")]
+const TAG_ZSTD: u8 = 1;
+/// keys between two full (uncompressed) keys in `encode_sorted_keys`
+const RESTART_INTERVAL: usize = 16;
+
+///
+/// Compression of index values, a size / speed trade-off.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexCompression {
+    /// no compression, fastest
+    #[default]
+    None,
+    ///
+    /// zstd at `level` (1 fastest ..= 22 smallest, 3 is a good default).
+    ///
+    #[cfg(feature = "compression")]
+    Zstd { level: i32 },
+}
+
+///
+/// Encoder / decoder of index values.
+///
+/// Every encoded value starts with a tag byte, so values written with
+/// different compression settings can be read by any `ValueCodec`
+/// (with the same dictionary).
+///
+#[derive(Debug, Clone, Default)]
+pub struct ValueCodec {
+    compression: IndexCompression,
+    dictionary: Option<Vec<u8>>,
+}
+
+impl ValueCodec {
+    pub fn new(compression: IndexCompression) -> Self {
+        ValueCodec {
+            compression,
+            dictionary: None,
+        }
+    }
+
+    ///
+    /// Use a zstd dictionary (see `train_dictionary`),
+    /// which greatly improves compression of small values.
+    ///
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    pub fn compression(&self) -> IndexCompression {
+        self.compression
+    }
+
+    pub fn encode(&self, value: &[u8]) -> OpResult<Vec<u8>> {
+        match self.compression {
+            IndexCompression::None => {
+                let mut out = Vec::with_capacity(value.len() + 1);
+                out.push(TAG_RAW);
+                out.extend_from_slice(value);
+                Ok(out)
+            }
+            #[cfg(feature = "compression")]
+            IndexCompression::Zstd { level } => {
+                let mut compressor = match &self.dictionary {
+                    Some(dict) => zstd::bulk::Compressor::with_dictionary(level, dict)?,
+                    None => zstd::bulk::Compressor::new(level)?,
+                };
+                let compressed = compressor.compress(value)?;
+                let mut out = Vec::with_capacity(compressed.len() + 10);
+                out.push(TAG_ZSTD);
+                VarInt(value.len() as u64).consensus_encode(&mut out)?;
+                out.extend(compressed);
+                Ok(out)
+            }
+        }
+    }
+
+    pub fn decode(&self, encoded: &[u8]) -> OpResult<Vec<u8>> {
+        match encoded.first() {
+            Some(&TAG_RAW) => Ok(encoded[1..].to_vec()),
+            #[cfg(feature = "compression")]
+            Some(&TAG_ZSTD) => {
+                let mut r = Cursor::new(&encoded[1..]);
+                let len = VarInt::consensus_decode(&mut r)?.0 as usize;
+                let compressed = &encoded[1 + r.position() as usize..];
+                let mut decompressor = match &self.dictionary {
+                    Some(dict) => zstd::bulk::Decompressor::with_dictionary(dict)?,
+                    None => zstd::bulk::Decompressor::new()?,
+                };
+                Ok(decompressor.decompress(compressed, len)?)
+            }
+            _ => Err(OpError::from("unknown index value encoding")),
+        }
+    }
+}
+
+///
+/// Train a zstd dictionary of at most `max_size` bytes from sample values.
+///
+#[cfg(feature = "compression")]
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> OpResult<Vec<u8>> {
+    Ok(zstd::dict::from_samples(samples, max_size)?)
+}
+
+///
+/// Prefix-compress sorted keys (front coding).
+///
+/// Each key is stored as the length of the prefix shared with the
+/// previous key, followed by the remaining suffix.
+/// Every 16th key is stored in full.
+///
+pub fn encode_sorted_keys(keys: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    VarInt(keys.len() as u64)
+        .consensus_encode(&mut out)
+        .unwrap();
+    let mut prev: &[u8] = &[];
+    for (i, key) in keys.iter().enumerate() {
+        let shared = if i % RESTART_INTERVAL == 0 {
+            0
+        } else {
+            prev.iter()
+                .zip(key.iter())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        VarInt(shared as u64).consensus_encode(&mut out).unwrap();
+        VarInt((key.len() - shared) as u64)
+            .consensus_encode(&mut out)
+            .unwrap();
+        out.extend_from_slice(&key[shared..]);
+        prev = key;
+    }
+    out
+}
+
+///
+/// Decode keys written by `encode_sorted_keys`.
+///
+pub fn decode_sorted_keys(encoded: &[u8]) -> OpResult<Vec<Vec<u8>>> {
+    let mut r = Cursor::new(encoded);
+    let count = VarInt::consensus_decode(&mut r)?.0 as usize;
+    let mut keys: Vec<Vec<u8>> = Vec::with_capacity(count.min(encoded.len()));
+    for _ in 0..count {
+        let shared = VarInt::consensus_decode(&mut r)?.0 as usize;
+        let suffix_len = VarInt::consensus_decode(&mut r)?.0 as usize;
+        let start = r.position() as usize;
+        let end = start
+            .checked_add(suffix_len)
+            .filter(|end| *end <= encoded.len())
+            .ok_or_else(|| OpError::from("truncated keys"))?;
+        let mut key = match keys.last() {
+            Some(prev) if shared <= prev.len() => prev[..shared].to_vec(),
+            None if shared == 0 => Vec::new(),
+            _ => return Err(OpError::from("corrupted keys")),
+        };
+        key.extend_from_slice(&encoded[start..end]);
+        r.set_position(end as u64);
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_sorted_keys, encode_sorted_keys, IndexCompression, ValueCodec};
+
+    #[test]
+    fn test_sorted_keys_roundtrip() {
+        let mut keys: Vec<Vec<u8>> = (0u32..100)
+            .map(|i| {
+                let mut k = b"address:1A1zP1".to_vec();
+                k.extend_from_slice(&i.to_be_bytes());
+                k
+            })
+            .collect();
+        keys.sort();
+        let encoded = encode_sorted_keys(&keys);
+        assert!(encoded.len() < keys.iter().map(|k| k.len()).sum::<usize>());
+        assert_eq!(decode_sorted_keys(&encoded).unwrap(), keys);
+    }
+
+    #[test]
+    fn test_value_codec_raw() {
+        let codec = ValueCodec::new(IndexCompression::None);
+        let encoded = codec.encode(b"value").unwrap();
+        assert_eq!(codec.decode(&encoded).unwrap(), b"value");
+        assert!(codec.decode(&[0xff]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_value_codec_zstd() {
+        let codec = ValueCodec::new(IndexCompression::Zstd { level: 3 });
+        let value = vec![7u8; 1000];
+        let encoded = codec.encode(&value).unwrap();
+        assert!(encoded.len() < 100);
+        assert_eq!(codec.decode(&encoded).unwrap(), value);
+        // readable by an uncompressed codec configuration too
+        let raw = ValueCodec::new(IndexCompression::None);
+        assert_eq!(raw.decode(&encoded).unwrap(), value);
+    }
+}
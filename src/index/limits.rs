@@ -0,0 +1,203 @@
+//!
+//! Per-call limits of index queries.
+//!
+//! Some queries have pathological inputs (e.g. the history of an exchange
+//! address with millions of transactions). `QueryLimits` bounds the
+//! number of results, the time and the memory of one call: the query
+//! stops early and reports which limit was reached.
+//!
+use crate::parser::errors::OpError;
+use std::convert::Infallible;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+///
+/// Limits of one query call, unlimited by default.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::QueryLimits;
+/// use std::time::Duration;
+///
+/// let limits = QueryLimits {
+///     max_results: Some(10000),
+///     max_bytes: Some(64 << 20),
+///     ..Default::default()
+/// }
+/// .with_timeout(Duration::from_secs(2));
+/// ```
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// maximum number of results (or blocks read, for lookups)
+    pub max_results: Option<usize>,
+    /// stop at this instant
+    pub deadline: Option<Instant>,
+    /// maximum size of results in memory (or of blocks read, for lookups)
+    pub max_bytes: Option<usize>,
+}
+
+impl QueryLimits {
+    /// set the deadline `timeout` from now
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+}
+
+///
+/// The limit that stopped a query.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitReached {
+    MaxResults,
+    Deadline,
+    MaxBytes,
+}
+
+impl fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitReached::MaxResults => write!(f, "max_results"),
+            LimitReached::Deadline => write!(f, "deadline"),
+            LimitReached::MaxBytes => write!(f, "max_bytes"),
+        }
+    }
+}
+
+impl From<LimitReached> for OpError {
+    fn from(limit: LimitReached) -> Self {
+        OpError::from(format!("query limit reached: {}", limit).as_str())
+    }
+}
+
+///
+/// Results of a query under `QueryLimits`, in the order of the full query.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryResults<T> {
+    pub items: Vec<T>,
+    /// `Some` if the query stopped early, `items` is then a prefix
+    pub limit_reached: Option<LimitReached>,
+}
+
+impl<T> QueryResults<T> {
+    /// `true` if all results were returned
+    pub fn is_complete(&self) -> bool {
+        self.limit_reached.is_none()
+    }
+}
+
+///
+/// Counts results of one call against its limits.
+///
+pub(crate) struct LimitTracker {
+    limits: QueryLimits,
+    results: usize,
+    bytes: usize,
+}
+
+impl LimitTracker {
+    pub(crate) fn new(limits: &QueryLimits) -> Self {
+        LimitTracker {
+            limits: *limits,
+            results: 0,
+            bytes: 0,
+        }
+    }
+
+    ///
+    /// Count one more result of `bytes`,
+    /// or return the limit it would exceed.
+    ///
+    pub(crate) fn admit(&mut self, bytes: usize) -> Result<(), LimitReached> {
+        if matches!(self.limits.max_results, Some(max) if self.results >= max) {
+            return Err(LimitReached::MaxResults);
+        }
+        if matches!(self.limits.max_bytes, Some(max) if self.bytes + bytes > max) {
+            return Err(LimitReached::MaxBytes);
+        }
+        if matches!(self.limits.deadline, Some(deadline) if Instant::now() >= deadline) {
+            return Err(LimitReached::Deadline);
+        }
+        self.results += 1;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    ///
+    /// Collect `items` (each of `size_of::<T>()` bytes) until a limit is reached.
+    ///
+    pub(crate) fn collect<T, I>(&mut self, items: I) -> QueryResults<T>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        match self.try_collect(items.into_iter().map(Ok::<T, Infallible>)) {
+            Ok(results) => results,
+            Err(never) => match never {},
+        }
+    }
+
+    ///
+    /// `collect` of fallible items, stopping at the first error.
+    ///
+    pub(crate) fn try_collect<T, E, I>(&mut self, items: I) -> Result<QueryResults<T>, E>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+    {
+        let mut results = QueryResults {
+            items: Vec::new(),
+            limit_reached: None,
+        };
+        for item in items {
+            let item = item?;
+            if let Err(limit) = self.admit(std::mem::size_of::<T>()) {
+                results.limit_reached = Some(limit);
+                break;
+            }
+            results.items.push(item);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_tracker() {
+        let items = 0..100u64;
+        let all = LimitTracker::new(&QueryLimits::default()).collect(items.clone());
+        assert!(all.is_complete());
+        assert_eq!(all.items.len(), 100);
+
+        let limits = QueryLimits {
+            max_results: Some(10),
+            ..Default::default()
+        };
+        let first = LimitTracker::new(&limits).collect(items.clone());
+        assert_eq!(first.items, (0..10).collect::<Vec<u64>>());
+        assert_eq!(first.limit_reached, Some(LimitReached::MaxResults));
+
+        let limits = QueryLimits {
+            max_bytes: Some(8 * 5 + 7),
+            ..Default::default()
+        };
+        let first = LimitTracker::new(&limits).collect(items.clone());
+        assert_eq!(first.items.len(), 5);
+        assert_eq!(first.limit_reached, Some(LimitReached::MaxBytes));
+
+        let limits = QueryLimits::default().with_timeout(Duration::from_secs(0));
+        let none = LimitTracker::new(&limits).collect(items);
+        assert!(none.items.is_empty());
+        assert_eq!(none.limit_reached, Some(LimitReached::Deadline));
+
+        let failing = vec![Ok(1), Err("bad"), Ok(2)];
+        assert_eq!(
+            LimitTracker::new(&QueryLimits::default()).try_collect(failing),
+            Err("bad")
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//!
+//! Storage primitives for indexes built by this crate.
+//!
+//! Index values can be compressed with zstd (optionally with a trained
+//! dictionary, feature `compression`), and sorted keys can be stored with
+//! prefix compression (front coding). The indexes below take a `ValueCodec`
+//! (`open_with_codec`, `save_compressed`).
+//!
+//! Long builds report progress through `BuildOptions` (a tree of stages
+//! with their ETAs for multi-stage jobs, see `StageMonitor`), which also
+//! carries an I/O throttle for running next to a live node.
+//!
+//! `AddressIndex` (feature `on-disk-utxo`) maps script public keys
+//! to their transaction history, updated as new blocks appear.
+//! `TxBloomIndex` narrows transaction lookups without `txindex`
+//! to a few blk files. `CoinbaseTagIndex` finds blocks by the text
+//! of their coinbase (e.g. pool tags). `TxIndex` (feature `on-disk-utxo`)
+//! replaces `txindex` for txid lookups once attached to `BitcoinDB`.
+//! `WtxidIndex` (feature `on-disk-utxo`) finds transactions by wtxid.
+//!
+//! Queries of these indexes have variants honoring `QueryLimits`
+//! (number of results, deadline, memory), for services exposed
+//! to arbitrary inputs.
+//!
+#[cfg(feature = "on-disk-utxo")]
+mod address;
+mod address_event;
+mod coinbase_tags;
+mod compression;
+mod limits;
+mod progress;
+mod tx_bloom;
+#[cfg(feature = "on-disk-utxo")]
+mod txid_index;
+#[cfg(feature = "on-disk-utxo")]
+mod wtxid_index;
+
+#[cfg(feature = "on-disk-utxo")]
+pub use address::{AddressIndex, BalanceMismatch, ChainStateReport};
+pub use address_event::{AddressEvent, AddressEventKind};
+pub use coinbase_tags::{coinbase_text, CoinbaseTagIndex};
+#[cfg(feature = "compression")]
+pub use compression::train_dictionary;
+pub use compression::{decode_sorted_keys, encode_sorted_keys, IndexCompression, ValueCodec};
+pub use limits::{LimitReached, QueryLimits, QueryResults};
+pub use progress::{
+    BuildMonitor, BuildOptions, BuildProgress, ProgressCallback, StageCallback, StageMonitor,
+    StageProgress, StageState,
+};
+pub use tx_bloom::TxBloomIndex;
+#[cfg(feature = "on-disk-utxo")]
+pub use txid_index::TxIndex;
+#[cfg(feature = "on-disk-utxo")]
+pub use wtxid_index::{WtxPosition, WtxidIndex};
@@ -0,0 +1,662 @@
+use crate::parser::errors::{OpError, OpResult};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+///
+/// Progress of an index build.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildProgress {
+    /// blocks processed so far
+    pub done: usize,
+    /// blocks to process in total
+    pub total: usize,
+    /// bytes of block data processed so far
+    pub bytes: u64,
+    /// time since the build started
+    pub elapsed: Duration,
+}
+
+impl BuildProgress {
+    ///
+    /// Fraction completed, between 0 and 1.
+    ///
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.done as f64 / self.total as f64
+        }
+    }
+
+    ///
+    /// Estimated remaining time, extrapolated from the average speed so far.
+    ///
+    /// `None` before any block is processed.
+    ///
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done == 0 {
+            return None;
+        }
+        let remaining = self.total.saturating_sub(self.done) as f64;
+        Some(self.elapsed.mul_f64(remaining / self.done as f64))
+    }
+}
+
+impl fmt::Display for BuildProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} blocks ({:.1}%)",
+            self.done,
+            self.total,
+            self.fraction() * 100.0
+        )?;
+        if let Some(eta) = self.eta() {
+            write!(f, ", eta {}s", eta.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// Progress callback of index builds.
+pub type ProgressCallback = Arc<dyn Fn(&BuildProgress) + Send + Sync>;
+
+/// Progress callback of multi-stage jobs, called with the root of the tree.
+pub type StageCallback = Arc<dyn Fn(&StageProgress) + Send + Sync>;
+
+///
+/// State of a stage of a multi-stage job.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageState {
+    Pending,
+    Running,
+    Done,
+}
+
+///
+/// Progress of a stage of a multi-stage job (e.g. scan, sort, write),
+/// and of its sub-stages.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageProgress {
+    pub name: String,
+    /// expected duration, relative to the sibling stages
+    pub weight: f64,
+    pub state: StageState,
+    /// units (blocks, partitions, keys...) processed so far
+    pub done: usize,
+    /// units to process, 0 if unknown
+    pub total: usize,
+    /// time since the stage started
+    pub elapsed: Duration,
+    /// sub-stages, in execution order
+    pub stages: Vec<StageProgress>,
+}
+
+impl StageProgress {
+    pub fn new(name: &str, weight: f64) -> Self {
+        StageProgress {
+            name: name.to_string(),
+            weight,
+            state: StageState::Pending,
+            done: 0,
+            total: 0,
+            elapsed: Duration::default(),
+            stages: Vec::new(),
+        }
+    }
+
+    ///
+    /// Fraction completed, between 0 and 1.
+    ///
+    /// Stages with sub-stages average the fractions of their
+    /// sub-stages, weighted by `weight`.
+    ///
+    pub fn fraction(&self) -> f64 {
+        match self.state {
+            StageState::Pending => 0.0,
+            StageState::Done => 1.0,
+            StageState::Running if self.stages.is_empty() => {
+                if self.total == 0 {
+                    0.0
+                } else {
+                    (self.done as f64 / self.total as f64).min(1.0)
+                }
+            }
+            StageState::Running => {
+                let weight: f64 = self.stages.iter().map(|s| s.weight).sum();
+                if weight <= 0.0 {
+                    return 0.0;
+                }
+                let done: f64 = self.stages.iter().map(|s| s.weight * s.fraction()).sum();
+                done / weight
+            }
+        }
+    }
+
+    ///
+    /// Estimated remaining time of the stage,
+    /// extrapolated from its fraction completed and elapsed time.
+    ///
+    /// `None` for pending stages and before any progress.
+    ///
+    pub fn eta(&self) -> Option<Duration> {
+        match self.state {
+            StageState::Pending => None,
+            StageState::Done => Some(Duration::default()),
+            StageState::Running => {
+                let fraction = self.fraction();
+                if fraction <= 0.0 {
+                    None
+                } else {
+                    Some(self.elapsed.mul_f64((1.0 - fraction) / fraction))
+                }
+            }
+        }
+    }
+
+    ///
+    /// Sub-stage at `path` of names, e.g. `&["transactions", "write"]`.
+    ///
+    pub fn find(&self, path: &[&str]) -> Option<&StageProgress> {
+        match path.split_first() {
+            None => Some(self),
+            Some((name, rest)) => self
+                .stages
+                .iter()
+                .find(|s| s.name == *name)
+                .and_then(|s| s.find(rest)),
+        }
+    }
+
+    fn write_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(
+            f,
+            "{:indent$}{}: {:.1}%",
+            "",
+            self.name,
+            self.fraction() * 100.0,
+            indent = 2 * depth
+        )?;
+        if self.stages.is_empty() && self.total > 0 {
+            write!(f, " ({}/{})", self.done, self.total)?;
+        }
+        match (self.state, self.eta()) {
+            (StageState::Pending, _) => write!(f, ", pending")?,
+            (StageState::Running, Some(eta)) => write!(f, ", eta {}s", eta.as_secs())?,
+            _ => {}
+        }
+        for stage in &self.stages {
+            writeln!(f)?;
+            stage.write_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for StageProgress {
+    ///
+    /// One line per stage, sub-stages indented.
+    ///
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_tree(f, 0)
+    }
+}
+
+///
+/// Options of index builds.
+///
+/// Block reading is pipelined, so throttling the build
+/// also throttles reads from the `blocks` directory.
+///
+#[derive(Clone)]
+pub struct BuildOptions {
+    /// called every `progress_interval` blocks, and once at the end
+    pub progress: Option<ProgressCallback>,
+    /// progress tree of multi-stage jobs (see `StageMonitor`), called when
+    /// a stage starts or ends, and every `progress_interval` units of a stage
+    pub stage_progress: Option<StageCallback>,
+    pub progress_interval: usize,
+    /// limit of block data processed per second (bytes)
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            progress: None,
+            stage_progress: None,
+            progress_interval: 1000,
+            max_bytes_per_sec: None,
+        }
+    }
+}
+
+impl fmt::Debug for BuildOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BuildOptions")
+            .field("progress", &self.progress.is_some())
+            .field("stage_progress", &self.stage_progress.is_some())
+            .field("progress_interval", &self.progress_interval)
+            .field("max_bytes_per_sec", &self.max_bytes_per_sec)
+            .finish()
+    }
+}
+
+impl BuildOptions {
+    pub fn with_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&BuildProgress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_stage_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&StageProgress) + Send + Sync + 'static,
+    {
+        self.stage_progress = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_io_limit(mut self, max_bytes_per_sec: u64) -> Self {
+        self.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+}
+
+///
+/// Tracks progress of a build, reports it, and applies the I/O throttle.
+///
+pub struct BuildMonitor<'a> {
+    options: &'a BuildOptions,
+    start: Instant,
+    progress: BuildProgress,
+    reported: Option<usize>,
+}
+
+impl<'a> BuildMonitor<'a> {
+    pub fn new(total: usize, options: &'a BuildOptions) -> Self {
+        BuildMonitor {
+            options,
+            start: Instant::now(),
+            progress: BuildProgress {
+                done: 0,
+                total,
+                bytes: 0,
+                elapsed: Duration::default(),
+            },
+            reported: None,
+        }
+    }
+
+    ///
+    /// Record one processed block of `bytes` bytes.
+    ///
+    /// Sleeps if the build is faster than `max_bytes_per_sec`.
+    ///
+    pub fn block_done(&mut self, bytes: u64) {
+        self.progress.done += 1;
+        self.progress.bytes += bytes;
+        if let Some(limit) = self.options.max_bytes_per_sec.filter(|l| *l > 0) {
+            let target = Duration::from_secs_f64(self.progress.bytes as f64 / limit as f64);
+            let elapsed = self.start.elapsed();
+            if target > elapsed {
+                thread::sleep(target - elapsed);
+            }
+        }
+        if self.progress.done % self.options.progress_interval.max(1) == 0 {
+            self.report();
+        }
+    }
+
+    ///
+    /// Report the final progress, unless the last periodic report
+    /// already covered it.
+    ///
+    pub fn finish(mut self) -> BuildProgress {
+        if self.reported != Some(self.progress.done) {
+            self.report();
+        }
+        self.progress
+    }
+
+    pub fn progress(&self) -> BuildProgress {
+        BuildProgress {
+            elapsed: self.start.elapsed(),
+            ..self.progress
+        }
+    }
+
+    fn report(&mut self) {
+        self.progress.elapsed = self.start.elapsed();
+        self.reported = Some(self.progress.done);
+        if let Some(callback) = &self.options.progress {
+            callback(&self.progress);
+        }
+    }
+}
+
+///
+/// Tracks the progress tree of a multi-stage job and reports it
+/// to `BuildOptions::stage_progress`.
+///
+/// Stages are declared with their relative weights, then started,
+/// advanced and finished by their path of names. Starting a stage
+/// also starts its parent stages.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::{BuildOptions, StageMonitor};
+///
+/// let options = BuildOptions::default().with_stage_progress(|tree| println!("{}", tree));
+/// let mut monitor = StageMonitor::new("export", &options);
+/// monitor.add_stages(&[], &[("scan", 8.0), ("sort", 1.0), ("write", 2.0)]).unwrap();
+/// monitor.start_stage(&["scan"], 700000).unwrap();
+/// for _ in 0..700000 {
+///     monitor.advance(&["scan"], 1).unwrap();
+/// }
+/// monitor.finish_stage(&["scan"]).unwrap();
+/// // sort, write...
+/// let tree = monitor.finish();
+/// ```
+///
+pub struct StageMonitor<'a> {
+    options: &'a BuildOptions,
+    root: StageProgress,
+    /// start of the started stages, by path of indexes
+    started: HashMap<Vec<usize>, Instant>,
+}
+
+impl<'a> StageMonitor<'a> {
+    ///
+    /// Start job `name`, without stages.
+    ///
+    pub fn new(name: &str, options: &'a BuildOptions) -> Self {
+        let mut root = StageProgress::new(name, 1.0);
+        root.state = StageState::Running;
+        let mut started = HashMap::new();
+        started.insert(Vec::new(), Instant::now());
+        StageMonitor {
+            options,
+            root,
+            started,
+        }
+    }
+
+    ///
+    /// Declare the sub-stages of `parent` (`&[]` for the job),
+    /// in execution order, with their relative weights.
+    ///
+    pub fn add_stages(&mut self, parent: &[&str], stages: &[(&str, f64)]) -> OpResult<()> {
+        let index = self.resolve(parent)?;
+        let node = self.node_mut(&index);
+        for (name, weight) in stages {
+            node.stages.push(StageProgress::new(name, *weight));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Start stage `path` of `total` units (0 if unknown).
+    ///
+    pub fn start_stage(&mut self, path: &[&str], total: usize) -> OpResult<()> {
+        let index = self.resolve(path)?;
+        let now = Instant::now();
+        for depth in 0..=index.len() {
+            let node = self.node_mut(&index[..depth]);
+            if node.state == StageState::Pending {
+                node.state = StageState::Running;
+            }
+            self.started.entry(index[..depth].to_vec()).or_insert(now);
+        }
+        let node = self.node_mut(&index);
+        node.state = StageState::Running;
+        node.done = 0;
+        node.total = total;
+        self.started.insert(index, now);
+        self.report();
+        Ok(())
+    }
+
+    ///
+    /// Record `units` processed units of stage `path`.
+    ///
+    pub fn advance(&mut self, path: &[&str], units: usize) -> OpResult<()> {
+        let index = self.resolve(path)?;
+        let interval = self.options.progress_interval.max(1);
+        let node = self.node_mut(&index);
+        let before = node.done / interval;
+        node.done += units;
+        if node.done / interval != before {
+            self.report();
+        }
+        Ok(())
+    }
+
+    ///
+    /// End stage `path`.
+    ///
+    pub fn finish_stage(&mut self, path: &[&str]) -> OpResult<()> {
+        let index = self.resolve(path)?;
+        let elapsed = self
+            .started
+            .get(&index)
+            .map(|start| start.elapsed())
+            .unwrap_or_default();
+        let node = self.node_mut(&index);
+        node.state = StageState::Done;
+        node.done = node.done.max(node.total);
+        node.elapsed = elapsed;
+        self.report();
+        Ok(())
+    }
+
+    ///
+    /// End the job and report the final tree.
+    ///
+    pub fn finish(mut self) -> StageProgress {
+        self.root.elapsed = self.started[&Vec::<usize>::new()].elapsed();
+        self.root.state = StageState::Done;
+        self.report();
+        self.root
+    }
+
+    ///
+    /// The progress tree so far.
+    ///
+    pub fn progress(&self) -> StageProgress {
+        let mut root = self.root.clone();
+        let mut index = Vec::new();
+        refresh(&mut root, &mut index, &self.started);
+        root
+    }
+
+    fn report(&mut self) {
+        let mut index = Vec::new();
+        refresh(&mut self.root, &mut index, &self.started);
+        if let Some(callback) = &self.options.stage_progress {
+            callback(&self.root);
+        }
+    }
+
+    /// indexes of the stages of `path`
+    fn resolve(&self, path: &[&str]) -> OpResult<Vec<usize>> {
+        let mut node = &self.root;
+        let mut index = Vec::with_capacity(path.len());
+        for name in path {
+            match node.stages.iter().position(|s| s.name == *name) {
+                Some(i) => {
+                    index.push(i);
+                    node = &node.stages[i];
+                }
+                None => {
+                    return Err(OpError::from(
+                        format!("unknown stage: {}", path.join("/")).as_str(),
+                    ))
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    fn node_mut(&mut self, index: &[usize]) -> &mut StageProgress {
+        index
+            .iter()
+            .fold(&mut self.root, |node, i| &mut node.stages[*i])
+    }
+}
+
+/// update the elapsed time of the running stages
+fn refresh(
+    node: &mut StageProgress,
+    index: &mut Vec<usize>,
+    started: &HashMap<Vec<usize>, Instant>,
+) {
+    if node.state == StageState::Running {
+        if let Some(start) = started.get(&*index) {
+            node.elapsed = start.elapsed();
+        }
+    }
+    for (i, stage) in node.stages.iter_mut().enumerate() {
+        index.push(i);
+        refresh(stage, index, started);
+        index.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_eta() {
+        let p = BuildProgress {
+            done: 25,
+            total: 100,
+            bytes: 0,
+            elapsed: Duration::from_secs(10),
+        };
+        assert_eq!(p.eta(), Some(Duration::from_secs(30)));
+        assert_eq!(p.fraction(), 0.25);
+    }
+
+    #[test]
+    fn test_monitor_reports_and_throttles() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let mut options = BuildOptions::default()
+            .with_progress(move |_| {
+                c.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_io_limit(100_000);
+        options.progress_interval = 2;
+        let mut monitor = BuildMonitor::new(4, &options);
+        for _ in 0..4 {
+            monitor.block_done(5_000);
+        }
+        let p = monitor.finish();
+        // the report at block 4 is the final one
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(p.done, 4);
+        // 20kB at 100kB/s
+        assert!(p.elapsed >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_monitor_final_report() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let mut options = BuildOptions::default().with_progress(move |_| {
+            c.fetch_add(1, Ordering::SeqCst);
+        });
+        options.progress_interval = 2;
+        let mut monitor = BuildMonitor::new(3, &options);
+        for _ in 0..3 {
+            monitor.block_done(1);
+        }
+        monitor.finish();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // an empty build still reports once
+        BuildMonitor::new(0, &options).finish();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_stage_progress() {
+        let mut root = StageProgress::new("job", 1.0);
+        root.state = StageState::Running;
+        root.elapsed = Duration::from_secs(30);
+        let mut scan = StageProgress::new("scan", 3.0);
+        scan.state = StageState::Done;
+        let mut write = StageProgress::new("write", 1.0);
+        write.state = StageState::Running;
+        write.done = 1;
+        write.total = 4;
+        write.elapsed = Duration::from_secs(2);
+        root.stages = vec![scan, write, StageProgress::new("compact", 1.0)];
+        // (3 + 0.25) / 5
+        assert!((root.fraction() - 0.65).abs() < 1e-9);
+        assert_eq!(
+            root.find(&["write"]).unwrap().eta(),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(root.find(&["compact"]).unwrap().eta(), None);
+        assert!(root.find(&["sort"]).is_none());
+        assert_eq!(
+            root.to_string(),
+            "job: 65.0%, eta 16s\n  scan: 100.0%\n  write: 25.0% (1/4), eta 6s\n  compact: 0.0%, pending"
+        );
+    }
+
+    #[test]
+    fn test_stage_monitor() {
+        let trees = Arc::new(Mutex::new(Vec::new()));
+        let t = trees.clone();
+        let mut options = BuildOptions::default().with_stage_progress(move |tree| {
+            t.lock().unwrap().push(tree.clone());
+        });
+        options.progress_interval = 2;
+        let mut monitor = StageMonitor::new("job", &options);
+        monitor
+            .add_stages(&[], &[("scan", 1.0), ("write", 1.0)])
+            .unwrap();
+        monitor
+            .add_stages(&["write"], &[("sort", 1.0), ("flush", 1.0)])
+            .unwrap();
+        assert!(monitor.start_stage(&["compact"], 1).is_err());
+
+        monitor.start_stage(&["scan"], 4).unwrap();
+        for _ in 0..4 {
+            monitor.advance(&["scan"], 1).unwrap();
+        }
+        monitor.finish_stage(&["scan"]).unwrap();
+        assert_eq!(monitor.progress().fraction(), 0.5);
+        // starts the parent stage
+        monitor.start_stage(&["write", "sort"], 0).unwrap();
+        assert_eq!(
+            monitor.progress().find(&["write"]).unwrap().state,
+            StageState::Running
+        );
+        monitor.finish_stage(&["write", "sort"]).unwrap();
+        assert_eq!(monitor.progress().fraction(), 0.75);
+        let tree = monitor.finish();
+        assert_eq!(tree.state, StageState::Done);
+        assert_eq!(tree.find(&["scan"]).unwrap().done, 4);
+
+        // start, 2 advances, end of scan, start and end of sort, end
+        let trees = trees.lock().unwrap();
+        assert_eq!(trees.len(), 7);
+        assert_eq!(trees[2].find(&["scan"]).unwrap().done, 4);
+        assert_eq!(trees[6], tree);
+    }
+}
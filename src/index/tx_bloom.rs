@@ -0,0 +1,388 @@
+//!
+//! Bloom filters of the txids of each blk file, to find transactions
+//! without Bitcoin Core's `txindex` by reading a few files only.
+//!
+use crate::api::{BitcoinDB, BlockHash, Transaction, Txid};
+use crate::index::limits::LimitTracker;
+use crate::index::{BuildMonitor, BuildOptions, BuildProgress, QueryLimits};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hashes::Hash;
+use bitcoin::Block;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// magic bytes of bloom index files
+const BLOOM_MAGIC: &[u8; 8] = b"TXBLOOM1";
+/// default false positive rate of each filter
+const DEFAULT_FP_RATE: f64 = 0.01;
+
+///
+/// Bloom filter of txids.
+///
+/// Txids are uniformly distributed, so the bit positions are derived
+/// from the txid itself (double hashing), without hashing again.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TxidFilter {
+    bits: Vec<u64>,
+    k: u32,
+}
+
+impl TxidFilter {
+    fn new(n: usize, fp_rate: f64) -> Self {
+        let n = n.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let m = (-n * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let k = ((m / n) * ln2).round().clamp(1.0, 30.0) as u32;
+        TxidFilter {
+            bits: vec![0; (m as usize + 63) / 64],
+            k,
+        }
+    }
+
+    fn positions(&self, txid: &Txid) -> impl Iterator<Item = usize> {
+        let bytes = txid.as_inner();
+        let h1 = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(bytes[8..16].try_into().unwrap()) | 1;
+        let m = self.bits.len() as u64 * 64;
+        (0..self.k as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    fn insert(&mut self, txid: &Txid) {
+        for p in self.positions(txid).collect::<Vec<_>>() {
+            self.bits[p / 64] |= 1 << (p % 64);
+        }
+    }
+
+    fn contains(&self, txid: &Txid) -> bool {
+        self.positions(txid)
+            .all(|p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+    }
+}
+
+/// filter of a blk file, with the main chain blocks it contains
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileFilter {
+    /// heights of the blocks of the file, sorted
+    heights: Vec<usize>,
+    /// hash of the highest block, to detect reorganized blocks
+    last_hash: BlockHash,
+    filter: TxidFilter,
+}
+
+///
+/// Bloom filters of the txids of each blk file.
+///
+/// With 1% false positives, the index takes about 1.2 bytes per
+/// transaction, and a lookup reads the file of the transaction
+/// plus on average 1% of the other files.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::{BuildOptions, TxBloomIndex};
+/// use bitcoin_explorer::{BitcoinDB, FromHex, Transaction, Txid};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let index_path = Path::new("./tx_bloom.bin");
+/// // no txindex needed
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let mut index = TxBloomIndex::load(index_path).unwrap_or_default();
+/// index.update(&db, &BuildOptions::default()).unwrap();
+/// index.save(index_path).unwrap();
+///
+/// let txid = Txid::from_hex("e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468").unwrap();
+/// let (height, tx): (usize, Transaction) = db.get_transaction_with_bloom(&index, &txid).unwrap();
+/// ```
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxBloomIndex {
+    fp_rate: f64,
+    files: BTreeMap<i32, FileFilter>,
+}
+
+impl Default for TxBloomIndex {
+    fn default() -> Self {
+        TxBloomIndex::new(DEFAULT_FP_RATE)
+    }
+}
+
+impl TxBloomIndex {
+    ///
+    /// An empty index, whose filters have a false positive rate of `fp_rate`.
+    ///
+    pub fn new(fp_rate: f64) -> Self {
+        TxBloomIndex {
+            fp_rate: fp_rate.clamp(1e-9, 0.5),
+            files: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// Build the filters of all blk files of `db`.
+    ///
+    pub fn build(db: &BitcoinDB, options: &BuildOptions) -> OpResult<Self> {
+        let mut index = TxBloomIndex::default();
+        index.update(db, options)?;
+        Ok(index)
+    }
+
+    ///
+    /// Number of blocks covered by the filters.
+    ///
+    pub fn indexed_blocks(&self) -> usize {
+        self.files.values().map(|f| f.heights.len()).sum()
+    }
+
+    ///
+    /// Rebuild the filters of blk files with new or reorganized blocks.
+    ///
+    /// Only the last files change as the node syncs,
+    /// so updates read little data.
+    ///
+    pub fn update(&mut self, db: &BitcoinDB, options: &BuildOptions) -> OpResult<BuildProgress> {
+        let count = db.get_block_count();
+        let mut by_file: BTreeMap<i32, Vec<usize>> = BTreeMap::new();
+        for h in 0..count {
+            by_file.entry(db.get_header(h)?.n_file).or_default().push(h);
+        }
+        self.files.retain(|n_file, f| {
+            let last = *f.heights.last().unwrap();
+            by_file.get(n_file) == Some(&f.heights)
+                && db.get_hash_from_height(last).ok() == Some(f.last_hash)
+        });
+        let stale: Vec<(i32, Vec<usize>)> = by_file
+            .into_iter()
+            .filter(|(n_file, _)| !self.files.contains_key(n_file))
+            .collect();
+        let total = stale.iter().map(|(_, heights)| heights.len()).sum();
+        let mut monitor = BuildMonitor::new(total, options);
+        for (n_file, heights) in stale {
+            let n_tx = heights
+                .iter()
+                .map(|h| Ok(db.get_header(*h)?.n_tx as usize))
+                .sum::<OpResult<usize>>()?;
+            let mut filter = TxidFilter::new(n_tx, self.fp_rate);
+            let mut blocks = db.iter_heights::<Block, _>(heights.clone());
+            let mut last_hash = BlockHash::default();
+            for _ in heights.iter() {
+                let block = match blocks.next() {
+                    Some(block) => block,
+                    None => return Err(OpError::from("failed to read block for bloom index")),
+                };
+                for tx in block.txdata.iter() {
+                    filter.insert(&tx.txid());
+                }
+                last_hash = block.block_hash();
+                monitor.block_done(block.size() as u64);
+            }
+            self.files.insert(
+                n_file,
+                FileFilter {
+                    heights,
+                    last_hash,
+                    filter,
+                },
+            );
+        }
+        Ok(monitor.finish())
+    }
+
+    ///
+    /// Blk files that may contain `txid` (no false negatives).
+    ///
+    pub fn candidate_files(&self, txid: &Txid) -> Vec<i32> {
+        self.files
+            .iter()
+            .filter(|(_, f)| f.filter.contains(txid))
+            .map(|(n_file, _)| *n_file)
+            .collect()
+    }
+
+    ///
+    /// Heights of blocks that may contain `txid`, sorted.
+    ///
+    pub fn candidate_heights(&self, txid: &Txid) -> Vec<usize> {
+        let heights: BTreeSet<usize> = self
+            .files
+            .values()
+            .filter(|f| f.filter.contains(txid))
+            .flat_map(|f| f.heights.iter().copied())
+            .collect();
+        heights.into_iter().collect()
+    }
+
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(BLOOM_MAGIC)?;
+        out.write_f64::<LittleEndian>(self.fp_rate)?;
+        out.write_u32::<LittleEndian>(self.files.len() as u32)?;
+        for (n_file, f) in self.files.iter() {
+            out.write_i32::<LittleEndian>(*n_file)?;
+            out.write_all(&f.last_hash[..])?;
+            out.write_u32::<LittleEndian>(f.heights.len() as u32)?;
+            for h in f.heights.iter() {
+                out.write_u32::<LittleEndian>(*h as u32)?;
+            }
+            out.write_u32::<LittleEndian>(f.filter.k)?;
+            out.write_u32::<LittleEndian>(f.filter.bits.len() as u32)?;
+            for word in f.filter.bits.iter() {
+                out.write_u64::<LittleEndian>(*word)?;
+            }
+        }
+        out.flush()?;
+        drop(out);
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> OpResult<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != BLOOM_MAGIC {
+            return Err(OpError::from("not a txid bloom index"));
+        }
+        let mut index = TxBloomIndex::new(reader.read_f64::<LittleEndian>()?);
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let n_file = reader.read_i32::<LittleEndian>()?;
+            let mut last_hash = [0u8; 32];
+            reader.read_exact(&mut last_hash)?;
+            let heights = (0..reader.read_u32::<LittleEndian>()?)
+                .map(|_| Ok(reader.read_u32::<LittleEndian>()? as usize))
+                .collect::<OpResult<Vec<usize>>>()?;
+            let k = reader.read_u32::<LittleEndian>()?;
+            let bits = (0..reader.read_u32::<LittleEndian>()?)
+                .map(|_| reader.read_u64::<LittleEndian>())
+                .collect::<Result<Vec<u64>, _>>()?;
+            if heights.is_empty() || bits.is_empty() || k == 0 {
+                return Err(OpError::from("invalid filter in txid bloom index"));
+            }
+            index.files.insert(
+                n_file,
+                FileFilter {
+                    heights,
+                    last_hash: BlockHash::from_inner(last_hash),
+                    filter: TxidFilter { bits, k },
+                },
+            );
+        }
+        Ok(index)
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Find a transaction and the height of its block, without `txindex`,
+    /// by reading only the blocks of blk files whose filter matches `txid`.
+    ///
+    /// Blocks not covered by `index` (see `TxBloomIndex::update`) are not searched.
+    ///
+    pub fn get_transaction_with_bloom<T: From<Transaction>>(
+        &self,
+        index: &TxBloomIndex,
+        txid: &Txid,
+    ) -> OpResult<(usize, T)> {
+        let heights = index.candidate_heights(txid);
+        for (i, block) in self.iter_heights::<Block, _>(heights.clone()).enumerate() {
+            if let Some(tx) = block.txdata.into_iter().find(|tx| &tx.txid() == txid) {
+                return Ok((heights[i], self.convert(|| tx.into())));
+            }
+        }
+        Err(OpError::from(
+            format!("transaction {} not found in bloom candidates", txid).as_str(),
+        ))
+    }
+
+    ///
+    /// `get_transaction_with_bloom` under `limits`, which bound the number
+    /// and total size of the candidate blocks read.
+    ///
+    /// Fails with the limit reached before the transaction is found.
+    ///
+    pub fn get_transaction_with_bloom_limited<T: From<Transaction>>(
+        &self,
+        index: &TxBloomIndex,
+        txid: &Txid,
+        limits: &QueryLimits,
+    ) -> OpResult<(usize, T)> {
+        let mut tracker = LimitTracker::new(limits);
+        for height in index.candidate_heights(txid) {
+            let size = self.get_block_location(height)?.size;
+            tracker.admit(size as usize)?;
+            let block = self.get_block::<Block>(height)?;
+            if let Some(tx) = block.txdata.into_iter().find(|tx| &tx.txid() == txid) {
+                return Ok((height, self.convert(|| tx.into())));
+            }
+        }
+        Err(OpError::from(
+            format!("transaction {} not found in bloom candidates", txid).as_str(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    #[test]
+    fn test_txid_filter() {
+        let txids: Vec<Txid> = (0u32..1000).map(|i| Txid::hash(&i.to_le_bytes())).collect();
+        let mut filter = TxidFilter::new(500, 0.01);
+        for txid in txids[..500].iter() {
+            filter.insert(txid);
+        }
+        assert!(txids[..500].iter().all(|t| filter.contains(t)));
+        let false_positives = txids[500..].iter().filter(|t| filter.contains(t)).count();
+        assert!(false_positives < 25, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_bloom_index() {
+        let dir = TestDir::new("tx_bloom");
+        let datadir = dir.join("datadir");
+
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        let mut index = TxBloomIndex::build(&db, &BuildOptions::default()).unwrap();
+        assert_eq!(index.indexed_blocks(), 4);
+
+        let path = dir.join("tx_bloom.bin");
+        index.save(&path).unwrap();
+        assert_eq!(TxBloomIndex::load(&path).unwrap(), index);
+
+        let coinbase = chain.block(&tips[1]).unwrap().txdata[0].clone();
+        assert_eq!(index.candidate_files(&coinbase.txid()), vec![0]);
+        let (height, tx): (usize, Transaction) = db
+            .get_transaction_with_bloom(&index, &coinbase.txid())
+            .unwrap();
+        assert_eq!((height, tx), (2, coinbase));
+        assert!(db
+            .get_transaction_with_bloom::<Transaction>(&index, &Txid::hash(b"missing"))
+            .is_err());
+
+        // new blocks are appended to the same file, whose filter is rebuilt
+        let tip = chain.extend(&tips[2], 2).pop().unwrap();
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        let progress = index.update(&db, &BuildOptions::default()).unwrap();
+        assert_eq!((progress.done, index.indexed_blocks()), (6, 6));
+        let coinbase = chain.block(&tip).unwrap().txdata[0].txid();
+        let (height, _): (usize, Transaction) =
+            db.get_transaction_with_bloom(&index, &coinbase).unwrap();
+        assert_eq!(height, 5);
+        // up to date
+        let progress = index.update(&db, &BuildOptions::default()).unwrap();
+        assert_eq!(progress.done, 0);
+    }
+}
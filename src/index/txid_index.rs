@@ -0,0 +1,453 @@
+//!
+//! Txid to transaction position index built by this crate,
+//! for datadirs of nodes running without `-txindex`.
+//!
+use crate::api::{BitcoinDB, BlockHash, Txid};
+use crate::index::{BuildMonitor, BuildOptions, BuildProgress, ValueCodec};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::hashes::Hash;
+use bitcoin::Block;
+use log::warn;
+use rocksdb::{BlockBasedOptions, Options, WriteBatch, DB};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// transactions: `'t' || txid` to `height || offset || block hash prefix`
+const TX_PREFIX: u8 = b't';
+/// indexed blocks: `'b' || height` to block hash
+const BLOCK_PREFIX: u8 = b'b';
+/// next height to index
+const META_KEY: &[u8] = b"m";
+
+///
+/// Position of a transaction found in a `TxIndex`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TxPosition {
+    pub height: usize,
+    /// offset from the end of the block header (as `n_tx_offset` of `txindex`)
+    pub n_tx_offset: u32,
+    /// first bytes of the hash of the indexed block
+    pub block_tag: [u8; 8],
+}
+
+///
+/// A txid to (block height, offset in block) index, stored in a RocksDB
+/// at a given path, so that transactions can be looked up by txid
+/// without Bitcoin Core's `txindex`.
+///
+/// The blk files are scanned once, later `update` calls only index new
+/// blocks, and reorganized blocks are re-indexed. Each block is written
+/// atomically: an interrupted build resumes at the first block not indexed.
+/// The index takes about 50 bytes per transaction.
+///
+/// Once attached by `BitcoinDB::with_tx_index`, `get_transaction`,
+/// `get_tx_location`, `get_height_of_transaction` and the connected
+/// queries use it when the node's `txindex` is not open.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::TxIndex;
+/// use bitcoin_explorer::{BitcoinDB, FromHex, Transaction, Txid};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// // no txindex needed
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// // scan the chain once (hours), later calls only index new blocks
+/// let index = TxIndex::build(&db, Path::new("./tx_index")).unwrap();
+/// let db = db.with_tx_index(index).unwrap();
+///
+/// let txid = Txid::from_hex("e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468").unwrap();
+/// let tx: Transaction = db.get_transaction(&txid).unwrap();
+/// let height = db.get_height_of_transaction(&txid).unwrap();
+/// ```
+///
+pub struct TxIndex {
+    db: DB,
+    codec: ValueCodec,
+}
+
+impl TxIndex {
+    ///
+    /// Open (or create) the index at `path`, without indexing any block.
+    ///
+    pub fn open(path: &Path) -> OpResult<Self> {
+        TxIndex::open_with_codec(path, ValueCodec::default())
+    }
+
+    ///
+    /// `open`, with values encoded by `codec` (e.g. zstd compressed).
+    ///
+    /// Values record their encoding: an index written with any
+    /// compression opens with any codec of the same dictionary.
+    ///
+    pub fn open_with_codec(path: &Path, codec: ValueCodec) -> OpResult<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        // lookups of absent txids (e.g. not yet indexed) skip most files
+        let mut block_options = BlockBasedOptions::default();
+        block_options.set_bloom_filter(10.0, false);
+        options.set_block_based_table_factory(&block_options);
+        let db = DB::open(&options, path).map_err(|e| {
+            OpError::from(format!("failed to open rocksDB for tx index: {}", e).as_str())
+        })?;
+        Ok(TxIndex { db, codec })
+    }
+
+    ///
+    /// Open the index at `path` and index all blocks of `db` not yet indexed.
+    ///
+    pub fn build(db: &BitcoinDB, path: &Path) -> OpResult<Self> {
+        let index = TxIndex::open(path)?;
+        index.update(db, &BuildOptions::default())?;
+        Ok(index)
+    }
+
+    ///
+    /// Number of blocks indexed (blocks `0..indexed_height()`).
+    ///
+    pub fn indexed_height(&self) -> OpResult<usize> {
+        match self.read(META_KEY)? {
+            Some(value) if value.len() == 4 => {
+                Ok(u32::from_le_bytes(value[..].try_into().unwrap()) as usize)
+            }
+            Some(_) => Err(OpError::from("invalid tx index metadata")),
+            None => Ok(0),
+        }
+    }
+
+    fn read(&self, key: &[u8]) -> OpResult<Option<Vec<u8>>> {
+        self.db
+            .get(key)
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?
+            .map(|value| self.codec.decode(&value))
+            .transpose()
+    }
+
+    /// hash of the indexed block at `height`
+    fn block_hash(&self, height: usize) -> OpResult<Option<BlockHash>> {
+        match self.read(&block_key(height))? {
+            Some(value) => Ok(Some(BlockHash::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Number of indexed blocks still in the main chain of `db`.
+    ///
+    fn common_height(&self, db: &BitcoinDB) -> OpResult<usize> {
+        let mut height = self.indexed_height()?.min(db.get_block_count());
+        while height > 0 && self.block_hash(height - 1)? != db.get_hash_from_height(height - 1).ok()
+        {
+            height -= 1;
+        }
+        Ok(height)
+    }
+
+    ///
+    /// Index the blocks of `db` added since the last build,
+    /// and re-index the blocks that replaced reorganized ones.
+    ///
+    /// Entries of transactions of reorganized blocks are kept,
+    /// but no longer found: lookups check the hash of their block.
+    ///
+    pub fn update(&self, db: &BitcoinDB, options: &BuildOptions) -> OpResult<BuildProgress> {
+        let start = self.common_height(db)?;
+        let end = db.get_block_count();
+        let mut monitor = BuildMonitor::new(end.saturating_sub(start), options);
+        for (i, block) in db.iter_block::<Block>(start, end).enumerate() {
+            self.index_block(start + i, &block)?;
+            monitor.block_done(block.size() as u64);
+        }
+        Ok(monitor.finish())
+    }
+
+    /// height and hash of the last indexed block
+    pub(crate) fn tip(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        let height = match self.indexed_height()?.checked_sub(1) {
+            Some(height) => height,
+            None => return Ok(None),
+        };
+        match self.block_hash(height)? {
+            Some(hash) => Ok(Some((height, hash))),
+            None => Err(OpError::from("invalid tx index metadata")),
+        }
+    }
+
+    pub(crate) fn index_block(&self, height: usize, block: &Block) -> OpResult<()> {
+        let block_hash = block.block_hash();
+        let mut value = [0u8; 16];
+        value[..4].copy_from_slice(&(height as u32).to_le_bytes());
+        value[8..].copy_from_slice(&block_hash[..8]);
+        let mut batch = WriteBatch::default();
+        // transactions follow the transaction count
+        let mut offset = VarInt(block.txdata.len() as u64).len();
+        for tx in block.txdata.iter() {
+            value[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+            batch.put(tx_key(&tx.txid()), self.codec.encode(&value)?);
+            offset += tx.size();
+        }
+        batch.put(block_key(height), self.codec.encode(&block_hash[..])?);
+        batch.put(
+            META_KEY,
+            self.codec.encode(&(height as u32 + 1).to_le_bytes())?,
+        );
+        self.db
+            .write(batch)
+            .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))
+    }
+
+    ///
+    /// Undo `index_block` of the last indexed block, at `height`.
+    ///
+    /// Entries of its transactions are kept, as in `update`:
+    /// lookups check the hash of their block.
+    ///
+    pub(crate) fn unindex_block(&self, height: usize) -> OpResult<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete(block_key(height));
+        batch.put(META_KEY, self.codec.encode(&(height as u32).to_le_bytes())?);
+        self.db
+            .write(batch)
+            .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))
+    }
+
+    ///
+    /// Position of `txid`, `None` if it is not indexed.
+    ///
+    pub(crate) fn position(&self, txid: &Txid) -> OpResult<Option<TxPosition>> {
+        match self.read(&tx_key(txid))? {
+            Some(value) if value.len() == 16 => Ok(Some(TxPosition {
+                height: u32::from_le_bytes(value[..4].try_into().unwrap()) as usize,
+                n_tx_offset: u32::from_le_bytes(value[4..8].try_into().unwrap()),
+                block_tag: value[8..].try_into().unwrap(),
+            })),
+            Some(_) => Err(OpError::from(
+                format!("invalid tx index entry for txid: {}", txid).as_str(),
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Path of the `TxIndex` opened by `BitcoinDB::new` (with `tx_index`)
+    /// when Bitcoin Core's `txindex` cannot be opened:
+    /// `indexes/explorer_txindex` in the datadir.
+    ///
+    pub fn default_tx_index_path(&self) -> PathBuf {
+        self.datadir.join("indexes").join("explorer_txindex")
+    }
+
+    ///
+    /// This database, with txid lookups answered by `index`
+    /// when Bitcoin Core's `txindex` is not open.
+    ///
+    /// Fails if the last indexed block is not in the main chain
+    /// (call `TxIndex::update` first). Transactions of blocks indexed
+    /// later than the index are not found.
+    ///
+    pub fn with_tx_index(&self, index: TxIndex) -> OpResult<BitcoinDB> {
+        let indexed = index.indexed_height()?;
+        if index.common_height(self)? != indexed.min(self.get_block_count()) {
+            return Err(OpError::from(
+                "tx index tip is not in the main chain, update the index",
+            ));
+        }
+        let tx_db = self
+            .tx_db
+            .with_built_index(Arc::new(index), &self.block_index);
+        Ok(self.with_tx_db(tx_db))
+    }
+
+    ///
+    /// This database, with txid lookups answered by the `TxIndex`
+    /// at `path` (if present) when Bitcoin Core's `txindex` is not open.
+    ///
+    /// Does not fail: without a usable index at `path` (e.g. its tip
+    /// was reorganized), txid lookups fail with
+    /// `OpErrorKind::TxIndexUnavailable` listing the alternatives,
+    /// and other queries work as usual.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    ///
+    /// // txindex if Bitcoin Core has one, else ./tx_index if built
+    /// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), true)
+    ///     .unwrap()
+    ///     .with_tx_index_fallback(Path::new("./tx_index"));
+    /// ```
+    ///
+    pub fn with_tx_index_fallback(&self, path: &Path) -> BitcoinDB {
+        if self.tx_db.is_open() || !path.exists() {
+            return self.clone();
+        }
+        match TxIndex::open(path).and_then(|index| self.with_tx_index(index)) {
+            Ok(db) => db,
+            Err(e) => {
+                warn!("cannot use tx index at {}: {}", path.display(), e);
+                self.with_tx_db(self.tx_db.with_stale_index(path))
+            }
+        }
+    }
+}
+
+fn tx_key(txid: &Txid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(TX_PREFIX);
+    key.extend_from_slice(&txid[..]);
+    key
+}
+
+fn block_key(height: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(5);
+    key.push(BLOCK_PREFIX);
+    key.extend_from_slice(&(height as u32).to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{FConnectedTransaction, Transaction};
+    use crate::parser::errors::{OpErrorKind, TxIndexAlternative};
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    #[test]
+    fn test_tx_index() {
+        let dir = TestDir::new("tx_index");
+        let datadir = dir.join("datadir");
+
+        // block 3 spends the coinbase of block 1
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        chain.mine(&tips[1], vec![spend.clone()]);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        assert!(db.get_transaction::<Transaction>(&spend.txid()).is_err());
+
+        let path = dir.join("tx_index");
+        let index = TxIndex::build(&db, &path).unwrap();
+        assert_eq!(index.indexed_height().unwrap(), 4);
+        let db = db.with_tx_index(index).unwrap();
+        let tx: Transaction = db.get_transaction(&spend.txid()).unwrap();
+        assert_eq!(tx, spend);
+        assert_eq!(db.get_height_of_transaction(&spend.txid()).unwrap(), 3);
+        let coinbase = chain.block(&tips[0]).unwrap().txdata[0].clone();
+        assert_eq!(db.get_height_of_transaction(&coinbase.txid()).unwrap(), 1);
+        let location = db.get_tx_location(&spend.txid()).unwrap();
+        assert_eq!(location.size as usize, spend.size());
+        let connected: FConnectedTransaction = db.get_connected_transaction(&spend.txid()).unwrap();
+        assert_eq!(connected.input[0].value.as_sat(), coinbase.output[0].value);
+        assert!(db
+            .get_transaction::<Transaction>(&Txid::hash(b"missing"))
+            .is_err());
+
+        // reorganize block 3 out: its transaction is no longer found
+        let reorg = chain.extend(&tips[1], 2);
+        drop(db);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        assert!(db.with_tx_index(TxIndex::open(&path).unwrap()).is_err());
+        let index = TxIndex::open(&path).unwrap();
+        index.update(&db, &BuildOptions::default()).unwrap();
+        assert_eq!(index.indexed_height().unwrap(), 5);
+        let db = db.with_tx_index(index).unwrap();
+        assert!(db.get_transaction::<Transaction>(&spend.txid()).is_err());
+        let coinbase = chain.block(&reorg[1]).unwrap().txdata[0].clone();
+        assert_eq!(db.get_height_of_transaction(&coinbase.txid()).unwrap(), 4);
+    }
+
+    fn alternatives(result: OpResult<Transaction>) -> Vec<TxIndexAlternative> {
+        match result {
+            Err(OpError {
+                kind: OpErrorKind::TxIndexUnavailable(alternatives),
+                ..
+            }) => alternatives,
+            _ => panic!("expected TxIndexUnavailable"),
+        }
+    }
+
+    #[test]
+    fn test_tx_index_fallback() {
+        let dir = TestDir::new("tx_index_fallback");
+        let datadir = dir.join("datadir");
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        chain.write(&datadir).unwrap();
+        let coinbase = chain.block(&tips[1]).unwrap().txdata[0].clone();
+
+        // no txindex: blocks can be read, txid lookups list the alternatives
+        let path = dir.join("tx_index");
+        let db = BitcoinDB::new(&datadir, true)
+            .unwrap()
+            .with_tx_index_fallback(&path);
+        assert!(db.get_block::<Block>(2).is_ok());
+        assert_eq!(
+            alternatives(db.get_transaction(&coinbase.txid())),
+            vec![
+                TxIndexAlternative::EnableCoreTxIndex,
+                TxIndexAlternative::BuildTxIndex,
+                TxIndexAlternative::TxBloomIndex
+            ]
+        );
+        assert!(db
+            .get_height_of_transaction(&coinbase.txid())
+            .unwrap_err()
+            .to_string()
+            .contains("run Bitcoin Core with `-txindex=1`"));
+
+        drop(TxIndex::build(&db, &path).unwrap());
+        let db = db.with_tx_index_fallback(&path);
+        assert_eq!(db.get_height_of_transaction(&coinbase.txid()).unwrap(), 2);
+        drop(db);
+
+        // the tip of the index is reorganized out
+        chain.extend(&tips[1], 3);
+        chain.write(&datadir).unwrap();
+        let mut db = BitcoinDB::new(&datadir, true)
+            .unwrap()
+            .with_tx_index_fallback(&path);
+        assert_eq!(
+            alternatives(db.get_transaction(&coinbase.txid()))[1],
+            TxIndexAlternative::UpdateTxIndex(path.clone())
+        );
+        // also after a refresh
+        db.refresh().unwrap();
+        assert_eq!(
+            alternatives(db.get_transaction(&coinbase.txid()))[1],
+            TxIndexAlternative::UpdateTxIndex(path.clone())
+        );
+    }
+
+    #[test]
+    fn test_default_tx_index_path() {
+        let dir = TestDir::new("default_tx_index");
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        chain.write(&dir).unwrap();
+        let coinbase = chain.block(&tips[1]).unwrap().txdata[0].txid();
+
+        let db = BitcoinDB::new(&dir, true).unwrap();
+        assert!(db.get_height_of_transaction(&coinbase).is_err());
+        drop(TxIndex::build(&db, &db.default_tx_index_path()).unwrap());
+        drop(db);
+
+        // found by `new`, only if asked for a txindex
+        let db = BitcoinDB::new(&dir, true).unwrap();
+        assert_eq!(db.get_height_of_transaction(&coinbase).unwrap(), 2);
+        drop(db);
+        let db = BitcoinDB::new(&dir, false).unwrap();
+        assert!(db.get_height_of_transaction(&coinbase).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//!
+//! Wtxid to transaction position index, to correlate relay data
+//! (compact blocks, `wtxidrelay`) with on-chain confirmations.
+//!
+use crate::api::{BitcoinDB, BlockHash, Transaction, Wtxid};
+use crate::index::{BuildMonitor, BuildOptions, BuildProgress, ValueCodec};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hashes::Hash;
+use bitcoin::Block;
+use rocksdb::{BlockBasedOptions, Options, WriteBatch, DB};
+use std::convert::TryInto;
+use std::path::Path;
+
+/// transactions: `'w' || wtxid` to `height || position || block hash prefix`
+const WTX_PREFIX: u8 = b'w';
+/// indexed blocks: `'b' || height` to block hash
+const BLOCK_PREFIX: u8 = b'b';
+/// next height to index
+const META_KEY: &[u8] = b"m";
+
+///
+/// Position of a transaction found in a `WtxidIndex`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WtxPosition {
+    pub height: usize,
+    /// position of the transaction in its block
+    pub position: usize,
+}
+
+///
+/// A wtxid to (block height, position in block) index, stored in a
+/// RocksDB at a given path.
+///
+/// Built and updated like `TxIndex`: the blk files are scanned once,
+/// later `update` calls only index new blocks, and reorganized blocks
+/// are re-indexed. Transactions without witness have their txid as wtxid.
+/// Coinbase transactions are indexed by the hash of their serialization
+/// (not the zero wtxid of the witness commitment).
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::index::WtxidIndex;
+/// use bitcoin_explorer::{BitcoinDB, FromHex, Transaction, Wtxid};
+/// use std::path::Path;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+///
+/// // scan the chain once (hours), later calls only index new blocks
+/// let index = WtxidIndex::build(&db, Path::new("./wtxid_index")).unwrap();
+///
+/// let wtxid = Wtxid::from_hex("e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468").unwrap();
+/// let (height, tx): (usize, Transaction) = db.get_transaction_by_wtxid(&index, &wtxid).unwrap();
+/// ```
+///
+pub struct WtxidIndex {
+    db: DB,
+    codec: ValueCodec,
+}
+
+impl WtxidIndex {
+    ///
+    /// Open (or create) the index at `path`, without indexing any block.
+    ///
+    pub fn open(path: &Path) -> OpResult<Self> {
+        WtxidIndex::open_with_codec(path, ValueCodec::default())
+    }
+
+    ///
+    /// `open`, with values encoded by `codec` (e.g. zstd compressed).
+    ///
+    /// Values record their encoding: an index written with any
+    /// compression opens with any codec of the same dictionary.
+    ///
+    pub fn open_with_codec(path: &Path, codec: ValueCodec) -> OpResult<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        // lookups of absent wtxids (e.g. unconfirmed) skip most files
+        let mut block_options = BlockBasedOptions::default();
+        block_options.set_bloom_filter(10.0, false);
+        options.set_block_based_table_factory(&block_options);
+        let db = DB::open(&options, path).map_err(|e| {
+            OpError::from(format!("failed to open rocksDB for wtxid index: {}", e).as_str())
+        })?;
+        Ok(WtxidIndex { db, codec })
+    }
+
+    ///
+    /// Open the index at `path` and index all blocks of `db` not yet indexed.
+    ///
+    pub fn build(db: &BitcoinDB, path: &Path) -> OpResult<Self> {
+        let index = WtxidIndex::open(path)?;
+        index.update(db, &BuildOptions::default())?;
+        Ok(index)
+    }
+
+    ///
+    /// Number of blocks indexed (blocks `0..indexed_height()`).
+    ///
+    pub fn indexed_height(&self) -> OpResult<usize> {
+        match self.read(META_KEY)? {
+            Some(value) if value.len() == 4 => {
+                Ok(u32::from_le_bytes(value[..].try_into().unwrap()) as usize)
+            }
+            Some(_) => Err(OpError::from("invalid wtxid index metadata")),
+            None => Ok(0),
+        }
+    }
+
+    fn read(&self, key: &[u8]) -> OpResult<Option<Vec<u8>>> {
+        self.db
+            .get(key)
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?
+            .map(|value| self.codec.decode(&value))
+            .transpose()
+    }
+
+    /// hash of the indexed block at `height`
+    fn block_hash(&self, height: usize) -> OpResult<Option<BlockHash>> {
+        match self.read(&block_key(height))? {
+            Some(value) => Ok(Some(BlockHash::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Number of indexed blocks still in the main chain of `db`.
+    ///
+    fn common_height(&self, db: &BitcoinDB) -> OpResult<usize> {
+        let mut height = self.indexed_height()?.min(db.get_block_count());
+        while height > 0 && self.block_hash(height - 1)? != db.get_hash_from_height(height - 1).ok()
+        {
+            height -= 1;
+        }
+        Ok(height)
+    }
+
+    ///
+    /// Index the blocks of `db` added since the last build,
+    /// and re-index the blocks that replaced reorganized ones.
+    ///
+    pub fn update(&self, db: &BitcoinDB, options: &BuildOptions) -> OpResult<BuildProgress> {
+        let start = self.common_height(db)?;
+        let end = db.get_block_count();
+        let mut monitor = BuildMonitor::new(end.saturating_sub(start), options);
+        for (i, block) in db.iter_block::<Block>(start, end).enumerate() {
+            self.index_block(start + i, &block)?;
+            monitor.block_done(block.size() as u64);
+        }
+        Ok(monitor.finish())
+    }
+
+    fn index_block(&self, height: usize, block: &Block) -> OpResult<()> {
+        let block_hash = block.block_hash();
+        let mut value = [0u8; 16];
+        value[..4].copy_from_slice(&(height as u32).to_le_bytes());
+        value[8..].copy_from_slice(&block_hash[..8]);
+        let mut batch = WriteBatch::default();
+        for (position, tx) in block.txdata.iter().enumerate() {
+            value[4..8].copy_from_slice(&(position as u32).to_le_bytes());
+            batch.put(wtx_key(&tx.wtxid()), self.codec.encode(&value)?);
+        }
+        batch.put(block_key(height), self.codec.encode(&block_hash[..])?);
+        batch.put(
+            META_KEY,
+            self.codec.encode(&(height as u32 + 1).to_le_bytes())?,
+        );
+        self.db
+            .write(batch)
+            .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))
+    }
+
+    ///
+    /// Position of `wtxid` in the main chain of `db`,
+    /// `None` if it is not indexed (or its block was reorganized).
+    ///
+    pub fn position(&self, db: &BitcoinDB, wtxid: &Wtxid) -> OpResult<Option<WtxPosition>> {
+        let value = match self.read(&wtx_key(wtxid))? {
+            Some(value) if value.len() == 16 => value,
+            Some(_) => {
+                return Err(OpError::from(
+                    format!("invalid wtxid index entry for wtxid: {}", wtxid).as_str(),
+                ))
+            }
+            None => return Ok(None),
+        };
+        let height = u32::from_le_bytes(value[..4].try_into().unwrap()) as usize;
+        // entries of reorganized blocks are kept, but no longer found
+        match db.get_hash_from_height(height) {
+            Ok(hash) if hash[..8] == value[8..] => Ok(Some(WtxPosition {
+                height,
+                position: u32::from_le_bytes(value[4..8].try_into().unwrap()) as usize,
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Find a transaction by wtxid, with the height of its block.
+    ///
+    /// Only the requested transaction of the block is decoded
+    /// (see `get_transaction_at`).
+    ///
+    pub fn get_transaction_by_wtxid<T: From<Transaction>>(
+        &self,
+        index: &WtxidIndex,
+        wtxid: &Wtxid,
+    ) -> OpResult<(usize, T)> {
+        match index.position(self, wtxid)? {
+            Some(p) => Ok((p.height, self.get_transaction_at(p.height, p.position)?)),
+            None => Err(OpError::from(
+                format!("transaction {} not found in wtxid index", wtxid).as_str(),
+            )),
+        }
+    }
+}
+
+fn wtx_key(wtxid: &Wtxid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(WTX_PREFIX);
+    key.extend_from_slice(&wtxid[..]);
+    key
+}
+
+fn block_key(height: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(5);
+    key.push(BLOCK_PREFIX);
+    key.extend_from_slice(&(height as u32).to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDir};
+    use bitcoin::Witness;
+
+    #[test]
+    fn test_wtxid_index() {
+        let dir = TestDir::new("wtxid_index");
+        let datadir = dir.join("datadir");
+
+        // block 3 spends the coinbase of block 1 with a witness
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let mut spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        spend.input[0].witness = Witness::from_vec(vec![vec![0x30; 72], vec![0x02; 33]]);
+        chain.mine(&tips[1], vec![spend.clone()]);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+
+        let path = dir.join("wtxid_index");
+        let index = WtxidIndex::build(&db, &path).unwrap();
+        assert_eq!(index.indexed_height().unwrap(), 4);
+        assert_ne!(spend.wtxid().as_hash(), spend.txid().as_hash());
+        let (height, tx): (usize, Transaction) =
+            db.get_transaction_by_wtxid(&index, &spend.wtxid()).unwrap();
+        assert_eq!((height, tx), (3, spend.clone()));
+        assert_eq!(
+            index.position(&db, &spend.wtxid()).unwrap(),
+            Some(WtxPosition {
+                height: 3,
+                position: 1
+            })
+        );
+        // without witness, the wtxid is the txid
+        let coinbase = chain.block(&tips[0]).unwrap().txdata[0].clone();
+        let (height, _): (usize, Transaction) = db
+            .get_transaction_by_wtxid(&index, &Wtxid::from_hash(coinbase.txid().as_hash()))
+            .unwrap();
+        assert_eq!(height, 1);
+        assert!(db
+            .get_transaction_by_wtxid::<Transaction>(&index, &Wtxid::hash(b"missing"))
+            .is_err());
+
+        // reorganize block 3 out
+        chain.extend(&tips[1], 2);
+        drop(db);
+        chain.write(&datadir).unwrap();
+        let db = BitcoinDB::new(&datadir, false).unwrap();
+        assert_eq!(index.position(&db, &spend.wtxid()).unwrap(), None);
+        index.update(&db, &BuildOptions::default()).unwrap();
+        assert_eq!(index.indexed_height().unwrap(), 5);
+        assert_eq!(index.position(&db, &spend.wtxid()).unwrap(), None);
+    }
+}
@@ -0,0 +1,260 @@
+//!
+//! Allocation counters by pipeline stage.
+//!
+//! `CountingAllocator` wraps a global allocator, and attributes every
+//! allocation to the pipeline stage running on the current thread.
+//! With the `alloc-stats` feature, this crate installs it as the global
+//! allocator, wrapping jemalloc or mimalloc if one of these features is
+//! enabled (the system allocator otherwise). Without `alloc-stats`,
+//! `jemalloc` and `mimalloc` install the bare allocator, and
+//! `PipelineStats` stays at zero unless the binary installs
+//! `CountingAllocator` itself. If both `jemalloc` and `mimalloc` are
+//! enabled, jemalloc is installed.
+//!
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const N_STAGES: usize = 5;
+
+///
+/// Stage of the block pipeline, for allocation accounting.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PipelineStage {
+    /// reading raw blocks from blk files
+    Read,
+    /// decoding raw blocks
+    Decode,
+    /// inserting into and taking from the UTXO store
+    Utxo,
+    /// building connected blocks
+    Connect,
+    /// everything else, including consumers of iterators
+    Other,
+}
+
+impl PipelineStage {
+    fn index(self) -> usize {
+        match self {
+            PipelineStage::Read => 0,
+            PipelineStage::Decode => 1,
+            PipelineStage::Utxo => 2,
+            PipelineStage::Connect => 3,
+            PipelineStage::Other => 4,
+        }
+    }
+
+    fn from_index(i: usize) -> Self {
+        match i {
+            0 => PipelineStage::Read,
+            1 => PipelineStage::Decode,
+            2 => PipelineStage::Utxo,
+            3 => PipelineStage::Connect,
+            _ => PipelineStage::Other,
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_STAGE: Cell<usize> = const { Cell::new(4) };
+}
+
+static ALLOCATIONS: [AtomicU64; N_STAGES] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static ALLOCATED_BYTES: [AtomicU64; N_STAGES] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+///
+/// Restores the previous stage of the thread when dropped.
+///
+pub(crate) struct StageGuard {
+    previous: usize,
+}
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        let _ = CURRENT_STAGE.try_with(|s| s.set(previous));
+    }
+}
+
+///
+/// Attribute allocations of this thread to `stage`, until the guard is dropped.
+///
+#[inline]
+pub(crate) fn enter_stage(stage: PipelineStage) -> StageGuard {
+    let previous = CURRENT_STAGE
+        .try_with(|s| s.replace(stage.index()))
+        .unwrap_or(PipelineStage::Other.index());
+    StageGuard { previous }
+}
+
+#[inline]
+fn count(size: usize) {
+    let stage = CURRENT_STAGE
+        .try_with(|s| s.get())
+        .unwrap_or(PipelineStage::Other.index());
+    ALLOCATIONS[stage].fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES[stage].fetch_add(size as u64, Ordering::Relaxed);
+}
+
+///
+/// A global allocator counting allocations by `PipelineStage`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::iter::CountingAllocator;
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static GLOBAL: CountingAllocator<System> = CountingAllocator(System);
+/// ```
+///
+pub struct CountingAllocator<A>(pub A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.0.alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.0.alloc_zeroed(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+///
+/// Allocations of a pipeline stage.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StageAllocStats {
+    /// number of allocations (including reallocations)
+    pub allocations: u64,
+    /// total requested bytes
+    pub bytes: u64,
+}
+
+///
+/// Process-wide allocation counters by pipeline stage.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::iter::{PipelineStage, PipelineStats};
+/// use bitcoin_explorer::{BitcoinDB, SBlock};
+/// use std::path::Path;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+///
+/// let before = PipelineStats::snapshot();
+/// for _ in db.iter_block::<SBlock>(0, 100000) {}
+/// let stats = PipelineStats::snapshot().since(&before);
+/// println!("decode: {:?}", stats.get(PipelineStage::Decode));
+/// ```
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    stages: [StageAllocStats; N_STAGES],
+}
+
+impl PipelineStats {
+    /// current values of the counters
+    pub fn snapshot() -> Self {
+        let mut stats = PipelineStats::default();
+        for (i, s) in stats.stages.iter_mut().enumerate() {
+            s.allocations = ALLOCATIONS[i].load(Ordering::Relaxed);
+            s.bytes = ALLOCATED_BYTES[i].load(Ordering::Relaxed);
+        }
+        stats
+    }
+
+    /// counters accumulated after `earlier`
+    pub fn since(&self, earlier: &PipelineStats) -> Self {
+        let mut stats = *self;
+        for (s, e) in stats.stages.iter_mut().zip(earlier.stages.iter()) {
+            s.allocations = s.allocations.saturating_sub(e.allocations);
+            s.bytes = s.bytes.saturating_sub(e.bytes);
+        }
+        stats
+    }
+
+    pub fn get(&self, stage: PipelineStage) -> StageAllocStats {
+        self.stages[stage.index()]
+    }
+
+    /// (stage, stats) of all stages
+    pub fn iter(&self) -> impl Iterator<Item = (PipelineStage, StageAllocStats)> + '_ {
+        self.stages
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (PipelineStage::from_index(i), *s))
+    }
+
+    pub fn total(&self) -> StageAllocStats {
+        let mut total = StageAllocStats::default();
+        for s in self.stages.iter() {
+            total.allocations += s.allocations;
+            total.bytes += s.bytes;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_guard() {
+        let current = || CURRENT_STAGE.with(|s| s.get());
+        assert_eq!(current(), PipelineStage::Other.index());
+        {
+            let _read = enter_stage(PipelineStage::Read);
+            {
+                let _decode = enter_stage(PipelineStage::Decode);
+                assert_eq!(current(), PipelineStage::Decode.index());
+            }
+            assert_eq!(current(), PipelineStage::Read.index());
+        }
+        assert_eq!(current(), PipelineStage::Other.index());
+
+        // counted through the allocator wrapper
+        let allocator = CountingAllocator(std::alloc::System);
+        let before = PipelineStats::snapshot();
+        unsafe {
+            let _utxo = enter_stage(PipelineStage::Utxo);
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let ptr = allocator.alloc(layout);
+            allocator.dealloc(ptr, layout);
+        }
+        let stats = PipelineStats::snapshot().since(&before);
+        assert!(stats.get(PipelineStage::Utxo).allocations >= 1);
+        assert!(stats.get(PipelineStage::Utxo).bytes >= 100);
+    }
+}
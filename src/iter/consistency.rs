@@ -0,0 +1,95 @@
+//!
+//! Structured report of chronological inconsistencies,
+//! witness commitment mismatches and bad data found during connected iteration.
+//!
+use bitcoin::{OutPoint, Txid};
+use std::sync::{Arc, Mutex};
+
+///
+/// Kind of inconsistency of a spent input.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// the spent output is created at a height later than the spending block
+    SpendsLaterOutput { created_height: usize },
+    /// the spent output cannot be found in the UTXO cache
+    MissingPrevout,
+}
+
+///
+/// An input found inconsistent during connected iteration.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChronologyViolation {
+    /// height of the spending block
+    pub height: usize,
+    /// the spending transaction
+    pub txid: Txid,
+    /// the spent outpoint
+    pub outpoint: OutPoint,
+    pub kind: ViolationKind,
+}
+
+///
+/// Violations recorded when `strict_chronology` is enabled,
+/// witness commitment mismatches recorded when
+/// `verify_witness_commitment` is enabled,
+/// and blocks skipped or truncated under `OnBadData`.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    pub violations: Vec<ChronologyViolation>,
+    /// heights of blocks whose witness data does not match the
+    /// BIP141 commitment in the coinbase
+    pub witness_mismatches: Vec<usize>,
+    /// heights of blocks left out of the iteration
+    /// (`OnBadData::SkipBlock` or `OnBadData::YieldPartial`)
+    pub skipped_blocks: Vec<usize>,
+    /// heights of blocks yielded with missing inputs (`OnBadData::YieldPartial`)
+    pub partial_blocks: Vec<usize>,
+}
+
+impl ConsistencyReport {
+    #[inline]
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+            && self.witness_mismatches.is_empty()
+            && self.skipped_blocks.is_empty()
+            && self.partial_blocks.is_empty()
+    }
+}
+
+///
+/// Shared recorder, written by worker threads.
+///
+#[derive(Clone, Default)]
+pub(crate) struct ConsistencyRecorder(Arc<Mutex<ConsistencyReport>>);
+
+impl ConsistencyRecorder {
+    pub(crate) fn record(&self, violation: ChronologyViolation) {
+        self.0.lock().unwrap().violations.push(violation);
+    }
+
+    pub(crate) fn record_witness_mismatch(&self, height: usize) {
+        self.0.lock().unwrap().witness_mismatches.push(height);
+    }
+
+    pub(crate) fn record_skipped_block(&self, height: usize) {
+        self.0.lock().unwrap().skipped_blocks.push(height);
+    }
+
+    pub(crate) fn record_partial_block(&self, height: usize) {
+        self.0.lock().unwrap().partial_blocks.push(height);
+    }
+
+    pub(crate) fn report(&self) -> ConsistencyReport {
+        let mut report = self.0.lock().unwrap().clone();
+        report
+            .violations
+            .sort_by_key(|v| (v.height, v.txid, v.outpoint.vout));
+        report.witness_mismatches.sort_unstable();
+        report.skipped_blocks.sort_unstable();
+        report.partial_blocks.sort_unstable();
+        report
+    }
+}
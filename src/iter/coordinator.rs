@@ -0,0 +1,510 @@
+//!
+//! Process-global coordination of resources shared by iterators.
+//!
+//! Running several iterators concurrently multiplies the number of threads
+//! reading blk files, and (with `on-disk-utxo`) the memory used by rocksDB.
+//! `ResourceCoordinator` limits the number of concurrent blk file readers,
+//! and provides a single block cache shared by all on-disk UTXO caches.
+//! Read-only consumers of the same UTXO set share one loaded `UtxoView`.
+//! Its `MemoryProfile` trades speed for a bounded memory footprint.
+//!
+//! In containers, the memory limit of the cgroup (rather than the memory
+//! of the host) is detected at startup: below `LOW_MEMORY_LIMIT` the
+//! profile is `Low`, and buffers and caches are capped to a share of it.
+//!
+use crate::api::BitcoinDB;
+use crate::parser::errors::OpResult;
+use crate::utxo::UtxoView;
+use bitcoin::BlockHash;
+#[cfg(feature = "on-disk-utxo")]
+use log::error;
+use log::info;
+#[cfg(feature = "on-disk-utxo")]
+use rocksdb::Cache;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+
+/// default size of the shared rocksDB block cache (256 MB)
+#[cfg(feature = "on-disk-utxo")]
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 0x10000000;
+
+/// size of the shared rocksDB block cache with `MemoryProfile::Low` (32 MB)
+#[cfg(feature = "on-disk-utxo")]
+const LOW_MEMORY_BLOCK_CACHE_SIZE: usize = 0x2000000;
+
+///
+/// Memory footprint of iterators and UTXO caches.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryProfile {
+    /// favour throughput (default without the `low-memory` feature)
+    Standard,
+    ///
+    /// For small devices (e.g. 4 GB Raspberry Pi): connected iterators use
+    /// a few worker threads with short queues, and the RocksDB UTXO cache
+    /// uses small memtables and block cache. Slower, but bounded.
+    ///
+    Low,
+}
+
+impl Default for MemoryProfile {
+    /// `Low` with the `low-memory` feature, `Standard` otherwise
+    fn default() -> Self {
+        if cfg!(feature = "low-memory") {
+            MemoryProfile::Low
+        } else {
+            MemoryProfile::Standard
+        }
+    }
+}
+
+/// container memory limit below which `MemoryProfile::Low` is used (8 GB)
+pub const LOW_MEMORY_LIMIT: u64 = 0x200000000;
+
+/// each buffer or cache uses at most `1 / MEMORY_LIMIT_SHARE` of the memory limit
+const MEMORY_LIMIT_SHARE: u64 = 16;
+
+/// cgroup v2, and v1, memory limit files
+const CGROUP_V2_MEMORY_MAX: &str = "/sys/fs/cgroup/memory.max";
+const CGROUP_V1_MEMORY_LIMIT: &str = "/sys/fs/cgroup/memory/memory.limit_in_bytes";
+
+///
+/// Memory limit of the cgroup of this process (Linux containers),
+/// `None` if there is none.
+///
+pub fn cgroup_memory_limit() -> Option<u64> {
+    [CGROUP_V2_MEMORY_MAX, CGROUP_V1_MEMORY_LIMIT]
+        .iter()
+        .find_map(|path| parse_cgroup_limit(&fs::read_to_string(path).ok()?))
+}
+
+///
+/// `max` (v2) and huge values (v1, rounded down `i64::MAX`) mean no limit.
+///
+fn parse_cgroup_limit(content: &str) -> Option<u64> {
+    let limit = content.trim().parse::<u64>().ok()?;
+    if limit == 0 || limit >= 1 << 60 {
+        None
+    } else {
+        Some(limit)
+    }
+}
+
+static COORDINATOR: OnceLock<ResourceCoordinator> = OnceLock::new();
+
+///
+/// Process-global resource coordinator.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::iter::ResourceCoordinator;
+///
+/// // allow at most 4 threads reading blk files at the same time,
+/// // across all iterators and queries in this process.
+/// ResourceCoordinator::global().set_max_blk_readers(4);
+/// ```
+///
+pub struct ResourceCoordinator {
+    /// 0 stands for unlimited
+    max_blk_readers: AtomicUsize,
+    blk_readers: Mutex<usize>,
+    blk_readers_released: Condvar,
+    active_iterators: AtomicUsize,
+    low_memory: AtomicBool,
+    /// 0 stands for no limit
+    memory_limit: AtomicU64,
+    /// 0 stands for the default size
+    #[cfg(feature = "on-disk-utxo")]
+    block_cache_size: AtomicUsize,
+    #[cfg(feature = "on-disk-utxo")]
+    block_cache: Mutex<Option<Cache>>,
+    /// shared UTXO views, by hash of their last block (`None` for empty)
+    utxo_views: Mutex<HashMap<Option<BlockHash>, ViewSlot>>,
+}
+
+///
+/// A shared UTXO view, locked while it is loaded.
+///
+type ViewSlot = Arc<Mutex<Weak<UtxoView>>>;
+
+///
+/// Released when dropped (`None` for readers not counted, without limit).
+///
+pub(crate) struct BlkReaderPermit<'a>(Option<&'a ResourceCoordinator>);
+
+///
+/// Held by iterators while alive.
+///
+pub(crate) struct IterRegistration(&'static ResourceCoordinator);
+
+impl ResourceCoordinator {
+    ///
+    /// Get the process-global coordinator.
+    ///
+    pub fn global() -> &'static ResourceCoordinator {
+        COORDINATOR.get_or_init(ResourceCoordinator::new)
+    }
+
+    fn new() -> Self {
+        let limit = cgroup_memory_limit();
+        if let Some(limit) = limit {
+            info!("container memory limit: {} MB", limit >> 20);
+        }
+        ResourceCoordinator::with_memory_limit(limit)
+    }
+
+    fn with_memory_limit(limit: Option<u64>) -> Self {
+        let coordinator = ResourceCoordinator {
+            max_blk_readers: AtomicUsize::new(0),
+            blk_readers: Mutex::new(0),
+            blk_readers_released: Condvar::new(),
+            active_iterators: AtomicUsize::new(0),
+            low_memory: AtomicBool::new(MemoryProfile::default() == MemoryProfile::Low),
+            memory_limit: AtomicU64::new(0),
+            #[cfg(feature = "on-disk-utxo")]
+            block_cache_size: AtomicUsize::new(0),
+            #[cfg(feature = "on-disk-utxo")]
+            block_cache: Mutex::new(None),
+            utxo_views: Mutex::new(HashMap::new()),
+        };
+        coordinator.set_memory_limit(limit);
+        coordinator
+    }
+
+    ///
+    /// Set the memory limit of this process, overriding the detected
+    /// cgroup limit (see `cgroup_memory_limit`).
+    ///
+    /// Below `LOW_MEMORY_LIMIT`, the memory profile is set to `Low`,
+    /// otherwise to the default profile. Iterator buffers, rocksDB memtables
+    /// and the shared block cache created afterwards use at most 1/16
+    /// of the limit each.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::iter::ResourceCoordinator;
+    ///
+    /// // a job limited to 2 GB, before creating the first iterator
+    /// ResourceCoordinator::global().set_memory_limit(Some(2 << 30));
+    /// ```
+    ///
+    pub fn set_memory_limit(&self, limit: Option<u64>) {
+        self.memory_limit
+            .store(limit.unwrap_or(0), Ordering::SeqCst);
+        self.set_memory_profile(match limit {
+            Some(limit) if limit < LOW_MEMORY_LIMIT => MemoryProfile::Low,
+            _ => MemoryProfile::default(),
+        });
+    }
+
+    ///
+    /// Memory limit of this process (detected or set), if any.
+    ///
+    pub fn memory_limit(&self) -> Option<u64> {
+        match self.memory_limit.load(Ordering::SeqCst) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    ///
+    /// `bytes`, capped to the share of the memory limit of one buffer.
+    ///
+    pub(crate) fn capped(&self, bytes: usize) -> usize {
+        match self.memory_limit() {
+            Some(limit) => {
+                let share = usize::try_from(limit / MEMORY_LIMIT_SHARE).unwrap_or(usize::MAX);
+                bytes.min(share).max(1)
+            }
+            None => bytes,
+        }
+    }
+
+    ///
+    /// Limit the number of threads reading blk files concurrently.
+    ///
+    /// `0` means unlimited (default). Readers started without limit
+    /// are not counted against a limit set later.
+    ///
+    pub fn set_max_blk_readers(&self, max: usize) {
+        self.max_blk_readers.store(max, Ordering::SeqCst);
+        self.blk_readers_released.notify_all();
+    }
+
+    ///
+    /// Current limit of concurrent blk file readers (`0` for unlimited).
+    ///
+    pub fn max_blk_readers(&self) -> usize {
+        self.max_blk_readers.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Set the memory profile of iterators and UTXO caches created afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::iter::{MemoryProfile, ResourceCoordinator};
+    ///
+    /// // before creating the first connected iterator
+    /// ResourceCoordinator::global().set_memory_profile(MemoryProfile::Low);
+    /// ```
+    ///
+    pub fn set_memory_profile(&self, profile: MemoryProfile) {
+        self.low_memory
+            .store(profile == MemoryProfile::Low, Ordering::SeqCst);
+    }
+
+    pub fn memory_profile(&self) -> MemoryProfile {
+        if self.low_memory.load(Ordering::SeqCst) {
+            MemoryProfile::Low
+        } else {
+            MemoryProfile::Standard
+        }
+    }
+
+    ///
+    /// Number of connected block iterators currently alive.
+    ///
+    pub fn active_iterators(&self) -> usize {
+        self.active_iterators.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Set the size of the rocksDB block cache shared by all on-disk UTXO caches.
+    ///
+    /// Only affects the cache if it has not been created yet
+    /// (i.e., call this before starting the first connected iterator).
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub fn set_block_cache_size(&self, bytes: usize) {
+        self.block_cache_size.store(bytes, Ordering::SeqCst);
+    }
+
+    ///
+    /// Size of the block cache to create, with the current
+    /// memory profile and memory limit.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    fn block_cache_size(&self) -> usize {
+        let size = match self.block_cache_size.load(Ordering::SeqCst) {
+            0 => DEFAULT_BLOCK_CACHE_SIZE,
+            size => size,
+        };
+        let size = match self.memory_profile() {
+            MemoryProfile::Low => size.min(LOW_MEMORY_BLOCK_CACHE_SIZE),
+            MemoryProfile::Standard => size,
+        };
+        self.capped(size)
+    }
+
+    ///
+    /// The shared block cache, created on first use.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub(crate) fn block_cache(&self) -> Option<Cache> {
+        let mut cache = self.block_cache.lock().unwrap();
+        if cache.is_none() {
+            match Cache::new_lru_cache(self.block_cache_size()) {
+                Ok(c) => *cache = Some(c),
+                Err(e) => error!("failed to create shared rocksDB block cache: {}", e),
+            }
+        }
+        cache.clone()
+    }
+
+    ///
+    /// The UTXO set before height `end` (see `UtxoView::load`),
+    /// shared by all read-only consumers in this process.
+    ///
+    /// The set is loaded once, and kept in memory while any consumer
+    /// holds it. Views are identified by the hash of block `end - 1`,
+    /// so that consumers with different `BitcoinDB` of the same chain
+    /// share them too. Concurrent calls for the same view wait for
+    /// a load in progress, other views load in parallel.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use bitcoin_explorer::iter::ResourceCoordinator;
+    /// use std::path::Path;
+    /// use std::thread;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// // both threads query the same UTXO set, loaded once
+    /// let handles: Vec<_> = (0..2)
+    ///     .map(|_| {
+    ///         let db = db.clone();
+    ///         thread::spawn(move || {
+    ///             let view = ResourceCoordinator::global().shared_utxo_view(&db, 100000).unwrap();
+    ///             view.len()
+    ///         })
+    ///     })
+    ///     .collect();
+    /// for h in handles {
+    ///     println!("{} unspent outputs", h.join().unwrap());
+    /// }
+    /// ```
+    ///
+    pub fn shared_utxo_view(&self, db: &BitcoinDB, end: usize) -> OpResult<Arc<UtxoView>> {
+        let key = match end {
+            0 => None,
+            end => Some(db.get_hash_from_height(end - 1)?),
+        };
+        let slot = {
+            let mut views = self.utxo_views.lock().unwrap();
+            // keep the views still held, or being loaded
+            views.retain(|_, slot| {
+                Arc::strong_count(slot) > 1
+                    || slot.try_lock().map_or(true, |view| view.strong_count() > 0)
+            });
+            views.entry(key).or_default().clone()
+        };
+        let mut shared = slot.lock().unwrap();
+        if let Some(view) = shared.upgrade() {
+            return Ok(view);
+        }
+        let view = Arc::new(UtxoView::load(db, end)?);
+        *shared = Arc::downgrade(&view);
+        Ok(view)
+    }
+
+    ///
+    /// Block until a blk file reader slot is available.
+    ///
+    pub(crate) fn acquire_blk_reader(&self) -> BlkReaderPermit<'_> {
+        if self.max_blk_readers() == 0 {
+            return BlkReaderPermit(None);
+        }
+        let mut readers = self.blk_readers.lock().unwrap();
+        loop {
+            let max = self.max_blk_readers();
+            if max == 0 || *readers < max {
+                break;
+            }
+            readers = self.blk_readers_released.wait(readers).unwrap();
+        }
+        *readers += 1;
+        BlkReaderPermit(Some(self))
+    }
+
+    pub(crate) fn register_iterator(&'static self) -> IterRegistration {
+        self.active_iterators.fetch_add(1, Ordering::SeqCst);
+        IterRegistration(self)
+    }
+}
+
+impl Drop for BlkReaderPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(coordinator) = self.0 {
+            *coordinator.blk_readers.lock().unwrap() -= 1;
+            coordinator.blk_readers_released.notify_one();
+        }
+    }
+}
+
+impl Drop for IterRegistration {
+    fn drop(&mut self) {
+        self.0.active_iterators.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use std::thread;
+
+    #[test]
+    fn test_memory_limit() {
+        assert_eq!(parse_cgroup_limit("max\n"), None);
+        assert_eq!(parse_cgroup_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_cgroup_limit("2147483648\n"), Some(2 << 30));
+
+        let unlimited = ResourceCoordinator::with_memory_limit(None);
+        assert_eq!(unlimited.memory_limit(), None);
+        assert_eq!(unlimited.capped(0x10000000), 0x10000000);
+
+        let limited = ResourceCoordinator::with_memory_limit(Some(2 << 30));
+        assert_eq!(limited.memory_profile(), MemoryProfile::Low);
+        assert_eq!(limited.capped(0x10000000), 0x8000000);
+        #[cfg(feature = "on-disk-utxo")]
+        assert_eq!(limited.block_cache_size(), LOW_MEMORY_BLOCK_CACHE_SIZE);
+
+        let large = ResourceCoordinator::with_memory_limit(Some(64 << 30));
+        assert_eq!(large.memory_profile(), MemoryProfile::default());
+        assert_eq!(large.capped(0x10000000), 0x10000000);
+
+        // raising the limit restores the profile and the block cache size
+        let raised = ResourceCoordinator::with_memory_limit(Some(256 << 20));
+        assert_eq!(raised.memory_profile(), MemoryProfile::Low);
+        #[cfg(feature = "on-disk-utxo")]
+        assert_eq!(raised.block_cache_size(), 0x1000000);
+        raised.set_memory_limit(Some(64 << 30));
+        assert_eq!(raised.memory_profile(), MemoryProfile::default());
+        assert_eq!(raised.capped(0x10000000), 0x10000000);
+        #[cfg(feature = "on-disk-utxo")]
+        assert_eq!(raised.block_cache_size(), large.block_cache_size());
+        raised.set_memory_limit(None);
+        assert_eq!(raised.memory_limit(), None);
+    }
+
+    #[test]
+    fn test_shared_utxo_view() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 3);
+        let db = TestDb::new("shared_utxo_view", &chain);
+        let coordinator = ResourceCoordinator::new();
+
+        let view = coordinator.shared_utxo_view(&db, 3).unwrap();
+        let same = coordinator.shared_utxo_view(&db, 3).unwrap();
+        assert!(Arc::ptr_eq(&view, &same));
+        assert_eq!(view.len(), UtxoView::load(&db, 3).unwrap().len());
+        let other = coordinator.shared_utxo_view(&db, 2).unwrap();
+        assert!(!Arc::ptr_eq(&view, &other));
+        assert_eq!(other.height(), 2);
+
+        // released with the last consumer
+        drop((view, same));
+        let reloaded = coordinator.shared_utxo_view(&db, 3).unwrap();
+        assert_eq!(reloaded.height(), 3);
+        assert_eq!(coordinator.utxo_views.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_blk_reader_limit() {
+        let coordinator = Arc::new(ResourceCoordinator::new());
+        // readers are not counted without limit
+        let unlimited = coordinator.acquire_blk_reader();
+        assert_eq!(*coordinator.blk_readers.lock().unwrap(), 0);
+        drop(unlimited);
+
+        coordinator.set_max_blk_readers(2);
+        let peak = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let coordinator = coordinator.clone();
+                let peak = peak.clone();
+                thread::spawn(move || {
+                    let _permit = coordinator.acquire_blk_reader();
+                    let current = *coordinator.blk_readers.lock().unwrap();
+                    peak.fetch_max(current, Ordering::SeqCst);
+                    thread::sleep(std::time::Duration::from_millis(5));
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert_eq!(*coordinator.blk_readers.lock().unwrap(), 0);
+    }
+}
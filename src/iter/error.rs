@@ -0,0 +1,45 @@
+//!
+//! Failures of block iterators, with the height they occurred at.
+//!
+use crate::parser::errors::OpError;
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fmt;
+
+///
+/// A block iterator stopped at `height`: the block could not be read,
+/// or (connected iterators with `OnBadData::Abort`) an input could not
+/// be connected.
+///
+/// Blocks below `height` were all produced, and none after.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IterError {
+    /// height of the failing block
+    pub height: usize,
+    pub message: String,
+}
+
+impl IterError {
+    pub(crate) fn new(height: usize, message: String) -> Self {
+        IterError { height, message }
+    }
+}
+
+impl fmt::Display for IterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "iteration stopped at height {}: {}",
+            self.height, self.message
+        )
+    }
+}
+
+impl error::Error for IterError {}
+
+impl From<IterError> for OpError {
+    fn from(err: IterError) -> Self {
+        OpError::from(err.to_string().as_str())
+    }
+}
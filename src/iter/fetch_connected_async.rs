@@ -1,159 +1,115 @@
-#[cfg(feature = "on-disk-utxo")]
-use crate::iter::iter_connected::KEY_LENGTH;
-#[cfg(not(feature = "on-disk-utxo"))]
-use crate::iter::util::VecMap;
+use crate::iter::alloc_stats::{enter_stage, PipelineStage};
+use crate::iter::consistency::{ChronologyViolation, ConsistencyRecorder, ViolationKind};
+use crate::iter::error::IterError;
+use crate::iter::iter_connected::OnBadData;
 use crate::parser::proto::connected_proto::{ConnectedBlock, ConnectedTx};
+use crate::utxo::store::UtxoStore;
 use crate::BitcoinDB;
-#[cfg(feature = "on-disk-utxo")]
-use bitcoin::consensus::{Decodable, Encodable};
-use bitcoin::hashes::Hash;
-#[cfg(feature = "on-disk-utxo")]
-use bitcoin::TxOut;
-use bitcoin::{Block, Txid};
-#[cfg(not(feature = "on-disk-utxo"))]
-use hash_hasher::HashedMap;
+use bitcoin::{Block, OutPoint, Txid};
 use log::error;
-#[cfg(not(feature = "on-disk-utxo"))]
-#[cfg(debug_assertions)]
-use log::warn;
-#[cfg(feature = "on-disk-utxo")]
-use rocksdb::{WriteBatch, DB};
-use std::sync::Arc;
-#[cfg(not(feature = "on-disk-utxo"))]
-use std::sync::Mutex;
+
+///
+/// Applies the `OnBadData` policy in worker threads.
+///
+#[derive(Clone)]
+pub(crate) struct BadDataHandler {
+    pub(crate) policy: OnBadData,
+    /// always `Some` unless the policy is `Abort`
+    pub(crate) recorder: Option<ConsistencyRecorder>,
+}
+
+impl BadDataHandler {
+    ///
+    /// Return the error if the iteration should abort,
+    /// otherwise record `height` as skipped.
+    ///
+    fn skip_block(&self, height: usize, message: String) -> Result<(), IterError> {
+        let err = IterError::new(height, message);
+        if self.policy == OnBadData::Abort {
+            return Err(err);
+        }
+        error!("{}, block skipped", err);
+        if let Some(recorder) = &self.recorder {
+            recorder.record_skipped_block(height);
+        }
+        Ok(())
+    }
+
+    fn record_partial_block(&self, height: usize) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_partial_block(height);
+        }
+    }
+}
 
 ///
 /// read block, update UTXO cache, return block
 ///
-pub(crate) fn update_unspent_cache<TBlock>(
-    #[cfg(not(feature = "on-disk-utxo"))] unspent: &Arc<
-        Mutex<HashedMap<Txid, Arc<Mutex<VecMap<<TBlock::Tx as ConnectedTx>::TOut>>>>>,
-    >,
-    #[cfg(feature = "on-disk-utxo")] unspent: &Arc<DB>,
+/// Returns `None` if the block cannot be read and the policy skips it.
+///
+pub(crate) fn update_unspent_cache(
+    unspent: &dyn UtxoStore,
     db: &BitcoinDB,
     height: usize,
-) -> Result<Block, ()>
-where
-    TBlock: ConnectedBlock,
-{
+    bad_data: &BadDataHandler,
+) -> Result<(usize, Option<Block>), IterError> {
     match db.get_block::<Block>(height) {
-        #[cfg(not(feature = "on-disk-utxo"))]
         Ok(block) => {
-            let mut new_unspent_cache = Vec::with_capacity(block.txdata.len());
-
-            // insert new transactions
-            for tx in block.txdata.iter() {
-                // clone outputs
-                let txid = tx.txid();
-                let mut outs: Vec<Option<Box<<TBlock::Tx as ConnectedTx>::TOut>>> =
-                    Vec::with_capacity(tx.output.len());
-                for o in tx.output.iter() {
-                    outs.push(Some(Box::new(o.clone().into())));
-                }
-
-                // update unspent cache
-                let outs: VecMap<<TBlock::Tx as ConnectedTx>::TOut> =
-                    VecMap::from_vec(outs.into_boxed_slice());
-                let new_unspent = Arc::new(Mutex::new(outs));
-
-                // the new transaction should not be in unspent
-                #[cfg(debug_assertions)]
-                if unspent.lock().unwrap().contains_key(&txid) {
-                    warn!("found duplicate key {}", &txid);
-                }
-
-                new_unspent_cache.push((txid, new_unspent));
+            let _stage = enter_stage(PipelineStage::Utxo);
+            match unspent.insert_block(height as u32, &block) {
+                Ok(_) => Ok((height, Some(block))),
+                Err(e) => Err(IterError::new(height, e.to_string())),
             }
-            unspent.lock().unwrap().extend(new_unspent_cache);
-            // if some exception happens in lower stream
-            Ok(block)
         }
-
-        #[cfg(feature = "on-disk-utxo")]
-        Ok(block) => {
-            let mut batch = WriteBatch::default();
-
-            // insert new transactions
-            for tx in block.txdata.iter() {
-                // clone outputs
-                let txid = tx.txid();
-
-                for (n, o) in (0_u32..).zip(tx.output.iter()) {
-                    let key = txo_key(txid, n);
-                    let value = txo_to_u8(o);
-                    batch.put(key, value);
-                }
-            }
-            match unspent.write_without_wal(batch) {
-                Ok(_) => Ok(block),
-                Err(e) => {
-                    error!("failed to write UTXO to cache, error: {}", e);
-                    Err(())
-                }
-            }
+        Err(e) => {
+            bad_data.skip_block(height, format!("cannot read block: {}", e))?;
+            Ok((height, None))
         }
-
-        Err(_) => Err(()),
     }
 }
 
 ///
 /// fetch_block_connected, thread safe
 ///
+/// Returns `None` if an input cannot be connected and the policy skips the block.
+///
 pub(crate) fn connect_outpoints<TBlock>(
-    #[cfg(not(feature = "on-disk-utxo"))] unspent: &Arc<
-        Mutex<HashedMap<Txid, Arc<Mutex<VecMap<<TBlock::Tx as ConnectedTx>::TOut>>>>>,
-    >,
-    #[cfg(feature = "on-disk-utxo")] unspent: &Arc<DB>,
+    unspent: &dyn UtxoStore,
+    recorder: &Option<ConsistencyRecorder>,
+    bad_data: &BadDataHandler,
+    height: usize,
     block: Block,
-) -> Result<TBlock, ()>
+) -> Result<Option<TBlock>, IterError>
 where
     TBlock: ConnectedBlock,
 {
+    let _stage = enter_stage(PipelineStage::Connect);
     let block_hash = block.header.block_hash();
     let mut output_block = TBlock::from(block.header, block_hash);
 
-    // collect rocks db keys
-    #[cfg(feature = "on-disk-utxo")]
-    let mut keys = Vec::new();
-
-    #[cfg(feature = "on-disk-utxo")]
-    for tx in block.txdata.iter() {
-        for input in tx.input.iter() {
-            // skip coinbase transaction
-            if input.previous_output.is_null() {
-                continue;
-            }
-
-            keys.push(txo_key(
-                input.previous_output.txid,
-                input.previous_output.vout,
-            ));
-        }
-    }
-
-    // get utxo
-    #[cfg(feature = "on-disk-utxo")]
-    let tx_outs = unspent.multi_get(keys.clone());
-
-    // remove keys
-    #[cfg(feature = "on-disk-utxo")]
-    for key in keys {
-        match unspent.delete(&key) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("failed to remove key {:?}, error: {}", &key, e);
-                return Err(());
-            }
-        }
-    }
-
-    // pointer to record read position in tx_outs
-    #[cfg(feature = "on-disk-utxo")]
-    let mut pos = 0;
-
+    // collect outpoints, skipping coinbase inputs
+    let outpoints: Vec<OutPoint> = block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.input.iter())
+        .map(|input| input.previous_output)
+        .filter(|outpoint| !outpoint.is_null())
+        .collect();
+
+    // get and remove utxo
+    let taken = {
+        let _stage = enter_stage(PipelineStage::Utxo);
+        unspent.take_at(height as u32, &outpoints)
+    };
+    let mut tx_outs = match taken {
+        Ok(tx_outs) => tx_outs.into_iter(),
+        Err(e) => return Err(IterError::new(height, e.to_string())),
+    };
+
+    let mut partial = false;
     for tx in block.txdata {
         let mut output_tx: TBlock::Tx = ConnectedTx::from(&tx);
+        let txid = output_tx_id(&tx, recorder);
 
         // spend new inputs
         for input in tx.input {
@@ -161,91 +117,85 @@ where
             if input.previous_output.is_null() {
                 continue;
             }
-
-            #[cfg(not(feature = "on-disk-utxo"))]
-            let prev_txid = &input.previous_output.txid;
-            #[cfg(not(feature = "on-disk-utxo"))]
-            let n = *&input.previous_output.vout as usize;
-
-            // temporarily lock unspent
-            #[cfg(not(feature = "on-disk-utxo"))]
-            let prev_tx = {
-                let prev_tx = unspent.lock().unwrap();
-                match prev_tx.get(prev_txid) {
-                    None => None,
-                    Some(tx) => Some(tx.clone()),
-                }
-            };
-
-            #[cfg(feature = "on-disk-utxo")]
-            let prev_txo = match tx_outs.get(pos).unwrap() {
-                Ok(bytes) => match bytes {
-                    None => None,
-                    Some(bytes) => txo_from_u8(bytes.as_slice()),
-                },
-                Err(_) => None,
-            };
-
-            #[cfg(not(feature = "on-disk-utxo"))]
-            if let Some(prev_tx) = prev_tx {
-                // temporarily lock prev_tx
-                let (tx_out, is_empty) = {
-                    let mut prev_tx_lock = prev_tx.lock().unwrap();
-                    let tx_out = prev_tx_lock.remove(n);
-                    let is_empty = prev_tx_lock.is_empty();
-                    (tx_out, is_empty)
-                };
-                // remove a key immediately when the key contains no transaction
-                if is_empty {
-                    unspent.lock().unwrap().remove(prev_txid);
-                }
-                if let Some(out) = tx_out {
-                    output_tx.add_input(*out);
+            if let Some((created_height, out)) = tx_outs.next().flatten() {
+                check_chronology(
+                    recorder,
+                    height,
+                    txid,
+                    &input.previous_output,
+                    created_height,
+                );
+                output_tx.add_input_at(out.into(), &input, created_height as usize);
+            } else {
+                record_missing(recorder, height, txid, &input.previous_output);
+                let message = format!("cannot find previous outpoint {}", input.previous_output);
+                if bad_data.policy == OnBadData::YieldPartial {
+                    error!("{} at height {}, input omitted", message, height);
+                    partial = true;
                 } else {
-                    error!("cannot find previous outpoint, bad data");
-                    return Err(());
+                    bad_data.skip_block(height, message)?;
+                    return Ok(None);
                 }
-            } else {
-                error!("cannot find previous transactions, bad data");
-                return Err(());
-            }
-
-            #[cfg(feature = "on-disk-utxo")]
-            if let Some(out) = prev_txo {
-                output_tx.add_input(out.into());
-                pos += 1;
-            } else {
-                error!("cannot find previous outpoint, bad data");
-                return Err(());
             }
         }
         output_block.add_tx(output_tx);
     }
-    Ok(output_block)
+    if partial {
+        bad_data.record_partial_block(height);
+    }
+    Ok(Some(output_block))
 }
 
+///
+/// Only compute txid when consistency check is enabled.
+///
 #[inline(always)]
-#[cfg(feature = "on-disk-utxo")]
-fn txo_key(txid: Txid, n: u32) -> Vec<u8> {
-    let mut bytes = Vec::with_capacity(KEY_LENGTH as usize);
-    bytes.extend(txid.into_inner());
-    bytes.extend(n.to_ne_bytes());
-    bytes
+fn output_tx_id(tx: &bitcoin::Transaction, recorder: &Option<ConsistencyRecorder>) -> Txid {
+    if recorder.is_some() {
+        tx.txid()
+    } else {
+        Txid::default()
+    }
 }
 
+///
+/// Record the input if it spends an output created later than `height`.
+///
 #[inline(always)]
-#[cfg(feature = "on-disk-utxo")]
-fn txo_to_u8(txo: &TxOut) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    txo.consensus_encode(&mut bytes).unwrap();
-    bytes
+fn check_chronology(
+    recorder: &Option<ConsistencyRecorder>,
+    height: usize,
+    txid: Txid,
+    outpoint: &OutPoint,
+    created_height: u32,
+) {
+    if let Some(recorder) = recorder {
+        if created_height as usize > height {
+            recorder.record(ChronologyViolation {
+                height,
+                txid,
+                outpoint: *outpoint,
+                kind: ViolationKind::SpendsLaterOutput {
+                    created_height: created_height as usize,
+                },
+            });
+        }
+    }
 }
 
 #[inline(always)]
-#[cfg(feature = "on-disk-utxo")]
-fn txo_from_u8(bytes: &[u8]) -> Option<TxOut> {
-    match TxOut::consensus_decode(bytes) {
-        Ok(txo) => Some(txo),
-        Err(_) => None,
+fn record_missing(
+    recorder: &Option<ConsistencyRecorder>,
+    height: usize,
+    txid: Txid,
+    outpoint: &OutPoint,
+) {
+    if let Some(recorder) = recorder {
+        recorder.record(ChronologyViolation {
+            height,
+            txid,
+            outpoint: *outpoint,
+            kind: ViolationKind::MissingPrevout,
+        });
     }
 }
@@ -0,0 +1,179 @@
+//!
+//! Bound the number of huge blocks in flight in an iterator.
+//!
+//! Queue lengths bound the number of blocks between pipeline stages,
+//! not their size: a run of 4 MB blocks in the reorder buffers can use
+//! several times the memory of an average run. `HugeBlockGate` admits
+//! blocks in height order, and lets a huge block be read only while
+//! fewer than `max_in_flight` huge blocks are read but not yet consumed.
+//!
+//! Admission is in height order so that the next block to be consumed
+//! never waits for permits held by higher blocks (which would deadlock).
+//!
+use std::sync::{Arc, Condvar, Mutex};
+
+///
+/// Limit on huge blocks in flight (`ConnectedBlockIterOptions::huge_blocks`).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HugeBlockLimit {
+    /// blocks of at least this serialized size (in bytes) are huge
+    pub min_size: u32,
+    /// maximum number of huge blocks read but not yet consumed (at least 1)
+    pub max_in_flight: usize,
+}
+
+impl Default for HugeBlockLimit {
+    /// at most 2 blocks larger than 1 MB in flight
+    fn default() -> Self {
+        HugeBlockLimit {
+            min_size: 1_000_000,
+            max_in_flight: 2,
+        }
+    }
+}
+
+struct GateState {
+    /// height admitted next
+    next: usize,
+    in_flight: usize,
+    /// set when the iterator is dropped, admits everything
+    closed: bool,
+}
+
+struct GateInner {
+    limit: HugeBlockLimit,
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+#[derive(Clone)]
+pub(crate) struct HugeBlockGate(Arc<GateInner>);
+
+///
+/// Held by a huge block until it is consumed.
+///
+pub(crate) struct HugeBlockPermit(HugeBlockGate);
+
+impl HugeBlockGate {
+    ///
+    /// Gate of an iteration starting at height `start`,
+    /// which must then admit every height in order.
+    ///
+    pub(crate) fn new(limit: HugeBlockLimit, start: usize) -> Self {
+        HugeBlockGate(Arc::new(GateInner {
+            limit,
+            state: Mutex::new(GateState {
+                next: start,
+                in_flight: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }))
+    }
+
+    ///
+    /// Block until all lower heights are admitted, and, if the block
+    /// is huge, until a permit is available.
+    ///
+    /// Returns the permit of a huge block.
+    ///
+    pub(crate) fn admit(&self, height: usize, size: u32) -> Option<HugeBlockPermit> {
+        let huge = size >= self.0.limit.min_size;
+        let max = self.0.limit.max_in_flight.max(1);
+        let mut state = self.0.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if state.next == height && (!huge || state.in_flight < max) {
+                break;
+            }
+            state = self.0.changed.wait(state).unwrap();
+        }
+        state.next += 1;
+        if huge {
+            state.in_flight += 1;
+        }
+        self.0.changed.notify_all();
+        if huge {
+            Some(HugeBlockPermit(self.clone()))
+        } else {
+            None
+        }
+    }
+
+    ///
+    /// Release all waiting workers (the iterator is dropped).
+    ///
+    pub(crate) fn close(&self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Drop for HugeBlockPermit {
+    fn drop(&mut self) {
+        let inner = &(self.0).0;
+        inner.state.lock().unwrap().in_flight -= 1;
+        inner.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_huge_block_gate() {
+        let limit = HugeBlockLimit {
+            min_size: 100,
+            max_in_flight: 2,
+        };
+        let gate = HugeBlockGate::new(limit, 10);
+        let admitted = Arc::new(AtomicUsize::new(0));
+        // heights requested in reverse order are admitted in order
+        let handles: Vec<_> = (10..16)
+            .rev()
+            .map(|height| {
+                let gate = gate.clone();
+                let admitted = admitted.clone();
+                thread::spawn(move || {
+                    let permit = gate.admit(height, 1000);
+                    let order = admitted.fetch_add(1, Ordering::SeqCst);
+                    if permit.is_some() {
+                        assert_eq!(order, height - 10);
+                    }
+                    permit
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(admitted.load(Ordering::SeqCst), 2);
+
+        // consuming (dropping) a huge block admits the next one
+        let mut handles = handles.into_iter().rev();
+        let first = handles.next().unwrap().join().unwrap();
+        assert!(first.is_some());
+        drop(first);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(admitted.load(Ordering::SeqCst), 3);
+
+        // closing releases waiting workers
+        gate.close();
+        let permits: Vec<_> = handles.map(|h| h.join().unwrap()).collect();
+        assert_eq!(admitted.load(Ordering::SeqCst), 6);
+        assert_eq!(permits.iter().filter(|p| p.is_some()).count(), 2);
+    }
+
+    #[test]
+    fn test_small_blocks_not_limited() {
+        let gate = HugeBlockGate::new(HugeBlockLimit::default(), 0);
+        let permits: Vec<_> = (0..100).map(|h| gate.admit(h, 1000)).collect();
+        assert!(permits.iter().all(|p| p.is_none()));
+        assert!(gate.admit(100, 2_000_000).is_some());
+    }
+}
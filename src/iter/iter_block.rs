@@ -2,11 +2,41 @@
 //! View development note of iter_connected.rs for implementation
 //! details of iter_block.rs, which follows similar principles.
 //!
+//! `with_options` with `buffer: 0` sizes its buffer by the average size
+//! of the blocks read (see `AdaptiveBuffer`): many small early blocks
+//! are buffered, and only a few of the recent large ones.
+//!
+//! With feature `mmap`, each task of the workers is a run of
+//! consecutive heights stored in the same blk file, so that a worker
+//! reads neighbouring pages of one memory map.
+//!
 use crate::api::BitcoinDB;
+use crate::iter::error::IterError;
+use crate::iter::par_iter::{
+    par_map_ordered, par_map_sized, AdaptiveBuffer, CancelHandle, ParIter, ParMapOptions,
+};
 use bitcoin::Block;
-use par_iter_sync::{IntoParallelIteratorSync, ParIterSync};
+use log::error;
+use par_iter_sync::IntoParallelIteratorSync;
+#[cfg(feature = "mmap")]
+use std::iter::Peekable;
+
+type BlockInner<TBlock> = Box<dyn Iterator<Item = Result<TBlock, IterError>> + Send>;
+
+/// a block with its serialized size
+type SizedBlock<TBlock> = Result<(TBlock, usize), IterError>;
 
-pub struct BlockIter<TBlock>(ParIterSync<TBlock>);
+///
+/// Iterate through blocks of given heights.
+///
+/// The iteration ends at the first height that cannot be read:
+/// use `results` or `error` to tell a failure from the end of the heights.
+///
+pub struct BlockIter<TBlock> {
+    inner: BlockInner<TBlock>,
+    cancel: CancelHandle,
+    error: Option<IterError>,
+}
 
 impl<TBlock> BlockIter<TBlock>
 where
@@ -19,12 +49,63 @@ where
         <T as IntoIterator>::IntoIter: Send + 'static,
     {
         let db_ref = db.clone();
-        BlockIter(
-            heights.into_par_iter_sync(move |h| match db_ref.get_block::<TBlock>(h) {
-                Ok(blk) => Ok(blk),
-                Err(_) => Err(()),
-            }),
-        )
+        // errors are yielded as items, the consumer stops at the first one
+        #[cfg(feature = "mmap")]
+        let inner: BlockInner<TBlock> = Box::new(
+            FileRuns::new(db, heights)
+                .into_par_iter_sync(move |run| Ok::<_, ()>(read_run(&db_ref, run)))
+                .flatten()
+                .map(without_size),
+        );
+        #[cfg(not(feature = "mmap"))]
+        let inner: BlockInner<TBlock> = Box::new(
+            heights
+                .into_par_iter_sync(move |h| Ok::<_, ()>(read_block(&db_ref, h)))
+                .map(without_size),
+        );
+        BlockIter {
+            inner,
+            cancel: CancelHandle::new(),
+            error: None,
+        }
+    }
+
+    ///
+    /// Same as `new`, with worker threads and queue length of `options`.
+    ///
+    /// With `options.buffer == 0`, the queue holds about 256 MB of blocks
+    /// (`AdaptiveBuffer::default()`) rather than `2 * threads` blocks.
+    ///
+    /// The worker threads are dispatched in this constructor!
+    ///
+    pub fn with_options<T>(db: &BitcoinDB, heights: T, options: ParMapOptions) -> Self
+    where
+        T: IntoIterator<Item = usize>,
+        <T as IntoIterator>::IntoIter: Send + 'static,
+    {
+        let db_ref = db.clone();
+        #[cfg(feature = "mmap")]
+        {
+            let runs = FileRuns::new(db, heights);
+            let size =
+                |run: &Vec<SizedBlock<TBlock>>| -> usize { run.iter().map(block_size).sum() };
+            let inner = par_read(runs, move |run| read_run(&db_ref, run), size, options);
+            BlockIter {
+                cancel: inner.cancel_handle(),
+                inner: Box::new(inner.flatten().map(without_size)),
+                error: None,
+            }
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            let read = move |h| read_block(&db_ref, h);
+            let inner = par_read(heights, read, block_size, options);
+            BlockIter {
+                cancel: inner.cancel_handle(),
+                inner: Box::new(inner.map(without_size)),
+                error: None,
+            }
+        }
     }
 
     /// the worker threads are dispatched in this `new` constructor!
@@ -37,10 +118,205 @@ where
     }
 }
 
-impl<TBlock> Iterator for BlockIter<TBlock> {
+///
+/// `par_map_ordered`, or `par_map_sized` by the size of the blocks
+/// if `options.buffer` is `0`.
+///
+fn par_read<T, F, R, S>(items: T, f: F, size: S, options: ParMapOptions) -> ParIter<R>
+where
+    T: IntoIterator,
+    T::IntoIter: Send + 'static,
+    T::Item: Send + 'static,
+    F: Fn(T::Item) -> R + Send + Sync + 'static,
+    R: Send + 'static,
+    S: Fn(&R) -> usize + Send + Sync + 'static,
+{
+    if options.buffer == 0 {
+        par_map_sized(items, f, size, options, AdaptiveBuffer::default())
+    } else {
+        par_map_ordered(items, f, options)
+    }
+}
+
+fn read_block<TBlock: From<Block>>(db: &BitcoinDB, height: usize) -> SizedBlock<TBlock> {
+    db.get_block_sized::<TBlock>(height)
+        .map_err(|e| IterError::new(height, format!("cannot read block: {}", e)))
+}
+
+fn block_size<TBlock>(block: &SizedBlock<TBlock>) -> usize {
+    block.as_ref().map_or(0, |(_, size)| *size)
+}
+
+fn without_size<TBlock>(block: SizedBlock<TBlock>) -> Result<TBlock, IterError> {
+    block.map(|(block, _)| block)
+}
+
+/// most heights of a run of `FileRuns`
+#[cfg(feature = "mmap")]
+const MAX_RUN: usize = 8;
+
+///
+/// Runs of consecutive heights stored in the same blk file
+/// (at most `MAX_RUN` heights each).
+///
+#[cfg(feature = "mmap")]
+struct FileRuns<I: Iterator<Item = usize>> {
+    db: BitcoinDB,
+    heights: Peekable<I>,
+}
+
+#[cfg(feature = "mmap")]
+impl<I: Iterator<Item = usize>> FileRuns<I> {
+    fn new<T: IntoIterator<IntoIter = I>>(db: &BitcoinDB, heights: T) -> Self {
+        FileRuns {
+            db: db.clone(),
+            heights: heights.into_iter().peekable(),
+        }
+    }
+
+    fn n_file(&self, height: usize) -> Option<i32> {
+        self.db.block_index.records.get(height).map(|r| r.n_file)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl<I: Iterator<Item = usize>> Iterator for FileRuns<I> {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Vec<usize>> {
+        let first = self.heights.next()?;
+        let n_file = self.n_file(first);
+        let mut run = vec![first];
+        while run.len() < MAX_RUN {
+            match self.heights.peek().copied() {
+                Some(h) if self.n_file(h) == n_file => run.push(h),
+                _ => break,
+            }
+            self.heights.next();
+        }
+        Some(run)
+    }
+}
+
+///
+/// Read the blocks of a run, stopping at the first error.
+///
+#[cfg(feature = "mmap")]
+fn read_run<TBlock: From<Block>>(db: &BitcoinDB, run: Vec<usize>) -> Vec<SizedBlock<TBlock>> {
+    let mut blocks = Vec::with_capacity(run.len());
+    for h in run {
+        let block = read_block(db, h);
+        let failed = block.is_err();
+        blocks.push(block);
+        if failed {
+            break;
+        }
+    }
+    blocks
+}
+
+impl<TBlock: 'static> BlockIter<TBlock> {
+    ///
+    /// Next block, or the error that ends the iteration
+    /// (`None` after the error, or after the last height).
+    ///
+    pub fn next_result(&mut self) -> Option<Result<TBlock, IterError>> {
+        if self.error.is_some() {
+            return None;
+        }
+        if self.cancel.is_cancelled() {
+            self.inner = Box::new(std::iter::empty());
+            return None;
+        }
+        match self.inner.next()? {
+            Ok(block) => Some(Ok(block)),
+            Err(e) => {
+                self.error = Some(e.clone());
+                // stop the workers
+                self.inner = Box::new(std::iter::empty());
+                Some(Err(e))
+            }
+        }
+    }
+
+    ///
+    /// Iterate through `Result`s of blocks, ending with the error if any.
+    ///
+    pub fn results(&mut self) -> impl Iterator<Item = Result<TBlock, IterError>> + '_ {
+        std::iter::from_fn(move || self.next_result())
+    }
+
+    ///
+    /// The error that ended the iteration, `None` if there was none (yet).
+    ///
+    pub fn error(&self) -> Option<&IterError> {
+        self.error.as_ref()
+    }
+
+    ///
+    /// Handle to stop this iterator early, e.g. from another thread.
+    ///
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+}
+
+impl<TBlock: 'static> Iterator for BlockIter<TBlock> {
     type Item = TBlock;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.next()
+        match self.next_result()? {
+            Ok(block) => Some(block),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iter::{BlockIter, ParMapOptions};
+    use crate::testutil::{SyntheticChain, TestDb};
+    use crate::SBlock;
+
+    #[test]
+    fn test_block_iter_error() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 3);
+        let db = TestDb::new("block_iter_error", &chain);
+
+        // height 9 does not exist
+        let mut iter = db.iter_heights::<SBlock, _>(vec![0, 1, 9, 2]);
+        let results: Vec<_> = iter.results().collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2].as_ref().unwrap_err().height, 9);
+        assert_eq!(iter.error().unwrap().height, 9);
+        assert!(iter.next().is_none());
+
+        let options = ParMapOptions {
+            threads: 2,
+            buffer: 2,
+        };
+        let mut iter = BlockIter::<SBlock>::with_options(&db, vec![3, 9], options);
+        assert!(iter.next().is_some());
+        assert!(iter.next().is_none());
+        assert_eq!(iter.error().unwrap().height, 9);
+
+        // buffer sized by the block sizes
+        let adaptive = ParMapOptions {
+            threads: 2,
+            buffer: 0,
+        };
+        let iterated: Vec<SBlock> = BlockIter::with_options(&db, 0..4, adaptive).collect();
+        assert_eq!(iterated, db.iter_block::<SBlock>(0, 4).collect::<Vec<_>>());
+
+        let mut iter = db.iter_block_with_options::<SBlock>(0, 4, options);
+        let cancel = iter.cancel_handle();
+        assert!(iter.next().is_some());
+        cancel.cancel();
+        assert!(iter.next().is_none());
+        assert!(iter.error().is_none());
     }
 }
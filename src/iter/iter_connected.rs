@@ -1,37 +1,268 @@
 use crate::api::BitcoinDB;
-use crate::iter::fetch_connected_async::{connect_outpoints, update_unspent_cache};
-#[cfg(not(feature = "on-disk-utxo"))]
-use crate::iter::util::VecMap;
+use crate::iter::consistency::{ConsistencyRecorder, ConsistencyReport};
+use crate::iter::coordinator::{IterRegistration, MemoryProfile, ResourceCoordinator};
+use crate::iter::error::IterError;
+use crate::iter::fetch_connected_async::{connect_outpoints, update_unspent_cache, BadDataHandler};
+use crate::iter::huge_blocks::{HugeBlockGate, HugeBlockLimit, HugeBlockPermit};
+use crate::iter::par_iter::{par_map_ordered, CancelHandle, ParMapOptions};
+use crate::iter::tee::{tee, TeeIter, TEE_BUFFER_SIZE};
+use crate::parser::errors::{OpError, OpResult};
 use crate::parser::proto::connected_proto::ConnectedBlock;
+use crate::parser::script::with_address_network;
 #[cfg(not(feature = "on-disk-utxo"))]
-use crate::parser::proto::connected_proto::ConnectedTx;
-#[cfg(not(feature = "on-disk-utxo"))]
-use bitcoin::Txid;
-#[cfg(not(feature = "on-disk-utxo"))]
-use hash_hasher::HashedMap;
+use crate::utxo::store::default_store;
+use crate::utxo::store::{
+    load_utxo_snapshot, read_utxo_snapshot_info, write_utxo_snapshot, UtxoSnapshotInfo, UtxoStore,
+};
 #[cfg(feature = "on-disk-utxo")]
+use crate::utxo::store::{RocksDbPreset, RocksDbUtxoStore};
+use bitcoin::{Block, BlockHash};
 use log::error;
+use par_iter_sync::IntoParallelIteratorSync;
+use std::ops::Range;
+use std::path::Path;
 #[cfg(feature = "on-disk-utxo")]
-use num_cpus;
-use par_iter_sync::{IntoParallelIteratorSync, ParIterSync};
-#[cfg(feature = "on-disk-utxo")]
-use rocksdb::{Options, SliceTransform, DB};
+use std::path::PathBuf;
 use std::sync::Arc;
-#[cfg(not(feature = "on-disk-utxo"))]
-use std::sync::Mutex;
-#[cfg(feature = "on-disk-utxo")]
-use tempdir::TempDir;
 
-/// 32 (txid) + 4 (i32 out n)
+///
+/// Options of `ConnectedBlockIter`.
+///
+#[derive(Debug, Clone, Default)]
+pub struct ConnectedBlockIterOptions {
+    ///
+    /// Check that every input spends an output created at a lower or equal height,
+    /// and record violations (and missing prevouts) in a `ConsistencyReport`,
+    /// retrieved by `ConnectedBlockIter::consistency_report()`.
+    ///
+    pub strict_chronology: bool,
+    ///
+    /// Check the BIP141 witness commitment of every block, and record
+    /// mismatching heights in the `ConsistencyReport`.
+    ///
+    pub verify_witness_commitment: bool,
+    ///
+    /// What to do when a block cannot be read or an input cannot be connected.
+    /// Skipped and partial heights are reported in the `ConsistencyReport`.
+    ///
+    pub on_bad_data: OnBadData,
+    ///
+    /// Memory profile of this iterator,
+    /// `None` for the profile of `ResourceCoordinator::global()`.
+    ///
+    pub memory_profile: Option<MemoryProfile>,
+    ///
+    /// Limit the number of huge blocks read but not yet consumed,
+    /// independent of queue lengths, `None` for no limit.
+    ///
+    pub huge_blocks: Option<HugeBlockLimit>,
+    ///
+    /// Worker threads and queue length of each stage (read and connect),
+    /// `None` for the defaults of the memory profile.
+    ///
+    pub par_map: Option<ParMapOptions>,
+    ///
+    /// Directory of the RocksDB UTXO cache (~10 GB for mainnet),
+    /// `None` for a temporary directory of the system.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub cache_dir: Option<PathBuf>,
+    ///
+    /// Keep the cache in `cache_dir` after iteration (otherwise it is
+    /// created in a temporary subdirectory, deleted when the iterator drops).
+    ///
+    /// A kept cache records the height it is complete at,
+    /// once all blocks of the iterator are consumed, and checkpoints
+    /// every 1000 blocks while iterating.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub keep_cache: bool,
+    ///
+    /// Reopen the kept cache in `cache_dir` and continue from the height it
+    /// is complete at: `ConnectedBlockIter::with_options` then starts at that
+    /// height, and `new_range_with_options` requires it as `start`.
+    ///
+    /// Without it, an existing cache in `cache_dir` is refused.
+    /// A cache of an interrupted iteration is rolled back to its last
+    /// checkpoint (see `RocksDbUtxoStore::roll_back`).
+    /// A cache not on the current main chain is refused: delete it to rebuild.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub reuse_existing: bool,
+    ///
+    /// Tuning of the RocksDB cache, `None` for `RocksDbPreset::default()`.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub rocksdb_tuning: Option<RocksDbPreset>,
+}
+
+///
+/// Blocks between checkpoints of a kept cache: an interrupted iteration
+/// is rolled back to the last one.
+///
 #[cfg(feature = "on-disk-utxo")]
-pub(crate) const KEY_LENGTH: u32 = 32 + 4;
+const KEPT_CACHE_CHECKPOINT_INTERVAL: usize = 1000;
+
+/// worker threads of each stage with `MemoryProfile::Low`
+const LOW_MEMORY_THREADS: usize = 2;
 
+/// blocks queued after each stage with `MemoryProfile::Low`
+const LOW_MEMORY_BUFFER: usize = 4;
+
+///
+/// Policy for blocks that cannot be read (e.g. a damaged region of a blk file)
+/// or that spend outputs missing from the UTXO cache.
+///
+/// Note that outputs of a skipped block are never added to the UTXO cache,
+/// so later blocks spending them will be skipped (or truncated) as well.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnBadData {
+    /// stop the iteration at the first bad block (default)
+    #[default]
+    Abort,
+    /// leave the bad block out and continue
+    SkipBlock,
+    /// yield blocks with unconnectable inputs omitted,
+    /// and leave out blocks that cannot be read
+    YieldPartial,
+}
+
+/// a block (`None` if skipped), and its permit if huge
+type ConnectedItem<TBlock> = (Option<TBlock>, Option<HugeBlockPermit>);
+
+type ConnectedInner<TBlock> =
+    Box<dyn Iterator<Item = Result<ConnectedItem<TBlock>, IterError>> + Send>;
+
+/// a block read and added to the UTXO cache, to be connected
+type ReadItem = (usize, Option<Block>, Option<HugeBlockPermit>);
+
+///
 /// iterate through blocks, and connecting outpoints.
-pub struct ConnectedBlockIter<TBlock> {
-    inner: ParIterSync<TBlock>,
-    #[cfg(feature = "on-disk-utxo")]
+///
+/// The iteration ends at the first block that cannot be read or connected
+/// (see `OnBadData`): use `results` or `error` to tell a failure
+/// from the end of the range.
+///
+pub struct ConnectedBlockIter<TBlock: 'static> {
+    inner: ConnectedInner<TBlock>,
+    gate: Option<HugeBlockGate>,
+    cancel: CancelHandle,
+    error: Option<IterError>,
+    recorder: Option<ConsistencyRecorder>,
     #[allow(dead_code)]
-    cache: Option<TempDir>,
+    registration: Option<IterRegistration>,
+    /// kept to write snapshots
+    store: Option<Arc<dyn UtxoStore>>,
+    /// heights to go through, and number of heights gone through
+    heights: Range<usize>,
+    processed: usize,
+    /// hash of block `heights.end - 1`
+    tip: BlockHash,
+    /// kept cache, its state is recorded when the iteration completes
+    #[cfg(feature = "on-disk-utxo")]
+    kept_cache: Option<KeptCache>,
+}
+
+///
+/// Kept cache of an iterator, checkpointed while iterating.
+///
+#[cfg(feature = "on-disk-utxo")]
+struct KeptCache {
+    store: Arc<RocksDbUtxoStore>,
+    /// hashes of checkpoints
+    db: BitcoinDB,
+}
+
+///
+/// UTXO store of `ConnectedBlockIterOptions`.
+///
+struct OpenedStore {
+    store: Arc<dyn UtxoStore>,
+    #[cfg(feature = "on-disk-utxo")]
+    kept: Option<Arc<RocksDbUtxoStore>>,
+    /// height the store is complete at, `0` for a new store
+    height: usize,
+}
+
+impl OpenedStore {
+    #[cfg(feature = "on-disk-utxo")]
+    fn open(db: &BitcoinDB, options: &ConnectedBlockIterOptions) -> OpResult<Self> {
+        let preset = options.rocksdb_tuning.unwrap_or_default();
+        let dir = match &options.cache_dir {
+            Some(dir) => dir,
+            None if options.keep_cache || options.reuse_existing => {
+                return Err(OpError::from(
+                    "keep_cache and reuse_existing require a cache_dir",
+                ))
+            }
+            None => {
+                return Ok(OpenedStore {
+                    store: Arc::new(RocksDbUtxoStore::temporary_with_preset(preset)?),
+                    kept: None,
+                    height: 0,
+                })
+            }
+        };
+        std::fs::create_dir_all(dir)?;
+        if !options.keep_cache {
+            if options.reuse_existing {
+                return Err(OpError::from("reuse_existing requires keep_cache"));
+            }
+            return Ok(OpenedStore {
+                store: Arc::new(RocksDbUtxoStore::temporary_in(dir, preset)?),
+                kept: None,
+                height: 0,
+            });
+        }
+        let is_new = dir.read_dir()?.next().is_none();
+        if !is_new && !options.reuse_existing {
+            return Err(OpError::from(
+                format!(
+                    "{} is not empty, set reuse_existing to continue from its UTXO cache",
+                    dir.display()
+                )
+                .as_str(),
+            ));
+        }
+        let kept = Arc::new(RocksDbUtxoStore::open(dir, preset)?);
+        let state = match kept.state()? {
+            // interrupted iteration
+            None if !is_new => kept.roll_back()?,
+            state => state,
+        };
+        let height = match state {
+            _ if is_new => 0,
+            Some((height, tip)) if tip == tip_hash(db, height) => height,
+            Some((height, _)) => {
+                return Err(OpError::from(
+                    format!(
+                        "UTXO cache at height {} is not on the current main chain, delete it to rebuild",
+                        height
+                    )
+                    .as_str(),
+                ))
+            }
+            None => {
+                return Err(OpError::from(
+                    "UTXO cache is incomplete (interrupted iteration without checkpoint), delete it to rebuild",
+                ))
+            }
+        };
+        Ok(OpenedStore {
+            store: kept.clone(),
+            kept: Some(kept),
+            height,
+        })
+    }
+
+    #[cfg(not(feature = "on-disk-utxo"))]
+    fn open(_db: &BitcoinDB, _options: &ConnectedBlockIterOptions) -> OpResult<Self> {
+        Ok(OpenedStore {
+            store: default_store()?,
+            height: 0,
+        })
+    }
 }
 
 impl<TBlock> ConnectedBlockIter<TBlock>
@@ -40,86 +271,587 @@ where
 {
     /// the worker threads are dispatched in this `new` constructor!
     pub fn new(db: &BitcoinDB, end: usize) -> Self {
-        // UTXO cache
-        #[cfg(not(feature = "on-disk-utxo"))]
-        let unspent: Arc<
-            Mutex<HashedMap<Txid, Arc<Mutex<VecMap<<TBlock::Tx as ConnectedTx>::TOut>>>>>,
-        > = Arc::new(Mutex::new(HashedMap::default()));
-        #[cfg(feature = "on-disk-utxo")]
-        let cache_dir = {
-            match TempDir::new("rocks_db") {
-                Ok(tempdir) => tempdir,
-                Err(e) => {
-                    error!("failed to create rocksDB tempdir for UTXO: {}", e);
-                    return ConnectedBlockIter::null();
+        ConnectedBlockIter::with_options(db, end, ConnectedBlockIterOptions::default())
+    }
+
+    ///
+    /// Iterate through blocks `start..end`, starting from the UTXO snapshot
+    /// written by `write_snapshot` at height `start`
+    /// (no snapshot is needed from the genesis block).
+    ///
+    /// Fails if the snapshot is missing, not at height `start`,
+    /// or not on the current main chain.
+    /// The worker threads are dispatched in this constructor!
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, ConnectedBlockIter, SConnectedBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    /// let snapshot = Path::new("/Users/me/utxo_600000");
+    ///
+    /// // first run: process blocks up to 600000 and checkpoint
+    /// let mut iter = ConnectedBlockIter::<SConnectedBlock>::new_range(&db, 0, 600000, None).unwrap();
+    /// for block in &mut iter {
+    ///     // ...
+    /// }
+    /// iter.write_snapshot(snapshot).unwrap();
+    ///
+    /// // later: resume from the checkpoint
+    /// let iter = ConnectedBlockIter::<SConnectedBlock>::new_range(&db, 600000, 700000, Some(snapshot)).unwrap();
+    /// for block in iter {
+    ///     // ...
+    /// }
+    /// ```
+    ///
+    pub fn new_range(
+        db: &BitcoinDB,
+        start: usize,
+        end: usize,
+        snapshot: Option<&Path>,
+    ) -> OpResult<Self> {
+        ConnectedBlockIter::new_range_with_options(
+            db,
+            start,
+            end,
+            snapshot,
+            ConnectedBlockIterOptions::default(),
+        )
+    }
+
+    ///
+    /// Same as `new_range`, with options.
+    ///
+    /// The worker threads are dispatched in this constructor!
+    ///
+    pub fn new_range_with_options(
+        db: &BitcoinDB,
+        start: usize,
+        end: usize,
+        snapshot: Option<&Path>,
+        options: ConnectedBlockIterOptions,
+    ) -> OpResult<Self> {
+        if start > end {
+            return Err(OpError::from("invalid block range"));
+        }
+        let opened = OpenedStore::open(db, &options)?;
+        match snapshot {
+            Some(dir) => {
+                if opened.height != 0 {
+                    return Err(OpError::from(
+                        "cannot load a UTXO snapshot into a reused UTXO cache",
+                    ));
+                }
+                let info = read_utxo_snapshot_info(dir)?;
+                if info.height != start {
+                    return Err(OpError::from(
+                        format!("UTXO snapshot is at height {}, not {}", info.height, start)
+                            .as_str(),
+                    ));
+                }
+                if info.tip != tip_hash(db, start) {
+                    return Err(OpError::from(
+                        "UTXO snapshot is not on the current main chain",
+                    ));
                 }
+                load_utxo_snapshot(opened.store.as_ref(), dir)?;
+            }
+            None if start != opened.height && opened.height != 0 => {
+                return Err(OpError::from(
+                    format!("UTXO cache is at height {}, not {}", opened.height, start).as_str(),
+                ))
+            }
+            None if start != 0 => {
+                return Err(OpError::from(
+                    "a UTXO snapshot is required to start after the genesis block",
+                ))
+            }
+            None => {}
+        }
+        Ok(ConnectedBlockIter::with_opened_store(
+            db,
+            start..end,
+            options,
+            opened,
+        ))
+    }
+
+    /// the worker threads are dispatched in this `with_options` constructor!
+    ///
+    /// With `reuse_existing`, blocks from the height of the reused cache.
+    ///
+    pub fn with_options(db: &BitcoinDB, end: usize, options: ConnectedBlockIterOptions) -> Self {
+        match OpenedStore::open(db, &options) {
+            Ok(opened) if opened.height > end => ConnectedBlockIter::failed(IterError::new(
+                end,
+                format!("UTXO cache is at height {}, after {}", opened.height, end),
+            )),
+            Ok(opened) => {
+                let heights = opened.height..end;
+                ConnectedBlockIter::with_opened_store(db, heights, options, opened)
+            }
+            Err(e) => ConnectedBlockIter::failed(IterError::new(0, e.to_string())),
+        }
+    }
+
+    #[cfg(feature = "on-disk-utxo")]
+    fn with_opened_store(
+        db: &BitcoinDB,
+        heights: Range<usize>,
+        options: ConnectedBlockIterOptions,
+        opened: OpenedStore,
+    ) -> Self {
+        if let Some(kept) = &opened.kept {
+            // until the iteration completes, writes are rolled back to checkpoints
+            let started = kept
+                .set_state(None)
+                .and_then(|_| kept.checkpoint(heights.start, tip_hash(db, heights.start)));
+            if let Err(e) = started {
+                return ConnectedBlockIter::failed(IterError::new(heights.start, e.to_string()));
             }
+        }
+        let mut iter = ConnectedBlockIter::with_store_range(db, heights, options, opened.store);
+        iter.kept_cache = opened.kept.map(|store| KeptCache {
+            store,
+            db: db.clone(),
+        });
+        iter
+    }
+
+    #[cfg(not(feature = "on-disk-utxo"))]
+    fn with_opened_store(
+        db: &BitcoinDB,
+        heights: Range<usize>,
+        options: ConnectedBlockIterOptions,
+        opened: OpenedStore,
+    ) -> Self {
+        ConnectedBlockIter::with_store_range(db, heights, options, opened.store)
+    }
+
+    ///
+    /// Connect outpoints using a custom UTXO store.
+    ///
+    /// The store should be empty.
+    /// The worker threads are dispatched in this constructor!
+    ///
+    pub fn with_store(
+        db: &BitcoinDB,
+        end: usize,
+        options: ConnectedBlockIterOptions,
+        store: Arc<dyn UtxoStore>,
+    ) -> Self {
+        ConnectedBlockIter::with_store_range(db, 0..end, options, store)
+    }
+
+    ///
+    /// Connect outpoints of blocks in `heights` using a custom UTXO store,
+    /// which should hold the outputs unspent before `heights.start`
+    /// (e.g. loaded by `load_utxo_snapshot`).
+    ///
+    /// The worker threads are dispatched in this constructor!
+    ///
+    pub fn with_store_range(
+        db: &BitcoinDB,
+        heights: Range<usize>,
+        options: ConnectedBlockIterOptions,
+        store: Arc<dyn UtxoStore>,
+    ) -> Self {
+        let recorder = if options.strict_chronology
+            || options.verify_witness_commitment
+            || options.on_bad_data != OnBadData::Abort
+        {
+            Some(ConsistencyRecorder::default())
+        } else {
+            None
         };
-        #[cfg(feature = "on-disk-utxo")]
-        let unspent = {
-            let mut options = Options::default();
-            // create table
-            options.create_if_missing(true);
-            // config to more jobs
-            options.set_max_background_jobs(num_cpus::get() as i32);
-            // configure mem-table to a large value (256 MB)
-            options.set_write_buffer_size(0x10000000);
-            // configure l0 and l1 size, let them have the same size (1 GB)
-            options.set_level_zero_file_num_compaction_trigger(4);
-            options.set_max_bytes_for_level_base(0x40000000);
-            // 256MB file size
-            options.set_target_file_size_base(0x10000000);
-            // use a smaller compaction multiplier
-            options.set_max_bytes_for_level_multiplier(4.0);
-            // use 8-byte prefix (2 ^ 64 is far enough for transaction counts)
-            options.set_prefix_extractor(SliceTransform::create_fixed_prefix(8));
-            Arc::new(match DB::open(&options, &cache_dir) {
-                Ok(db) => db,
-                Err(e) => {
-                    error!("failed to create temp rocksDB for UTXO: {}", e);
-                    return ConnectedBlockIter::null();
+        let verify_witness = options.verify_witness_commitment;
+        let range = heights.clone();
+        let tip = tip_hash(db, heights.end);
+        let store_copy = store.clone();
+        let db_copy = db.clone();
+        let unspent = store.clone();
+        let recorder_copy = recorder.clone();
+        let strict_recorder = if options.strict_chronology {
+            recorder.clone()
+        } else {
+            None
+        };
+        let bad_data = BadDataHandler {
+            policy: options.on_bad_data,
+            recorder: recorder.clone(),
+        };
+        let bad_data_copy = bad_data.clone();
+        let gate = options
+            .huge_blocks
+            .map(|limit| HugeBlockGate::new(limit, heights.start));
+        let gate_copy = gate.clone();
+
+        let read = move |height| -> Result<ReadItem, IterError> {
+            // admit before anything can fail, later heights wait for this one
+            let permit = gate_copy.as_ref().and_then(|gate| {
+                let size = db_copy.get_block_location(height).map_or(0, |loc| loc.size);
+                gate.admit(height, size)
+            });
+            let (height, blk) =
+                update_unspent_cache(store.as_ref(), &db_copy, height, &bad_data_copy)?;
+            if let Some(blk) = &blk {
+                if verify_witness && !blk.check_witness_commitment() {
+                    if let Some(recorder) = &recorder_copy {
+                        recorder.record_witness_mismatch(height);
+                    }
                 }
-            })
+            }
+            Ok((height, blk, permit))
+        };
+        let network = db.network();
+        let connect = move |read: Result<ReadItem, IterError>| {
+            let (height, blk, permit) = read?;
+            match blk {
+                Some(blk) => with_address_network(network, || {
+                    connect_outpoints(unspent.as_ref(), &strict_recorder, &bad_data, height, blk)
+                })
+                .map(|blk| (blk, permit)),
+                None => Ok((None, permit)),
+            }
         };
-        // all tasks
-        let heights = 0..end;
-        let db_copy = db.clone();
-        let unspent_copy = unspent.clone();
 
-        let output_iterator = heights
-            .into_par_iter_sync(move |height| {
-                update_unspent_cache::<TBlock>(&unspent_copy, &db_copy, height)
-            })
-            .into_par_iter_sync(move |blk| connect_outpoints(&unspent, blk));
+        // the store is dropped (e.g. cache dir deleted)
+        // when ConnectedBlockIter is dropped
+        let profile = options
+            .memory_profile
+            .unwrap_or_else(|| ResourceCoordinator::global().memory_profile());
+        let par_map = options.par_map.or(match profile {
+            MemoryProfile::Standard => None,
+            // few threads and short queues bound the number of blocks in memory
+            MemoryProfile::Low => Some(ParMapOptions {
+                threads: LOW_MEMORY_THREADS,
+                buffer: LOW_MEMORY_BUFFER,
+            }),
+        });
+        // errors are yielded as items, the consumer stops at the first one
+        let gate_cancel = gate.clone();
+        let (inner, stage_cancel): (ConnectedInner<TBlock>, Option<CancelHandle>) = match par_map {
+            None => (
+                Box::new(
+                    heights
+                        .into_par_iter_sync(move |h| Ok::<_, ()>(read(h)))
+                        .into_par_iter_sync(move |r| Ok::<_, ()>(connect(r))),
+                ),
+                None,
+            ),
+            Some(options) => {
+                let blocks = par_map_ordered(heights, read, options);
+                let connected = par_map_ordered(blocks, connect, options);
+                let cancel = connected.cancel_handle();
+                (Box::new(connected), Some(cancel))
+            }
+        };
+        let cancel = CancelHandle::with_callback(move || {
+            if let Some(gate) = &gate_cancel {
+                gate.close();
+            }
+            if let Some(cancel) = &stage_cancel {
+                cancel.cancel();
+            }
+        });
 
         ConnectedBlockIter {
-            inner: output_iterator,
-            // cache dir will be deleted when ConnectedBlockIter is dropped
+            inner,
+            gate,
+            cancel,
+            error: None,
+            recorder,
+            registration: Some(ResourceCoordinator::global().register_iterator()),
+            store: Some(store_copy),
+            heights: range,
+            processed: 0,
+            tip,
             #[cfg(feature = "on-disk-utxo")]
-            cache: Some(cache_dir),
+            kept_cache: None,
         }
     }
 
-    #[cfg(feature = "on-disk-utxo")]
+    #[cfg(test)]
     fn null() -> Self {
+        ConnectedBlockIter::from_inner(Box::new(std::iter::empty()))
+    }
+
+    /// yields `err` only
+    fn failed(err: IterError) -> Self {
+        ConnectedBlockIter::from_inner(Box::new(std::iter::once(Err(err))))
+    }
+
+    fn from_inner(inner: ConnectedInner<TBlock>) -> Self {
         ConnectedBlockIter {
-            inner: Vec::new().into_par_iter_sync(|_: usize| Err(())),
+            inner,
+            gate: None,
+            cancel: CancelHandle::new(),
+            error: None,
+            recorder: None,
+            registration: None,
+            store: None,
+            heights: 0..0,
+            processed: 0,
+            tip: BlockHash::default(),
             #[cfg(feature = "on-disk-utxo")]
-            cache: None,
+            kept_cache: None,
+        }
+    }
+}
+
+/// hash of the parent of block `height` (all zeros for the genesis block)
+fn tip_hash(db: &BitcoinDB, height: usize) -> BlockHash {
+    height
+        .checked_sub(1)
+        .and_then(|h| db.get_hash_from_height(h).ok())
+        .unwrap_or_default()
+}
+
+impl<TBlock: 'static> ConnectedBlockIter<TBlock> {
+    ///
+    /// Next block, or the error that ends the iteration
+    /// (`None` after the error, or at the end of the range).
+    ///
+    pub fn next_result(&mut self) -> Option<Result<TBlock, IterError>> {
+        if self.error.is_some() {
+            return None;
+        }
+        if self.cancel.is_cancelled() {
+            self.stop();
+            return None;
+        }
+        // skipped blocks are `None`
+        // the permit of a huge block is released once it is consumed
+        loop {
+            match self.inner.next()? {
+                Ok((block, _permit)) => {
+                    self.processed += 1;
+                    #[cfg(feature = "on-disk-utxo")]
+                    if self.processed % KEPT_CACHE_CHECKPOINT_INTERVAL == 0 {
+                        self.checkpoint_kept_cache();
+                    }
+                    if let Some(block) = block {
+                        return Some(Ok(block));
+                    }
+                }
+                Err(e) => {
+                    self.error = Some(e.clone());
+                    self.stop();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    ///
+    /// Iterate through `Result`s of blocks, ending with the error if any.
+    ///
+    /// The iterator is borrowed, so that reports remain available afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SConnectedBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let mut iter = db.iter_connected_block::<SConnectedBlock>(700000);
+    /// for block in iter.results() {
+    ///     match block {
+    ///         Ok(block) => println!("{}", block.txdata.len()),
+    ///         Err(e) => eprintln!("broken at height {}: {}", e.height, e.message),
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn results(&mut self) -> impl Iterator<Item = Result<TBlock, IterError>> + '_ {
+        std::iter::from_fn(move || self.next_result())
+    }
+
+    ///
+    /// The error that ended the iteration, `None` if there was none (yet).
+    ///
+    pub fn error(&self) -> Option<&IterError> {
+        self.error.as_ref()
+    }
+
+    ///
+    /// Handle to stop this iterator early, e.g. from another thread.
+    ///
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// release waiting workers and drop the pipeline
+    fn stop(&mut self) {
+        if let Some(gate) = &self.gate {
+            gate.close();
         }
+        self.inner = Box::new(std::iter::empty());
+    }
+
+    ///
+    /// Violations found so far, if `strict_chronology`
+    /// or `verify_witness_commitment` is enabled,
+    /// or `on_bad_data` is not `OnBadData::Abort`.
+    ///
+    /// Call this after iteration for a complete report.
+    ///
+    pub fn consistency_report(&self) -> Option<ConsistencyReport> {
+        self.recorder.as_ref().map(|r| r.report())
+    }
+
+    ///
+    /// Write the UTXO set after the last block of the range to `dir`,
+    /// to resume from with `new_range`.
+    ///
+    /// Only after all blocks have been consumed, and none was skipped
+    /// or partial. The in-memory and RocksDB stores support snapshots.
+    ///
+    pub fn write_snapshot(&self, dir: &Path) -> OpResult<UtxoSnapshotInfo> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Err(OpError::from("iterator has no UTXO store")),
+        };
+        self.check_complete()?;
+        write_utxo_snapshot(store.as_ref(), self.heights.end, self.tip, dir)
+    }
+
+    ///
+    /// Fails unless all blocks have been consumed, and none was skipped or partial.
+    ///
+    fn check_complete(&self) -> OpResult<()> {
+        if self.processed != self.heights.len() {
+            return Err(OpError::from(
+                format!(
+                    "snapshot requires a complete iteration ({} of {} blocks)",
+                    self.processed,
+                    self.heights.len()
+                )
+                .as_str(),
+            ));
+        }
+        if let Some(report) = self.consistency_report() {
+            if !report.skipped_blocks.is_empty() || !report.partial_blocks.is_empty() {
+                return Err(OpError::from(
+                    "cannot snapshot the UTXO set after skipped or partial blocks",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Record the height of the kept cache if the iteration is complete,
+    /// otherwise it stays incomplete and is refused by `reuse_existing`.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    fn record_kept_cache(&mut self) {
+        if let Some(kept) = self.kept_cache.take() {
+            if self.check_complete().is_ok() {
+                if let Err(e) = kept.store.set_state(Some((self.heights.end, self.tip))) {
+                    error!("failed to record the state of the UTXO cache: {}", e);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Checkpoint the kept cache at the next height to consume:
+    /// all blocks before it are in the cache.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    fn checkpoint_kept_cache(&self) {
+        let kept = match &self.kept_cache {
+            Some(kept) => kept,
+            None => return,
+        };
+        // skipped or partial blocks leave the cache incomplete
+        if let Some(report) = self.consistency_report() {
+            if !report.skipped_blocks.is_empty() || !report.partial_blocks.is_empty() {
+                return;
+            }
+        }
+        let height = self.heights.start + self.processed;
+        if let Err(e) = kept.store.checkpoint(height, tip_hash(&kept.db, height)) {
+            error!("failed to checkpoint the UTXO cache: {}", e);
+        }
+    }
+}
+
+impl<TBlock> ConnectedBlockIter<TBlock>
+where
+    TBlock: 'static + Send + Sync,
+{
+    ///
+    /// Share this iterator among `n` consumers.
+    ///
+    /// Each returned handle receives every block (wrapped in `Arc`), so that
+    /// several analyses can share one expensive connected pass.
+    ///
+    /// Buffering is bounded: the slowest consumer throttles the others,
+    /// so consume the handles in different threads.
+    /// Dropped handles are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::{BitcoinDB, SConnectedBlock};
+    /// use std::path::Path;
+    /// use std::thread;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let mut handles = db.iter_connected_block::<SConnectedBlock>(700000).tee(2);
+    /// let tx_counter = handles.pop().unwrap();
+    /// let input_counter = handles.pop().unwrap();
+    ///
+    /// let t1 = thread::spawn(move || tx_counter.map(|b| b.txdata.len()).sum::<usize>());
+    /// let t2 = thread::spawn(move || {
+    ///     input_counter
+    ///         .map(|b| b.txdata.iter().map(|tx| tx.input.len()).sum::<usize>())
+    ///         .sum::<usize>()
+    /// });
+    /// println!("{} transactions, {} inputs", t1.join().unwrap(), t2.join().unwrap());
+    /// ```
+    ///
+    pub fn tee(self, n: usize) -> Vec<TeeIter<TBlock>> {
+        tee(self, n, TEE_BUFFER_SIZE)
     }
 }
 
-impl<TBlock> Iterator for ConnectedBlockIter<TBlock> {
+impl<TBlock: 'static> Iterator for ConnectedBlockIter<TBlock> {
     type Item = TBlock;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next()
+        match self.next_result()? {
+            Ok(block) => Some(block),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<TBlock: 'static> Drop for ConnectedBlockIter<TBlock> {
+    fn drop(&mut self) {
+        // workers waiting for permits must not block the worker shutdown
+        if let Some(gate) = &self.gate {
+            gate.close();
+        }
+        #[cfg(feature = "on-disk-utxo")]
+        self.record_kept_cache();
     }
 }
 
 #[cfg(test)]
-#[cfg(feature = "on-disk-utxo")]
 mod test_empty {
     use crate::{ConnectedBlockIter, SConnectedBlock};
 
@@ -132,3 +864,265 @@ mod test_empty {
         }
     }
 }
+
+#[cfg(test)]
+mod test_bad_data {
+    use super::ConnectedBlockIter;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use crate::{BitcoinDB, ConnectedBlockIterOptions, OnBadData, SConnectedBlock};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+
+    fn iter_with(db: &BitcoinDB, on_bad_data: OnBadData) -> ConnectedBlockIter<SConnectedBlock> {
+        let options = ConnectedBlockIterOptions {
+            on_bad_data,
+            ..Default::default()
+        };
+        db.iter_connected_block_with_options(4, options)
+    }
+
+    #[test]
+    fn test_on_bad_data() {
+        // block 2 spends an outpoint that never existed
+        let mut chain = SyntheticChain::new();
+        let tip = chain.extend(&chain.genesis(), 1).pop().unwrap();
+        let bad = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([1; 32]), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let bad_block = chain.mine(&tip, vec![bad]);
+        chain.extend(&bad_block, 1);
+        let db = TestDb::new("on_bad_data", &chain);
+
+        let aborted: Vec<SConnectedBlock> = iter_with(&db, OnBadData::Abort).collect();
+        assert_eq!(aborted.len(), 2);
+        let mut aborted = iter_with(&db, OnBadData::Abort);
+        let results: Vec<_> = aborted.results().collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[..2].iter().all(Result::is_ok));
+        let error = results[2].as_ref().unwrap_err();
+        assert_eq!(error.height, 2);
+        assert_eq!(aborted.error(), Some(error));
+        assert!(aborted.next().is_none());
+
+        let mut skip = iter_with(&db, OnBadData::SkipBlock);
+        let hashes: Vec<_> = (&mut skip).map(|b| b.header.block_hash).collect();
+        let main_chain = chain.main_chain();
+        assert_eq!(hashes, vec![main_chain[0], main_chain[1], main_chain[3]]);
+        let report = skip.consistency_report().unwrap();
+        assert_eq!(report.skipped_blocks, vec![2]);
+        assert!(report.partial_blocks.is_empty());
+        assert!(!report.is_consistent());
+
+        let mut partial = iter_with(&db, OnBadData::YieldPartial);
+        let blocks: Vec<SConnectedBlock> = (&mut partial).collect();
+        assert_eq!(blocks.len(), 4);
+        assert!(blocks[2].txdata[1].input.is_empty());
+        let report = partial.consistency_report().unwrap();
+        assert!(report.skipped_blocks.is_empty());
+        assert_eq!(report.partial_blocks, vec![2]);
+    }
+}
+
+#[cfg(test)]
+mod test_low_memory {
+    use crate::iter::{HugeBlockLimit, MemoryProfile};
+    use crate::testutil::{SyntheticChain, TestDb};
+    use crate::{ConnectedBlockIterOptions, SConnectedBlock};
+
+    #[test]
+    fn test_low_memory_profile() {
+        // block 3 spends the coinbase of block 1
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        let spending = chain.mine(&tips[1], vec![spend]);
+        chain.extend(&spending, 5);
+        let db = TestDb::new("low_memory", &chain);
+
+        let iter_with = |profile| {
+            let options = ConnectedBlockIterOptions {
+                memory_profile: Some(profile),
+                ..Default::default()
+            };
+            db.iter_connected_block_with_options::<SConnectedBlock>(db.get_block_count(), options)
+                .map(|b| b.header.block_hash)
+                .collect::<Vec<_>>()
+        };
+        let standard = iter_with(MemoryProfile::Standard);
+        assert_eq!(standard, chain.main_chain());
+        assert_eq!(iter_with(MemoryProfile::Low), standard);
+
+        // every block is huge, one in flight at a time
+        let huge_blocks = Some(HugeBlockLimit {
+            min_size: 0,
+            max_in_flight: 1,
+        });
+        for profile in [MemoryProfile::Standard, MemoryProfile::Low] {
+            let options = ConnectedBlockIterOptions {
+                memory_profile: Some(profile),
+                huge_blocks,
+                ..Default::default()
+            };
+            let mut iter = db.iter_connected_block_with_options::<SConnectedBlock>(
+                db.get_block_count(),
+                options,
+            );
+            let first = iter.next().unwrap();
+            assert_eq!(first.header.block_hash, standard[0]);
+            // dropped while workers wait for the permit
+            drop(iter);
+            let options = ConnectedBlockIterOptions {
+                memory_profile: Some(profile),
+                huge_blocks,
+                ..Default::default()
+            };
+            let hashes: Vec<_> = db
+                .iter_connected_block_with_options::<SConnectedBlock>(db.get_block_count(), options)
+                .map(|b| b.header.block_hash)
+                .collect();
+            assert_eq!(hashes, standard);
+        }
+
+        let options = ConnectedBlockIterOptions {
+            memory_profile: Some(MemoryProfile::Low),
+            ..Default::default()
+        };
+        let blocks: Vec<SConnectedBlock> =
+            db.iter_connected_block_with_options(4, options).collect();
+        assert_eq!(
+            blocks[3].txdata[1].input[0].value,
+            bitcoin::Amount::from_sat(50 * 100_000_000)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_snapshot {
+    use crate::testutil::{SyntheticChain, TestDir};
+    use crate::{BitcoinDB, ConnectedBlockIter, SConnectedBlock};
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        let dir = TestDir::new("resume_snapshot");
+        let snapshot = dir.join("utxo_3");
+
+        // block 4 spends the coinbase of block 1, across the snapshot
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        let spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        let spending = chain.mine(&tips[2], vec![spend]);
+        chain.extend(&spending, 2);
+        chain.write(&dir.join("datadir")).unwrap();
+        let db = BitcoinDB::new(&dir.join("datadir"), false).unwrap();
+
+        let mut first = ConnectedBlockIter::<SConnectedBlock>::new_range(&db, 0, 3, None).unwrap();
+        assert!(first.write_snapshot(&snapshot).is_err());
+        assert_eq!((&mut first).count(), 3);
+        let info = first.write_snapshot(&snapshot).unwrap();
+        assert_eq!((info.height, info.tip), (3, chain.main_chain()[2]));
+
+        let blocks: Vec<SConnectedBlock> = db
+            .iter_connected_block_range(3, 7, Some(&snapshot))
+            .unwrap()
+            .collect();
+        let hashes: Vec<_> = blocks.iter().map(|b| b.header.block_hash).collect();
+        assert_eq!(hashes, chain.main_chain()[3..].to_vec());
+        assert_eq!(
+            blocks[1].txdata[1].input[0].value,
+            bitcoin::Amount::from_sat(50 * 100_000_000)
+        );
+
+        // the snapshot is at height 3 only
+        assert!(db
+            .iter_connected_block_range::<SConnectedBlock>(2, 7, Some(&snapshot))
+            .is_err());
+        assert!(db
+            .iter_connected_block_range::<SConnectedBlock>(3, 7, None)
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "on-disk-utxo")]
+    fn test_kept_cache() {
+        use crate::ConnectedBlockIterOptions;
+
+        let dir = TestDir::new("kept_cache");
+        let cache_dir = dir.join("utxo");
+
+        // block 4 spends the coinbase of block 1, across runs
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        let spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        let spending = chain.mine(&tips[2], vec![spend]);
+        chain.extend(&spending, 2);
+        chain.write(&dir.join("datadir")).unwrap();
+        let db = BitcoinDB::new(&dir.join("datadir"), false).unwrap();
+        let options = |reuse_existing| ConnectedBlockIterOptions {
+            cache_dir: Some(cache_dir.clone()),
+            keep_cache: true,
+            reuse_existing,
+            ..Default::default()
+        };
+        let expected: Vec<SConnectedBlock> = db.iter_connected_block(7).collect();
+
+        let first = ConnectedBlockIter::<SConnectedBlock>::with_options(&db, 3, options(false));
+        assert_eq!(first.count(), 3);
+        // the kept cache is not overwritten
+        assert!(
+            ConnectedBlockIter::<SConnectedBlock>::with_options(&db, 7, options(false))
+                .next()
+                .is_none()
+        );
+
+        // continue at height 3
+        assert!(
+            ConnectedBlockIter::<SConnectedBlock>::new_range_with_options(
+                &db,
+                2,
+                7,
+                None,
+                options(true)
+            )
+            .is_err()
+        );
+        let mut second = ConnectedBlockIter::<SConnectedBlock>::with_options(&db, 5, options(true));
+        let blocks: Vec<SConnectedBlock> = (&mut second).collect();
+        assert_eq!(blocks, expected[3..5].to_vec());
+        assert!(second.error().is_none());
+        drop(second);
+
+        // interrupted at height 6, the cache is rolled back to height 5
+        let mut third = ConnectedBlockIter::<SConnectedBlock>::new_range_with_options(
+            &db,
+            5,
+            7,
+            None,
+            options(true),
+        )
+        .unwrap();
+        assert!(third.next().is_some());
+        drop(third);
+        let blocks: Vec<SConnectedBlock> =
+            ConnectedBlockIter::with_options(&db, 7, options(true)).collect();
+        assert_eq!(blocks, expected[5..7].to_vec());
+
+        // a temporary cache in cache_dir is deleted
+        let temporary = ConnectedBlockIterOptions {
+            cache_dir: Some(dir.join("tmp")),
+            ..Default::default()
+        };
+        let blocks: Vec<SConnectedBlock> =
+            ConnectedBlockIter::with_options(&db, 7, temporary).collect();
+        assert_eq!(blocks, expected);
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+    }
+}
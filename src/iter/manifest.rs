@@ -0,0 +1,353 @@
+//!
+//! Run manifests: machine-readable provenance of an iteration.
+//!
+//! A `RunRecorder` captures the input range, options, crate version and
+//! chain tip of a run. Wrapping an iterator of results in `RecordedIter`
+//! adds the number of items, a checksum of the items, the errors and the
+//! duration, and writes the manifest as JSON when the iteration ends.
+//! Checksums of files derived from the items can be added with
+//! `RunRecorder::record_output`.
+//!
+use crate::api::{BitcoinDB, BlockHash};
+use crate::iter::{IterError, ParMapOptions};
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// version of the run manifest format
+pub const RUN_MANIFEST_VERSION: u32 = 1;
+
+///
+/// Provenance of a run, saved as JSON.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunManifest {
+    /// version of this manifest format, see `RUN_MANIFEST_VERSION`
+    pub manifest_version: u32,
+    /// version of bitcoin-explorer that ran the iteration
+    pub crate_version: String,
+    /// name of the iterator, e.g. `iter_block`
+    pub iterator: String,
+    /// input heights
+    pub start: usize,
+    pub end: usize,
+    /// options of the iterator, and parameters set by the caller
+    pub parameters: BTreeMap<String, String>,
+    /// chain tip when the run started
+    pub tip_height: usize,
+    pub tip_hash: BlockHash,
+    /// unix time of the start of the run, in seconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+    /// number of items produced
+    pub items: u64,
+    /// sha256 of the digests of the items, in order
+    pub items_checksum: String,
+    /// the iteration ended by itself (not dropped early)
+    pub complete: bool,
+    pub errors: Vec<IterError>,
+    /// sha256 of output files, by name
+    pub outputs: BTreeMap<String, String>,
+}
+
+impl RunManifest {
+    pub fn load(path: &Path) -> OpResult<Self> {
+        serde_json::from_reader(File::open(path)?)
+            .map_err(|e| OpError::from(format!("invalid run manifest: {}", e).as_str()))
+    }
+
+    pub fn save(&self, path: &Path) -> OpResult<()> {
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|e| OpError::from(format!("failed to write run manifest: {}", e).as_str()))
+    }
+}
+
+///
+/// Records a run into a `RunManifest`.
+///
+pub struct RunRecorder {
+    manifest: RunManifest,
+    started: Instant,
+    checksum: sha256::HashEngine,
+}
+
+impl RunRecorder {
+    ///
+    /// Start recording a run of `iterator` over heights `range`.
+    ///
+    pub fn new(db: &BitcoinDB, iterator: &str, range: Range<usize>) -> OpResult<Self> {
+        let tip_height = db.get_block_count().saturating_sub(1);
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(RunRecorder {
+            manifest: RunManifest {
+                manifest_version: RUN_MANIFEST_VERSION,
+                crate_version: env!("CARGO_PKG_VERSION").to_string(),
+                iterator: iterator.to_string(),
+                start: range.start,
+                end: range.end,
+                parameters: BTreeMap::new(),
+                tip_height,
+                tip_hash: db.get_hash_from_height(tip_height)?,
+                started_at,
+                duration_ms: 0,
+                items: 0,
+                items_checksum: String::new(),
+                complete: false,
+                errors: Vec::new(),
+                outputs: BTreeMap::new(),
+            },
+            started: Instant::now(),
+            checksum: sha256::Hash::engine(),
+        })
+    }
+
+    ///
+    /// Record a parameter of the run.
+    ///
+    pub fn parameter<V: ToString>(mut self, key: &str, value: V) -> Self {
+        self.manifest
+            .parameters
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    ///
+    /// Record the options of a parallel iterator.
+    ///
+    pub fn options(self, options: &ParMapOptions) -> Self {
+        self.parameter("threads", options.threads)
+            .parameter("buffer", options.buffer)
+    }
+
+    ///
+    /// Record an item produced, by a digest of it (e.g. its hash).
+    ///
+    pub fn record_item(&mut self, digest: &[u8]) {
+        // length prefixed, so that digests cannot run into each other
+        self.checksum.input(&(digest.len() as u32).to_le_bytes());
+        self.checksum.input(digest);
+        self.manifest.items += 1;
+    }
+
+    pub fn record_error(&mut self, error: &IterError) {
+        self.manifest.errors.push(error.clone());
+    }
+
+    ///
+    /// Record the sha256 of output file `path` under `name`.
+    ///
+    pub fn record_output(&mut self, name: &str, path: &Path) -> OpResult<()> {
+        let mut engine = sha256::Hash::engine();
+        io::copy(&mut File::open(path)?, &mut engine)?;
+        self.manifest.outputs.insert(
+            name.to_string(),
+            sha256::Hash::from_engine(engine).to_string(),
+        );
+        Ok(())
+    }
+
+    ///
+    /// The manifest of the run so far.
+    ///
+    pub fn manifest(&self) -> RunManifest {
+        let mut manifest = self.manifest.clone();
+        manifest.duration_ms = self.started.elapsed().as_millis() as u64;
+        manifest.items_checksum = sha256::Hash::from_engine(self.checksum.clone()).to_string();
+        manifest
+    }
+}
+
+///
+/// Iterator of `Result`s (e.g. `BlockIter::results`) recording
+/// its run in a `RunRecorder`.
+///
+/// Yields the items, and ends at the first error (which is recorded).
+/// The manifest is written to the path of `write_to` (if any)
+/// when the iteration ends, or when the iterator is dropped early.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::iter::{RecordedIter, RunRecorder};
+/// use bitcoin_explorer::{BitcoinDB, Block, ToHex};
+/// use std::path::Path;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+///
+/// let run = RunRecorder::new(&db, "iter_block", 0..1000).unwrap();
+/// let mut iter = db.iter_block::<Block>(0, 1000);
+/// let blocks = RecordedIter::new(iter.results(), run, |b: &Block| b.block_hash().to_hex().into_bytes())
+///     .write_to(Path::new("/Users/me/run.json"));
+/// for block in blocks {
+///     // derive a dataset
+/// }
+/// ```
+///
+pub struct RecordedIter<I, F> {
+    inner: I,
+    digest: F,
+    recorder: RunRecorder,
+    path: Option<PathBuf>,
+    ended: bool,
+}
+
+impl<I, F> RecordedIter<I, F> {
+    pub fn new(inner: I, recorder: RunRecorder, digest: F) -> Self {
+        RecordedIter {
+            inner,
+            digest,
+            recorder,
+            path: None,
+            ended: false,
+        }
+    }
+
+    ///
+    /// Write the manifest to `path` when the iteration ends.
+    ///
+    pub fn write_to(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+
+    ///
+    /// The recorder, e.g. to record output files.
+    ///
+    pub fn recorder(&mut self) -> &mut RunRecorder {
+        &mut self.recorder
+    }
+
+    ///
+    /// The manifest of the run so far.
+    ///
+    pub fn manifest(&self) -> RunManifest {
+        let mut manifest = self.recorder.manifest();
+        manifest.complete = self.ended && manifest.errors.is_empty();
+        manifest
+    }
+
+    fn end(&mut self, complete: bool) {
+        self.ended = complete;
+        if let Some(path) = self.path.take() {
+            if let Err(e) = self.manifest().save(&path) {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+impl<I, F, T> Iterator for RecordedIter<I, F>
+where
+    I: Iterator<Item = Result<T, IterError>>,
+    F: FnMut(&T) -> Vec<u8>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.ended {
+            return None;
+        }
+        match self.inner.next() {
+            Some(Ok(item)) => {
+                let digest = (self.digest)(&item);
+                self.recorder.record_item(&digest);
+                Some(item)
+            }
+            Some(Err(e)) => {
+                error!("{}", e);
+                self.recorder.record_error(&e);
+                self.end(true);
+                None
+            }
+            None => {
+                self.end(true);
+                None
+            }
+        }
+    }
+}
+
+impl<I, F> Drop for RecordedIter<I, F> {
+    fn drop(&mut self) {
+        // dropped early: the manifest is still written, as incomplete
+        if !self.ended {
+            self.end(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Block;
+    use crate::testutil::{SyntheticChain, TestDb};
+
+    #[test]
+    fn test_run_manifest() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 5);
+        let db = TestDb::new("run_manifest", &chain);
+        let digest = |b: &Block| b.block_hash().into_inner().to_vec();
+
+        let path = db.dir.join("run.json");
+        let run = RunRecorder::new(&db, "iter_block", 0..6)
+            .unwrap()
+            .options(&ParMapOptions::default())
+            .parameter("purpose", "test");
+        let mut iter = db.iter_block::<Block>(0, 6);
+        let blocks: Vec<Block> = RecordedIter::new(iter.results(), run, digest)
+            .write_to(&path)
+            .collect();
+        assert_eq!(blocks.len(), 6);
+        let manifest = RunManifest::load(&path).unwrap();
+        assert_eq!((manifest.start, manifest.end, manifest.items), (0, 6, 6));
+        assert_eq!(manifest.tip_height, 5);
+        assert_eq!(manifest.tip_hash, db.get_hash_from_height(5).unwrap());
+        assert_eq!(manifest.parameters["purpose"], "test");
+        assert_eq!(manifest.parameters["threads"], "0");
+        assert!(manifest.complete);
+        assert!(manifest.errors.is_empty());
+
+        // same items, same checksum; an error ends the run
+        let run = RunRecorder::new(&db, "iter_heights", 0..9).unwrap();
+        let mut iter = db.iter_heights::<Block, _>(vec![0, 1, 2, 3, 4, 5, 9]);
+        let mut recorded = RecordedIter::new(iter.results(), run, digest);
+        assert_eq!(recorded.by_ref().count(), 6);
+        let output = db.dir.join("output.txt");
+        std::fs::write(&output, b"abc").unwrap();
+        recorded
+            .recorder()
+            .record_output("output", &output)
+            .unwrap();
+        let failed = recorded.manifest();
+        assert_eq!(failed.items_checksum, manifest.items_checksum);
+        assert_eq!(failed.errors.len(), 1);
+        assert_eq!(failed.errors[0].height, 9);
+        assert!(!failed.complete);
+        assert_eq!(
+            failed.outputs["output"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        // dropped early
+        let run = RunRecorder::new(&db, "iter_block", 0..6).unwrap();
+        let mut iter = db.iter_block::<Block>(0, 6);
+        let mut recorded = RecordedIter::new(iter.results(), run, digest).write_to(&path);
+        assert!(recorded.next().is_some());
+        drop(recorded);
+        let manifest = RunManifest::load(&path).unwrap();
+        assert_eq!(manifest.items, 1);
+        assert!(!manifest.complete);
+    }
+}
@@ -2,10 +2,33 @@
 //! This module defines the infrastructure for efficient iteration over blocks
 //!
 
+mod alloc_stats;
+mod consistency;
+mod coordinator;
+mod error;
 mod fetch_connected_async;
+mod huge_blocks;
 mod iter_block;
 mod iter_connected;
-mod util;
+mod manifest;
+mod par_iter;
+mod pool;
+mod side_channel;
+mod tee;
+pub(crate) mod util;
 
+pub(crate) use alloc_stats::enter_stage;
+pub use alloc_stats::{CountingAllocator, PipelineStage, PipelineStats, StageAllocStats};
+pub use consistency::{ChronologyViolation, ConsistencyReport, ViolationKind};
+pub use coordinator::{cgroup_memory_limit, MemoryProfile, ResourceCoordinator, LOW_MEMORY_LIMIT};
+pub use error::IterError;
+pub use huge_blocks::HugeBlockLimit;
 pub use iter_block::BlockIter;
-pub use iter_connected::ConnectedBlockIter;
+pub use iter_connected::{ConnectedBlockIter, ConnectedBlockIterOptions, OnBadData};
+pub use manifest::{RecordedIter, RunManifest, RunRecorder, RUN_MANIFEST_VERSION};
+pub use par_iter::{
+    par_map_ordered, par_map_sized, AdaptiveBuffer, CancelHandle, ParIter, ParMapOptions,
+};
+pub use pool::{pool_stats, recycle_vec, take_vec, PoolStats};
+pub use side_channel::{AuxBlockIter, AuxRecord, AuxSender};
+pub use tee::TeeIter;
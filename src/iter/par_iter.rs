@@ -0,0 +1,636 @@
+//!
+//! A generic ordered parallel map.
+//!
+//! Items of the input iterator are processed by a pool of worker threads,
+//! while results are produced in the original order of the input.
+//!
+//! The number of items in flight is either fixed (`ParMapOptions::buffer`),
+//! or follows the average size of the results (`par_map_sized`).
+//!
+//! A panic in a worker is resumed in the consumer thread,
+//! once the results of all items before the panicked one are consumed.
+//!
+use crate::iter::coordinator::{MemoryProfile, ResourceCoordinator};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+///
+/// Options of `par_map_ordered`.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParMapOptions {
+    /// number of worker threads, `0` for the number of logical cpus
+    pub threads: usize,
+    ///
+    /// maximum number of items taken from the input but not yet consumed,
+    /// bounding memory when the consumer is slower than the workers.
+    /// `0` for `2 * threads`.
+    ///
+    pub buffer: usize,
+}
+
+impl ParMapOptions {
+    pub(crate) fn resolved(&self) -> (usize, usize) {
+        let threads = if self.threads == 0 {
+            num_cpus::get()
+        } else {
+            self.threads
+        };
+        let buffer = if self.buffer == 0 {
+            2 * threads
+        } else {
+            self.buffer
+        };
+        (threads, buffer.max(1))
+    }
+}
+
+///
+/// Buffer of `par_map_sized`, sized by a memory budget: the number of
+/// items taken but not yet consumed is `budget / average result size`
+/// (within `min..=max`), the average following the recent results.
+///
+/// With blocks, the buffer holds many of the small early blocks,
+/// and few of the recent 4M-weight ones.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveBuffer {
+    /// bytes of results in flight
+    pub budget: usize,
+    /// minimum number of items in flight (at least 1)
+    pub min: usize,
+    /// maximum number of items in flight
+    pub max: usize,
+}
+
+impl Default for AdaptiveBuffer {
+    ///
+    /// 256 MB for 2 to 1024 items, 32 MB for 2 to 64 items with `MemoryProfile::Low`,
+    /// within the share of a buffer of the memory limit (if any).
+    ///
+    fn default() -> Self {
+        let coordinator = ResourceCoordinator::global();
+        match coordinator.memory_profile() {
+            MemoryProfile::Standard => AdaptiveBuffer {
+                budget: coordinator.capped(0x10000000),
+                min: 2,
+                max: 1024,
+            },
+            MemoryProfile::Low => AdaptiveBuffer {
+                budget: coordinator.capped(0x2000000),
+                min: 2,
+                max: 64,
+            },
+        }
+    }
+}
+
+/// weight of a new size in the average (1/16)
+const AVERAGE_SHIFT: u32 = 4;
+
+impl AdaptiveBuffer {
+    fn capacity(&self, average_size: usize) -> usize {
+        let min = self.min.max(1);
+        (self.budget / average_size.max(1)).clamp(min, self.max.max(min))
+    }
+}
+
+/// size of results, and buffer sized by it
+struct Sizing<R> {
+    size: Box<dyn Fn(&R) -> usize + Send + Sync>,
+    buffer: AdaptiveBuffer,
+}
+
+struct State<R> {
+    /// index of the next item to take from source
+    next_issue: usize,
+    /// index of the next result to yield
+    next_yield: usize,
+    exhausted: bool,
+    /// stop workers (set when the iterator is dropped)
+    cancelled: bool,
+    /// the first item whose worker panicked, and its panic payload
+    panicked: Option<(usize, Box<dyn Any + Send>)>,
+    results: BTreeMap<usize, R>,
+    /// maximum number of items taken but not yet consumed
+    capacity: usize,
+    /// moving average of the result sizes (`par_map_sized`)
+    average_size: Option<usize>,
+}
+
+struct Shared<I, R> {
+    ///
+    /// The input, locked apart from `state`: taking an item may block
+    /// (e.g. `HugeBlockGate::admit` of an upstream stage waits for results
+    /// of this stage to be consumed), and must not block the consumer.
+    /// Items are numbered while it is held, so they are taken in order.
+    ///
+    source: Mutex<I>,
+    state: Mutex<State<R>>,
+    /// notified when a result is inserted or a worker exits
+    result_ready: Condvar,
+    /// notified when a result is consumed (capacity released)
+    capacity_released: Condvar,
+    sizing: Option<Sizing<R>>,
+}
+
+///
+/// Cancels an iterator from the consumer side, from any thread.
+///
+/// Once cancelled, the iterator ends at its next call to `next`
+/// and its worker threads stop taking new items.
+/// A `ParIter` waiting for a result also returns `None` right away.
+///
+#[derive(Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+    /// wakes up waiting threads
+    on_cancel: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl CancelHandle {
+    pub(crate) fn new() -> Self {
+        CancelHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            on_cancel: None,
+        }
+    }
+
+    pub(crate) fn with_callback<F>(on_cancel: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        CancelHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            on_cancel: Some(Arc::new(on_cancel)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(on_cancel) = &self.on_cancel {
+            on_cancel();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+///
+/// Iterator returned by `par_map_ordered`.
+///
+/// Worker threads stop when this iterator is dropped
+/// or cancelled (see `cancel_handle`).
+///
+pub struct ParIter<R> {
+    next: Box<dyn FnMut() -> Option<R> + Send>,
+    cancel: CancelHandle,
+}
+
+///
+/// Map `f` over `iter` with a pool of worker threads,
+/// producing results in the order of `iter`.
+///
+/// This is the pattern used internally by the block iterators of this crate.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::iter::{par_map_ordered, ParMapOptions};
+///
+/// let squares: Vec<u64> = par_map_ordered(0..1000u64, |x| x * x, ParMapOptions::default()).collect();
+/// assert_eq!(squares, (0..1000u64).map(|x| x * x).collect::<Vec<u64>>());
+/// ```
+///
+/// # Panics
+///
+/// If `f` panics in a worker, the panic is resumed by `next`
+/// in place of the missing result, after all results before it.
+///
+pub fn par_map_ordered<T, F, R>(iter: T, f: F, options: ParMapOptions) -> ParIter<R>
+where
+    T: IntoIterator,
+    T::IntoIter: Send + 'static,
+    T::Item: Send + 'static,
+    F: Fn(T::Item) -> R + Send + Sync + 'static,
+    R: Send + 'static,
+{
+    par_map(iter, f, options, None)
+}
+
+///
+/// Same as `par_map_ordered`, with a buffer sized by the average `size`
+/// of the results (in bytes, see `AdaptiveBuffer`).
+///
+/// `options.buffer` is the initial buffer, until results are measured.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::iter::{par_map_sized, AdaptiveBuffer, ParMapOptions};
+///
+/// // about 64 vectors of 1 MB in flight
+/// let buffer = AdaptiveBuffer { budget: 64 << 20, min: 2, max: 1024 };
+/// let iter = par_map_sized(0..1000, |_| vec![0u8; 1 << 20], |v| v.len(), ParMapOptions::default(), buffer);
+/// assert_eq!(iter.count(), 1000);
+/// ```
+///
+pub fn par_map_sized<T, F, R, S>(
+    iter: T,
+    f: F,
+    size: S,
+    options: ParMapOptions,
+    buffer: AdaptiveBuffer,
+) -> ParIter<R>
+where
+    T: IntoIterator,
+    T::IntoIter: Send + 'static,
+    T::Item: Send + 'static,
+    F: Fn(T::Item) -> R + Send + Sync + 'static,
+    R: Send + 'static,
+    S: Fn(&R) -> usize + Send + Sync + 'static,
+{
+    let sizing = Sizing {
+        size: Box::new(size),
+        buffer,
+    };
+    par_map(iter, f, options, Some(sizing))
+}
+
+fn par_map<T, F, R>(iter: T, f: F, options: ParMapOptions, sizing: Option<Sizing<R>>) -> ParIter<R>
+where
+    T: IntoIterator,
+    T::IntoIter: Send + 'static,
+    T::Item: Send + 'static,
+    F: Fn(T::Item) -> R + Send + Sync + 'static,
+    R: Send + 'static,
+{
+    let (threads, buffer) = options.resolved();
+    let capacity = match &sizing {
+        Some(sizing) => {
+            let min = sizing.buffer.min.max(1);
+            buffer.clamp(min, sizing.buffer.max.max(min))
+        }
+        None => buffer,
+    };
+    let shared = Arc::new(Shared {
+        source: Mutex::new(iter.into_iter()),
+        state: Mutex::new(State {
+            next_issue: 0,
+            next_yield: 0,
+            exhausted: false,
+            cancelled: false,
+            panicked: None,
+            results: BTreeMap::new(),
+            capacity,
+            average_size: None,
+        }),
+        result_ready: Condvar::new(),
+        capacity_released: Condvar::new(),
+        sizing,
+    });
+    let f = Arc::new(f);
+    for _ in 0..threads {
+        let shared = shared.clone();
+        let f = f.clone();
+        thread::spawn(move || worker(&shared, f.as_ref()));
+    }
+    let shared_next = shared.clone();
+    ParIter {
+        next: Box::new(move || next_result(&shared_next)),
+        cancel: CancelHandle::with_callback(move || {
+            shared.state.lock().unwrap().cancelled = true;
+            shared.capacity_released.notify_all();
+            shared.result_ready.notify_all();
+        }),
+    }
+}
+
+fn worker<I, F, R>(shared: &Shared<I, R>, f: &F)
+where
+    I: Iterator,
+    F: Fn(I::Item) -> R,
+{
+    loop {
+        let (index, item) = {
+            let mut source = shared.source.lock().unwrap();
+            let index = {
+                let mut state = shared.state.lock().unwrap();
+                loop {
+                    if state.cancelled || state.exhausted || state.panicked.is_some() {
+                        return;
+                    }
+                    if state.next_issue < state.next_yield + state.capacity {
+                        break;
+                    }
+                    state = shared.capacity_released.wait(state).unwrap();
+                }
+                state.next_issue += 1;
+                state.next_issue - 1
+            };
+            // take the item without holding the state lock
+            match source.next() {
+                None => {
+                    let mut state = shared.state.lock().unwrap();
+                    state.next_issue = index;
+                    state.exhausted = true;
+                    drop(state);
+                    shared.result_ready.notify_all();
+                    return;
+                }
+                Some(item) => (index, item),
+            }
+        };
+        // compute without holding the lock
+        let computed = panic::catch_unwind(AssertUnwindSafe(|| {
+            let result = f(item);
+            let size = shared.sizing.as_ref().map(|s| (s.size)(&result));
+            (result, size)
+        }));
+        let mut state = shared.state.lock().unwrap();
+        let (result, size) = match computed {
+            Ok(computed) => computed,
+            Err(payload) => {
+                // items before it are still computed and yielded
+                if state
+                    .panicked
+                    .as_ref()
+                    .map_or(true, |(first, _)| index < *first)
+                {
+                    state.panicked = Some((index, payload));
+                }
+                drop(state);
+                shared.result_ready.notify_all();
+                return;
+            }
+        };
+        state.results.insert(index, result);
+        if let (Some(size), Some(sizing)) = (size, &shared.sizing) {
+            let average = match state.average_size {
+                Some(average) => average - (average >> AVERAGE_SHIFT) + (size >> AVERAGE_SHIFT),
+                None => size,
+            };
+            state.average_size = Some(average);
+            let capacity = sizing.buffer.capacity(average);
+            if capacity > state.capacity {
+                shared.capacity_released.notify_all();
+            }
+            state.capacity = capacity;
+        }
+        drop(state);
+        shared.result_ready.notify_all();
+    }
+}
+
+fn next_result<I, R>(shared: &Shared<I, R>) -> Option<R> {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        let index = state.next_yield;
+        if let Some(result) = state.results.remove(&index) {
+            state.next_yield += 1;
+            shared.capacity_released.notify_all();
+            return Some(result);
+        }
+        if matches!(&state.panicked, Some((first, _)) if *first == index) {
+            let (_, payload) = state.panicked.take().unwrap();
+            // stop the other workers, later calls return `None`
+            state.cancelled = true;
+            drop(state);
+            shared.capacity_released.notify_all();
+            panic::resume_unwind(payload);
+        }
+        if (state.exhausted && index == state.next_issue) || state.cancelled {
+            return None;
+        }
+        state = shared.result_ready.wait(state).unwrap();
+    }
+}
+
+impl<R> ParIter<R> {
+    ///
+    /// Handle to stop this iterator early, e.g. from another thread.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::iter::{par_map_ordered, ParMapOptions};
+    ///
+    /// let mut iter = par_map_ordered(0..u64::MAX, |x| x, ParMapOptions::default());
+    /// let cancel = iter.cancel_handle();
+    /// assert_eq!(iter.next(), Some(0));
+    /// cancel.cancel();
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    ///
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+}
+
+impl<R> Iterator for ParIter<R> {
+    type Item = R;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        (self.next)()
+    }
+}
+
+impl<R> Drop for ParIter<R> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_par_map_ordered() {
+        let options = ParMapOptions {
+            threads: 4,
+            buffer: 8,
+        };
+        let output: Vec<u64> = par_map_ordered(
+            0..200u64,
+            |x| {
+                thread::sleep(Duration::from_micros((x * 7919) % 500));
+                x * 2
+            },
+            options,
+        )
+        .collect();
+        assert_eq!(output, (0..200u64).map(|x| x * 2).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_par_map_bounded_buffer() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let started_copy = started.clone();
+        let options = ParMapOptions {
+            threads: 4,
+            buffer: 5,
+        };
+        let mut iter = par_map_ordered(
+            0..1000,
+            move |x| {
+                started_copy.fetch_add(1, Ordering::SeqCst);
+                x
+            },
+            options,
+        );
+        for consumed in 1..=10 {
+            assert!(iter.next().is_some());
+            thread::sleep(Duration::from_millis(5));
+            // at most `buffer` items taken beyond those consumed
+            assert!(started.load(Ordering::SeqCst) <= consumed + 5);
+        }
+        drop(iter);
+        thread::sleep(Duration::from_millis(20));
+        assert!(started.load(Ordering::SeqCst) < 1000);
+    }
+
+    #[test]
+    fn test_par_map_sized() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let started_copy = started.clone();
+        let options = ParMapOptions {
+            threads: 4,
+            buffer: 4,
+        };
+        let buffer = AdaptiveBuffer {
+            budget: 10_000,
+            min: 2,
+            max: 50,
+        };
+        // 1000 bytes (10 in flight), then 5000 bytes (2 in flight)
+        let size = |x: &usize| if *x < 100 { 1000 } else { 5000 };
+        let mut iter = par_map_sized(
+            0..1000usize,
+            move |x| {
+                started_copy.fetch_add(1, Ordering::SeqCst);
+                x
+            },
+            size,
+            options,
+            buffer,
+        );
+        for consumed in 1..=200 {
+            assert_eq!(iter.next(), Some(consumed - 1));
+            if consumed % 50 == 0 {
+                thread::sleep(Duration::from_millis(10));
+                let in_flight = started.load(Ordering::SeqCst) - consumed;
+                if consumed == 50 {
+                    assert!((5..=10).contains(&in_flight), "{}", in_flight);
+                }
+                if consumed == 200 {
+                    assert!(in_flight <= 2, "{}", in_flight);
+                }
+            }
+        }
+        assert_eq!(iter.count(), 800);
+        assert_eq!(buffer.capacity(0), 50);
+        assert_eq!(buffer.capacity(1 << 20), 2);
+    }
+
+    #[test]
+    fn test_par_map_cancel() {
+        let options = ParMapOptions {
+            threads: 2,
+            buffer: 4,
+        };
+        let mut iter = par_map_ordered(
+            0..100,
+            |x| {
+                if x == 3 {
+                    thread::sleep(Duration::from_secs(2));
+                }
+                x
+            },
+            options,
+        );
+        let cancel = iter.cancel_handle();
+        assert_eq!(iter.next(), Some(0));
+        // cancel a consumer waiting for a slow item
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let rest: Vec<i32> = iter.collect();
+        assert!(rest.len() <= 2);
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn test_par_map_blocking_source() {
+        // item `x` can only be taken once all items before it are consumed,
+        // as with a huge block waiting for the permit of an unconsumed one
+        let consumed = Arc::new((Mutex::new(0usize), Condvar::new()));
+        let consumed_copy = consumed.clone();
+        let source = (0..50usize).map(move |x| {
+            let (count, changed) = consumed_copy.as_ref();
+            let mut count = count.lock().unwrap();
+            while *count < x {
+                count = changed.wait(count).unwrap();
+            }
+            x
+        });
+        let options = ParMapOptions {
+            threads: 4,
+            buffer: 8,
+        };
+        let mut output = Vec::new();
+        for x in par_map_ordered(source, |x| x, options) {
+            output.push(x);
+            let (count, changed) = consumed.as_ref();
+            *count.lock().unwrap() += 1;
+            changed.notify_all();
+        }
+        assert_eq!(output, (0..50).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_par_map_worker_panic() {
+        let options = ParMapOptions {
+            threads: 2,
+            buffer: 4,
+        };
+        let mut iter = par_map_ordered(
+            0..100,
+            |x| {
+                if x == 50 || x == 52 {
+                    panic!("expected panic in test");
+                }
+                x
+            },
+            options,
+        );
+        let mut output = Vec::new();
+        let consumed = panic::catch_unwind(AssertUnwindSafe(|| {
+            for x in &mut iter {
+                output.push(x);
+            }
+        }));
+        // every result before the first panic, then the panic
+        assert!(consumed.is_err());
+        assert_eq!(output, (0..50).collect::<Vec<i32>>());
+        assert_eq!(iter.next(), None);
+    }
+}
@@ -0,0 +1,130 @@
+//!
+//! Thread-local pools of reusable `Vec`s.
+//!
+//! Full scans allocate and free a block-sized byte buffer (and many
+//! smaller vectors) for every block. Each worker thread keeps the buffers
+//! it is done with, and hands them back out on the next iteration,
+//! so that the allocator is mostly out of the hot path.
+//!
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// number of vectors kept per element type and thread
+const MAX_POOLED_VECS: usize = 4;
+
+/// vectors with larger capacity (bytes) are freed instead of pooled
+const MAX_POOLED_BYTES: usize = 8 * 1024 * 1024;
+
+thread_local! {
+    static POOLS: RefCell<HashMap<TypeId, Vec<Box<dyn Any>>>> = RefCell::new(HashMap::new());
+}
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Counters of `take_vec` calls, over all threads.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// served with a recycled vector
+    pub hits: u64,
+    /// served with a new vector
+    pub misses: u64,
+}
+
+pub fn pool_stats() -> PoolStats {
+    PoolStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
+
+///
+/// Take an empty vector from the pool of this thread,
+/// keeping the capacity of a previously recycled one.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::iter::{recycle_vec, take_vec};
+/// use bitcoin_explorer::{BitcoinDB, SBlock};
+/// use std::path::Path;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+///
+/// for block in db.iter_block::<SBlock>(0, 100000) {
+///     let mut values = take_vec::<u64>();
+///     for tx in block.txdata {
+///         values.extend(tx.output.iter().map(|o| o.value));
+///     }
+///     // ...
+///     recycle_vec(values);
+/// }
+/// ```
+///
+pub fn take_vec<T: 'static>() -> Vec<T> {
+    let recycled = POOLS.with(|pools| {
+        pools
+            .borrow_mut()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|pool| pool.pop())
+    });
+    match recycled.and_then(|v| v.downcast::<Vec<T>>().ok()) {
+        Some(v) => {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            *v
+        }
+        None => {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+            Vec::new()
+        }
+    }
+}
+
+///
+/// Return a vector to the pool of this thread.
+///
+/// The content is dropped, the capacity is kept for the next `take_vec`.
+///
+pub fn recycle_vec<T: 'static>(mut v: Vec<T>) {
+    let bytes = v.capacity().saturating_mul(mem::size_of::<T>());
+    if v.capacity() == 0 || bytes > MAX_POOLED_BYTES {
+        return;
+    }
+    v.clear();
+    POOLS.with(|pools| {
+        let mut pools = pools.borrow_mut();
+        let pool = pools.entry(TypeId::of::<T>()).or_default();
+        if pool.len() < MAX_POOLED_VECS {
+            pool.push(Box::new(v));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vec_pool() {
+        let mut v = take_vec::<u8>();
+        v.extend_from_slice(&[1, 2, 3]);
+        let capacity = v.capacity();
+        recycle_vec(v);
+
+        // pools are per element type
+        assert_eq!(take_vec::<u16>().capacity(), 0);
+
+        let v = take_vec::<u8>();
+        assert!(v.is_empty());
+        assert_eq!(v.capacity(), capacity);
+
+        // oversized vectors are not kept
+        recycle_vec(Vec::<u8>::with_capacity(MAX_POOLED_BYTES + 1));
+        assert_eq!(take_vec::<u8>().capacity(), 0);
+    }
+}
@@ -0,0 +1,168 @@
+//!
+//! Height-indexed side channel for auxiliary per-block records.
+//!
+//! Workers of `BitcoinDB::iter_block_with_aux` may emit records
+//! (detected events, errors, metrics, ...) of a second type alongside
+//! the blocks they produce. Records are delivered through
+//! `AuxBlockIter::aux_receiver()` in height order: all records of a block
+//! are sent just before the block is yielded.
+//!
+use crate::api::BitcoinDB;
+use crate::iter::par_iter::{par_map_ordered, ParIter, ParMapOptions};
+use bitcoin::Block;
+use std::cell::RefCell;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+///
+/// An auxiliary record emitted while processing the block at `height`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuxRecord<A> {
+    pub height: usize,
+    pub record: A,
+}
+
+///
+/// Handle passed to the worker function of `iter_block_with_aux`
+/// to emit auxiliary records of the current block.
+///
+pub struct AuxSender<A> {
+    height: usize,
+    records: RefCell<Vec<A>>,
+}
+
+impl<A> AuxSender<A> {
+    /// height of the block being processed
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// emit a record of the current block
+    #[inline]
+    pub fn emit(&self, record: A) {
+        self.records.borrow_mut().push(record);
+    }
+}
+
+///
+/// Iterator returned by `BitcoinDB::iter_block_with_aux`.
+///
+/// Worker threads stop when this iterator is dropped.
+///
+pub struct AuxBlockIter<TBlock, A> {
+    inner: Option<ParIter<Option<(usize, TBlock, Vec<A>)>>>,
+    sender: Sender<AuxRecord<A>>,
+    receiver: Option<Receiver<AuxRecord<A>>>,
+}
+
+impl<TBlock, A> AuxBlockIter<TBlock, A> {
+    ///
+    /// Take the receiving end of the side channel.
+    ///
+    /// Returns `None` if it has already been taken.
+    /// The channel is unbounded: records not received are kept
+    /// in memory until the receiver is dropped.
+    ///
+    pub fn aux_receiver(&mut self) -> Option<Receiver<AuxRecord<A>>> {
+        self.receiver.take()
+    }
+}
+
+impl<TBlock, A> Iterator for AuxBlockIter<TBlock, A> {
+    type Item = TBlock;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.as_mut()?.next() {
+            Some(Some((height, block, records))) => {
+                for record in records {
+                    // the receiver may have been dropped
+                    let _ = self.sender.send(AuxRecord { height, record });
+                }
+                Some(block)
+            }
+            _ => {
+                // stop workers
+                self.inner = None;
+                None
+            }
+        }
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Map `f` over blocks from `start` to `end` (excluded) in worker threads,
+    /// where `f` may emit auxiliary records through an `AuxSender`.
+    ///
+    /// Blocks are produced in order, the iteration stops at the
+    /// first block that cannot be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::iter::ParMapOptions;
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use std::path::Path;
+    /// use std::thread;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let mut iter = db.iter_block_with_aux(
+    ///     0,
+    ///     700000,
+    ///     |_, block, aux| {
+    ///         if block.txdata.len() > 2000 {
+    ///             aux.emit(format!("large block, {} txs", block.txdata.len()));
+    ///         }
+    ///         block.txdata.len()
+    ///     },
+    ///     ParMapOptions::default(),
+    /// );
+    /// let events = iter.aux_receiver().unwrap();
+    /// let logger = thread::spawn(move || {
+    ///     for event in events {
+    ///         println!("{}: {}", event.height, event.record);
+    ///     }
+    /// });
+    /// let n_tx: usize = iter.sum();
+    /// logger.join().unwrap();
+    /// println!("{} transactions", n_tx);
+    /// ```
+    ///
+    pub fn iter_block_with_aux<TBlock, A, F>(
+        &self,
+        start: usize,
+        end: usize,
+        f: F,
+        options: ParMapOptions,
+    ) -> AuxBlockIter<TBlock, A>
+    where
+        TBlock: Send + 'static,
+        A: Send + 'static,
+        F: Fn(usize, Block, &AuxSender<A>) -> TBlock + Send + Sync + 'static,
+    {
+        let db = self.clone();
+        let end = end.min(self.get_block_count());
+        let inner = par_map_ordered(
+            start..end.max(start),
+            move |height| {
+                let block = db.get_block::<Block>(height).ok()?;
+                let aux = AuxSender {
+                    height,
+                    records: RefCell::new(Vec::new()),
+                };
+                let output = f(height, block, &aux);
+                Some((height, output, aux.records.into_inner()))
+            },
+            options,
+        );
+        let (sender, receiver) = channel();
+        AuxBlockIter {
+            inner: Some(inner),
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//!
+//! Broadcast one iterator to multiple consumers.
+//!
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+/// number of items buffered for each consumer
+pub(crate) const TEE_BUFFER_SIZE: usize = 16;
+
+///
+/// One of the consumer handles produced by `tee`.
+///
+/// Each handle receives every item of the source iterator, in order.
+///
+pub struct TeeIter<T> {
+    receiver: Receiver<Arc<T>>,
+}
+
+///
+/// Dispatch a thread that pulls from `iter` and sends every item to `n` consumers.
+///
+/// The source stops being consumed when all handles are dropped.
+///
+pub(crate) fn tee<I>(iter: I, n: usize, capacity: usize) -> Vec<TeeIter<I::Item>>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + Sync + 'static,
+{
+    let mut senders = Vec::with_capacity(n);
+    let mut handles = Vec::with_capacity(n);
+    for _ in 0..n {
+        let (sender, receiver) = sync_channel(capacity);
+        senders.push(Some(sender));
+        handles.push(TeeIter { receiver });
+    }
+    thread::spawn(move || {
+        for item in iter {
+            let item = Arc::new(item);
+            let mut alive = false;
+            for sender in senders.iter_mut() {
+                if let Some(s) = sender {
+                    if s.send(item.clone()).is_ok() {
+                        alive = true;
+                    } else {
+                        // this consumer has been dropped
+                        *sender = None;
+                    }
+                }
+            }
+            if !alive {
+                break;
+            }
+        }
+    });
+    handles
+}
+
+impl<T> Iterator for TeeIter<T> {
+    type Item = Arc<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tee() {
+        let mut handles = tee(0..1000, 3, 4);
+        let dropped = handles.pop().unwrap();
+        drop(dropped);
+        let threads: Vec<_> = handles
+            .into_iter()
+            .map(|h| thread::spawn(move || h.map(|x| *x).collect::<Vec<i32>>()))
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), (0..1000).collect::<Vec<i32>>());
+        }
+    }
+}
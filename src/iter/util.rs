@@ -1,13 +1,11 @@
 ///
 /// a light weighted data structure for storing unspent output
 ///
-#[cfg(not(feature = "on-disk-utxo"))]
 pub(crate) struct VecMap<T> {
     size: u32,
     inner: Box<[Option<Box<T>>]>,
 }
 
-#[cfg(not(feature = "on-disk-utxo"))]
 impl<T> VecMap<T> {
     #[inline(always)]
     pub(crate) fn from_vec(slice: Box<[Option<Box<T>>]>) -> Self {
@@ -30,10 +28,32 @@ impl<T> VecMap<T> {
         };
         element.take()
     }
+
+    ///
+    /// Set element `n`, growing the map if needed.
+    ///
+    pub(crate) fn insert(&mut self, n: usize, value: T) {
+        if n >= self.inner.len() {
+            let mut inner = std::mem::take(&mut self.inner).into_vec();
+            inner.resize_with(n + 1, || None);
+            self.inner = inner.into_boxed_slice();
+        }
+        if self.inner[n].is_none() {
+            self.size += 1;
+        }
+        self.inner[n] = Some(Box::new(value));
+    }
+
+    /// remaining elements and their index
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.inner
+            .iter()
+            .enumerate()
+            .filter_map(|(n, e)| e.as_deref().map(|e| (n, e)))
+    }
 }
 
 #[cfg(test)]
-#[cfg(not(feature = "on-disk-utxo"))]
 mod test_vec_map {
     use crate::api::STxOut;
     use crate::iter::util::VecMap;
@@ -61,5 +81,9 @@ mod test_vec_map {
         assert!(!vec.is_empty());
         assert!(vec.remove(2).is_some());
         assert!(vec.is_empty());
+        vec.insert(4, TxOut::default().into());
+        vec.insert(1, TxOut::default().into());
+        assert_eq!(vec.size, 2);
+        assert_eq!(vec.iter().map(|(n, _)| n).collect::<Vec<_>>(), vec![1, 4]);
     }
 }
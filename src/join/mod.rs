@@ -0,0 +1,107 @@
+//!
+//! Streaming join of external per-block / per-transaction datasets.
+//!
+//! A `JoinSource` provides rows sorted by block height (e.g., price data
+//! per height, labels per txid), which `BitcoinDB::iter_block_joined`
+//! merges into the blocks as they are produced, in a single pass.
+//!
+//! # Example
+//!
+//! ```rust
+//! use bitcoin_explorer::join::{JoinKey, JoinSource};
+//! use bitcoin_explorer::{BitcoinDB, SBlock};
+//! use std::path::Path;
+//!
+//! let path = Path::new("/Users/me/bitcoin");
+//! let db = BitcoinDB::new(path, false).unwrap();
+//!
+//! // height,txid,label
+//! let labels = JoinSource::from_csv(Path::new("./labels.csv"), JoinKey::Txid).unwrap();
+//! for blk in db.iter_block_joined::<SBlock>(600000, 700000, labels) {
+//!     let blk = blk.unwrap();
+//!     for row in blk.rows.iter() {
+//!         println!("{}: {} is {}", blk.height, row.txid.unwrap(), row.values[0]);
+//!     }
+//! }
+//! ```
+//!
+mod source;
+
+pub(crate) use source::split_csv_line;
+pub use source::{JoinKey, JoinRow, JoinSource};
+
+use crate::api::{BitcoinDB, Block, Txid};
+use crate::parser::errors::OpResult;
+use std::collections::HashSet;
+
+///
+/// A block with the rows of a `JoinSource` at its height.
+///
+#[derive(Debug, Clone)]
+pub struct JoinedBlock<T> {
+    pub height: usize,
+    pub block: T,
+    pub rows: Vec<JoinRow>,
+}
+
+impl<T> JoinedBlock<T> {
+    ///
+    /// Rows joined to a transaction (`JoinKey::Txid` sources).
+    ///
+    pub fn rows_of<'a>(&'a self, txid: &'a Txid) -> impl Iterator<Item = &'a JoinRow> + 'a {
+        self.rows
+            .iter()
+            .filter(move |r| r.txid.as_ref() == Some(txid))
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Iterate through blocks from `start` to `end` (excluded),
+    /// merging the rows of `source` into each block.
+    ///
+    /// `source` must be sorted by height. Rows below `start` are skipped.
+    /// The iteration stops after the first error (source not sorted,
+    /// unreadable source, or a txid not found in the block of its row).
+    ///
+    pub fn iter_block_joined<T>(
+        &self,
+        start: usize,
+        end: usize,
+        mut source: JoinSource,
+    ) -> impl Iterator<Item = OpResult<JoinedBlock<T>>>
+    where
+        T: From<Block> + Send + 'static,
+    {
+        let db = self.clone();
+        let mut failed = false;
+        self.iter_block::<Block>(start, end)
+            .zip(start..)
+            .map_while(move |(block, height)| {
+                if failed {
+                    return None;
+                }
+                let result = join_block(&db, &mut source, height, block);
+                failed = result.is_err();
+                Some(result)
+            })
+    }
+}
+
+fn join_block<T: From<Block>>(
+    db: &BitcoinDB,
+    source: &mut JoinSource,
+    height: usize,
+    block: Block,
+) -> OpResult<JoinedBlock<T>> {
+    let txids: Option<HashSet<Txid>> = match source.key() {
+        JoinKey::Height => None,
+        JoinKey::Txid => Some(block.txdata.iter().map(|tx| tx.txid()).collect()),
+    };
+    let rows = source.take_height(height, txids.as_ref())?;
+    Ok(JoinedBlock {
+        height,
+        block: db.convert(|| T::from(block)),
+        rows,
+    })
+}
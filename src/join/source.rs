@@ -0,0 +1,236 @@
+use crate::api::{FromHex, Txid};
+use crate::parser::errors::{OpError, OpResult};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::iter::Peekable;
+use std::path::Path;
+
+///
+/// How rows of a `JoinSource` are attached to blocks.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKey {
+    /// rows keyed by block height (e.g., price per height)
+    Height,
+    ///
+    /// rows keyed by txid (e.g., labels per transaction).
+    ///
+    /// Since txids have no order, rows also carry the height
+    /// of the block containing the transaction, and are sorted by it.
+    ///
+    Txid,
+}
+
+///
+/// A row of a `JoinSource`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRow {
+    pub height: usize,
+    /// `Some` for `JoinKey::Txid` sources
+    pub txid: Option<Txid>,
+    pub values: Vec<String>,
+}
+
+type RowIter = Box<dyn Iterator<Item = OpResult<JoinRow>> + Send>;
+
+///
+/// A stream of rows sorted by height, to be joined to blocks.
+///
+pub struct JoinSource {
+    key: JoinKey,
+    columns: Vec<String>,
+    rows: Peekable<RowIter>,
+    started: bool,
+}
+
+impl JoinSource {
+    ///
+    /// Rows from an iterator (must be sorted by height).
+    ///
+    pub fn from_rows<I>(key: JoinKey, columns: Vec<String>, rows: I) -> Self
+    where
+        I: IntoIterator<Item = JoinRow>,
+        I::IntoIter: Send + 'static,
+    {
+        let rows: RowIter = Box::new(rows.into_iter().map(Ok));
+        JoinSource {
+            key,
+            columns,
+            rows: rows.peekable(),
+            started: false,
+        }
+    }
+
+    ///
+    /// Rows from a CSV file with a header line, sorted by height.
+    ///
+    /// The first column is the height, followed by the txid
+    /// for `JoinKey::Txid`. Other columns are kept as strings.
+    /// Fields may be quoted (`"a,b"`, `""` escapes a quote),
+    /// but may not contain line breaks.
+    ///
+    pub fn from_csv(path: &Path, key: JoinKey) -> OpResult<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = match lines.next() {
+            Some(line) => split_csv_line(&line?)?,
+            None => return Err(OpError::from("empty csv file")),
+        };
+        let n_key = match key {
+            JoinKey::Height => 1,
+            JoinKey::Txid => 2,
+        };
+        if header.len() < n_key {
+            return Err(OpError::from("missing key columns in csv header"));
+        }
+        let columns = header[n_key..].to_vec();
+        let rows: RowIter = Box::new(
+            lines
+                .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+                .map(move |line| parse_row(&line?, key, n_key)),
+        );
+        Ok(JoinSource {
+            key,
+            columns,
+            rows: rows.peekable(),
+            started: false,
+        })
+    }
+
+    pub fn key(&self) -> JoinKey {
+        self.key
+    }
+
+    ///
+    /// Names of the value columns.
+    ///
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    ///
+    /// Take the rows at `height`.
+    ///
+    /// On the first call, rows below `height` are skipped;
+    /// afterwards, heights must be taken in increasing order.
+    /// For `JoinKey::Txid` sources, every txid must be in `txids`.
+    ///
+    pub(crate) fn take_height(
+        &mut self,
+        height: usize,
+        txids: Option<&HashSet<Txid>>,
+    ) -> OpResult<Vec<JoinRow>> {
+        let mut rows = Vec::new();
+        loop {
+            let row_height = match self.rows.peek() {
+                None => break,
+                Some(Err(_)) => return Err(self.rows.next().unwrap().unwrap_err()),
+                Some(Ok(row)) => row.height,
+            };
+            if row_height > height {
+                break;
+            }
+            let row = self.rows.next().unwrap()?;
+            if row_height < height {
+                if self.started {
+                    return Err(OpError::from("join source not sorted by height"));
+                }
+                continue;
+            }
+            if let (Some(txids), Some(txid)) = (txids, row.txid.as_ref()) {
+                if !txids.contains(txid) {
+                    return Err(OpError::from("joined txid not found in block"));
+                }
+            }
+            rows.push(row);
+        }
+        self.started = true;
+        Ok(rows)
+    }
+}
+
+fn parse_row(line: &str, key: JoinKey, n_key: usize) -> OpResult<JoinRow> {
+    let mut fields = split_csv_line(line)?;
+    if fields.len() < n_key {
+        return Err(OpError::from("missing key columns in csv row"));
+    }
+    let height = fields[0]
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| OpError::from("invalid height in csv row"))?;
+    let txid = match key {
+        JoinKey::Height => None,
+        JoinKey::Txid => Some(Txid::from_hex(fields[1].trim())?),
+    };
+    Ok(JoinRow {
+        height,
+        txid,
+        values: fields.split_off(n_key),
+    })
+}
+
+pub(crate) fn split_csv_line(line: &str) -> OpResult<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(OpError::from("unterminated quote in csv row"));
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_split_csv_line() {
+        assert_eq!(
+            split_csv_line(r#"1,"a,b","say ""hi""",,x"#).unwrap(),
+            vec!["1", "a,b", r#"say "hi""#, "", "x"]
+        );
+        assert!(split_csv_line(r#"1,"open"#).is_err());
+    }
+
+    #[test]
+    fn test_take_height() {
+        let txid = Txid::hash(b"t");
+        let rows = vec![(1, "skipped"), (3, "a"), (3, "b"), (5, "c"), (4, "late")]
+            .into_iter()
+            .map(|(height, v)| JoinRow {
+                height,
+                txid: Some(txid),
+                values: vec![v.to_string()],
+            })
+            .collect::<Vec<_>>();
+        let mut source = JoinSource::from_rows(JoinKey::Txid, vec!["label".into()], rows);
+        let txids: HashSet<Txid> = vec![txid].into_iter().collect();
+        assert!(source.take_height(2, Some(&txids)).unwrap().is_empty());
+        assert_eq!(source.take_height(3, Some(&txids)).unwrap().len(), 2);
+        assert!(source
+            .take_height(4, Some(&HashSet::new()))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            source.take_height(5, Some(&txids)).unwrap()[0].values,
+            vec!["c"]
+        );
+        assert!(source.take_height(6, Some(&txids)).is_err());
+    }
+}
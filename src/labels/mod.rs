@@ -0,0 +1,595 @@
+//!
+//! Address and transaction labels.
+//!
+//! A `LabelStore` keeps labels of addresses and txids in a small RocksDB.
+//! Labels are imported from CSV files (`address,label[,source]`),
+//! JSON objects (`{"<address or txid>": "<label>"}`)
+//! or BIP-329 JSON lines, and exported back to CSV or BIP-329.
+//!
+//! Labels annotate query results (`LabelStore::labels_of`)
+//! and enriched blocks (`EnrichSpec::labels`).
+//!
+//! # Example
+//!
+//! ```rust
+//! use bitcoin_explorer::enrich::EnrichSpec;
+//! use bitcoin_explorer::labels::{LabelStore, Labels};
+//! use bitcoin_explorer::BitcoinDB;
+//! use std::path::Path;
+//! use std::sync::Arc;
+//!
+//! let store = LabelStore::open(Path::new("./labels")).unwrap();
+//! store.import_csv(Path::new("./exchanges.csv"), Some("exchanges")).unwrap();
+//!
+//! let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+//! let spec = EnrichSpec::new().labels(Arc::new(store));
+//! for blk in db.iter_enriched(700000, 700100, &spec) {
+//!     for (key, labels) in &blk.enrichments.get::<Labels>().unwrap().0 {
+//!         println!("{}: {} {:?}", blk.height, key, labels);
+//!     }
+//! }
+//! ```
+//!
+use crate::analysis::hll::hash64;
+use crate::api::{Address, BitcoinDB, Block, FromHex, SearchResult, Txid};
+use crate::enrich::{EnrichContext, EnrichSpec, Enricher, Enrichments};
+use crate::join::split_csv_line;
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::script::evaluate_script;
+use bitcoin::hashes::Hash;
+use rocksdb::{IteratorMode, Options, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+const ADDRESS_PREFIX: u8 = b'a';
+const TXID_PREFIX: u8 = b't';
+/// key of the number of changes made to the store
+const VERSION_KEY: &[u8] = b"version";
+
+///
+/// What a label is attached to.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LabelKey {
+    Address(Address),
+    Txid(Txid),
+}
+
+impl LabelKey {
+    ///
+    /// Parse a txid (64 hex digits) or an address.
+    ///
+    pub fn parse(s: &str) -> OpResult<Self> {
+        let s = s.trim();
+        if s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(LabelKey::Txid(Txid::from_hex(s)?));
+        }
+        Address::from_str(s)
+            .map(LabelKey::Address)
+            .map_err(|_| OpError::from(format!("not an address or txid: {}", s).as_str()))
+    }
+
+    fn to_db_key(&self) -> Vec<u8> {
+        match self {
+            LabelKey::Address(address) => {
+                let mut key = vec![ADDRESS_PREFIX];
+                key.extend_from_slice(address.to_string().as_bytes());
+                key
+            }
+            LabelKey::Txid(txid) => {
+                let mut key = vec![TXID_PREFIX];
+                key.extend_from_slice(&txid[..]);
+                key
+            }
+        }
+    }
+
+    fn from_db_key(key: &[u8]) -> OpResult<Self> {
+        match key.split_first() {
+            Some((&ADDRESS_PREFIX, address)) => {
+                Address::from_str(&String::from_utf8(address.to_vec())?)
+                    .map(LabelKey::Address)
+                    .map_err(|_| OpError::from("invalid address in label store"))
+            }
+            Some((&TXID_PREFIX, txid)) => Ok(LabelKey::Txid(Txid::from_slice(txid)?)),
+            _ => Err(OpError::from("invalid key in label store")),
+        }
+    }
+
+    /// type of the BIP-329 record
+    fn bip329_type(&self) -> &'static str {
+        match self {
+            LabelKey::Address(_) => "addr",
+            LabelKey::Txid(_) => "tx",
+        }
+    }
+}
+
+impl fmt::Display for LabelKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LabelKey::Address(address) => write!(f, "{}", address),
+            LabelKey::Txid(txid) => write!(f, "{}", txid),
+        }
+    }
+}
+
+///
+/// A label, with the name of the list it was imported from.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Label {
+    pub label: String,
+    pub source: Option<String>,
+}
+
+impl Label {
+    pub fn new(label: &str, source: Option<&str>) -> Self {
+        Label {
+            label: label.to_string(),
+            source: source.map(|s| s.to_string()),
+        }
+    }
+}
+
+///
+/// Labels of addresses and txids, stored in a RocksDB at a given path.
+///
+/// A key can have several labels, a label is stored once per key.
+///
+pub struct LabelStore {
+    db: DB,
+    path: PathBuf,
+    /// number of changes, stored under `VERSION_KEY`
+    version: Mutex<u64>,
+}
+
+impl LabelStore {
+    ///
+    /// Open (or create) a label store at `path`.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> OpResult<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.set_max_background_jobs(1);
+        let db = DB::open(&options, path.as_ref()).map_err(|e| {
+            OpError::from(format!("failed to open rocksDB for labels: {}", e).as_str())
+        })?;
+        let version = match db
+            .get(VERSION_KEY)
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?
+        {
+            Some(value) => u64::from_le_bytes(
+                value
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| OpError::from("invalid version in label store"))?,
+            ),
+            None => 0,
+        };
+        Ok(LabelStore {
+            db,
+            path: path.as_ref().to_path_buf(),
+            version: Mutex::new(version),
+        })
+    }
+
+    ///
+    /// Add a label to `key`, returns `false` if it was already there.
+    ///
+    pub fn add(&self, key: &LabelKey, label: Label) -> OpResult<bool> {
+        let mut labels = self.get(key)?;
+        if labels.contains(&label) {
+            return Ok(false);
+        }
+        labels.push(label);
+        self.put(key, &labels)?;
+        Ok(true)
+    }
+
+    ///
+    /// Labels of `key` (empty if it has none).
+    ///
+    pub fn get(&self, key: &LabelKey) -> OpResult<Vec<Label>> {
+        match self
+            .db
+            .get(key.to_db_key())
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?
+        {
+            Some(value) => decode_labels(&value),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    ///
+    /// Remove all labels of `key`.
+    ///
+    pub fn remove(&self, key: &LabelKey) -> OpResult<()> {
+        self.write(key, None)
+    }
+
+    ///
+    /// Number of changes made to this store, it increases
+    /// with every `add`, `remove` and imported label.
+    ///
+    pub fn version(&self) -> u64 {
+        *self.version.lock().unwrap()
+    }
+
+    ///
+    /// All labelled keys with their labels, addresses first.
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = OpResult<(LabelKey, Vec<Label>)>> + '_ {
+        self.db
+            .iterator(IteratorMode::Start)
+            .filter(|entry| !matches!(entry, Ok((key, _)) if key.as_ref() == VERSION_KEY))
+            .map(|entry| {
+                let (key, value) = entry.map_err(|e| {
+                    OpError::from(format!("failed to read rocksDB: {}", e).as_str())
+                })?;
+                Ok((LabelKey::from_db_key(&key)?, decode_labels(&value)?))
+            })
+    }
+
+    ///
+    /// Labels of a search result: of the address, or of the transaction.
+    ///
+    pub fn labels_of(&self, result: &SearchResult) -> OpResult<Vec<Label>> {
+        match result {
+            SearchResult::Address(address) => self.get(&LabelKey::Address(address.clone())),
+            SearchResult::Transaction { txid, .. } => self.get(&LabelKey::Txid(*txid)),
+            SearchResult::Block { .. } => Ok(Vec::new()),
+        }
+    }
+
+    ///
+    /// Import a CSV file with rows `key,label` or `key,label,source`,
+    /// where `key` is an address or a txid.
+    ///
+    /// A first row whose key does not parse is taken as a header.
+    /// `source` (if given) applies to rows without a source column.
+    /// Returns the number of labels added.
+    ///
+    pub fn import_csv(&self, path: &Path, source: Option<&str>) -> OpResult<usize> {
+        let mut added = 0;
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = split_csv_line(&line)?;
+            let key = match LabelKey::parse(&fields[0]) {
+                Ok(key) => key,
+                Err(_) if i == 0 => continue,
+                Err(e) => return Err(e),
+            };
+            let label = match fields.get(1) {
+                Some(label) => label.trim(),
+                None => return Err(OpError::from("missing label column in csv row")),
+            };
+            let row_source = fields
+                .get(2)
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .or(source);
+            if self.add(&key, Label::new(label, row_source))? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    ///
+    /// Import a JSON object mapping addresses or txids to a label
+    /// (or to a list of labels), or BIP-329 JSON lines
+    /// (`{"type": "addr", "ref": "<address>", "label": "<label>"}`,
+    /// records of other types are skipped).
+    ///
+    /// Returns the number of labels added.
+    ///
+    pub fn import_json(&self, path: &Path, source: Option<&str>) -> OpResult<usize> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        let mut added = 0;
+        if let Ok(Value::Object(map)) = serde_json::from_str::<Value>(&content) {
+            if !map.contains_key("ref") {
+                for (key, value) in map {
+                    let key = LabelKey::parse(&key)?;
+                    let labels = match value {
+                        Value::String(s) => vec![s],
+                        Value::Array(values) => values
+                            .into_iter()
+                            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                            .collect(),
+                        _ => return Err(OpError::from("label must be a string or a list")),
+                    };
+                    for label in labels {
+                        if self.add(&key, Label::new(&label, source))? {
+                            added += 1;
+                        }
+                    }
+                }
+                return Ok(added);
+            }
+        }
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let record: Map<String, Value> = serde_json::from_str(line)
+                .map_err(|e| OpError::from(format!("invalid BIP-329 record: {}", e).as_str()))?;
+            let field = |name: &str| record.get(name).and_then(|v| v.as_str());
+            let key = match (field("type"), field("ref")) {
+                (Some("addr"), Some(r)) | (Some("tx"), Some(r)) => LabelKey::parse(r)?,
+                _ => continue,
+            };
+            let label = match field("label") {
+                Some(label) => label,
+                None => continue,
+            };
+            if self.add(&key, Label::new(label, field("source").or(source)))? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    ///
+    /// Export all labels to a CSV file with header `key,label,source`.
+    ///
+    /// Returns the number of labels written.
+    ///
+    pub fn export_csv(&self, path: &Path) -> OpResult<usize> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "key,label,source")?;
+        let mut written = 0;
+        for entry in self.iter() {
+            let (key, labels) = entry?;
+            for label in labels {
+                writeln!(
+                    out,
+                    "{},{},{}",
+                    key,
+                    csv_field(&label.label),
+                    csv_field(label.source.as_deref().unwrap_or(""))
+                )?;
+                written += 1;
+            }
+        }
+        out.flush()?;
+        Ok(written)
+    }
+
+    ///
+    /// Export all labels as BIP-329 JSON lines
+    /// (with an extra `source` field when known).
+    ///
+    /// Returns the number of labels written.
+    ///
+    pub fn export_bip329(&self, path: &Path) -> OpResult<usize> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut written = 0;
+        for entry in self.iter() {
+            let (key, labels) = entry?;
+            for label in labels {
+                let mut record = Map::new();
+                record.insert("type".to_string(), Value::from(key.bip329_type()));
+                record.insert("ref".to_string(), Value::from(key.to_string()));
+                record.insert("label".to_string(), Value::from(label.label));
+                if let Some(source) = label.source {
+                    record.insert("source".to_string(), Value::from(source));
+                }
+                serde_json::to_writer(&mut out, &record).map_err(|e| {
+                    OpError::from(format!("failed to write labels: {}", e).as_str())
+                })?;
+                writeln!(out)?;
+                written += 1;
+            }
+        }
+        out.flush()?;
+        Ok(written)
+    }
+
+    fn put(&self, key: &LabelKey, labels: &[Label]) -> OpResult<()> {
+        let value = serde_json::to_vec(labels)
+            .map_err(|e| OpError::from(format!("failed to encode labels: {}", e).as_str()))?;
+        self.write(key, Some(value))
+    }
+
+    /// put (or delete) the labels of `key` and bump the version together
+    fn write(&self, key: &LabelKey, value: Option<Vec<u8>>) -> OpResult<()> {
+        let mut version = self.version.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        match value {
+            Some(value) => batch.put(key.to_db_key(), value),
+            None => batch.delete(key.to_db_key()),
+        }
+        batch.put(VERSION_KEY, (*version + 1).to_le_bytes());
+        self.db
+            .write(batch)
+            .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))?;
+        *version += 1;
+        Ok(())
+    }
+}
+
+fn decode_labels(value: &[u8]) -> OpResult<Vec<Label>> {
+    serde_json::from_slice(value)
+        .map_err(|e| OpError::from(format!("invalid labels in label store: {}", e).as_str()))
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+///
+/// Labels of the txids and output addresses of a block
+/// (only labelled keys are present).
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels(pub HashMap<LabelKey, Vec<Label>>);
+
+pub(crate) struct LabelsEnricher(pub(crate) Arc<LabelStore>);
+
+impl Enricher for LabelsEnricher {
+    fn name(&self) -> &'static str {
+        "labels"
+    }
+
+    fn enrich(&self, ctx: &EnrichContext, block: &Block, out: &mut Enrichments) {
+        let mut labels = HashMap::new();
+        for tx in &block.txdata {
+            let mut keys = vec![LabelKey::Txid(tx.txid())];
+            for o in &tx.output {
+                let eval = evaluate_script(&o.script_pubkey, ctx.db.network());
+                keys.extend(eval.addresses.into_iter().map(LabelKey::Address));
+            }
+            for key in keys {
+                if labels.contains_key(&key) {
+                    continue;
+                }
+                if let Ok(found) = self.0.get(&key) {
+                    if !found.is_empty() {
+                        labels.insert(key, found);
+                    }
+                }
+            }
+        }
+        out.insert(Labels(labels));
+    }
+
+    fn fingerprint(&self) -> u64 {
+        // labels change without the path changing
+        let desc = format!("{}:{}", self.0.path.to_string_lossy(), self.0.version());
+        hash64(desc.as_bytes())
+    }
+}
+
+impl EnrichSpec {
+    /// compute `Labels` from `store`
+    pub fn labels(self, store: Arc<LabelStore>) -> Self {
+        self.with(LabelsEnricher(store))
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Search `query` (see `BitcoinDB::search`) and annotate
+    /// the result with its labels in `store`.
+    ///
+    pub fn search_labelled(
+        &self,
+        query: &str,
+        store: &LabelStore,
+    ) -> OpResult<(SearchResult, Vec<Label>)> {
+        let result = self.search(query)?;
+        let labels = store.labels_of(&result)?;
+        Ok((result, labels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_label_store_import_export() {
+        let dir = TempDir::new("labels").unwrap();
+        let store = LabelStore::open(dir.path().join("db")).unwrap();
+        let genesis = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+        let csv = dir.path().join("in.csv");
+        fs::write(
+            &csv,
+            format!(
+                "address,label\n1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa,\"Satoshi, genesis\"\n{},coinbase,core\n",
+                genesis
+            ),
+        )
+        .unwrap();
+        assert_eq!(store.import_csv(&csv, Some("list")).unwrap(), 2);
+        // already imported
+        assert_eq!(store.import_csv(&csv, Some("list")).unwrap(), 0);
+
+        let address = LabelKey::parse("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        let txid = LabelKey::parse(genesis).unwrap();
+        assert_eq!(
+            store.get(&address).unwrap(),
+            vec![Label::new("Satoshi, genesis", Some("list"))]
+        );
+        assert_eq!(
+            store.get(&txid).unwrap(),
+            vec![Label::new("coinbase", Some("core"))]
+        );
+
+        let json = dir.path().join("in.json");
+        fs::write(
+            &json,
+            "{\"1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa\": [\"genesis\", \"Satoshi, genesis\"]}",
+        )
+        .unwrap();
+        assert_eq!(store.import_json(&json, Some("list")).unwrap(), 1);
+        assert_eq!(store.get(&address).unwrap().len(), 2);
+
+        // round trip through BIP-329
+        let bip329 = dir.path().join("out.jsonl");
+        assert_eq!(store.export_bip329(&bip329).unwrap(), 3);
+        let copy = LabelStore::open(dir.path().join("copy")).unwrap();
+        assert_eq!(copy.import_json(&bip329, None).unwrap(), 3);
+        assert_eq!(copy.get(&address).unwrap(), store.get(&address).unwrap());
+        assert_eq!(copy.get(&txid).unwrap(), store.get(&txid).unwrap());
+
+        // round trip through CSV
+        let out = dir.path().join("out.csv");
+        assert_eq!(store.export_csv(&out).unwrap(), 3);
+        let copy = LabelStore::open(dir.path().join("copy_csv")).unwrap();
+        assert_eq!(copy.import_csv(&out, None).unwrap(), 3);
+        assert_eq!(copy.get(&address).unwrap(), store.get(&address).unwrap());
+
+        let result = SearchResult::Transaction {
+            txid: Txid::from_hex(genesis).unwrap(),
+            height: 0,
+        };
+        assert_eq!(store.labels_of(&result).unwrap().len(), 1);
+        store.remove(&txid).unwrap();
+        assert!(store.labels_of(&result).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_labels_fingerprint() {
+        let dir = TempDir::new("labels").unwrap();
+        let store = Arc::new(LabelStore::open(dir.path().join("db")).unwrap());
+        let enricher = LabelsEnricher(store.clone());
+        let empty = enricher.fingerprint();
+        let address = LabelKey::parse("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        assert!(store.add(&address, Label::new("genesis", None)).unwrap());
+        let added = enricher.fingerprint();
+        assert_ne!(added, empty);
+        // unchanged labels keep the fingerprint
+        assert!(!store.add(&address, Label::new("genesis", None)).unwrap());
+        assert_eq!(enricher.fingerprint(), added);
+        store.remove(&address).unwrap();
+        let removed = enricher.fingerprint();
+        assert_ne!(removed, added);
+        assert_ne!(removed, empty);
+        assert_eq!(store.iter().count(), 0);
+
+        // the version survives reopening
+        drop(enricher);
+        drop(store);
+        let store = Arc::new(LabelStore::open(dir.path().join("db")).unwrap());
+        assert_eq!(store.version(), 2);
+        assert_eq!(LabelsEnricher(store).fingerprint(), removed);
+    }
+}
@@ -38,9 +38,61 @@
 //! which requires 32GB+ RAM.
 //!
 
+pub mod analysis;
 pub(crate) mod api;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod daemon;
+pub mod enrich;
+pub mod events;
+pub mod export;
+pub mod ids;
+pub mod index;
 pub mod iter;
+pub mod join;
+#[cfg(feature = "on-disk-utxo")]
+pub mod labels;
+pub mod meta;
 pub mod parser;
+pub mod pipelines;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+pub mod utxo;
 
 #[doc(inline)]
 pub use crate::api::*;
+
+// jemalloc takes precedence over mimalloc if both are enabled (e.g. `--all-features`),
+// wrapped in `CountingAllocator` only with `alloc-stats`
+#[cfg(all(feature = "jemalloc", feature = "alloc-stats"))]
+#[global_allocator]
+static GLOBAL: iter::CountingAllocator<tikv_jemallocator::Jemalloc> =
+    iter::CountingAllocator(tikv_jemallocator::Jemalloc);
+
+#[cfg(all(feature = "jemalloc", not(feature = "alloc-stats")))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(
+    feature = "mimalloc",
+    feature = "alloc-stats",
+    not(feature = "jemalloc")
+))]
+#[global_allocator]
+static GLOBAL: iter::CountingAllocator<mimalloc::MiMalloc> =
+    iter::CountingAllocator(mimalloc::MiMalloc);
+
+#[cfg(all(
+    feature = "mimalloc",
+    not(any(feature = "jemalloc", feature = "alloc-stats"))
+))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(
+    feature = "alloc-stats",
+    not(any(feature = "jemalloc", feature = "mimalloc"))
+))]
+#[global_allocator]
+static GLOBAL: iter::CountingAllocator<std::alloc::System> =
+    iter::CountingAllocator(std::alloc::System);
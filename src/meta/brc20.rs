@@ -0,0 +1,123 @@
+//!
+//! BRC-20: fungible tokens as JSON inscriptions.
+//!
+use crate::meta::envelope::inscriptions;
+use crate::meta::{MetaEvent, MetaProtocolDecoder};
+use bitcoin::Transaction;
+use serde::Deserialize;
+
+///
+/// A BRC-20 operation.
+///
+/// Amounts are kept as the decimal strings of the inscription,
+/// tickers as inscribed (the protocol compares them case-insensitively).
+/// Operations are not validated against token state (e.g. supply caps).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Brc20Op {
+    Deploy {
+        tick: String,
+        max: String,
+        lim: Option<String>,
+        dec: Option<String>,
+    },
+    Mint {
+        tick: String,
+        amt: String,
+    },
+    Transfer {
+        tick: String,
+        amt: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct Brc20Json {
+    p: String,
+    op: String,
+    tick: String,
+    max: Option<String>,
+    lim: Option<String>,
+    dec: Option<String>,
+    amt: Option<String>,
+}
+
+impl Brc20Op {
+    ///
+    /// Parse the body of an inscription, `None` if it is not a BRC-20 operation.
+    ///
+    pub fn parse(body: &[u8]) -> Option<Brc20Op> {
+        let json: Brc20Json = serde_json::from_slice(body).ok()?;
+        if json.p != "brc-20" {
+            return None;
+        }
+        match json.op.as_str() {
+            "deploy" => Some(Brc20Op::Deploy {
+                tick: json.tick,
+                max: json.max?,
+                lim: json.lim,
+                dec: json.dec,
+            }),
+            "mint" => Some(Brc20Op::Mint {
+                tick: json.tick,
+                amt: json.amt?,
+            }),
+            "transfer" => Some(Brc20Op::Transfer {
+                tick: json.tick,
+                amt: json.amt?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Decoder of BRC-20 inscriptions, producing `MetaEvent::Brc20`.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Brc20Decoder;
+
+impl MetaProtocolDecoder for Brc20Decoder {
+    fn name(&self) -> &'static str {
+        "brc-20"
+    }
+
+    fn decode(&self, tx: &Transaction) -> Vec<MetaEvent> {
+        inscriptions(tx)
+            .into_iter()
+            .filter(|i| {
+                i.content_type.as_deref().map_or(false, |t| {
+                    t.starts_with(b"text/plain") || t.starts_with(b"application/json")
+                })
+            })
+            .filter_map(|i| Brc20Op::parse(&i.body))
+            .map(MetaEvent::Brc20)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Brc20Decoder, Brc20Op};
+    use crate::meta::envelope::tests::inscription_tx;
+    use crate::meta::{MetaEvent, MetaProtocolDecoder};
+
+    #[test]
+    fn test_brc20_mint() {
+        let body = br#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#;
+        let tx = inscription_tx(b"text/plain;charset=utf-8", body);
+        assert_eq!(
+            Brc20Decoder.decode(&tx),
+            vec![MetaEvent::Brc20(Brc20Op::Mint {
+                tick: "ordi".to_string(),
+                amt: "1000".to_string(),
+            })]
+        );
+    }
+
+    #[test]
+    fn test_not_brc20() {
+        assert_eq!(Brc20Op::parse(br#"{"p":"sns","op":"reg"}"#), None);
+        assert_eq!(Brc20Op::parse(b"hello"), None);
+    }
+}
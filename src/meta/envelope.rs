@@ -0,0 +1,166 @@
+//!
+//! Ordinals inscription envelopes in taproot script path spends.
+//!
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{Script, Transaction, TxIn};
+
+/// tag of the content type field
+const CONTENT_TYPE_TAG: &[u8] = &[1];
+/// first byte of taproot annex
+const ANNEX_TAG: u8 = 0x50;
+
+///
+/// An inscription revealed in a taproot witness.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inscription {
+    /// index of the input revealing the inscription
+    pub input: usize,
+    pub content_type: Option<Vec<u8>>,
+    pub body: Vec<u8>,
+}
+
+///
+/// All inscriptions revealed by a transaction.
+///
+/// Envelopes are `OP_FALSE OP_IF "ord" <fields> OP_0 <body> OP_ENDIF`
+/// in the tapscript of script path spends.
+///
+pub fn inscriptions(tx: &Transaction) -> Vec<Inscription> {
+    let mut found = Vec::new();
+    for (i, tx_in) in tx.input.iter().enumerate() {
+        if let Some(script) = tapscript(tx_in) {
+            found.extend(parse_envelopes(&script, i));
+        }
+    }
+    found
+}
+
+///
+/// The tapscript of a script path spend (the second to last witness item,
+/// not counting the annex).
+///
+fn tapscript(tx_in: &TxIn) -> Option<Script> {
+    let items: Vec<&[u8]> = tx_in.witness.iter().collect();
+    let len = match items.last() {
+        Some(last) if items.len() >= 2 && last.first() == Some(&ANNEX_TAG) => items.len() - 1,
+        _ => items.len(),
+    };
+    if len < 2 {
+        return None;
+    }
+    Some(Script::from(items[len - 2].to_vec()))
+}
+
+/// normalize `OP_N` pushes used as field tags by early inscriptions
+fn push_data(instruction: &Instruction) -> Option<Vec<u8>> {
+    match instruction {
+        Instruction::PushBytes(data) => Some(data.to_vec()),
+        Instruction::Op(op) => {
+            let op = op.into_u8();
+            if op >= all::OP_PUSHNUM_1.into_u8() && op <= all::OP_PUSHNUM_16.into_u8() {
+                Some(vec![op - all::OP_PUSHNUM_1.into_u8() + 1])
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn parse_envelopes(script: &Script, input: usize) -> Vec<Inscription> {
+    let instructions: Vec<Instruction> = match script.instructions().collect() {
+        Ok(instructions) => instructions,
+        Err(_) => return Vec::new(),
+    };
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i + 2 < instructions.len() {
+        let is_header = matches!(instructions[i], Instruction::PushBytes(d) if d.is_empty())
+            && instructions[i + 1] == Instruction::Op(all::OP_IF)
+            && instructions[i + 2] == Instruction::PushBytes(&b"ord"[..]);
+        if !is_header {
+            i += 1;
+            continue;
+        }
+        i += 3;
+        let mut content_type = None;
+        let mut body = Vec::new();
+        let mut in_body = false;
+        while i < instructions.len() {
+            let instruction = &instructions[i];
+            i += 1;
+            if *instruction == Instruction::Op(all::OP_ENDIF) {
+                found.push(Inscription {
+                    input,
+                    content_type: content_type.take(),
+                    body: std::mem::take(&mut body),
+                });
+                break;
+            }
+            let data = match push_data(instruction) {
+                Some(data) => data,
+                // invalid envelope
+                None => break,
+            };
+            if in_body {
+                body.extend(data);
+            } else if data.is_empty() {
+                in_body = true;
+            } else if i < instructions.len() {
+                // a field: tag followed by value
+                let value = push_data(&instructions[i]);
+                i += 1;
+                if data == CONTENT_TYPE_TAG && content_type.is_none() {
+                    content_type = value;
+                }
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::inscriptions;
+    use bitcoin::blockdata::opcodes::all;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{Transaction, TxIn, Witness};
+
+    pub(crate) fn inscription_tx(content_type: &[u8], body: &[u8]) -> Transaction {
+        let tapscript = Builder::new()
+            .push_slice(&[0x20; 32])
+            .push_opcode(all::OP_CHECKSIG)
+            .push_opcode(all::OP_PUSHBYTES_0)
+            .push_opcode(all::OP_IF)
+            .push_slice(b"ord")
+            .push_slice(&[1])
+            .push_slice(content_type)
+            .push_opcode(all::OP_PUSHBYTES_0)
+            .push_slice(body)
+            .push_opcode(all::OP_ENDIF)
+            .into_script();
+        let witness = Witness::from_vec(vec![vec![0x30; 64], tapscript.to_bytes(), vec![0xc0; 33]]);
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                witness,
+                ..Default::default()
+            }],
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_inscription_envelope() {
+        let tx = inscription_tx(b"text/plain;charset=utf-8", b"hello");
+        let found = inscriptions(&tx);
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].content_type.as_deref(),
+            Some(&b"text/plain;charset=utf-8"[..])
+        );
+        assert_eq!(found[0].body, b"hello");
+    }
+}
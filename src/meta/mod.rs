@@ -0,0 +1,125 @@
+//!
+//! Decoders of meta-protocols embedded in transactions
+//! (OP_RETURN payloads and witness envelopes).
+//!
+//! Decoders implement `MetaProtocolDecoder`, and are run over blocks by
+//! `iter_meta_events`. Built-in decoders:
+//! - `RunestoneDecoder`: runestones (OP_RETURN OP_13 payloads)
+//! - `Brc20Decoder`: BRC-20 JSON inscriptions
+//! - `OpReturnDecoder`: OP_RETURN outputs of protocols of an `OpReturnRegistry`
+//!
+//! `iter_op_return_counts` counts OP_RETURN outputs by protocol in each block.
+//!
+//! # Example
+//!
+//! ```rust
+//! use bitcoin_explorer::BitcoinDB;
+//! use bitcoin_explorer::meta::{iter_meta_events, Brc20Decoder, MetaProtocolDecoder, RunestoneDecoder};
+//! use std::path::Path;
+//!
+//! let path = Path::new("/Users/me/bitcoin");
+//! let db = BitcoinDB::new(path, false).unwrap();
+//!
+//! let decoders: Vec<Box<dyn MetaProtocolDecoder>> =
+//!     vec![Box::new(RunestoneDecoder), Box::new(Brc20Decoder)];
+//! for record in iter_meta_events(&db, 840000..840010, decoders) {
+//!     println!("{} {}: {:?}", record.height, record.txid, record.event);
+//! }
+//! ```
+//!
+mod brc20;
+mod envelope;
+mod op_return;
+mod runes;
+
+pub use brc20::{Brc20Decoder, Brc20Op};
+pub use envelope::{inscriptions, Inscription};
+pub use op_return::{
+    iter_op_return_counts, op_return_payload, OpReturnCounts, OpReturnDecoder, OpReturnRegistry,
+    UNKNOWN_PROTOCOL,
+};
+pub use runes::{Edict, Rune, RuneId, Runestone, RunestoneDecoder};
+
+use crate::api::BitcoinDB;
+use bitcoin::{Block, Transaction, Txid};
+use std::ops::Range;
+
+///
+/// Events produced by meta-protocol decoders.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetaEvent {
+    Runestone(Runestone),
+    Brc20(Brc20Op),
+    /// an OP_RETURN output of a registered protocol
+    OpReturn {
+        protocol: &'static str,
+        payload: Vec<u8>,
+    },
+    /// events of user-defined decoders
+    Other {
+        protocol: &'static str,
+        payload: Vec<u8>,
+    },
+}
+
+///
+/// A `MetaEvent` with the transaction it was found in.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaEventRecord {
+    pub height: usize,
+    pub txid: Txid,
+    pub event: MetaEvent,
+}
+
+///
+/// A decoder of a meta-protocol.
+///
+/// Implement this trait to extract custom protocols,
+/// returning `MetaEvent::Other` for protocols without a built-in event type.
+///
+pub trait MetaProtocolDecoder: Send + Sync {
+    /// name of the protocol
+    fn name(&self) -> &'static str;
+
+    /// events carried by a transaction, empty if none
+    fn decode(&self, tx: &Transaction) -> Vec<MetaEvent>;
+}
+
+///
+/// Iterate through the events of `decoders` in blocks of `range`,
+/// in block order, transaction order, then decoder order.
+///
+pub fn iter_meta_events(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    decoders: Vec<Box<dyn MetaProtocolDecoder>>,
+) -> impl Iterator<Item = MetaEventRecord> {
+    let start = range.start;
+    db.iter_block::<Block>(range.start, range.end)
+        .enumerate()
+        .flat_map(move |(i, block)| decode_block(start + i, &block, &decoders))
+}
+
+fn decode_block(
+    height: usize,
+    block: &Block,
+    decoders: &[Box<dyn MetaProtocolDecoder>],
+) -> Vec<MetaEventRecord> {
+    let mut records = Vec::new();
+    for tx in &block.txdata {
+        let mut txid = None;
+        for decoder in decoders {
+            for event in decoder.decode(tx) {
+                let txid = *txid.get_or_insert_with(|| tx.txid());
+                records.push(MetaEventRecord {
+                    height,
+                    txid,
+                    event,
+                });
+            }
+        }
+    }
+    records
+}
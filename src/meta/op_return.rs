@@ -0,0 +1,296 @@
+//!
+//! OP_RETURN outputs classified by the prefix of their payload.
+//!
+use crate::api::BitcoinDB;
+use crate::meta::{MetaEvent, MetaProtocolDecoder};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{Block, Script, Transaction};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// protocol of payloads matching no prefix of the registry
+pub const UNKNOWN_PROTOCOL: &str = "unknown";
+
+///
+/// Protocols recognized by the prefix of their OP_RETURN payload
+/// (the concatenated data pushes after `OP_RETURN`).
+///
+/// The longest matching prefix wins. Protocols committing bare hashes
+/// (e.g. OpenTimestamps calendars, see `analysis::opentimestamps`)
+/// have no prefix to register.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpReturnRegistry {
+    /// sorted by decreasing prefix length
+    prefixes: Vec<(Vec<u8>, &'static str)>,
+}
+
+impl Default for OpReturnRegistry {
+    fn default() -> Self {
+        OpReturnRegistry::builtin()
+    }
+}
+
+impl OpReturnRegistry {
+    ///
+    /// A registry without any protocol.
+    ///
+    pub fn empty() -> Self {
+        OpReturnRegistry {
+            prefixes: Vec::new(),
+        }
+    }
+
+    ///
+    /// A registry of well-known protocols:
+    /// omni, counterparty, stacks, rsk (merge-mining tags),
+    /// open_assets, proof_of_existence, and segwit witness commitments.
+    ///
+    pub fn builtin() -> Self {
+        let mut registry = OpReturnRegistry::empty();
+        registry.register("omni", b"omni");
+        registry.register("counterparty", b"CNTRPRTY");
+        registry.register("stacks", b"X2");
+        registry.register("rsk", b"RSKBLOCK:");
+        registry.register("open_assets", b"OA\x01\x00");
+        registry.register("proof_of_existence", b"DOCPROOF");
+        registry.register("witness_commitment", &[0xaa, 0x21, 0xa9, 0xed]);
+        registry
+    }
+
+    ///
+    /// Add a prefix of `protocol` (a protocol may have several prefixes).
+    ///
+    /// An already registered prefix is reassigned to `protocol`.
+    ///
+    pub fn register(&mut self, protocol: &'static str, prefix: &[u8]) -> &mut Self {
+        self.prefixes.retain(|(p, _)| p != prefix);
+        self.prefixes.push((prefix.to_vec(), protocol));
+        self.prefixes
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        self
+    }
+
+    ///
+    /// Remove all prefixes of `protocol`, returns whether any was registered.
+    ///
+    /// Its payloads are then counted as `UNKNOWN_PROTOCOL`
+    /// (or under another protocol with a shorter matching prefix).
+    ///
+    pub fn unregister(&mut self, protocol: &str) -> bool {
+        let len = self.prefixes.len();
+        self.prefixes.retain(|(_, name)| *name != protocol);
+        self.prefixes.len() != len
+    }
+
+    /// registered protocols, sorted and deduplicated
+    pub fn protocols(&self) -> Vec<&'static str> {
+        let mut protocols: Vec<&'static str> = self.prefixes.iter().map(|(_, n)| *n).collect();
+        protocols.sort_unstable();
+        protocols.dedup();
+        protocols
+    }
+
+    ///
+    /// Protocol of a payload, `None` if no prefix matches.
+    ///
+    pub fn classify(&self, payload: &[u8]) -> Option<&'static str> {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| payload.starts_with(prefix))
+            .map(|(_, name)| *name)
+    }
+}
+
+///
+/// Payload of an OP_RETURN output: its data pushes concatenated,
+/// `None` if the script is not OP_RETURN.
+///
+/// Parsing stops at the first invalid instruction.
+///
+pub fn op_return_payload(script: &Script) -> Option<Vec<u8>> {
+    if !script.is_op_return() {
+        return None;
+    }
+    let mut payload = Vec::new();
+    for instruction in script.instructions().skip(1) {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) => payload.extend_from_slice(data),
+            Ok(Instruction::Op(_)) => {}
+            Err(_) => break,
+        }
+    }
+    Some(payload)
+}
+
+///
+/// Decoder of OP_RETURN outputs of registered protocols,
+/// producing `MetaEvent::OpReturn` (one per output).
+///
+#[derive(Debug, Clone, Default)]
+pub struct OpReturnDecoder {
+    pub registry: OpReturnRegistry,
+}
+
+impl OpReturnDecoder {
+    pub fn new(registry: OpReturnRegistry) -> Self {
+        OpReturnDecoder { registry }
+    }
+}
+
+impl MetaProtocolDecoder for OpReturnDecoder {
+    fn name(&self) -> &'static str {
+        "op_return"
+    }
+
+    fn decode(&self, tx: &Transaction) -> Vec<MetaEvent> {
+        tx.output
+            .iter()
+            .filter_map(|o| op_return_payload(&o.script_pubkey))
+            .filter_map(|payload| {
+                self.registry
+                    .classify(&payload)
+                    .map(|protocol| MetaEvent::OpReturn { protocol, payload })
+            })
+            .collect()
+    }
+}
+
+///
+/// Number of OP_RETURN outputs of a block by protocol.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpReturnCounts {
+    pub height: usize,
+    /// all OP_RETURN outputs of the block
+    pub total: u64,
+    /// outputs by protocol, including `UNKNOWN_PROTOCOL`
+    pub by_protocol: BTreeMap<&'static str, u64>,
+}
+
+impl OpReturnCounts {
+    fn of(height: usize, block: &Block, registry: &OpReturnRegistry) -> Self {
+        let mut counts = OpReturnCounts {
+            height,
+            total: 0,
+            by_protocol: BTreeMap::new(),
+        };
+        for output in block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+            if let Some(payload) = op_return_payload(&output.script_pubkey) {
+                let protocol = registry.classify(&payload).unwrap_or(UNKNOWN_PROTOCOL);
+                *counts.by_protocol.entry(protocol).or_insert(0) += 1;
+                counts.total += 1;
+            }
+        }
+        counts
+    }
+
+    /// share of the OP_RETURN outputs of `protocol` (0 if none)
+    pub fn share(&self, protocol: &str) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.by_protocol.get(protocol).copied().unwrap_or(0) as f64 / self.total as f64
+    }
+}
+
+///
+/// Iterate through the OP_RETURN counts by protocol of blocks in `range`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::BitcoinDB;
+/// use bitcoin_explorer::meta::{iter_op_return_counts, OpReturnRegistry};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let mut registry = OpReturnRegistry::builtin();
+/// registry.register("my_protocol", b"MYP");
+/// for counts in iter_op_return_counts(&db, 600000..600100, registry) {
+///     println!("{}: {:.1}% omni", counts.height, counts.share("omni") * 100.0);
+/// }
+/// ```
+///
+pub fn iter_op_return_counts(
+    db: &BitcoinDB,
+    range: Range<usize>,
+    registry: OpReturnRegistry,
+) -> impl Iterator<Item = OpReturnCounts> {
+    let start = range.start;
+    db.iter_block::<Block>(range.start, range.end)
+        .enumerate()
+        .map(move |(i, block)| OpReturnCounts::of(start + i, &block, &registry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::opcodes::all;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{Network, TxOut};
+
+    fn op_return(pushes: &[&[u8]]) -> TxOut {
+        let mut builder = Builder::new().push_opcode(all::OP_RETURN);
+        for push in pushes {
+            builder = builder.push_slice(push);
+        }
+        TxOut {
+            value: 0,
+            script_pubkey: builder.into_script(),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut registry = OpReturnRegistry::builtin();
+        assert_eq!(registry.classify(b"omni\x00\x00\x00\x00"), Some("omni"));
+        assert_eq!(registry.classify(b"X2[abc"), Some("stacks"));
+        assert_eq!(registry.classify(b"hello"), None);
+
+        // the longest prefix wins
+        registry.register("stacks_extra", b"X2[");
+        assert_eq!(registry.classify(b"X2[abc"), Some("stacks_extra"));
+        assert!(registry.unregister("stacks_extra"));
+        assert!(!registry.unregister("stacks_extra"));
+        assert_eq!(registry.classify(b"X2[abc"), Some("stacks"));
+        assert!(registry.protocols().contains(&"omni"));
+
+        // pushes are concatenated
+        let out = op_return(&[b"om", b"ni"]);
+        assert_eq!(op_return_payload(&out.script_pubkey).unwrap(), b"omni");
+        assert_eq!(op_return_payload(&Script::new()), None);
+    }
+
+    #[test]
+    fn test_op_return_counts() {
+        let mut block = genesis_block(Network::Bitcoin);
+        let mut tx = block.txdata[0].clone();
+        tx.output = vec![
+            op_return(&[b"omni\x00\x01"]),
+            op_return(&[b"omni\x00\x02"]),
+            op_return(&[b"something else"]),
+            TxOut::default(),
+        ];
+        block.txdata.push(tx.clone());
+
+        let counts = OpReturnCounts::of(7, &block, &OpReturnRegistry::builtin());
+        assert_eq!(counts.total, 3);
+        assert_eq!(counts.by_protocol["omni"], 2);
+        assert_eq!(counts.by_protocol[UNKNOWN_PROTOCOL], 1);
+        assert!((counts.share("omni") - 2.0 / 3.0).abs() < 1e-9);
+
+        let events = OpReturnDecoder::default().decode(&tx);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0],
+            MetaEvent::OpReturn {
+                protocol: "omni",
+                payload: b"omni\x00\x01".to_vec()
+            }
+        );
+    }
+}
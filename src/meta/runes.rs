@@ -0,0 +1,330 @@
+//!
+//! Runestones: rune protocol messages in `OP_RETURN OP_13` outputs.
+//!
+use crate::meta::{MetaEvent, MetaProtocolDecoder};
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{Script, Transaction};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// tag terminating fields, followed by edicts
+const TAG_BODY: u128 = 0;
+/// tag of the etched rune name
+const TAG_RUNE: u128 = 4;
+/// tag of the rune id to mint, as two integers (block, tx)
+const TAG_MINT: u128 = 20;
+/// tag of the output receiving unallocated runes
+const TAG_POINTER: u128 = 22;
+/// tag of flags (bit 0: etching)
+const TAG_FLAGS: u128 = 2;
+
+///
+/// Rune id: height and transaction index of the etching.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RuneId {
+    pub block: u64,
+    pub tx: u32,
+}
+
+///
+/// A rune name, displayed in modified base-26 (`A`..`Z`).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Rune(pub u128);
+
+impl fmt::Display for Rune {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut n = self.0;
+        let mut symbol = Vec::new();
+        if n == u128::MAX {
+            return write!(f, "BCGDENLQRQWDSLRUGSNLBTMFIJAV");
+        }
+        n += 1;
+        while n > 0 {
+            symbol.push(b'A' + ((n - 1) % 26) as u8);
+            n = (n - 1) / 26;
+        }
+        symbol.reverse();
+        write!(f, "{}", String::from_utf8_lossy(&symbol))
+    }
+}
+
+///
+/// Transfer `amount` of rune `id` to output `output`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edict {
+    pub id: RuneId,
+    pub amount: u128,
+    pub output: u32,
+}
+
+///
+/// A decoded runestone.
+///
+/// This is a structural decoding: unknown fields are kept in `fields`,
+/// and `cenotaph` is set for malformed messages
+/// (which burn the input runes under the protocol rules).
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Runestone {
+    pub edicts: Vec<Edict>,
+    /// all fields (tag -> values) before the body
+    pub fields: BTreeMap<u128, Vec<u128>>,
+    pub cenotaph: bool,
+}
+
+impl Runestone {
+    ///
+    /// Decode the runestone of a transaction,
+    /// i.e. the first output starting with `OP_RETURN OP_13`.
+    ///
+    pub fn decipher(tx: &Transaction) -> Option<Runestone> {
+        let payload = tx.output.iter().find_map(|o| payload(&o.script_pubkey))?;
+        let (payload, mut cenotaph) = match payload {
+            Some(payload) => (payload, false),
+            None => (Vec::new(), true),
+        };
+        let integers = match integers(&payload) {
+            Some(integers) => integers,
+            None => {
+                cenotaph = true;
+                Vec::new()
+            }
+        };
+        let mut runestone = Runestone {
+            cenotaph,
+            ..Runestone::default()
+        };
+        let mut i = 0;
+        while i < integers.len() {
+            let tag = integers[i];
+            if tag == TAG_BODY {
+                match edicts(&integers[i + 1..], tx.output.len()) {
+                    Some(edicts) => runestone.edicts = edicts,
+                    None => runestone.cenotaph = true,
+                }
+                break;
+            }
+            match integers.get(i + 1) {
+                Some(value) => runestone.fields.entry(tag).or_default().push(*value),
+                // truncated field
+                None => runestone.cenotaph = true,
+            }
+            i += 2;
+        }
+        // unrecognized even tags are fatal
+        if runestone
+            .fields
+            .keys()
+            .any(|tag| tag % 2 == 0 && !KNOWN_EVEN_TAGS.contains(tag))
+        {
+            runestone.cenotaph = true;
+        }
+        Some(runestone)
+    }
+
+    /// whether this runestone etches a new rune
+    pub fn is_etching(&self) -> bool {
+        self.field(TAG_FLAGS).map_or(false, |flags| flags & 1 == 1)
+    }
+
+    /// name of the etched rune, if specified
+    pub fn rune(&self) -> Option<Rune> {
+        self.field(TAG_RUNE).map(Rune)
+    }
+
+    /// rune to mint
+    pub fn mint(&self) -> Option<RuneId> {
+        let values = self.fields.get(&TAG_MINT)?;
+        Some(RuneId {
+            block: u64::try_from(*values.first()?).ok()?,
+            tx: u32::try_from(*values.get(1)?).ok()?,
+        })
+    }
+
+    /// output receiving unallocated runes
+    pub fn pointer(&self) -> Option<u32> {
+        u32::try_from(self.field(TAG_POINTER)?).ok()
+    }
+
+    fn field(&self, tag: u128) -> Option<u128> {
+        self.fields.get(&tag)?.first().copied()
+    }
+}
+
+/// even tags defined by the protocol
+const KNOWN_EVEN_TAGS: [u128; 11] = [2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22];
+
+///
+/// `Some(Some(payload))` for a valid runestone output,
+/// `Some(None)` if it contains non-push opcodes,
+/// `None` if the output is not a runestone.
+///
+fn payload(script: &Script) -> Option<Option<Vec<u8>>> {
+    let mut instructions = script.instructions();
+    if instructions.next() != Some(Ok(Instruction::Op(all::OP_RETURN))) {
+        return None;
+    }
+    if instructions.next() != Some(Ok(Instruction::Op(all::OP_PUSHNUM_13))) {
+        return None;
+    }
+    let mut payload = Vec::new();
+    for instruction in instructions {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) => payload.extend_from_slice(data),
+            _ => return Some(None),
+        }
+    }
+    Some(Some(payload))
+}
+
+///
+/// Decode LEB128 integers, `None` on truncated or overlong integers.
+///
+fn integers(payload: &[u8]) -> Option<Vec<u128>> {
+    let mut integers = Vec::new();
+    let mut value: u128 = 0;
+    let mut shift = 0;
+    for byte in payload {
+        if shift > 126 || (shift == 126 && byte & 0x7f > 0b11) {
+            return None;
+        }
+        value |= u128::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            integers.push(value);
+            value = 0;
+            shift = 0;
+        } else {
+            shift += 7;
+        }
+    }
+    if shift != 0 {
+        return None;
+    }
+    Some(integers)
+}
+
+///
+/// Decode delta-encoded edicts, `None` if malformed.
+///
+fn edicts(integers: &[u128], n_outputs: usize) -> Option<Vec<Edict>> {
+    let mut edicts = Vec::with_capacity(integers.len() / 4);
+    let mut id = RuneId::default();
+    for chunk in integers.chunks(4) {
+        if chunk.len() != 4 {
+            return None;
+        }
+        let block = u64::try_from(chunk[0]).ok()?;
+        let tx = u32::try_from(chunk[1]).ok()?;
+        id = if block == 0 {
+            RuneId {
+                block: id.block,
+                tx: id.tx.checked_add(tx)?,
+            }
+        } else {
+            RuneId {
+                block: id.block.checked_add(block)?,
+                tx,
+            }
+        };
+        let output = u32::try_from(chunk[3]).ok()?;
+        // output == n_outputs splits among all non-OP_RETURN outputs
+        if output as usize > n_outputs {
+            return None;
+        }
+        edicts.push(Edict {
+            id,
+            amount: chunk[2],
+            output,
+        });
+    }
+    Some(edicts)
+}
+
+///
+/// Decoder of runestones, producing `MetaEvent::Runestone`.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunestoneDecoder;
+
+impl MetaProtocolDecoder for RunestoneDecoder {
+    fn name(&self) -> &'static str {
+        "runes"
+    }
+
+    fn decode(&self, tx: &Transaction) -> Vec<MetaEvent> {
+        Runestone::decipher(tx)
+            .map(MetaEvent::Runestone)
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rune, RuneId, Runestone};
+    use bitcoin::blockdata::opcodes::all;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{Transaction, TxOut};
+
+    fn runestone_tx(payload: &[u8]) -> Transaction {
+        let script_pubkey = Builder::new()
+            .push_opcode(all::OP_RETURN)
+            .push_opcode(all::OP_PUSHNUM_13)
+            .push_slice(payload)
+            .into_script();
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: Vec::new(),
+            output: vec![
+                TxOut {
+                    value: 0,
+                    script_pubkey,
+                },
+                TxOut::default(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_rune_name() {
+        assert_eq!(Rune(0).to_string(), "A");
+        assert_eq!(Rune(25).to_string(), "Z");
+        assert_eq!(Rune(26).to_string(), "AA");
+    }
+
+    #[test]
+    fn test_decipher_mint_and_edict() {
+        // mint 840000:3, then body with one edict of 1000 (0xe8 0x07) to output 1
+        let payload = [
+            20, 0xc0, 0xa2, 0x33, 20, 3, 0, 0xc0, 0xa2, 0x33, 3, 0xe8, 0x07, 1,
+        ];
+        let runestone = Runestone::decipher(&runestone_tx(&payload)).unwrap();
+        assert!(!runestone.cenotaph);
+        let id = RuneId {
+            block: 840000,
+            tx: 3,
+        };
+        assert_eq!(runestone.mint(), Some(id));
+        assert_eq!(runestone.edicts.len(), 1);
+        assert_eq!(runestone.edicts[0].id, id);
+        assert_eq!(runestone.edicts[0].amount, 1000);
+        assert_eq!(runestone.edicts[0].output, 1);
+    }
+
+    #[test]
+    fn test_decipher_cenotaph() {
+        // truncated varint
+        let runestone = Runestone::decipher(&runestone_tx(&[0x80])).unwrap();
+        assert!(runestone.cenotaph);
+        // not a runestone
+        let mut tx = runestone_tx(&[]);
+        tx.output.remove(0);
+        assert_eq!(Runestone::decipher(&tx), None);
+    }
+}
@@ -0,0 +1,418 @@
+//!
+//! Bitcoin Core compatible asm rendering of scripts.
+//!
+use bitcoin::hashes::hex::ToHex;
+use std::fmt;
+
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_RETURN: u8 = 0x6a;
+/// scripts larger than this are unspendable (`MAX_SCRIPT_SIZE` in Core)
+const MAX_SCRIPT_SIZE: usize = 10000;
+
+///
+/// An operation of a script.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptOp<'a> {
+    ///
+    /// Push of `data` by `opcode`
+    /// (direct push `0x00..=0x4b`, or `OP_PUSHDATA1/2/4`).
+    ///
+    Push { opcode: u8, data: &'a [u8] },
+    /// Any other opcode (including `OP_1NEGATE` and `OP_1..=OP_16`)
+    Op(u8),
+}
+
+///
+/// A push running past the end of the script.
+///
+/// Iteration stops after it, as in Bitcoin Core.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedPush {
+    /// byte offset of the push opcode
+    pub offset: usize,
+    pub opcode: u8,
+}
+
+impl fmt::Display for TruncatedPush {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "truncated push (opcode 0x{:02x}) at offset {}",
+            self.opcode, self.offset
+        )
+    }
+}
+
+///
+/// Iterator of the operations of a script, see `script_ops`.
+///
+#[derive(Debug, Clone)]
+pub struct ScriptOps<'a> {
+    script: &'a [u8],
+    pos: usize,
+}
+
+///
+/// Iterate through the operations of raw script bytes.
+///
+/// Unlike parsing a `Script`, malformed scripts are handled gracefully:
+/// all operations before a truncated push are yielded,
+/// followed by a single `Err(TruncatedPush)`.
+///
+pub fn script_ops(script: &[u8]) -> ScriptOps<'_> {
+    ScriptOps { script, pos: 0 }
+}
+
+impl<'a> Iterator for ScriptOps<'a> {
+    type Item = Result<ScriptOp<'a>, TruncatedPush>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.pos;
+        let opcode = *self.script.get(offset)?;
+        let truncated = TruncatedPush { offset, opcode };
+        let (len_bytes, len) = match opcode {
+            0x00..=0x4b => (0, Some(opcode as usize)),
+            OP_PUSHDATA1 => (1, self.read_len(offset + 1, 1)),
+            OP_PUSHDATA2 => (2, self.read_len(offset + 1, 2)),
+            OP_PUSHDATA4 => (4, self.read_len(offset + 1, 4)),
+            _ => {
+                self.pos += 1;
+                return Some(Ok(ScriptOp::Op(opcode)));
+            }
+        };
+        let start = offset + 1 + len_bytes;
+        match len.and_then(|len| start.checked_add(len)) {
+            Some(end) if end <= self.script.len() => {
+                self.pos = end;
+                Some(Ok(ScriptOp::Push {
+                    opcode,
+                    data: &self.script[start..end],
+                }))
+            }
+            _ => {
+                self.pos = self.script.len();
+                Some(Err(truncated))
+            }
+        }
+    }
+}
+
+impl ScriptOps<'_> {
+    /// little-endian length of `n` bytes at `at`
+    fn read_len(&self, at: usize, n: usize) -> Option<usize> {
+        let bytes = self.script.get(at..at + n)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize),
+        )
+    }
+}
+
+///
+/// Render a script as `asm`, identical to Bitcoin Core
+/// (e.g., the `asm` field of `scriptPubKey` in RPC results).
+///
+/// Pushes of at most 4 bytes are rendered as numbers, longer pushes as hex.
+/// A truncated push is rendered as `[error]`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::script_to_asm;
+/// use bitcoin_explorer::FromHex;
+///
+/// let script = Vec::<u8>::from_hex("76a914000000000000000000000000000000000000000088ac").unwrap();
+/// assert_eq!(
+///     script_to_asm(&script),
+///     "OP_DUP OP_HASH160 0000000000000000000000000000000000000000 OP_EQUALVERIFY OP_CHECKSIG"
+/// );
+/// ```
+///
+pub fn script_to_asm(script: &[u8]) -> String {
+    to_asm(script, false)
+}
+
+///
+/// Render a scriptSig as `asm`, identical to Bitcoin Core
+/// (the `asm` field of `scriptSig` in RPC results): signatures
+/// are rendered with their decoded sighash type, e.g. `3044...01` as
+/// `3044...[ALL]`.
+///
+pub fn script_sig_to_asm(script: &[u8]) -> String {
+    to_asm(script, true)
+}
+
+fn to_asm(script: &[u8], attempt_sighash_decode: bool) -> String {
+    let unspendable = script.first() == Some(&OP_RETURN) || script.len() > MAX_SCRIPT_SIZE;
+    let mut parts: Vec<String> = Vec::new();
+    for op in script_ops(script) {
+        parts.push(match op {
+            Err(_) => "[error]".to_string(),
+            Ok(ScriptOp::Op(opcode)) => op_name(opcode).to_string(),
+            Ok(ScriptOp::Push { data, .. }) if data.len() <= 4 => script_num(data).to_string(),
+            Ok(ScriptOp::Push { data, .. }) => {
+                match sighash_name(data).filter(|_| attempt_sighash_decode && !unspendable) {
+                    Some(name) => format!("{}[{}]", data[..data.len() - 1].to_hex(), name),
+                    None => data.to_hex(),
+                }
+            }
+        });
+    }
+    parts.join(" ")
+}
+
+///
+/// Decode a minimally or non-minimally encoded script number
+/// (little-endian, sign bit in the most significant byte).
+///
+fn script_num(data: &[u8]) -> i64 {
+    let mut n = data
+        .iter()
+        .rev()
+        .fold(0i64, |acc, b| (acc << 8) | *b as i64);
+    if let Some(last) = data.last() {
+        if last & 0x80 != 0 {
+            let sign_bit = 0x80i64 << (8 * (data.len() - 1));
+            n = -(n & !sign_bit);
+        }
+    }
+    n
+}
+
+///
+/// Name of the sighash type of a strictly DER encoded signature
+/// with a defined sighash type.
+///
+fn sighash_name(sig: &[u8]) -> Option<&'static str> {
+    if !is_valid_signature_encoding(sig) {
+        return None;
+    }
+    match *sig.last()? {
+        0x01 => Some("ALL"),
+        0x02 => Some("NONE"),
+        0x03 => Some("SINGLE"),
+        0x81 => Some("ALL|ANYONECANPAY"),
+        0x82 => Some("NONE|ANYONECANPAY"),
+        0x83 => Some("SINGLE|ANYONECANPAY"),
+        _ => None,
+    }
+}
+
+///
+/// BIP66 strict DER check (`IsValidSignatureEncoding` in Core),
+/// `sig` includes the trailing sighash byte.
+///
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+    let valid_int = |marker: u8, int: &[u8]| {
+        marker == 0x02
+            && !int.is_empty()
+            && int[0] & 0x80 == 0
+            && !(int.len() > 1 && int[0] == 0x00 && int[1] & 0x80 == 0)
+    };
+    valid_int(sig[2], &sig[4..4 + len_r])
+        && valid_int(sig[len_r + 4], &sig[6 + len_r..6 + len_r + len_s])
+}
+
+///
+/// Opcode names as rendered by Bitcoin Core (`GetOpName`).
+///
+fn op_name(opcode: u8) -> &'static str {
+    const NAMES: [&str; 0x6c] = [
+        "-1",
+        "OP_RESERVED",
+        "1",
+        "2",
+        "3",
+        "4",
+        "5",
+        "6",
+        "7",
+        "8",
+        "9",
+        "10",
+        "11",
+        "12",
+        "13",
+        "14",
+        "15",
+        "16",
+        "OP_NOP",
+        "OP_VER",
+        "OP_IF",
+        "OP_NOTIF",
+        "OP_VERIF",
+        "OP_VERNOTIF",
+        "OP_ELSE",
+        "OP_ENDIF",
+        "OP_VERIFY",
+        "OP_RETURN",
+        "OP_TOALTSTACK",
+        "OP_FROMALTSTACK",
+        "OP_2DROP",
+        "OP_2DUP",
+        "OP_3DUP",
+        "OP_2OVER",
+        "OP_2ROT",
+        "OP_2SWAP",
+        "OP_IFDUP",
+        "OP_DEPTH",
+        "OP_DROP",
+        "OP_DUP",
+        "OP_NIP",
+        "OP_OVER",
+        "OP_PICK",
+        "OP_ROLL",
+        "OP_ROT",
+        "OP_SWAP",
+        "OP_TUCK",
+        "OP_CAT",
+        "OP_SUBSTR",
+        "OP_LEFT",
+        "OP_RIGHT",
+        "OP_SIZE",
+        "OP_INVERT",
+        "OP_AND",
+        "OP_OR",
+        "OP_XOR",
+        "OP_EQUAL",
+        "OP_EQUALVERIFY",
+        "OP_RESERVED1",
+        "OP_RESERVED2",
+        "OP_1ADD",
+        "OP_1SUB",
+        "OP_2MUL",
+        "OP_2DIV",
+        "OP_NEGATE",
+        "OP_ABS",
+        "OP_NOT",
+        "OP_0NOTEQUAL",
+        "OP_ADD",
+        "OP_SUB",
+        "OP_MUL",
+        "OP_DIV",
+        "OP_MOD",
+        "OP_LSHIFT",
+        "OP_RSHIFT",
+        "OP_BOOLAND",
+        "OP_BOOLOR",
+        "OP_NUMEQUAL",
+        "OP_NUMEQUALVERIFY",
+        "OP_NUMNOTEQUAL",
+        "OP_LESSTHAN",
+        "OP_GREATERTHAN",
+        "OP_LESSTHANOREQUAL",
+        "OP_GREATERTHANOREQUAL",
+        "OP_MIN",
+        "OP_MAX",
+        "OP_WITHIN",
+        "OP_RIPEMD160",
+        "OP_SHA1",
+        "OP_SHA256",
+        "OP_HASH160",
+        "OP_HASH256",
+        "OP_CODESEPARATOR",
+        "OP_CHECKSIG",
+        "OP_CHECKSIGVERIFY",
+        "OP_CHECKMULTISIG",
+        "OP_CHECKMULTISIGVERIFY",
+        "OP_NOP1",
+        "OP_CHECKLOCKTIMEVERIFY",
+        "OP_CHECKSEQUENCEVERIFY",
+        "OP_NOP4",
+        "OP_NOP5",
+        "OP_NOP6",
+        "OP_NOP7",
+        "OP_NOP8",
+        "OP_NOP9",
+        "OP_NOP10",
+        "OP_CHECKSIGADD",
+    ];
+    match opcode {
+        0x4f..=0xba => NAMES[(opcode - 0x4f) as usize],
+        0xff => "OP_INVALIDOPCODE",
+        _ => "OP_UNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+
+    fn hex(s: &str) -> Vec<u8> {
+        Vec::from_hex(s).unwrap()
+    }
+
+    #[test]
+    fn test_script_to_asm() {
+        // P2WPKH
+        assert_eq!(
+            script_to_asm(&hex("0014751e76e8199196d454941c45d1b3a323f1433bd6")),
+            "0 751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+        // 1-of-1 multisig with small numbers
+        assert_eq!(script_to_asm(&hex("5151ae")), "1 1 OP_CHECKMULTISIG");
+        // numbers, including negative
+        assert_eq!(script_to_asm(&hex("4f02e80303ffff80")), "-1 1000 -65535");
+        // CLTV and unknown opcodes
+        assert_eq!(
+            script_to_asm(&hex("b1bbff")),
+            "OP_CHECKLOCKTIMEVERIFY OP_UNKNOWN OP_INVALIDOPCODE"
+        );
+        // truncated push
+        assert_eq!(script_to_asm(&hex("76a914ab")), "OP_DUP OP_HASH160 [error]");
+        assert_eq!(script_to_asm(&hex("4c")), "[error]");
+        assert_eq!(script_to_asm(&[]), "");
+    }
+
+    #[test]
+    fn test_script_sig_to_asm() {
+        let sig = "3044022063e9d1e13a4b7a2fd4f65a8b4b7da4a4b4a9f8f4a6b1f7ffd0b1ee4e85e7fa2e02203b7c09dfa4f5b36e7d6e3f9e4b7aa2a7e0a6a0e9b1d2c3e4f5a6b7c8d9e0f1a201";
+        let script = hex(&format!("47{}", sig));
+        assert_eq!(
+            script_sig_to_asm(&script),
+            format!("{}[ALL]", &sig[..sig.len() - 2])
+        );
+        assert_eq!(script_to_asm(&script), sig);
+    }
+
+    #[test]
+    fn test_script_ops() {
+        let script = hex("4c020102ac4d0500");
+        let ops: Vec<_> = script_ops(&script).collect();
+        assert_eq!(
+            ops,
+            vec![
+                Ok(ScriptOp::Push {
+                    opcode: OP_PUSHDATA1,
+                    data: &[1, 2][..]
+                }),
+                Ok(ScriptOp::Op(0xac)),
+                Err(TruncatedPush {
+                    offset: 4,
+                    opcode: OP_PUSHDATA2
+                }),
+            ]
+        );
+    }
+}
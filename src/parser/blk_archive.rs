@@ -0,0 +1,565 @@
+//!
+//! Read blk files stored as zstd archives (`blk00042.dat.zst`).
+//!
+//! Archival copies of blk files compress by about 40%. An archive
+//! is a sequence of independent zstd frames, read through an index of
+//! their offsets: a random read decompresses only the frames it covers,
+//! and sequential reads decompress the next frames in parallel.
+//!
+//! The index is the seek table of the zstd seekable format when present
+//! (as written by `compress_blk_file`), otherwise it is built by walking
+//! the frame headers. An archive compressed as a single frame
+//! (e.g. by `zstd blk00042.dat`) is readable, but every read
+//! decompresses the whole file.
+//!
+use crate::parser::errors::{OpError, OpResult};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// extension of archived blk files (after `.dat`)
+pub(crate) const ARCHIVE_EXTENSION: &str = "zst";
+/// magic number of zstd frames
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+/// magic numbers of skippable frames (low 4 bits are free)
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+/// skippable frame magic of the seek table
+const SEEK_TABLE_MAGIC: u32 = 0x184D_2A5E;
+/// last 4 bytes of a seekable archive
+const SEEKABLE_FOOTER_MAGIC: u32 = 0x8F92_EAB1;
+/// frames decompressed ahead by `ArchiveReader` when reading forward
+const DEFAULT_READ_AHEAD: usize = 1;
+
+///
+/// Default decompressed size of the frames of `compress_blk_file` (1 MB):
+/// reading a block decompresses at most 5 MB.
+///
+pub const DEFAULT_FRAME_SIZE: usize = 0x100000;
+
+/// a zstd frame of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    /// position in the archive
+    offset: u64,
+    compressed_size: u64,
+    /// position of the content in the blk file
+    start: u64,
+    size: u64,
+}
+
+///
+/// Offsets of the frames of an archive.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FrameIndex {
+    frames: Vec<Frame>,
+}
+
+impl FrameIndex {
+    ///
+    /// Index the frames of the archive at `path`,
+    /// from its seek table or by walking its frame headers.
+    ///
+    pub(crate) fn build(path: &Path) -> OpResult<FrameIndex> {
+        let mut file = BufReader::new(File::open(path)?);
+        let len = file.seek(SeekFrom::End(0))?;
+        let index = match read_seek_table(&mut file, len)? {
+            Some(index) => index,
+            None => walk_frames(&mut file, len)?,
+        };
+        Ok(index)
+    }
+
+    /// decompressed size of the archive
+    fn size(&self) -> u64 {
+        self.frames.last().map_or(0, |f| f.start + f.size)
+    }
+
+    /// frame containing byte `pos` of the blk file
+    fn frame_at(&self, pos: u64) -> Option<usize> {
+        let i = match self.frames.binary_search_by(|f| f.start.cmp(&pos)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let frame = &self.frames[i];
+        if pos < frame.start + frame.size {
+            Some(i)
+        } else {
+            None
+        }
+    }
+}
+
+fn read_u32_at<R: Read + Seek>(file: &mut R, pos: u64) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+///
+/// Index from the seek table of the zstd seekable format:
+/// a skippable frame of (compressed size, decompressed size[, checksum])
+/// entries, followed by the number of frames, a descriptor and a magic.
+///
+fn read_seek_table<R: Read + Seek>(file: &mut R, len: u64) -> OpResult<Option<FrameIndex>> {
+    if len < 17 || read_u32_at(file, len - 4)? != SEEKABLE_FOOTER_MAGIC {
+        return Ok(None);
+    }
+    let n_frames = read_u32_at(file, len - 9)? as u64;
+    let mut descriptor = [0u8; 1];
+    file.read_exact(&mut descriptor)?;
+    let entry_size = if descriptor[0] & 0x80 != 0 { 12 } else { 8 };
+    let table_size = n_frames * entry_size + 9;
+    if len < table_size + 8
+        || read_u32_at(file, len - table_size - 8)? != SEEK_TABLE_MAGIC
+        || read_u32_at(file, len - table_size - 4)? as u64 != table_size
+    {
+        return Err(OpError::from("invalid seek table in zstd archive"));
+    }
+    let mut table = vec![0u8; (n_frames * entry_size) as usize];
+    file.read_exact(&mut table)?;
+    let mut frames = Vec::with_capacity(n_frames as usize);
+    let (mut offset, mut start) = (0u64, 0u64);
+    for entry in table.chunks_exact(entry_size as usize) {
+        let compressed_size = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as u64;
+        let size = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as u64;
+        frames.push(Frame {
+            offset,
+            compressed_size,
+            start,
+            size,
+        });
+        offset += compressed_size;
+        start += size;
+    }
+    if offset > len - table_size - 8 {
+        return Err(OpError::from("invalid seek table in zstd archive"));
+    }
+    Ok(Some(FrameIndex { frames }))
+}
+
+///
+/// Index by walking the frame headers and block headers of each frame.
+/// Frames without content size are decompressed to measure it.
+///
+fn walk_frames<R: Read + Seek>(file: &mut R, len: u64) -> OpResult<FrameIndex> {
+    let mut frames = Vec::new();
+    let (mut offset, mut start) = (0u64, 0u64);
+    while offset < len {
+        let magic = read_u32_at(file, offset)?;
+        if magic & 0xFFFF_FFF0 == SKIPPABLE_MAGIC {
+            let size = read_u32_at(file, offset + 4)? as u64;
+            offset += 8 + size;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            return Err(OpError::from(
+                format!("not a zstd frame at byte {} of archive", offset).as_str(),
+            ));
+        }
+        let (compressed_size, content_size) = frame_sizes(file, offset)?;
+        let size = match content_size {
+            Some(size) => size,
+            None => decompress_frame(file, offset, compressed_size, None)?.len() as u64,
+        };
+        frames.push(Frame {
+            offset,
+            compressed_size,
+            start,
+            size,
+        });
+        offset += compressed_size;
+        start += size;
+    }
+    Ok(FrameIndex { frames })
+}
+
+///
+/// Compressed size and content size (if in the header) of the frame at `offset`.
+///
+fn frame_sizes<R: Read + Seek>(file: &mut R, offset: u64) -> OpResult<(u64, Option<u64>)> {
+    let mut descriptor = [0u8; 1];
+    file.seek(SeekFrom::Start(offset + 4))?;
+    file.read_exact(&mut descriptor)?;
+    let descriptor = descriptor[0];
+    let single_segment = descriptor & 0x20 != 0;
+    let has_checksum = descriptor & 0x04 != 0;
+    let window_size = if single_segment { 0 } else { 1 };
+    let dict_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let content_size_size = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    file.seek(SeekFrom::Current(window_size + dict_id_size))?;
+    let mut bytes = [0u8; 8];
+    file.read_exact(&mut bytes[..content_size_size])?;
+    let content_size = match content_size_size {
+        0 => None,
+        // 2 bytes sizes are offset by 256
+        2 => Some(u64::from_le_bytes(bytes) + 256),
+        _ => Some(u64::from_le_bytes(bytes)),
+    };
+    let mut pos = offset + 5 + (window_size + dict_id_size) as u64 + content_size_size as u64;
+    loop {
+        let mut header = [0u8; 4];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header[..3])?;
+        let header = u32::from_le_bytes(header);
+        let block_size = match (header >> 1) & 0x03 {
+            // RLE blocks repeat a single byte
+            1 => 1,
+            3 => return Err(OpError::from("reserved block type in zstd archive")),
+            _ => (header >> 3) as u64,
+        };
+        pos += 3 + block_size;
+        if header & 0x01 != 0 {
+            break;
+        }
+    }
+    if has_checksum {
+        pos += 4;
+    }
+    Ok((pos - offset, content_size))
+}
+
+///
+/// Decompress the frame at `offset`, of `size` bytes once decompressed if known.
+///
+fn decompress_frame<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    compressed_size: u64,
+    size: Option<u64>,
+) -> OpResult<Vec<u8>> {
+    let compressed = read_frame(file, offset, compressed_size)?;
+    decompress(&compressed, size)
+}
+
+fn read_frame<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    compressed_size: u64,
+) -> io::Result<Vec<u8>> {
+    let mut compressed = vec![0u8; compressed_size as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut compressed)?;
+    Ok(compressed)
+}
+
+fn decompress(compressed: &[u8], size: Option<u64>) -> OpResult<Vec<u8>> {
+    let content = match size {
+        Some(size) => zstd::bulk::decompress(compressed, size as usize)?,
+        None => zstd::stream::decode_all(compressed)?,
+    };
+    match size {
+        Some(size) if content.len() as u64 != size => Err(OpError::from(
+            "zstd frame size differs from the archive index",
+        )),
+        _ => Ok(content),
+    }
+}
+
+///
+/// A reader of the blk file in an archive.
+///
+/// Decompressed frames are kept while they are read, and reading
+/// past them decompresses the next `read_ahead` frames in parallel
+/// (sequentially when read from a rayon worker).
+///
+pub(crate) struct ArchiveReader {
+    file: File,
+    index: Arc<FrameIndex>,
+    /// decompressed frames, by frame number
+    frames: BTreeMap<usize, Vec<u8>>,
+    read_ahead: usize,
+    pos: u64,
+}
+
+impl ArchiveReader {
+    pub(crate) fn new(file: File, index: Arc<FrameIndex>) -> Self {
+        ArchiveReader {
+            file,
+            index,
+            frames: BTreeMap::new(),
+            read_ahead: DEFAULT_READ_AHEAD,
+            pos: 0,
+        }
+    }
+
+    ///
+    /// Number of frames decompressed (in parallel) when reading
+    /// a frame not yet decompressed, for sequential reads.
+    ///
+    pub(crate) fn set_read_ahead(&mut self, frames: usize) {
+        self.read_ahead = frames.max(1);
+    }
+
+    /// decompress frame `i` and up to `read_ahead - 1` frames after it
+    fn load(&mut self, i: usize) -> io::Result<()> {
+        if self.frames.contains_key(&i) {
+            return Ok(());
+        }
+        // frames before `i` are no longer read
+        self.frames = self.frames.split_off(&i);
+        let end = (i + self.read_ahead).min(self.index.frames.len());
+        let mut compressed = Vec::with_capacity(end - i);
+        for n in i..end {
+            if !self.frames.contains_key(&n) {
+                let frame = self.index.frames[n];
+                let bytes = read_frame(&mut self.file, frame.offset, frame.compressed_size)?;
+                compressed.push((n, frame.size, bytes));
+            }
+        }
+        let decompress_one =
+            |(n, size, bytes): (usize, u64, Vec<u8>)| (n, decompress(&bytes, Some(size)));
+        // on a rayon worker (e.g. `get_blocks`), the caller holds a blk reader
+        // permit: a nested parallel iterator could steal a task waiting
+        // for that permit on this thread, so decompress sequentially
+        let decompressed: Vec<(usize, OpResult<Vec<u8>>)> =
+            if rayon::current_thread_index().is_some() {
+                compressed.into_iter().map(decompress_one).collect()
+            } else {
+                compressed.into_par_iter().map(decompress_one).collect()
+            };
+        for (n, content) in decompressed {
+            let content =
+                content.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            self.frames.insert(n, content);
+        }
+        Ok(())
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let i = match self.index.frame_at(self.pos) {
+            Some(i) => i,
+            // end of the blk file
+            None => return Ok(0),
+        };
+        self.load(i)?;
+        let start = self.index.frames[i].start;
+        let content = &self.frames[&i][(self.pos - start) as usize..];
+        let n = content.len().min(buf.len());
+        buf[..n].copy_from_slice(&content[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ArchiveReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => offset_by(self.index.size(), delta),
+            SeekFrom::Current(delta) => offset_by(self.pos, delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the archive",
+            )),
+        }
+    }
+}
+
+fn offset_by(pos: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        pos.checked_sub(delta.unsigned_abs())
+    } else {
+        pos.checked_add(delta as u64)
+    }
+}
+
+///
+/// Whether `path` is an archived blk file (`.dat.zst`).
+///
+pub(crate) fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext == ARCHIVE_EXTENSION)
+}
+
+///
+/// Compress the blk file `src` into a seekable archive `dst`,
+/// in independent frames of `frame_size` bytes (compressed in parallel)
+/// followed by a seek table. Returns the size of the archive.
+///
+/// `BitcoinDB` reads `blk{n}.dat.zst` in place of a missing `blk{n}.dat`
+/// (with feature `compression`).
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::parser::blk_archive::{compress_blk_file, DEFAULT_FRAME_SIZE};
+/// use std::path::Path;
+///
+/// let blocks = Path::new("/Users/me/bitcoin/blocks");
+/// compress_blk_file(
+///     &blocks.join("blk00042.dat"),
+///     &blocks.join("blk00042.dat.zst"),
+///     3,
+///     DEFAULT_FRAME_SIZE,
+/// )
+/// .unwrap();
+/// std::fs::remove_file(blocks.join("blk00042.dat")).unwrap();
+/// ```
+///
+pub fn compress_blk_file(src: &Path, dst: &Path, level: i32, frame_size: usize) -> OpResult<u64> {
+    let content = fs::read(src)?;
+    let frames: Vec<io::Result<Vec<u8>>> = content
+        .par_chunks(frame_size.max(1))
+        .map(|chunk| zstd::bulk::compress(chunk, level))
+        .collect();
+    let tmp = dst.with_extension("tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    let mut table = Vec::with_capacity(frames.len() * 8);
+    let mut size = 0u64;
+    for (frame, chunk) in frames.into_iter().zip(content.chunks(frame_size.max(1))) {
+        let frame = frame?;
+        w.write_all(&frame)?;
+        table.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        size += frame.len() as u64;
+    }
+    let n_frames = (table.len() / 8) as u32;
+    w.write_all(&SEEK_TABLE_MAGIC.to_le_bytes())?;
+    w.write_all(&(table.len() as u32 + 9).to_le_bytes())?;
+    w.write_all(&table)?;
+    w.write_all(&n_frames.to_le_bytes())?;
+    // no checksums
+    w.write_all(&[0u8])?;
+    w.write_all(&SEEKABLE_FOOTER_MAGIC.to_le_bytes())?;
+    w.flush()?;
+    drop(w);
+    fs::rename(&tmp, dst)?;
+    Ok(size + 8 + table.len() as u64 + 9)
+}
+
+///
+/// Decompress a whole archive (all frames, skipping the seek table).
+///
+pub(crate) fn decompress_archive(path: &Path) -> OpResult<Vec<u8>> {
+    Ok(zstd::stream::decode_all(BufReader::new(File::open(path)?))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{BitcoinDB, BlkStorage, Block};
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    fn read_all(path: &Path) -> Vec<u8> {
+        let index = Arc::new(FrameIndex::build(path).unwrap());
+        let mut r = ArchiveReader::new(File::open(path).unwrap(), index);
+        r.set_read_ahead(4);
+        let mut content = Vec::new();
+        r.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_blk_archive() {
+        let dir = TestDir::new("blk_archive");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 20);
+        chain.write(&dir).unwrap();
+        let blocks = dir.join("blocks");
+        let blk = blocks.join("blk00000.dat");
+        let plain = fs::read(&blk).unwrap();
+        let expected: Vec<Block> = BitcoinDB::new(&dir, false)
+            .unwrap()
+            .iter_block(0, 21)
+            .collect();
+
+        // seekable archive of small frames
+        let archive = blocks.join("blk00000.dat.zst");
+        compress_blk_file(&blk, &archive, 3, 100).unwrap();
+        let index = FrameIndex::build(&archive).unwrap();
+        assert_eq!(index.frames.len(), (plain.len() + 99) / 100);
+        assert_eq!(read_all(&archive), plain);
+        assert_eq!(decompress_archive(&archive).unwrap(), plain);
+
+        // random reads
+        let mut r = ArchiveReader::new(File::open(&archive).unwrap(), Arc::new(index));
+        let mut bytes = [0u8; 150];
+        r.seek(SeekFrom::Start(250)).unwrap();
+        r.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes[..], plain[250..400]);
+        r.seek(SeekFrom::Current(-200)).unwrap();
+        r.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes[..], plain[200..350]);
+
+        // archive without seek table, and a single frame
+        let frames: Vec<u8> = plain
+            .chunks(300)
+            .flat_map(|chunk| zstd::bulk::compress(chunk, 1).unwrap())
+            .collect();
+        let walked = dir.join("walked.dat.zst");
+        fs::write(&walked, frames).unwrap();
+        assert_eq!(
+            FrameIndex::build(&walked).unwrap().frames.len(),
+            (plain.len() + 299) / 300
+        );
+        assert_eq!(read_all(&walked), plain);
+        let single = dir.join("single.dat.zst");
+        fs::write(&single, zstd::stream::encode_all(&plain[..], 3).unwrap()).unwrap();
+        assert_eq!(read_all(&single), plain);
+
+        // the archive replaces the blk file
+        fs::remove_file(&blk).unwrap();
+        let db = BitcoinDB::new(&dir, false).unwrap();
+        let blocks: Vec<Block> = db.iter_block(0, 21).collect();
+        assert_eq!(blocks, expected);
+        assert_eq!(db.get_block::<Block>(7).unwrap(), expected[7]);
+        let loc = db.get_block_location(7).unwrap();
+        assert_eq!(loc.storage, BlkStorage::Archived);
+        db.prefetch_blocks(&[7]).unwrap();
+    }
+
+    #[test]
+    fn test_blk_archive_single_reader() {
+        use crate::iter::ResourceCoordinator;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let dir = TestDir::new("blk_archive_single_reader");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 23);
+        chain.write_split(&dir, 3).unwrap();
+        let blocks = dir.join("blocks");
+        for n in 0..8 {
+            let blk = blocks.join(format!("blk{:05}.dat", n));
+            compress_blk_file(&blk, &blk.with_extension("dat.zst"), 3, 100).unwrap();
+            fs::remove_file(&blk).unwrap();
+        }
+        let db = BitcoinDB::new(&dir, false).unwrap();
+        let heights: Vec<usize> = (0..24).collect();
+
+        // archives decompressed by `get_blocks` workers holding the only permit
+        let coordinator = ResourceCoordinator::global();
+        let max_readers = coordinator.max_blk_readers();
+        coordinator.set_max_blk_readers(1);
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || {
+            let read: Vec<OpResult<Block>> = db.get_blocks(&heights);
+            send.send(read.into_iter().filter(|b| b.is_ok()).count())
+                .unwrap();
+        });
+        let read = recv.recv_timeout(Duration::from_secs(60));
+        coordinator.set_max_blk_readers(max_readers);
+        assert_eq!(read, Ok(24));
+    }
+}
@@ -1,18 +1,174 @@
+#[cfg(feature = "mmap")]
+use crate::iter::take_vec;
+use crate::iter::{enter_stage, recycle_vec, PipelineStage, ResourceCoordinator};
+#[cfg(feature = "compression")]
+use crate::parser::blk_archive::{is_archive, ArchiveReader, FrameIndex, ARCHIVE_EXTENSION};
+#[cfg(feature = "mmap")]
+use crate::parser::blk_mmap::{MappedBytes, MmapCache};
+use crate::parser::era::{decode_block, decode_transaction_at, BlockEra};
 use crate::parser::errors::{OpError, OpErrorKind, OpResult};
 use crate::parser::reader::BlockchainRead;
-use bitcoin::{Block, Transaction};
+#[cfg(feature = "mmap")]
+use crate::parser::xor::xor_in_place;
+use crate::parser::xor::{read_xor_key, XorKey, XorReader};
+use bitcoin::consensus::encode::{serialize, Decodable, VarInt};
+use bitcoin::{Block, Network, Transaction};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::convert::From;
 use std::fs::{self, DirEntry, File};
-use std::io::{self, BufReader, Cursor, Seek, SeekFrom};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+#[cfg(any(feature = "compression", feature = "mmap"))]
+use std::sync::Arc;
+#[cfg(feature = "compression")]
+use std::sync::Mutex;
+
+/// network magic written before each block in mainnet blk files
+pub(crate) const MAINNET_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+
+///
+/// Magic bytes written before each block in blk files of `network`.
+///
+pub(crate) fn magic_of(network: Network) -> [u8; 4] {
+    network.magic().to_le_bytes()
+}
+
+/// read buffer of `read_raw_blocks` (8 MB)
+const COALESCED_READ_BUFFER: usize = 0x800000;
+
+///
+/// Position of the size prefix of the block at `offset`
+/// (block offsets of the block index follow it).
+///
+pub(crate) fn size_prefix_pos(offset: u32) -> OpResult<u64> {
+    (offset as u64)
+        .checked_sub(4)
+        .ok_or_else(|| OpError::from(format!("invalid block offset {}", offset).as_str()))
+}
 
 ///
 /// An index of all blk files found.
 ///
+/// With feature `compression`, zstd archives (`blk{n}.dat.zst`,
+/// see `blk_archive`) are read in place of missing blk files.
+///
+/// With feature `mmap`, blk files are read through memory maps
+/// (see `blk_mmap`), except archived ones. Blocks of obfuscated
+/// files are deobfuscated into a buffer, instead of decoded in place.
+///
 #[derive(Debug, Clone)]
 pub struct BlkFile {
     files: HashMap<i32, PathBuf>,
+    /// key of obfuscated blocks directories
+    xor_key: Option<XorKey>,
+    /// frame indexes of the archives read so far
+    #[cfg(feature = "compression")]
+    archives: Arc<Mutex<HashMap<i32, Arc<FrameIndex>>>>,
+    /// memory maps of the blk files read so far
+    #[cfg(feature = "mmap")]
+    mapped: Arc<MmapCache>,
+}
+
+///
+/// Reader of a blk file, plain or archived.
+///
+pub(crate) enum BlkReader {
+    Plain(File),
+    #[cfg(feature = "compression")]
+    Archive(ArchiveReader),
+}
+
+impl BlkReader {
+    ///
+    /// Open the blk file at `path` (indexing its frames if archived).
+    ///
+    pub(crate) fn open(path: &Path, key: Option<XorKey>) -> OpResult<XorReader<BlkReader>> {
+        #[cfg(feature = "compression")]
+        if is_archive(path) {
+            let index = Arc::new(FrameIndex::build(path)?);
+            let reader = BlkReader::Archive(ArchiveReader::new(File::open(path)?, index));
+            return Ok(XorReader::new(reader, key));
+        }
+        Ok(XorReader::new(BlkReader::Plain(File::open(path)?), key))
+    }
+}
+
+impl Read for BlkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BlkReader::Plain(f) => f.read(buf),
+            #[cfg(feature = "compression")]
+            BlkReader::Archive(a) => a.read(buf),
+        }
+    }
+}
+
+impl Seek for BlkReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            BlkReader::Plain(f) => f.seek(pos),
+            #[cfg(feature = "compression")]
+            BlkReader::Archive(a) => a.seek(pos),
+        }
+    }
+}
+
+///
+/// How a blk file is stored on disk.
+///
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlkStorage {
+    /// `blk{n}.dat` as written by Bitcoin Core
+    Plain,
+    /// `blk{n}.dat` XORed with `blocks/xor.dat` (Bitcoin Core v28+):
+    /// offsets address the bytes on disk, which must be XORed with `blk_xor_key()`
+    Obfuscated,
+    /// zstd archive `blk{n}.dat.zst`: offsets are positions in the
+    /// decompressed file, and do not address the bytes on disk
+    Archived,
+}
+
+///
+/// Location of a serialized block in blk files.
+///
+/// `path` and `offset` address the raw block bytes on disk
+/// only if `storage` is `BlkStorage::Plain`.
+///
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockLocation {
+    /// index of the blk file (`blk{n_file}.dat`)
+    pub n_file: i32,
+    /// path of the blk file
+    pub path: PathBuf,
+    /// byte offset of the block (right after the 8 bytes magic + size prefix)
+    pub offset: u32,
+    /// size of the serialized block in bytes
+    pub size: u32,
+    /// how the blk file is stored
+    pub storage: BlkStorage,
+}
+
+///
+/// Location of a serialized transaction in blk files.
+///
+/// `path` and `offset` address the raw transaction bytes on disk
+/// only if `storage` is `BlkStorage::Plain`.
+///
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TxLocation {
+    /// index of the blk file (`blk{n_file}.dat`)
+    pub n_file: i32,
+    /// path of the blk file
+    pub path: PathBuf,
+    /// byte offset of the block containing this transaction
+    pub block_offset: u32,
+    /// byte offset of the transaction
+    pub offset: u64,
+    /// size of the serialized transaction in bytes
+    pub size: u32,
+    /// how the blk file is stored
+    pub storage: BlkStorage,
 }
 
 impl BlkFile {
@@ -22,30 +178,332 @@ impl BlkFile {
     pub(crate) fn new(path: &Path) -> OpResult<BlkFile> {
         Ok(BlkFile {
             files: BlkFile::scan_path(path)?,
+            xor_key: read_xor_key(path)?,
+            #[cfg(feature = "compression")]
+            archives: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "mmap")]
+            mapped: Arc::new(MmapCache::new()),
         })
     }
 
+    ///
+    /// Path of `blk{n_file}.dat` if it can be read through a memory map
+    /// (not archived).
+    ///
+    #[cfg(feature = "mmap")]
+    fn mapped_path(&self, n_file: i32) -> Option<&Path> {
+        let blk_path = self.files.get(&n_file)?;
+        #[cfg(feature = "compression")]
+        if is_archive(blk_path) {
+            return None;
+        }
+        Some(blk_path)
+    }
+
+    ///
+    /// Whether blocks of `blk{n_file}.dat` can be decoded in place
+    /// from its memory map (neither archived nor obfuscated).
+    ///
+    #[cfg(feature = "mmap")]
+    fn decodes_in_map(&self, n_file: i32) -> bool {
+        self.xor_key.is_none() && self.mapped_path(n_file).is_some()
+    }
+
+    ///
+    /// `len` bytes at `offset` of `blk{n_file}.dat` as stored
+    /// (obfuscated or not), from its memory map.
+    ///
+    #[cfg(feature = "mmap")]
+    fn map_stored(&self, n_file: i32, offset: u64, len: usize) -> OpResult<MappedBytes> {
+        if !self.files.contains_key(&n_file) {
+            return Err(OpError::from("blk file not found, sync with bitcoin core"));
+        }
+        match self.mapped_path(n_file) {
+            Some(blk_path) => self.mapped.slice(n_file, blk_path, offset, len),
+            None => Err(OpError::from(
+                "blk file is archived, cannot be memory mapped",
+            )),
+        }
+    }
+
+    ///
+    /// Bytes of the block at `offset` of `blk{n_file}.dat` as stored,
+    /// from its memory map.
+    ///
+    #[cfg(feature = "mmap")]
+    fn map_stored_block(&self, n_file: i32, offset: u32) -> OpResult<MappedBytes> {
+        let _stage = enter_stage(PipelineStage::Read);
+        let start = size_prefix_pos(offset)?;
+        let mut prefix = [0u8; 4];
+        prefix.copy_from_slice(&self.map_stored(n_file, start, 4)?);
+        if let Some(key) = &self.xor_key {
+            xor_in_place(&mut prefix, key, start);
+        }
+        self.map_stored(n_file, offset as u64, u32::from_le_bytes(prefix) as usize)
+    }
+
+    ///
+    /// `len` bytes at `offset` of `blk{n_file}.dat`, from its memory map.
+    ///
+    #[cfg(feature = "mmap")]
+    pub(crate) fn map_range(&self, n_file: i32, offset: u64, len: usize) -> OpResult<MappedBytes> {
+        if self.xor_key.is_some() {
+            return Err(OpError::from(
+                "blk file is obfuscated, its bytes cannot be borrowed from the memory map",
+            ));
+        }
+        self.map_stored(n_file, offset, len)
+    }
+
+    ///
+    /// Bytes of the block at `offset` of `blk{n_file}.dat`, from its memory map.
+    ///
+    #[cfg(feature = "mmap")]
+    pub(crate) fn map_block(&self, n_file: i32, offset: u32) -> OpResult<MappedBytes> {
+        if self.xor_key.is_some() {
+            return Err(OpError::from(
+                "blk file is obfuscated, its bytes cannot be borrowed from the memory map",
+            ));
+        }
+        self.map_stored_block(n_file, offset)
+    }
+
+    ///
+    /// Open `blk{n_file}.dat`, decompressing up to `read_ahead` frames
+    /// at once (in parallel) if it is archived.
+    ///
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn open(&self, n_file: i32, read_ahead: usize) -> OpResult<XorReader<BlkReader>> {
+        let blk_path = match self.files.get(&n_file) {
+            Some(blk_path) => blk_path,
+            None => return Err(OpError::from("blk file not found, sync with bitcoin core")),
+        };
+        #[cfg(feature = "compression")]
+        if is_archive(blk_path) {
+            let cached = self.archives.lock().unwrap().get(&n_file).cloned();
+            let index = match cached {
+                Some(index) => index,
+                None => {
+                    // built outside the lock, archives are indexed once in most cases
+                    let index = Arc::new(FrameIndex::build(blk_path)?);
+                    self.archives.lock().unwrap().insert(n_file, index.clone());
+                    index
+                }
+            };
+            let mut reader = ArchiveReader::new(File::open(blk_path)?, index);
+            reader.set_read_ahead(read_ahead);
+            return Ok(XorReader::new(BlkReader::Archive(reader), self.xor_key));
+        }
+        Ok(XorReader::new(
+            BlkReader::Plain(File::open(blk_path)?),
+            self.xor_key,
+        ))
+    }
+
+    ///
+    /// XOR key of the blk files, `None` if not obfuscated.
+    ///
+    pub(crate) fn xor_key(&self) -> Option<XorKey> {
+        self.xor_key
+    }
+
+    ///
+    /// Network of the blk files, from the magic bytes starting
+    /// the lowest numbered file (`None` if unknown).
+    ///
+    pub(crate) fn detect_network(&self) -> OpResult<Option<Network>> {
+        let first = match self.files.keys().min() {
+            Some(n_file) => *n_file,
+            None => return Ok(None),
+        };
+        let mut magic = [0u8; 4];
+        self.open(first, 1)?.read_exact(&mut magic)?;
+        Ok(Network::from_magic(u32::from_le_bytes(magic)))
+    }
+
+    ///
+    /// Path of `blk{n_file}.dat`.
+    ///
+    pub(crate) fn path_of(&self, n_file: i32) -> Option<&Path> {
+        self.files.get(&n_file).map(|p| p.as_path())
+    }
+
     ///
     /// Read a Block from blk file.
     ///
     #[inline]
     pub(crate) fn read_raw_block(&self, n_file: i32, offset: u32) -> OpResult<Vec<u8>> {
+        #[cfg(feature = "mmap")]
+        if self.mapped_path(n_file).is_some() {
+            let block = self.map_stored_block(n_file, offset)?;
+            let mut raw = take_vec::<u8>();
+            raw.extend_from_slice(&block);
+            if let Some(key) = &self.xor_key {
+                xor_in_place(&mut raw, key, offset as u64);
+            }
+            return Ok(raw);
+        }
+        let start = size_prefix_pos(offset)?;
+        let _stage = enter_stage(PipelineStage::Read);
+        let _permit = ResourceCoordinator::global().acquire_blk_reader();
+        let mut r = BufReader::new(self.open(n_file, 1)?);
+        r.seek(SeekFrom::Start(start))?;
+        let block_size = r.read_u32()?;
+        let block = r.read_u8_vec(block_size)?;
+        Ok(block)
+    }
+
+    ///
+    /// Read several blocks of the same blk file with a single file handle.
+    ///
+    /// `offsets` must be sorted, so that the file is read forward,
+    /// and nearby blocks are served from the same read buffer.
+    ///
+    pub(crate) fn read_raw_blocks(&self, n_file: i32, offsets: &[u32]) -> Vec<OpResult<Vec<u8>>> {
+        #[cfg(feature = "mmap")]
+        if self.mapped_path(n_file).is_some() {
+            return offsets
+                .iter()
+                .map(|offset| self.read_raw_block(n_file, *offset))
+                .collect();
+        }
+        let _stage = enter_stage(PipelineStage::Read);
+        let _permit = ResourceCoordinator::global().acquire_blk_reader();
+        // frames of archives are read forward, decompressed in parallel
+        let read_ahead = if offsets.len() > 1 {
+            rayon::current_num_threads()
+        } else {
+            1
+        };
+        let mut r = match self.open(n_file, read_ahead) {
+            Ok(f) => BufReader::with_capacity(COALESCED_READ_BUFFER, f),
+            Err(e) => {
+                let message = e.to_string();
+                return offsets
+                    .iter()
+                    .map(|_| Err(OpError::from(message.as_str())))
+                    .collect();
+            }
+        };
+        // current position of the reader
+        let mut pos: Option<u64> = None;
+        let mut blocks = Vec::with_capacity(offsets.len());
+        for offset in offsets {
+            let start = match size_prefix_pos(*offset) {
+                Ok(start) => start,
+                Err(e) => {
+                    blocks.push(Err(e));
+                    continue;
+                }
+            };
+            let seek = match pos {
+                // move within the buffer when possible
+                Some(pos) => r.seek_relative(start as i64 - pos as i64),
+                None => r.seek(SeekFrom::Start(start)).map(|_| ()),
+            };
+            let block = seek.map_err(OpError::from).and_then(|_| {
+                let block_size = r.read_u32()?;
+                r.read_u8_vec(block_size)
+            });
+            pos = match &block {
+                Ok(b) => Some(start + 4 + b.len() as u64),
+                // unknown position after a failed read
+                Err(_) => None,
+            };
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    ///
+    /// How the blk file at `blk_path` is stored.
+    ///
+    fn storage(&self, blk_path: &Path) -> BlkStorage {
+        #[cfg(feature = "compression")]
+        if is_archive(blk_path) {
+            return BlkStorage::Archived;
+        }
+        #[cfg(not(feature = "compression"))]
+        let _ = blk_path;
+        if self.xor_key.is_some() {
+            BlkStorage::Obfuscated
+        } else {
+            BlkStorage::Plain
+        }
+    }
+
+    ///
+    /// Locate a Block in blk file, reading its size prefix.
+    ///
+    pub(crate) fn locate_block(&self, n_file: i32, offset: u32) -> OpResult<BlockLocation> {
         if let Some(blk_path) = self.files.get(&n_file) {
-            let mut r = BufReader::new(File::open(blk_path)?);
-            r.seek(SeekFrom::Start(offset as u64 - 4))?;
-            let block_size = r.read_u32()?;
-            let block = r.read_u8_vec(block_size)?;
-            Ok(block)
+            let mut r = BufReader::new(self.open(n_file, 1)?);
+            r.seek(SeekFrom::Start(size_prefix_pos(offset)?))?;
+            let size = r.read_u32()?;
+            Ok(BlockLocation {
+                n_file,
+                path: blk_path.clone(),
+                offset,
+                size,
+                storage: self.storage(blk_path),
+            })
         } else {
             Err(OpError::from("blk file not found, sync with bitcoin core"))
         }
     }
 
     ///
-    /// Read a Block from blk file.
+    /// Locate a transaction in blk file, decoding it to obtain its size.
+    ///
+    pub(crate) fn locate_transaction(
+        &self,
+        n_file: i32,
+        n_pos: u32,
+        n_tx_offset: u32,
+    ) -> OpResult<TxLocation> {
+        if let Some(blk_path) = self.files.get(&n_file) {
+            let tx = self.read_transaction(n_file, n_pos, n_tx_offset)?;
+            Ok(TxLocation {
+                n_file,
+                path: blk_path.clone(),
+                block_offset: n_pos,
+                // the size of a header is 80.
+                offset: n_pos as u64 + n_tx_offset as u64 + 80,
+                size: serialize(&tx).len() as u32,
+                storage: self.storage(blk_path),
+            })
+        } else {
+            Err(OpError::from("blk file not found, sync with bitcoin core"))
+        }
+    }
+
+    ///
+    /// Read a Block from blk file, with the decode path of `era`.
+    ///
+    pub(crate) fn read_block(&self, n_file: i32, offset: u32, era: BlockEra) -> OpResult<Block> {
+        Ok(self.read_block_sized(n_file, offset, era)?.0)
+    }
+
     ///
-    pub(crate) fn read_block(&self, n_file: i32, offset: u32) -> OpResult<Block> {
-        Cursor::new(self.read_raw_block(n_file, offset)?).read_block()
+    /// Read a block from blk file, with its serialized size.
+    ///
+    pub(crate) fn read_block_sized(
+        &self,
+        n_file: i32,
+        offset: u32,
+        era: BlockEra,
+    ) -> OpResult<(Block, usize)> {
+        // decoded in place, without copying out of the map
+        #[cfg(feature = "mmap")]
+        if self.decodes_in_map(n_file) {
+            let mapped = self.map_block(n_file, offset)?;
+            return Ok((decode_block(&mapped, era)?, mapped.len()));
+        }
+        let raw = self.read_raw_block(n_file, offset)?;
+        let size = raw.len();
+        let block = decode_block(&raw, era);
+        recycle_vec(raw);
+        Ok((block?, size))
     }
 
     ///
@@ -57,8 +515,16 @@ impl BlkFile {
         n_pos: u32,
         n_tx_offset: u32,
     ) -> OpResult<Transaction> {
-        if let Some(blk_path) = self.files.get(&n_file) {
-            let mut r = BufReader::new(File::open(blk_path)?);
+        #[cfg(feature = "mmap")]
+        if self.decodes_in_map(n_file) {
+            let block = self.map_block(n_file, n_pos)?;
+            // the size of a header is 80.
+            let start = (n_tx_offset as usize).saturating_add(80).min(block.len());
+            return io::Cursor::new(&block[start..]).read_transaction();
+        }
+        if self.files.contains_key(&n_file) {
+            let _permit = ResourceCoordinator::global().acquire_blk_reader();
+            let mut r = BufReader::new(self.open(n_file, 1)?);
             // the size of a header is 80.
             r.seek(SeekFrom::Start(n_pos as u64 + n_tx_offset as u64 + 80))?;
             r.read_transaction()
@@ -67,6 +533,48 @@ impl BlkFile {
         }
     }
 
+    ///
+    /// Read transaction `tx_index` of a block, decoding only this transaction.
+    ///
+    pub(crate) fn read_transaction_at(
+        &self,
+        n_file: i32,
+        offset: u32,
+        tx_index: usize,
+    ) -> OpResult<Transaction> {
+        #[cfg(feature = "mmap")]
+        if self.decodes_in_map(n_file) {
+            return decode_transaction_at(&self.map_block(n_file, offset)?, tx_index);
+        }
+        let raw = self.read_raw_block(n_file, offset)?;
+        let tx = decode_transaction_at(&raw, tx_index);
+        recycle_vec(raw);
+        tx
+    }
+
+    ///
+    /// Read the first (coinbase) transaction of a block,
+    /// without reading the rest of the block.
+    ///
+    pub(crate) fn read_coinbase(&self, n_file: i32, n_pos: u32) -> OpResult<Transaction> {
+        #[cfg(feature = "mmap")]
+        if self.decodes_in_map(n_file) {
+            let block = self.map_block(n_file, n_pos)?;
+            // the size of a header is 80.
+            let mut r = io::Cursor::new(&block[80.min(block.len())..]);
+            VarInt::consensus_decode(&mut r)?;
+            return r.read_transaction();
+        }
+        let _stage = enter_stage(PipelineStage::Read);
+        let _permit = ResourceCoordinator::global().acquire_blk_reader();
+        let mut r = BufReader::new(self.open(n_file, 1)?);
+        // the size of a header is 80.
+        r.seek(SeekFrom::Start(n_pos as u64 + 80))?;
+        // number of transactions
+        VarInt::consensus_decode(&mut r)?;
+        r.read_transaction()
+    }
+
     ///
     /// Scan blk folder to build an index of all blk files.
     ///
@@ -83,6 +591,9 @@ impl BlkFile {
                         if let Some(file_name) = file_name.to_str() {
                             if let Some(index) = BlkFile::parse_blk_index(file_name) {
                                 collected.insert(index, path);
+                            } else if let Some(index) = BlkFile::parse_archive_index(file_name) {
+                                // blk files take precedence over their archives
+                                collected.entry(index).or_insert(path);
                             }
                         }
                     }
@@ -125,6 +636,22 @@ impl BlkFile {
             None
         }
     }
+
+    ///
+    /// Extract index from archived block file name (`blk{n}.dat.zst`).
+    ///
+    #[cfg(feature = "compression")]
+    fn parse_archive_index(file_name: &str) -> Option<i32> {
+        file_name
+            .strip_suffix(ARCHIVE_EXTENSION)?
+            .strip_suffix('.')
+            .and_then(BlkFile::parse_blk_index)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn parse_archive_index(_file_name: &str) -> Option<i32> {
+        None
+    }
 }
 
 #[cfg(test)]
@@ -143,4 +670,10 @@ mod tests {
         assert_eq!(true, BlkFile::parse_blk_index("blkindex.dat").is_none());
         assert_eq!(true, BlkFile::parse_blk_index("invalid.dat").is_none());
     }
+
+    #[test]
+    fn test_size_prefix_pos() {
+        assert_eq!(size_prefix_pos(8).unwrap(), 4);
+        assert!(size_prefix_pos(3).is_err());
+    }
 }
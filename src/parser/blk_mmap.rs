@@ -0,0 +1,195 @@
+//!
+//! Read blk files through memory maps.
+//!
+//! Each blk file is mapped once and shared by all readers: reading a
+//! block is a bounds check instead of an `open`, a `seek` and a `read`,
+//! and blocks are decoded directly from the mapped pages.
+//!
+//! A map is extended (mapped again) when a read goes past its end,
+//! as Bitcoin Core appends to the last blk file while running.
+//!
+use crate::parser::errors::{OpError, OpResult};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+///
+/// Bytes of a blk file, borrowed from its memory map.
+///
+/// The map stays alive as long as any `MappedBytes` of it.
+///
+#[derive(Clone)]
+pub struct MappedBytes {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl MappedBytes {
+    ///
+    /// Sub-slice of these bytes, `None` if out of range.
+    ///
+    pub fn slice(&self, range: Range<usize>) -> Option<MappedBytes> {
+        if range.start > range.end || range.end > self.range.len() {
+            return None;
+        }
+        Some(MappedBytes {
+            map: self.map.clone(),
+            range: self.range.start + range.start..self.range.start + range.end,
+        })
+    }
+}
+
+impl Deref for MappedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for MappedBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for MappedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedBytes")
+            .field("range", &self.range)
+            .finish()
+    }
+}
+
+///
+/// Memory maps of the blk files read so far.
+///
+#[derive(Debug, Default)]
+pub(crate) struct MmapCache {
+    maps: Mutex<HashMap<i32, Arc<Mmap>>>,
+}
+
+impl MmapCache {
+    pub(crate) fn new() -> Self {
+        MmapCache::default()
+    }
+
+    ///
+    /// `len` bytes at `offset` of `blk{n_file}.dat` (at `path`).
+    ///
+    pub(crate) fn slice(
+        &self,
+        n_file: i32,
+        path: &Path,
+        offset: u64,
+        len: usize,
+    ) -> OpResult<MappedBytes> {
+        let end = offset
+            .checked_add(len as u64)
+            .ok_or_else(|| OpError::from("blk file range overflows"))?;
+        let cached = self.maps.lock().unwrap().get(&n_file).cloned();
+        let map = match cached {
+            Some(map) if map.len() as u64 >= end => map,
+            // not mapped yet, or the file has grown since
+            _ => {
+                let map = Arc::new(map_file(path)?);
+                self.maps.lock().unwrap().insert(n_file, map.clone());
+                map
+            }
+        };
+        if map.len() as u64 >= end {
+            Ok(MappedBytes {
+                map,
+                range: offset as usize..end as usize,
+            })
+        } else {
+            Err(OpError::from(
+                format!(
+                    "range {}..{} out of blk file {} of {} bytes",
+                    offset,
+                    end,
+                    path.display(),
+                    map.len()
+                )
+                .as_str(),
+            ))
+        }
+    }
+}
+
+fn map_file(path: &Path) -> OpResult<Mmap> {
+    let f = File::open(path)?;
+    // SAFETY: Bitcoin Core only appends to blk files, the mapped bytes
+    // are never modified. Files removed by pruning stay mapped until
+    // the last `MappedBytes` of them is dropped.
+    Ok(unsafe { Mmap::map(&f)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{BitcoinDB, BlkStorage, Block};
+    use crate::testutil::{SyntheticChain, TestDb, TestDir};
+    use bitcoin::consensus::deserialize;
+    use std::fs;
+
+    #[test]
+    fn test_blk_mmap() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 30);
+        let db = TestDb::new("blk_mmap", &chain);
+
+        for h in 0..31 {
+            let mapped = db.get_mapped_block(h).unwrap();
+            assert_eq!(&mapped[..], &db.get_raw_block(h).unwrap()[..]);
+            let block: Block = deserialize(&mapped).unwrap();
+            assert_eq!(Some(block.block_hash()), db.get_hash_from_height(h).ok());
+        }
+
+        // slices by (file, offset, length)
+        let loc = db.get_block_location(5).unwrap();
+        assert_eq!(loc.storage, BlkStorage::Plain);
+        let header = db.get_blk_bytes(loc.n_file, loc.offset as u64, 80).unwrap();
+        assert_eq!(&header[..], &db.get_raw_block(5).unwrap()[..80]);
+        assert_eq!(header.slice(4..36).unwrap().len(), 32);
+        assert!(header.slice(0..81).is_none());
+        let size = fs::metadata(&loc.path).unwrap().len();
+        assert!(db.get_blk_bytes(loc.n_file, size, 1).is_err());
+        assert!(db.get_blk_bytes(loc.n_file + 1, 0, 1).is_err());
+
+        // grouped iteration yields the same blocks
+        let iterated: Vec<Block> = db.iter_block(0, 31).collect();
+        let expected: Vec<Block> = (0..31).map(|h| db.get_block(h).unwrap()).collect();
+        assert_eq!(iterated, expected);
+        let mut iter = db.iter_heights::<Block, _>(vec![3, 2, 1, 40, 5]);
+        assert_eq!(iter.by_ref().count(), 3);
+        assert_eq!(iter.error().unwrap().height, 40);
+    }
+
+    #[test]
+    fn test_blk_mmap_obfuscated() {
+        let dir = TestDir::new("blk_mmap_xor");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 10);
+        chain
+            .write_obfuscated(&dir, [1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        let db = BitcoinDB::new(&dir, false).unwrap();
+
+        // read from the map, deobfuscated
+        let iterated: Vec<Block> = db.iter_block(0, 11).collect();
+        for (h, block) in iterated.iter().enumerate() {
+            assert_eq!(Some(block.block_hash()), db.get_hash_from_height(h).ok());
+            let raw = db.get_raw_block(h).unwrap();
+            assert_eq!(deserialize::<Block>(&raw).unwrap(), *block);
+        }
+        // mapped bytes would be obfuscated
+        assert!(db.get_mapped_block(1).is_err());
+        let loc = db.get_block_location(1).unwrap();
+        assert_eq!(loc.storage, BlkStorage::Obfuscated);
+    }
+}
@@ -0,0 +1,242 @@
+//!
+//! Sequential scanning of blk files, with recovery from damaged framing.
+//!
+//! Blocks are normally located through the block index. When a blk file
+//! is damaged (broken magic or size prefix), `scan_blk_file` can still
+//! walk it from the start: in recovery mode, it searches forward for the
+//! next magic followed by a decodable block and resumes from there,
+//! reporting the skipped byte ranges.
+//!
+use crate::api::BitcoinDB;
+#[cfg(feature = "compression")]
+use crate::parser::blk_archive::{decompress_archive, is_archive};
+use crate::parser::blk_file::MAINNET_MAGIC;
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::xor::{read_xor_key, xor_in_place};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::{Block, BlockHash};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+/// maximum serialized size of a block (4M weight units)
+const MAX_BLOCK_SERIALIZED_SIZE: u32 = 4_000_000;
+
+///
+/// Options of `scan_blk_file`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// network magic preceding each block
+    pub magic: [u8; 4],
+    ///
+    /// Search for the next valid block after damaged framing,
+    /// instead of stopping at the first damaged byte.
+    ///
+    pub recover: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            magic: MAINNET_MAGIC,
+            recover: true,
+        }
+    }
+}
+
+///
+/// A block found by `scan_blk_file`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScannedBlock {
+    pub block_hash: BlockHash,
+    /// byte offset of the block (right after the 8 bytes magic + size prefix)
+    pub offset: u64,
+    /// size of the serialized block in bytes
+    pub size: u32,
+}
+
+///
+/// Result of `scan_blk_file`.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlkScan {
+    /// blocks in file order
+    pub blocks: Vec<ScannedBlock>,
+    ///
+    /// Damaged byte ranges that do not frame a valid block.
+    /// Without `recover`, this is the remainder of the file after the
+    /// first damaged byte. Trailing zero bytes (preallocation) are not damage.
+    ///
+    pub skipped: Vec<Range<u64>>,
+}
+
+impl BlkScan {
+    pub fn is_intact(&self) -> bool {
+        self.skipped.is_empty()
+    }
+}
+
+///
+/// Scan the blk file at `path` from the start.
+///
+/// Files of obfuscated blocks directories (with `xor.dat`
+/// next to them) are deobfuscated, and archives (`.dat.zst`,
+/// feature `compression`) are decompressed.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::parser::blk_scan::{scan_blk_file, ScanOptions};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin/blocks/blk00042.dat");
+/// let scan = scan_blk_file(path, &ScanOptions::default()).unwrap();
+/// for range in scan.skipped.iter() {
+///     println!("damaged bytes {}..{}", range.start, range.end);
+/// }
+/// println!("{} blocks recovered", scan.blocks.len());
+/// ```
+///
+pub fn scan_blk_file(path: &Path, options: &ScanOptions) -> OpResult<BlkScan> {
+    #[cfg(feature = "compression")]
+    let mut bytes = if is_archive(path) {
+        decompress_archive(path)?
+    } else {
+        fs::read(path)?
+    };
+    #[cfg(not(feature = "compression"))]
+    let mut bytes = fs::read(path)?;
+    if let Some(key) = read_xor_key(path.parent().unwrap_or_else(|| Path::new(".")))? {
+        xor_in_place(&mut bytes, &key, 0);
+    }
+    Ok(scan_bytes(&bytes, options))
+}
+
+impl BitcoinDB {
+    ///
+    /// Scan `blk{n_file}.dat`, see `scan_blk_file`.
+    ///
+    pub fn scan_blk_file(&self, n_file: i32, options: &ScanOptions) -> OpResult<BlkScan> {
+        match self.blk_file.path_of(n_file) {
+            Some(path) => scan_blk_file(path, options),
+            None => Err(OpError::from("blk file not found, sync with bitcoin core")),
+        }
+    }
+}
+
+pub(crate) fn scan_bytes(bytes: &[u8], options: &ScanOptions) -> BlkScan {
+    let mut scan = BlkScan::default();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if let Some(block) = block_at(bytes, pos, &options.magic) {
+            pos = block.offset as usize + block.size as usize;
+            scan.blocks.push(block);
+            continue;
+        }
+        if bytes[pos..].iter().all(|b| *b == 0) {
+            // preallocated space
+            break;
+        }
+        if !options.recover {
+            scan.skipped.push(pos as u64..bytes.len() as u64);
+            break;
+        }
+        let next = next_block(bytes, pos + 1, &options.magic).unwrap_or(bytes.len());
+        // do not report trailing zeros of the damaged region
+        let damaged_end = if next == bytes.len() {
+            bytes[pos..]
+                .iter()
+                .rposition(|b| *b != 0)
+                .map(|i| pos + i + 1)
+                .unwrap_or(next)
+        } else {
+            next
+        };
+        scan.skipped.push(pos as u64..damaged_end as u64);
+        pos = next;
+    }
+    scan
+}
+
+/// the block framed at `pos`, if the magic, size and block are valid
+fn block_at(bytes: &[u8], pos: usize, magic: &[u8; 4]) -> Option<ScannedBlock> {
+    let prefix = bytes.get(pos..pos + 8)?;
+    if prefix[..4] != magic[..] {
+        return None;
+    }
+    let size = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+    if !(80..=MAX_BLOCK_SERIALIZED_SIZE).contains(&size) {
+        return None;
+    }
+    let offset = pos + 8;
+    let block: Block = deserialize(bytes.get(offset..offset + size as usize)?).ok()?;
+    Some(ScannedBlock {
+        block_hash: block.block_hash(),
+        offset: offset as u64,
+        size,
+    })
+}
+
+/// position of the next valid block at or after `from`
+fn next_block(bytes: &[u8], from: usize, magic: &[u8; 4]) -> Option<usize> {
+    let mut from = from;
+    while from + 8 <= bytes.len() {
+        let found = from + bytes[from..].windows(4).position(|w| w == magic)?;
+        if block_at(bytes, found, magic).is_some() {
+            return Some(found);
+        }
+        from = found + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::Network;
+
+    fn framed(block: &Block) -> Vec<u8> {
+        let bytes = serialize(block);
+        let mut out = MAINNET_MAGIC.to_vec();
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend(bytes);
+        out
+    }
+
+    #[test]
+    fn test_scan_recovery() {
+        let a = genesis_block(Network::Bitcoin);
+        let b = genesis_block(Network::Testnet);
+        let mut file = framed(&a);
+        let damage_start = file.len() as u64;
+        // a truncated block, followed by garbage containing the magic
+        file.extend_from_slice(&framed(&b)[..50]);
+        file.extend_from_slice(&MAINNET_MAGIC);
+        file.extend_from_slice(&[0xab; 20]);
+        let damage_end = file.len() as u64;
+        file.extend(framed(&b));
+        // preallocated space
+        file.extend_from_slice(&[0; 1000]);
+
+        let scan = scan_bytes(&file, &ScanOptions::default());
+        let hashes: Vec<BlockHash> = scan.blocks.iter().map(|b| b.block_hash).collect();
+        assert_eq!(hashes, vec![a.block_hash(), b.block_hash()]);
+        assert_eq!(scan.skipped, vec![damage_start..damage_end]);
+        assert_eq!(scan.blocks[1].offset, damage_end + 8);
+
+        let options = ScanOptions {
+            recover: false,
+            ..Default::default()
+        };
+        let scan = scan_bytes(&file, &options);
+        assert_eq!(scan.blocks.len(), 1);
+        assert_eq!(scan.skipped, vec![damage_start..file.len() as u64]);
+
+        let intact = [framed(&a), vec![0; 100]].concat();
+        assert!(scan_bytes(&intact, &ScanOptions::default()).is_intact());
+    }
+}
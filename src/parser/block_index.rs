@@ -4,7 +4,8 @@ use bitcoin::{BlockHash, BlockHeader};
 use leveldb::database::iterator::LevelDBIterator;
 use leveldb::database::Database;
 use leveldb::iterator::Iterable;
-use leveldb::options::{Options, ReadOptions};
+use leveldb::kv::KV;
+use leveldb::options::{Options, ReadOptions, WriteOptions};
 use log::info;
 use serde::Serialize;
 use std::collections::{BTreeMap, HashMap};
@@ -19,14 +20,14 @@ const BLOCK_VALID_HEADER: u32 = 1;
 const BLOCK_VALID_TREE: u32 = 2;
 const BLOCK_VALID_TRANSACTIONS: u32 = 3;
 const BLOCK_VALID_CHAIN: u32 = 4;
-const BLOCK_VALID_SCRIPTS: u32 = 5;
+pub(crate) const BLOCK_VALID_SCRIPTS: u32 = 5;
 const BLOCK_VALID_MASK: u32 = BLOCK_VALID_HEADER
     | BLOCK_VALID_TREE
     | BLOCK_VALID_TRANSACTIONS
     | BLOCK_VALID_CHAIN
     | BLOCK_VALID_SCRIPTS;
-const BLOCK_HAVE_DATA: u32 = 8;
-const BLOCK_HAVE_UNDO: u32 = 16;
+pub(crate) const BLOCK_HAVE_DATA: u32 = 8;
+pub(crate) const BLOCK_HAVE_UNDO: u32 = 16;
 
 ///
 /// - Map from block height to block hash (records)
@@ -139,6 +140,22 @@ pub fn load_block_index(path: &Path) -> OpResult<Vec<BlockIndexRecord>> {
     }
 }
 
+///
+/// Write block index records to a (new) leveldb at `path`,
+/// in the format read by `load_block_index`.
+///
+pub(crate) fn write_block_index(path: &Path, records: &[BlockIndexRecord]) -> OpResult<()> {
+    let mut options = Options::new();
+    options.create_if_missing = true;
+    let db: Database<BlockKey> = Database::open(path, options)?;
+    for record in records {
+        let mut key = vec![b'b'];
+        key.extend_from_slice(&record.block_header.block_hash()[..]);
+        db.put(WriteOptions::new(), &BlockKey { key }, &record.to_bytes())?;
+    }
+    Ok(())
+}
+
 /// levelDB key util
 struct BlockKey {
     key: Vec<u8>,
@@ -198,6 +215,46 @@ impl BlockIndexRecord {
     }
 }
 
+impl BlockIndexRecord {
+    ///
+    /// Encode as levelDB value for Block Index Record.
+    ///
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, self.n_version as u64);
+        write_varint(&mut out, self.n_height as u64);
+        write_varint(&mut out, self.n_status as u64);
+        write_varint(&mut out, self.n_tx as u64);
+        if self.n_status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) > 0 {
+            write_varint(&mut out, self.n_file as u64);
+        }
+        if self.n_status & BLOCK_HAVE_DATA > 0 {
+            write_varint(&mut out, self.n_data_pos as u64);
+        }
+        if self.n_status & BLOCK_HAVE_UNDO > 0 {
+            write_varint(&mut out, self.n_undo_pos as u64);
+        }
+        out.extend(bitcoin::consensus::serialize(&self.block_header));
+        out
+    }
+}
+
+///
+/// Bitcoin Core's VARINT (inverse of `read_varint`).
+///
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = Vec::with_capacity(10);
+    loop {
+        let mark = if tmp.is_empty() { 0 } else { 0x80 };
+        tmp.push((n & 0x7F) as u8 | mark);
+        if n <= 0x7F {
+            break;
+        }
+        n = (n >> 7) - 1;
+    }
+    out.extend(tmp.iter().rev());
+}
+
 #[inline]
 fn is_block_index_record(data: &[u8]) -> bool {
     data.first() == Some(&b'b')
@@ -216,3 +273,36 @@ impl fmt::Debug for BlockIndexRecord {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_roundtrip() {
+        for (status, height) in [(BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA, 700000), (1, 0)] {
+            let record = BlockIndexRecord {
+                n_version: 250000,
+                n_height: height,
+                n_status: status,
+                n_tx: 2500,
+                n_file: 3000,
+                n_data_pos: 123456789,
+                n_undo_pos: u32::MAX,
+                block_header: bitcoin::blockdata::constants::genesis_block(
+                    bitcoin::Network::Bitcoin,
+                )
+                .header,
+            };
+            let decoded = BlockIndexRecord::from(&record.to_bytes()).unwrap();
+            assert_eq!(decoded.n_height, record.n_height);
+            assert_eq!(decoded.n_tx, record.n_tx);
+            assert_eq!(decoded.n_status, record.n_status);
+            assert_eq!(decoded.block_header, record.block_header);
+            if status & BLOCK_HAVE_DATA > 0 {
+                assert_eq!(decoded.n_file, record.n_file);
+                assert_eq!(decoded.n_data_pos, record.n_data_pos);
+            }
+        }
+    }
+}
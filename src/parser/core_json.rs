@@ -0,0 +1,451 @@
+//!
+//! JSON rendering of transactions matching Bitcoin Core's
+//! verbose `getrawtransaction` (verbosity 2, with prevouts when known).
+//!
+use crate::api::BitcoinDB;
+use crate::parser::asm::{script_ops, script_sig_to_asm, script_to_asm, ScriptOp};
+use crate::parser::errors::OpResult;
+use crate::parser::proto::amount::AmountFormat;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::{Address, BlockHash, Network, PublicKey, Script, Transaction, TxOut};
+use serde_json::{json, Map, Value};
+
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const OP_RETURN: u8 = 0x6a;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+
+/// characters allowed in descriptors, in checksum order (`INPUT_CHARSET` in Core)
+const DESCRIPTOR_INPUT_CHARSET: &[u8] =
+    b"0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const DESCRIPTOR_CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const DESCRIPTOR_GENERATORS: [u64; 5] = [
+    0xf5dee51989,
+    0xa9fdca3312,
+    0x1bab10e32d,
+    0x3706b1677a,
+    0x644d626ffd,
+];
+
+///
+/// The block containing a transaction.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreBlockInfo {
+    pub hash: BlockHash,
+    pub time: u32,
+    /// `tip_height - height + 1`, where the tip is the last downloaded block
+    pub confirmations: usize,
+}
+
+///
+/// An output spent by a transaction input.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorePrevout {
+    /// height of the block creating the output
+    pub height: usize,
+    /// the output is created by a coinbase transaction
+    pub generated: bool,
+    pub tx_out: TxOut,
+}
+
+///
+/// Context of `tx_to_corelike_json`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreTxContext {
+    /// network of rendered addresses
+    pub network: Network,
+    /// adds `blockhash`, `confirmations`, `time` and `blocktime`
+    pub block: Option<CoreBlockInfo>,
+    ///
+    /// Spent outputs, one per input (ignored for coinbase transactions).
+    /// Adds `prevout` to inputs and `fee` to the transaction.
+    ///
+    pub prevouts: Option<Vec<CorePrevout>>,
+    ///
+    /// Format of `value` and `fee`: BTC numbers like Core by default,
+    /// `DecimalString` to quote them for parsers reading numbers as floats.
+    ///
+    pub amount_format: AmountFormat,
+}
+
+impl Default for CoreTxContext {
+    fn default() -> Self {
+        CoreTxContext {
+            network: Network::Bitcoin,
+            block: None,
+            prevouts: None,
+            amount_format: AmountFormat::BtcNumber,
+        }
+    }
+}
+
+impl CoreTxContext {
+    ///
+    /// Context of a transaction in the block at `height` (without prevouts).
+    ///
+    pub fn from_block(db: &BitcoinDB, height: usize) -> OpResult<Self> {
+        let header = db.get_header(height)?;
+        Ok(CoreTxContext {
+            block: Some(CoreBlockInfo {
+                hash: header.block_header.block_hash(),
+                time: header.block_header.time,
+                confirmations: db.get_block_count().saturating_sub(height),
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+///
+/// Render `tx` as the JSON of Bitcoin Core's verbose `getrawtransaction`.
+///
+/// Amounts are formatted by `context.amount_format`.
+/// Object keys are sorted, which does not affect the structure of the JSON.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{tx_to_corelike_json, BitcoinDB, CoreTxContext};
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// let block = db.get_block::<bitcoin::Block>(170).unwrap();
+/// let context = CoreTxContext::from_block(&db, 170).unwrap();
+/// println!("{}", tx_to_corelike_json(&block.txdata[1], &context));
+/// ```
+///
+pub fn tx_to_corelike_json(tx: &Transaction, context: &CoreTxContext) -> Value {
+    let bytes = serialize(tx);
+    let weight = tx.weight();
+    let is_coinbase = tx.is_coin_base();
+    let prevouts = if is_coinbase {
+        None
+    } else {
+        context
+            .prevouts
+            .as_ref()
+            .filter(|p| p.len() == tx.input.len())
+    };
+
+    let vin: Vec<Value> = tx
+        .input
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            let mut obj = Map::new();
+            if is_coinbase {
+                obj.insert(
+                    "coinbase".into(),
+                    input.script_sig.as_bytes().to_hex().into(),
+                );
+            } else {
+                obj.insert("txid".into(), input.previous_output.txid.to_hex().into());
+                obj.insert("vout".into(), input.previous_output.vout.into());
+                obj.insert(
+                    "scriptSig".into(),
+                    json!({
+                        "asm": script_sig_to_asm(input.script_sig.as_bytes()),
+                        "hex": input.script_sig.as_bytes().to_hex(),
+                    }),
+                );
+            }
+            if !input.witness.is_empty() {
+                let items: Vec<Value> = input.witness.iter().map(|w| w.to_hex().into()).collect();
+                obj.insert("txinwitness".into(), items.into());
+            }
+            if let Some(prevouts) = prevouts {
+                let prevout = &prevouts[i];
+                let script_pubkey =
+                    script_pubkey_json(&prevout.tx_out.script_pubkey, context.network);
+                obj.insert(
+                    "prevout".into(),
+                    json!({
+                        "generated": prevout.generated,
+                        "height": prevout.height,
+                        "value": context.amount_format.to_json(prevout.tx_out.value),
+                        "scriptPubKey": script_pubkey,
+                    }),
+                );
+            }
+            obj.insert("sequence".into(), input.sequence.into());
+            Value::Object(obj)
+        })
+        .collect();
+
+    let vout: Vec<Value> = tx
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, o)| {
+            json!({
+                "value": context.amount_format.to_json(o.value),
+                "n": n,
+                "scriptPubKey": script_pubkey_json(&o.script_pubkey, context.network),
+            })
+        })
+        .collect();
+
+    let mut obj = Map::new();
+    obj.insert("txid".into(), tx.txid().to_hex().into());
+    obj.insert("hash".into(), tx.wtxid().to_hex().into());
+    obj.insert("version".into(), tx.version.into());
+    obj.insert("size".into(), bytes.len().into());
+    obj.insert("vsize".into(), ((weight + 3) / 4).into());
+    obj.insert("weight".into(), weight.into());
+    obj.insert("locktime".into(), tx.lock_time.into());
+    obj.insert("vin".into(), vin.into());
+    obj.insert("vout".into(), vout.into());
+    if let Some(prevouts) = prevouts {
+        let value_in: u64 = prevouts.iter().map(|p| p.tx_out.value).sum();
+        let value_out: u64 = tx.output.iter().map(|o| o.value).sum();
+        if value_in >= value_out {
+            obj.insert(
+                "fee".into(),
+                context.amount_format.to_json(value_in - value_out),
+            );
+        }
+    }
+    obj.insert("hex".into(), bytes.to_hex().into());
+    if let Some(block) = &context.block {
+        obj.insert("blockhash".into(), block.hash.to_hex().into());
+        obj.insert("confirmations".into(), block.confirmations.into());
+        obj.insert("time".into(), block.time.into());
+        obj.insert("blocktime".into(), block.time.into());
+    }
+    Value::Object(obj)
+}
+
+///
+/// The `scriptPubKey` object of Core: `asm`, `desc`, `hex`, `address` and `type`.
+///
+pub fn script_pubkey_json(script: &Script, network: Network) -> Value {
+    let script_type = core_script_type(script);
+    let address = match script_type {
+        "nonstandard" | "pubkey" | "multisig" | "nulldata" => None,
+        _ => Address::from_script(script, network),
+    };
+    let mut obj = Map::new();
+    obj.insert("asm".into(), script_to_asm(script.as_bytes()).into());
+    obj.insert(
+        "desc".into(),
+        infer_descriptor(script, script_type, address.as_ref()).into(),
+    );
+    obj.insert("hex".into(), script.as_bytes().to_hex().into());
+    if let Some(address) = address {
+        obj.insert("address".into(), address.to_string().into());
+    }
+    obj.insert("type".into(), script_type.into());
+    Value::Object(obj)
+}
+
+///
+/// Type of a script pubkey as named by Core (`GetTxnOutputType`).
+///
+pub fn core_script_type(script: &Script) -> &'static str {
+    let bytes = script.as_bytes();
+    if bytes == [OP_1, 0x02, 0x4e, 0x73] {
+        "anchor"
+    } else if script.is_p2sh() {
+        "scripthash"
+    } else if script.is_witness_program() {
+        let program_len = bytes.len() - 2;
+        match (bytes[0], program_len) {
+            (0, 20) => "witness_v0_keyhash",
+            (0, 32) => "witness_v0_scripthash",
+            (0, _) => "nonstandard",
+            (OP_1, 32) => "witness_v1_taproot",
+            _ => "witness_unknown",
+        }
+    } else if bytes.first() == Some(&OP_RETURN) && is_push_only(&bytes[1..]) {
+        "nulldata"
+    } else if p2pk_key(bytes).is_some() {
+        "pubkey"
+    } else if script.is_p2pkh() {
+        "pubkeyhash"
+    } else if multisig_keys(bytes).is_some() {
+        "multisig"
+    } else {
+        "nonstandard"
+    }
+}
+
+///
+/// Descriptor of a script without key information (`InferDescriptor` in Core),
+/// with checksum.
+///
+fn infer_descriptor(script: &Script, script_type: &str, address: Option<&Address>) -> String {
+    let bytes = script.as_bytes();
+    let desc = match script_type {
+        "pubkey" => p2pk_key(bytes)
+            .filter(|key| PublicKey::from_slice(key).is_ok())
+            .map(|key| format!("pk({})", key.to_hex())),
+        "multisig" => multisig_keys(bytes)
+            .filter(|(_, keys)| keys.iter().all(|k| PublicKey::from_slice(k).is_ok()))
+            .map(|(required, keys)| {
+                let keys: Vec<String> = keys.iter().map(|k| k.to_hex()).collect();
+                format!("multi({},{})", required, keys.join(","))
+            }),
+        "witness_v1_taproot" => XOnlyPublicKey::from_slice(&bytes[2..])
+            .ok()
+            .map(|_| format!("rawtr({})", bytes[2..].to_hex()))
+            .or_else(|| address.map(|a| format!("addr({})", a))),
+        _ => address.map(|a| format!("addr({})", a)),
+    }
+    .unwrap_or_else(|| format!("raw({})", bytes.to_hex()));
+    let checksum = descriptor_checksum(&desc);
+    format!("{}#{}", desc, checksum)
+}
+
+/// `IsPushOnly` in Core
+fn is_push_only(bytes: &[u8]) -> bool {
+    script_ops(bytes).all(|op| match op {
+        Ok(ScriptOp::Push { .. }) => true,
+        Ok(ScriptOp::Op(op)) => op <= OP_16,
+        Err(_) => false,
+    })
+}
+
+/// `CPubKey::ValidSize` in Core
+fn is_valid_key_size(key: &[u8]) -> bool {
+    match key.len() {
+        33 => key[0] == 0x02 || key[0] == 0x03,
+        65 => key[0] == 0x04 || key[0] == 0x06 || key[0] == 0x07,
+        _ => false,
+    }
+}
+
+/// public key of a P2PK script
+fn p2pk_key(bytes: &[u8]) -> Option<&[u8]> {
+    let (&last, rest) = bytes.split_last()?;
+    let (&len, key) = rest.split_first()?;
+    if last == OP_CHECKSIG && len as usize == key.len() && is_valid_key_size(key) {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// required signatures and public keys of a bare multisig script (`MatchMultisig` in Core)
+fn multisig_keys(bytes: &[u8]) -> Option<(u8, Vec<&[u8]>)> {
+    let ops: Vec<ScriptOp> = script_ops(bytes).collect::<Result<_, _>>().ok()?;
+    if ops.len() < 4 || ops[ops.len() - 1] != ScriptOp::Op(OP_CHECKMULTISIG) {
+        return None;
+    }
+    let small_int = |op: &ScriptOp| match op {
+        ScriptOp::Op(op) if (OP_1..=OP_16).contains(op) => Some(op - OP_1 + 1),
+        _ => None,
+    };
+    let required = small_int(&ops[0])?;
+    let n_keys = small_int(&ops[ops.len() - 2])?;
+    let mut keys = Vec::new();
+    for op in &ops[1..ops.len() - 2] {
+        match op {
+            ScriptOp::Push { data, .. } if is_valid_key_size(data) => keys.push(*data),
+            _ => return None,
+        }
+    }
+    if keys.len() != n_keys as usize || required > n_keys {
+        return None;
+    }
+    Some((required, keys))
+}
+
+fn descriptor_polymod(c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (i, g) in DESCRIPTOR_GENERATORS.iter().enumerate() {
+        if (c0 >> i) & 1 == 1 {
+            c ^= g;
+        }
+    }
+    c
+}
+
+///
+/// Checksum of a descriptor (`DescriptorChecksum` in Core).
+///
+/// Returns an empty string if `desc` contains invalid characters.
+///
+pub fn descriptor_checksum(desc: &str) -> String {
+    let mut c = 1u64;
+    let mut cls = 0u64;
+    let mut cls_count = 0;
+    for ch in desc.bytes() {
+        let pos = match DESCRIPTOR_INPUT_CHARSET.iter().position(|&x| x == ch) {
+            Some(pos) => pos as u64,
+            None => return String::new(),
+        };
+        c = descriptor_polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = descriptor_polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = descriptor_polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = descriptor_polymod(c, 0);
+    }
+    c ^= 1;
+    (0..8)
+        .map(|j| DESCRIPTOR_CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+
+    #[test]
+    fn test_descriptor_checksum() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)"), "89f8spxm");
+        assert_eq!(descriptor_checksum("raw(\u{e9})"), "");
+    }
+
+    #[test]
+    fn test_script_pubkey_json() {
+        let p2pkh = Script::from(
+            Vec::from_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap(),
+        );
+        let json = script_pubkey_json(&p2pkh, Network::Bitcoin);
+        assert_eq!(json["type"], "pubkeyhash");
+        assert_eq!(json["address"], "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        assert_eq!(
+            json["asm"],
+            "OP_DUP OP_HASH160 62e907b15cbf27d5425399ebf6f0fb50ebb88f18 OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        assert!(json["desc"]
+            .as_str()
+            .unwrap()
+            .starts_with("addr(1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa)#"));
+
+        let op_return = Script::from(Vec::from_hex("6a0401020304").unwrap());
+        let json = script_pubkey_json(&op_return, Network::Bitcoin);
+        assert_eq!(json["type"], "nulldata");
+        assert!(json.get("address").is_none());
+        assert!(json["desc"]
+            .as_str()
+            .unwrap()
+            .starts_with("raw(6a0401020304)#"));
+
+        // OP_RETURN followed by a non-push opcode
+        let op_return = Script::from(Vec::from_hex("6aac").unwrap());
+        assert_eq!(core_script_type(&op_return), "nonstandard");
+
+        let v0_unknown = Script::from(Vec::from_hex("0003010203").unwrap());
+        assert_eq!(core_script_type(&v0_unknown), "nonstandard");
+    }
+}
@@ -0,0 +1,331 @@
+//!
+//! Era-specialized block decoding.
+//!
+//! Blocks before segwit activation carry no witness data, so their
+//! transactions are decoded directly from the serialized bytes, without
+//! the segwit marker handling and `io::Read` indirection of the generic
+//! decoder. If a block of the legacy era does not decode this way
+//! (e.g. it does carry witness data), the generic decoder is used.
+//!
+use crate::iter::{enter_stage, PipelineStage};
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::reader::BlockchainRead;
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    Block, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxIn, TxMerkleNode,
+    TxOut, Txid, Witness,
+};
+use std::convert::TryFrom;
+use std::io::Cursor;
+
+///
+/// Height of the first block with segwit rules active on `network`.
+///
+pub fn segwit_activation_height(network: Network) -> usize {
+    match network {
+        Network::Bitcoin => 481_824,
+        Network::Testnet => 834_624,
+        Network::Signet => 1,
+        Network::Regtest => 0,
+    }
+}
+
+/// serialized size of the smallest input (empty script) and output
+const MIN_TX_IN_SIZE: usize = 41;
+const MIN_TX_OUT_SIZE: usize = 9;
+
+///
+/// Era of a block, selecting its decode path.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockEra {
+    /// before segwit activation
+    Legacy,
+    Segwit,
+}
+
+impl BlockEra {
+    #[inline]
+    pub fn of_height(height: usize, network: Network) -> Self {
+        if height < segwit_activation_height(network) {
+            BlockEra::Legacy
+        } else {
+            BlockEra::Segwit
+        }
+    }
+}
+
+///
+/// Decode a serialized block with the decode path of `era`.
+///
+pub(crate) fn decode_block(raw: &[u8], era: BlockEra) -> OpResult<Block> {
+    let _stage = enter_stage(PipelineStage::Decode);
+    if era == BlockEra::Legacy {
+        if let Some(block) = decode_legacy_block(raw) {
+            return Ok(block);
+        }
+    }
+    Cursor::new(raw).read_block()
+}
+
+///
+/// Decode transaction `index` of a serialized block,
+/// skipping the transactions before it without decoding them.
+///
+pub(crate) fn decode_transaction_at(raw: &[u8], index: usize) -> OpResult<Transaction> {
+    let _stage = enter_stage(PipelineStage::Decode);
+    let malformed = || OpError::from("malformed block");
+    let mut r = SliceReader::new(raw);
+    r.take(80).ok_or_else(malformed)?;
+    let n_tx = r.compact_size().ok_or_else(malformed)?;
+    if index >= n_tx {
+        return Err(OpError::from(
+            format!(
+                "transaction {} out of block of {} transactions",
+                index, n_tx
+            )
+            .as_str(),
+        ));
+    }
+    for _ in 0..index {
+        r.skip_transaction().ok_or_else(malformed)?;
+    }
+    Cursor::new(&raw[r.position()..]).read_transaction()
+}
+
+///
+/// Bounds-checked reader of a byte slice.
+///
+pub(crate) struct SliceReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        SliceReader { bytes, pos: 0 }
+    }
+
+    /// number of bytes read
+    #[inline]
+    pub(crate) fn position(&self) -> usize {
+        self.pos
+    }
+
+    #[inline]
+    pub(crate) fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let out = self.bytes.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(out)
+    }
+
+    #[inline]
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    #[inline]
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        let b = self.take(4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    #[inline]
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        let b = self.take(8)?;
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(b);
+        Some(u64::from_le_bytes(arr))
+    }
+
+    /// compact size, rejecting non-minimal encodings like the generic decoder
+    #[inline]
+    pub(crate) fn compact_size(&mut self) -> Option<usize> {
+        let first = self.take(1)?[0];
+        let n = match first {
+            0xfd => {
+                let b = self.take(2)?;
+                let n = u16::from_le_bytes([b[0], b[1]]) as u64;
+                if n < 0xfd {
+                    return None;
+                }
+                n
+            }
+            0xfe => {
+                let n = self.u32()? as u64;
+                if n <= 0xffff {
+                    return None;
+                }
+                n
+            }
+            0xff => {
+                let n = self.u64()?;
+                if n <= 0xffff_ffff {
+                    return None;
+                }
+                n
+            }
+            n => n as u64,
+        };
+        usize::try_from(n).ok()
+    }
+
+    ///
+    /// Skip a serialized transaction (with or without witness)
+    /// by its lengths, without decoding it.
+    ///
+    pub(crate) fn skip_transaction(&mut self) -> Option<()> {
+        self.take(4)?;
+        // segwit marker and flag
+        let segwit = self.bytes.get(self.pos..self.pos + 2) == Some(&[0u8, 1u8][..]);
+        if segwit {
+            self.take(2)?;
+        }
+        let n_in = self.compact_size()?;
+        for _ in 0..n_in {
+            self.take(36)?;
+            let script_len = self.compact_size()?;
+            self.take(script_len)?;
+            self.take(4)?;
+        }
+        for _ in 0..self.compact_size()? {
+            self.take(8)?;
+            let script_len = self.compact_size()?;
+            self.take(script_len)?;
+        }
+        if segwit {
+            for _ in 0..n_in {
+                for _ in 0..self.compact_size()? {
+                    let item_len = self.compact_size()?;
+                    self.take(item_len)?;
+                }
+            }
+        }
+        self.take(4)?;
+        Some(())
+    }
+
+    #[inline]
+    fn script(&mut self) -> Option<Script> {
+        let len = self.compact_size()?;
+        Some(Script::from(self.take(len)?.to_vec()))
+    }
+}
+
+fn decode_legacy_block(raw: &[u8]) -> Option<Block> {
+    let mut r = SliceReader { bytes: raw, pos: 0 };
+    let header = BlockHeader {
+        version: r.u32()? as i32,
+        prev_blockhash: BlockHash::from_slice(r.take(32)?).ok()?,
+        merkle_root: TxMerkleNode::from_slice(r.take(32)?).ok()?,
+        time: r.u32()?,
+        bits: r.u32()?,
+        nonce: r.u32()?,
+    };
+    let n_tx = r.compact_size()?;
+    let mut txdata = Vec::with_capacity(n_tx.min(r.remaining() / 60));
+    for _ in 0..n_tx {
+        txdata.push(decode_legacy_transaction(&mut r)?);
+    }
+    if r.remaining() != 0 {
+        return None;
+    }
+    Some(Block { header, txdata })
+}
+
+fn decode_legacy_transaction(r: &mut SliceReader) -> Option<Transaction> {
+    let version = r.u32()? as i32;
+    let n_in = r.compact_size()?;
+    if n_in == 0 {
+        // segwit marker, left to the generic decoder
+        return None;
+    }
+    let mut input = Vec::with_capacity(n_in.min(r.remaining() / MIN_TX_IN_SIZE));
+    for _ in 0..n_in {
+        let txid = Txid::from_slice(r.take(32)?).ok()?;
+        let vout = r.u32()?;
+        input.push(TxIn {
+            previous_output: OutPoint { txid, vout },
+            script_sig: r.script()?,
+            sequence: r.u32()?,
+            witness: Witness::default(),
+        });
+    }
+    let n_out = r.compact_size()?;
+    let mut output = Vec::with_capacity(n_out.min(r.remaining() / MIN_TX_OUT_SIZE));
+    for _ in 0..n_out {
+        output.push(TxOut {
+            value: r.u64()?,
+            script_pubkey: r.script()?,
+        });
+    }
+    Some(Transaction {
+        version,
+        lock_time: r.u32()?,
+        input,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+
+    #[test]
+    fn test_legacy_decode_matches_generic() {
+        let mut block = genesis_block(Network::Bitcoin);
+        let mut spend = block.txdata[0].clone();
+        spend.input[0].previous_output = OutPoint::new(block.txdata[0].txid(), 0);
+        spend.output.push(spend.output[0].clone());
+        block.txdata.push(spend.clone());
+        let raw = serialize(&block);
+        assert_eq!(decode_legacy_block(&raw), Some(block.clone()));
+        assert_eq!(decode_block(&raw, BlockEra::Legacy).unwrap(), block);
+
+        // witness data falls back to the generic decoder
+        block.txdata[1].input[0].witness = Witness::from_vec(vec![vec![1, 2, 3]]);
+        let raw = serialize(&block);
+        assert_eq!(decode_legacy_block(&raw), None);
+        assert_eq!(decode_block(&raw, BlockEra::Legacy).unwrap(), block);
+
+        // trailing bytes are left to the generic decoder too
+        let mut raw = serialize(&block);
+        raw.push(0);
+        assert_eq!(decode_legacy_block(&raw), None);
+        assert_eq!(decode_block(&raw, BlockEra::Legacy).unwrap(), block);
+    }
+
+    #[test]
+    fn test_decode_transaction_at() {
+        let mut block = genesis_block(Network::Bitcoin);
+        let mut spend = block.txdata[0].clone();
+        spend.input[0].previous_output = OutPoint::new(block.txdata[0].txid(), 0);
+        spend.input[0].witness = Witness::from_vec(vec![vec![0x30; 72], vec![0x02; 33]]);
+        block.txdata.push(spend.clone());
+        spend.input[0].witness = Witness::default();
+        spend.output.push(spend.output[0].clone());
+        block.txdata.push(spend);
+        let raw = serialize(&block);
+        for (i, tx) in block.txdata.iter().enumerate() {
+            assert_eq!(&decode_transaction_at(&raw, i).unwrap(), tx);
+        }
+        assert!(decode_transaction_at(&raw, 3).is_err());
+        assert!(decode_transaction_at(&raw[..raw.len() - 40], 2).is_err());
+    }
+
+    #[test]
+    fn test_block_era() {
+        assert_eq!(BlockEra::of_height(0, Network::Bitcoin), BlockEra::Legacy);
+        assert_eq!(
+            BlockEra::of_height(481_824, Network::Bitcoin),
+            BlockEra::Segwit
+        );
+        assert_eq!(
+            BlockEra::of_height(481_824, Network::Testnet),
+            BlockEra::Legacy
+        );
+        assert_eq!(BlockEra::of_height(0, Network::Regtest), BlockEra::Segwit);
+    }
+}
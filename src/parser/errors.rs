@@ -3,6 +3,7 @@ use std::convert::{self, From};
 use std::error;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::string;
 use std::sync;
 
@@ -52,7 +53,11 @@ impl error::Error for OpError {
     }
 }
 
+///
+/// Kind of an `OpError`, more kinds may be added in minor releases.
+///
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum OpErrorKind {
     None,
     IoError(io::Error),
@@ -60,6 +65,55 @@ pub enum OpErrorKind {
     RuntimeError,
     PoisonError,
     SendError,
+    /// txid lookups without txindex, with the ways to enable them
+    TxIndexUnavailable(Vec<TxIndexAlternative>),
+}
+
+///
+/// A way to enable txid lookups, listed by `OpErrorKind::TxIndexUnavailable`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TxIndexAlternative {
+    /// Bitcoin Core's txindex found at this path, not opened
+    /// (`tx_index = false`, or locked by a running Bitcoin Core)
+    CoreTxIndex(PathBuf),
+    /// restart Bitcoin Core with `-txindex=1`
+    EnableCoreTxIndex,
+    /// a `TxIndex` found at this path but not usable, to update
+    UpdateTxIndex(PathBuf),
+    /// build a `TxIndex` (feature `on-disk-utxo`), found by `BitcoinDB::new`
+    /// at `BitcoinDB::default_tx_index_path`
+    BuildTxIndex,
+    /// look up transactions with a `TxBloomIndex`
+    TxBloomIndex,
+}
+
+impl fmt::Display for TxIndexAlternative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxIndexAlternative::CoreTxIndex(path) => write!(
+                f,
+                "open txindex at {} with `tx_index = true`",
+                path.display()
+            ),
+            TxIndexAlternative::EnableCoreTxIndex => {
+                write!(f, "run Bitcoin Core with `-txindex=1`")
+            }
+            TxIndexAlternative::UpdateTxIndex(path) => {
+                write!(f, "update the TxIndex at {}", path.display())
+            }
+            TxIndexAlternative::BuildTxIndex => {
+                write!(
+                    f,
+                    "build a TxIndex at `default_tx_index_path`, or attach one with `with_tx_index`"
+                )
+            }
+            TxIndexAlternative::TxBloomIndex => {
+                write!(f, "use `get_transaction_with_bloom` with a TxBloomIndex")
+            }
+        }
+    }
 }
 
 impl fmt::Display for OpErrorKind {
@@ -70,6 +124,13 @@ impl fmt::Display for OpErrorKind {
             ref err @ OpErrorKind::PoisonError => write!(f, "Threading Error: {}", err),
             ref err @ OpErrorKind::SendError => write!(f, "Sync: {}", err),
             ref err @ OpErrorKind::RuntimeError => write!(f, "RuntimeError: {}", err),
+            OpErrorKind::TxIndexUnavailable(ref alternatives) => {
+                write!(f, "txindex unavailable, alternatives:")?;
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { "" } else { ";" }, alternative)?;
+                }
+                Ok(())
+            }
             OpErrorKind::None => write!(f, ""),
         }
     }
@@ -111,6 +172,20 @@ impl From<bitcoin::hashes::Error> for OpError {
     }
 }
 
+#[cfg(feature = "parquet")]
+impl From<arrow::error::ArrowError> for OpError {
+    fn from(err: arrow::error::ArrowError) -> Self {
+        Self::from(format!("arrow error: {}", err).as_str())
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for OpError {
+    fn from(err: parquet::errors::ParquetError) -> Self {
+        Self::from(format!("parquet error: {}", err).as_str())
+    }
+}
+
 impl convert::From<i32> for OpError {
     fn from(err_code: i32) -> Self {
         Self::from(io::Error::from_raw_os_error(err_code))
@@ -0,0 +1,180 @@
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::util::hash::bitcoin_merkle_root;
+use bitcoin::{Block, Script, Transaction, TxMerkleNode, Txid};
+
+///
+/// See BIP141: `OP_RETURN`, push 36 bytes, `0xaa21a9ed`.
+///
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+///
+/// Result of checking the commitments of a block against its transactions.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitmentCheck {
+    /// header merkle root matches the transactions
+    pub merkle_root: bool,
+    /// coinbase witness commitment matches the witness data (or is not required)
+    pub witness_commitment: bool,
+}
+
+impl CommitmentCheck {
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.merkle_root && self.witness_commitment
+    }
+}
+
+///
+/// Compute merkle root from a sequence of txids.
+///
+/// Returns `None` if `txids` is empty.
+///
+pub fn merkle_root_from_txids<I>(txids: I) -> Option<TxMerkleNode>
+where
+    I: IntoIterator<Item = Txid>,
+{
+    bitcoin_merkle_root(txids.into_iter().map(|t| t.as_hash())).map(TxMerkleNode::from_hash)
+}
+
+///
+/// Find the output position of the witness commitment in a coinbase transaction.
+///
+/// If multiple outputs match, the last one is the commitment (BIP141).
+///
+pub fn witness_commitment_index(coinbase: &Transaction) -> Option<usize> {
+    coinbase.output.iter().rposition(|o| {
+        let bytes = o.script_pubkey.as_bytes();
+        bytes.len() >= 38 && bytes[0..6] == WITNESS_COMMITMENT_HEADER
+    })
+}
+
+///
+/// Check header merkle root and witness commitment of a block.
+///
+pub fn check_commitments(block: &Block) -> CommitmentCheck {
+    CommitmentCheck {
+        merkle_root: block.check_merkle_root(),
+        witness_commitment: block.check_witness_commitment(),
+    }
+}
+
+///
+/// Recompute the header merkle root after modifying `block.txdata`.
+///
+pub fn recompute_merkle_root(block: &mut Block) -> OpResult<()> {
+    match block.compute_merkle_root() {
+        Some(root) => {
+            block.header.merkle_root = root;
+            Ok(())
+        }
+        None => Err(OpError::from("cannot compute merkle root of empty block")),
+    }
+}
+
+///
+/// Recompute the coinbase witness commitment after modifying `block.txdata`.
+///
+/// Does nothing if the coinbase has no witness commitment output.
+///
+/// Note that this modifies the coinbase, call `recompute_merkle_root`
+/// afterwards (`filter_block` takes care of the order).
+///
+pub fn recompute_witness_commitment(block: &mut Block) -> OpResult<()> {
+    let witness_root = block.witness_root();
+    let coinbase = match block.txdata.first_mut() {
+        Some(tx) if tx.is_coin_base() => tx,
+        _ => return Err(OpError::from("first transaction is not coinbase")),
+    };
+    let pos = match witness_commitment_index(coinbase) {
+        None => return Ok(()),
+        Some(pos) => pos,
+    };
+    let reserved_value = match coinbase.input.first().and_then(|i| i.witness.iter().next()) {
+        Some(value) if value.len() == 32 => value.to_vec(),
+        _ => {
+            return Err(OpError::from(
+                "witness reserved value not found in coinbase",
+            ))
+        }
+    };
+    let witness_root = match witness_root {
+        Some(root) => root,
+        None => return Err(OpError::from("cannot compute witness root of empty block")),
+    };
+    let commitment = Block::compute_witness_commitment(&witness_root, &reserved_value);
+    let mut script = coinbase.output[pos].script_pubkey.to_bytes();
+    script[6..38].copy_from_slice(&commitment[..]);
+    coinbase.output[pos].script_pubkey = Script::from(script);
+    Ok(())
+}
+
+///
+/// Remove transactions from a block, and recompute witness commitment and
+/// merkle root so that the resulting block is internally consistent.
+///
+/// The coinbase transaction is always kept.
+/// `keep` decides whether to keep each of the non-coinbase transactions.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{BitcoinDB, Block};
+/// use bitcoin_explorer::parser::merkle::filter_block;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// // keep only transactions with at most two outputs
+/// let block: Block = db.get_block(600000).unwrap();
+/// let pruned = filter_block(block, |tx| tx.output.len() <= 2).unwrap();
+/// assert!(pruned.check_merkle_root());
+/// ```
+///
+pub fn filter_block<F>(mut block: Block, mut keep: F) -> OpResult<Block>
+where
+    F: FnMut(&Transaction) -> bool,
+{
+    let mut is_first = true;
+    block.txdata.retain(|tx| {
+        if is_first {
+            is_first = false;
+            true
+        } else {
+            keep(tx)
+        }
+    });
+    recompute_witness_commitment(&mut block)?;
+    recompute_merkle_root(&mut block)?;
+    let check = check_commitments(&block);
+    if check.is_valid() {
+        Ok(block)
+    } else {
+        Err(OpError::from("commitments mismatch after filtering block"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+
+    #[test]
+    fn test_genesis_merkle_root() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let root = merkle_root_from_txids(genesis.txdata.iter().map(|tx| tx.txid()));
+        assert_eq!(root, Some(genesis.header.merkle_root));
+        assert!(merkle_root_from_txids(Vec::new()).is_none());
+        assert!(check_commitments(&genesis).is_valid());
+    }
+
+    #[test]
+    fn test_filter_keeps_coinbase() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let filtered = filter_block(genesis.clone(), |_| false).unwrap();
+        assert_eq!(filtered, genesis);
+        assert!(witness_commitment_index(&genesis.txdata[0]).is_none());
+    }
+}
@@ -2,12 +2,35 @@
 //! This module defines how to parse binary data on disk to Block structs defined in proto.
 //!
 
+/// Bitcoin Core compatible asm rendering and opcode iteration
+pub mod asm;
+
+/// Bitcoin Core compatible JSON rendering of transactions
+pub mod core_json;
+
 /// read transactions and blocks from blk.dat files
 pub mod blk_file;
 
+/// read blk files stored as zstd archives (`blk.dat.zst`)
+#[cfg(feature = "compression")]
+pub mod blk_archive;
+
+/// read blk files through memory maps
+#[cfg(feature = "mmap")]
+pub mod blk_mmap;
+
+/// sequential scanning of blk files, with recovery from damaged framing
+pub mod blk_scan;
+
+/// era-specialized block decoding (legacy blocks skip witness handling)
+pub mod era;
+
 /// read block index in memory from levelDB
 pub mod block_index;
 
+/// recompute merkle root and witness commitment of modified blocks
+pub mod merkle;
+
 /// define binary file readers
 pub mod reader;
 
@@ -17,6 +40,12 @@ pub mod script;
 /// on disk transaction index database
 pub mod tx_index;
 
+/// read spent outputs from rev.dat undo files
+pub mod undo_file;
+
+/// deobfuscate blk and rev files of Bitcoin Core v28+ (`blocks/xor.dat`)
+pub(crate) mod xor;
+
 /// various formats of blockchain data representation
 pub mod proto;
 
@@ -0,0 +1,191 @@
+//!
+//! Helpers for handling `Amount` values of proto types.
+//!
+use bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+const SATS_PER_BTC: u64 = 100_000_000;
+
+///
+/// Representation of amounts in exports and serialized outputs.
+///
+/// All formats are exact and locale independent.
+/// Text is never produced from floating point numbers.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::AmountFormat;
+///
+/// assert_eq!(AmountFormat::Satoshis.format(5_000_000_001), "5000000001");
+/// assert_eq!(AmountFormat::DecimalString.format(5_000_000_001), "50.00000001");
+/// assert_eq!(AmountFormat::DecimalString.parse("50.00000001"), Some(5_000_000_001));
+/// assert_eq!(AmountFormat::BtcNumber.to_json(5_000_000_001).to_string(), "50.00000001");
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+pub enum AmountFormat {
+    /// integer number of satoshis (default)
+    #[default]
+    Satoshis,
+    /// BTC as a decimal string with 8 fractional digits, e.g. `"0.00010000"`
+    DecimalString,
+    ///
+    /// BTC as a JSON number, as in Bitcoin Core RPC results.
+    /// Exact up to `MAX_MONEY`: the JSON text of the nearest double
+    /// is the shortest decimal of the amount (e.g. `50.0`, `1e-8`).
+    /// Text outside JSON is formatted as `DecimalString`.
+    ///
+    BtcNumber,
+}
+
+impl AmountFormat {
+    /// format `sats` as text (CSV cells, etc.)
+    pub fn format(&self, sats: u64) -> String {
+        match self {
+            AmountFormat::Satoshis => sats.to_string(),
+            AmountFormat::DecimalString | AmountFormat::BtcNumber => {
+                format!("{}.{:08}", sats / SATS_PER_BTC, sats % SATS_PER_BTC)
+            }
+        }
+    }
+
+    /// `sats` as a JSON integer, a JSON string or a JSON BTC number
+    pub fn to_json(&self, sats: u64) -> Value {
+        match self {
+            AmountFormat::Satoshis => Value::from(sats),
+            AmountFormat::DecimalString => Value::from(self.format(sats)),
+            // `sats` and 1e8 are exact doubles and the division is correctly rounded
+            AmountFormat::BtcNumber => Value::from(sats as f64 / SATS_PER_BTC as f64),
+        }
+    }
+
+    ///
+    /// Parse an amount formatted in this format.
+    ///
+    /// Decimal strings may have up to 8 fractional digits.
+    /// Returns `None` for invalid input or on overflow.
+    ///
+    pub fn parse(&self, s: &str) -> Option<u64> {
+        match self {
+            AmountFormat::Satoshis => s.parse().ok(),
+            AmountFormat::DecimalString | AmountFormat::BtcNumber => {
+                let (int, frac) = match s.find('.') {
+                    Some(dot) => (&s[..dot], &s[dot + 1..]),
+                    None => (s, ""),
+                };
+                let all_digits = |x: &str| x.bytes().all(|b| b.is_ascii_digit());
+                if int.is_empty() || frac.len() > 8 || !all_digits(int) || !all_digits(frac) {
+                    return None;
+                }
+                let frac_sats = format!("{:0<8}", frac).parse::<u64>().ok()?;
+                int.parse::<u64>()
+                    .ok()?
+                    .checked_mul(SATS_PER_BTC)?
+                    .checked_add(frac_sats)
+            }
+        }
+    }
+}
+
+impl fmt::Display for AmountFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmountFormat::Satoshis => write!(f, "satoshis"),
+            AmountFormat::DecimalString => write!(f, "decimal_string"),
+            AmountFormat::BtcNumber => write!(f, "btc_number"),
+        }
+    }
+}
+
+///
+/// Sum amounts, returning `None` on overflow.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{checked_sum, Amount};
+///
+/// let total = checked_sum(vec![Amount::from_sat(1), Amount::from_btc(1.0).unwrap()]);
+/// assert_eq!(total, Some(Amount::from_sat(100_000_001)));
+/// assert_eq!(checked_sum(vec![Amount::MAX, Amount::ONE_SAT]), None);
+/// ```
+///
+pub fn checked_sum<I>(amounts: I) -> Option<Amount>
+where
+    I: IntoIterator<Item = Amount>,
+{
+    amounts
+        .into_iter()
+        .try_fold(Amount::ZERO, |acc, a| acc.checked_add(a))
+}
+
+///
+/// Inputs minus outputs, `None` for coinbase (no inputs),
+/// on overflow, or if outputs exceed inputs.
+///
+pub(crate) fn fee_of<I, O>(inputs: I, outputs: O) -> Option<Amount>
+where
+    I: IntoIterator<Item = Amount>,
+    O: IntoIterator<Item = Amount>,
+{
+    let mut inputs = inputs.into_iter().peekable();
+    inputs.peek()?;
+    checked_sum(inputs)?.checked_sub(checked_sum(outputs)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_of() {
+        let sat = Amount::from_sat;
+        assert_eq!(fee_of(vec![sat(10), sat(5)], vec![sat(12)]), Some(sat(3)));
+        assert_eq!(fee_of(vec![], vec![sat(12)]), None);
+        assert_eq!(fee_of(vec![sat(10)], vec![sat(12)]), None);
+        assert_eq!(checked_sum(Vec::new()), Some(Amount::ZERO));
+    }
+
+    #[test]
+    fn test_amount_format() {
+        let max = 21_000_000 * SATS_PER_BTC - 1;
+        assert_eq!(AmountFormat::DecimalString.format(max), "20999999.99999999");
+        assert_eq!(AmountFormat::DecimalString.format(1), "0.00000001");
+        let formats = [
+            AmountFormat::Satoshis,
+            AmountFormat::DecimalString,
+            AmountFormat::BtcNumber,
+        ];
+        for format in formats {
+            for sats in [0, 1, 99_999_999, SATS_PER_BTC, max, u64::MAX] {
+                assert_eq!(format.parse(&format.format(sats)), Some(sats));
+            }
+        }
+        assert_eq!(AmountFormat::DecimalString.parse("1.5"), Some(150_000_000));
+        assert_eq!(AmountFormat::DecimalString.parse("1"), Some(SATS_PER_BTC));
+        assert_eq!(AmountFormat::DecimalString.parse("1.000000001"), None);
+        assert_eq!(AmountFormat::DecimalString.parse("-1.0"), None);
+        assert_eq!(AmountFormat::DecimalString.parse(".5"), None);
+        assert_eq!(AmountFormat::DecimalString.to_json(1), "0.00000001");
+        assert_eq!(AmountFormat::Satoshis.to_json(1), 1);
+        // BTC numbers round trip through JSON text for every valid amount
+        let number = |sats| AmountFormat::BtcNumber.to_json(sats).to_string();
+        assert_eq!(number(max), "20999999.99999999");
+        assert_eq!(number(5_000_000_001), "50.00000001");
+        assert_eq!(number(1_000_000_000), "10.0");
+        for sats in [0, 1, 12_345_678, 99_999_999, max, max - 12_345_678] {
+            let parsed: f64 = number(sats).parse().unwrap();
+            assert_eq!(
+                AmountFormat::DecimalString
+                    .format(sats)
+                    .parse::<f64>()
+                    .unwrap(),
+                parsed
+            );
+            assert_eq!((parsed * SATS_PER_BTC as f64).round() as u64, sats);
+        }
+    }
+}
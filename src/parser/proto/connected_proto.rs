@@ -1,10 +1,15 @@
 use crate::parser::blk_file::BlkFile;
+use crate::parser::era::BlockEra;
 use crate::parser::errors::{OpError, OpResult};
+use crate::parser::proto::amount::{checked_sum, fee_of};
 use crate::parser::proto::full_proto::{FBlockHeader, FTxOut};
 use crate::parser::proto::simple_proto::{SBlockHeader, STxOut};
+use crate::parser::script::is_dust;
+#[cfg(feature = "input-scripts")]
+use crate::parser::script::{evaluate_wrapped_script, ScriptType};
 use crate::parser::tx_index::TxDB;
 use crate::BlockIndex;
-use bitcoin::{Block, BlockHash, BlockHeader, Transaction, TxIn, TxOut, Txid};
+use bitcoin::{Amount, Block, BlockHash, BlockHeader, Transaction, TxIn, TxOut, Txid};
 use log::warn;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -79,6 +84,24 @@ pub trait ConnectedTx {
     ///
     fn add_input(&mut self, input: Self::TOut);
 
+    ///
+    /// Add a input, `tx_in` being the spending input connected to it.
+    ///
+    /// Defaults to `add_input`. This function is used in `iter_connected.rs`.
+    ///
+    fn add_spending_input(&mut self, input: Self::TOut, _tx_in: &TxIn) {
+        self.add_input(input)
+    }
+
+    ///
+    /// Add a input created in block `created_height`.
+    ///
+    /// Defaults to `add_spending_input`. This function is used in `iter_connected.rs`.
+    ///
+    fn add_input_at(&mut self, input: Self::TOut, tx_in: &TxIn, _created_height: usize) {
+        self.add_spending_input(input, tx_in)
+    }
+
     ///
     /// Build ConnectedTx from Tx,
     /// and attach inputs to this ConnectedTx using tx-index.
@@ -134,9 +157,91 @@ pub struct FConnectedTransaction {
     pub lock_time: u32,
     pub txid: Txid,
     pub input: Vec<FTxOut>,
+    /// Type of the redeem script or witness script revealed by each input,
+    /// `None` unless the input spends a P2SH or P2WSH output.
+    #[cfg(feature = "input-scripts")]
+    pub wrapped_script_types: Vec<Option<ScriptType>>,
+    /// Witness stack of each input (empty for non-segwit inputs).
+    #[cfg(feature = "input-scripts")]
+    pub input_witnesses: Vec<Vec<Vec<u8>>>,
     pub output: Vec<FTxOut>,
 }
 
+impl FConnectedTransaction {
+    ///
+    /// Sum of connected input values, `None` on overflow.
+    ///
+    pub fn input_value(&self) -> Option<Amount> {
+        checked_sum(self.input.iter().map(|o| o.value))
+    }
+
+    ///
+    /// Sum of output values, `None` on overflow.
+    ///
+    pub fn output_value(&self) -> Option<Amount> {
+        checked_sum(self.output.iter().map(|o| o.value))
+    }
+
+    ///
+    /// Transaction fee, `None` for coinbase transactions.
+    ///
+    pub fn fee(&self) -> Option<Amount> {
+        fee_of(
+            self.input.iter().map(|o| o.value),
+            self.output.iter().map(|o| o.value),
+        )
+    }
+
+    ///
+    /// Index of the ephemeral dust output, if any.
+    ///
+    /// A zero-fee transaction with exactly one dust output
+    /// (which must be spent by a child in the same package under
+    /// Bitcoin Core's ephemeral dust relay policy).
+    ///
+    pub fn ephemeral_dust(&self) -> Option<usize> {
+        if self.fee() != Some(Amount::ZERO) {
+            return None;
+        }
+        let mut dust = self
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| is_dust(o.value.as_sat(), &o.script_pubkey))
+            .map(|(i, _)| i);
+        match (dust.next(), dust.next()) {
+            (Some(i), None) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+impl SConnectedTransaction {
+    ///
+    /// Sum of connected input values, `None` on overflow.
+    ///
+    pub fn input_value(&self) -> Option<Amount> {
+        checked_sum(self.input.iter().map(|o| o.value))
+    }
+
+    ///
+    /// Sum of output values, `None` on overflow.
+    ///
+    pub fn output_value(&self) -> Option<Amount> {
+        checked_sum(self.output.iter().map(|o| o.value))
+    }
+
+    ///
+    /// Transaction fee, `None` for coinbase transactions.
+    ///
+    pub fn fee(&self) -> Option<Amount> {
+        fee_of(
+            self.input.iter().map(|o| o.value),
+            self.output.iter().map(|o| o.value),
+        )
+    }
+}
+
 impl ConnectedTx for FConnectedTransaction {
     type TOut = FTxOut;
 
@@ -146,11 +251,29 @@ impl ConnectedTx for FConnectedTransaction {
             lock_time: tx.lock_time,
             txid: tx.txid(),
             input: Vec::new(),
+            #[cfg(feature = "input-scripts")]
+            wrapped_script_types: Vec::new(),
+            #[cfg(feature = "input-scripts")]
+            input_witnesses: Vec::new(),
             output: tx.output.clone().into_iter().map(|x| x.into()).collect(),
         }
     }
 
     fn add_input(&mut self, input: Self::TOut) {
+        #[cfg(feature = "input-scripts")]
+        {
+            // the spending input is unknown
+            self.wrapped_script_types.push(None);
+            self.input_witnesses.push(Vec::new());
+        }
+        self.input.push(input);
+    }
+
+    #[cfg(feature = "input-scripts")]
+    fn add_spending_input(&mut self, input: Self::TOut, tx_in: &TxIn) {
+        self.wrapped_script_types
+            .push(evaluate_wrapped_script(&input.script_pubkey, tx_in));
+        self.input_witnesses.push(tx_in.witness.to_vec());
         self.input.push(input);
     }
 
@@ -161,16 +284,12 @@ impl ConnectedTx for FConnectedTransaction {
         blk_file: &BlkFile,
     ) -> OpResult<Self> {
         let is_coinbase = tx.is_coin_base();
-        Ok(FConnectedTransaction {
-            version: tx.version,
-            lock_time: tx.lock_time,
-            txid: tx.txid(),
-            input: connect_tx_inputs(&tx.input, is_coinbase, tx_db, blk_index, blk_file)?
-                .into_iter()
-                .map(|x| x.into())
-                .collect(),
-            output: tx.output.into_iter().map(|x| x.into()).collect(),
-        })
+        let inputs = connect_tx_inputs(&tx.input, is_coinbase, tx_db, blk_index, blk_file)?;
+        let mut connected = <FConnectedTransaction as ConnectedTx>::from(&tx);
+        for (out, tx_in) in inputs.into_iter().zip(tx.input.iter()) {
+            connected.add_spending_input(out.into(), tx_in);
+        }
+        Ok(connected)
     }
 }
 
@@ -309,20 +428,95 @@ where
                 "some outpoints aren't found, tx_index is not fully synced",
             ));
         }
-        let mut tx = Tx::from(&tx);
-        for o in outputs {
-            tx.add_input(o.into());
+        let mut connected = Tx::from(&tx);
+        for (o, tx_in) in outputs.into_iter().zip(tx.input.iter()) {
+            connected.add_spending_input(o.into(), tx_in);
         }
-        connected_tx.push(tx);
+        connected_tx.push(connected);
     }
     Ok(connected_tx)
 }
 
+///
+/// Connected block of the header time and hash, and transactions of type `T`,
+/// for internal iterators that only keep a summary of each transaction.
+///
+pub(crate) struct SummaryBlock<T> {
+    pub(crate) hash: BlockHash,
+    pub(crate) time: u32,
+    pub(crate) txdata: Vec<T>,
+}
+
+impl<T: ConnectedTx + Send> ConnectedBlock for SummaryBlock<T> {
+    type Tx = T;
+
+    fn from(block_header: BlockHeader, block_hash: BlockHash) -> Self {
+        SummaryBlock {
+            hash: block_hash,
+            time: block_header.time,
+            txdata: Vec::new(),
+        }
+    }
+
+    fn add_tx(&mut self, tx: Self::Tx) {
+        self.txdata.push(tx);
+    }
+
+    fn connect(
+        block: Block,
+        tx_db: &TxDB,
+        blk_index: &BlockIndex,
+        blk_file: &BlkFile,
+    ) -> OpResult<Self> {
+        let mut connected = <Self as ConnectedBlock>::from(block.header, block.block_hash());
+        for tx in block.txdata {
+            connected.add_tx(T::connect(tx, tx_db, blk_index, blk_file)?);
+        }
+        Ok(connected)
+    }
+}
+
+///
+/// `ConnectedTx::connect` through `from` and `add_spending_input`.
+///
+pub(crate) fn connect_tx<T: ConnectedTx>(
+    tx: Transaction,
+    tx_db: &TxDB,
+    blk_index: &BlockIndex,
+    blk_file: &BlkFile,
+) -> OpResult<T> {
+    let mut connected = T::from(&tx);
+    let outputs = connect_tx_inputs(&tx.input, tx.is_coin_base(), tx_db, blk_index, blk_file)?;
+    for (tx_in, out) in tx.input.iter().zip(outputs) {
+        connected.add_spending_input(out.into(), tx_in);
+    }
+    Ok(connected)
+}
+
+///
+/// `ConnectedTx::connect` through `from` and `add_input_at`,
+/// looking up the creation height of every input.
+///
+pub(crate) fn connect_tx_at<T: ConnectedTx>(
+    tx: Transaction,
+    tx_db: &TxDB,
+    blk_index: &BlockIndex,
+    blk_file: &BlkFile,
+) -> OpResult<T> {
+    let mut connected = T::from(&tx);
+    let outputs = connect_tx_inputs(&tx.input, tx.is_coin_base(), tx_db, blk_index, blk_file)?;
+    for (tx_in, out) in tx.input.iter().zip(outputs) {
+        let created_height = tx_db.get_block_height_of_tx(&tx_in.previous_output.txid)?;
+        connected.add_input_at(out.into(), tx_in, created_height);
+    }
+    Ok(connected)
+}
+
 ///
 /// This function converts multiple Inputs of a single transaction to Outputs in parallel.
 ///
 #[inline]
-fn connect_tx_inputs(
+pub(crate) fn connect_tx_inputs(
     tx_in: &[TxIn],
     is_coinbase: bool,
     tx_db: &TxDB,
@@ -370,13 +564,15 @@ fn connect_input(
         if tx_db.is_genesis_tx(tx_id) {
             return match blk_index.records.first() {
                 None => None,
-                Some(pos) => match blk_file.read_block(pos.n_file, pos.n_data_pos) {
-                    Ok(mut blk) => {
-                        let mut tx = blk.txdata.swap_remove(0);
-                        Some(tx.output.swap_remove(0))
+                Some(pos) => {
+                    match blk_file.read_block(pos.n_file, pos.n_data_pos, BlockEra::Legacy) {
+                        Ok(mut blk) => {
+                            let mut tx = blk.txdata.swap_remove(0);
+                            Some(tx.output.swap_remove(0))
+                        }
+                        Err(_) => None,
                     }
-                    Err(_) => None,
-                },
+                }
             };
         }
         if let Ok(record) = tx_db.get_tx_record(tx_id) {
@@ -2,8 +2,15 @@
 //! Add addresses, block_hash, tx_id to the bitcoin library format
 //!
 use crate::api::Block;
-use crate::parser::script::{evaluate_script, ScriptType};
-use bitcoin::{Address, BlockHash, Transaction, TxMerkleNode, TxOut, Txid};
+use crate::parser::proto::amount::checked_sum;
+use crate::parser::script::{
+    address_network, evaluate_script, extract_input_pubkeys, extract_pubkeys, op_return_data,
+    ScriptType,
+};
+use bitcoin::util::amount::serde::as_sat;
+use bitcoin::{
+    Address, Amount, BlockHash, PublicKey, Transaction, TxMerkleNode, TxOut, Txid, Wtxid,
+};
 use serde::{Deserialize, Serialize};
 
 ///
@@ -62,6 +69,7 @@ impl FBlockHeader {
 /// `FTransaction` compared to `Transaction` has the following
 /// precomputed:
 /// - `transaction ID`
+/// - `witness transaction ID` (BIP141)
 /// - `output script type`
 /// - `output addresses`
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -69,6 +77,8 @@ pub struct FTransaction {
     pub version: i32,
     pub lock_time: u32,
     pub txid: Txid,
+    /// hash including witness data (equals `txid` for non-segwit transactions)
+    pub wtxid: Wtxid,
     /// List of inputs
     pub input: Vec<bitcoin::TxIn>,
     /// List of outputs
@@ -79,30 +89,66 @@ impl From<Transaction> for FTransaction {
     fn from(tx: Transaction) -> FTransaction {
         let is_coinbase = tx.is_coin_base();
         let txid = tx.txid();
+        let wtxid = tx.wtxid();
         let input = if is_coinbase { Vec::new() } else { tx.input };
         FTransaction {
             version: tx.version,
             lock_time: tx.lock_time,
             txid,
+            wtxid,
             input,
             output: tx.output.into_iter().map(FTxOut::from).collect(),
         }
     }
 }
 
+impl FTransaction {
+    ///
+    /// Public keys revealed by this transaction,
+    /// both in inputs (signatures, redeem and witness scripts)
+    /// and in outputs (e.g. P2PK and bare multisig).
+    ///
+    /// Computed on demand.
+    ///
+    pub fn pubkeys(&self) -> Vec<PublicKey> {
+        let mut keys: Vec<PublicKey> = self.input.iter().flat_map(extract_input_pubkeys).collect();
+        for o in &self.output {
+            keys.extend(extract_pubkeys(&o.script_pubkey));
+        }
+        keys
+    }
+
+    ///
+    /// Sum of output values, `None` on overflow.
+    ///
+    pub fn output_value(&self) -> Option<Amount> {
+        checked_sum(self.output.iter().map(|o| o.value))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct FTxOut {
-    pub value: u64,
+    #[serde(with = "as_sat")]
+    pub value: Amount,
     pub script_pubkey: bitcoin::Script,
     pub script_type: ScriptType,
     pub addresses: Box<[Address]>,
 }
 
+impl FTxOut {
+    ///
+    /// Data pushed by an `OP_RETURN` output, `None` for other outputs.
+    ///
+    pub fn op_return_data(&self) -> Option<Vec<u8>> {
+        op_return_data(&self.script_pubkey)
+    }
+}
+
 impl From<TxOut> for FTxOut {
     fn from(out: bitcoin::TxOut) -> FTxOut {
-        let eval = evaluate_script(&out.script_pubkey, bitcoin::Network::Bitcoin);
+        let eval = evaluate_script(&out.script_pubkey, address_network());
         FTxOut {
-            value: out.value,
+            value: Amount::from_sat(out.value),
             script_pubkey: out.script_pubkey,
             script_type: eval.pattern,
             addresses: eval.addresses.into_boxed_slice(),
@@ -0,0 +1,207 @@
+//!
+//! Witness-stripped (pre-segwit) serialization, for tools that cannot
+//! parse BIP144 transactions.
+//!
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::{serialize, Encodable};
+use bitcoin::{Amount, Block, BlockHash, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+
+///
+/// Serialize a transaction without witness data (no marker, flag or witnesses),
+/// as before segwit. The txid is the hash of these bytes.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{serialize_tx_legacy, BitcoinDB, Block};
+/// use std::path::Path;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+/// let block: Block = db.get_block(700000).unwrap();
+/// for tx in block.txdata.iter() {
+///     let bytes = serialize_tx_legacy(tx);
+///     // hand over to pre-segwit tooling
+/// }
+/// ```
+///
+pub fn serialize_tx_legacy(tx: &Transaction) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    encode_tx_legacy(tx, &mut bytes);
+    bytes
+}
+
+///
+/// Serialize a block with all its transactions stripped of witness data.
+///
+pub fn serialize_block_legacy(block: &Block) -> Vec<u8> {
+    let mut bytes = serialize(&block.header);
+    // writing to a Vec cannot fail
+    VarInt(block.txdata.len() as u64)
+        .consensus_encode(&mut bytes)
+        .unwrap();
+    for tx in block.txdata.iter() {
+        encode_tx_legacy(tx, &mut bytes);
+    }
+    bytes
+}
+
+fn encode_tx_legacy(tx: &Transaction, bytes: &mut Vec<u8>) {
+    // `TxIn` encodes without its witness, writing to a Vec cannot fail
+    tx.version.consensus_encode(&mut *bytes).unwrap();
+    tx.input.consensus_encode(&mut *bytes).unwrap();
+    tx.output.consensus_encode(&mut *bytes).unwrap();
+    tx.lock_time.consensus_encode(&mut *bytes).unwrap();
+}
+
+///
+/// Block in a `legacy` format: witness-stripped transactions,
+/// with the sizes of the full transactions kept separately.
+///
+/// `db.iter_block::<LegacyBlock>(start, end)` iterates legacy blocks.
+///
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LegacyBlock {
+    pub block_hash: BlockHash,
+    /// serialized header
+    pub header: Vec<u8>,
+    pub txdata: Vec<LegacyTransaction>,
+}
+
+impl LegacyBlock {
+    ///
+    /// The legacy serialization of the block
+    /// (header, transaction count, and the `raw` of each transaction).
+    ///
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = self.header.clone();
+        VarInt(self.txdata.len() as u64)
+            .consensus_encode(&mut bytes)
+            .unwrap();
+        for tx in self.txdata.iter() {
+            bytes.extend_from_slice(&tx.raw);
+        }
+        bytes
+    }
+
+    ///
+    /// Weight of the full block (with witness data), as counted by consensus.
+    ///
+    pub fn weight(&self) -> usize {
+        // header and transaction count are counted 4 times
+        let base = self.header.len() + VarInt(self.txdata.len() as u64).len();
+        base * 4 + self.txdata.iter().map(|tx| tx.weight).sum::<usize>()
+    }
+}
+
+impl From<Block> for LegacyBlock {
+    fn from(block: Block) -> LegacyBlock {
+        LegacyBlock {
+            block_hash: block.block_hash(),
+            header: serialize(&block.header),
+            txdata: block.txdata.iter().map(LegacyTransaction::from).collect(),
+        }
+    }
+}
+
+///
+/// A witness-stripped transaction, identified by txid only.
+///
+/// `size`, `weight` and `vsize` are those of the full transaction:
+/// fee rates must be computed from `vsize`, not from the length of `raw`.
+///
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LegacyTransaction {
+    pub txid: Txid,
+    /// serialization without witness data
+    pub raw: Vec<u8>,
+    /// size with witness data
+    pub size: usize,
+    pub weight: usize,
+    pub vsize: usize,
+}
+
+impl LegacyTransaction {
+    ///
+    /// Whether witness data was stripped.
+    ///
+    pub fn had_witness(&self) -> bool {
+        self.size != self.raw.len()
+    }
+
+    ///
+    /// Fee rate (sat/vB) of the full transaction paying `fee`.
+    ///
+    pub fn fee_rate(&self, fee: Amount) -> f64 {
+        fee.as_sat() as f64 / self.vsize as f64
+    }
+}
+
+impl From<&Transaction> for LegacyTransaction {
+    fn from(tx: &Transaction) -> LegacyTransaction {
+        let raw = serialize_tx_legacy(tx);
+        let size = tx.size();
+        // BIP141: base size * 3 + total size
+        let weight = raw.len() * 3 + size;
+        LegacyTransaction {
+            txid: tx.txid(),
+            raw,
+            size,
+            weight,
+            vsize: (weight + 3) / 4,
+        }
+    }
+}
+
+impl From<Transaction> for LegacyTransaction {
+    fn from(tx: Transaction) -> LegacyTransaction {
+        LegacyTransaction::from(&tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::deserialize;
+    use bitcoin::{Network, OutPoint, Script, TxIn, TxOut, Witness};
+
+    #[test]
+    fn test_legacy_serialization() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::default(), 1),
+                witness: Witness::from_vec(vec![vec![0x30; 72], vec![0x02; 33]]),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let raw = serialize_tx_legacy(&tx);
+        let stripped: Transaction = deserialize(&raw).unwrap();
+        assert!(stripped.input[0].witness.is_empty());
+        assert_eq!(stripped.txid(), tx.txid());
+        assert!(raw.len() < serialize(&tx).len());
+
+        let legacy = LegacyTransaction::from(&tx);
+        assert!(legacy.had_witness());
+        assert_eq!(legacy.size, serialize(&tx).len());
+        assert_eq!(legacy.weight, tx.weight());
+        assert_eq!(legacy.fee_rate(Amount::from_sat(legacy.vsize as u64)), 1.0);
+
+        // blocks without witness serialize identically
+        let genesis = genesis_block(Network::Bitcoin);
+        assert_eq!(serialize_block_legacy(&genesis), serialize(&genesis));
+        let mut block = genesis;
+        block.txdata.push(tx);
+        let legacy = LegacyBlock::from(block.clone());
+        assert_eq!(legacy.serialize(), serialize_block_legacy(&block));
+        assert_eq!(legacy.weight(), block.weight());
+        let decoded: Block = deserialize(&legacy.serialize()).unwrap();
+        assert_eq!(decoded.txdata[1].txid(), block.txdata[1].txid());
+    }
+}
@@ -1,10 +1,11 @@
 //!
 //! ## Basic Block Types
 //!
-//! There are three variants of basic block types.
+//! There are four variants of basic block types.
 //! - Block: imported from rust-bitcoin
 //! - FBlock: `full_proto::FBlock`, with extra info pre-computed.
 //! - SBlock: `simple_proto::SBlock`, with minimal amount of necessary info.
+//! - LegacyBlock: `legacy_proto::LegacyBlock`, witness-stripped transactions.
 //!
 //! For details, see the struct documentations.
 //!
@@ -17,11 +18,17 @@
 //! Corresponding to the basic F/S Blocks.
 //!
 
+/// overflow-safe helpers and exact formatting of `Amount` values
+pub mod amount;
+
 /// connect outpoints of inputs to previous outputs
 pub mod connected_proto;
 
 /// add block hash, transaction id, script type, addresses to original `bitcoin::Block`
 pub mod full_proto;
 
+/// witness-stripped serialization, for pre-segwit tooling
+pub mod legacy_proto;
+
 /// simplified blockchain objects, for faster python processing
 pub mod simple_proto;
@@ -1,5 +1,7 @@
-use crate::parser::script::evaluate_script;
-use bitcoin::{Address, Block, BlockHash, Transaction, TxIn, TxOut, Txid};
+use crate::parser::proto::amount::checked_sum;
+use crate::parser::script::{address_network, evaluate_script, op_return_data, ScriptType};
+use bitcoin::util::amount::serde::as_sat;
+use bitcoin::{Address, Amount, Block, BlockHash, Transaction, TxIn, TxOut, Txid};
 use serde::{Deserialize, Serialize};
 
 ///
@@ -11,6 +13,7 @@ use serde::{Deserialize, Serialize};
 /// - `transaction id`
 /// - `output addresses`
 /// - `output script types`
+/// - `OP_RETURN payloads`
 ///
 /// But is has the following attributes removed:
 /// - `nounce`
@@ -62,6 +65,7 @@ impl SBlockHeader {
 /// - `transaction ID`
 /// - `output script type`
 /// - `output addresses`
+/// - `OP_RETURN payloads`
 ///
 /// It has the following removed:
 /// - `input witness`
@@ -94,6 +98,15 @@ impl From<Transaction> for STransaction {
     }
 }
 
+impl STransaction {
+    ///
+    /// Sum of output values, `None` on overflow.
+    ///
+    pub fn output_value(&self) -> Option<Amount> {
+        checked_sum(self.output.iter().map(|o| o.value))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct STxIn {
     pub txid: Txid,
@@ -111,15 +124,21 @@ impl From<TxIn> for STxIn {
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct STxOut {
-    pub value: u64,
+    #[serde(with = "as_sat")]
+    pub value: Amount,
+    pub script_type: ScriptType,
     pub addresses: Box<[Address]>,
+    /// data pushed by an `OP_RETURN` output, `None` for other outputs
+    pub op_return: Option<Box<[u8]>>,
 }
 
 impl From<TxOut> for STxOut {
     fn from(out: TxOut) -> STxOut {
-        let eval = evaluate_script(&out.script_pubkey, bitcoin::Network::Bitcoin);
+        let eval = evaluate_script(&out.script_pubkey, address_network());
         STxOut {
-            value: out.value,
+            value: Amount::from_sat(out.value),
+            op_return: op_return_data(&out.script_pubkey).map(Vec::into_boxed_slice),
+            script_type: eval.pattern,
             addresses: eval.addresses.into_boxed_slice(),
         }
     }
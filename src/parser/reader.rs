@@ -1,9 +1,9 @@
+use crate::iter::take_vec;
 use crate::parser::errors::OpResult;
 use bitcoin::consensus::Decodable;
 use bitcoin::{Block, BlockHeader, Transaction};
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::fs::File;
-use std::io::{BufReader, Cursor};
+use std::io::{BufReader, Cursor, Read};
 
 ///
 /// binary file read utilities.
@@ -52,7 +52,8 @@ pub trait BlockchainRead: std::io::Read {
 
     #[inline]
     fn read_u8_vec(&mut self, count: u32) -> OpResult<Vec<u8>> {
-        let mut arr = vec![0u8; count as usize];
+        let mut arr = take_vec::<u8>();
+        arr.resize(count as usize, 0);
         self.read_exact(&mut arr)?;
         Ok(arr)
     }
@@ -75,4 +76,4 @@ pub trait BlockchainRead: std::io::Read {
 
 impl BlockchainRead for Cursor<&[u8]> {}
 impl BlockchainRead for Cursor<Vec<u8>> {}
-impl BlockchainRead for BufReader<File> {}
+impl<R: Read> BlockchainRead for BufReader<R> {}
@@ -1,12 +1,58 @@
 use bitcoin::blockdata::opcodes::{all, All};
 use bitcoin::blockdata::script::Instruction;
+use bitcoin::consensus::encode::VarInt;
 use bitcoin::hashes::{hash160, Hash};
 use bitcoin::util::address::Payload;
-use bitcoin::{Address, Network, PubkeyHash, PublicKey, Script};
+use bitcoin::{Address, Network, PubkeyHash, PublicKey, Script, TxIn};
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::fmt;
 use Instruction::{Op, PushBytes};
 
+thread_local! {
+    /// network of the addresses of proto types converted on this thread
+    static ADDRESS_NETWORK: Cell<Network> = Cell::new(Network::Bitcoin);
+}
+
+///
+/// Network for which addresses of `SBlock`, `FBlock` etc. converted on
+/// this thread are derived: `Network::Bitcoin`, except within
+/// `with_address_network`.
+///
+pub fn address_network() -> Network {
+    ADDRESS_NETWORK.with(|n| n.get())
+}
+
+///
+/// Run `f` with addresses of the proto types converted by it (on this
+/// thread) derived for `network`.
+///
+/// `BitcoinDB` converts blocks and transactions within the network of
+/// its datadir. Use this to convert blocks read by other means, since
+/// `From<Block>` does not take the network.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::parser::script::with_address_network;
+/// use bitcoin_explorer::{Block, Network, SBlock};
+///
+/// # fn read() -> Block { unimplemented!() }
+/// let block: Block = read();
+/// let block: SBlock = with_address_network(Network::Testnet, || block.into());
+/// ```
+///
+pub fn with_address_network<R, F: FnOnce() -> R>(network: Network, f: F) -> R {
+    struct Restore(Network);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            ADDRESS_NETWORK.with(|n| n.set(self.0));
+        }
+    }
+    let _restore = Restore(ADDRESS_NETWORK.with(|n| n.replace(network)));
+    f()
+}
+
 ///
 /// Different types of bitcoin Scripts.
 ///
@@ -22,7 +68,13 @@ pub enum ScriptType {
     Pay2ScriptHash,
     Pay2WitnessPublicKeyHash,
     Pay2WitnessScriptHash,
+    /// pay-to-taproot (`OP_1 <32 bytes>`), segwit v1 (BIP341)
+    Pay2Taproot,
+    /// pay-to-anchor (`OP_1 <0x4e73>`), keyless anchor output
+    Pay2Anchor,
     WitnessProgram,
+    /// bare `OP_TRUE`, anyone-can-spend anchor output
+    OpTrueAnchor,
     Unspendable,
     NotRecognised,
 }
@@ -42,7 +94,7 @@ pub struct ScriptInfo {
 pub fn evaluate_script(script: &Script, net: Network) -> ScriptInfo {
     let address = Address::from_script(script, net);
     if script.is_p2pk() {
-        ScriptInfo::new(p2pk_to_address(script), ScriptType::Pay2PublicKey)
+        ScriptInfo::new(p2pk_to_address(script, net), ScriptType::Pay2PublicKey)
     } else if script.is_p2pkh() {
         ScriptInfo::new(address, ScriptType::Pay2PublicKeyHash)
     } else if script.is_p2sh() {
@@ -51,19 +103,196 @@ pub fn evaluate_script(script: &Script, net: Network) -> ScriptInfo {
         ScriptInfo::new(address, ScriptType::Pay2WitnessPublicKeyHash)
     } else if script.is_v0_p2wsh() {
         ScriptInfo::new(address, ScriptType::Pay2WitnessScriptHash)
+    } else if is_p2tr(script) {
+        ScriptInfo::new(address, ScriptType::Pay2Taproot)
+    } else if is_p2a(script) {
+        ScriptInfo::new(address, ScriptType::Pay2Anchor)
     } else if script.is_witness_program() {
         ScriptInfo::new(address, ScriptType::WitnessProgram)
     } else if script.is_op_return() {
         ScriptInfo::new(address, ScriptType::OpReturn)
     } else if script.is_provably_unspendable() {
         ScriptInfo::new(address, ScriptType::Unspendable)
+    } else if script.as_bytes() == [all::OP_PUSHNUM_1.into_u8()] {
+        ScriptInfo::new(address, ScriptType::OpTrueAnchor)
     } else if is_multisig(script) {
-        ScriptInfo::from_vec(multisig_addresses(script), ScriptType::Pay2MultiSig)
+        ScriptInfo::from_vec(multisig_addresses(script, net), ScriptType::Pay2MultiSig)
     } else {
         ScriptInfo::new(address, ScriptType::NotRecognised)
     }
 }
 
+///
+/// Payload of an `OP_RETURN` output: the data pushed after `OP_RETURN`,
+/// concatenated.
+///
+/// Returns `None` if the script is not `OP_RETURN` followed by pushes only.
+///
+pub fn op_return_data(script: &Script) -> Option<Vec<u8>> {
+    if !script.is_op_return() {
+        return None;
+    }
+    let mut data = Vec::new();
+    for instruction in Script::from(script.as_bytes()[1..].to_vec()).instructions() {
+        match instruction.ok()? {
+            PushBytes(bytes) => data.extend_from_slice(bytes),
+            // OP_0 and OP_N push small numbers, not data
+            Op(op) => {
+                get_num_keys(&Op(op))?;
+            }
+        }
+    }
+    Some(data)
+}
+
+///
+/// Classify the script wrapped by a P2SH or P2WSH output.
+///
+/// `script_pubkey` is the script of the spent output and `tx_in` the spending input.
+/// For P2SH the redeem script is the last push of the `script_sig`,
+/// for P2WSH (also nested in P2SH) the witness script is the last witness item.
+/// Returns the type of the innermost revealed script,
+/// or `None` if the output is neither P2SH nor P2WSH,
+/// or if no script is revealed.
+///
+pub fn evaluate_wrapped_script(script_pubkey: &Script, tx_in: &TxIn) -> Option<ScriptType> {
+    let wrapped = if script_pubkey.is_p2sh() {
+        let redeem_script = redeem_script(&tx_in.script_sig)?;
+        if redeem_script.is_v0_p2wsh() {
+            witness_script(tx_in)?
+        } else {
+            redeem_script
+        }
+    } else if script_pubkey.is_v0_p2wsh() {
+        witness_script(tx_in)?
+    } else {
+        return None;
+    };
+    Some(evaluate_script(&wrapped, Network::Bitcoin).pattern)
+}
+
+///
+/// The redeem script of a P2SH spending `script_sig` (its last push).
+///
+/// Returns `None` if `script_sig` contains non-push opcodes.
+///
+pub fn redeem_script(script_sig: &Script) -> Option<Script> {
+    let mut last = None;
+    for instruction in script_sig.instructions() {
+        match instruction.ok()? {
+            PushBytes(data) => last = Some(data),
+            Op(op) => {
+                // OP_0 and OP_N are push operations too
+                get_num_keys(&Op(op))?;
+                last = None;
+            }
+        }
+    }
+    last.map(|data| Script::from(data.to_vec()))
+}
+
+///
+/// The witness script of a P2WSH spending input (its last witness item).
+///
+pub fn witness_script(tx_in: &TxIn) -> Option<Script> {
+    tx_in
+        .witness
+        .iter()
+        .last()
+        .map(|data| Script::from(data.to_vec()))
+}
+
+///
+/// Public keys pushed in a script (e.g. P2PK, bare multisig, or a redeem script).
+///
+pub fn extract_pubkeys(script: &Script) -> Vec<PublicKey> {
+    script
+        .instructions()
+        .filter_map(|i| match i {
+            Ok(PushBytes(data)) => PublicKey::from_slice(data).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+///
+/// Public keys revealed by a spending input.
+///
+/// This includes keys pushed in `script_sig` and witness,
+/// as well as keys inside the revealed redeem script and witness script.
+///
+pub fn extract_input_pubkeys(tx_in: &TxIn) -> Vec<PublicKey> {
+    let mut keys = extract_pubkeys(&tx_in.script_sig);
+    if let Some(redeem_script) = redeem_script(&tx_in.script_sig) {
+        // the last push of a p2pkh script_sig is a key, not a script
+        if PublicKey::from_slice(redeem_script.as_bytes()).is_err() {
+            keys.extend(extract_pubkeys(&redeem_script));
+        }
+    }
+    keys.extend(
+        tx_in
+            .witness
+            .iter()
+            .filter_map(|item| PublicKey::from_slice(item).ok()),
+    );
+    if let Some(witness_script) = witness_script(tx_in) {
+        if PublicKey::from_slice(witness_script.as_bytes()).is_err() {
+            keys.extend(extract_pubkeys(&witness_script));
+        }
+    }
+    keys
+}
+
+/// Bitcoin Core default dust relay fee (sat/kvB)
+const DUST_RELAY_FEE: u64 = 3000;
+
+///
+/// Dust threshold of an output with this script under Bitcoin Core's default
+/// dust relay fee, following `GetDustThreshold()`:
+/// outputs worth less than this cost more to spend than they are worth.
+///
+/// Unspendable scripts have a threshold of zero.
+///
+pub fn dust_threshold(script: &Script) -> u64 {
+    if script.is_provably_unspendable() {
+        return 0;
+    }
+    let script_len = script.len() as u64;
+    // value + compact size + script
+    let output_size = 8 + VarInt(script_len).len() as u64 + script_len;
+    // outpoint + script_sig length + sequence, plus a typical script_sig or witness
+    let input_size = if script.is_witness_program() {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    (output_size + input_size) * DUST_RELAY_FEE / 1000
+}
+
+///
+/// Whether an output is below its dust threshold, see `dust_threshold`.
+///
+pub fn is_dust(value: u64, script: &Script) -> bool {
+    value < dust_threshold(script)
+}
+
+///
+/// pay-to-taproot: `OP_1 OP_PUSHBYTES_32 <x-only key>`
+///
+#[inline]
+fn is_p2tr(script: &Script) -> bool {
+    let bytes = script.as_bytes();
+    bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20
+}
+
+///
+/// pay-to-anchor: `OP_1 OP_PUSHBYTES_2 4e73`
+///
+#[inline]
+fn is_p2a(script: &Script) -> bool {
+    script.as_bytes() == [0x51, 0x02, 0x4e, 0x73]
+}
+
 impl ScriptInfo {
     pub(crate) fn new(address: Option<Address>, pattern: ScriptType) -> Self {
         if let Some(address) = address {
@@ -142,7 +371,7 @@ fn is_multisig(script: &Script) -> bool {
 ///
 /// Obtain addresses for multisig transactions.
 ///
-fn multisig_addresses(script: &Script) -> Vec<Address> {
+fn multisig_addresses(script: &Script, net: Network) -> Vec<Address> {
     assert!(is_multisig(script));
     let ops: Vec<Instruction> = script.instructions().filter_map(|o| o.ok()).collect();
 
@@ -161,7 +390,7 @@ fn multisig_addresses(script: &Script) -> Vec<Address> {
             match PublicKey::from_slice(data) {
                 Ok(pk) => public_keys.push(Address {
                     payload: Payload::PubkeyHash(pk.pubkey_hash()),
-                    network: Network::Bitcoin,
+                    network: net,
                 }),
                 Err(_) => return Vec::new(),
             }
@@ -216,14 +445,14 @@ fn get_num_keys(op: &Instruction) -> Option<i32> {
 /// otherwise panic.
 ///
 #[inline]
-fn p2pk_to_address(script: &Script) -> Option<Address> {
+fn p2pk_to_address(script: &Script, net: Network) -> Option<Address> {
     assert!(script.is_p2pk());
     if let Some(Ok(Instruction::PushBytes(pk))) = script.instructions().next() {
         // hash the 20 bytes public key
         let pkh = hash160::Hash::hash(pk);
         Some(Address {
             payload: Payload::PubkeyHash(PubkeyHash::from_slice(&pkh).ok()?),
-            network: Network::Bitcoin,
+            network: net,
         })
     } else {
         unreachable!()
@@ -250,7 +479,10 @@ impl fmt::Display for ScriptType {
             ScriptType::Pay2ScriptHash => write!(f, "Pay2ScriptHash"),
             ScriptType::Pay2WitnessPublicKeyHash => write!(f, "Pay2WitnessPublicKeyHash"),
             ScriptType::Pay2WitnessScriptHash => write!(f, "Pay2WitnessScriptHash"),
+            ScriptType::Pay2Taproot => write!(f, "Pay2Taproot"),
+            ScriptType::Pay2Anchor => write!(f, "Pay2Anchor"),
             ScriptType::WitnessProgram => write!(f, "WitnessProgram"),
+            ScriptType::OpTrueAnchor => write!(f, "OpTrueAnchor"),
             ScriptType::Unspendable => write!(f, "Unspendable"),
             ScriptType::NotRecognised => write!(f, "NotRecognised"),
         }
@@ -259,9 +491,29 @@ impl fmt::Display for ScriptType {
 
 #[cfg(test)]
 mod tests {
-    use super::{evaluate_script, ScriptType};
+    use super::{
+        address_network, dust_threshold, evaluate_script, evaluate_wrapped_script,
+        extract_input_pubkeys, is_dust, op_return_data, with_address_network, ScriptType,
+    };
+    use bitcoin::blockdata::script::Builder;
     use bitcoin::hashes::hex::{FromHex, ToHex};
-    use bitcoin::{Network, Script};
+    use bitcoin::{Network, Script, TxIn};
+
+    #[test]
+    fn test_with_address_network() {
+        assert_eq!(address_network(), Network::Bitcoin);
+        let scoped = with_address_network(Network::Regtest, || {
+            let nested = with_address_network(Network::Testnet, address_network);
+            (address_network(), nested)
+        });
+        assert_eq!(scoped, (Network::Regtest, Network::Testnet));
+        assert_eq!(address_network(), Network::Bitcoin);
+        // other threads are not affected
+        with_address_network(Network::Signet, || {
+            let other = std::thread::spawn(address_network).join().unwrap();
+            assert_eq!(other, Network::Bitcoin);
+        });
+    }
 
     #[test]
     fn test_bitcoin_script_p2pkh() {
@@ -363,6 +615,130 @@ mod tests {
         assert_eq!(result.pattern, ScriptType::NotRecognised);
     }
 
+    #[test]
+    fn test_wrapped_script_p2sh_multisig() {
+        // 1-of-1 multisig wrapped in P2SH, spent by OP_0 <sig> <redeem script>
+        let redeem = Script::from_hex(
+            "5121022df8750480ad5b26950b25c7ba79d3e37d75f640f8e5d9bcd5b150a0f85014da51ae",
+        )
+        .unwrap();
+        let script_pubkey = Script::new_p2sh(&redeem.script_hash());
+        let script_sig = Builder::new()
+            .push_int(0)
+            .push_slice(&[0x30; 71])
+            .push_slice(redeem.as_bytes())
+            .into_script();
+        let tx_in = TxIn {
+            script_sig,
+            ..Default::default()
+        };
+        assert_eq!(
+            evaluate_wrapped_script(&script_pubkey, &tx_in),
+            Some(ScriptType::Pay2MultiSig)
+        );
+    }
+
+    #[test]
+    fn test_wrapped_script_p2sh_p2wpkh() {
+        let redeem = Script::from_hex("00147a1fe22b6c6a4f0ac37b9a4e1ec3a3e1ac3aad10").unwrap();
+        let script_pubkey = Script::new_p2sh(&redeem.script_hash());
+        let tx_in = TxIn {
+            script_sig: Builder::new().push_slice(redeem.as_bytes()).into_script(),
+            ..Default::default()
+        };
+        assert_eq!(
+            evaluate_wrapped_script(&script_pubkey, &tx_in),
+            Some(ScriptType::Pay2WitnessPublicKeyHash)
+        );
+        // not a wrapping output
+        assert_eq!(evaluate_wrapped_script(&redeem, &tx_in), None);
+    }
+
+    #[test]
+    fn test_extract_input_pubkeys() {
+        let pk = "022df8750480ad5b26950b25c7ba79d3e37d75f640f8e5d9bcd5b150a0f85014da";
+        let pk = Vec::<u8>::from_hex(pk).unwrap();
+        // p2pkh spending: <sig> <pubkey>
+        let tx_in = TxIn {
+            script_sig: Builder::new()
+                .push_slice(&[0x30; 71])
+                .push_slice(&pk)
+                .into_script(),
+            ..Default::default()
+        };
+        let keys = extract_input_pubkeys(&tx_in);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].to_bytes(), pk);
+    }
+
+    #[test]
+    fn test_bitcoin_script_p2tr() {
+        // BIP86 test vector, first receiving address
+        let script = Script::from_hex(
+            "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+        )
+        .unwrap();
+        let result = evaluate_script(&script, Network::Bitcoin);
+        assert_eq!(result.pattern, ScriptType::Pay2Taproot);
+        assert_eq!(
+            result.addresses[0].to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        // other witness versions stay generic
+        let v2 = Script::from_hex("52020001").unwrap();
+        assert_eq!(
+            evaluate_script(&v2, Network::Bitcoin).pattern,
+            ScriptType::WitnessProgram
+        );
+    }
+
+    #[test]
+    fn test_op_return_data() {
+        let script = Builder::new()
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+            .push_slice(b"omni")
+            .push_slice(&[0, 1])
+            .into_script();
+        assert_eq!(
+            evaluate_script(&script, Network::Bitcoin).pattern,
+            ScriptType::OpReturn
+        );
+        assert_eq!(op_return_data(&script), Some(b"omni\x00\x01".to_vec()));
+        assert_eq!(
+            op_return_data(&Script::from_hex("6a").unwrap()),
+            Some(Vec::new())
+        );
+        // not push only
+        assert_eq!(op_return_data(&Script::from_hex("6a76").unwrap()), None);
+        let p2wpkh = Script::from_hex("00147a1fe22b6c6a4f0ac37b9a4e1ec3a3e1ac3aad10").unwrap();
+        assert_eq!(op_return_data(&p2wpkh), None);
+    }
+
+    #[test]
+    fn test_anchor_scripts() {
+        let p2a = Script::from_hex("51024e73").unwrap();
+        assert_eq!(
+            evaluate_script(&p2a, Network::Bitcoin).pattern,
+            ScriptType::Pay2Anchor
+        );
+        assert_eq!(dust_threshold(&p2a), 240);
+        let op_true = Script::from_hex("51").unwrap();
+        assert_eq!(
+            evaluate_script(&op_true, Network::Bitcoin).pattern,
+            ScriptType::OpTrueAnchor
+        );
+    }
+
+    #[test]
+    fn test_dust_threshold() {
+        let p2pkh = Script::from_hex("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac").unwrap();
+        assert_eq!(dust_threshold(&p2pkh), 546);
+        let p2wpkh = Script::from_hex("00147a1fe22b6c6a4f0ac37b9a4e1ec3a3e1ac3aad10").unwrap();
+        assert_eq!(dust_threshold(&p2wpkh), 294);
+        assert!(is_dust(293, &p2wpkh));
+        assert!(!is_dust(294, &p2wpkh));
+    }
+
     #[test]
     fn test_bitcoin_bogus_script() {
         let bytes = [0x4c as u8, 0xFF, 0x00];
@@ -1,3 +1,5 @@
+#[cfg(feature = "on-disk-utxo")]
+use crate::index::TxIndex;
 use crate::parser::block_index::BlockIndex;
 use crate::parser::errors::{OpError, OpResult};
 use crate::parser::reader::BlockchainRead;
@@ -10,17 +12,31 @@ use log::{info, warn};
 use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::path::Path;
+#[cfg(feature = "on-disk-utxo")]
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
 const GENESIS_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
 
 ///
 /// tx-index: looking up transaction position using txid.
 ///
-/// This is possible if Bitcoin Core has `txindex=1`.
+/// This is possible if Bitcoin Core has `txindex=1`,
+/// or with a `TxIndex` built by this crate.
 ///
 pub struct TxDB {
-    db: Option<Database<TxKey>>,
+    /// shared with refreshed `TxDB`s (LevelDB can be opened once per process)
+    db: Option<Arc<Database<TxKey>>>,
+    /// used when `db` is not open
+    #[cfg(feature = "on-disk-utxo")]
+    built: Option<Arc<TxIndex>>,
+    /// file, position and hash of main chain blocks, for `built`
+    #[cfg(feature = "on-disk-utxo")]
+    blocks: Vec<(i32, u32, bitcoin::BlockHash)>,
+    /// `TxIndex` that could not be attached, listed in errors
+    #[cfg(feature = "on-disk-utxo")]
+    stale: Option<PathBuf>,
     // used for reverse looking up to block height
     file_pos_to_height: BTreeMap<(i32, u32), i32>,
     genesis_txid: Txid,
@@ -51,22 +67,88 @@ impl TxDB {
     pub fn new(path: &Path, blk_index: &BlockIndex) -> TxDB {
         let option_db = TxDB::try_open_db(path);
         if let Some(db) = option_db {
-            let mut file_pos_to_height = BTreeMap::new();
-            for b in blk_index.records.iter() {
-                file_pos_to_height.insert((b.n_file, b.n_data_pos), b.n_height);
-            }
-            TxDB {
-                db: Some(db),
-                file_pos_to_height,
-                genesis_txid: Txid::from_str(GENESIS_TXID).unwrap(),
-            }
+            TxDB::with_db(Arc::new(db), blk_index)
         } else {
             TxDB::null()
         }
     }
 
+    ///
+    /// The same tx_index DB, for a refreshed block index.
+    ///
+    pub(crate) fn reindexed(&self, blk_index: &BlockIndex) -> TxDB {
+        let tx_db = match &self.db {
+            Some(db) => TxDB::with_db(db.clone(), blk_index),
+            None => TxDB::null(),
+        };
+        #[cfg(feature = "on-disk-utxo")]
+        if let Some(built) = &self.built {
+            return tx_db.with_built_index(built.clone(), blk_index);
+        }
+        #[cfg(feature = "on-disk-utxo")]
+        if let Some(path) = &self.stale {
+            return tx_db.with_stale_index(path);
+        }
+        tx_db
+    }
+
+    fn with_db(db: Arc<Database<TxKey>>, blk_index: &BlockIndex) -> TxDB {
+        let mut file_pos_to_height = BTreeMap::new();
+        for b in blk_index.records.iter() {
+            file_pos_to_height.insert((b.n_file, b.n_data_pos), b.n_height);
+        }
+        TxDB {
+            db: Some(db),
+            file_pos_to_height,
+            ..TxDB::null()
+        }
+    }
+
+    ///
+    /// The same `TxDB`, falling back to `built` when tx_index is not open.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub(crate) fn with_built_index(&self, built: Arc<TxIndex>, blk_index: &BlockIndex) -> TxDB {
+        TxDB {
+            db: self.db.clone(),
+            built: Some(built),
+            blocks: blk_index
+                .records
+                .iter()
+                .map(|b| (b.n_file, b.n_data_pos, b.block_header.block_hash()))
+                .collect(),
+            stale: None,
+            file_pos_to_height: self.file_pos_to_height.clone(),
+            genesis_txid: self.genesis_txid,
+        }
+    }
+
+    ///
+    /// The same `TxDB`, recording the `TxIndex` at `path` as unusable.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    pub(crate) fn with_stale_index(&self, path: &Path) -> TxDB {
+        TxDB {
+            db: self.db.clone(),
+            built: self.built.clone(),
+            blocks: self.blocks.clone(),
+            stale: Some(path.to_path_buf()),
+            file_pos_to_height: self.file_pos_to_height.clone(),
+            genesis_txid: self.genesis_txid,
+        }
+    }
+
+    #[cfg(feature = "on-disk-utxo")]
+    pub(crate) fn stale_index(&self) -> Option<&Path> {
+        self.stale.as_deref()
+    }
+
     #[inline]
     pub(crate) fn is_open(&self) -> bool {
+        #[cfg(feature = "on-disk-utxo")]
+        if self.built.is_some() {
+            return true;
+        }
         self.db.is_some()
     }
 
@@ -74,6 +156,12 @@ impl TxDB {
     pub(crate) fn null() -> TxDB {
         TxDB {
             db: None,
+            #[cfg(feature = "on-disk-utxo")]
+            built: None,
+            #[cfg(feature = "on-disk-utxo")]
+            blocks: Vec::new(),
+            #[cfg(feature = "on-disk-utxo")]
+            stale: None,
             file_pos_to_height: BTreeMap::new(),
             genesis_txid: Txid::from_str(GENESIS_TXID).unwrap(),
         }
@@ -107,6 +195,14 @@ impl TxDB {
 
     /// note that this function cannot find genesis block, which needs special treatment
     pub(crate) fn get_tx_record(&self, txid: &Txid) -> OpResult<TransactionRecord> {
+        self.find_tx_record(txid)?
+            .ok_or_else(|| OpError::from(format!("value not found for txid: {}", txid).as_str()))
+    }
+
+    ///
+    /// Same as `get_tx_record`, `None` if `txid` is not in the index.
+    ///
+    pub(crate) fn find_tx_record(&self, txid: &Txid) -> OpResult<Option<TransactionRecord>> {
         if let Some(db) = &self.db {
             let inner = txid.as_inner();
             let mut key = Vec::with_capacity(inner.len() + 1);
@@ -115,35 +211,79 @@ impl TxDB {
             let key = TxKey { key };
             let read_options = ReadOptions::new();
             match db.get(read_options, &key) {
-                Ok(value) => {
-                    if let Some(value) = value {
-                        Ok(TransactionRecord::from(&key.key[1..], value.as_slice())?)
-                    } else {
-                        Err(OpError::from(
-                            format!("value not found for txid: {}", txid).as_str(),
-                        ))
-                    }
-                }
+                Ok(Some(value)) => Ok(Some(TransactionRecord::from(
+                    &key.key[1..],
+                    value.as_slice(),
+                )?)),
+                Ok(None) => Ok(None),
                 Err(e) => Err(OpError::from(
-                    format!("value not found for txid: {}", e).as_str(),
+                    format!("failed to read tx_index: {}", e).as_str(),
                 )),
             }
         } else {
+            #[cfg(feature = "on-disk-utxo")]
+            if self.built.is_some() {
+                return Ok(self.find_built_record(txid)?.map(|(_, record)| record));
+            }
             Err(OpError::from("TxDB not open"))
         }
     }
 
+    ///
+    /// Height and record of `txid` in the `TxIndex` built by this crate,
+    /// `None` unless its block is still in the main chain.
+    ///
+    #[cfg(feature = "on-disk-utxo")]
+    fn find_built_record(&self, txid: &Txid) -> OpResult<Option<(usize, TransactionRecord)>> {
+        let built = match &self.built {
+            Some(built) => built,
+            None => return Err(OpError::from("TxDB not open")),
+        };
+        let position = match built.position(txid)? {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        match self.blocks.get(position.height) {
+            Some((n_file, n_pos, hash)) if hash[..8] == position.block_tag => Ok(Some((
+                position.height,
+                TransactionRecord {
+                    txid: *txid,
+                    n_file: *n_file,
+                    n_pos: *n_pos,
+                    n_tx_offset: position.n_tx_offset,
+                },
+            ))),
+            // the entry of a reorganized block
+            _ => Ok(None),
+        }
+    }
+
     pub(crate) fn get_block_height_of_tx(&self, txid: &Txid) -> OpResult<usize> {
+        self.find_block_height_of_tx(txid)?
+            .ok_or_else(|| OpError::from("transaction not found"))
+    }
+
+    ///
+    /// Same as `get_block_height_of_tx`, `None` if `txid` is not
+    /// in the index or not in the main chain.
+    ///
+    pub(crate) fn find_block_height_of_tx(&self, txid: &Txid) -> OpResult<Option<usize>> {
         // genesis transaction requires special treatment
         if self.is_genesis_tx(txid) {
-            return Ok(0);
+            return Ok(Some(0));
         }
-        let record: TransactionRecord = self.get_tx_record(txid)?;
-        let file_pos_height = &self.file_pos_to_height;
-        match file_pos_height.get(&(record.n_file, record.n_pos)) {
-            None => Err(OpError::from("transaction not found")),
-            Some(pos_height) => Ok(*pos_height as usize),
+        #[cfg(feature = "on-disk-utxo")]
+        if self.db.is_none() && self.built.is_some() {
+            return Ok(self.find_built_record(txid)?.map(|(height, _)| height));
         }
+        let record = match self.find_tx_record(txid)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let file_pos_height = &self.file_pos_to_height;
+        Ok(file_pos_height
+            .get(&(record.n_file, record.n_pos))
+            .map(|pos_height| *pos_height as usize))
     }
 }
 
@@ -0,0 +1,368 @@
+//!
+//! Bitcoin Core undo files (`rev*.dat`), which record the outputs
+//! spent by each block, so that inputs can be connected without
+//! rebuilding the UTXO set.
+//!
+use crate::parser::blk_file::size_prefix_pos;
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::reader::BlockchainRead;
+use crate::parser::xor::{read_xor_key, XorKey, XorReader};
+use bitcoin::blockdata::opcodes::all;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::Decodable;
+#[cfg(any(test, feature = "testutil"))]
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::{sha256d, Hash, HashEngine};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{BlockHash, PubkeyHash, Script, ScriptHash, TxOut};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// scripts with a special compressed encoding (`nSpecialScripts` in Core)
+const SPECIAL_SCRIPTS: usize = 6;
+/// larger scripts are replaced by `OP_RETURN` in undo data (`MAX_SCRIPT_SIZE`)
+const MAX_SCRIPT_SIZE: usize = 10000;
+
+///
+/// An output spent by a block, as recorded in undo files.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpentOutput {
+    pub txout: TxOut,
+    /// height of the block creating the output
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+///
+/// Undo data of a block (`CBlockUndo`): the outputs spent
+/// by each non-coinbase transaction, in input order.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    pub txs: Vec<Vec<SpentOutput>>,
+}
+
+///
+/// An index of all rev files found.
+///
+#[derive(Debug, Clone)]
+pub struct UndoFile {
+    files: HashMap<i32, PathBuf>,
+    /// key of obfuscated blocks directories
+    xor_key: Option<XorKey>,
+}
+
+impl UndoFile {
+    ///
+    /// Construct an index of the rev files of the blocks directory
+    /// (possibly empty, e.g. for datadirs copied without them).
+    ///
+    pub(crate) fn new(path: &Path) -> OpResult<UndoFile> {
+        let mut files = HashMap::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            let index = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(parse_rev_index);
+            if let Some(index) = index {
+                files.insert(index, path);
+            }
+        }
+        Ok(UndoFile {
+            files,
+            xor_key: read_xor_key(path)?,
+        })
+    }
+
+    ///
+    /// Read the undo data at `offset` (right after the magic and size prefix)
+    /// of `rev{n_file}.dat`, checking its checksum against `prev_hash`,
+    /// the hash of the parent block.
+    ///
+    pub(crate) fn read_block_undo(
+        &self,
+        n_file: i32,
+        offset: u32,
+        prev_hash: &BlockHash,
+    ) -> OpResult<BlockUndo> {
+        let path = match self.files.get(&n_file) {
+            Some(path) => path,
+            None => return Err(OpError::from("rev file not found")),
+        };
+        let mut r = BufReader::new(XorReader::open(path, self.xor_key)?);
+        r.seek(SeekFrom::Start(size_prefix_pos(offset)?))?;
+        let size = r.read_u32()?;
+        let data = r.read_u8_vec(size)?;
+        let checksum = r.read_u256()?;
+        if undo_checksum(prev_hash, &data) != checksum {
+            return Err(OpError::from("undo data checksum mismatch"));
+        }
+        parse_block_undo(&data)
+    }
+}
+
+/// index of `rev?????.dat`
+fn parse_rev_index(file_name: &str) -> Option<i32> {
+    if file_name.len() == 12 && file_name.starts_with("rev") && file_name.ends_with(".dat") {
+        file_name[3..8].parse::<i32>().ok()
+    } else {
+        None
+    }
+}
+
+///
+/// Checksum written after undo data: double SHA256 of the parent block
+/// hash followed by the undo data.
+///
+pub(crate) fn undo_checksum(prev_hash: &BlockHash, data: &[u8]) -> [u8; 32] {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&prev_hash[..]);
+    engine.input(data);
+    sha256d::Hash::from_engine(engine).into_inner()
+}
+
+///
+/// Parse serialized `CBlockUndo`.
+///
+pub fn parse_block_undo(data: &[u8]) -> OpResult<BlockUndo> {
+    let mut r = Cursor::new(data);
+    let n_tx = VarInt::consensus_decode(&mut r)?.0;
+    let mut txs = Vec::with_capacity(n_tx.min(data.len() as u64) as usize);
+    for _ in 0..n_tx {
+        let n_in = VarInt::consensus_decode(&mut r)?.0;
+        let mut spent = Vec::with_capacity(n_in.min(data.len() as u64) as usize);
+        for _ in 0..n_in {
+            spent.push(read_spent_output(&mut r)?);
+        }
+        txs.push(spent);
+    }
+    if r.position() != data.len() as u64 {
+        return Err(OpError::from("trailing bytes in undo data"));
+    }
+    Ok(BlockUndo { txs })
+}
+
+fn read_spent_output(r: &mut Cursor<&[u8]>) -> OpResult<SpentOutput> {
+    let code = r.read_varint()?;
+    let height = (code >> 1) as u32;
+    if height > 0 {
+        // version dummy, kept for compatibility
+        r.read_varint()?;
+    }
+    let value = decompress_amount(r.read_varint()? as u64);
+    let script_pubkey = read_compressed_script(r)?;
+    Ok(SpentOutput {
+        txout: TxOut {
+            value,
+            script_pubkey,
+        },
+        height,
+        is_coinbase: code & 1 == 1,
+    })
+}
+
+pub(crate) fn read_compressed_script(r: &mut Cursor<&[u8]>) -> OpResult<Script> {
+    let n_size = r.read_varint()?;
+    let script = match n_size {
+        0 => {
+            let hash = PubkeyHash::from_slice(&r.read_u8_vec(20)?)?;
+            Script::new_p2pkh(&hash)
+        }
+        1 => {
+            let hash = ScriptHash::from_slice(&r.read_u8_vec(20)?)?;
+            Script::new_p2sh(&hash)
+        }
+        2..=5 => {
+            let mut key = [0u8; 33];
+            key[0] = (if n_size < 4 { n_size } else { n_size - 2 }) as u8;
+            r.read_exact(&mut key[1..])?;
+            let key_bytes = if n_size < 4 {
+                key.to_vec()
+            } else {
+                PublicKey::from_slice(&key)
+                    .map_err(|_| OpError::from("invalid compressed public key in undo data"))?
+                    .serialize_uncompressed()
+                    .to_vec()
+            };
+            Builder::new()
+                .push_slice(&key_bytes)
+                .push_opcode(all::OP_CHECKSIG)
+                .into_script()
+        }
+        _ => {
+            let size = n_size - SPECIAL_SCRIPTS;
+            let bytes = r.read_u8_vec(size as u32)?;
+            if size > MAX_SCRIPT_SIZE {
+                Builder::new().push_opcode(all::OP_RETURN).into_script()
+            } else {
+                Script::from(bytes)
+            }
+        }
+    };
+    Ok(script)
+}
+
+/// `DecompressAmount` in Core
+pub(crate) fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+/// `CompressAmount` in Core
+#[cfg(any(test, feature = "testutil"))]
+pub(crate) fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n % 10 == 0 && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+/// Core `VARINT` encoding
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = Vec::with_capacity(10);
+    loop {
+        let mark = if tmp.is_empty() { 0 } else { 0x80 };
+        tmp.push((n & 0x7F) as u8 | mark);
+        if n <= 0x7F {
+            break;
+        }
+        n = (n >> 7) - 1;
+    }
+    out.extend(tmp.iter().rev());
+}
+
+///
+/// Serialize `CBlockUndo`, with scripts stored uncompressed.
+///
+#[cfg(any(test, feature = "testutil"))]
+pub(crate) fn serialize_block_undo(undo: &BlockUndo) -> Vec<u8> {
+    let mut out = Vec::new();
+    VarInt(undo.txs.len() as u64)
+        .consensus_encode(&mut out)
+        .unwrap();
+    for spent in undo.txs.iter() {
+        VarInt(spent.len() as u64)
+            .consensus_encode(&mut out)
+            .unwrap();
+        for s in spent {
+            write_varint(&mut out, s.height as u64 * 2 + s.is_coinbase as u64);
+            if s.height > 0 {
+                write_varint(&mut out, 0);
+            }
+            write_varint(&mut out, compress_amount(s.txout.value));
+            let script = s.txout.script_pubkey.as_bytes();
+            write_varint(&mut out, (script.len() + SPECIAL_SCRIPTS) as u64);
+            out.extend_from_slice(script);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_compression() {
+        for n in [
+            0,
+            1,
+            9,
+            10,
+            50 * 100_000_000,
+            123_456_789,
+            21_000_000 * 100_000_000,
+        ] {
+            assert_eq!(decompress_amount(compress_amount(n)), n);
+        }
+        // values from Core's compress_tests
+        assert_eq!(compress_amount(100_000_000), 0x9);
+        assert_eq!(compress_amount(50 * 100_000_000), 0x32);
+    }
+
+    #[test]
+    fn test_block_undo_roundtrip() {
+        let spent = |height, value, script: Script| SpentOutput {
+            txout: TxOut {
+                value,
+                script_pubkey: script,
+            },
+            height,
+            is_coinbase: height % 2 == 1,
+        };
+        let undo = BlockUndo {
+            txs: vec![
+                vec![
+                    spent(
+                        1,
+                        50 * 100_000_000,
+                        Script::new_p2pkh(&PubkeyHash::hash(b"a")),
+                    ),
+                    spent(0, 0, Script::new()),
+                ],
+                vec![spent(
+                    700000,
+                    1234,
+                    Builder::new().push_opcode(all::OP_RETURN).into_script(),
+                )],
+            ],
+        };
+        let data = serialize_block_undo(&undo);
+        assert_eq!(parse_block_undo(&data).unwrap(), undo);
+
+        // compressed p2pkh (special script 0) from Core
+        let hash = [7u8; 20];
+        let mut data = vec![0x01, 0x01];
+        write_varint(&mut data, 2 * 100 + 1);
+        write_varint(&mut data, 0);
+        write_varint(&mut data, compress_amount(1000));
+        data.push(0x00);
+        data.extend_from_slice(&hash);
+        let undo = parse_block_undo(&data).unwrap();
+        let s = &undo.txs[0][0];
+        assert_eq!((s.height, s.is_coinbase, s.txout.value), (100, true, 1000));
+        assert_eq!(
+            s.txout.script_pubkey,
+            Script::new_p2pkh(&PubkeyHash::from_slice(&hash).unwrap())
+        );
+        assert!(parse_block_undo(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_rev_index() {
+        assert_eq!(parse_rev_index("rev00012.dat"), Some(12));
+        assert_eq!(parse_rev_index("blk00012.dat"), None);
+    }
+}
@@ -0,0 +1,129 @@
+//!
+//! Obfuscation of blk and rev files.
+//!
+//! Since v28, Bitcoin Core XORs the content of blk and rev files with
+//! the 8 bytes key of `blocks/xor.dat`, repeated from the start of each
+//! file. Directories without `xor.dat` (or with an all-zero key)
+//! are not obfuscated.
+//!
+use crate::parser::errors::{OpError, OpResult};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// key file in the blocks directory
+pub(crate) const XOR_KEY_FILE: &str = "xor.dat";
+
+pub(crate) type XorKey = [u8; 8];
+
+///
+/// Key of the blocks directory, `None` if it is not obfuscated.
+///
+pub(crate) fn read_xor_key(blocks_dir: &Path) -> OpResult<Option<XorKey>> {
+    let path = blocks_dir.join(XOR_KEY_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(&path)?;
+    if bytes.len() != 8 {
+        return Err(OpError::from(
+            format!(
+                "{} should be 8 bytes, found {}",
+                path.display(),
+                bytes.len()
+            )
+            .as_str(),
+        ));
+    }
+    let mut key = [0u8; 8];
+    key.copy_from_slice(&bytes);
+    Ok(if key == [0u8; 8] { None } else { Some(key) })
+}
+
+///
+/// XOR `buf`, read at offset `pos` of a file, with `key`
+/// (obfuscating and deobfuscating are the same operation).
+///
+pub(crate) fn xor_in_place(buf: &mut [u8], key: &XorKey, pos: u64) {
+    let shift = (pos % 8) as usize;
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b ^= key[(shift + i) % 8];
+    }
+}
+
+///
+/// A reader of a possibly obfuscated file, yielding plain bytes.
+///
+/// Tracks the position in the file, so it supports seeking
+/// (and `BufReader::seek_relative` when wrapped in a `BufReader`).
+///
+pub(crate) struct XorReader<R> {
+    inner: R,
+    key: Option<XorKey>,
+    pos: u64,
+}
+
+impl XorReader<File> {
+    pub(crate) fn open(path: &Path, key: Option<XorKey>) -> io::Result<Self> {
+        Ok(XorReader::new(File::open(path)?, key))
+    }
+}
+
+impl<R> XorReader<R> {
+    /// `inner` must be at the start of the file
+    pub(crate) fn new(inner: R, key: Option<XorKey>) -> Self {
+        XorReader { inner, key, pos: 0 }
+    }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(key) = &self.key {
+            xor_in_place(&mut buf[..n], key, self.pos);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for XorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    #[test]
+    fn test_xor_reader() {
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+        let plain: Vec<u8> = (0..100u8).collect();
+        let mut obfuscated = plain.clone();
+        xor_in_place(&mut obfuscated, &key, 0);
+        assert_ne!(obfuscated, plain);
+
+        let mut r =
+            BufReader::with_capacity(16, XorReader::new(Cursor::new(obfuscated), Some(key)));
+        let mut buf = [0u8; 5];
+        r.seek(SeekFrom::Start(13)).unwrap();
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, plain[13..18]);
+        // within and beyond the buffer
+        r.seek_relative(2).unwrap();
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, plain[20..25]);
+        r.seek_relative(50).unwrap();
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, plain[75..80]);
+
+        let mut r = XorReader::new(Cursor::new(plain.clone()), None);
+        let mut all = Vec::new();
+        r.read_to_end(&mut all).unwrap();
+        assert_eq!(all, plain);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::api::{Address, BitcoinDB};
+use crate::index::{AddressEvent, AddressIndex, BuildOptions};
+use crate::parser::errors::{OpError, OpResult};
+use std::path::{Path, PathBuf};
+
+///
+/// Configuration of `address_index` and `address_report`.
+///
+#[derive(Debug, Clone)]
+pub struct AddressIndexConfig {
+    pub datadir: PathBuf,
+    /// directory of the index (RocksDB), created if missing
+    pub index_dir: PathBuf,
+    /// progress reporting and I/O throttle of the update
+    pub build_options: BuildOptions,
+}
+
+impl AddressIndexConfig {
+    pub fn new(datadir: &Path, index_dir: &Path) -> Self {
+        AddressIndexConfig {
+            datadir: datadir.to_path_buf(),
+            index_dir: index_dir.to_path_buf(),
+            build_options: BuildOptions::default(),
+        }
+    }
+}
+
+///
+/// History and balance of an address.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressReport {
+    pub address: Address,
+    pub history: Vec<AddressEvent>,
+    /// balance after the last indexed block (satoshi)
+    pub balance: u64,
+}
+
+///
+/// Open the datadir and the index of `cfg`,
+/// and index the blocks added since the last run.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::pipelines::{address_index, AddressIndexConfig};
+/// use std::path::Path;
+///
+/// let cfg = AddressIndexConfig::new(Path::new("/Users/me/bitcoin"), Path::new("./addresses"));
+/// let (db, index) = address_index(&cfg).unwrap();
+/// println!("{} blocks indexed", index.indexed_height().unwrap());
+/// ```
+///
+pub fn address_index(cfg: &AddressIndexConfig) -> OpResult<(BitcoinDB, AddressIndex)> {
+    let db = BitcoinDB::new(&cfg.datadir, false)?;
+    let index = AddressIndex::open(&cfg.index_dir)?;
+    index.update(&db, &cfg.build_options)?;
+    Ok((db, index))
+}
+
+///
+/// Update the index of `cfg`, then report history and balance of `addresses`.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::pipelines::{address_report, AddressIndexConfig};
+/// use bitcoin_explorer::Address;
+/// use std::path::Path;
+/// use std::str::FromStr;
+///
+/// let cfg = AddressIndexConfig::new(Path::new("/Users/me/bitcoin"), Path::new("./addresses"));
+/// let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+/// for report in address_report(&cfg, &[address]).unwrap() {
+///     println!("{}: {} events, {} sat", report.address, report.history.len(), report.balance);
+/// }
+/// ```
+///
+pub fn address_report(
+    cfg: &AddressIndexConfig,
+    addresses: &[Address],
+) -> OpResult<Vec<AddressReport>> {
+    let (db, index) = address_index(cfg)?;
+    let indexed = index.indexed_height()?;
+    if indexed == 0 {
+        return Err(OpError::from("no block indexed"));
+    }
+    addresses
+        .iter()
+        .map(|address| {
+            Ok(AddressReport {
+                address: address.clone(),
+                history: db.get_address_history(&index, address)?,
+                balance: db.get_address_balance(&index, address, indexed - 1)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::AddressEventKind;
+    use crate::testutil::{SyntheticChain, TestDir};
+    use bitcoin::Network;
+
+    #[test]
+    fn test_address_report() {
+        let dir = TestDir::new("address_pipeline");
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        chain.write(&dir.join("datadir")).unwrap();
+
+        let miner = &chain.block(&tips[0]).unwrap().txdata[0].output[0].script_pubkey;
+        let address = Address::from_script(miner, Network::Bitcoin).unwrap();
+        let cfg = AddressIndexConfig::new(&dir.join("datadir"), &dir.join("index"));
+        let reports = address_report(&cfg, &[address.clone()]).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].address, address);
+        let history = &reports[0].history;
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            (history[0].height, history[0].kind),
+            (1, AddressEventKind::Received)
+        );
+        assert_eq!(reports[0].balance, history[0].value);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::api::{AmountFormat, BitcoinDB, SBlock};
+use crate::export::{
+    export_partitioned, DatasetManifest, ExportOptions, HeaderStyle, JobJournal, Pseudonymizer,
+};
+use crate::index::{BuildOptions, StageMonitor};
+use crate::parser::errors::{OpError, OpResult};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+///
+/// Tables written by `full_etl`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EtlTable {
+    /// `height,block_hash,time,n_tx`
+    Blocks,
+    /// `height,txid,n_inputs,n_outputs,output_value` (no inputs for coinbases)
+    Transactions,
+    ///
+    /// `height,txid,vout,value,addresses` (addresses separated by `;`,
+    /// pseudonyms with `EtlConfig::pseudonymizer`)
+    ///
+    Outputs,
+}
+
+impl EtlTable {
+    /// prefix of the files of this table
+    fn prefix(&self) -> &'static str {
+        match self {
+            EtlTable::Blocks => "blocks-",
+            EtlTable::Transactions => "transactions-",
+            EtlTable::Outputs => "outputs-",
+        }
+    }
+
+    /// name of the stage of this table in the progress tree
+    fn stage(&self) -> &'static str {
+        match self {
+            EtlTable::Blocks => "blocks",
+            EtlTable::Transactions => "transactions",
+            EtlTable::Outputs => "outputs",
+        }
+    }
+
+    /// rough time to export a block, relative to the other tables
+    fn weight(&self) -> f64 {
+        match self {
+            EtlTable::Blocks => 1.0,
+            EtlTable::Transactions => 2.0,
+            EtlTable::Outputs => 4.0,
+        }
+    }
+
+    fn header(&self) -> &'static str {
+        match self {
+            EtlTable::Blocks => "height,block_hash,time,n_tx",
+            EtlTable::Transactions => "height,txid,n_inputs,n_outputs,output_value",
+            EtlTable::Outputs => "height,txid,vout,value,addresses",
+        }
+    }
+}
+
+///
+/// Configuration of `full_etl`.
+///
+#[derive(Debug, Clone)]
+pub struct EtlConfig {
+    pub datadir: PathBuf,
+    pub out_dir: PathBuf,
+    /// heights to export, `None` for all blocks
+    pub range: Option<Range<usize>>,
+    /// number of heights per partition file
+    pub partition_size: usize,
+    pub amount_format: AmountFormat,
+    pub tables: Vec<EtlTable>,
+    /// write keyed pseudonyms in place of addresses (see `Pseudonymizer`)
+    pub pseudonymizer: Option<Pseudonymizer>,
+    ///
+    /// Progress of the export through `BuildOptions::stage_progress`:
+    /// one stage per table, counting the heights left to export.
+    ///
+    pub build_options: BuildOptions,
+}
+
+impl EtlConfig {
+    /// all tables of all blocks, in partitions of 10000 heights
+    pub fn new(datadir: &Path, out_dir: &Path) -> Self {
+        EtlConfig {
+            datadir: datadir.to_path_buf(),
+            out_dir: out_dir.to_path_buf(),
+            range: None,
+            partition_size: 10000,
+            amount_format: AmountFormat::default(),
+            tables: vec![EtlTable::Blocks, EtlTable::Transactions, EtlTable::Outputs],
+            pseudonymizer: None,
+            build_options: BuildOptions::default(),
+        }
+    }
+}
+
+///
+/// Manifests of the tables written by `full_etl`.
+///
+#[derive(Debug, Clone)]
+pub struct EtlReport {
+    pub tables: Vec<(EtlTable, DatasetManifest)>,
+}
+
+///
+/// Export the tables of `cfg` as CSV partitions under `cfg.out_dir`
+/// (`{table}-{start}-{end}.csv`, with a journal and a manifest per table).
+/// Each file starts with a `#` comment line of run metadata
+/// (`HeaderStyle::Comment`), then the CSV header.
+///
+/// Each table is a resumable export: run again after an interruption
+/// to continue where it stopped.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::pipelines::{full_etl, EtlConfig};
+/// use std::path::Path;
+///
+/// let cfg = EtlConfig::new(Path::new("/Users/me/bitcoin"), Path::new("./etl"));
+/// let report = full_etl(&cfg).unwrap();
+/// for (table, manifest) in report.tables {
+///     println!("{:?}: {} partitions", table, manifest.partitions.len());
+/// }
+/// ```
+///
+pub fn full_etl(cfg: &EtlConfig) -> OpResult<EtlReport> {
+    if cfg.tables.is_empty() {
+        return Err(OpError::from("no table to export"));
+    }
+    let db = BitcoinDB::new(&cfg.datadir, false)?;
+    let range = cfg.range.clone().unwrap_or(0..db.get_block_count());
+    let mut monitor = StageMonitor::new("full_etl", &cfg.build_options);
+    let stages: Vec<(&str, f64)> = cfg.tables.iter().map(|t| (t.stage(), t.weight())).collect();
+    monitor.add_stages(&[], &stages)?;
+    let mut tables = Vec::with_capacity(cfg.tables.len());
+    for table in cfg.tables.iter().copied() {
+        let options = ExportOptions {
+            partition_size: cfg.partition_size,
+            prefix: table.prefix().to_string(),
+            extension: ".csv".to_string(),
+            amount_format: cfg.amount_format,
+            pseudonymizer: cfg.pseudonymizer.clone(),
+            header: HeaderStyle::Comment,
+            ..Default::default()
+        };
+        // partitions completed by a previous run are not exported again
+        let journal = JobJournal::open(cfg.out_dir.join(format!("{}journal", table.prefix())))?;
+        let resumed: usize = journal
+            .records()
+            .iter()
+            .filter(|r| r.heights.start >= range.start && r.heights.end <= range.end)
+            .map(|r| r.heights.len())
+            .sum();
+        monitor.start_stage(&[table.stage()], range.len().saturating_sub(resumed))?;
+        let manifest = export_partitioned(
+            &db,
+            range.clone(),
+            &cfg.out_dir,
+            &options,
+            |db, heights, file| {
+                let len = heights.len();
+                write_partition(db, table, &options, heights, file)?;
+                monitor.advance(&[table.stage()], len)
+            },
+        )?;
+        monitor.finish_stage(&[table.stage()])?;
+        tables.push((table, manifest));
+    }
+    monitor.finish();
+    Ok(EtlReport { tables })
+}
+
+fn write_partition(
+    db: &BitcoinDB,
+    table: EtlTable,
+    options: &ExportOptions,
+    heights: Range<usize>,
+    file: &mut BufWriter<File>,
+) -> OpResult<()> {
+    let format = options.amount_format;
+    writeln!(file, "{}", table.header())?;
+    let start = heights.start;
+    for (i, block) in db
+        .iter_block::<SBlock>(heights.start, heights.end)
+        .enumerate()
+    {
+        let height = start + i;
+        match table {
+            EtlTable::Blocks => writeln!(
+                file,
+                "{},{},{},{}",
+                height,
+                block.header.block_hash,
+                block.header.time,
+                block.txdata.len()
+            )?,
+            EtlTable::Transactions => {
+                for tx in block.txdata.iter() {
+                    let value = tx.output.iter().map(|o| o.value.as_sat()).sum();
+                    writeln!(
+                        file,
+                        "{},{},{},{},{}",
+                        height,
+                        tx.txid,
+                        tx.input.len(),
+                        tx.output.len(),
+                        format.format(value)
+                    )?;
+                }
+            }
+            EtlTable::Outputs => {
+                for tx in block.txdata.iter() {
+                    for (vout, o) in tx.output.iter().enumerate() {
+                        let addresses: Vec<String> = match &options.pseudonymizer {
+                            Some(p) => o.addresses.iter().map(|a| p.address(a)).collect(),
+                            None => o.addresses.iter().map(|a| a.to_string()).collect(),
+                        };
+                        writeln!(
+                            file,
+                            "{},{},{},{},{}",
+                            height,
+                            tx.txid,
+                            vout,
+                            format.format(o.value.as_sat()),
+                            addresses.join(";")
+                        )?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::DatasetHeader;
+    use crate::index::StageState;
+    use crate::testutil::{SyntheticChain, TestDir};
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_full_etl() {
+        let dir = TestDir::new("etl");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 4);
+        chain.write(&dir.join("datadir")).unwrap();
+
+        let trees = Arc::new(Mutex::new(Vec::new()));
+        let t = trees.clone();
+        let mut cfg = EtlConfig::new(&dir.join("datadir"), &dir.join("out"));
+        cfg.partition_size = 2;
+        cfg.build_options = BuildOptions::default().with_stage_progress(move |tree| {
+            t.lock().unwrap().push(tree.clone());
+        });
+        cfg.build_options.progress_interval = 1;
+        let report = full_etl(&cfg).unwrap();
+        {
+            let trees = trees.lock().unwrap();
+            let last = trees.last().unwrap();
+            assert_eq!(last.stages.len(), 3);
+            assert_eq!(last.find(&["outputs"]).unwrap().done, 5);
+            // 3 partitions, and the start and end of each table
+            assert_eq!(trees.len(), 3 * (3 + 2) + 1);
+            assert_eq!(trees[4].find(&["blocks"]).unwrap().state, StageState::Done);
+        }
+        assert_eq!(report.tables.len(), 3);
+        for (_, manifest) in report.tables.iter() {
+            assert_eq!(manifest.partitions.len(), 3);
+        }
+        let blocks = fs::read_to_string(dir.join("out").join("blocks-4-5.csv")).unwrap();
+        let hash = chain.main_chain()[4];
+        let mut lines = blocks.lines();
+        let header = DatasetHeader::parse_record(lines.next().unwrap()).unwrap();
+        assert_eq!(header.tip_hash, report.tables[0].1.tip_hash);
+        assert_eq!(header.parameters["partition_size"], "2");
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            vec![
+                EtlTable::Blocks.header().to_string(),
+                format!("4,{},{},1", hash, chain.block(&hash).unwrap().header.time)
+            ]
+        );
+        let outputs = fs::read_to_string(dir.join("out").join("outputs-0-2.csv")).unwrap();
+        assert!(outputs.lines().count() > 2);
+
+        // resuming rewrites nothing
+        trees.lock().unwrap().clear();
+        let report = full_etl(&cfg).unwrap();
+        assert_eq!(report.tables[0].1.partitions.len(), 3);
+        assert_eq!(trees.lock().unwrap()[0].find(&["blocks"]).unwrap().total, 0);
+    }
+
+    #[test]
+    fn test_full_etl_pseudonyms() {
+        let dir = TestDir::new("etl_pseudonyms");
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 2);
+        chain.write(&dir.join("datadir")).unwrap();
+
+        let pseudonymizer = Pseudonymizer::new(&[1u8; 32]);
+        let mut cfg = EtlConfig::new(&dir.join("datadir"), &dir.join("out"));
+        cfg.tables = vec![EtlTable::Outputs];
+        cfg.pseudonymizer = Some(pseudonymizer.clone());
+        let report = full_etl(&cfg).unwrap();
+        assert_eq!(
+            report.tables[0].1.parameters.get("address_pseudonyms"),
+            Some(&"hmac-sha256".to_string())
+        );
+
+        let db = BitcoinDB::new(&dir.join("datadir"), false).unwrap();
+        let block = db.get_block::<SBlock>(1).unwrap();
+        let address = &block.txdata[0].output[0].addresses[0];
+        let outputs = fs::read_to_string(dir.join("out").join("outputs-0-3.csv")).unwrap();
+        assert!(!outputs.contains(&address.to_string()));
+        assert!(outputs.contains(&pseudonymizer.address(address)));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::api::{BitcoinDB, SConnectedBlock};
+use crate::parser::errors::{OpError, OpResult};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+///
+/// Configuration of `fee_time_series`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeSeriesConfig {
+    pub datadir: PathBuf,
+    /// first height of the series
+    pub start: usize,
+    /// end of the series (excluded), `None` for all blocks
+    pub end: Option<usize>,
+    /// number of blocks per point of the series
+    pub bucket_blocks: usize,
+}
+
+impl FeeSeriesConfig {
+    /// daily (144 blocks) points over all blocks
+    pub fn new(datadir: &Path) -> Self {
+        FeeSeriesConfig {
+            datadir: datadir.to_path_buf(),
+            start: 0,
+            end: None,
+            bucket_blocks: 144,
+        }
+    }
+}
+
+///
+/// Fees paid in a bucket of blocks.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeePoint {
+    pub heights: Range<usize>,
+    /// timestamp of the last block of the bucket
+    pub time: u32,
+    /// number of transactions, excluding coinbases
+    pub n_tx: u64,
+    /// sum of fees (satoshi)
+    pub total_fee: u64,
+    /// median fee of a transaction (satoshi), `0` without transactions
+    pub median_fee: u64,
+}
+
+///
+/// Fees paid per bucket of `cfg.bucket_blocks` blocks.
+///
+/// Fees need connected inputs, so blocks are connected from the genesis
+/// block even when the series starts later.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::pipelines::{fee_time_series, write_fee_series_csv, FeeSeriesConfig};
+/// use std::path::Path;
+///
+/// let mut cfg = FeeSeriesConfig::new(Path::new("/Users/me/bitcoin"));
+/// cfg.start = 600000;
+/// let series = fee_time_series(&cfg).unwrap();
+/// write_fee_series_csv(&series, Path::new("./fees.csv")).unwrap();
+/// ```
+///
+pub fn fee_time_series(cfg: &FeeSeriesConfig) -> OpResult<Vec<FeePoint>> {
+    if cfg.bucket_blocks == 0 {
+        return Err(OpError::from("bucket_blocks must be positive"));
+    }
+    let db = BitcoinDB::new(&cfg.datadir, false)?;
+    let end = cfg.end.unwrap_or_else(|| db.get_block_count());
+    if end > db.get_block_count() || cfg.start > end {
+        return Err(OpError::from("invalid block range"));
+    }
+    let mut series = Vec::new();
+    let mut fees: Vec<u64> = Vec::new();
+    let mut bucket_start = cfg.start;
+    let blocks = db.iter_connected_block::<SConnectedBlock>(end);
+    for (height, block) in blocks.enumerate().skip(cfg.start) {
+        fees.extend(
+            block
+                .txdata
+                .iter()
+                .filter_map(|tx| tx.fee())
+                .map(|fee| fee.as_sat()),
+        );
+        if height + 1 - bucket_start == cfg.bucket_blocks || height + 1 == end {
+            fees.sort_unstable();
+            series.push(FeePoint {
+                heights: bucket_start..height + 1,
+                time: block.header.time,
+                n_tx: fees.len() as u64,
+                total_fee: fees.iter().sum(),
+                median_fee: fees.get(fees.len() / 2).copied().unwrap_or(0),
+            });
+            fees.clear();
+            bucket_start = height + 1;
+        }
+    }
+    if bucket_start != end {
+        return Err(OpError::from(
+            format!("connected iteration stopped at height {}", bucket_start).as_str(),
+        ));
+    }
+    Ok(series)
+}
+
+///
+/// Write a fee series as CSV
+/// (`start,end,time,n_tx,total_fee,median_fee`).
+///
+pub fn write_fee_series_csv(series: &[FeePoint], path: &Path) -> OpResult<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "start,end,time,n_tx,total_fee,median_fee")?;
+    for p in series {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            p.heights.start, p.heights.end, p.time, p.n_tx, p.total_fee, p.median_fee
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDir};
+
+    #[test]
+    fn test_fee_time_series() {
+        let dir = TestDir::new("fee_series");
+
+        // block 3 pays a fee of 1000 sat
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let spend = SyntheticChain::spend(
+            chain.coinbase_outpoint(&tips[0]).unwrap(),
+            50 * 100_000_000 - 1000,
+        );
+        let spending = chain.mine(&tips[1], vec![spend]);
+        chain.extend(&spending, 2);
+        chain.write(&dir).unwrap();
+
+        let mut cfg = FeeSeriesConfig::new(&dir);
+        cfg.start = 1;
+        cfg.bucket_blocks = 2;
+        let series = fee_time_series(&cfg).unwrap();
+        let buckets: Vec<_> = series.iter().map(|p| p.heights.clone()).collect();
+        assert_eq!(buckets, vec![1..3, 3..5, 5..6]);
+        assert_eq!((series[1].n_tx, series[1].total_fee), (1, 1000));
+        assert_eq!(series[1].median_fee, 1000);
+        assert_eq!(series[0].total_fee + series[2].total_fee, 0);
+
+        let csv = dir.join("fees.csv");
+        write_fee_series_csv(&series, &csv).unwrap();
+        assert_eq!(std::fs::read_to_string(&csv).unwrap().lines().count(), 4);
+    }
+}
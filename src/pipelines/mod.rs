@@ -0,0 +1,22 @@
+//!
+//! End-to-end pipelines over a datadir, configured by typed configs.
+//!
+//! Each pipeline opens the datadir of its config and runs a complete,
+//! tuned flow built from the rest of this crate:
+//! - `full_etl`: resumable CSV export of blocks, transactions and outputs
+//!   (see `export::export_partitioned`);
+//! - `address_index` / `address_report`: build or update an `AddressIndex`
+//!   and query addresses (feature `on-disk-utxo`);
+//! - `fee_time_series`: fees paid per bucket of blocks.
+//!
+//! The `examples` directory runs each of them from the command line.
+//!
+#[cfg(feature = "on-disk-utxo")]
+mod address;
+mod etl;
+mod fees;
+
+#[cfg(feature = "on-disk-utxo")]
+pub use address::{address_index, address_report, AddressIndexConfig, AddressReport};
+pub use etl::{full_etl, EtlConfig, EtlReport, EtlTable};
+pub use fees::{fee_time_series, write_fee_series_csv, FeePoint, FeeSeriesConfig};
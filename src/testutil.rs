@@ -0,0 +1,551 @@
+//!
+//! Synthetic chains for testing reorg handling (feature `testutil`).
+//!
+//! Build a block tree with competing branches, write it as a Bitcoin Core
+//! datadir (`blocks/blk00000.dat`, `blocks/rev00000.dat` and `blocks/index`), and open it with
+//! `BitcoinDB`. Rewriting the datadir after extending another branch
+//! simulates a reorg, deterministically.
+//!
+//! # Example
+//!
+//! ```rust
+//! use bitcoin_explorer::testutil::{assert_main_chain, reorg_depth, SyntheticChain, TestDb};
+//!
+//! let mut chain = SyntheticChain::new();
+//! let old_tip = chain.extend(&chain.genesis(), 5).pop().unwrap();
+//! let mut db = TestDb::new("reorg_example", &chain);
+//! let old = chain.main_chain();
+//!
+//! // a longer branch forking from height 3
+//! chain.extend(&old[3], 4);
+//! db.rewrite(&chain);
+//! let new = chain.main_chain();
+//! assert_eq!(reorg_depth(&old, &new), 2);
+//! assert_main_chain(&db, &new);
+//! ```
+//!
+//! `TestDir` and `TestDb` give each test its own temporary directory,
+//! removed when the fixture is dropped.
+//!
+use crate::api::{network_dir_name, BitcoinDB};
+use crate::parser::blk_file::magic_of;
+use crate::parser::block_index::{
+    write_block_index, BlockIndexRecord, BLOCK_HAVE_DATA, BLOCK_HAVE_UNDO, BLOCK_VALID_SCRIPTS,
+};
+use crate::parser::errors::OpResult;
+use crate::parser::undo_file::{serialize_block_undo, undo_checksum, BlockUndo, SpentOutput};
+use crate::parser::xor::{xor_in_place, XOR_KEY_FILE};
+use bitcoin::blockdata::opcodes::all::OP_RETURN;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    Block, BlockHash, BlockHeader, Network, OutPoint, PubkeyHash, Script, Transaction, TxIn,
+    TxMerkleNode, TxOut, Witness,
+};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// regtest difficulty
+const REGTEST_BITS: u32 = 0x207fffff;
+const GENESIS_TIME: u32 = 1296688602;
+const COINBASE_VALUE: u64 = 50 * 100_000_000;
+
+///
+/// A tree of synthetic blocks.
+///
+/// Blocks are not mined (proof of work is not checked by this crate),
+/// and each coinbase pays to a distinct p2pkh script.
+///
+#[derive(Debug, Clone)]
+pub struct SyntheticChain {
+    blocks: Vec<(usize, Block)>,
+    by_hash: HashMap<BlockHash, usize>,
+}
+
+impl Default for SyntheticChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyntheticChain {
+    ///
+    /// A chain with only a genesis block.
+    ///
+    pub fn new() -> Self {
+        let mut chain = SyntheticChain {
+            blocks: Vec::new(),
+            by_hash: HashMap::new(),
+        };
+        chain.push(None, Vec::new());
+        chain
+    }
+
+    pub fn genesis(&self) -> BlockHash {
+        self.blocks[0].1.block_hash()
+    }
+
+    ///
+    /// Add a block on top of `parent`, with a coinbase followed by `txs`.
+    ///
+    /// Panics if `parent` is unknown.
+    ///
+    pub fn mine(&mut self, parent: &BlockHash, txs: Vec<Transaction>) -> BlockHash {
+        let parent = *self.by_hash.get(parent).expect("unknown parent block");
+        self.push(Some(parent), txs)
+    }
+
+    ///
+    /// Add `n` blocks (coinbase only) on top of `parent`.
+    ///
+    pub fn extend(&mut self, parent: &BlockHash, n: usize) -> Vec<BlockHash> {
+        let mut hashes = Vec::with_capacity(n);
+        let mut parent = *parent;
+        for _ in 0..n {
+            parent = self.mine(&parent, Vec::new());
+            hashes.push(parent);
+        }
+        hashes
+    }
+
+    pub fn block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.by_hash.get(hash).map(|i| &self.blocks[*i].1)
+    }
+
+    pub fn height_of(&self, hash: &BlockHash) -> Option<usize> {
+        self.by_hash.get(hash).map(|i| self.blocks[*i].0)
+    }
+
+    ///
+    /// The coinbase output of a block, for building spending transactions.
+    ///
+    pub fn coinbase_outpoint(&self, hash: &BlockHash) -> Option<OutPoint> {
+        self.block(hash)
+            .map(|b| OutPoint::new(b.txdata[0].txid(), 0))
+    }
+
+    ///
+    /// A version 2 transaction spending `outpoint` into one output
+    /// of `value` satoshis, with an empty script.
+    ///
+    pub fn spend(outpoint: OutPoint, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    ///
+    /// Hashes of the chain `BitcoinDB` selects: the highest branch,
+    /// ties broken by the smallest tip hash.
+    ///
+    pub fn main_chain(&self) -> Vec<BlockHash> {
+        let tip = self
+            .blocks
+            .iter()
+            .map(|(h, b)| (*h, b.block_hash()))
+            .min_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)))
+            .map(|(_, hash)| hash)
+            .unwrap();
+        let mut chain = vec![tip];
+        let mut current = self.block(&tip).unwrap();
+        while current.header.prev_blockhash != BlockHash::default() {
+            chain.push(current.header.prev_blockhash);
+            current = self.block(&current.header.prev_blockhash).unwrap();
+        }
+        chain.reverse();
+        chain
+    }
+
+    ///
+    /// Write all blocks (of every branch) as a Bitcoin Core datadir.
+    ///
+    /// An existing `blocks` directory under `datadir` is replaced.
+    /// Undo data is written for blocks spending only outputs of the tree.
+    ///
+    pub fn write(&self, datadir: &Path) -> OpResult<()> {
+        self.write_network(datadir, Network::Bitcoin)
+    }
+
+    ///
+    /// Write all blocks as an obfuscated datadir (Bitcoin Core v28+),
+    /// with blk and rev files XORed with `key` (written to `blocks/xor.dat`).
+    ///
+    pub fn write_obfuscated(&self, datadir: &Path, key: [u8; 8]) -> OpResult<()> {
+        self.write(datadir)?;
+        let blocks_dir = datadir.join("blocks");
+        for name in ["blk00000.dat", "rev00000.dat"] {
+            let path = blocks_dir.join(name);
+            let mut bytes = fs::read(&path)?;
+            xor_in_place(&mut bytes, &key, 0);
+            fs::write(&path, bytes)?;
+        }
+        fs::write(blocks_dir.join(XOR_KEY_FILE), key)?;
+        Ok(())
+    }
+
+    ///
+    /// Write all blocks as the datadir of `network`, in the
+    /// subdirectory used by Bitcoin Core (e.g. `regtest/blocks`).
+    ///
+    pub fn write_network(&self, datadir: &Path, network: Network) -> OpResult<()> {
+        self.write_blocks(datadir, network, usize::MAX)
+    }
+
+    ///
+    /// Write all blocks as a mainnet datadir, in blk (and rev) files
+    /// of `blocks_per_file` blocks each.
+    ///
+    pub fn write_split(&self, datadir: &Path, blocks_per_file: usize) -> OpResult<()> {
+        self.write_blocks(datadir, Network::Bitcoin, blocks_per_file.max(1))
+    }
+
+    fn write_blocks(
+        &self,
+        datadir: &Path,
+        network: Network,
+        blocks_per_file: usize,
+    ) -> OpResult<()> {
+        let magic = magic_of(network);
+        let blocks_dir = datadir.join(network_dir_name(network)).join("blocks");
+        if blocks_dir.exists() {
+            fs::remove_dir_all(&blocks_dir)?;
+        }
+        fs::create_dir_all(&blocks_dir)?;
+        let mut records = Vec::with_capacity(self.blocks.len());
+        for (n_file, blocks) in self.blocks.chunks(blocks_per_file).enumerate() {
+            self.write_blk_file(&blocks_dir, magic, n_file, blocks, &mut records)?;
+        }
+        write_block_index(&blocks_dir.join("index"), &records)
+    }
+
+    /// write `blocks` to blk and rev files number `n_file`
+    fn write_blk_file(
+        &self,
+        blocks_dir: &Path,
+        magic: [u8; 4],
+        n_file: usize,
+        blocks: &[(usize, Block)],
+        records: &mut Vec<BlockIndexRecord>,
+    ) -> OpResult<()> {
+        let name = |prefix: &str| blocks_dir.join(format!("{}{:05}.dat", prefix, n_file));
+        let mut w = BufWriter::new(File::create(name("blk"))?);
+        let mut rev = BufWriter::new(File::create(name("rev"))?);
+        let mut pos = 0u32;
+        let mut undo_pos = 0u32;
+        for (height, block) in blocks.iter() {
+            let bytes = serialize(block);
+            w.write_all(&magic)?;
+            w.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            w.write_all(&bytes)?;
+            let mut record = BlockIndexRecord {
+                n_version: 250000,
+                n_height: *height as i32,
+                n_status: BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA,
+                n_tx: block.txdata.len() as u32,
+                n_file: n_file as i32,
+                n_data_pos: pos + 8,
+                n_undo_pos: u32::MAX,
+                block_header: block.header,
+            };
+            if let Some(undo) = self.block_undo(block).filter(|_| *height > 0) {
+                let data = serialize_block_undo(&undo);
+                rev.write_all(&magic)?;
+                rev.write_all(&(data.len() as u32).to_le_bytes())?;
+                rev.write_all(&data)?;
+                rev.write_all(&undo_checksum(&block.header.prev_blockhash, &data))?;
+                record.n_status |= BLOCK_HAVE_UNDO;
+                record.n_undo_pos = undo_pos + 8;
+                undo_pos += 8 + data.len() as u32 + 32;
+            }
+            records.push(record);
+            pos += 8 + bytes.len() as u32;
+        }
+        w.flush()?;
+        rev.flush()?;
+        Ok(())
+    }
+
+    /// outputs spent by `block`, `None` if some are not in the tree
+    fn block_undo(&self, block: &Block) -> Option<BlockUndo> {
+        let mut txs = Vec::with_capacity(block.txdata.len().saturating_sub(1));
+        for tx in block.txdata.iter().skip(1) {
+            let mut spent = Vec::with_capacity(tx.input.len());
+            for input in tx.input.iter() {
+                let outpoint = input.previous_output;
+                let (height, creating) = self.blocks.iter().find_map(|(height, b)| {
+                    b.txdata
+                        .iter()
+                        .position(|t| t.txid() == outpoint.txid)
+                        .map(|i| (*height, (i, &b.txdata[i])))
+                })?;
+                let (index, creating_tx) = creating;
+                spent.push(SpentOutput {
+                    txout: creating_tx.output.get(outpoint.vout as usize)?.clone(),
+                    height: height as u32,
+                    is_coinbase: index == 0,
+                });
+            }
+            txs.push(spent);
+        }
+        Some(BlockUndo { txs })
+    }
+
+    fn push(&mut self, parent: Option<usize>, txs: Vec<Transaction>) -> BlockHash {
+        let nonce = self.blocks.len() as u32;
+        let (height, prev_blockhash) = match parent {
+            Some(i) => (self.blocks[i].0 + 1, self.blocks[i].1.block_hash()),
+            None => (0, BlockHash::default()),
+        };
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(height as i64)
+                    .push_int(nonce as i64)
+                    .into_script(),
+                sequence: 0xffffffff,
+                witness: Witness::default(),
+            }],
+            output: vec![
+                TxOut {
+                    value: COINBASE_VALUE,
+                    script_pubkey: Script::new_p2pkh(&PubkeyHash::hash(&nonce.to_le_bytes())),
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+                },
+            ],
+        };
+        let mut txdata = vec![coinbase];
+        txdata.extend(txs);
+        let mut block = Block {
+            header: BlockHeader {
+                version: 4,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::default(),
+                time: GENESIS_TIME + 600 * height as u32,
+                bits: REGTEST_BITS,
+                nonce,
+            },
+            txdata,
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let hash = block.block_hash();
+        self.by_hash.insert(hash, self.blocks.len());
+        self.blocks.push((height, block));
+        hash
+    }
+}
+
+///
+/// Number of blocks of `old` disconnected when switching to `new`.
+///
+pub fn reorg_depth(old: &[BlockHash], new: &[BlockHash]) -> usize {
+    let common = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    old.len() - common
+}
+
+///
+/// Assert that `db` sees exactly `expected` as its main chain,
+/// through the block index and through block iteration.
+///
+pub fn assert_main_chain(db: &BitcoinDB, expected: &[BlockHash]) {
+    assert_eq!(db.get_block_count(), expected.len(), "block count mismatch");
+    for (height, hash) in expected.iter().enumerate() {
+        assert_eq!(&db.get_hash_from_height(height).unwrap(), hash);
+        assert_eq!(db.get_height_from_hash(hash).unwrap(), height);
+    }
+    let iterated: Vec<BlockHash> = db
+        .iter_block::<Block>(0, expected.len())
+        .map(|b| b.block_hash())
+        .collect();
+    assert_eq!(iterated, expected, "iterated blocks mismatch");
+}
+
+static TEST_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// A temporary directory, unique to one test of one process,
+/// removed on drop (also when the test panics).
+///
+/// Dereferences to its `Path`.
+///
+#[derive(Debug)]
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    ///
+    /// Create an empty directory under `std::env::temp_dir()`,
+    /// with `name` in its file name.
+    ///
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "bitcoin_explorer_test_{}_{}_{}",
+            name,
+            std::process::id(),
+            TEST_DIR_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("failed to create test directory");
+        TestDir { path }
+    }
+
+    ///
+    /// Path of the directory.
+    ///
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+///
+/// A `SyntheticChain` written to a `TestDir`, opened as a `BitcoinDB`.
+///
+/// Dereferences to the `BitcoinDB`. The db is closed
+/// before the directory is removed.
+///
+pub struct TestDb {
+    pub db: BitcoinDB,
+    pub dir: TestDir,
+}
+
+impl TestDb {
+    ///
+    /// Write `chain` (as a mainnet datadir) to a new `TestDir` and open it.
+    ///
+    pub fn new(name: &str, chain: &SyntheticChain) -> Self {
+        Self::with_network(name, chain, Network::Bitcoin)
+    }
+
+    ///
+    /// Write `chain` as the datadir of `network` to a new `TestDir` and open it.
+    ///
+    pub fn with_network(name: &str, chain: &SyntheticChain, network: Network) -> Self {
+        let dir = TestDir::new(name);
+        chain
+            .write_network(&dir, network)
+            .expect("failed to write synthetic chain");
+        let db = BitcoinDB::new(&dir, false).expect("failed to open synthetic chain");
+        TestDb { db, dir }
+    }
+
+    ///
+    /// Rewrite the datadir with `chain` (e.g. after a reorg) and reopen it.
+    ///
+    pub fn rewrite(&mut self, chain: &SyntheticChain) {
+        let network = self.db.network();
+        chain
+            .write_network(&self.dir, network)
+            .expect("failed to write synthetic chain");
+        self.db = BitcoinDB::new(&self.dir, false).expect("failed to open synthetic chain");
+    }
+}
+
+impl Deref for TestDb {
+    type Target = BitcoinDB;
+
+    fn deref(&self) -> &BitcoinDB {
+        &self.db
+    }
+}
+
+impl DerefMut for TestDb {
+    fn deref_mut(&mut self) -> &mut BitcoinDB {
+        &mut self.db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::SConnectedBlock;
+    use bitcoin::Amount;
+
+    #[test]
+    fn test_reorg() {
+        let mut chain = SyntheticChain::new();
+        chain.extend(&chain.genesis(), 5);
+        let mut db = TestDb::new("reorg", &chain);
+        let old = chain.main_chain();
+        assert_eq!(old.len(), 6);
+        assert_main_chain(&db, &old);
+
+        // a competing branch of the same height does not reorg
+        let side = chain.extend(&old[3], 2);
+        assert_eq!(chain.height_of(&side[1]), Some(5));
+
+        // spend the coinbase of block 1 on a longer branch
+        let spend = SyntheticChain::spend(
+            chain.coinbase_outpoint(&old[1]).unwrap(),
+            COINBASE_VALUE - 1000,
+        );
+        let fork = chain.mine(&old[3], vec![spend]);
+        chain.extend(&fork, 3);
+        db.rewrite(&chain);
+        let new = chain.main_chain();
+        assert_eq!(new.len(), 8);
+        assert_eq!(reorg_depth(&old, &new), 2);
+
+        assert_main_chain(&db, &new);
+        let connected: Vec<SConnectedBlock> = db.iter_connected_block(8).collect();
+        assert_eq!(connected.len(), 8);
+        assert_eq!(
+            connected[4].txdata[1].input[0].value,
+            Amount::from_sat(COINBASE_VALUE)
+        );
+    }
+
+    #[test]
+    fn test_dir_removed_on_drop() {
+        let dir = TestDir::new("drop");
+        let other = TestDir::new("drop");
+        assert_ne!(dir.path(), other.path());
+        let path = dir.path().to_path_buf();
+        assert!(path.is_dir());
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
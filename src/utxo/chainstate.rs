@@ -0,0 +1,385 @@
+//!
+//! The UTXO set of Bitcoin Core, read from its `chainstate` levelDB.
+//!
+use crate::api::BitcoinDB;
+use crate::parser::errors::{OpError, OpResult};
+use crate::parser::reader::BlockchainRead;
+use crate::parser::undo_file::{decompress_amount, read_compressed_script, write_varint};
+use crate::utxo::store::{write_snapshot_with, UtxoSnapshotInfo};
+use crate::utxo::Utxo;
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, OutPoint, TxOut, Txid};
+use leveldb::database::Database;
+use leveldb::iterator::Iterable;
+use leveldb::kv::KV;
+use leveldb::options::{Options, ReadOptions};
+use std::io::Cursor;
+use std::path::Path;
+
+/// key of the obfuscation key, written by Core on creation of the database
+const OBFUSCATE_KEY_KEY: &[u8] = b"\x0e\x00obfuscate_key";
+/// prefix of coin records (`DB_COIN`)
+const DB_COIN: u8 = b'C';
+/// key of the hash of the block the UTXO set is at (`DB_BEST_BLOCK`)
+const DB_BEST_BLOCK: u8 = b'B';
+
+///
+/// Summary of the UTXO set, as `gettxoutsetinfo` of Bitcoin Core.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoSetStats {
+    pub best_block: BlockHash,
+    /// transactions with unspent outputs
+    pub transactions: u64,
+    pub txouts: u64,
+    /// unspent outputs created by coinbase transactions
+    pub coinbase_txouts: u64,
+    /// total value (satoshi)
+    pub total_amount: u64,
+}
+
+///
+/// Read-only access to the `chainstate` levelDB of Bitcoin Core,
+/// which holds the UTXO set at the tip of the node.
+///
+/// Bitcoin Core must not be running, since it locks the database.
+///
+pub struct ChainState {
+    db: Database<ChainStateKey>,
+    /// values are XORed with this key, repeated (empty for old databases)
+    obfuscate_key: Vec<u8>,
+}
+
+impl ChainState {
+    ///
+    /// Open `datadir/chainstate`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::utxo::ChainState;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let chainstate = ChainState::open(path).unwrap();
+    ///
+    /// let stats = chainstate.utxo_stats().unwrap();
+    /// println!("{} unspent outputs at {}", stats.txouts, stats.best_block);
+    /// ```
+    ///
+    pub fn open(datadir: &Path) -> OpResult<ChainState> {
+        ChainState::open_path(&datadir.join("chainstate"))
+    }
+
+    ///
+    /// Open a chainstate levelDB at `path` (e.g. a copy of `datadir/chainstate`).
+    ///
+    pub fn open_path(path: &Path) -> OpResult<ChainState> {
+        if !path.exists() {
+            return Err(OpError::from("chainstate does not exist"));
+        }
+        let mut options = Options::new();
+        options.create_if_missing = false;
+        let db: Database<ChainStateKey> = Database::open(path, options)?;
+        let key = ChainStateKey {
+            key: OBFUSCATE_KEY_KEY.to_vec(),
+        };
+        // a serialized vector: its length, then the key
+        let obfuscate_key = match db.get(ReadOptions::new(), &key)? {
+            Some(value) if !value.is_empty() => value[1..].to_vec(),
+            _ => Vec::new(),
+        };
+        Ok(ChainState { db, obfuscate_key })
+    }
+
+    fn deobfuscate(&self, mut value: Vec<u8>) -> Vec<u8> {
+        if !self.obfuscate_key.is_empty() {
+            for (i, b) in value.iter_mut().enumerate() {
+                *b ^= self.obfuscate_key[i % self.obfuscate_key.len()];
+            }
+        }
+        value
+    }
+
+    fn read(&self, key: Vec<u8>) -> OpResult<Option<Vec<u8>>> {
+        let value = self.db.get(ReadOptions::new(), &ChainStateKey { key })?;
+        Ok(value.map(|v| self.deobfuscate(v)))
+    }
+
+    ///
+    /// Hash of the block the UTXO set is at.
+    ///
+    pub fn best_block(&self) -> OpResult<BlockHash> {
+        match self.read(vec![DB_BEST_BLOCK])? {
+            Some(value) => Ok(BlockHash::from_slice(&value)?),
+            None => Err(OpError::from("best block not found in chainstate")),
+        }
+    }
+
+    ///
+    /// Get an unspent output, `None` if it is spent or does not exist.
+    ///
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> OpResult<Option<Utxo>> {
+        match self.read(coin_key(outpoint))? {
+            Some(value) => Ok(Some(parse_coin(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Iterate through all unspent outputs, sorted by txid.
+    ///
+    pub fn iter_utxos(&self) -> impl Iterator<Item = OpResult<(OutPoint, Utxo)>> + '_ {
+        self.db
+            .iter(ReadOptions::new())
+            .filter(|(k, _)| k.key.first() == Some(&DB_COIN))
+            .map(move |(k, v)| {
+                let outpoint = parse_coin_key(&k.key)?;
+                Ok((outpoint, parse_coin(&self.deobfuscate(v))?))
+            })
+    }
+
+    ///
+    /// Summarize the UTXO set (reads the whole database).
+    ///
+    pub fn utxo_stats(&self) -> OpResult<UtxoSetStats> {
+        let mut stats = UtxoSetStats {
+            best_block: self.best_block()?,
+            transactions: 0,
+            txouts: 0,
+            coinbase_txouts: 0,
+            total_amount: 0,
+        };
+        let mut last_txid = None;
+        for utxo in self.iter_utxos() {
+            let (outpoint, utxo) = utxo?;
+            // coins are sorted by txid
+            if last_txid != Some(outpoint.txid) {
+                stats.transactions += 1;
+                last_txid = Some(outpoint.txid);
+            }
+            stats.txouts += 1;
+            stats.coinbase_txouts += utxo.is_coinbase as u64;
+            stats.total_amount += utxo.txout.value;
+        }
+        Ok(stats)
+    }
+
+    ///
+    /// Write the UTXO set as a snapshot of `utxo::store`, to seed
+    /// `ConnectedBlockIter::new_range` at the block after `best_block`
+    /// instead of rebuilding unspent outputs from the genesis block.
+    ///
+    /// `db` must contain the best block of the chainstate.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::utxo::ChainState;
+    /// use bitcoin_explorer::{BitcoinDB, SConnectedBlock};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let snapshot = Path::new("/Users/me/utxo_snapshot");
+    /// let chainstate = ChainState::open(path).unwrap();
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let info = chainstate.write_utxo_snapshot(&db, snapshot).unwrap();
+    /// drop(chainstate);
+    ///
+    /// // later, after the node has downloaded more blocks
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    /// let end = db.get_block_count();
+    /// for block in db
+    ///     .iter_connected_block_range::<SConnectedBlock>(info.height, end, Some(snapshot))
+    ///     .unwrap()
+    /// {
+    ///     println!("{}", block.header.block_hash);
+    /// }
+    /// ```
+    ///
+    pub fn write_utxo_snapshot(&self, db: &BitcoinDB, dir: &Path) -> OpResult<UtxoSnapshotInfo> {
+        let best_block = self.best_block()?;
+        let height = db.get_height_from_hash(&best_block)? + 1;
+        write_snapshot_with(height, best_block, dir, |f| {
+            for utxo in self.iter_utxos() {
+                let (outpoint, utxo) = utxo?;
+                f(outpoint, (utxo.height as u32, utxo.txout))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// `'C' || txid || VARINT(vout)`
+fn coin_key(outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = Vec::with_capacity(38);
+    key.push(DB_COIN);
+    key.extend_from_slice(&outpoint.txid[..]);
+    write_varint(&mut key, outpoint.vout as u64);
+    key
+}
+
+fn parse_coin_key(key: &[u8]) -> OpResult<OutPoint> {
+    if key.len() < 34 {
+        return Err(OpError::from("invalid coin key in chainstate"));
+    }
+    let txid = Txid::from_slice(&key[1..33])?;
+    let vout = Cursor::new(&key[33..]).read_varint()? as u32;
+    Ok(OutPoint { txid, vout })
+}
+
+/// `Coin`: `VARINT(height * 2 + coinbase)`, then the compressed output
+fn parse_coin(value: &[u8]) -> OpResult<Utxo> {
+    let mut r = Cursor::new(value);
+    let code = r.read_varint()?;
+    let amount = decompress_amount(r.read_varint()? as u64);
+    let script_pubkey = read_compressed_script(&mut r)?;
+    Ok(Utxo {
+        txout: TxOut {
+            value: amount,
+            script_pubkey,
+        },
+        height: code >> 1,
+        is_coinbase: code & 1 == 1,
+    })
+}
+
+/// levelDB key utility
+struct ChainStateKey {
+    key: Vec<u8>,
+}
+
+/// levelDB key utility
+impl db_key::Key for ChainStateKey {
+    fn from_u8(key: &[u8]) -> Self {
+        ChainStateKey {
+            key: Vec::from(key),
+        }
+    }
+
+    fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
+        f(&self.key)
+    }
+}
+
+/// write a chainstate at `best_block` with obfuscated values (for tests)
+#[cfg(test)]
+pub(crate) fn write_chainstate(datadir: &Path, best_block: &BlockHash, utxos: &[(OutPoint, Utxo)]) {
+    use crate::parser::undo_file::compress_amount;
+    use leveldb::options::WriteOptions;
+
+    let mut options = Options::new();
+    options.create_if_missing = true;
+    let db: Database<ChainStateKey> = Database::open(&datadir.join("chainstate"), options).unwrap();
+    let obfuscate_key = [0x5a, 0x01, 0xff, 0x10, 0x00, 0x33, 0x77, 0x81];
+    let obfuscate = |mut value: Vec<u8>| {
+        for (i, b) in value.iter_mut().enumerate() {
+            *b ^= obfuscate_key[i % 8];
+        }
+        value
+    };
+    let put = |key: Vec<u8>, value: Vec<u8>| {
+        db.put(WriteOptions::new(), &ChainStateKey { key }, &value)
+            .unwrap()
+    };
+    put(
+        OBFUSCATE_KEY_KEY.to_vec(),
+        [&[8u8][..], &obfuscate_key].concat(),
+    );
+    put(vec![DB_BEST_BLOCK], obfuscate(best_block[..].to_vec()));
+    for (outpoint, utxo) in utxos {
+        let mut value = Vec::new();
+        write_varint(
+            &mut value,
+            (utxo.height * 2 + utxo.is_coinbase as usize) as u64,
+        );
+        write_varint(&mut value, compress_amount(utxo.txout.value));
+        let script = utxo.txout.script_pubkey.as_bytes();
+        write_varint(&mut value, script.len() as u64 + 6);
+        value.extend_from_slice(script);
+        put(coin_key(outpoint), obfuscate(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDir};
+    use crate::SConnectedBlock;
+    use bitcoin::{Script, Transaction, TxIn};
+
+    #[test]
+    fn test_chainstate() {
+        let dir = TestDir::new("chainstate");
+
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 2);
+        let utxos: Vec<(OutPoint, Utxo)> = [chain.genesis(), tips[0], tips[1]]
+            .iter()
+            .enumerate()
+            .map(|(height, hash)| {
+                let coinbase = &chain.block(hash).unwrap().txdata[0];
+                let utxo = Utxo {
+                    txout: coinbase.output[0].clone(),
+                    height,
+                    is_coinbase: true,
+                };
+                (chain.coinbase_outpoint(hash).unwrap(), utxo)
+            })
+            .collect();
+        // the block after the chainstate spends the coinbase of block 1
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: utxos[1].0,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        chain.mine(&tips[1], vec![spend]);
+        chain.write(&dir).unwrap();
+        write_chainstate(&dir, &tips[1], &utxos);
+
+        let chainstate = ChainState::open(&dir).unwrap();
+        assert_eq!(chainstate.best_block().unwrap(), tips[1]);
+        assert_eq!(
+            chainstate.get_utxo(&utxos[2].0).unwrap(),
+            Some(utxos[2].1.clone())
+        );
+        let missing = OutPoint {
+            vout: 1,
+            ..utxos[2].0
+        };
+        assert_eq!(chainstate.get_utxo(&missing).unwrap(), None);
+        let mut expected = utxos.clone();
+        expected.sort_by_key(|(o, _)| o.txid);
+        let all: Vec<(OutPoint, Utxo)> = chainstate.iter_utxos().map(|u| u.unwrap()).collect();
+        assert_eq!(all, expected);
+        let stats = chainstate.utxo_stats().unwrap();
+        assert_eq!(
+            (stats.transactions, stats.txouts, stats.coinbase_txouts),
+            (3, 3, 3)
+        );
+        assert_eq!(stats.total_amount, 3 * 50 * 100_000_000);
+
+        // seed connected iteration from the chainstate
+        let db = BitcoinDB::new(&dir, false).unwrap();
+        let snapshot = dir.join("snapshot");
+        let info = chainstate.write_utxo_snapshot(&db, &snapshot).unwrap();
+        assert_eq!((info.height, info.len), (3, 3));
+        let blocks: Vec<SConnectedBlock> = db
+            .iter_connected_block_range(3, 4, Some(&snapshot))
+            .unwrap()
+            .collect();
+        assert_eq!(
+            blocks[0].txdata[1].input[0].value.as_sat(),
+            50 * 100_000_000
+        );
+        drop(chainstate);
+    }
+}
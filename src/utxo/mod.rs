@@ -0,0 +1,23 @@
+//!
+//! This module defines in-memory views of the UTXO set, which can be
+//! loaded up to a certain height and modified by hypothetical transactions.
+//! The UTXO set of a Bitcoin Core node can also be read directly
+//! from its `chainstate` database (`ChainState`).
+//!
+
+mod chainstate;
+mod prevout_cache;
+mod stats;
+pub mod store;
+mod view;
+mod wallet;
+
+#[cfg(all(test, feature = "on-disk-utxo"))]
+pub(crate) use chainstate::write_chainstate;
+pub use chainstate::{ChainState, UtxoSetStats};
+pub use prevout_cache::{PrevoutCache, PrevoutCacheStats};
+pub use stats::ScriptTypeSummary;
+pub use view::{Checkpoint, Effects, Utxo, UtxoView};
+pub use wallet::{
+    suggest_consolidation, ConsolidationBatch, DescriptorKind, WalletDescriptor, WalletUtxo,
+};
@@ -0,0 +1,387 @@
+//!
+//! Persistent LRU cache of resolved prevouts, for repeated connected
+//! runs over overlapping ranges.
+//!
+use crate::api::{BitcoinDB, ConnectedBlock, ConnectedTx};
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::store::StoredTxOut;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::{Block, OutPoint, TxOut};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+use par_iter_sync::IntoParallelIteratorSync;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const PREVOUT_CACHE_MAGIC: &[u8; 8] = b"BEPREVC1";
+
+///
+/// Hits and misses of a `PrevoutCache` since it was opened.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrevoutCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// number of cached prevouts
+    pub len: usize,
+}
+
+#[derive(Default)]
+struct LruState {
+    /// prevout and last use of each outpoint
+    entries: HashMap<OutPoint, (StoredTxOut, u64)>,
+    /// outpoints by last use, least recent first
+    by_use: BTreeMap<u64, OutPoint>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    dirty: bool,
+}
+
+impl LruState {
+    fn touch(&mut self, outpoint: &OutPoint) -> Option<StoredTxOut> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (prevout, last_use) = self.entries.get_mut(outpoint)?;
+        self.by_use.remove(last_use);
+        *last_use = clock;
+        self.by_use.insert(clock, *outpoint);
+        Some(prevout.clone())
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, prevout: StoredTxOut, capacity: usize) {
+        self.clock += 1;
+        if let Some((_, last_use)) = self.entries.insert(outpoint, (prevout, self.clock)) {
+            self.by_use.remove(&last_use);
+        }
+        self.by_use.insert(self.clock, outpoint);
+        while self.entries.len() > capacity {
+            let oldest = match self.by_use.keys().next() {
+                Some(last_use) => *last_use,
+                None => break,
+            };
+            if let Some(outpoint) = self.by_use.remove(&oldest) {
+                self.entries.remove(&outpoint);
+            }
+        }
+        self.dirty = true;
+    }
+}
+
+///
+/// Prevouts (spent outputs and the heights creating them) resolved by
+/// connected runs, keyed by outpoint, persisted in a file.
+///
+/// At most `capacity` prevouts are kept, the least recently used are
+/// evicted first. The cache is written by `flush` (and when dropped),
+/// in order of use, so recency survives reopening.
+///
+/// An outpoint always refers to the same output, so cached outputs stay
+/// valid across reorgs; only the creation height of an output whose
+/// transaction is mined again at another height can become stale.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::utxo::PrevoutCache;
+/// use bitcoin_explorer::{BitcoinDB, SConnectedBlock};
+/// use std::path::Path;
+/// use std::sync::Arc;
+///
+/// let db = BitcoinDB::new(Path::new("/Users/me/bitcoin"), false).unwrap();
+/// let cache = Arc::new(PrevoutCache::open(Path::new("./prevouts.bin"), 50_000_000).unwrap());
+///
+/// // daily job over the last ~month: most prevouts are cached by earlier runs
+/// let end = db.get_block_count();
+/// for block in db.iter_connected_block_cached::<SConnectedBlock>(end - 4320, end, &cache) {
+///     // ...
+/// }
+/// println!("{:?}", cache.stats());
+/// cache.flush().unwrap();
+/// ```
+///
+pub struct PrevoutCache {
+    path: PathBuf,
+    capacity: usize,
+    state: Mutex<LruState>,
+}
+
+impl PrevoutCache {
+    ///
+    /// Open the cache persisted at `path` (empty if the file does not exist),
+    /// keeping at most `capacity` prevouts.
+    ///
+    pub fn open(path: &Path, capacity: usize) -> OpResult<Self> {
+        if capacity == 0 {
+            return Err(OpError::from("prevout cache capacity must be positive"));
+        }
+        let cache = PrevoutCache {
+            path: path.to_path_buf(),
+            capacity,
+            state: Mutex::new(LruState::default()),
+        };
+        if path.exists() {
+            cache.load()?;
+        }
+        Ok(cache)
+    }
+
+    /// the cached prevout of `outpoint`, marked as recently used
+    pub fn get(&self, outpoint: &OutPoint) -> Option<StoredTxOut> {
+        let mut state = self.state.lock().unwrap();
+        let prevout = state.touch(outpoint);
+        if prevout.is_some() {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+        prevout
+    }
+
+    pub fn insert(&self, outpoint: OutPoint, prevout: StoredTxOut) {
+        self.state
+            .lock()
+            .unwrap()
+            .insert(outpoint, prevout, self.capacity);
+    }
+
+    pub fn stats(&self) -> PrevoutCacheStats {
+        let state = self.state.lock().unwrap();
+        PrevoutCacheStats {
+            hits: state.hits,
+            misses: state.misses,
+            len: state.entries.len(),
+        }
+    }
+
+    ///
+    /// Write the cache to its file (atomically), if it changed.
+    ///
+    pub fn flush(&self) -> OpResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.dirty {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(PREVOUT_CACHE_MAGIC)?;
+        out.write_u64::<LittleEndian>(state.by_use.len() as u64)?;
+        for outpoint in state.by_use.values() {
+            let (height, txout) = &state.entries[outpoint].0;
+            outpoint.consensus_encode(&mut out)?;
+            out.write_u32::<LittleEndian>(*height)?;
+            txout.consensus_encode(&mut out)?;
+        }
+        out.flush()?;
+        drop(out);
+        fs::rename(&tmp, &self.path)?;
+        state.dirty = false;
+        Ok(())
+    }
+
+    fn load(&self) -> OpResult<()> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != PREVOUT_CACHE_MAGIC {
+            return Err(OpError::from("not a prevout cache"));
+        }
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..reader.read_u64::<LittleEndian>()? {
+            let outpoint = OutPoint::consensus_decode(&mut reader)?;
+            let height = reader.read_u32::<LittleEndian>()?;
+            let txout = TxOut::consensus_decode(&mut reader)?;
+            state.insert(outpoint, (height, txout), self.capacity);
+        }
+        state.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for PrevoutCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!(
+                "failed to write prevout cache {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl BitcoinDB {
+    ///
+    /// Iterate through connected blocks `start..end`, resolving
+    /// prevouts from `cache` first.
+    ///
+    /// Prevouts missing from the cache are read from undo files
+    /// (or from `txindex` for blocks without undo data), and cached.
+    /// Blocks are connected independently, so any sub-range can be iterated.
+    /// The iterator stops at the first block that cannot be connected.
+    ///
+    pub fn iter_connected_block_cached<TBlock>(
+        &self,
+        start: usize,
+        end: usize,
+        cache: &Arc<PrevoutCache>,
+    ) -> impl Iterator<Item = TBlock>
+    where
+        TBlock: 'static + ConnectedBlock + Send,
+    {
+        let db = self.clone();
+        let cache = cache.clone();
+        (start..end.max(start)).into_par_iter_sync(move |h| {
+            db.get_connected_block_cached::<TBlock>(h, &cache)
+                .map_err(|e| warn!("cannot connect block {}: {}", h, e))
+        })
+    }
+
+    ///
+    /// Get a connected block, resolving prevouts from `cache` first
+    /// (see `iter_connected_block_cached`).
+    ///
+    pub fn get_connected_block_cached<T: ConnectedBlock>(
+        &self,
+        height: usize,
+        cache: &PrevoutCache,
+    ) -> OpResult<T> {
+        let block = self.get_block::<Block>(height)?;
+        let outpoints: Vec<OutPoint> = block
+            .txdata
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.input.iter().map(|i| i.previous_output))
+            .collect();
+        let mut prevouts: Vec<Option<StoredTxOut>> =
+            outpoints.iter().map(|o| cache.get(o)).collect();
+        if prevouts.iter().any(Option::is_none) {
+            let resolved = self.resolve_prevouts(height, &block)?;
+            for ((outpoint, prevout), resolved) in
+                outpoints.iter().zip(prevouts.iter_mut()).zip(resolved)
+            {
+                if prevout.is_none() {
+                    cache.insert(*outpoint, resolved.clone());
+                    *prevout = Some(resolved);
+                }
+            }
+        }
+
+        let mut connected = T::from(block.header, block.block_hash());
+        let mut prevouts = prevouts.into_iter().flatten();
+        for (i, tx) in block.txdata.iter().enumerate() {
+            let mut connected_tx = <T::Tx as ConnectedTx>::from(tx);
+            // the coinbase spends nothing
+            if i > 0 {
+                for tx_in in tx.input.iter() {
+                    let (created, txout) = prevouts.next().unwrap();
+                    connected_tx.add_input_at(txout.into(), tx_in, created as usize);
+                }
+            }
+            connected.add_tx(connected_tx);
+        }
+        Ok(connected)
+    }
+
+    ///
+    /// Prevouts of all inputs of `block` except the coinbase, in order.
+    ///
+    fn resolve_prevouts(&self, height: usize, block: &Block) -> OpResult<Vec<StoredTxOut>> {
+        let n_inputs: usize = block.txdata.iter().skip(1).map(|tx| tx.input.len()).sum();
+        if let Ok(undo) = self.get_block_undo(height) {
+            let spent: Vec<StoredTxOut> = undo
+                .txs
+                .into_iter()
+                .flatten()
+                .map(|s| (s.height, s.txout))
+                .collect();
+            if spent.len() == n_inputs {
+                return Ok(spent);
+            }
+        }
+        if !self.tx_db.is_open() {
+            return Err(OpError::from(
+                format!(
+                    "cannot resolve prevouts of block {}: no undo data and no txindex",
+                    height
+                )
+                .as_str(),
+            ));
+        }
+        let mut prevouts = Vec::with_capacity(n_inputs);
+        for tx_in in block.txdata.iter().skip(1).flat_map(|tx| tx.input.iter()) {
+            let outpoint = tx_in.previous_output;
+            let prev_tx = self.get_transaction::<bitcoin::Transaction>(&outpoint.txid)?;
+            let txout = prev_tx
+                .output
+                .get(outpoint.vout as usize)
+                .cloned()
+                .ok_or_else(|| OpError::from(format!("no output {}", outpoint).as_str()))?;
+            let created = self.get_height_of_transaction(&outpoint.txid)?;
+            prevouts.push((created as u32, txout));
+        }
+        Ok(prevouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use crate::FConnectedBlock;
+    use bitcoin::{Script, TxIn};
+
+    #[test]
+    fn test_prevout_cache() {
+        // block 4 spends the coinbases of blocks 1 and 2
+        let mut chain = SyntheticChain::new();
+        let tips = chain.extend(&chain.genesis(), 3);
+        let mut spend = SyntheticChain::spend(chain.coinbase_outpoint(&tips[0]).unwrap(), 1000);
+        spend.input.push(TxIn {
+            previous_output: chain.coinbase_outpoint(&tips[1]).unwrap(),
+            ..Default::default()
+        });
+        let spending = chain.mine(&tips[2], vec![spend]);
+        chain.extend(&spending, 1);
+        let db = TestDb::new("prevout_cache", &chain);
+        let path = db.dir.join("prevouts.bin");
+
+        let expected: Vec<FConnectedBlock> = db.iter_connected_block(6).collect();
+        let cache = Arc::new(PrevoutCache::open(&path, 10).unwrap());
+        let first: Vec<FConnectedBlock> = db.iter_connected_block_cached(3, 6, &cache).collect();
+        assert_eq!(first, expected[3..].to_vec());
+        assert_eq!(cache.stats().len, 2);
+        assert_eq!(cache.stats().hits, 0);
+        drop(cache);
+
+        // reopened, overlapping range: prevouts come from the cache
+        let cache = Arc::new(PrevoutCache::open(&path, 10).unwrap());
+        assert_eq!(cache.stats().len, 2);
+        let second: Vec<FConnectedBlock> = db.iter_connected_block_cached(4, 5, &cache).collect();
+        assert_eq!(second, expected[4..5].to_vec());
+        assert_eq!((cache.stats().hits, cache.stats().misses), (2, 0));
+
+        // least recently used first out
+        let cache = PrevoutCache::open(&db.dir.join("small.bin"), 2).unwrap();
+        let prevout = |v| {
+            (
+                1,
+                TxOut {
+                    value: v,
+                    script_pubkey: Script::new(),
+                },
+            )
+        };
+        let outpoint = |vout| OutPoint::new(Default::default(), vout);
+        cache.insert(outpoint(0), prevout(0));
+        cache.insert(outpoint(1), prevout(1));
+        assert!(cache.get(&outpoint(0)).is_some());
+        cache.insert(outpoint(2), prevout(2));
+        assert!(cache.get(&outpoint(1)).is_none());
+        assert_eq!(cache.get(&outpoint(0)), Some(prevout(0)));
+        drop(cache);
+    }
+}
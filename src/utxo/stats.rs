@@ -0,0 +1,150 @@
+use crate::api::{sample_uniform, SplitMix64};
+use crate::parser::script::{evaluate_script, ScriptType};
+use crate::utxo::{Utxo, UtxoView};
+use bitcoin::{Network, OutPoint};
+use std::collections::HashMap;
+
+///
+/// Number and value of unspent outputs of a script type.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptTypeSummary {
+    pub script_type: ScriptType,
+    pub count: usize,
+    /// total value (satoshi)
+    pub value: u64,
+}
+
+impl UtxoView {
+    ///
+    /// Values (satoshi) at the given percentiles (`0.0..=100.0`),
+    /// using the nearest-rank method.
+    ///
+    /// `None` if the view is empty.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use bitcoin_explorer::utxo::UtxoView;
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    ///
+    /// let view = UtxoView::load(&db, 300000).unwrap();
+    /// let quartiles = view.value_percentiles(&[25.0, 50.0, 75.0]).unwrap();
+    /// ```
+    ///
+    pub fn value_percentiles(&self, percentiles: &[f64]) -> Option<Vec<u64>> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut values: Vec<u64> = self.iter().map(|(_, u)| u.txout.value).collect();
+        values.sort_unstable();
+        let n = values.len();
+        Some(
+            percentiles
+                .iter()
+                .map(|p| {
+                    let rank = (p.clamp(0.0, 100.0) / 100.0 * n as f64).ceil() as usize;
+                    values[rank.clamp(1, n) - 1]
+                })
+                .collect(),
+        )
+    }
+
+    ///
+    /// Draw `n` distinct unspent outputs, uniformly.
+    ///
+    /// The result is deterministic given `seed` and the content of the view.
+    /// If `n` exceeds the size of the view, all outputs are returned.
+    ///
+    pub fn sample(&self, n: usize, seed: u64) -> Vec<(OutPoint, Utxo)> {
+        let mut outpoints: Vec<&OutPoint> = self.iter().map(|(o, _)| o).collect();
+        outpoints.sort_unstable();
+        let mut rng = SplitMix64::new(seed);
+        sample_uniform(&mut rng, &outpoints, n)
+            .into_iter()
+            .map(|o| (*o, self.get(o).unwrap().clone()))
+            .collect()
+    }
+
+    ///
+    /// Number and value of unspent outputs by script type,
+    /// sorted by value (descending).
+    ///
+    pub fn script_type_composition(&self) -> Vec<ScriptTypeSummary> {
+        let mut by_type: HashMap<ScriptType, (usize, u64)> = HashMap::new();
+        for (_, utxo) in self.iter() {
+            let script_type = evaluate_script(&utxo.txout.script_pubkey, Network::Bitcoin).pattern;
+            let entry = by_type.entry(script_type).or_default();
+            entry.0 += 1;
+            entry.1 += utxo.txout.value;
+        }
+        let mut summary: Vec<ScriptTypeSummary> = by_type
+            .into_iter()
+            .map(|(script_type, (count, value))| ScriptTypeSummary {
+                script_type,
+                count,
+                value,
+            })
+            .collect();
+        summary.sort_unstable_by(|a, b| {
+            b.value
+                .cmp(&a.value)
+                .then_with(|| a.script_type.cmp(&b.script_type))
+        });
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utxo::UtxoView;
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+
+    fn view(values: &[u64]) -> UtxoView {
+        let mut view = UtxoView::new();
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: values
+                .iter()
+                .map(|v| TxOut {
+                    value: *v,
+                    script_pubkey: Script::new_op_return(&[]),
+                })
+                .collect(),
+        };
+        view.apply_coinbase_tx(&coinbase).unwrap();
+        view
+    }
+
+    #[test]
+    fn test_value_percentiles() {
+        assert_eq!(UtxoView::new().value_percentiles(&[50.0]), None);
+        let view = view(&[40, 10, 30, 20]);
+        assert_eq!(
+            view.value_percentiles(&[0.0, 25.0, 50.0, 75.0, 100.0]),
+            Some(vec![10, 10, 20, 30, 40])
+        );
+    }
+
+    #[test]
+    fn test_sample_and_composition() {
+        let view = view(&[1, 2, 3, 4, 5, 6]);
+        let a = view.sample(3, 7);
+        assert_eq!(a.len(), 3);
+        assert_eq!(a, view.sample(3, 7));
+        assert_eq!(view.sample(100, 7).len(), 6);
+        let composition = view.script_type_composition();
+        assert_eq!(composition.len(), 1);
+        assert_eq!(composition[0].count, 6);
+        assert_eq!(composition[0].value, 21);
+    }
+}
@@ -0,0 +1,130 @@
+//!
+//! Key and value encoding shared by on-disk stores.
+//!
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::store::StoredTxOut;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::Hash;
+use bitcoin::{OutPoint, TxOut, Txid};
+
+/// magic bytes of the UTXO cache format marker
+pub(crate) const CACHE_MAGIC: &[u8; 4] = b"BEUC";
+///
+/// version of the key / value format, bump on any change of
+/// `txo_key`, `txo_to_u8` or `txo_from_u8`
+///
+pub(crate) const CACHE_FORMAT_VERSION: u32 = 1;
+/// key of the format marker, cannot collide with `txo_key` (36 bytes)
+pub(crate) const FORMAT_KEY: &[u8] = b"__utxo_cache_format__";
+
+///
+/// value of `FORMAT_KEY`: magic + little endian version
+///
+pub(crate) fn format_marker() -> Vec<u8> {
+    let mut bytes = CACHE_MAGIC.to_vec();
+    bytes.extend(CACHE_FORMAT_VERSION.to_le_bytes());
+    bytes
+}
+
+///
+/// Check the format marker read from an existing cache.
+///
+/// `is_empty` tells whether the cache has no data,
+/// in which case a missing marker is accepted (a new cache).
+///
+pub(crate) fn check_format_marker(marker: Option<&[u8]>, is_empty: bool) -> OpResult<()> {
+    match marker {
+        None if is_empty => Ok(()),
+        None => Err(OpError::from(
+            "UTXO cache has no format marker (created by an older version), delete it to rebuild",
+        )),
+        Some(bytes) if bytes.len() != 8 || &bytes[..4] != CACHE_MAGIC => Err(OpError::from(
+            "not a UTXO cache of bitcoin-explorer (bad magic)",
+        )),
+        Some(bytes) => {
+            let mut version = [0u8; 4];
+            version.copy_from_slice(&bytes[4..]);
+            let version = u32::from_le_bytes(version);
+            if version == CACHE_FORMAT_VERSION {
+                Ok(())
+            } else {
+                Err(OpError::from(
+                    format!(
+                        "UTXO cache format version {} is not supported (expected {}), delete it to rebuild",
+                        version, CACHE_FORMAT_VERSION
+                    )
+                    .as_str(),
+                ))
+            }
+        }
+    }
+}
+
+/// 32 (txid) + 4 (i32 out n)
+pub(crate) const KEY_LENGTH: usize = 32 + 4;
+
+///
+/// key: 32 bytes txid + 4 bytes little endian output index,
+/// byte order is fixed so that caches are portable across platforms
+///
+#[inline(always)]
+pub(crate) fn txo_key(txid: Txid, n: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(KEY_LENGTH);
+    bytes.extend(txid.into_inner());
+    bytes.extend(n.to_le_bytes());
+    bytes
+}
+
+///
+/// inverse of `txo_key`, `None` for other keys (e.g. `FORMAT_KEY`)
+///
+#[inline(always)]
+pub(crate) fn outpoint_from_key(bytes: &[u8]) -> Option<OutPoint> {
+    if bytes.len() != KEY_LENGTH {
+        return None;
+    }
+    let txid = Txid::from_slice(&bytes[..32]).ok()?;
+    let mut n = [0u8; 4];
+    n.copy_from_slice(&bytes[32..]);
+    Some(OutPoint::new(txid, u32::from_le_bytes(n)))
+}
+
+///
+/// value: 4 bytes creation height + consensus encoded TxOut
+///
+#[inline(always)]
+pub(crate) fn txo_to_u8(txo: &TxOut, height: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend(height.to_le_bytes());
+    txo.consensus_encode(&mut bytes).unwrap();
+    bytes
+}
+
+#[inline(always)]
+pub(crate) fn txo_from_u8(bytes: &[u8]) -> Option<StoredTxOut> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let mut height = [0u8; 4];
+    height.copy_from_slice(&bytes[..4]);
+    match TxOut::consensus_decode(&bytes[4..]) {
+        Ok(txo) => Some((u32::from_le_bytes(height), txo)),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_format_marker, format_marker, CACHE_FORMAT_VERSION};
+
+    #[test]
+    fn test_format_marker() {
+        assert!(check_format_marker(Some(&format_marker()), false).is_ok());
+        assert!(check_format_marker(None, true).is_ok());
+        assert!(check_format_marker(None, false).is_err());
+        let mut newer = format_marker();
+        newer[4..].copy_from_slice(&(CACHE_FORMAT_VERSION + 1).to_le_bytes());
+        assert!(check_format_marker(Some(&newer), false).is_err());
+        assert!(check_format_marker(Some(b"XXXX\x01\0\0\0"), false).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::store::codec::{txo_from_u8, txo_key, txo_to_u8};
+use crate::utxo::store::{StoredTxOut, UtxoStore};
+use bitcoin::{Block, OutPoint};
+use lmdb::{Database, Environment, Transaction, WriteFlags};
+use tempdir::TempDir;
+
+/// maximum size of the memory map (virtual address space, not RAM)
+const MAP_SIZE: usize = 1 << 40;
+
+///
+/// Unspent outputs in a LMDB environment in a temporary directory,
+/// which is deleted when the store is dropped.
+///
+/// Requires a 64-bit target (the map reserves 1TB of address space).
+///
+pub struct LmdbUtxoStore {
+    env: Environment,
+    db: Database,
+    // dropped after `env`
+    _dir: TempDir,
+}
+
+fn lmdb_error(context: &str, e: lmdb::Error) -> OpError {
+    OpError::from(format!("{}, error: {}", context, e).as_str())
+}
+
+impl LmdbUtxoStore {
+    ///
+    /// Create an empty store in a new temporary directory.
+    ///
+    pub fn temporary() -> OpResult<Self> {
+        let dir = TempDir::new("lmdb").map_err(|e| {
+            OpError::from(format!("failed to create LMDB tempdir for UTXO: {}", e).as_str())
+        })?;
+        let env = Environment::new()
+            .set_map_size(MAP_SIZE)
+            .open(dir.path())
+            .map_err(|e| lmdb_error("failed to create LMDB for UTXO", e))?;
+        let db = env
+            .open_db(None)
+            .map_err(|e| lmdb_error("failed to open LMDB database", e))?;
+        Ok(LmdbUtxoStore { env, db, _dir: dir })
+    }
+}
+
+impl UtxoStore for LmdbUtxoStore {
+    fn insert_block(&self, height: u32, block: &Block) -> OpResult<()> {
+        let mut txn = self
+            .env
+            .begin_rw_txn()
+            .map_err(|e| lmdb_error("failed to begin LMDB transaction", e))?;
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            for (n, o) in (0_u32..).zip(tx.output.iter()) {
+                txn.put(
+                    self.db,
+                    &txo_key(txid, n),
+                    &txo_to_u8(o, height),
+                    WriteFlags::empty(),
+                )
+                .map_err(|e| lmdb_error("failed to write UTXO to cache", e))?;
+            }
+        }
+        txn.commit()
+            .map_err(|e| lmdb_error("failed to write UTXO to cache", e))
+    }
+
+    fn take(&self, outpoints: &[OutPoint]) -> OpResult<Vec<Option<StoredTxOut>>> {
+        let mut txn = self
+            .env
+            .begin_rw_txn()
+            .map_err(|e| lmdb_error("failed to begin LMDB transaction", e))?;
+        let mut taken = Vec::with_capacity(outpoints.len());
+        for o in outpoints {
+            let key = txo_key(o.txid, o.vout);
+            let value = match txn.get(self.db, &key) {
+                Ok(bytes) => txo_from_u8(bytes),
+                Err(lmdb::Error::NotFound) => None,
+                Err(e) => return Err(lmdb_error("failed to read UTXO", e)),
+            };
+            if value.is_some() {
+                txn.del(self.db, &key, None)
+                    .map_err(|e| lmdb_error("failed to remove UTXO", e))?;
+            }
+            taken.push(value);
+        }
+        txn.commit()
+            .map_err(|e| lmdb_error("failed to remove UTXO", e))?;
+        Ok(taken)
+    }
+}
@@ -0,0 +1,118 @@
+use crate::iter::util::VecMap;
+use crate::parser::errors::OpResult;
+use crate::utxo::store::{StoredTxOut, UtxoStore};
+use bitcoin::{Block, OutPoint, TxOut, Txid};
+use hash_hasher::HashedMap;
+#[cfg(debug_assertions)]
+use log::warn;
+use std::sync::{Arc, Mutex};
+
+type Outputs = Arc<Mutex<VecMap<TxOut>>>;
+
+///
+/// Unspent outputs in a hash map indexed by txid.
+///
+/// Requires a lot of memory for the full chain (> 32GB).
+///
+#[derive(Default)]
+pub struct InMemoryUtxoStore {
+    unspent: Mutex<HashedMap<Txid, (u32, Outputs)>>,
+}
+
+impl InMemoryUtxoStore {
+    pub fn new() -> Self {
+        InMemoryUtxoStore::default()
+    }
+
+    /// number of transactions with unspent outputs
+    pub fn len(&self) -> usize {
+        self.unspent.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unspent.lock().unwrap().is_empty()
+    }
+}
+
+impl UtxoStore for InMemoryUtxoStore {
+    fn insert_block(&self, height: u32, block: &Block) -> OpResult<()> {
+        let mut new_unspent = Vec::with_capacity(block.txdata.len());
+        for tx in block.txdata.iter() {
+            let outs: Vec<Option<Box<TxOut>>> = tx
+                .output
+                .iter()
+                .map(|o| Some(Box::new(o.clone())))
+                .collect();
+            let outs = VecMap::from_vec(outs.into_boxed_slice());
+            new_unspent.push((tx.txid(), (height, Arc::new(Mutex::new(outs)))));
+        }
+        let mut unspent = self.unspent.lock().unwrap();
+        // the new transaction should not be in unspent
+        #[cfg(debug_assertions)]
+        for (txid, _) in new_unspent.iter() {
+            if unspent.contains_key(txid) {
+                warn!("found duplicate key {}", txid);
+            }
+        }
+        unspent.extend(new_unspent);
+        Ok(())
+    }
+
+    fn take(&self, outpoints: &[OutPoint]) -> OpResult<Vec<Option<StoredTxOut>>> {
+        let mut taken = Vec::with_capacity(outpoints.len());
+        for outpoint in outpoints {
+            // temporarily lock unspent
+            let prev_tx = self
+                .unspent
+                .lock()
+                .unwrap()
+                .get(&outpoint.txid)
+                .map(|(created_height, outs)| (*created_height, outs.clone()));
+            let (created_height, outs) = match prev_tx {
+                Some(prev_tx) => prev_tx,
+                None => {
+                    taken.push(None);
+                    continue;
+                }
+            };
+            // temporarily lock prev_tx
+            let (tx_out, is_empty) = {
+                let mut outs = outs.lock().unwrap();
+                let tx_out = outs.remove(outpoint.vout as usize);
+                (tx_out, outs.is_empty())
+            };
+            // remove a key immediately when the key contains no transaction
+            if is_empty {
+                self.unspent.lock().unwrap().remove(&outpoint.txid);
+            }
+            taken.push(tx_out.map(|o| (created_height, *o)));
+        }
+        Ok(taken)
+    }
+
+    fn for_each_unspent(
+        &self,
+        f: &mut dyn FnMut(OutPoint, StoredTxOut) -> OpResult<()>,
+    ) -> OpResult<()> {
+        let unspent = self.unspent.lock().unwrap();
+        for (txid, (height, outs)) in unspent.iter() {
+            let outs = outs.lock().unwrap();
+            for (n, out) in outs.iter() {
+                f(OutPoint::new(*txid, n as u32), (*height, out.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_outputs(&self, outputs: Vec<(OutPoint, StoredTxOut)>) -> OpResult<()> {
+        let mut unspent = self.unspent.lock().unwrap();
+        for (outpoint, (height, out)) in outputs {
+            let (_, outs) = unspent.entry(outpoint.txid).or_insert_with(|| {
+                let outs = VecMap::from_vec(Vec::new().into_boxed_slice());
+                (height, Arc::new(Mutex::new(outs)))
+            });
+            outs.lock().unwrap().insert(outpoint.vout as usize, out);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//!
+//! Storage of unspent outputs used by `ConnectedBlockIter`.
+//!
+//! Four stores are provided:
+//! - `InMemoryUtxoStore`: a hash map, fastest, but needs a lot of memory
+//! - `RocksDbUtxoStore`: a temporary RocksDB (feature `on-disk-utxo`)
+//! - `SledUtxoStore`: a temporary sled database, pure Rust (feature `sled-utxo`)
+//! - `LmdbUtxoStore`: a temporary LMDB environment (feature `lmdb-utxo`)
+//!
+//! Other stores can be plugged in by implementing `UtxoStore`,
+//! and passed to `ConnectedBlockIter::with_store`.
+//!
+//! The in-memory and RocksDB stores can be dumped to and loaded from
+//! a snapshot directory (`write_utxo_snapshot`, `load_utxo_snapshot`),
+//! to resume connected iteration at a later height
+//! (`ConnectedBlockIter::new_range`).
+//! The RocksDB cache can also be kept in a chosen directory and reopened
+//! by the next run (`ConnectedBlockIterOptions::keep_cache`), its writes
+//! are logged ahead so that an interrupted run is rolled back (see `wal`).
+//!
+#[cfg(any(feature = "on-disk-utxo", feature = "sled-utxo", feature = "lmdb-utxo"))]
+mod codec;
+#[cfg(feature = "lmdb-utxo")]
+mod lmdb_store;
+mod memory;
+#[cfg(feature = "on-disk-utxo")]
+mod rocks;
+#[cfg(feature = "sled-utxo")]
+mod sled_store;
+mod snapshot;
+#[cfg(feature = "on-disk-utxo")]
+mod wal;
+
+#[cfg(feature = "lmdb-utxo")]
+pub use lmdb_store::LmdbUtxoStore;
+pub use memory::InMemoryUtxoStore;
+#[cfg(feature = "on-disk-utxo")]
+pub use rocks::{RocksDbPreset, RocksDbUtxoStore};
+#[cfg(feature = "sled-utxo")]
+pub use sled_store::SledUtxoStore;
+pub(crate) use snapshot::write_snapshot_with;
+pub use snapshot::{
+    load_utxo_snapshot, read_utxo_snapshot_info, write_utxo_snapshot, UtxoSnapshotInfo,
+};
+
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::{Block, OutPoint, TxOut};
+#[cfg(not(feature = "on-disk-utxo"))]
+use std::sync::Arc;
+
+///
+/// An unspent output and the height of the block creating it.
+///
+pub type StoredTxOut = (u32, TxOut);
+
+///
+/// A store of unspent outputs, shared by the threads of `ConnectedBlockIter`.
+///
+/// `insert_block` is called for blocks in height order,
+/// and `take` for a block only after `insert_block` of the same block.
+/// Calls for different blocks may be concurrent.
+///
+pub trait UtxoStore: Send + Sync {
+    ///
+    /// Add all outputs created by `block` at `height`.
+    ///
+    fn insert_block(&self, height: u32, block: &Block) -> OpResult<()>;
+
+    ///
+    /// Remove and return the outputs of `outpoints`, in the same order,
+    /// `None` for outputs not in the store.
+    ///
+    fn take(&self, outpoints: &[OutPoint]) -> OpResult<Vec<Option<StoredTxOut>>>;
+
+    ///
+    /// Same as `take`, for the outputs spent by the block at `height`.
+    ///
+    /// Stores logging their writes (`RocksDbUtxoStore`) record the height,
+    /// others ignore it.
+    ///
+    fn take_at(&self, _height: u32, outpoints: &[OutPoint]) -> OpResult<Vec<Option<StoredTxOut>>> {
+        self.take(outpoints)
+    }
+
+    ///
+    /// Call `f` with every output in the store (in any order, but outputs
+    /// of the same transaction consecutively), see `write_utxo_snapshot`.
+    ///
+    /// Stores without snapshot support return an error.
+    ///
+    fn for_each_unspent(
+        &self,
+        _f: &mut dyn FnMut(OutPoint, StoredTxOut) -> OpResult<()>,
+    ) -> OpResult<()> {
+        Err(OpError::from("this UTXO store does not support snapshots"))
+    }
+
+    ///
+    /// Add outputs read from a snapshot, see `load_utxo_snapshot`.
+    ///
+    /// Stores without snapshot support return an error.
+    ///
+    fn insert_outputs(&self, _outputs: Vec<(OutPoint, StoredTxOut)>) -> OpResult<()> {
+        Err(OpError::from("this UTXO store does not support snapshots"))
+    }
+}
+
+///
+/// The store used without `on-disk-utxo` (which opens its own RocksDB
+/// cache), the first enabled of: `SledUtxoStore` (feature `sled-utxo`),
+/// `LmdbUtxoStore` (feature `lmdb-utxo`), and `InMemoryUtxoStore`.
+///
+#[cfg(not(feature = "on-disk-utxo"))]
+pub(crate) fn default_store() -> OpResult<Arc<dyn UtxoStore>> {
+    #[cfg(feature = "sled-utxo")]
+    let store: Arc<dyn UtxoStore> = Arc::new(SledUtxoStore::temporary()?);
+    #[cfg(all(not(feature = "sled-utxo"), feature = "lmdb-utxo"))]
+    let store: Arc<dyn UtxoStore> = Arc::new(LmdbUtxoStore::temporary()?);
+    #[cfg(not(any(feature = "sled-utxo", feature = "lmdb-utxo")))]
+    let store: Arc<dyn UtxoStore> = Arc::new(InMemoryUtxoStore::new());
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InMemoryUtxoStore, UtxoStore};
+    use crate::testutil::TestDir;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Block, Network, OutPoint, Transaction, TxIn, TxOut};
+
+    ///
+    /// Check a store: two blocks, the second spending the first.
+    ///
+    pub(crate) fn check_store(store: &dyn UtxoStore) {
+        let block = genesis_block(Network::Bitcoin);
+        let coinbase = block.txdata[0].clone();
+        let spend = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(coinbase.txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut::default(), TxOut::default()],
+        };
+        let next = Block {
+            header: block.header,
+            txdata: vec![spend.clone()],
+        };
+        store.insert_block(0, &block).unwrap();
+        store.insert_block(1, &next).unwrap();
+
+        let outpoints = [
+            OutPoint::new(coinbase.txid(), 0),
+            OutPoint::new(spend.txid(), 1),
+            OutPoint::new(spend.txid(), 2),
+        ];
+        let taken = store.take(&outpoints).unwrap();
+        assert_eq!(taken[0], Some((0, coinbase.output[0].clone())));
+        assert_eq!(taken[1], Some((1, TxOut::default())));
+        assert_eq!(taken[2], None);
+        // outputs are removed once taken
+        assert_eq!(store.take(&outpoints[..1]).unwrap(), vec![None]);
+        assert_eq!(
+            store.take(&[OutPoint::new(spend.txid(), 0)]).unwrap(),
+            vec![Some((1, TxOut::default()))]
+        );
+    }
+
+    ///
+    /// Dump `from` (after the first block of `check_store`) and load into `to`.
+    ///
+    pub(crate) fn check_snapshot(from: &dyn UtxoStore, to: &dyn UtxoStore, dir: &std::path::Path) {
+        let block = genesis_block(Network::Bitcoin);
+        let coinbase = block.txdata[0].clone();
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: Vec::new(),
+            output: vec![TxOut::default(), TxOut::default(), TxOut::default()],
+        };
+        let next = Block {
+            header: block.header,
+            txdata: vec![tx.clone()],
+        };
+        from.insert_block(0, &block).unwrap();
+        from.insert_block(1, &next).unwrap();
+        from.take(&[OutPoint::new(tx.txid(), 1)]).unwrap();
+
+        let tip = block.block_hash();
+        let info = super::write_utxo_snapshot(from, 2, tip, dir).unwrap();
+        assert_eq!((info.height, info.tip, info.len), (2, tip, 3));
+        assert_eq!(super::read_utxo_snapshot_info(dir).unwrap(), info);
+        assert_eq!(super::load_utxo_snapshot(to, dir).unwrap(), info);
+
+        let outpoints = [
+            OutPoint::new(coinbase.txid(), 0),
+            OutPoint::new(tx.txid(), 0),
+            OutPoint::new(tx.txid(), 1),
+            OutPoint::new(tx.txid(), 2),
+        ];
+        let taken = to.take(&outpoints).unwrap();
+        assert_eq!(taken[0], Some((0, coinbase.output[0].clone())));
+        assert_eq!(taken[1], Some((1, TxOut::default())));
+        assert_eq!(taken[2], None);
+        assert_eq!(taken[3], Some((1, TxOut::default())));
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        check_store(&InMemoryUtxoStore::new());
+    }
+
+    #[test]
+    fn test_in_memory_snapshot() {
+        let dir = TestDir::new("memory_snapshot");
+        check_snapshot(&InMemoryUtxoStore::new(), &InMemoryUtxoStore::new(), &dir);
+    }
+
+    #[test]
+    #[cfg(feature = "on-disk-utxo")]
+    fn test_rocksdb_store() {
+        check_store(&super::RocksDbUtxoStore::temporary().unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "on-disk-utxo")]
+    fn test_rocksdb_snapshot() {
+        use super::RocksDbUtxoStore;
+        let dir = tempdir::TempDir::new("utxo_snapshot").unwrap();
+        let rocks = RocksDbUtxoStore::temporary().unwrap();
+        // snapshots are portable across stores
+        check_snapshot(&rocks, &InMemoryUtxoStore::new(), dir.path());
+        let memory = InMemoryUtxoStore::new();
+        check_snapshot(&memory, &RocksDbUtxoStore::temporary().unwrap(), dir.path());
+    }
+
+    #[test]
+    #[cfg(feature = "on-disk-utxo")]
+    fn test_rocksdb_store_reopen() {
+        use super::{RocksDbPreset, RocksDbUtxoStore};
+        let dir = tempdir::TempDir::new("utxo_reopen").unwrap();
+        drop(RocksDbUtxoStore::open(dir.path(), RocksDbPreset::General).unwrap());
+        // same format
+        let store = RocksDbUtxoStore::open(dir.path(), RocksDbPreset::General).unwrap();
+        check_store(&store);
+    }
+
+    #[test]
+    #[cfg(feature = "sled-utxo")]
+    fn test_sled_store() {
+        check_store(&super::SledUtxoStore::temporary().unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "lmdb-utxo")]
+    fn test_lmdb_store() {
+        check_store(&super::LmdbUtxoStore::temporary().unwrap());
+    }
+}
@@ -0,0 +1,407 @@
+use crate::iter::{MemoryProfile, ResourceCoordinator};
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::store::codec::{
+    check_format_marker, format_marker, outpoint_from_key, txo_from_u8, txo_key, txo_to_u8,
+    FORMAT_KEY,
+};
+use crate::utxo::store::wal::{roll_back_plan, UtxoWal, WalRecord, UNKNOWN_HEIGHT};
+use crate::utxo::store::{StoredTxOut, UtxoStore};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::{Block, BlockHash, OutPoint, Txid};
+use rocksdb::{BlockBasedOptions, IteratorMode, Options, SliceTransform, WriteBatch, DB};
+use std::path::Path;
+use tempdir::TempDir;
+
+///
+/// key of the height (and hash of the block before it) up to which
+/// a kept cache is complete, absent while an iteration is running
+///
+const STATE_KEY: &[u8] = b"__utxo_cache_state__";
+
+/// subdirectory of the audit log of a kept store (see `wal`)
+const WAL_DIR: &str = "wal";
+
+///
+/// Unspent outputs in a RocksDB, either in a temporary directory
+/// (deleted when the store is dropped) or in a given directory.
+///
+/// The key / value format is versioned by a marker,
+/// an existing cache with a different format is refused by `open`.
+///
+/// A store opened in a given directory logs its writes ahead,
+/// so that an interrupted iteration can be rolled back (see `roll_back`).
+///
+pub struct RocksDbUtxoStore {
+    db: DB,
+    /// audit log of a kept store
+    wal: Option<UtxoWal>,
+    // dropped after `db`
+    _dir: Option<TempDir>,
+}
+
+///
+/// Tuning presets of the RocksDB UTXO cache.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RocksDbPreset {
+    /// large memtables and one background job per logical cpu
+    General,
+    ///
+    /// Apple Silicon (M-series) with fast internal SSDs:
+    /// background jobs limited to the performance cores,
+    /// smaller memtables flushed more often, and larger compaction reads,
+    /// which favours the high random read throughput of these SSDs.
+    ///
+    AppleSilicon,
+    ///
+    /// Small devices (`MemoryProfile::Low`): two 16 MB memtables
+    /// and two background jobs.
+    ///
+    LowMemory,
+}
+
+impl Default for RocksDbPreset {
+    ///
+    /// `LowMemory` with `MemoryProfile::Low`,
+    /// otherwise `AppleSilicon` on macOS aarch64, and `General` elsewhere.
+    ///
+    fn default() -> Self {
+        if ResourceCoordinator::global().memory_profile() == MemoryProfile::Low {
+            RocksDbPreset::LowMemory
+        } else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+            RocksDbPreset::AppleSilicon
+        } else {
+            RocksDbPreset::General
+        }
+    }
+}
+
+impl RocksDbUtxoStore {
+    ///
+    /// Create an empty store in a new temporary directory,
+    /// tuned by `RocksDbPreset::default()`.
+    ///
+    pub fn temporary() -> OpResult<Self> {
+        RocksDbUtxoStore::temporary_with_preset(RocksDbPreset::default())
+    }
+
+    ///
+    /// Create an empty store in a new temporary directory, tuned by `preset`.
+    ///
+    pub fn temporary_with_preset(preset: RocksDbPreset) -> OpResult<Self> {
+        let dir = TempDir::new("rocks_db").map_err(|e| {
+            OpError::from(format!("failed to create rocksDB tempdir for UTXO: {}", e).as_str())
+        })?;
+        let mut store = RocksDbUtxoStore::open_db(dir.path(), preset)?;
+        store._dir = Some(dir);
+        Ok(store)
+    }
+
+    ///
+    /// Create an empty store in a new temporary directory under `dir`
+    /// (e.g. on a larger disk), tuned by `preset`.
+    ///
+    pub fn temporary_in<P: AsRef<Path>>(dir: P, preset: RocksDbPreset) -> OpResult<Self> {
+        let dir = TempDir::new_in(dir, "rocks_db").map_err(|e| {
+            OpError::from(format!("failed to create rocksDB tempdir for UTXO: {}", e).as_str())
+        })?;
+        let mut store = RocksDbUtxoStore::open_db(dir.path(), preset)?;
+        store._dir = Some(dir);
+        Ok(store)
+    }
+
+    ///
+    /// Open (or create) a store at `path`, which is kept after the store is dropped.
+    ///
+    /// Fails if `path` contains a cache of a different format version.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P, preset: RocksDbPreset) -> OpResult<Self> {
+        let mut store = RocksDbUtxoStore::open_db(path.as_ref(), preset)?;
+        store.wal = Some(UtxoWal::open(&path.as_ref().join(WAL_DIR))?);
+        Ok(store)
+    }
+
+    /// open or create a store at `path`, without audit log
+    fn open_db(path: &Path, preset: RocksDbPreset) -> OpResult<Self> {
+        let db = DB::open(&rocksdb_options(preset), path).map_err(|e| {
+            OpError::from(format!("failed to open rocksDB for UTXO: {}", e).as_str())
+        })?;
+        let marker = db
+            .get(FORMAT_KEY)
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?;
+        let is_empty = db.iterator(IteratorMode::Start).next().is_none();
+        check_format_marker(marker.as_deref(), is_empty)?;
+        if marker.is_none() {
+            db.put(FORMAT_KEY, format_marker())
+                .map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))?;
+        }
+        Ok(RocksDbUtxoStore {
+            db,
+            wal: None,
+            _dir: None,
+        })
+    }
+
+    ///
+    /// The height up to which the store is complete, with the hash of
+    /// the block before it, `None` if not recorded (e.g. an interrupted iteration).
+    ///
+    pub fn state(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        let bytes = self
+            .db
+            .get(STATE_KEY)
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?;
+        match bytes {
+            Some(bytes) if bytes.len() == 8 + 32 => {
+                let mut height = [0u8; 8];
+                height.copy_from_slice(&bytes[..8]);
+                let tip = deserialize(&bytes[8..])?;
+                Ok(Some((u64::from_le_bytes(height) as usize, tip)))
+            }
+            Some(_) => Err(OpError::from("corrupted UTXO cache state")),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Record (after flushing all outputs to disk) or clear the state of the store.
+    ///
+    pub(crate) fn set_state(&self, state: Option<(usize, BlockHash)>) -> OpResult<()> {
+        let result = match state {
+            Some((height, tip)) => {
+                let mut bytes = (height as u64).to_le_bytes().to_vec();
+                bytes.extend(serialize(&tip));
+                // outputs are written without WAL
+                self.db.flush().and_then(|_| self.db.put(STATE_KEY, bytes))
+            }
+            None => self.db.delete(STATE_KEY),
+        };
+        result.map_err(|e| OpError::from(format!("failed to write rocksDB: {}", e).as_str()))?;
+        match state {
+            Some((height, tip)) => self.checkpoint(height, tip),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Flush all outputs to disk, and record in the audit log that the store
+    /// is complete at `height` (`tip` is the hash of block `height - 1`).
+    ///
+    /// All batches of blocks before `height` must have been applied,
+    /// batches of later blocks may be in progress.
+    ///
+    pub(crate) fn checkpoint(&self, height: usize, tip: BlockHash) -> OpResult<()> {
+        if let Some(wal) = &self.wal {
+            self.db
+                .flush()
+                .map_err(|e| OpError::from(format!("failed to flush rocksDB: {}", e).as_str()))?;
+            wal.checkpoint(height as u32, tip)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Roll an interrupted store back to its last checkpoint, by undoing the
+    /// batches of its audit log at or after the checkpoint height.
+    ///
+    /// Returns the height of the checkpoint (recorded as the `state` of the
+    /// store) with the hash of the block before it, `None` if the store
+    /// has no audit log, or no checkpoint.
+    ///
+    pub fn roll_back(&self) -> OpResult<Option<(usize, BlockHash)>> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(None),
+        };
+        let ((height, tip), undo) = match roll_back_plan(wal.records()?) {
+            Some(plan) => plan,
+            None => return Ok(None),
+        };
+        // undoing is idempotent, an interrupted roll back is done again
+        let mut batch = WriteBatch::default();
+        for record in undo {
+            match record {
+                WalRecord::Insert { outputs, .. } => {
+                    for (txid, vout, count) in outputs {
+                        for n in vout..vout.saturating_add(count) {
+                            batch.delete(txo_key(txid, n));
+                        }
+                    }
+                }
+                WalRecord::Take { outputs, .. } => {
+                    for (outpoint, value) in outputs {
+                        batch.put(txo_key(outpoint.txid, outpoint.vout), value);
+                    }
+                }
+                WalRecord::Checkpoint { .. } => {}
+            }
+        }
+        self.db.write_without_wal(batch).map_err(|e| {
+            OpError::from(format!("failed to roll back UTXO cache, error: {}", e).as_str())
+        })?;
+        self.set_state(Some((height as usize, tip)))?;
+        Ok(Some((height as usize, tip)))
+    }
+}
+
+fn rocksdb_options(preset: RocksDbPreset) -> Options {
+    let mut options = Options::default();
+    // create table
+    options.create_if_missing(true);
+    // configure l0 and l1 size, let them have the same size (1 GB)
+    options.set_level_zero_file_num_compaction_trigger(4);
+    options.set_max_bytes_for_level_base(0x40000000);
+    // 256MB file size
+    options.set_target_file_size_base(0x10000000);
+    // use a smaller compaction multiplier
+    options.set_max_bytes_for_level_multiplier(4.0);
+    // use 8-byte prefix (2 ^ 64 is far enough for transaction counts)
+    options.set_prefix_extractor(SliceTransform::create_fixed_prefix(8));
+    // most lookups hit (outputs are spent once), a memtable bloom skips the rest
+    options.set_memtable_prefix_bloom_ratio(0.1);
+    // mem-tables are capped by the container memory limit
+    let coordinator = ResourceCoordinator::global();
+    match preset {
+        RocksDbPreset::General => {
+            // config to more jobs
+            options.set_max_background_jobs(num_cpus::get() as i32);
+            // configure mem-table to a large value (256 MB)
+            options.set_write_buffer_size(coordinator.capped(0x10000000));
+        }
+        RocksDbPreset::AppleSilicon => {
+            // efficiency cores slow down compaction
+            options.set_max_background_jobs((num_cpus::get() / 2).max(2) as i32);
+            // 128 MB mem-tables, up to 4
+            options.set_write_buffer_size(coordinator.capped(0x8000000));
+            options.set_max_write_buffer_number(4);
+            // 2 MB compaction reads, 1 MB incremental syncs
+            options.set_compaction_readahead_size(0x200000);
+            options.set_bytes_per_sync(0x100000);
+        }
+        RocksDbPreset::LowMemory => {
+            options.set_max_background_jobs(2);
+            // 16 MB mem-tables, up to 2
+            options.set_write_buffer_size(coordinator.capped(0x1000000));
+            options.set_max_write_buffer_number(2);
+            // 64 MB level 1 and files
+            options.set_max_bytes_for_level_base(0x4000000);
+            options.set_target_file_size_base(0x4000000);
+        }
+    }
+    let mut block_options = BlockBasedOptions::default();
+    // 10 bits per key bloom filters (~1% false positive)
+    block_options.set_bloom_filter(10.0, false);
+    // share block cache among all UTXO caches of this process
+    if let Some(cache) = coordinator.block_cache() {
+        block_options.set_block_cache(&cache);
+    }
+    options.set_block_based_table_factory(&block_options);
+    options
+}
+
+impl UtxoStore for RocksDbUtxoStore {
+    fn insert_block(&self, height: u32, block: &Block) -> OpResult<()> {
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        if let Some(wal) = &self.wal {
+            let outputs = txids
+                .iter()
+                .zip(block.txdata.iter())
+                .map(|(txid, tx)| (*txid, 0, tx.output.len() as u32))
+                .collect();
+            wal.append(&WalRecord::Insert { height, outputs })?;
+        }
+        let mut batch = WriteBatch::default();
+        for (txid, tx) in txids.into_iter().zip(block.txdata.iter()) {
+            for (n, o) in (0_u32..).zip(tx.output.iter()) {
+                batch.put(txo_key(txid, n), txo_to_u8(o, height));
+            }
+        }
+        self.db.write_without_wal(batch).map_err(|e| {
+            OpError::from(format!("failed to write UTXO to cache, error: {}", e).as_str())
+        })
+    }
+
+    fn take(&self, outpoints: &[OutPoint]) -> OpResult<Vec<Option<StoredTxOut>>> {
+        self.take_at(UNKNOWN_HEIGHT, outpoints)
+    }
+
+    fn take_at(&self, height: u32, outpoints: &[OutPoint]) -> OpResult<Vec<Option<StoredTxOut>>> {
+        let keys: Vec<Vec<u8>> = outpoints.iter().map(|o| txo_key(o.txid, o.vout)).collect();
+        // read and decode everything before the WAL append and the deletes,
+        // so that a failed read leaves the store untouched
+        let values: Vec<Option<Vec<u8>>> = self
+            .db
+            .multi_get(keys.iter())
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?;
+        let mut taken = Vec::with_capacity(values.len());
+        for (outpoint, value) in outpoints.iter().zip(values.iter()) {
+            taken.push(match value {
+                Some(value) => Some(txo_from_u8(value).ok_or_else(|| {
+                    OpError::from(format!("corrupted UTXO cache entry {}", outpoint).as_str())
+                })?),
+                None => None,
+            });
+        }
+        if let Some(wal) = &self.wal {
+            let outputs = outpoints
+                .iter()
+                .zip(values)
+                .filter_map(|(outpoint, value)| Some((*outpoint, value?)))
+                .collect();
+            wal.append(&WalRecord::Take { height, outputs })?;
+        }
+        for key in keys {
+            self.db.delete(&key).map_err(|e| {
+                OpError::from(format!("failed to remove key {:?}, error: {}", &key, e).as_str())
+            })?;
+        }
+        Ok(taken)
+    }
+
+    fn for_each_unspent(
+        &self,
+        f: &mut dyn FnMut(OutPoint, StoredTxOut) -> OpResult<()>,
+    ) -> OpResult<()> {
+        // keys are ordered by txid, so outputs of a transaction are consecutive
+        for item in self.db.iterator(IteratorMode::Start) {
+            let (key, value) =
+                item.map_err(|e| OpError::from(format!("failed to read rocksDB: {}", e).as_str()))?;
+            if let Some(outpoint) = outpoint_from_key(&key) {
+                match txo_from_u8(&value) {
+                    Some(stored) => f(outpoint, stored)?,
+                    None => {
+                        return Err(OpError::from(
+                            format!("corrupted UTXO cache entry {}", outpoint).as_str(),
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_outputs(&self, outputs: Vec<(OutPoint, StoredTxOut)>) -> OpResult<()> {
+        if let Some(wal) = &self.wal {
+            let outputs = outputs
+                .iter()
+                .map(|(outpoint, _)| (outpoint.txid, outpoint.vout, 1))
+                .collect();
+            wal.append(&WalRecord::Insert {
+                height: UNKNOWN_HEIGHT,
+                outputs,
+            })?;
+        }
+        let mut batch = WriteBatch::default();
+        for (outpoint, (height, out)) in outputs.iter() {
+            batch.put(
+                txo_key(outpoint.txid, outpoint.vout),
+                txo_to_u8(out, *height),
+            );
+        }
+        self.db.write_without_wal(batch).map_err(|e| {
+            OpError::from(format!("failed to write UTXO to cache, error: {}", e).as_str())
+        })
+    }
+}
@@ -0,0 +1,52 @@
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::store::codec::{txo_from_u8, txo_key, txo_to_u8};
+use crate::utxo::store::{StoredTxOut, UtxoStore};
+use bitcoin::{Block, OutPoint};
+
+///
+/// Unspent outputs in a temporary sled database (pure Rust),
+/// which is deleted when the store is dropped.
+///
+/// Slower than `RocksDbUtxoStore`, but requires no C++ toolchain.
+///
+pub struct SledUtxoStore {
+    db: sled::Db,
+}
+
+impl SledUtxoStore {
+    ///
+    /// Create an empty store in a new temporary directory.
+    ///
+    pub fn temporary() -> OpResult<Self> {
+        let db = sled::Config::new().temporary(true).open().map_err(|e| {
+            OpError::from(format!("failed to create sled for UTXO: {}", e).as_str())
+        })?;
+        Ok(SledUtxoStore { db })
+    }
+}
+
+impl UtxoStore for SledUtxoStore {
+    fn insert_block(&self, height: u32, block: &Block) -> OpResult<()> {
+        let mut batch = sled::Batch::default();
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            for (n, o) in (0_u32..).zip(tx.output.iter()) {
+                batch.insert(txo_key(txid, n), txo_to_u8(o, height));
+            }
+        }
+        self.db.apply_batch(batch).map_err(|e| {
+            OpError::from(format!("failed to write UTXO to cache, error: {}", e).as_str())
+        })
+    }
+
+    fn take(&self, outpoints: &[OutPoint]) -> OpResult<Vec<Option<StoredTxOut>>> {
+        let mut taken = Vec::with_capacity(outpoints.len());
+        for o in outpoints {
+            let value = self.db.remove(txo_key(o.txid, o.vout)).map_err(|e| {
+                OpError::from(format!("failed to remove UTXO, error: {}", e).as_str())
+            })?;
+            taken.push(value.and_then(|bytes| txo_from_u8(&bytes)));
+        }
+        Ok(taken)
+    }
+}
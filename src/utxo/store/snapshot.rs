@@ -0,0 +1,140 @@
+//!
+//! Snapshots of a `UtxoStore`, to resume connected iteration
+//! at a later height without replaying the chain.
+//!
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::store::{StoredTxOut, UtxoStore};
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, OutPoint, TxOut};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// magic bytes of UTXO store snapshot files
+const SNAPSHOT_MAGIC: &[u8; 8] = b"UTXOSNAP";
+/// name of the snapshot file in the snapshot directory
+const SNAPSHOT_FILE: &str = "utxo.snapshot";
+/// outputs passed to `UtxoStore::insert_outputs` at once (at least)
+const LOAD_BATCH: usize = 100_000;
+
+///
+/// Header of a UTXO store snapshot.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtxoSnapshotInfo {
+    /// the snapshot holds the outputs unspent before block `height`
+    pub height: usize,
+    /// hash of block `height - 1` (all zeros for height 0),
+    /// to detect snapshots of another chain or of a reorganized tip
+    pub tip: BlockHash,
+    /// number of outputs
+    pub len: u64,
+}
+
+///
+/// Write all outputs of `store`, unspent before block `height`
+/// (whose parent is `tip`), to `dir/utxo.snapshot`.
+///
+/// The file is written to a temporary name first, so that
+/// an interrupted dump never leaves a truncated snapshot.
+///
+pub fn write_utxo_snapshot(
+    store: &dyn UtxoStore,
+    height: usize,
+    tip: BlockHash,
+    dir: &Path,
+) -> OpResult<UtxoSnapshotInfo> {
+    write_snapshot_with(height, tip, dir, |f| store.for_each_unspent(f))
+}
+
+///
+/// Write the outputs passed by `for_each_unspent` to the snapshot in `dir`.
+///
+pub(crate) fn write_snapshot_with<F>(
+    height: usize,
+    tip: BlockHash,
+    dir: &Path,
+    for_each_unspent: F,
+) -> OpResult<UtxoSnapshotInfo>
+where
+    F: FnOnce(&mut dyn FnMut(OutPoint, StoredTxOut) -> OpResult<()>) -> OpResult<()>,
+{
+    fs::create_dir_all(dir)?;
+    let path = dir.join(SNAPSHOT_FILE);
+    let tmp = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    out.write_all(SNAPSHOT_MAGIC)?;
+    out.write_u64::<LittleEndian>(height as u64)?;
+    out.write_all(&tip.into_inner())?;
+    // number of outputs, filled in at the end
+    let len_offset = (SNAPSHOT_MAGIC.len() + 8 + 32) as u64;
+    out.write_u64::<LittleEndian>(0)?;
+    let mut len = 0u64;
+    for_each_unspent(&mut |outpoint, (created, txout)| {
+        outpoint.consensus_encode(&mut out)?;
+        out.write_u32::<LittleEndian>(created)?;
+        txout.consensus_encode(&mut out)?;
+        len += 1;
+        Ok(())
+    })?;
+    let mut file = out
+        .into_inner()
+        .map_err(|e| OpError::from(format!("failed to write snapshot: {}", e).as_str()))?;
+    file.seek(SeekFrom::Start(len_offset))?;
+    file.write_u64::<LittleEndian>(len)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, &path)?;
+    Ok(UtxoSnapshotInfo { height, tip, len })
+}
+
+///
+/// Read the header of the snapshot in `dir`.
+///
+pub fn read_utxo_snapshot_info(dir: &Path) -> OpResult<UtxoSnapshotInfo> {
+    let mut reader = BufReader::new(File::open(dir.join(SNAPSHOT_FILE))?);
+    read_header(&mut reader)
+}
+
+///
+/// Add the outputs of the snapshot in `dir` to `store` (which should be empty).
+///
+pub fn load_utxo_snapshot(store: &dyn UtxoStore, dir: &Path) -> OpResult<UtxoSnapshotInfo> {
+    let mut reader = BufReader::new(File::open(dir.join(SNAPSHOT_FILE))?);
+    let info = read_header(&mut reader)?;
+    let mut batch: Vec<(OutPoint, StoredTxOut)> = Vec::with_capacity(LOAD_BATCH);
+    for _ in 0..info.len {
+        let outpoint = OutPoint::consensus_decode(&mut reader)?;
+        let created = reader.read_u32::<LittleEndian>()?;
+        let txout = TxOut::consensus_decode(&mut reader)?;
+        // keep the outputs of a transaction in the same batch
+        let txid_changed = batch.last().map_or(false, |(o, _)| o.txid != outpoint.txid);
+        if batch.len() >= LOAD_BATCH && txid_changed {
+            store.insert_outputs(std::mem::take(&mut batch))?;
+        }
+        batch.push((outpoint, (created, txout)));
+    }
+    if !batch.is_empty() {
+        store.insert_outputs(batch)?;
+    }
+    Ok(info)
+}
+
+fn read_header<R: Read>(reader: &mut R) -> OpResult<UtxoSnapshotInfo> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(OpError::from("not a UTXO store snapshot"));
+    }
+    let height = reader.read_u64::<LittleEndian>()? as usize;
+    let mut tip = [0u8; 32];
+    reader.read_exact(&mut tip)?;
+    let len = reader.read_u64::<LittleEndian>()?;
+    Ok(UtxoSnapshotInfo {
+        height,
+        tip: BlockHash::from_inner(tip),
+        len,
+    })
+}
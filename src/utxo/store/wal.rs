@@ -0,0 +1,363 @@
+//!
+//! Write-ahead audit log of the writes to a kept `RocksDbUtxoStore`.
+//!
+//! Outputs are written to the cache without the RocksDB WAL (for speed),
+//! so an interrupted iteration leaves the cache at no particular height.
+//! Before a batch of outputs is added (outputs created by a block) or
+//! removed (outputs spent by a block), its keys (and removed values) are
+//! appended to this log with the height of the block: an interrupted
+//! cache is rolled back to its last checkpoint by undoing the batches
+//! at or after the checkpoint height, instead of being rebuilt.
+//!
+//! The log is a sequence of segments (`wal/{n}.wal`), each starting with
+//! a checkpoint record. A segment is deleted once all of its batches
+//! are before the last checkpoint. A record is framed by its tag, height
+//! and length, and ends with a checksum: a torn record at the end of a
+//! segment (written when the process was interrupted) and anything after
+//! it are ignored, the batch of the torn record was never applied.
+//!
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, OutPoint, Txid};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// extension of segment files
+const SEGMENT_EXTENSION: &str = "wal";
+
+///
+/// Height of batches whose block is unknown (e.g. `UtxoStore::take`),
+/// undone by any roll back.
+///
+pub(crate) const UNKNOWN_HEIGHT: u32 = u32::MAX;
+
+const CHECKPOINT_TAG: u8 = 0;
+const INSERT_TAG: u8 = 1;
+const TAKE_TAG: u8 = 2;
+
+/// tag (1) + height (4) + payload length (4)
+const FRAME_HEADER: usize = 9;
+
+///
+/// A record of the log.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum WalRecord {
+    /// the cache is complete at `height`, `tip` is the hash of block `height - 1`
+    Checkpoint { height: u32, tip: BlockHash },
+    /// outputs `vout..vout + count` of each txid added by block `height`
+    Insert {
+        height: u32,
+        outputs: Vec<(Txid, u32, u32)>,
+    },
+    /// outputs (and their stored values) removed by block `height`
+    Take {
+        height: u32,
+        outputs: Vec<(OutPoint, Vec<u8>)>,
+    },
+}
+
+impl WalRecord {
+    fn height(&self) -> u32 {
+        match self {
+            WalRecord::Checkpoint { height, .. } => *height,
+            WalRecord::Insert { height, .. } => *height,
+            WalRecord::Take { height, .. } => *height,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let (tag, payload) = match self {
+            WalRecord::Checkpoint { tip, .. } => (CHECKPOINT_TAG, tip.into_inner().to_vec()),
+            WalRecord::Insert { outputs, .. } => {
+                let mut payload = Vec::with_capacity(outputs.len() * 40);
+                for (txid, vout, count) in outputs {
+                    payload.extend(txid.into_inner());
+                    payload.extend(vout.to_le_bytes());
+                    payload.extend(count.to_le_bytes());
+                }
+                (INSERT_TAG, payload)
+            }
+            WalRecord::Take { outputs, .. } => {
+                let mut payload = Vec::new();
+                for (outpoint, value) in outputs {
+                    payload.extend(outpoint.txid.into_inner());
+                    payload.extend(outpoint.vout.to_le_bytes());
+                    payload.extend((value.len() as u32).to_le_bytes());
+                    payload.extend(value);
+                }
+                (TAKE_TAG, payload)
+            }
+        };
+        let mut bytes = Vec::with_capacity(FRAME_HEADER + payload.len() + 4);
+        bytes.push(tag);
+        bytes.extend(self.height().to_le_bytes());
+        bytes.extend((payload.len() as u32).to_le_bytes());
+        bytes.extend(payload);
+        let checksum = fnv1a(&bytes);
+        bytes.extend(checksum.to_le_bytes());
+        bytes
+    }
+
+    ///
+    /// Decode the record at the start of `bytes`, with its encoded length,
+    /// `None` if torn or corrupted.
+    ///
+    fn decode(bytes: &[u8]) -> Option<(WalRecord, usize)> {
+        if bytes.len() < FRAME_HEADER {
+            return None;
+        }
+        let tag = bytes[0];
+        let height = u32_at(bytes, 1)?;
+        let len = u32_at(bytes, 5)? as usize;
+        let end = FRAME_HEADER.checked_add(len)?;
+        if u32_at(bytes, end)? != fnv1a(&bytes[..end]) {
+            return None;
+        }
+        let mut payload = &bytes[FRAME_HEADER..end];
+        let record = match tag {
+            CHECKPOINT_TAG => WalRecord::Checkpoint {
+                height,
+                tip: BlockHash::from_slice(payload).ok()?,
+            },
+            INSERT_TAG => {
+                let mut outputs = Vec::with_capacity(payload.len() / 40);
+                while !payload.is_empty() {
+                    let txid = Txid::from_slice(payload.get(..32)?).ok()?;
+                    outputs.push((txid, u32_at(payload, 32)?, u32_at(payload, 36)?));
+                    payload = &payload[40..];
+                }
+                WalRecord::Insert { height, outputs }
+            }
+            TAKE_TAG => {
+                let mut outputs = Vec::new();
+                while !payload.is_empty() {
+                    let txid = Txid::from_slice(payload.get(..32)?).ok()?;
+                    let vout = u32_at(payload, 32)?;
+                    let value_len = u32_at(payload, 36)? as usize;
+                    let value = payload.get(40..40usize.checked_add(value_len)?)?;
+                    outputs.push((OutPoint::new(txid, vout), value.to_vec()));
+                    payload = &payload[40 + value_len..];
+                }
+                WalRecord::Take { height, outputs }
+            }
+            _ => return None,
+        };
+        Some((record, end + 4))
+    }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    let mut le = [0u8; 4];
+    le.copy_from_slice(bytes.get(at..at.checked_add(4)?)?);
+    Some(u32::from_le_bytes(le))
+}
+
+/// 32 bits FNV-1a, detects torn records
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash: u32, b| {
+        (hash ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+///
+/// The log of a kept store, inactive (nothing logged) until the first
+/// `checkpoint`.
+///
+pub(crate) struct UtxoWal {
+    dir: PathBuf,
+    state: Mutex<WalState>,
+}
+
+struct WalState {
+    /// the segment records are appended to, `None` before the first checkpoint
+    current: Option<File>,
+    /// segments written by this process, with the highest height logged
+    segments: BTreeMap<u64, u32>,
+}
+
+impl UtxoWal {
+    ///
+    /// Open the log in `dir` (created if missing), without modifying it.
+    ///
+    pub(crate) fn open(dir: &Path) -> OpResult<UtxoWal> {
+        fs::create_dir_all(dir)?;
+        Ok(UtxoWal {
+            dir: dir.to_path_buf(),
+            state: Mutex::new(WalState {
+                current: None,
+                segments: BTreeMap::new(),
+            }),
+        })
+    }
+
+    ///
+    /// Append `record` before applying its batch (ignored before the first checkpoint).
+    ///
+    pub(crate) fn append(&self, record: &WalRecord) -> OpResult<()> {
+        let bytes = record.encode();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(current) = &mut state.current {
+            current.write_all(&bytes)?;
+            if let Some((_, max)) = state.segments.iter_mut().next_back() {
+                *max = (*max).max(record.height());
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Start a new segment at checkpoint `height`, once the store holds
+    /// (on disk) all batches before `height`, and delete the segments
+    /// no longer needed to roll back to it.
+    ///
+    /// The first checkpoint of a process deletes all previous segments,
+    /// the store must be complete at exactly `height`.
+    ///
+    pub(crate) fn checkpoint(&self, height: u32, tip: BlockHash) -> OpResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let first = state.current.is_none();
+        let previous = segment_numbers(&self.dir)?;
+        let n = previous.last().map_or(0, |n| n + 1);
+        let mut segment = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(segment_path(&self.dir, n))?;
+        segment.write_all(&WalRecord::Checkpoint { height, tip }.encode())?;
+        segment.sync_all()?;
+        for old in previous {
+            let obsolete = match state.segments.get(&old) {
+                Some(max) => *max < height,
+                None => first,
+            };
+            if obsolete {
+                fs::remove_file(segment_path(&self.dir, old))?;
+                state.segments.remove(&old);
+            }
+        }
+        state.segments.insert(n, height);
+        state.current = Some(segment);
+        Ok(())
+    }
+
+    ///
+    /// Records of all segments, in the order they were appended.
+    ///
+    pub(crate) fn records(&self) -> OpResult<Vec<WalRecord>> {
+        let mut records = Vec::new();
+        for n in segment_numbers(&self.dir)? {
+            let bytes = fs::read(segment_path(&self.dir, n))?;
+            let mut rest = bytes.as_slice();
+            while let Some((record, len)) = WalRecord::decode(rest) {
+                records.push(record);
+                rest = &rest[len..];
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn segment_path(dir: &Path, n: u64) -> PathBuf {
+    dir.join(format!("{:010}.{}", n, SEGMENT_EXTENSION))
+}
+
+/// numbers of the segments in `dir`, sorted
+fn segment_numbers(dir: &Path) -> OpResult<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        match path.file_stem().and_then(|s| s.to_str()).map(str::parse) {
+            Some(Ok(n)) => numbers.push(n),
+            _ => {
+                return Err(OpError::from(
+                    format!("unexpected file {} in UTXO cache log", path.display()).as_str(),
+                ))
+            }
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+///
+/// Last checkpoint of `records`, and the batches to undo (in reverse order)
+/// to roll back to it, `None` if there is no checkpoint.
+///
+pub(crate) fn roll_back_plan(
+    mut records: Vec<WalRecord>,
+) -> Option<((u32, BlockHash), Vec<WalRecord>)> {
+    let checkpoint = records.iter().rev().find_map(|r| match r {
+        WalRecord::Checkpoint { height, tip } => Some((*height, *tip)),
+        _ => None,
+    })?;
+    records.retain(|r| match r {
+        WalRecord::Checkpoint { .. } => false,
+        r => r.height() >= checkpoint.0,
+    });
+    records.reverse();
+    Some((checkpoint, records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestDir;
+
+    #[test]
+    fn test_wal_records() {
+        let dir = TestDir::new("utxo_wal");
+        let wal = UtxoWal::open(&dir).unwrap();
+        let txid = Txid::from_slice(&[7u8; 32]).unwrap();
+        let insert = |height| WalRecord::Insert {
+            height,
+            outputs: vec![(txid, 0, height)],
+        };
+        let take = |height| WalRecord::Take {
+            height,
+            outputs: vec![(OutPoint::new(txid, height), vec![1, 2, height as u8])],
+        };
+
+        // inactive before the first checkpoint
+        wal.append(&insert(1)).unwrap();
+        assert!(wal.records().unwrap().is_empty());
+
+        let tip = BlockHash::from_slice(&[1u8; 32]).unwrap();
+        wal.checkpoint(2, tip).unwrap();
+        wal.append(&insert(2)).unwrap();
+        wal.append(&insert(3)).unwrap();
+        wal.append(&take(2)).unwrap();
+        let tip_3 = BlockHash::from_slice(&[3u8; 32]).unwrap();
+        wal.checkpoint(3, tip_3).unwrap();
+        wal.append(&take(3)).unwrap();
+        // the first segment has a batch of height 3, kept
+        assert_eq!(segment_numbers(&dir).unwrap(), vec![0, 1]);
+        let (checkpoint, undo) = roll_back_plan(wal.records().unwrap()).unwrap();
+        assert_eq!(checkpoint, (3, tip_3));
+        assert_eq!(undo, vec![take(3), insert(3)]);
+
+        // a torn record is ignored
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, 1))
+            .unwrap();
+        f.write_all(&insert(4).encode()[..12]).unwrap();
+        assert_eq!(wal.records().unwrap().len(), 6);
+
+        wal.checkpoint(4, tip).unwrap();
+        assert_eq!(segment_numbers(&dir).unwrap(), vec![2]);
+
+        // a new process deletes all previous segments at its first checkpoint
+        drop(wal);
+        let wal = UtxoWal::open(&dir).unwrap();
+        assert_eq!(roll_back_plan(wal.records().unwrap()).unwrap().0, (4, tip));
+        wal.checkpoint(4, tip).unwrap();
+        assert_eq!(segment_numbers(&dir).unwrap(), vec![3]);
+    }
+}
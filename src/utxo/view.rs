@@ -0,0 +1,556 @@
+use crate::api::BitcoinDB;
+use crate::parser::errors::{OpError, OpResult};
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::{Block, OutPoint, Transaction, TxOut, Txid};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// magic bytes of `UtxoView` snapshot files
+const SNAPSHOT_MAGIC: &[u8; 8] = b"UTXOVIEW";
+
+///
+/// An unspent transaction output with its creation context.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub txout: TxOut,
+    /// height of the block creating this output
+    pub height: usize,
+    pub is_coinbase: bool,
+}
+
+///
+/// Changes made to a `UtxoView` by applying a transaction.
+///
+/// Pass it back to `UtxoView::rollback` to undo the transaction.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Effects {
+    pub txid: Txid,
+    /// outputs spent by the transaction (removed from the view)
+    pub spent: Vec<(OutPoint, Utxo)>,
+    /// outputs created by the transaction (added to the view)
+    pub created: Vec<OutPoint>,
+    /// outputs replaced by created outputs of the same outpoint,
+    /// i.e. unspent outputs of an earlier transaction with the same txid
+    /// (possible for coinbase transactions before BIP 34)
+    pub overwritten: Vec<(OutPoint, Utxo)>,
+    /// total input value minus total output value (0 for coinbase)
+    pub fee: u64,
+}
+
+///
+/// A position in the journal of `UtxoView`, see `UtxoView::checkpoint`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Checkpoint(usize);
+
+///
+/// An in-memory UTXO set snapshot.
+///
+/// Load the snapshot at a historical height with `UtxoView::load`,
+/// then simulate spends with `apply_tx`, and undo them with `rollback`
+/// or `rollback_to`.
+///
+/// # Memory
+///
+/// The full UTXO set is kept in memory. Loading the view near the
+/// chain tip requires tens of GB of RAM.
+///
+/// # Example
+///
+/// ```rust
+/// use bitcoin_explorer::{BitcoinDB, Transaction, Txid, FromHex};
+/// use bitcoin_explorer::utxo::UtxoView;
+/// use std::path::Path;
+///
+/// let path = Path::new("/Users/me/bitcoin");
+/// let db = BitcoinDB::new(path, false).unwrap();
+///
+/// // UTXO set right before block 100000
+/// let mut view = UtxoView::load(&db, 100000).unwrap();
+/// let checkpoint = view.checkpoint();
+///
+/// // simulate spending some transaction
+/// let tx: Transaction = db.get_block::<bitcoin_explorer::Block>(100000).unwrap().txdata.remove(1);
+/// let effects = view.apply_tx(&tx).unwrap();
+/// println!("fee paid: {}", effects.fee);
+///
+/// // restore the historical state
+/// view.rollback_to(checkpoint).unwrap();
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub struct UtxoView {
+    utxos: HashMap<OutPoint, Utxo>,
+    /// number of blocks applied, i.e. height of the next block
+    height: usize,
+    journal: Vec<Effects>,
+}
+
+impl UtxoView {
+    ///
+    /// An empty view (the UTXO set before the genesis block).
+    ///
+    pub fn new() -> Self {
+        UtxoView::default()
+    }
+
+    ///
+    /// Load the UTXO set before block `end` by replaying blocks `0..end`.
+    ///
+    pub fn load(db: &BitcoinDB, end: usize) -> OpResult<Self> {
+        let mut view = UtxoView::new();
+        view.advance_to(db, end)?;
+        Ok(view)
+    }
+
+    ///
+    /// Apply blocks from `height()` up to `end` (excluded),
+    /// e.g. to bring a view read by `read_snapshot` to a later height.
+    ///
+    pub fn advance_to(&mut self, db: &BitcoinDB, end: usize) -> OpResult<()> {
+        if end < self.height {
+            return Err(OpError::from("cannot advance a UTXO view backwards"));
+        }
+        for block in db.iter_block::<Block>(self.height, end) {
+            self.apply_block(&block)?;
+        }
+        if self.height != end {
+            return Err(OpError::from(
+                format!("failed to load UTXO up to height {}", end).as_str(),
+            ));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Write this view to `path` (outputs sorted by outpoint),
+    /// to be restored by `read_snapshot` without replaying blocks.
+    ///
+    /// Fails if hypothetical transactions are not committed or rolled back.
+    ///
+    pub fn write_snapshot(&self, path: &Path) -> OpResult<()> {
+        if !self.journal.is_empty() {
+            return Err(OpError::from(
+                "cannot write a UTXO view with uncommitted transactions",
+            ));
+        }
+        let mut outpoints: Vec<&OutPoint> = self.utxos.keys().collect();
+        outpoints.sort_unstable();
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(SNAPSHOT_MAGIC)?;
+        out.write_u64::<LittleEndian>(self.height as u64)?;
+        out.write_u64::<LittleEndian>(outpoints.len() as u64)?;
+        for outpoint in outpoints {
+            let utxo = &self.utxos[outpoint];
+            outpoint.consensus_encode(&mut out)?;
+            out.write_u32::<LittleEndian>(utxo.height as u32)?;
+            out.write_u8(utxo.is_coinbase as u8)?;
+            utxo.txout.consensus_encode(&mut out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Read a view written by `write_snapshot`.
+    ///
+    pub fn read_snapshot(path: &Path) -> OpResult<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(OpError::from("not a UTXO view snapshot"));
+        }
+        let height = reader.read_u64::<LittleEndian>()? as usize;
+        let len = reader.read_u64::<LittleEndian>()?;
+        // the length is not trusted, grow past the first entries
+        let mut utxos = HashMap::with_capacity(len.min(1 << 20) as usize);
+        for _ in 0..len {
+            let outpoint = OutPoint::consensus_decode(&mut reader)?;
+            let utxo_height = reader.read_u32::<LittleEndian>()? as usize;
+            let is_coinbase = reader.read_u8()? != 0;
+            let txout = TxOut::consensus_decode(&mut reader)?;
+            utxos.insert(
+                outpoint,
+                Utxo {
+                    txout,
+                    height: utxo_height,
+                    is_coinbase,
+                },
+            );
+        }
+        Ok(UtxoView {
+            utxos,
+            height,
+            journal: Vec::new(),
+        })
+    }
+
+    ///
+    /// Height of the next block to be applied.
+    ///
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    #[inline]
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.utxos.get(outpoint)
+    }
+
+    #[inline]
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.utxos.contains_key(outpoint)
+    }
+
+    ///
+    /// Iterate through all unspent outputs in this view (no particular order).
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &Utxo)> {
+        self.utxos.iter()
+    }
+
+    ///
+    /// Apply a confirmed block and advance the height.
+    ///
+    /// Blocks are not recorded in the journal, and cannot be rolled back.
+    /// The view is left unchanged if any transaction of the block is invalid.
+    ///
+    pub fn apply_block(&mut self, block: &Block) -> OpResult<()> {
+        let mut applied = Vec::with_capacity(block.txdata.len());
+        for tx in block.txdata.iter() {
+            match self.apply(tx) {
+                Ok(effects) => applied.push(effects),
+                Err(e) => {
+                    while let Some(effects) = applied.pop() {
+                        self.undo(effects);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.height += 1;
+        Ok(())
+    }
+
+    ///
+    /// Apply a hypothetical transaction to this view.
+    ///
+    /// Outputs created are recorded at the height of the next block.
+    /// The view is left unchanged if the transaction is invalid
+    /// (missing inputs, double spends, or outputs exceeding inputs),
+    /// or if it is a coinbase transaction (see `apply_coinbase_tx`).
+    ///
+    pub fn apply_tx(&mut self, tx: &Transaction) -> OpResult<Effects> {
+        if tx.is_coin_base() {
+            return Err(OpError::from(
+                format!("{} is a coinbase transaction", tx.txid()).as_str(),
+            ));
+        }
+        let effects = self.apply(tx)?;
+        self.journal.push(effects.clone());
+        Ok(effects)
+    }
+
+    ///
+    /// Apply a hypothetical coinbase transaction to this view,
+    /// e.g. to fund the inputs of simulated transactions.
+    ///
+    /// Its outputs are created without any input, and can be
+    /// rolled back like those of `apply_tx`.
+    ///
+    pub fn apply_coinbase_tx(&mut self, tx: &Transaction) -> OpResult<Effects> {
+        if !tx.is_coin_base() {
+            return Err(OpError::from(
+                format!("{} is not a coinbase transaction", tx.txid()).as_str(),
+            ));
+        }
+        let effects = self.apply(tx)?;
+        self.journal.push(effects.clone());
+        Ok(effects)
+    }
+
+    ///
+    /// Undo a transaction applied by `apply_tx`.
+    ///
+    /// Transactions must be rolled back in the reverse order of application.
+    ///
+    pub fn rollback(&mut self, effects: &Effects) -> OpResult<()> {
+        match self.journal.last() {
+            Some(last) if last.txid == effects.txid => {
+                let last = self.journal.pop().unwrap();
+                self.undo(last);
+                Ok(())
+            }
+            _ => Err(OpError::from(
+                "can only rollback the last applied transaction",
+            )),
+        }
+    }
+
+    ///
+    /// Mark the current state, to return to with `rollback_to`.
+    ///
+    #[inline]
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.journal.len())
+    }
+
+    ///
+    /// Undo all transactions applied after `checkpoint`.
+    ///
+    pub fn rollback_to(&mut self, checkpoint: Checkpoint) -> OpResult<()> {
+        if checkpoint.0 > self.journal.len() {
+            return Err(OpError::from("checkpoint no longer exists"));
+        }
+        while self.journal.len() > checkpoint.0 {
+            let last = self.journal.pop().unwrap();
+            self.undo(last);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Make all applied transactions permanent (clears the journal).
+    ///
+    #[inline]
+    pub fn commit(&mut self) {
+        self.journal.clear();
+    }
+
+    fn apply(&mut self, tx: &Transaction) -> OpResult<Effects> {
+        let txid = tx.txid();
+        let is_coinbase = tx.is_coin_base();
+        // validate all inputs before modifying anything
+        let mut spent = Vec::with_capacity(tx.input.len());
+        let mut value_in: u64 = 0;
+        if !is_coinbase {
+            for input in tx.input.iter() {
+                let outpoint = input.previous_output;
+                if spent.iter().any(|(o, _)| o == &outpoint) {
+                    return Err(OpError::from(
+                        format!("double spend of {} in {}", outpoint, txid).as_str(),
+                    ));
+                }
+                match self.utxos.get(&outpoint) {
+                    None => {
+                        return Err(OpError::from(
+                            format!("missing input {} of {}", outpoint, txid).as_str(),
+                        ))
+                    }
+                    Some(utxo) => {
+                        value_in = value_in.saturating_add(utxo.txout.value);
+                        spent.push((outpoint, utxo.clone()));
+                    }
+                }
+            }
+        }
+        let value_out = tx
+            .output
+            .iter()
+            .fold(0u64, |acc, o| acc.saturating_add(o.value));
+        let fee = if is_coinbase {
+            0
+        } else if value_out > value_in {
+            return Err(OpError::from(
+                format!("outputs of {} exceed inputs", txid).as_str(),
+            ));
+        } else {
+            value_in - value_out
+        };
+        for (outpoint, _) in spent.iter() {
+            self.utxos.remove(outpoint);
+        }
+        let mut created = Vec::with_capacity(tx.output.len());
+        let mut overwritten = Vec::new();
+        for (vout, o) in (0_u32..).zip(tx.output.iter()) {
+            let outpoint = OutPoint { txid, vout };
+            let utxo = Utxo {
+                txout: o.clone(),
+                height: self.height,
+                is_coinbase,
+            };
+            if let Some(previous) = self.utxos.insert(outpoint, utxo) {
+                overwritten.push((outpoint, previous));
+            }
+            created.push(outpoint);
+        }
+        Ok(Effects {
+            txid,
+            spent,
+            created,
+            overwritten,
+            fee,
+        })
+    }
+
+    fn undo(&mut self, effects: Effects) {
+        for outpoint in effects.created.iter() {
+            self.utxos.remove(outpoint);
+        }
+        self.utxos.extend(effects.spent);
+        self.utxos.extend(effects.overwritten);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestDir;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Network, Script, TxIn, Witness};
+
+    fn tx(inputs: Vec<OutPoint>, values: Vec<u64>) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xFFFFFFFF,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output: values
+                .into_iter()
+                .map(|value| TxOut {
+                    value,
+                    script_pubkey: Script::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_apply_and_rollback() {
+        let mut view = UtxoView::new();
+        let coinbase = tx(vec![OutPoint::null()], vec![50, 25]);
+        view.apply_coinbase_tx(&coinbase).unwrap();
+        view.commit();
+        assert_eq!(view.len(), 2);
+
+        let checkpoint = view.checkpoint();
+        let spend = tx(vec![OutPoint::new(coinbase.txid(), 0)], vec![30, 10]);
+        let effects = view.apply_tx(&spend).unwrap();
+        assert_eq!(effects.fee, 10);
+        assert_eq!(view.len(), 3);
+        assert!(!view.contains(&OutPoint::new(coinbase.txid(), 0)));
+
+        // double spend and overspend are rejected without changing the view
+        assert!(view.apply_tx(&spend).is_err());
+        let overspend = tx(vec![OutPoint::new(coinbase.txid(), 1)], vec![26]);
+        assert!(view.apply_tx(&overspend).is_err());
+        assert_eq!(view.len(), 3);
+
+        let chained = tx(vec![OutPoint::new(spend.txid(), 1)], vec![10]);
+        view.apply_tx(&chained).unwrap();
+        assert!(view.rollback(&effects).is_err());
+
+        view.rollback_to(checkpoint).unwrap();
+        assert_eq!(view.len(), 2);
+        assert!(view.contains(&OutPoint::new(coinbase.txid(), 0)));
+        assert!(view.contains(&OutPoint::new(coinbase.txid(), 1)));
+    }
+
+    #[test]
+    fn test_coinbase_apply_and_rollback() {
+        let mut view = UtxoView::new();
+        let coinbase = tx(vec![OutPoint::null()], vec![50, 25]);
+        assert!(view.apply_tx(&coinbase).is_err());
+        let spend = tx(vec![OutPoint::null(), OutPoint::null()], vec![1]);
+        assert!(view.apply_coinbase_tx(&spend).is_err());
+        assert!(view.is_empty());
+
+        view.apply_coinbase_tx(&coinbase).unwrap();
+        view.commit();
+        let first = view.get(&OutPoint::new(coinbase.txid(), 0)).cloned();
+
+        // a duplicate txid overwrites the unspent outputs of the first one
+        view.apply_block(&Block {
+            header: genesis_block(Network::Bitcoin).header,
+            txdata: vec![],
+        })
+        .unwrap();
+        let effects = view.apply_coinbase_tx(&coinbase).unwrap();
+        assert_eq!(effects.overwritten.len(), 2);
+        assert_eq!(
+            view.get(&OutPoint::new(coinbase.txid(), 0)).unwrap().height,
+            1
+        );
+
+        view.rollback(&effects).unwrap();
+        assert_eq!(view.len(), 2);
+        assert_eq!(view.get(&OutPoint::new(coinbase.txid(), 0)).cloned(), first);
+    }
+
+    #[test]
+    fn test_apply_block_atomic() {
+        let mut view = UtxoView::new();
+        let funding = tx(vec![OutPoint::null()], vec![50]);
+        view.apply_coinbase_tx(&funding).unwrap();
+        view.commit();
+        let before: HashMap<OutPoint, Utxo> = view.iter().map(|(o, u)| (*o, u.clone())).collect();
+
+        // the second transaction spends a missing output
+        let coinbase = tx(vec![OutPoint::null()], vec![25]);
+        let spend = tx(vec![OutPoint::new(funding.txid(), 0)], vec![40]);
+        let missing = tx(vec![OutPoint::new(funding.txid(), 1)], vec![5]);
+        let header = genesis_block(Network::Bitcoin).header;
+        for txdata in [
+            vec![coinbase.clone(), missing.clone()],
+            vec![spend, missing],
+        ] {
+            let block = Block { header, txdata };
+            assert!(view.apply_block(&block).is_err());
+            assert_eq!(view.height(), 0);
+            let after: HashMap<OutPoint, Utxo> =
+                view.iter().map(|(o, u)| (*o, u.clone())).collect();
+            assert_eq!(after, before);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut view = UtxoView::new();
+        let coinbase = tx(vec![OutPoint::null()], vec![50, 25]);
+        view.apply_coinbase_tx(&coinbase).unwrap();
+        let dir = TestDir::new("utxo_snapshot");
+        let path = dir.join("snapshot");
+        assert!(view.write_snapshot(&path).is_err());
+        view.commit();
+        view.write_snapshot(&path).unwrap();
+
+        let copy = UtxoView::read_snapshot(&path).unwrap();
+        assert_eq!(copy.height(), view.height());
+        assert_eq!(copy.len(), 2);
+        assert_eq!(
+            copy.get(&OutPoint::new(coinbase.txid(), 1)),
+            view.get(&OutPoint::new(coinbase.txid(), 1))
+        );
+
+        // a truncated snapshot claiming u64::MAX entries is an error
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, data).unwrap();
+        assert!(UtxoView::read_snapshot(&path).is_err());
+    }
+}
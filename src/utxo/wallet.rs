@@ -0,0 +1,461 @@
+use crate::api::descriptor_checksum;
+use crate::parser::errors::{OpError, OpResult};
+use crate::utxo::UtxoView;
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::{OutPoint, PublicKey, Script};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// weight of version, locktime, and input and output counts
+const TX_OVERHEAD_WEIGHT: u64 = 4 * (4 + 4 + 1 + 1);
+/// segwit marker and flag
+const SEGWIT_OVERHEAD_WEIGHT: u64 = 2;
+
+///
+/// Output type of a `WalletDescriptor`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DescriptorKind {
+    /// `pkh(KEY)`
+    Pkh,
+    /// `wpkh(KEY)`
+    Wpkh,
+    /// `sh(wpkh(KEY))`
+    ShWpkh,
+}
+
+impl DescriptorKind {
+    ///
+    /// Weight of an input spending this output type
+    /// (with a 72-byte signature and a compressed public key).
+    ///
+    pub fn input_weight(&self) -> u64 {
+        match self {
+            // outpoint, script length, <sig> <pubkey>, sequence
+            DescriptorKind::Pkh => 4 * (36 + 1 + 107 + 4),
+            // outpoint, empty script, sequence, witness <sig> <pubkey>
+            DescriptorKind::Wpkh => 4 * (36 + 1 + 4) + 108,
+            // the script pushes the 22-byte witness program
+            DescriptorKind::ShWpkh => 4 * (36 + 1 + 23 + 4) + 108,
+        }
+    }
+
+    ///
+    /// Weight of an output of this type.
+    ///
+    pub fn output_weight(&self) -> u64 {
+        match self {
+            DescriptorKind::Pkh => 4 * (8 + 1 + 25),
+            DescriptorKind::Wpkh => 4 * (8 + 1 + 22),
+            DescriptorKind::ShWpkh => 4 * (8 + 1 + 23),
+        }
+    }
+
+    fn is_segwit(&self) -> bool {
+        !matches!(self, DescriptorKind::Pkh)
+    }
+}
+
+///
+/// A ranged single-key output descriptor over an extended public key,
+/// e.g. `wpkh([d34db33f/84'/0'/0']xpub.../0/*)`.
+///
+/// Supports `pkh`, `wpkh`, and `sh(wpkh)` with unhardened derivation steps
+/// ending with `/*`. Key origins are ignored and checksums (`#...`) are
+/// verified if present. A bare extended key is read as `wpkh(KEY/*)`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletDescriptor {
+    pub kind: DescriptorKind,
+    pub xpub: ExtendedPubKey,
+    /// derivation steps before the wildcard
+    pub path: Vec<ChildNumber>,
+}
+
+impl FromStr for WalletDescriptor {
+    type Err = OpError;
+
+    fn from_str(s: &str) -> OpResult<Self> {
+        let s = s.trim();
+        let desc = match s.rfind('#') {
+            Some(i) => {
+                let (desc, checksum) = (&s[..i], &s[i + 1..]);
+                if descriptor_checksum(desc) != checksum {
+                    return Err(OpError::from("invalid descriptor checksum"));
+                }
+                desc
+            }
+            None => s,
+        };
+        let (kind, key) = if let Some(key) = unwrap_fn(desc, "sh(wpkh(", "))") {
+            (DescriptorKind::ShWpkh, key.to_string())
+        } else if let Some(key) = unwrap_fn(desc, "wpkh(", ")") {
+            (DescriptorKind::Wpkh, key.to_string())
+        } else if let Some(key) = unwrap_fn(desc, "pkh(", ")") {
+            (DescriptorKind::Pkh, key.to_string())
+        } else if !desc.contains('(') && !desc.contains('/') {
+            (DescriptorKind::Wpkh, format!("{}/*", desc))
+        } else {
+            return Err(OpError::from(
+                format!("unsupported descriptor: {}", desc).as_str(),
+            ));
+        };
+        // skip the key origin
+        let key = if key.starts_with('[') {
+            match key.find(']') {
+                Some(i) => &key[i + 1..],
+                None => return Err(OpError::from("unterminated key origin")),
+            }
+        } else {
+            &key[..]
+        };
+        let mut steps = key.split('/');
+        let xpub = ExtendedPubKey::from_str(steps.next().unwrap_or_default())
+            .map_err(|e| OpError::from(format!("invalid extended key: {}", e).as_str()))?;
+        let mut path = Vec::new();
+        let mut ranged = false;
+        for step in steps {
+            if ranged {
+                return Err(OpError::from("wildcard must be the last step"));
+            }
+            if step == "*" {
+                ranged = true;
+                continue;
+            }
+            let child = step
+                .parse::<u32>()
+                .ok()
+                .and_then(|i| ChildNumber::from_normal_idx(i).ok())
+                .ok_or_else(|| {
+                    OpError::from(format!("invalid or hardened derivation step: {}", step).as_str())
+                })?;
+            path.push(child);
+        }
+        if !ranged {
+            return Err(OpError::from("descriptor must end with /*"));
+        }
+        Ok(WalletDescriptor { kind, xpub, path })
+    }
+}
+
+impl fmt::Display for WalletDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut key = self.xpub.to_string();
+        for step in self.path.iter() {
+            key.push_str(&format!("/{}", step));
+        }
+        key.push_str("/*");
+        let desc = match self.kind {
+            DescriptorKind::Pkh => format!("pkh({})", key),
+            DescriptorKind::Wpkh => format!("wpkh({})", key),
+            DescriptorKind::ShWpkh => format!("sh(wpkh({}))", key),
+        };
+        write!(f, "{}#{}", desc, descriptor_checksum(&desc))
+    }
+}
+
+fn unwrap_fn<'a>(desc: &'a str, open: &str, close: &str) -> Option<&'a str> {
+    desc.strip_prefix(open).and_then(|d| d.strip_suffix(close))
+}
+
+impl WalletDescriptor {
+    ///
+    /// Script pubkeys of derivation indexes `0..n_indexes`.
+    ///
+    pub fn script_pubkeys(&self, n_indexes: u32) -> OpResult<Vec<Script>> {
+        let secp = Secp256k1::verification_only();
+        let base = self
+            .xpub
+            .derive_pub(&secp, &self.path)
+            .map_err(|e| OpError::from(format!("key derivation failed: {}", e).as_str()))?;
+        (0..n_indexes)
+            .map(|i| self.derive_script(&secp, &base, i))
+            .collect()
+    }
+
+    fn derive_script<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        base: &ExtendedPubKey,
+        index: u32,
+    ) -> OpResult<Script> {
+        let child = ChildNumber::from_normal_idx(index)
+            .and_then(|c| base.ckd_pub(secp, c))
+            .map_err(|e| OpError::from(format!("key derivation failed: {}", e).as_str()))?;
+        let key = PublicKey::new(child.public_key);
+        let wpkh = || Script::new_v0_p2wpkh(&key.wpubkey_hash().unwrap());
+        Ok(match self.kind {
+            DescriptorKind::Pkh => Script::new_p2pkh(&key.pubkey_hash()),
+            DescriptorKind::Wpkh => wpkh(),
+            DescriptorKind::ShWpkh => Script::new_p2sh(&wpkh().script_hash()),
+        })
+    }
+}
+
+///
+/// An unspent output of a wallet, found by `UtxoView::scan_descriptor`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletUtxo {
+    pub outpoint: OutPoint,
+    /// derivation index of the receiving script
+    pub index: u32,
+    /// value (satoshi)
+    pub value: u64,
+    /// height of the block creating this output
+    pub height: usize,
+    pub is_coinbase: bool,
+    pub kind: DescriptorKind,
+}
+
+impl WalletUtxo {
+    ///
+    /// Fee (satoshi) to spend this output at `feerate` (sat/vB).
+    ///
+    pub fn spend_fee(&self, feerate: f64) -> u64 {
+        (self.kind.input_weight() as f64 / 4.0 * feerate).ceil() as u64
+    }
+
+    ///
+    /// Whether the output is worth more than the fee to spend it.
+    ///
+    pub fn is_economical(&self, feerate: f64) -> bool {
+        self.value > self.spend_fee(feerate)
+    }
+}
+
+///
+/// A suggested consolidation transaction, spending `inputs`
+/// to a single output of the wallet.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsolidationBatch {
+    pub inputs: Vec<WalletUtxo>,
+    /// estimated weight of the consolidation transaction
+    pub weight: u64,
+    /// fee (satoshi) at the target feerate
+    pub fee: u64,
+    /// value of the consolidated output (satoshi)
+    pub output_value: u64,
+}
+
+impl ConsolidationBatch {
+    pub fn vsize(&self) -> u64 {
+        (self.weight + 3) / 4
+    }
+
+    ///
+    /// Fee (satoshi) saved by consolidating now instead of spending
+    /// all inputs at `future_feerate` (negative if consolidating costs more).
+    ///
+    pub fn savings(&self, future_feerate: f64) -> i64 {
+        let later: u64 = self
+            .inputs
+            .iter()
+            .map(|u| u.spend_fee(future_feerate))
+            .sum();
+        let consolidated = self
+            .inputs
+            .first()
+            .map_or(0, |u| u.spend_fee(future_feerate));
+        later as i64 - consolidated as i64 - self.fee as i64
+    }
+}
+
+impl UtxoView {
+    ///
+    /// List the unspent outputs paying to the first `n_indexes` scripts
+    /// of `descriptor` (like the range of Core's `scantxoutset`),
+    /// sorted by value.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use bitcoin_explorer::BitcoinDB;
+    /// use bitcoin_explorer::utxo::{suggest_consolidation, UtxoView, WalletDescriptor};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/Users/me/bitcoin");
+    /// let db = BitcoinDB::new(path, false).unwrap();
+    /// let view = UtxoView::load(&db, db.get_block_count()).unwrap();
+    ///
+    /// let descriptor: WalletDescriptor = "wpkh([d34db33f/84'/0'/0']xpub.../0/*)".parse().unwrap();
+    /// let utxos = view.scan_descriptor(&descriptor, 1000).unwrap();
+    /// for batch in suggest_consolidation(&utxos, 2.0, 50) {
+    ///     println!(
+    ///         "{} inputs, fee {} sat, saves {} sat at 30 sat/vB",
+    ///         batch.inputs.len(),
+    ///         batch.fee,
+    ///         batch.savings(30.0)
+    ///     );
+    /// }
+    /// ```
+    ///
+    pub fn scan_descriptor(
+        &self,
+        descriptor: &WalletDescriptor,
+        n_indexes: u32,
+    ) -> OpResult<Vec<WalletUtxo>> {
+        let scripts: HashMap<Script, u32> = descriptor
+            .script_pubkeys(n_indexes)?
+            .into_iter()
+            .zip(0..)
+            .collect();
+        let mut utxos: Vec<WalletUtxo> = self
+            .iter()
+            .filter_map(|(outpoint, utxo)| {
+                scripts
+                    .get(&utxo.txout.script_pubkey)
+                    .map(|&index| WalletUtxo {
+                        outpoint: *outpoint,
+                        index,
+                        value: utxo.txout.value,
+                        height: utxo.height,
+                        is_coinbase: utxo.is_coinbase,
+                        kind: descriptor.kind,
+                    })
+            })
+            .collect();
+        utxos.sort_by_key(|u| (u.value, u.outpoint));
+        Ok(utxos)
+    }
+}
+
+///
+/// Group wallet outputs into consolidation transactions of at most
+/// `max_inputs` inputs, paying `feerate` (sat/vB).
+///
+/// Outputs not worth their spending fee at `feerate` are left out.
+/// The smallest outputs are consolidated first, and batches of
+/// a single input are dropped.
+///
+pub fn suggest_consolidation(
+    utxos: &[WalletUtxo],
+    feerate: f64,
+    max_inputs: usize,
+) -> Vec<ConsolidationBatch> {
+    let mut eligible: Vec<&WalletUtxo> =
+        utxos.iter().filter(|u| u.is_economical(feerate)).collect();
+    eligible.sort_by_key(|u| (u.value, u.outpoint));
+    eligible
+        .chunks(max_inputs.max(2))
+        .filter(|chunk| chunk.len() > 1)
+        .filter_map(|chunk| {
+            let inputs: Vec<WalletUtxo> = chunk.iter().map(|&u| u.clone()).collect();
+            // the consolidated output has the type of the inputs
+            let kind = inputs[0].kind;
+            let mut weight = TX_OVERHEAD_WEIGHT
+                + inputs.iter().map(|u| u.kind.input_weight()).sum::<u64>()
+                + kind.output_weight();
+            if inputs.iter().any(|u| u.kind.is_segwit()) {
+                weight += SEGWIT_OVERHEAD_WEIGHT;
+            }
+            let fee = ((weight + 3) / 4) as f64 * feerate;
+            let fee = fee.ceil() as u64;
+            let total: u64 = inputs.iter().map(|u| u.value).sum();
+            total
+                .checked_sub(fee)
+                .map(|output_value| ConsolidationBatch {
+                    inputs,
+                    weight,
+                    fee,
+                    output_value,
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{SyntheticChain, TestDb};
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use bitcoin::{Network, Transaction, TxIn, TxOut};
+
+    fn test_xpub() -> ExtendedPubKey {
+        let secp = Secp256k1::new();
+        let xpriv = ExtendedPrivKey::new_master(Network::Bitcoin, &[7u8; 32]).unwrap();
+        ExtendedPubKey::from_priv(&secp, &xpriv)
+    }
+
+    #[test]
+    fn test_parse_descriptor() {
+        let xpub = test_xpub();
+        let desc: WalletDescriptor = format!("sh(wpkh([d34db33f/49'/0'/0']{}/1/*))", xpub)
+            .parse()
+            .unwrap();
+        assert_eq!(desc.kind, DescriptorKind::ShWpkh);
+        assert_eq!(desc.path, vec![ChildNumber::from_normal_idx(1).unwrap()]);
+        // display adds the checksum, which is verified
+        assert_eq!(desc.to_string().parse::<WalletDescriptor>().unwrap(), desc);
+
+        let bare: WalletDescriptor = xpub.to_string().parse().unwrap();
+        assert_eq!((bare.kind, bare.path.len()), (DescriptorKind::Wpkh, 0));
+
+        assert!(format!("wpkh({}/0/*)#qqqqqqqq", xpub)
+            .parse::<WalletDescriptor>()
+            .is_err());
+        assert!(format!("wpkh({}/0h/*)", xpub)
+            .parse::<WalletDescriptor>()
+            .is_err());
+        assert!(format!("wpkh({}/0)", xpub)
+            .parse::<WalletDescriptor>()
+            .is_err());
+        assert!(format!("tr({}/0/*)", xpub)
+            .parse::<WalletDescriptor>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_scan_and_consolidate() {
+        let desc: WalletDescriptor = format!("wpkh({}/0/*)", test_xpub()).parse().unwrap();
+        let scripts = desc.script_pubkeys(10).unwrap();
+        let pay = |index: usize, value: u64| TxOut {
+            value,
+            script_pubkey: scripts[index].clone(),
+        };
+
+        let mut chain = SyntheticChain::new();
+        let tip = chain.extend(&chain.genesis(), 1).pop().unwrap();
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: chain.coinbase_outpoint(&tip).unwrap(),
+                ..Default::default()
+            }],
+            output: vec![
+                pay(0, 300),
+                pay(1, 20_000),
+                pay(5, 30_000),
+                pay(2, 40_000),
+                TxOut {
+                    value: 1_000_000,
+                    script_pubkey: Script::new(),
+                },
+            ],
+        };
+        chain.mine(&tip, vec![spend]);
+        let db = TestDb::new("wallet", &chain);
+        let view = UtxoView::load(&db, db.get_block_count()).unwrap();
+
+        let utxos = view.scan_descriptor(&desc, 10).unwrap();
+        let found: Vec<(u32, u64)> = utxos.iter().map(|u| (u.index, u.value)).collect();
+        assert_eq!(found, vec![(0, 300), (1, 20_000), (5, 30_000), (2, 40_000)]);
+        assert_eq!(view.scan_descriptor(&desc, 2).unwrap().len(), 2);
+        assert!(!utxos[0].is_economical(10.0));
+
+        let batches = suggest_consolidation(&utxos, 10.0, 2);
+        // the 300 sat output is dust, the 40000 sat output is left alone
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.inputs.len(), 2);
+        assert_eq!(batch.weight, 40 + 2 + 2 * 272 + 124);
+        assert_eq!(batch.fee, 1780);
+        assert_eq!(batch.output_value, 50_000 - 1780);
+        // spending both inputs later at 50 sat/vB costs 2 * 3400 instead of 3400
+        assert_eq!(batch.savings(50.0), 3400 - 1780);
+    }
+}
@@ -6,9 +6,10 @@
 #[cfg(test)]
 mod iterator_tests {
     use bitcoin::{Block, Transaction};
+    use bitcoin_explorer::testutil::TestDir;
     use bitcoin_explorer::{
-        BitcoinDB, FBlock, FTransaction, SBlock, SConnectedBlock, SConnectedTransaction,
-        STransaction,
+        BitcoinDB, ConnectedBlockIterOptions, FBlock, FTransaction, SBlock, SConnectedBlock,
+        SConnectedTransaction, STransaction,
     };
     use std::path::PathBuf;
 
@@ -182,4 +183,732 @@ mod iterator_tests {
         let blocks: Vec<SBlock> = db.iter_heights::<SBlock, _>(test_heights).collect();
         assert_eq!(blocks, blocks_ref)
     }
+
+    #[test]
+    /// check that block and transaction locations slice out the right bytes
+    fn test_block_and_tx_location() {
+        use bitcoin::consensus::encode::serialize;
+        use std::fs::File;
+        use std::io::{Read, Seek, SeekFrom};
+
+        let db = get_test_db();
+        for h in [0, 1, 170, 1000] {
+            let loc = db.get_block_location(h).unwrap();
+            let raw = db.get_raw_block(h).unwrap();
+            assert_eq!(loc.size as usize, raw.len());
+            let mut f = File::open(&loc.path).unwrap();
+            f.seek(SeekFrom::Start(loc.offset as u64)).unwrap();
+            let mut sliced = vec![0u8; loc.size as usize];
+            f.read_exact(&mut sliced).unwrap();
+            assert_eq!(sliced, raw);
+
+            for tx in db.get_block::<Block>(h).unwrap().txdata {
+                let tx_loc = db.get_tx_location(&tx.txid()).unwrap();
+                let tx_raw = serialize(&tx);
+                assert_eq!(tx_loc.size as usize, tx_raw.len());
+                f.seek(SeekFrom::Start(tx_loc.offset)).unwrap();
+                let mut sliced = vec![0u8; tx_loc.size as usize];
+                f.read_exact(&mut sliced).unwrap();
+                assert_eq!(sliced, tx_raw);
+            }
+        }
+    }
+
+    #[test]
+    /// strict chronology check reports no violation on valid data
+    fn test_iter_connected_strict_chronology() {
+        let db = get_test_db();
+        let options = ConnectedBlockIterOptions {
+            strict_chronology: true,
+            ..Default::default()
+        };
+        let mut iter = db.iter_connected_block_with_options::<SConnectedBlock>(END, options);
+        let count = (&mut iter).count();
+        assert_eq!(count, db.get_block_count());
+        assert!(iter.consistency_report().unwrap().is_consistent());
+    }
+
+    #[test]
+    /// witness commitment check reports no violation on valid data
+    fn test_iter_connected_witness_commitment() {
+        let db = get_test_db();
+        let options = ConnectedBlockIterOptions {
+            verify_witness_commitment: true,
+            ..Default::default()
+        };
+        let mut iter = db.iter_connected_block_with_options::<SConnectedBlock>(END, options);
+        let count = (&mut iter).count();
+        assert_eq!(count, db.get_block_count());
+        assert!(iter.consistency_report().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_verify_header_chain() {
+        let db = get_test_db();
+        assert_eq!(db.verify_header_chain(), Ok(()));
+    }
+
+    #[test]
+    fn test_iter_headers() {
+        let db = get_test_db();
+        let headers: Vec<_> = db.iter_headers(5, 100).collect();
+        assert_eq!(headers.len(), 95);
+        for info in headers {
+            let header = &db.get_header(info.height).unwrap().block_header;
+            assert_eq!(info.block_hash, header.block_hash());
+            assert_eq!(info.time, header.time);
+            assert!(info.time_max >= info.time);
+            assert!(info.median_time_past <= info.time_max);
+        }
+    }
+
+    #[test]
+    fn test_chain_view_at_height() {
+        let db = get_test_db();
+        let coinbase = db.get_block::<Block>(5).unwrap().txdata.remove(0);
+        let script = &coinbase.output[0].script_pubkey;
+        let before = db.at_height(4).unwrap();
+        assert!(before.get_utxos_of_script(script).is_empty());
+        let after = db.at_height(5).unwrap();
+        let utxos = after.get_utxos_of_script(script);
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].0.txid, coinbase.txid());
+        assert_eq!(utxos[0].1.height, 5);
+        assert!(after.tx_exists(&coinbase.txid()).unwrap());
+    }
+
+    #[test]
+    fn test_export_partitioned_and_verify() {
+        use bitcoin_explorer::export::{export_partitioned, verify_dataset, ExportOptions};
+        use std::io::Write;
+
+        let db = get_test_db();
+        let tmp = TestDir::new("export");
+        let dir = tmp.join("export");
+        let options = ExportOptions {
+            partition_size: 30,
+            prefix: "blocks-".to_string(),
+            extension: ".csv".to_string(),
+            ..Default::default()
+        };
+        let manifest = export_partitioned(&db, 0..100, &dir, &options, |db, heights, file| {
+            for blk in db.iter_block::<SBlock>(heights.start, heights.end) {
+                writeln!(file, "{},{}", blk.header.block_hash, blk.txdata.len())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(manifest.partitions.len(), 4);
+        assert_eq!(manifest.parameters["amount_format"], "satoshis");
+        let manifest_path = dir.join("blocks-manifest.json");
+        assert!(verify_dataset(&db, &manifest_path).unwrap().is_ok());
+
+        std::fs::write(dir.join("blocks-30-60.csv"), b"").unwrap();
+        let report = verify_dataset(&db, &manifest_path).unwrap();
+        assert_eq!(report.corrupted, vec![dir.join("blocks-30-60.csv")]);
+    }
+
+    #[test]
+    fn test_iter_enriched() {
+        use bitcoin_explorer::enrich::{EnrichSpec, OutputScriptTypes, TxIds};
+
+        let db = get_test_db();
+        let spec = EnrichSpec::new().txids().script_types();
+        let mut h = 0;
+        for (blk, f_blk) in db
+            .iter_enriched(0, 500, &spec)
+            .zip(db.iter_block::<FBlock>(0, 500))
+        {
+            assert_eq!(blk.height, h);
+            let txids = blk.enrichments.get::<TxIds>().unwrap();
+            let types = blk.enrichments.get::<OutputScriptTypes>().unwrap();
+            for ((txid, types), tx) in txids.0.iter().zip(types.0.iter()).zip(f_blk.txdata) {
+                assert_eq!(*txid, tx.txid);
+                let expected: Vec<_> = tx.output.into_iter().map(|o| o.script_type).collect();
+                assert_eq!(*types, expected);
+            }
+            h += 1;
+        }
+        assert_eq!(h, 500);
+    }
+
+    #[test]
+    fn test_get_blocks() {
+        let db = get_test_db();
+        let heights = vec![120, 3, 50000, 3, db.get_block_count() + 10, 7];
+        let blocks = db.get_blocks::<Block>(&heights);
+        assert_eq!(blocks.len(), heights.len());
+        for (h, blk) in heights.iter().zip(blocks) {
+            match db.get_block::<Block>(*h) {
+                Ok(expected) => assert_eq!(blk.unwrap(), expected),
+                Err(_) => assert!(blk.is_err()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_tx_id_map_progress() {
+        use bitcoin_explorer::ids::build_tx_id_map;
+        use bitcoin_explorer::index::BuildOptions;
+        use std::sync::{Arc, Mutex};
+
+        let db = get_test_db();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let r = reports.clone();
+        let mut options =
+            BuildOptions::default().with_progress(move |p| r.lock().unwrap().push(*p));
+        options.progress_interval = 100;
+        let (map, progress) = build_tx_id_map(&db, 0, 1000, &options);
+        assert_eq!(progress.done, 1000);
+        assert_eq!(reports.lock().unwrap().len(), 11);
+        let n_tx: usize = db
+            .iter_block::<Block>(0, 1000)
+            .map(|b| b.txdata.len())
+            .sum();
+        assert_eq!(map.len(), n_tx);
+    }
+
+    #[test]
+    fn test_keyset_pagination() {
+        let db = get_test_db();
+        let tip = db.get_block_count();
+        let mut heights = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db.list_blocks(cursor, 7);
+            if page.is_empty() {
+                break;
+            }
+            cursor = page.last().map(|b| b.height);
+            heights.extend(page.iter().map(|b| b.height));
+        }
+        assert_eq!(heights, (0..tip).rev().collect::<Vec<_>>());
+
+        let height = (0..tip)
+            .find(|h| db.get_header(*h).unwrap().n_tx > 3)
+            .unwrap();
+        let hash = db.get_hash_from_height(height).unwrap();
+        let block = db.get_block::<Block>(height).unwrap();
+        let mut txids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db.list_txs_of_block(&hash, cursor, 2).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            cursor = page.last().map(|t| t.index);
+            txids.extend(page.into_iter().map(|t| t.txid));
+        }
+        let expected: Vec<_> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        assert_eq!(txids, expected);
+    }
+
+    #[test]
+    fn test_iter_block_joined() {
+        use bitcoin_explorer::join::{JoinKey, JoinSource};
+        use std::io::Write;
+
+        let db = get_test_db();
+        let dir = TestDir::new("join");
+        let path = dir.join("join.csv");
+        let mut f = std::fs::File::create(&path).unwrap();
+        writeln!(f, "height,txid,label").unwrap();
+        for (h, blk) in db.iter_block::<SBlock>(0, 300).enumerate().step_by(7) {
+            writeln!(f, "{},{},\"coinbase, {}\"", h, blk.txdata[0].txid, h).unwrap();
+        }
+        drop(f);
+
+        let source = JoinSource::from_csv(&path, JoinKey::Txid).unwrap();
+        assert_eq!(source.columns(), &["label".to_string()]);
+        let mut n_rows = 0;
+        for blk in db.iter_block_joined::<SBlock>(100, 300, source) {
+            let blk = blk.unwrap();
+            let coinbase = &blk.block.txdata[0].txid;
+            let labels: Vec<_> = blk.rows_of(coinbase).collect();
+            if blk.height % 7 == 0 {
+                assert_eq!(labels.len(), 1);
+                assert_eq!(labels[0].values[0], format!("coinbase, {}", blk.height));
+                n_rows += 1;
+            } else {
+                assert!(blk.rows.is_empty());
+            }
+        }
+        assert_eq!(n_rows, (100..300).filter(|h| h % 7 == 0).count());
+    }
+
+    #[test]
+    fn test_iter_enriched_fiat() {
+        use bitcoin_explorer::analysis::PriceTable;
+        use bitcoin_explorer::enrich::{EnrichSpec, FiatValues};
+        use std::sync::Arc;
+
+        let db = get_test_db();
+        let prices = PriceTable::from_heights(vec![(100, 2.0), (200, 4.0)]);
+        let spec = EnrichSpec::new().fiat(Arc::new(prices));
+        for blk in db.iter_enriched(0, 300, &spec) {
+            let fiat = blk.enrichments.get::<FiatValues>().unwrap();
+            let expected_price = match blk.height {
+                0..=99 => None,
+                100..=199 => Some(2.0),
+                _ => Some(4.0),
+            };
+            assert_eq!(fiat.price, expected_price);
+            let sats: u64 = blk
+                .block
+                .txdata
+                .iter()
+                .flat_map(|tx| tx.output.iter())
+                .map(|o| o.value)
+                .sum();
+            let expected = sats as f64 / 1e8 * expected_price.unwrap_or(0.0);
+            assert!((fiat.total_output() - expected).abs() < 1e-6);
+            assert!(fiat.fees.is_none());
+        }
+    }
+
+    #[test]
+    fn test_iter_realized_metrics() {
+        use bitcoin_explorer::analysis::{iter_realized_metrics, PriceTable};
+
+        let db = get_test_db();
+        let end = 1000;
+        let prices = PriceTable::from_heights(vec![(0, 1.0), (500, 3.0)]);
+        let mut supply = 0u64;
+        let mut n = 0;
+        for (m, blk) in iter_realized_metrics(&db, end, prices).zip(db.iter_block::<Block>(0, end))
+        {
+            assert_eq!(m.height, n);
+            n += 1;
+            assert!(m.realized_loss.abs() < 1e-9);
+            if m.height < 500 {
+                // constant price: realized cap is the spendable supply
+                supply += blk
+                    .txdata
+                    .iter()
+                    .flat_map(|tx| tx.output.iter())
+                    .filter(|o| !o.script_pubkey.is_provably_unspendable())
+                    .map(|o| o.value)
+                    .sum::<u64>();
+                assert!((m.realized_cap - supply as f64 / 1e8).abs() < 1e-6);
+                if let Some(sopr) = m.sopr() {
+                    assert!((sopr - 1.0).abs() < 1e-9);
+                }
+            } else if let Some(sopr) = m.sopr() {
+                assert!(sopr >= 1.0);
+            }
+        }
+        assert_eq!(n, end);
+    }
+
+    #[test]
+    fn test_extract_slice() {
+        let db = get_test_db();
+        let tmp = TestDir::new("slice");
+        let dir = tmp.join("slice");
+        let heights = vec![300, 0, 1, 5, 1];
+        assert_eq!(db.extract_slice(&heights, &dir).unwrap(), 4);
+        assert!(db.extract_slice(&heights, &dir).is_err());
+
+        let slice = BitcoinDB::new(&dir, false).unwrap();
+        assert_eq!(slice.get_block_count(), 2);
+        for h in 0..=300 {
+            assert_eq!(
+                slice.get_hash_from_height(h).unwrap(),
+                db.get_hash_from_height(h).unwrap()
+            );
+            let block = slice.get_block::<Block>(h);
+            if heights.contains(&h) {
+                assert_eq!(block.unwrap(), db.get_block::<Block>(h).unwrap());
+            } else {
+                assert!(block.is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter_enriched_cached() {
+        use bitcoin_explorer::enrich::{EnrichSpec, ResultsCache, TxIds};
+
+        let db = get_test_db();
+        let tmp = TestDir::new("enriched_cache");
+        let dir = tmp.join("enriched_cache");
+        let cache = ResultsCache::open(&dir).unwrap();
+        let spec = EnrichSpec::new().txids();
+        let run = || {
+            db.iter_enriched_cached(0, 200, &spec, &cache, 0, |mut blk| {
+                blk.enrichments.remove::<TxIds>().unwrap().0
+            })
+            .collect::<Vec<Vec<bitcoin::Txid>>>()
+        };
+        let first = run();
+        assert_eq!(first.len(), 200);
+        assert_ne!(
+            spec.cache_key(),
+            EnrichSpec::new().script_types().cache_key()
+        );
+        // served from the cache
+        assert_eq!(run(), first);
+        let expected: Vec<bitcoin::Txid> = db
+            .get_block::<Block>(100)
+            .unwrap()
+            .txdata
+            .iter()
+            .map(|tx| tx.txid())
+            .collect();
+        assert_eq!(first[100], expected);
+    }
+
+    #[test]
+    fn test_wtxid_and_witness_commitments() {
+        let db = get_test_db();
+        let end = db.get_block_count().min(1000);
+        assert!(db.verify_witness_commitments(0, end).is_empty());
+        let block = db.get_block::<FBlock>(170).unwrap();
+        for tx in block.txdata {
+            // no witness data before segwit activation
+            assert_eq!(tx.wtxid.as_hash(), tx.txid.as_hash());
+        }
+    }
+
+    #[test]
+    fn test_iter_block_with_aux() {
+        use bitcoin_explorer::iter::ParMapOptions;
+
+        let db = get_test_db();
+        let mut iter = db.iter_block_with_aux(
+            0,
+            300,
+            |height, block, aux| {
+                if block.txdata.len() > 1 {
+                    aux.emit(block.txdata.len());
+                }
+                assert_eq!(aux.height(), height);
+                block.block_hash()
+            },
+            ParMapOptions::default(),
+        );
+        let receiver = iter.aux_receiver().unwrap();
+        assert!(iter.aux_receiver().is_none());
+        let hashes: Vec<bitcoin::BlockHash> = iter.collect();
+        assert_eq!(hashes.len(), 300);
+        assert_eq!(hashes[170], db.get_hash_from_height(170).unwrap());
+
+        let records: Vec<_> = receiver.iter().collect();
+        assert!(!records.is_empty());
+        assert!(records.windows(2).all(|w| w[0].height < w[1].height));
+        for r in records {
+            let n_tx = db.get_block::<Block>(r.height).unwrap().txdata.len();
+            assert_eq!(r.record, n_tx);
+        }
+    }
+
+    #[test]
+    fn test_write_checkpoints() {
+        use bitcoin_explorer::export::load_checkpoints;
+
+        let db = get_test_db();
+        let dir = TestDir::new("checkpoints");
+        let path = dir.join("checkpoints.jsonl");
+        let checkpoints = db.write_checkpoints(0..250, 100, &path).unwrap();
+        let heights: Vec<usize> = checkpoints.iter().map(|c| c.height).collect();
+        assert_eq!(heights, vec![99, 199, 249]);
+        assert_eq!(load_checkpoints(&path).unwrap(), checkpoints);
+
+        let n_tx: u64 = db
+            .iter_block::<Block>(0, 200)
+            .map(|b| b.txdata.len() as u64)
+            .sum();
+        assert_eq!(checkpoints[1].cumulative_tx_count, n_tx);
+        assert_eq!(
+            checkpoints[1].block_hash,
+            db.get_hash_from_height(199).unwrap()
+        );
+
+        // the digest only depends on the block hashes
+        let again = db.write_checkpoints(0..200, 50, &path).unwrap();
+        assert_eq!(again[3], checkpoints[1]);
+        assert!(db.write_checkpoints(0..250, 0, &path).is_err());
+    }
+
+    #[test]
+    fn test_is_main_chain() {
+        let db = get_test_db();
+        for height in [0, 1, 170, 299] {
+            let hash = db.get_hash_from_height(height).unwrap();
+            assert!(db.is_main_chain(&hash));
+        }
+        let header = db.get_header(1).unwrap().block_header;
+        // the previous block of block 1 is the genesis block
+        assert!(db.is_main_chain(&header.prev_blockhash));
+        assert!(!db.is_main_chain(&bitcoin::BlockHash::default()));
+    }
+
+    #[test]
+    fn test_tx_numbering() {
+        let db = get_test_db();
+        let numbering = db.tx_numbering();
+        assert_eq!(numbering.len(), db.get_block_count());
+        let mut n = 0u64;
+        for (height, block) in db.iter_block::<Block>(0, 300).enumerate() {
+            assert_eq!(db.ntx_at(height).unwrap(), block.txdata.len());
+            assert_eq!(numbering.first_tx_number(height), Some(n));
+            for index in 0..block.txdata.len() {
+                assert_eq!(numbering.position(n), Some((height, index)));
+                n += 1;
+            }
+            assert_eq!(numbering.cumulative_tx_count(height), Some(n));
+        }
+        assert!(db.ntx_at(db.get_block_count() + 1000000).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        use bitcoin_explorer::{SearchResult, ToHex};
+
+        let db = get_test_db();
+        let hash = db.get_hash_from_height(170).unwrap();
+        let block = SearchResult::Block {
+            height: 170,
+            block_hash: hash,
+        };
+        assert_eq!(db.search(" 170 ").unwrap(), block);
+        assert_eq!(db.search(&hash.to_hex()).unwrap(), block);
+        assert_eq!(db.search(&hash.to_hex().to_uppercase()).unwrap(), block);
+
+        let txid = db.get_block::<Block>(170).unwrap().txdata[1].txid();
+        assert_eq!(
+            db.search(&txid.to_hex()).unwrap(),
+            SearchResult::Transaction { txid, height: 170 }
+        );
+
+        match db.search("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap() {
+            SearchResult::Address(address) => {
+                assert_eq!(address.to_string(), "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(db.search("").is_err());
+        assert!(db.search("not a query").is_err());
+        assert!(db.search("100000000").is_err());
+        assert!(db.search(&"00".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_export_with_merkle_sum() {
+        use bitcoin_explorer::export::{export_with_merkle_sum, verify_merkle_sum, ExportOptions};
+
+        let db = get_test_db();
+        let tmp = TestDir::new("merkle_sum");
+        let dir = tmp.join("merkle_sum");
+        let options = ExportOptions {
+            partition_size: 30,
+            prefix: "values-".to_string(),
+            extension: ".csv".to_string(),
+            ..Default::default()
+        };
+        let (_, commitment) = export_with_merkle_sum(&db, 0..100, &dir, &options, |db, h, file| {
+            let block = db.get_block::<Block>(h)?;
+            let value: u64 = block
+                .txdata
+                .iter()
+                .flat_map(|tx| tx.output.iter())
+                .map(|o| o.value)
+                .sum();
+            writeln!(file, "{},{}", h, value)?;
+            Ok(value)
+        })
+        .unwrap();
+        assert_eq!(commitment.leaves.len(), 100);
+        assert_eq!(commitment.root.sum, 100 * 50 * 100_000_000);
+        let leaf = &commitment.leaves[42];
+        assert_eq!(leaf.file, "values-30-60.csv");
+        assert!(commitment.proof(42).unwrap().verify(leaf, &commitment.root));
+
+        let path = dir.join("values-merkle_sum.json");
+        let report = verify_merkle_sum(&dir, &path).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.root, commitment.root);
+
+        // tamper with a line of the second partition
+        let file = dir.join("values-30-60.csv");
+        let content = std::fs::read_to_string(&file).unwrap();
+        std::fs::write(&file, content.replacen("31,", "31,9", 1)).unwrap();
+        let report = verify_merkle_sum(&dir, &path).unwrap();
+        assert!(report.root_matches);
+        assert_eq!(report.mismatched.len(), 29);
+        assert_eq!(report.mismatched[0], 31);
+    }
+
+    #[test]
+    fn test_background_prefetch() {
+        use bitcoin_explorer::PrefetchBudget;
+
+        let db = get_test_db();
+        let prefetch = db
+            .background_prefetch(0..200, PrefetchBudget::default())
+            .unwrap();
+        assert_eq!(prefetch.join(), 200);
+
+        // stops once the byte budget is spent
+        let size: u64 = (0..3)
+            .map(|h| db.get_raw_block(h).unwrap().len() as u64)
+            .sum();
+        let budget = PrefetchBudget {
+            max_bytes: Some(size),
+            ..Default::default()
+        };
+        let prefetch = db.background_prefetch(0..200, budget).unwrap();
+        assert_eq!(prefetch.join(), 3);
+        assert!(db
+            .background_prefetch(0..db.get_block_count() + 1, budget)
+            .is_err());
+    }
+
+    #[test]
+    fn test_partition_heights() {
+        use bitcoin_explorer::utxo::UtxoView;
+        use bitcoin_explorer::PartitionStrategy;
+
+        let db = get_test_db();
+        for strategy in [
+            PartitionStrategy::Blocks,
+            PartitionStrategy::Transactions,
+            PartitionStrategy::Bytes,
+        ] {
+            let shards = db.partition_heights(4, strategy).unwrap();
+            assert_eq!(shards.len(), 4);
+            assert_eq!(shards[0].start, 0);
+            assert_eq!(shards[3].end, db.get_block_count());
+            assert!(shards.windows(2).all(|w| w[0].end == w[1].start));
+        }
+        assert!(db.partition_heights(0, PartitionStrategy::Blocks).is_err());
+
+        let tmp = TestDir::new("partition_snapshots");
+        let dir = tmp.join("partition_snapshots");
+        let shards = db
+            .partition_range(0..300, 3, PartitionStrategy::Transactions)
+            .unwrap();
+        let paths = db.write_partition_snapshots(&shards, &dir).unwrap();
+        let view = UtxoView::read_snapshot(&paths[2]).unwrap();
+        assert_eq!(view.height(), shards[2].start);
+        assert_eq!(
+            view.len(),
+            UtxoView::load(&db, shards[2].start).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_export_spv_proofs() {
+        use bitcoin_explorer::export::verify_spv_proofs;
+
+        let db = get_test_db();
+        let height = (0..db.get_block_count())
+            .find(|h| db.get_header(*h).unwrap().n_tx > 1)
+            .unwrap();
+        let block = db.get_block::<Block>(height).unwrap();
+        let txids = vec![block.txdata[1].txid(), block.txdata[0].txid()];
+        let dir = TestDir::new("spv_proofs");
+        let path = dir.join("spv_proofs.json");
+        let bundle = db.export_spv_proofs(&txids, &path).unwrap();
+        assert_eq!(bundle.proofs.len(), 1);
+        let report = verify_spv_proofs(&path).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.tip, block.block_hash());
+        assert_eq!(report.verified.len(), 2);
+    }
+
+    /// `getrawtransaction f4184fc5...9e16 2` of Bitcoin Core,
+    /// without `desc` (checked separately) and `confirmations` (depends on the tip)
+    const CORE_TX_170: &str = r#"{
+  "txid": "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+  "hash": "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+  "version": 1,
+  "size": 275,
+  "vsize": 275,
+  "weight": 1100,
+  "locktime": 0,
+  "vin": [
+    {
+      "txid": "0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9",
+      "vout": 0,
+      "scriptSig": {
+        "asm": "304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d09[ALL]",
+        "hex": "47304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901"
+      },
+      "prevout": {
+        "generated": true,
+        "height": 9,
+        "value": 50.00000000,
+        "scriptPubKey": {
+          "asm": "0411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3 OP_CHECKSIG",
+          "hex": "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac",
+          "type": "pubkey"
+        }
+      },
+      "sequence": 4294967295
+    }
+  ],
+  "vout": [
+    {
+      "value": 10.00000000,
+      "n": 0,
+      "scriptPubKey": {
+        "asm": "04ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84c OP_CHECKSIG",
+        "hex": "4104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac",
+        "type": "pubkey"
+      }
+    },
+    {
+      "value": 40.00000000,
+      "n": 1,
+      "scriptPubKey": {
+        "asm": "0411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3 OP_CHECKSIG",
+        "hex": "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac",
+        "type": "pubkey"
+      }
+    }
+  ],
+  "fee": 0.00000000,
+  "hex": "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000",
+  "blockhash": "00000000d1145790a8694403d4063f323d499e655c83426834d4ce2f8dd4a2ee",
+  "time": 1231731025,
+  "blocktime": 1231731025
+}"#;
+
+    #[test]
+    /// render the first bitcoin transfer (block 170) like Bitcoin Core
+    fn test_tx_to_corelike_json() {
+        use bitcoin_explorer::{tx_to_corelike_json, CorePrevout, CoreTxContext};
+        use serde_json::Value;
+
+        let db = get_test_db();
+        let tx = db.get_block::<Block>(170).unwrap().txdata.remove(1);
+        let funding = db.get_block::<Block>(9).unwrap().txdata.remove(0);
+        let context = CoreTxContext {
+            prevouts: Some(vec![CorePrevout {
+                height: 9,
+                generated: true,
+                tx_out: funding.output[0].clone(),
+            }]),
+            ..CoreTxContext::from_block(&db, 170).unwrap()
+        };
+        let mut json = tx_to_corelike_json(&tx, &context);
+
+        let mut expected: Value = serde_json::from_str(CORE_TX_170).unwrap();
+        expected["confirmations"] = (db.get_block_count() - 170).into();
+        /// descriptors are `pk(<pubkey>)#<checksum>`
+        fn take_desc(script_pubkey: &mut Value) {
+            let desc = script_pubkey.as_object_mut().unwrap().remove("desc");
+            let hex = script_pubkey["hex"].as_str().unwrap();
+            let prefix = format!("pk({})#", &hex[2..hex.len() - 2]);
+            let desc = desc.unwrap().as_str().unwrap().to_string();
+            assert!(desc.starts_with(&prefix) && desc.len() == prefix.len() + 8);
+        }
+        take_desc(&mut json["vin"][0]["prevout"]["scriptPubKey"]);
+        for o in json["vout"].as_array_mut().unwrap() {
+            take_desc(&mut o["scriptPubKey"]);
+        }
+        assert_eq!(json, expected);
+    }
 }